@@ -7,8 +7,11 @@ use std::time::{Duration, Instant};
 use dns_parser::rdata::a;
 use dns_parser::rdata::aaaa;
 
+/// Default time to wait on a server before retrying with the next one
 const QUERY_TIMEOUT_MS: u64 = 1000;
 
+/// Non blocking stub resolver. Any number of queries may be in flight at
+/// once, each one trying the configured servers in order until one answers.
 pub struct Resolver {
     servers: Vec<SocketAddr>,
     cache: HashMap<String, CacheEntry>,
@@ -21,7 +24,6 @@ pub struct Resolver {
 
 struct Query {
     domain: String,
-    query_deadline: Instant,
     deadline: Instant,
     v4: bool,
     server: usize,
@@ -52,18 +54,22 @@ impl Resolver {
             queries: HashMap::new(),
             responses: HashMap::new(),
             cache: HashMap::new(),
-            timeout: Duration::from_secs(3),
+            timeout: Duration::from_millis(QUERY_TIMEOUT_MS),
             buf,
             qnum: 0,
         }
     }
 
+    /// Sets how long to wait for a server's answer before moving on to the next.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
     pub fn purge(&mut self) {
         self.cache.clear();
     }
 
     pub fn from_resolv() -> io::Result<Resolver> {
-        let buf = vec![0u8; 512];
         let mut conf = Vec::with_capacity(4096);
         let mut f = File::open("/etc/resolv.conf")?;
         f.read_to_end(&mut conf)?;
@@ -83,15 +89,7 @@ impl Resolver {
             return Err(io::Error::other("No nameservers found in resolv.conf!"));
         }
 
-        Ok(Resolver {
-            servers,
-            queries: HashMap::new(),
-            responses: HashMap::new(),
-            cache: HashMap::new(),
-            timeout: Duration::from_secs(cfg.timeout as u64),
-            buf,
-            qnum: 0,
-        })
+        Ok(Resolver::new(&servers))
     }
 
     pub fn query(
@@ -105,7 +103,9 @@ impl Resolver {
         }
 
         if let Some(entry) = self.cache.get(domain) {
-            return Ok(Some(entry.ip));
+            if Instant::now() < entry.deadline {
+                return Ok(Some(entry.ip));
+            }
         }
         if let Ok(entry) = domain.parse() {
             return Ok(Some(entry));
@@ -113,28 +113,15 @@ impl Resolver {
         if !self.responses.contains_key(domain) {
             let qn = self.qnum;
             self.qnum = self.qnum.wrapping_add(1);
-            let mut query = dns_parser::Builder::new_query(qn, true);
-            query.add_question(
-                domain,
-                false,
-                dns_parser::QueryType::A,
-                dns_parser::QueryClass::IN,
-            );
-            let packet = query.build().unwrap_or_else(|d| d);
-            sock.send_to(&packet, self.servers[0])?;
-
+            let query = Query {
+                v4: true,
+                server: 0,
+                domain: domain.to_string(),
+                deadline: Instant::now() + self.timeout,
+            };
+            sock.send_to(&query.packet(qn), self.servers[0])?;
             self.responses.insert(domain.to_string(), vec![]);
-            let now = Instant::now();
-            self.queries.insert(
-                qn,
-                Query {
-                    v4: true,
-                    server: 0,
-                    domain: domain.to_string(),
-                    deadline: now + self.timeout,
-                    query_deadline: now + Duration::from_millis(QUERY_TIMEOUT_MS),
-                },
-            );
+            self.queries.insert(qn, query);
         }
         self.responses.get_mut(domain).unwrap().push(id);
         Ok(None)
@@ -193,7 +180,7 @@ impl Resolver {
                                     _ => continue,
                                 }
                             }
-                            let pkt = q.next(qn);
+                            let pkt = q.next(qn, self.timeout);
                             if q.server != self.servers.len() {
                                 sock.send_to(&pkt, self.servers[q.server])?;
                                 self.queries.insert(qn, q);
@@ -206,11 +193,9 @@ impl Resolver {
                                 }
                             }
                         }
-                        Err(e) => {
-                            return Err(io::Error::other(format!(
-                                "malformed dns packet received: {e}"
-                            )));
-                        }
+                        // Keep draining the socket, other queries may
+                        // have answers waiting behind this one.
+                        Err(_) => continue,
                     }
                 }
                 Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
@@ -223,71 +208,223 @@ impl Resolver {
         let now = Instant::now();
         let responses = &mut self.responses;
         let servers = &self.servers;
+        let timeout = self.timeout;
         let mut res = Ok(());
         self.cache.retain(|_, entry| now < entry.deadline);
         self.queries.retain(|qn, query| {
-            if now > query.query_deadline {
-                if now > query.deadline {
-                    for id in responses.remove(&query.domain).unwrap() {
-                        f(Response {
-                            id,
-                            result: Err(Error::Timeout),
-                        });
-                    }
-                } else {
-                    let pkt = query.next(*qn);
-                    if query.server != servers.len() {
-                        res = sock.send_to(&pkt, servers[query.server]).map(|_| ());
-                        return true;
-                    } else {
-                        for id in responses.remove(&query.domain).unwrap() {
-                            f(Response {
-                                id,
-                                result: Err(Error::Timeout),
-                            });
-                        }
-                    }
-                }
-                false
-            } else {
-                true
+            if now <= query.deadline {
+                return true;
+            }
+            // An unresponsive server won't do any better with an AAAA
+            // query, so retry from the top with the next one.
+            let pkt = query.next_server(*qn, timeout);
+            if query.server != servers.len() {
+                res = sock.send_to(&pkt, servers[query.server]).map(|_| ());
+                return true;
+            }
+            for id in responses.remove(&query.domain).unwrap() {
+                f(Response {
+                    id,
+                    result: Err(Error::Timeout),
+                });
             }
+            false
         });
         res
     }
 }
 
 impl Query {
-    pub fn next(&mut self, qn: u16) -> Vec<u8> {
-        self.query_deadline = Instant::now() + Duration::from_millis(QUERY_TIMEOUT_MS);
+    /// Builds the retry for a query the server had no address for, trying
+    /// AAAA after A and then moving on to the next server.
+    pub fn next(&mut self, qn: u16, timeout: Duration) -> Vec<u8> {
         if self.v4 {
             self.v4 = false;
-            let mut query = dns_parser::Builder::new_query(qn, true);
-            query.add_question(
-                &self.domain,
-                false,
-                dns_parser::QueryType::AAAA,
-                dns_parser::QueryClass::IN,
-            );
-            query.build().unwrap_or_else(|d| d)
+            self.deadline = Instant::now() + timeout;
+            self.packet(qn)
         } else {
-            self.server += 1;
-            self.v4 = true;
-            let mut query = dns_parser::Builder::new_query(qn, true);
-            query.add_question(
-                &self.domain,
-                false,
-                dns_parser::QueryType::A,
-                dns_parser::QueryClass::IN,
-            );
-            query.build().unwrap_or_else(|d| d)
+            self.next_server(qn, timeout)
         }
     }
+
+    pub fn next_server(&mut self, qn: u16, timeout: Duration) -> Vec<u8> {
+        self.server += 1;
+        self.v4 = true;
+        self.deadline = Instant::now() + timeout;
+        self.packet(qn)
+    }
+
+    fn packet(&self, qn: u16) -> Vec<u8> {
+        let qtype = if self.v4 {
+            dns_parser::QueryType::A
+        } else {
+            dns_parser::QueryType::AAAA
+        };
+        let mut query = dns_parser::Builder::new_query(qn, true);
+        query.add_question(&self.domain, false, qtype, dns_parser::QueryClass::IN);
+        query.build().unwrap_or_else(|d| d)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::net::Ipv4Addr;
+
+    /// Nameserver which only answers when told to.
+    struct MockServer {
+        sock: UdpSocket,
+    }
+
+    impl MockServer {
+        fn new() -> MockServer {
+            let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+            sock.set_read_timeout(Some(Duration::from_millis(200)))
+                .unwrap();
+            MockServer { sock }
+        }
+
+        fn addr(&self) -> SocketAddr {
+            self.sock.local_addr().unwrap()
+        }
+
+        fn recv(&self) -> Option<(Vec<u8>, SocketAddr)> {
+            let mut buf = [0u8; 512];
+            let (amnt, from) = self.sock.recv_from(&mut buf).ok()?;
+            Some((buf[..amnt].to_vec(), from))
+        }
+
+        fn answer(&self, query: &[u8], to: SocketAddr, ip: Ipv4Addr) {
+            let mut resp = query.to_vec();
+            // Response flags and a single A record pointing back at the question
+            resp[2..4].copy_from_slice(&[0x81, 0x80]);
+            resp[6..8].copy_from_slice(&[0, 1]);
+            resp.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+            resp.extend_from_slice(&ip.octets());
+            self.sock.send_to(&resp, to).unwrap();
+        }
+    }
+
+    fn client() -> UdpSocket {
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sock.set_nonblocking(true).unwrap();
+        sock
+    }
+
+    fn question(query: &[u8]) -> (String, dns_parser::QueryType) {
+        let packet = dns_parser::Packet::parse(query).unwrap();
+        let q = &packet.questions[0];
+        (q.qname.to_string(), q.qtype)
+    }
+
+    fn read_n(resolver: &mut Resolver, sock: &mut UdpSocket, n: usize) -> Vec<Response> {
+        let mut resps = Vec::new();
+        for _ in 0..100 {
+            resolver.read(sock, |r| resps.push(r)).unwrap();
+            if resps.len() >= n {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        resps
+    }
+
+    #[test]
+    fn test_concurrent() {
+        let server = MockServer::new();
+        let mut resolver = Resolver::new(&[server.addr()]);
+        let mut sock = client();
+
+        assert_eq!(resolver.query(&mut sock, 0, "a.test").unwrap(), None);
+        assert_eq!(resolver.query(&mut sock, 1, "b.test").unwrap(), None);
+        assert_eq!(resolver.query(&mut sock, 2, "c.test").unwrap(), None);
+        // Lookups of a name already in flight share its query
+        assert_eq!(resolver.query(&mut sock, 3, "a.test").unwrap(), None);
+
+        let queries: Vec<_> = (0..3).map(|_| server.recv().unwrap()).collect();
+        assert!(server.recv().is_none());
+        for (query, from) in queries.iter().rev() {
+            let ip = match question(query).0.as_str() {
+                "a.test" => Ipv4Addr::new(10, 0, 0, 1),
+                "b.test" => Ipv4Addr::new(10, 0, 0, 2),
+                _ => Ipv4Addr::new(10, 0, 0, 3),
+            };
+            server.answer(query, *from, ip);
+        }
+
+        let mut resps = read_n(&mut resolver, &mut sock, 4);
+        resps.sort_by_key(|r| r.id);
+        let ips: Vec<_> = resps.into_iter().map(|r| r.result.unwrap()).collect();
+        let ip = |n| IpAddr::V4(Ipv4Addr::new(10, 0, 0, n));
+        assert_eq!(ips, vec![ip(1), ip(2), ip(3), ip(1)]);
+    }
+
+    #[test]
+    fn test_failover() {
+        let dead = MockServer::new();
+        let server = MockServer::new();
+        let mut resolver = Resolver::new(&[dead.addr(), server.addr()]);
+        resolver.set_timeout(Duration::from_millis(50));
+        let mut sock = client();
+
+        assert_eq!(resolver.query(&mut sock, 0, "a.test").unwrap(), None);
+        assert!(dead.recv().is_some());
+        resolver
+            .tick(&mut sock, |_| panic!("query timed out early"))
+            .unwrap();
+        assert!(server.recv().is_none());
+
+        // After the timeout the A query moves straight on to the next server
+        std::thread::sleep(Duration::from_millis(60));
+        resolver
+            .tick(&mut sock, |_| panic!("query should be retried"))
+            .unwrap();
+        let (query, from) = server.recv().unwrap();
+        assert_eq!(
+            question(&query),
+            ("a.test".to_owned(), dns_parser::QueryType::A)
+        );
+        server.answer(&query, from, Ipv4Addr::new(10, 0, 0, 1));
+        let resps = read_n(&mut resolver, &mut sock, 1);
+        assert_eq!(resps[0].result, Ok(Ipv4Addr::new(10, 0, 0, 1).into()));
+        assert!(dead.recv().is_none());
+
+        // Failing once every server was tried
+        let mut resolver = Resolver::new(&[dead.addr()]);
+        resolver.set_timeout(Duration::from_millis(50));
+        resolver.query(&mut sock, 1, "b.test").unwrap();
+        std::thread::sleep(Duration::from_millis(60));
+        let mut resps = Vec::new();
+        resolver.tick(&mut sock, |r| resps.push(r)).unwrap();
+        assert_eq!(
+            resps,
+            vec![Response {
+                id: 1,
+                result: Err(Error::Timeout)
+            }]
+        );
+    }
+
+    #[test]
+    fn test_cache() {
+        let server = MockServer::new();
+        let mut resolver = Resolver::new(&[server.addr()]);
+        let mut sock = client();
+
+        resolver.query(&mut sock, 0, "a.test").unwrap();
+        let (query, from) = server.recv().unwrap();
+        server.answer(&query, from, Ipv4Addr::new(10, 0, 0, 1));
+        assert_eq!(read_n(&mut resolver, &mut sock, 1).len(), 1);
+
+        // Answered from the cache without hitting the network
+        let ip = Some(Ipv4Addr::new(10, 0, 0, 1).into());
+        assert_eq!(resolver.query(&mut sock, 1, "a.test").unwrap(), ip);
+        assert!(server.recv().is_none());
+
+        resolver.purge();
+        assert_eq!(resolver.query(&mut sock, 2, "a.test").unwrap(), None);
+        assert!(server.recv().is_some());
+    }
 
     #[test]
     fn test_google() {
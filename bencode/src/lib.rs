@@ -1,7 +1,7 @@
 use std::collections::BTreeMap;
 use std::error::Error;
 use std::io::{self, Cursor};
-use std::{cmp, fmt, str};
+use std::{cmp, fmt, mem, str};
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub enum BEncode {
@@ -193,11 +193,12 @@ pub fn decode<R: io::Read>(bytes: &mut R) -> Result<BEncode, BError> {
     do_decode(bytes, false)
 }
 
+enum Kind {
+    Dict(usize),
+    List(usize),
+}
+
 fn do_decode<R: io::Read>(bytes: &mut R, first: bool) -> Result<BEncode, BError> {
-    enum Kind {
-        Dict(usize),
-        List(usize),
-    }
     let mut cstack = vec![];
     let mut vstack = vec![];
     let mut buf = [0];
@@ -224,33 +225,7 @@ fn do_decode<R: io::Read>(bytes: &mut R, first: bool) -> Result<BEncode, BError>
                 cstack.push(Kind::Dict(vstack.len()));
             }
             Err(BError::EOF) => break,
-            Ok(b'e') => match cstack.pop() {
-                Some(Kind::List(i)) => {
-                    let mut l = Vec::with_capacity(vstack.len() - i);
-                    while vstack.len() > i {
-                        l.push(vstack.pop().unwrap());
-                    }
-                    l.reverse();
-                    vstack.push(BEncode::List(l));
-                }
-                Some(Kind::Dict(i)) => {
-                    let mut d = BTreeMap::new();
-                    if (vstack.len() - i) % 2 != 0 {
-                        return Err(BError::InvalidDict);
-                    }
-                    while vstack.len() > i {
-                        let val = vstack.pop().unwrap();
-                        match vstack.pop().and_then(BEncode::into_bytes) {
-                            Some(key) => {
-                                d.insert(key, val);
-                            }
-                            None => return Err(BError::InvalidDict),
-                        }
-                    }
-                    vstack.push(BEncode::Dict(d))
-                }
-                None => return Err(BError::InvalidChar(b'e')),
-            },
+            Ok(b'e') => close(&mut cstack, &mut vstack)?,
             Ok(d @ b'0'..=b'9') => {
                 if cstack.is_empty() && !vstack.is_empty() {
                     return Err(BError::EOF);
@@ -282,6 +257,147 @@ fn do_decode<R: io::Read>(bytes: &mut R, first: bool) -> Result<BEncode, BError>
     }
 }
 
+/// Ends the innermost list or dictionary, replacing its values on the stack with it.
+fn close(cstack: &mut Vec<Kind>, vstack: &mut Vec<BEncode>) -> Result<(), BError> {
+    match cstack.pop() {
+        Some(Kind::List(i)) => {
+            let l = vstack.split_off(i);
+            vstack.push(BEncode::List(l));
+        }
+        Some(Kind::Dict(i)) => {
+            let mut d = BTreeMap::new();
+            if !(vstack.len() - i).is_multiple_of(2) {
+                return Err(BError::InvalidDict);
+            }
+            while vstack.len() > i {
+                let val = vstack.pop().unwrap();
+                match vstack.pop().and_then(BEncode::into_bytes) {
+                    Some(key) => {
+                        d.insert(key, val);
+                    }
+                    None => return Err(BError::InvalidDict),
+                }
+            }
+            vstack.push(BEncode::Dict(d))
+        }
+        None => return Err(BError::InvalidChar(b'e')),
+    }
+    Ok(())
+}
+
+/// Outcome of feeding a chunk to a [`Decoder`].
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Decoded {
+    Done(BEncode),
+    NeedMore,
+}
+
+/// Incrementally decodes an object from chunks of data as they arrive, so the whole
+/// encoding never has to be buffered. Like [`decode_buf_first`], bytes after a complete
+/// object are ignored.
+#[derive(Default)]
+pub struct Decoder {
+    cstack: Vec<Kind>,
+    vstack: Vec<BEncode>,
+    partial: Partial,
+}
+
+/// A scalar value split across chunks
+#[derive(Default)]
+enum Partial {
+    #[default]
+    None,
+    Int(Vec<u8>),
+    StrLen(Vec<u8>),
+    Str {
+        data: Vec<u8>,
+        len: usize,
+    },
+}
+
+impl Decoder {
+    pub fn new() -> Decoder {
+        Decoder::default()
+    }
+
+    /// Decodes the next chunk, returning the object once it's complete. The decoder starts
+    /// over afterwards.
+    pub fn feed(&mut self, chunk: &[u8]) -> Result<Decoded, BError> {
+        let mut i = 0;
+        while i < chunk.len() {
+            let rest = &chunk[i..];
+            match &mut self.partial {
+                Partial::None => {
+                    i += 1;
+                    match rest[0] {
+                        b'i' => self.partial = Partial::Int(Vec::new()),
+                        b'l' => self.cstack.push(Kind::List(self.vstack.len())),
+                        b'd' => self.cstack.push(Kind::Dict(self.vstack.len())),
+                        b'e' => close(&mut self.cstack, &mut self.vstack)?,
+                        d @ b'0'..=b'9' => self.partial = Partial::StrLen(vec![d]),
+                        c => return Err(BError::InvalidChar(c)),
+                    }
+                }
+                Partial::Int(v) => {
+                    let Some(used) = read_scalar(v, rest, b'e') else {
+                        break;
+                    };
+                    i += used;
+                    let n = decode_int(mem::take(v))?;
+                    self.partial = Partial::None;
+                    self.vstack.push(BEncode::Int(n));
+                }
+                Partial::StrLen(v) => {
+                    let Some(used) = read_scalar(v, rest, b':') else {
+                        break;
+                    };
+                    i += used;
+                    let len = decode_int(mem::take(v))?;
+                    if len < 0 {
+                        return Err(BError::ParseInt);
+                    }
+                    let len = len as usize;
+                    self.partial = Partial::Str {
+                        data: Vec::with_capacity(cmp::min(len, MAX_ALLOC_LEN)),
+                        len,
+                    };
+                }
+                Partial::Str { data, len } => {
+                    let take = cmp::min(*len - data.len(), rest.len());
+                    data.extend_from_slice(&rest[..take]);
+                    i += take;
+                }
+            }
+            if let Partial::Str { data, len } = &mut self.partial {
+                if data.len() == *len {
+                    let data = mem::take(data);
+                    self.partial = Partial::None;
+                    self.vstack.push(BEncode::String(data));
+                }
+            }
+            if self.cstack.is_empty() && self.vstack.len() == 1 {
+                return Ok(Decoded::Done(self.vstack.pop().unwrap()));
+            }
+        }
+        Ok(Decoded::NeedMore)
+    }
+}
+
+/// Buffers the digits of a scalar up to its terminator, returning the number of bytes
+/// consumed if the terminator was found.
+fn read_scalar(v: &mut Vec<u8>, rest: &[u8], end: u8) -> Option<usize> {
+    match rest.iter().position(|&b| b == end) {
+        Some(pos) => {
+            v.extend_from_slice(&rest[..pos]);
+            Some(pos + 1)
+        }
+        None => {
+            v.extend_from_slice(rest);
+            None
+        }
+    }
+}
+
 fn next_byte<R: io::Read>(r: &mut R, buf: &mut [u8; 1]) -> Result<u8, BError> {
     let amnt = r.read(buf).map_err(|_| BError::IO)?;
     if amnt == 0 {
@@ -310,7 +426,7 @@ fn decode_int(v: Vec<u8>) -> Result<i64, BError> {
 
 #[cfg(test)]
 mod tests {
-    use super::{decode_buf, decode_buf_first, BEncode};
+    use super::{decode_buf, decode_buf_first, BEncode, BError, Decoded, Decoder};
     use std::collections::BTreeMap;
 
     #[test]
@@ -411,4 +527,54 @@ mod tests {
         let content = b"d2:\x80\x811:ae";
         decode_buf(content).unwrap();
     }
+
+    #[test]
+    fn test_decoder_bytewise() {
+        let data = b"d8:completei5e10:incompletei12e5:peersl5:abcdei-3eee";
+        let expected = decode_buf(data).unwrap();
+        let mut d = Decoder::new();
+        for b in &data[..data.len() - 1] {
+            assert_eq!(d.feed(&[*b]).unwrap(), Decoded::NeedMore);
+        }
+        assert_eq!(
+            d.feed(&data[data.len() - 1..]).unwrap(),
+            Decoded::Done(expected)
+        );
+    }
+
+    #[test]
+    fn test_decoder_chunks() {
+        let data = b"d4:infod6:lengthi1024e4:name3:foo6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+        let expected = decode_buf(data).unwrap();
+        for split in 1..data.len() {
+            let mut d = Decoder::new();
+            assert_eq!(d.feed(&data[..split]).unwrap(), Decoded::NeedMore);
+            assert_eq!(
+                d.feed(&data[split..]).unwrap(),
+                Decoded::Done(expected.clone())
+            );
+        }
+
+        // Anything past the first complete object is ignored
+        let mut d = Decoder::new();
+        assert_eq!(d.feed(b"i4").unwrap(), Decoded::NeedMore);
+        assert_eq!(d.feed(b"2ei3e").unwrap(), Decoded::Done(BEncode::Int(42)));
+        assert_eq!(
+            Decoder::new().feed(b"0:").unwrap(),
+            Decoded::Done(BEncode::String(vec![]))
+        );
+    }
+
+    #[test]
+    fn test_decoder_invalid() {
+        assert_eq!(Decoder::new().feed(b"x"), Err(BError::InvalidChar(b'x')));
+        assert_eq!(Decoder::new().feed(b"e"), Err(BError::InvalidChar(b'e')));
+        assert_eq!(Decoder::new().feed(b"di1ei2ee"), Err(BError::InvalidDict));
+        assert_eq!(Decoder::new().feed(b"d1:ae"), Err(BError::InvalidDict));
+        assert_eq!(Decoder::new().feed(b"i1x2e"), Err(BError::ParseInt));
+        assert_eq!(Decoder::new().feed(b"-1:a"), Err(BError::InvalidChar(b'-')));
+        let mut d = Decoder::new();
+        assert_eq!(d.feed(b"l1").unwrap(), Decoded::NeedMore);
+        assert_eq!(d.feed(b"a:"), Err(BError::ParseInt));
+    }
 }
@@ -8,6 +8,9 @@ pub const DHT_EXT: (usize, u8) = (7, 1);
 pub const EXT_PROTO: (usize, u8) = (5, 0x10);
 pub const UT_META_ID: u8 = 9;
 pub const UT_PEX_ID: u8 = 11;
+/// Largest length prefix encoded for messages other than pieces, whose length is
+/// bounded by the block size
+pub const MAX_MSG_LEN: u32 = 4 * 1024 * 1024;
 
 pub trait Bitfield: Clone + From<Vec<u8>> {
     fn bytes(&self) -> usize;
@@ -314,7 +317,7 @@ impl<BF: Bitfield, Buf: Buffer> Message<BF, Buf> {
                 buf.write_u32::<BigEndian>(piece)?;
             }
             Message::Bitfield(ref pf) => {
-                buf.write_u32::<BigEndian>(1 + pf.bytes() as u32)?;
+                buf.write_u32::<BigEndian>(length_prefix(1 + pf.bytes() as u64, MAX_MSG_LEN)?)?;
                 buf.write_u8(5)?;
                 for i in 0..pf.bytes() {
                     buf.write_u8(pf.byte_at(i))?;
@@ -337,7 +340,7 @@ impl<BF: Bitfield, Buf: Buffer> Message<BF, Buf> {
                 length,
                 ..
             } => {
-                buf.write_u32::<BigEndian>(9 + length)?;
+                buf.write_u32::<BigEndian>(length_prefix(9 + u64::from(length), u32::MAX)?)?;
                 buf.write_u8(7)?;
                 buf.write_u32::<BigEndian>(index)?;
                 buf.write_u32::<BigEndian>(begin)?;
@@ -354,7 +357,7 @@ impl<BF: Bitfield, Buf: Buffer> Message<BF, Buf> {
                 buf.write_u32::<BigEndian>(length)?;
             }
             Message::Extension { id, ref payload } => {
-                buf.write_u32::<BigEndian>(2 + payload.len() as u32)?;
+                buf.write_u32::<BigEndian>(length_prefix(2 + payload.len() as u64, MAX_MSG_LEN)?)?;
                 buf.write_u8(20)?;
                 buf.write_u8(id)?;
                 buf.write_all(payload)?;
@@ -364,6 +367,18 @@ impl<BF: Bitfield, Buf: Buffer> Message<BF, Buf> {
     }
 }
 
+/// Checks a message's length against `max` before it's written as a prefix, so it can't
+/// be truncated into one describing some other length.
+fn length_prefix(len: u64, max: u32) -> io::Result<u32> {
+    if len > u64::from(max) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("message length {} exceeds {}", len, max),
+        ));
+    }
+    Ok(len as u32)
+}
+
 #[cfg(test)]
 mod tests {
     #[test]
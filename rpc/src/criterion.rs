@@ -40,6 +40,8 @@ pub enum Operation {
     Has,
     #[serde(rename = "!has")]
     NotHas,
+    #[serde(rename = "~=")]
+    Matches,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
@@ -73,16 +75,53 @@ pub trait Queryable {
     fn field(&self, field: &str) -> Option<Field<'_>>;
 }
 
+/// A criterion prepared for evaluation. The pattern of a like, ilike, has or ~= test is
+/// compiled once here, rather than for every resource it's matched against.
+#[derive(Clone, Debug)]
+pub struct CompiledCriterion {
+    pub criterion: Criterion,
+    pattern: Option<Regex>,
+}
+
 impl Criterion {
+    /// Prepares the criterion for evaluation, failing if a regular expression in it is
+    /// invalid.
+    pub fn compile(self) -> Result<CompiledCriterion, String> {
+        let pattern = match (self.op, &self.value) {
+            (Operation::Matches, Value::S(pat)) => Some(pat.clone()),
+            (Operation::Matches, _) => {
+                return Err(format!(
+                    "regex match on field {} requires a string value",
+                    self.field
+                ))
+            }
+            (Operation::Like, Value::S(pat)) => Some(like_regex(pat)),
+            (Operation::ILike | Operation::Has | Operation::NotHas, Value::S(pat)) => {
+                Some(like_regex(&pat.to_lowercase()))
+            }
+            _ => None,
+        };
+        let pattern = pattern
+            .map(|p| Regex::new(&p))
+            .transpose()
+            .map_err(|e| format!("invalid regex for field {}: {}", self.field, e))?;
+        Ok(CompiledCriterion {
+            criterion: self,
+            pattern,
+        })
+    }
+}
+
+impl CompiledCriterion {
     pub fn matches<Q: Queryable>(&self, q: &Q) -> bool {
-        if let Some(f) = q.field(&self.field) {
-            self.match_field(&f, self.op, &self.value)
+        let c = &self.criterion;
+        if let Some(f) = q.field(&c.field) {
+            self.match_field(&f, c.op, &c.value)
         } else {
             false
         }
     }
 
-    #[allow(clippy::only_used_in_recursion)]
     fn match_field(&self, field: &Field<'_>, op: Operation, value: &Value) -> bool {
         match (field, value) {
             (Field::V(items), Value::V(vals)) => match op {
@@ -123,11 +162,11 @@ impl Criterion {
                 Operation::Neq => f != v,
                 _ => false,
             },
-            (&Field::S(f), Value::S(v)) => match op {
-                Operation::Eq => f == v,
-                Operation::Neq => f != v,
-                Operation::Like => match_like(v, f),
-                Operation::ILike => match_ilike(v, f),
+            (&Field::S(f), Value::S(v)) => match (op, &self.pattern) {
+                (Operation::Eq, _) => f == v,
+                (Operation::Neq, _) => f != v,
+                (Operation::Like | Operation::Matches, Some(re)) => re.is_match(f),
+                (Operation::ILike, Some(re)) => re.is_match(&f.to_lowercase()),
                 _ => false,
             },
             (&Field::N(f), &Value::N(v)) => match op {
@@ -182,25 +221,57 @@ impl Criterion {
     }
 }
 
-fn match_like(pat: &str, s: &str) -> bool {
-    let mut p = regex::escape(pat);
-    p = p.replace("%", ".*");
-    p = p.replace("_", ".");
-    if let Ok(re) = Regex::new(&p) {
-        re.is_match(s)
-    } else {
-        false
+/// Translates a LIKE pattern to a regex, where % stands for any run of characters, _ for
+/// any single one, and a backslash makes the character following it literal.
+fn like_regex(pat: &str) -> String {
+    let mut p = String::new();
+    let mut chars = pat.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '%' => p.push_str(".*"),
+            '_' => p.push('.'),
+            '\\' => {
+                let lit = chars.next().unwrap_or('\\');
+                p.push_str(&regex::escape(lit.encode_utf8(&mut [0; 4])));
+            }
+            c => p.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
     }
-}
-
-fn match_ilike(pat: &str, s: &str) -> bool {
-    match_like(&pat.to_lowercase(), &s.to_lowercase())
+    p
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    struct Str<'a>(&'a str);
+    impl Queryable for Str<'_> {
+        fn field(&self, _: &str) -> Option<Field<'_>> {
+            Some(Field::S(self.0))
+        }
+    }
+
+    fn match_op(op: Operation, pat: &str, s: &str) -> bool {
+        let c = Criterion {
+            field: "s".to_owned(),
+            op,
+            value: Value::S(pat.to_owned()),
+        };
+        c.compile().unwrap().matches(&Str(s))
+    }
+
+    fn match_like(pat: &str, s: &str) -> bool {
+        match_op(Operation::Like, pat, s)
+    }
+
+    fn match_ilike(pat: &str, s: &str) -> bool {
+        match_op(Operation::ILike, pat, s)
+    }
+
+    fn match_regex(pat: &str, s: &str) -> bool {
+        match_op(Operation::Matches, pat, s)
+    }
+
     #[test]
     fn test_like() {
         assert!(match_like("hello", "hello"));
@@ -209,6 +280,47 @@ mod tests {
         assert!(!match_like("% world", "helloworld"));
         assert!(match_like("%", "foo bar"));
         assert!(match_like("fo%", "foo"));
+        assert!(match_like("f_o", "foo"));
+        assert!(match_like("a.b", "a.b"));
+        assert!(!match_like("a.b", "axb"));
+    }
+
+    #[test]
+    fn test_like_escape() {
+        assert!(match_like(r"100\%", "100% done"));
+        assert!(!match_like(r"100\%", "1000"));
+        assert!(match_like(r"a\_b", "a_b"));
+        assert!(!match_like(r"a\_b", "axb"));
+        assert!(match_like(r"a\\b", r"a\b"));
+        assert!(match_like(r"a\", r"a\"));
+        assert!(match_ilike(r"%\%%", "50% OFF"));
+    }
+
+    #[test]
+    fn test_regex() {
+        assert!(match_regex(r"S0[1-3]E\d+", "Show.S02E10.mkv"));
+        assert!(!match_regex(r"S0[1-3]E\d+", "Show.S04E10.mkv"));
+        assert!(!match_regex(r"^foo$", "foobar"));
+    }
+
+    #[test]
+    fn test_compile() {
+        let c = |op, value| Criterion {
+            field: "name".to_owned(),
+            op,
+            value,
+        };
+        assert!(c(Operation::Matches, Value::S("a+b".to_owned()))
+            .compile()
+            .is_ok());
+        // Invalid patterns are rejected up front rather than never matching
+        assert!(c(Operation::Matches, Value::S("a(".to_owned()))
+            .compile()
+            .is_err());
+        assert!(c(Operation::Matches, Value::N(1)).compile().is_err());
+        assert!(c(Operation::ILike, Value::S("a(".to_owned()))
+            .compile()
+            .is_ok());
     }
 
     struct Q;
@@ -219,6 +331,7 @@ mod tests {
                 "n" => Some(Field::N(1)),
                 "ob" => Some(Field::B(true)),
                 "on" => Some(Field::E(None)),
+                "v" => Some(Field::V(vec![Field::S("Foo"), Field::S("bar")])),
                 _ => None,
             }
         }
@@ -234,7 +347,7 @@ mod tests {
 
         let q = Q;
         assert_eq!(q.field("asdf"), None);
-        assert!(!c.matches(&q));
+        assert!(!c.compile().unwrap().matches(&q));
     }
 
     #[test]
@@ -246,7 +359,7 @@ mod tests {
         };
 
         let q = Q;
-        assert!(c.matches(&q));
+        assert!(c.compile().unwrap().matches(&q));
     }
 
     #[test]
@@ -258,7 +371,7 @@ mod tests {
         };
 
         let q = Q;
-        assert!(c.matches(&q));
+        assert!(c.compile().unwrap().matches(&q));
     }
 
     #[test]
@@ -270,7 +383,7 @@ mod tests {
         };
 
         let q = Q;
-        assert!(c.matches(&q));
+        assert!(c.compile().unwrap().matches(&q));
     }
 
     #[test]
@@ -282,7 +395,7 @@ mod tests {
         };
 
         let q = Q;
-        assert!(c.matches(&q));
+        assert!(c.compile().unwrap().matches(&q));
     }
 
     #[test]
@@ -294,6 +407,27 @@ mod tests {
         };
 
         let q = Q;
-        assert!(c.matches(&q));
+        assert!(c.compile().unwrap().matches(&q));
+    }
+
+    #[test]
+    fn test_match_vec() {
+        let c = |op, value: &str| {
+            Criterion {
+                field: "v".to_owned(),
+                op,
+                value: Value::S(value.to_owned()),
+            }
+            .compile()
+            .unwrap()
+            .matches(&Q)
+        };
+        assert!(c(Operation::Has, "foo"));
+        assert!(c(Operation::Has, "B_R"));
+        assert!(!c(Operation::NotHas, "%o%"));
+        assert!(c(Operation::NotHas, "baz"));
+        assert!(c(Operation::Like, "ba%"));
+        assert!(!c(Operation::Like, "foo"));
+        assert!(c(Operation::Matches, "^F"));
     }
 }
@@ -6,4 +6,6 @@ pub mod message;
 pub mod resource;
 
 pub const MAJOR_VERSION: u16 = 0;
-pub const MINOR_VERSION: u16 = 2;
+pub const MINOR_VERSION: u16 = 36;
+/// Lowest minor version a client must advertise to be sent piece field deltas
+pub const PIECES_DELTA_MINOR: u16 = 20;
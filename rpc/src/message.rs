@@ -1,9 +1,12 @@
 use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::IpAddr;
 
 use chrono::{DateTime, Utc};
 
 use super::criterion::Criterion;
-use super::resource::{CResourceUpdate, ResourceKind, SResourceUpdate};
+use super::resource::{CResourceUpdate, Resource, ResourceKind, SResourceUpdate, Strategy};
 
 #[derive(Copy, Clone, Debug, Serialize, Deserialize)]
 pub struct Version {
@@ -25,6 +28,10 @@ pub enum CMessage {
     Subscribe {
         serial: u64,
         ids: Vec<String>,
+        /// Also receive block level progress of in-flight pieces for
+        /// any torrents in `ids`
+        #[serde(default)]
+        block_progress: bool,
     },
     Unsubscribe {
         serial: u64,
@@ -51,6 +58,21 @@ pub enum CMessage {
         serial: u64,
         filter_serial: u64,
     },
+    /// Fetches a page of the resources matching some criteria, ordered by ID
+    QueryResources {
+        serial: u64,
+        #[serde(default)]
+        kind: ResourceKind,
+        #[serde(default)]
+        criteria: Vec<Criterion>,
+        #[serde(default)]
+        offset: usize,
+        #[serde(default)]
+        limit: Option<usize>,
+        /// Keys to keep in each resource, besides `id` and `type`
+        #[serde(default)]
+        fields: Option<Vec<String>>,
+    },
 
     // Special messages
     UploadTorrent {
@@ -61,6 +83,11 @@ pub enum CMessage {
         start: bool,
         #[serde(default = "default_false")]
         import: bool,
+        /// Priorities by file index, set before any pieces are requested
+        #[serde(default)]
+        file_priorities: BTreeMap<usize, u8>,
+        #[serde(default)]
+        strategy: Option<Strategy>,
     },
     UploadMagnet {
         serial: u64,
@@ -68,12 +95,36 @@ pub enum CMessage {
         path: Option<String>,
         #[serde(default = "default_true")]
         start: bool,
+        /// Applied once the torrent's metadata has been fetched
+        #[serde(default)]
+        file_priorities: BTreeMap<usize, u8>,
+        #[serde(default)]
+        strategy: Option<Strategy>,
     },
     UploadFiles {
         serial: u64,
         size: u64,
         path: String,
     },
+    /// Adds a torrent given inline, answered with TORRENT_ADDED or ADD_TORRENT_FAILED
+    AddTorrent {
+        serial: u64,
+        /// Base64 of the bencoded .torrent file
+        #[serde(default)]
+        torrent: Option<String>,
+        /// Magnet link, in place of `torrent`
+        #[serde(default)]
+        magnet: Option<String>,
+        path: Option<String>,
+        #[serde(default = "default_true")]
+        start: bool,
+        #[serde(default = "default_false")]
+        import: bool,
+        #[serde(default)]
+        file_priorities: BTreeMap<usize, u8>,
+        #[serde(default)]
+        strategy: Option<Strategy>,
+    },
     PauseTorrent {
         serial: u64,
         id: String,
@@ -103,6 +154,49 @@ pub enum CMessage {
     PurgeDns {
         serial: u64,
     },
+    /// Announces to every tracker of every active torrent, at a limited pace
+    ReannounceAll {
+        serial: u64,
+    },
+    AnalyzePath {
+        serial: u64,
+        path: String,
+    },
+    GetMetainfo {
+        serial: u64,
+        id: String,
+    },
+    GetDiskStats {
+        serial: u64,
+    },
+    /// Fetches the work log of a torrent
+    GetHistory {
+        serial: u64,
+        id: String,
+    },
+    GetHealth {
+        serial: u64,
+    },
+    UpdateIpFilter {
+        serial: u64,
+        action: IpFilterAction,
+        /// CIDR prefixes, addresses or `start - end` address ranges
+        #[serde(default)]
+        ranges: Vec<String>,
+        /// Server side CIDR or DAT file to read ranges from
+        #[serde(default)]
+        path: Option<String>,
+    },
+}
+
+/// How the ranges of an UPDATE_IP_FILTER request are applied to the blocklist.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IpFilterAction {
+    Add,
+    Remove,
+    /// Drops every blocked range before adding the given ones
+    Replace,
 }
 
 /// Server -> client message
@@ -124,6 +218,12 @@ pub enum SMessage<'a> {
         serial: Option<u64>,
         resources: Vec<SResourceUpdate<'a>>,
     },
+    ResourcesQueried {
+        serial: u64,
+        /// Number of resources matching the criteria, across every page
+        total_matched: usize,
+        resources: Vec<serde_json::Value>,
+    },
 
     // Special messages
     RpcVersion(Version),
@@ -137,6 +237,22 @@ pub enum SMessage<'a> {
         serial: u64,
         id: String,
     },
+    PathAnalysis(PathAnalysis),
+    Metainfo(Metainfo),
+    DiskStats(DiskStats),
+    History(History),
+    Health(Health),
+    IpFilterUpdated {
+        serial: u64,
+        /// Number of blocked prefixes after the update
+        blocked: usize,
+    },
+    TorrentAdded {
+        serial: u64,
+        /// Hex encoded infohash, which is also the torrent's id
+        infohash: String,
+        resource: Resource,
+    },
 
     // Error messages
     UnknownResource(Error),
@@ -145,13 +261,292 @@ pub enum SMessage<'a> {
     InvalidRequest(Error),
     PermissionDenied(Error),
     TransferFailed(Error),
+    AddTorrentFailed {
+        serial: u64,
+        error: AddError,
+        reason: String,
+    },
+}
+
+/// Why an ADD_TORRENT request failed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AddError {
+    /// The torrent has already been added
+    Duplicate,
+    /// The torrent isn't valid base64 encoded bencode
+    InvalidBencode,
+    /// The torrent has no info dictionary
+    NoInfo,
+    /// The info dictionary or magnet link is malformed
+    InvalidMetainfo,
+    /// The torrent's data can't be stored at its path, e.g. a file conflicts with a
+    /// directory already there
+    Storage,
+}
+
+/// Summary of a path's contents used to pick a piece length when creating a torrent.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PathAnalysis {
+    pub serial: u64,
+    pub total_size: u64,
+    pub file_count: u64,
+    /// Recommended piece length, in bytes
+    pub piece_length: u64,
+    pub candidates: Vec<PieceCandidate>,
+    /// False if the scan was cut short, in which case sizes are a lower bound
+    pub complete: bool,
+    /// Directories under the path which couldn't be read and were skipped
+    #[serde(default)]
+    pub unreadable: Vec<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PieceCandidate {
+    pub piece_length: u64,
+    pub pieces: u64,
+}
+
+/// Metainfo of a torrent, sufficient to rebuild its .torrent file.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Metainfo {
+    pub serial: u64,
+    /// Hex encoded infohash
+    pub hash: String,
+    pub name: String,
+    /// Name as it appears in the info dictionary, base64 encoded
+    pub name_raw: Option<String>,
+    pub piece_length: u32,
+    /// Concatenated SHA-1 piece hashes, base64 encoded
+    pub pieces: String,
+    /// Files in torrent order, multi-file paths begin with the torrent name
+    pub files: Vec<MetainfoFile>,
+    pub private: bool,
+    pub announce: Option<String>,
+    /// Tracker urls grouped by tier
+    pub announce_list: Vec<Vec<String>>,
+    pub creator: Option<String>,
+    pub comment: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MetainfoFile {
+    pub path: Vec<String>,
+    pub length: u64,
+}
+
+/// Significant actions taken on a torrent, oldest first.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct History {
+    pub serial: u64,
+    pub id: String,
+    pub entries: Vec<HistoryEntry>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HistoryEntry {
+    pub time: DateTime<Utc>,
+    /// `system` for actions synapse took by itself, otherwise the address of the RPC
+    /// client, preceded by `user@` if it authenticated with a username
+    pub by: String,
+    pub action: HistoryAction,
+    /// The new priority, path or tracker url, depending on the action
+    pub detail: Option<String>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HistoryAction {
+    Pause,
+    Resume,
+    Priority,
+    FilePriority,
+    Move,
+    AddTracker,
+    EditTracker,
+    RemoveTracker,
+    Remove,
+}
+
+/// Disk I/O counters, totalled since the disk worker started and
+/// over roughly the last minute.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DiskStats {
+    pub serial: u64,
+    pub total: DiskCounters,
+    pub last_minute: DiskCounters,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DiskCounters {
+    pub reads: u64,
+    pub writes: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    /// File accesses served by an already open file
+    pub cache_hits: u64,
+    /// File accesses which had to open the file
+    pub cache_misses: u64,
+    pub fallocate_ok: u64,
+    pub fallocate_failed: u64,
+    pub fsyncs: u64,
+    /// Blocks served from pieces already read into memory
+    #[serde(default)]
+    pub piece_cache_hits: u64,
+    /// Blocks whose piece had to be read from disk
+    #[serde(default)]
+    pub piece_cache_misses: u64,
+}
+
+impl DiskCounters {
+    /// Counts accumulated since `earlier` was taken.
+    pub fn since(&self, earlier: &DiskCounters) -> DiskCounters {
+        DiskCounters {
+            reads: self.reads.saturating_sub(earlier.reads),
+            writes: self.writes.saturating_sub(earlier.writes),
+            bytes_read: self.bytes_read.saturating_sub(earlier.bytes_read),
+            bytes_written: self.bytes_written.saturating_sub(earlier.bytes_written),
+            cache_hits: self.cache_hits.saturating_sub(earlier.cache_hits),
+            cache_misses: self.cache_misses.saturating_sub(earlier.cache_misses),
+            fallocate_ok: self.fallocate_ok.saturating_sub(earlier.fallocate_ok),
+            fallocate_failed: self
+                .fallocate_failed
+                .saturating_sub(earlier.fallocate_failed),
+            fsyncs: self.fsyncs.saturating_sub(earlier.fsyncs),
+            piece_cache_hits: self
+                .piece_cache_hits
+                .saturating_sub(earlier.piece_cache_hits),
+            piece_cache_misses: self
+                .piece_cache_misses
+                .saturating_sub(earlier.piece_cache_misses),
+        }
+    }
+}
+
+/// State of the RPC server's connection handling.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Health {
+    pub serial: u64,
+    /// Connected websocket clients
+    pub clients: usize,
+    /// Addresses banned after repeatedly failing auth
+    pub bans: Vec<Ban>,
+    /// Milliseconds between control loop ticks, stretched while idle
+    #[serde(default)]
+    pub tick_interval: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Ban {
+    pub ip: IpAddr,
+    pub expires: DateTime<Utc>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct Error {
     pub serial: Option<u64>,
+    #[serde(default)]
+    pub code: ErrorCode,
     pub reason: String,
+    /// The offending id or value, when there's one
+    #[serde(default)]
+    pub detail: Option<String>,
+}
+
+/// Machine readable cause of an error message, finer grained than its type.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// No resource has the given id
+    UnknownResource,
+    /// The resource is of the wrong kind for the request
+    InvalidResource,
+    /// The message isn't valid JSON or doesn't match any message type
+    InvalidSchema,
+    InvalidCriterion,
+    /// A field of the request has a bad value
+    InvalidArgument,
+    /// The torrent has already been added
+    Duplicate,
+    /// The request can't be served yet, e.g. a magnet's metainfo isn't known
+    NotReady,
+    TransferFailed,
+    PermissionDenied,
+    /// The server failed to carry out a valid request
+    ServerError,
+    /// Sent by servers predating error codes, or one this client doesn't know
+    #[default]
+    #[serde(other)]
+    Unknown,
+}
+
+impl Error {
+    pub fn new<R: Into<String>>(serial: Option<u64>, code: ErrorCode, reason: R) -> Error {
+        Error {
+            serial,
+            code,
+            reason: reason.into(),
+            detail: None,
+        }
+    }
+
+    pub fn with_detail<D: Into<String>>(mut self, detail: D) -> Error {
+        self.detail = Some(detail.into());
+        self
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} [{}]", self.reason, self.code)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let code = serde_json::to_value(self).unwrap();
+        f.write_str(code.as_str().unwrap())
+    }
+}
+
+impl From<AddError> for ErrorCode {
+    fn from(error: AddError) -> ErrorCode {
+        match error {
+            AddError::Duplicate => ErrorCode::Duplicate,
+            AddError::Storage => ErrorCode::ServerError,
+            AddError::InvalidBencode | AddError::NoInfo | AddError::InvalidMetainfo => {
+                ErrorCode::InvalidArgument
+            }
+        }
+    }
+}
+
+impl<'a> SMessage<'a> {
+    /// The error carried by an error message.
+    pub fn error(&self) -> Option<&Error> {
+        match self {
+            SMessage::UnknownResource(e)
+            | SMessage::InvalidResource(e)
+            | SMessage::InvalidSchema(e)
+            | SMessage::InvalidRequest(e)
+            | SMessage::PermissionDenied(e)
+            | SMessage::TransferFailed(e) => Some(e),
+            _ => None,
+        }
+    }
 }
 
 impl Version {
@@ -207,4 +602,173 @@ mod tests {
             unreachable!();
         }
     }
+
+    #[test]
+    fn test_announce_ip_repr() {
+        let update = |ip| {
+            let data = format!(
+                r#"{{"type": "UPDATE_RESOURCE", "serial": 0, "resource": {{"id": "t", "announce_ip": {ip}}}}}"#
+            );
+            match serde_json::from_str(&data) {
+                Ok(CMessage::UpdateResource { resource, .. }) => Ok(resource.announce_ip),
+                Ok(m) => panic!("unexpected message {:?}", m),
+                Err(e) => Err(e),
+            }
+        };
+        assert_eq!(
+            update(r#""203.0.113.7""#).unwrap(),
+            Some(Some("203.0.113.7".parse().unwrap()))
+        );
+        assert_eq!(update("null").unwrap(), Some(None));
+        assert!(update(r#""example.com""#).is_err());
+
+        let u = resource::SResourceUpdate::TorrentAnnounceIp {
+            id: "t".to_owned(),
+            kind: resource::ResourceKind::Torrent,
+            announce_ip: None,
+        };
+        let data = serde_json::to_string(&u).unwrap();
+        assert_eq!(
+            serde_json::from_str::<resource::SResourceUpdate>(&data).unwrap(),
+            u
+        );
+    }
+
+    #[test]
+    fn test_allocation_repr() {
+        let update = |a| {
+            let data = format!(
+                r#"{{"type": "UPDATE_RESOURCE", "serial": 0, "resource": {{"id": "t", "allocation": {a}}}}}"#
+            );
+            match serde_json::from_str(&data) {
+                Ok(CMessage::UpdateResource { resource, .. }) => Ok(resource.allocation),
+                Ok(m) => panic!("unexpected message {:?}", m),
+                Err(e) => Err(e),
+            }
+        };
+        assert_eq!(
+            update(r#""skip_unwanted""#).unwrap(),
+            Some(Some(resource::Allocation::SkipUnwanted))
+        );
+        assert_eq!(update("null").unwrap(), Some(None));
+        assert!(update(r#""dense""#).is_err());
+        assert!(update("1").is_err());
+    }
+
+    #[test]
+    fn test_max_peers_repr() {
+        let update = |n| {
+            let data = format!(
+                r#"{{"type": "UPDATE_RESOURCE", "serial": 0, "resource": {{"id": "t", "max_peers": {n}}}}}"#
+            );
+            match serde_json::from_str(&data) {
+                Ok(CMessage::UpdateResource { resource, .. }) => Ok(resource.max_peers),
+                Ok(m) => panic!("unexpected message {:?}", m),
+                Err(e) => Err(e),
+            }
+        };
+        assert_eq!(update("20").unwrap(), Some(Some(20)));
+        assert_eq!(update("null").unwrap(), Some(None));
+        assert!(update("-1").is_err());
+        assert!(update("70000").is_err());
+
+        let u = resource::SResourceUpdate::TorrentMaxPeers {
+            id: "t".to_owned(),
+            kind: resource::ResourceKind::Torrent,
+            max_peers: 20,
+        };
+        let data = serde_json::to_string(&u).unwrap();
+        assert_eq!(
+            serde_json::from_str::<resource::SResourceUpdate>(&data).unwrap(),
+            u
+        );
+    }
+
+    #[test]
+    fn test_tracker_status_repr() {
+        // Updates and resources from servers predating the announce schedule still parse
+        let data = r#"{"id": "trk", "type": "tracker", "last_report": "2024-01-01T00:00:00Z",
+            "error": "timed out", "retry_at": null}"#;
+        match serde_json::from_str(data).unwrap() {
+            resource::SResourceUpdate::TrackerStatus {
+                error: Some(_),
+                seeders: None,
+                leechers: None,
+                last_interval: None,
+                ..
+            } => {}
+            u => panic!("unexpected update {:?}", u),
+        }
+        let data = r#"{"id": "trk", "type": "tracker", "torrent_id": "t", "tier": 0,
+            "url": "http://tracker.example/announce", "last_report": "2024-01-01T00:00:00Z",
+            "error": null, "user_data": null}"#;
+        match serde_json::from_str(data).unwrap() {
+            resource::Resource::Tracker(t) => {
+                assert_eq!(t.seeders, None);
+                assert_eq!(t.last_interval, None);
+            }
+            r => panic!("unexpected resource {:?}", r),
+        }
+
+        let u = resource::SResourceUpdate::TrackerStatus {
+            id: "trk".to_owned(),
+            kind: resource::ResourceKind::Tracker,
+            last_report: "2024-01-01T00:00:00Z".parse().unwrap(),
+            error: None,
+            retry_at: None,
+            next_announce: "2024-01-01T00:30:00Z".parse().unwrap(),
+            seeders: Some(12),
+            leechers: Some(3),
+            last_interval: Some(1800),
+        };
+        let data = serde_json::to_string(&u).unwrap();
+        assert_eq!(
+            serde_json::from_str::<resource::SResourceUpdate>(&data).unwrap(),
+            u
+        );
+        let mut t = resource::Tracker::default();
+        t.update(u);
+        assert_eq!(t.seeders, Some(12));
+        assert_eq!(
+            t.next_announce,
+            "2024-01-01T00:30:00Z".parse::<DateTime<Utc>>().unwrap()
+        );
+        let r = resource::Resource::Tracker(t);
+        let data = serde_json::to_string(&r).unwrap();
+        assert_eq!(
+            serde_json::from_str::<resource::Resource>(&data).unwrap(),
+            r
+        );
+    }
+
+    #[test]
+    fn test_query_repr() {
+        let data = r#"{"type": "QUERY_RESOURCES", "serial": 1, "kind": "piece", "limit": 10}"#;
+        match serde_json::from_str(data).unwrap() {
+            CMessage::QueryResources {
+                serial: 1,
+                kind: resource::ResourceKind::Piece,
+                criteria,
+                offset: 0,
+                limit: Some(10),
+                fields: None,
+            } => assert!(criteria.is_empty()),
+            m => panic!("unexpected message {:?}", m),
+        }
+
+        let m = SMessage::ResourcesQueried {
+            serial: 1,
+            total_matched: 2,
+            resources: vec![serde_json::json!({"id": "a", "type": "piece"})],
+        };
+        let data = serde_json::to_string(&m).unwrap();
+        match serde_json::from_str(&data).unwrap() {
+            SMessage::ResourcesQueried {
+                total_matched: 2,
+                resources,
+                ..
+            } => assert_eq!(resources[0]["id"], "a"),
+            m => panic!("unexpected message {:?}", m),
+        }
+    }
 }
@@ -1,7 +1,9 @@
 use std::borrow::Cow;
 use std::fmt;
 use std::mem;
+use std::net::IpAddr;
 
+use base64::prelude::{Engine, BASE64_STANDARD};
 use chrono::prelude::{DateTime, Utc};
 use serde;
 use serde_json as json;
@@ -87,6 +89,40 @@ pub enum SResourceUpdate<'a> {
         kind: ResourceKind,
         download_token: String,
     },
+    ServerPortMapping {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        external_ip: Option<String>,
+        external_port: Option<u16>,
+        external_dht_port: Option<u16>,
+        port_mapping_expires: Option<DateTime<Utc>>,
+    },
+    ServerReannounce {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        reannounce_queued: Option<u64>,
+        reannounce_done: Option<u64>,
+        reannounce_failed: Option<u64>,
+        reannounce_deferred: Option<u64>,
+    },
+    ServerQuota {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        quota_used: u64,
+        quota_threshold: u8,
+        quota_reset: Option<DateTime<Utc>>,
+        quota_days_left: Option<u32>,
+    },
+    ServerPieceCache {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        piece_cache_hits: u64,
+        piece_cache_misses: u64,
+    },
 
     TorrentStatus {
         id: String,
@@ -103,7 +139,13 @@ pub enum SResourceUpdate<'a> {
         rate_down: u64,
         transferred_up: u64,
         transferred_down: u64,
+        #[serde(default)]
+        ses_transferred_up: u64,
+        #[serde(default)]
+        ses_transferred_down: u64,
         progress: f32,
+        #[serde(default)]
+        endgame: bool,
     },
     TorrentPeers {
         id: String,
@@ -112,6 +154,12 @@ pub enum SResourceUpdate<'a> {
         peers: u16,
         availability: f32,
     },
+    TorrentPartialSeed {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        partial_seed: bool,
+    },
     TorrentPicker {
         id: String,
         #[serde(rename = "type")]
@@ -136,6 +184,54 @@ pub enum SResourceUpdate<'a> {
         kind: ResourceKind,
         piece_field: String,
     },
+    /// Pieces completed since the last piece field update, sent to clients which
+    /// advertise a minor version of at least `PIECES_DELTA_MINOR`
+    TorrentPiecesDelta {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        pieces_set: Vec<u64>,
+    },
+    TorrentAnnounceIp {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        announce_ip: Option<String>,
+    },
+    TorrentMaxPeers {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        max_peers: u16,
+    },
+    TorrentVerifyOnRead {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        verify_on_read: bool,
+    },
+    TorrentAllocation {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        allocation: Allocation,
+    },
+    TorrentFileOrder {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        file_order: Vec<String>,
+    },
+    /// Transient update, only sent to clients which subscribed with
+    /// `block_progress` set.
+    TorrentBlockProgress {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        piece: u32,
+        blocks_done: u32,
+        blocks_total: u32,
+    },
 
     TrackerStatus {
         id: String,
@@ -143,6 +239,28 @@ pub enum SResourceUpdate<'a> {
         kind: ResourceKind,
         last_report: DateTime<Utc>,
         error: Option<String>,
+        #[serde(default)]
+        retry_at: Option<DateTime<Utc>>,
+        #[serde(default = "Utc::now")]
+        next_announce: DateTime<Utc>,
+        #[serde(default)]
+        seeders: Option<u32>,
+        #[serde(default)]
+        leechers: Option<u32>,
+        #[serde(default)]
+        last_interval: Option<u32>,
+    },
+    TrackerHeaders {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        headers: Vec<String>,
+    },
+    TrackerUrl {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        url: Url,
     },
 
     FilePriority {
@@ -157,6 +275,12 @@ pub enum SResourceUpdate<'a> {
         kind: ResourceKind,
         progress: f32,
     },
+    FileMoveStatus {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        move_status: MoveStatus,
+    },
 
     PieceAvailable {
         id: String,
@@ -171,7 +295,15 @@ pub enum SResourceUpdate<'a> {
         downloaded: bool,
     },
 
-    PeerAvailability {
+    PeerQueue {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        queue_depth: u16,
+    },
+
+    /// Availability of a peer or file
+    Availability {
         id: String,
         #[serde(rename = "type")]
         kind: ResourceKind,
@@ -200,6 +332,31 @@ pub struct CResourceUpdate {
     #[serde(deserialize_with = "deserialize_throttle")]
     #[serde(default)]
     pub throttle_down: Option<Option<i64>>,
+    /// Keep server throttle changes in place across scheduler boundaries
+    pub throttle_permanent: Option<bool>,
+    /// Tracker announce headers as `Name: value` lines, an empty value removes the header
+    pub headers: Option<Vec<String>>,
+    /// New announce url of a tracker, which keeps its id
+    pub url: Option<Url>,
+    /// Address reported to trackers, null reverts to the configured one
+    #[serde(deserialize_with = "deserialize_announce_ip")]
+    #[serde(default)]
+    pub announce_ip: Option<Option<IpAddr>>,
+    /// Peer connection limit of a torrent, null reverts to the configured one
+    #[serde(deserialize_with = "deserialize_max_peers")]
+    #[serde(default)]
+    pub max_peers: Option<Option<u16>>,
+    /// Whether pieces are verified before being uploaded, null reverts to the configured
+    /// setting
+    #[serde(deserialize_with = "deserialize_verify_on_read")]
+    #[serde(default)]
+    pub verify_on_read: Option<Option<bool>>,
+    /// How space is reserved for the torrent's files, null reverts to the configured policy
+    #[serde(deserialize_with = "deserialize_allocation")]
+    #[serde(default)]
+    pub allocation: Option<Option<Allocation>>,
+    /// Ids of files to download to completion one after the other, before the rest
+    pub file_order: Option<Vec<String>>,
     pub user_data: Option<json::Value>,
 }
 
@@ -218,9 +375,58 @@ pub struct Server {
     pub ses_transferred_down: u64,
     pub free_space: u64,
     pub started: DateTime<Utc>,
+    /// Address and ports forwarded to us by the gateway, if port mapping succeeded
+    #[serde(default)]
+    pub external_ip: Option<String>,
+    #[serde(default)]
+    pub external_port: Option<u16>,
+    #[serde(default)]
+    pub external_dht_port: Option<u16>,
+    #[serde(default)]
+    pub port_mapping_expires: Option<DateTime<Utc>>,
+    /// Progress of a REANNOUNCE_ALL, null unless one is running
+    #[serde(default)]
+    pub reannounce_queued: Option<u64>,
+    #[serde(default)]
+    pub reannounce_done: Option<u64>,
+    #[serde(default)]
+    pub reannounce_failed: Option<u64>,
+    #[serde(default)]
+    pub reannounce_deferred: Option<u64>,
+    /// Transfer allowed per quota period in bytes, null if there's no quota
+    #[serde(default)]
+    pub quota: Option<u64>,
+    #[serde(default)]
+    pub quota_used: Option<u64>,
+    /// Highest warning threshold crossed this period, as a percentage
+    #[serde(default)]
+    pub quota_threshold: Option<u8>,
+    #[serde(default)]
+    pub quota_reset: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub quota_days_left: Option<u32>,
+    /// Session entries passed over at startup because their torrent was already loaded
+    #[serde(default)]
+    pub duplicate_sessions: Vec<DuplicateSession>,
+    /// Blocks served from the disk piece cache, and those which had to be read from disk
+    #[serde(default)]
+    pub piece_cache_hits: u64,
+    #[serde(default)]
+    pub piece_cache_misses: u64,
     pub user_data: json::Value,
 }
 
+/// A session entry for a torrent which another entry had already loaded.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct DuplicateSession {
+    pub infohash: String,
+    /// Session file the torrent was loaded from
+    pub loaded: String,
+    /// Where the passed over session file was moved, or still is if that failed
+    pub quarantined: String,
+}
+
 impl Server {
     pub fn update(&mut self, update: SResourceUpdate<'_>) {
         match update {
@@ -254,6 +460,50 @@ impl Server {
             SResourceUpdate::ServerSpace { free_space, .. } => {
                 self.free_space = free_space;
             }
+            SResourceUpdate::ServerPortMapping {
+                external_ip,
+                external_port,
+                external_dht_port,
+                port_mapping_expires,
+                ..
+            } => {
+                self.external_ip = external_ip;
+                self.external_port = external_port;
+                self.external_dht_port = external_dht_port;
+                self.port_mapping_expires = port_mapping_expires;
+            }
+            SResourceUpdate::ServerReannounce {
+                reannounce_queued,
+                reannounce_done,
+                reannounce_failed,
+                reannounce_deferred,
+                ..
+            } => {
+                self.reannounce_queued = reannounce_queued;
+                self.reannounce_done = reannounce_done;
+                self.reannounce_failed = reannounce_failed;
+                self.reannounce_deferred = reannounce_deferred;
+            }
+            SResourceUpdate::ServerQuota {
+                quota_used,
+                quota_threshold,
+                quota_reset,
+                quota_days_left,
+                ..
+            } => {
+                self.quota_used = Some(quota_used);
+                self.quota_threshold = Some(quota_threshold);
+                self.quota_reset = quota_reset;
+                self.quota_days_left = quota_days_left;
+            }
+            SResourceUpdate::ServerPieceCache {
+                piece_cache_hits,
+                piece_cache_misses,
+                ..
+            } => {
+                self.piece_cache_hits = piece_cache_hits;
+                self.piece_cache_misses = piece_cache_misses;
+            }
             SResourceUpdate::Rate {
                 rate_up, rate_down, ..
             } => {
@@ -270,6 +520,9 @@ impl Server {
 pub struct Torrent {
     pub id: String,
     pub name: Option<String>,
+    /// Base64 of the raw name, when it isn't valid UTF-8 and `name` is lossy
+    #[serde(default)]
+    pub name_raw_b64: Option<String>,
     pub creator: Option<String>,
     pub comment: Option<String>,
     pub private: bool,
@@ -288,9 +541,34 @@ pub struct Torrent {
     pub throttle_down: Option<i64>,
     pub transferred_up: u64,
     pub transferred_down: u64,
+    /// Transferred since the server started
+    #[serde(default)]
+    pub ses_transferred_up: u64,
+    #[serde(default)]
+    pub ses_transferred_down: u64,
+    /// Whether blocks in flight are also being requested from other peers
+    #[serde(default)]
+    pub endgame: bool,
+    /// Whether all wanted pieces are downloaded while some files are skipped
+    #[serde(default)]
+    pub partial_seed: bool,
     pub peers: u16,
+    /// Limit on `peers`, past which no more connections are accepted
+    #[serde(default)]
+    pub max_peers: u16,
+    /// Whether pieces are hashed before any of their blocks are uploaded
+    #[serde(default)]
+    pub verify_on_read: bool,
+    /// How space is reserved for the torrent's files
+    #[serde(default)]
+    pub allocation: Allocation,
     pub trackers: u8,
     pub tracker_urls: Vec<String>,
+    #[serde(default)]
+    pub announce_ip: Option<String>,
+    /// Ids of the files being downloaded first, in order, until all are complete
+    #[serde(default)]
+    pub file_order: Vec<String>,
     pub size: Option<u64>,
     pub pieces: Option<u64>,
     pub piece_size: Option<u32>,
@@ -320,18 +598,27 @@ impl Torrent {
                 rate_down,
                 transferred_up,
                 transferred_down,
+                ses_transferred_up,
+                ses_transferred_down,
                 progress,
+                endgame,
                 ..
             } => {
                 self.rate_up = rate_up;
                 self.rate_down = rate_down;
                 self.transferred_up = transferred_up;
                 self.transferred_down = transferred_down;
+                self.ses_transferred_up = ses_transferred_up;
+                self.ses_transferred_down = ses_transferred_down;
                 self.progress = progress;
+                self.endgame = endgame;
             }
             SResourceUpdate::TorrentPath { path, .. } => {
                 self.path = path;
             }
+            SResourceUpdate::TorrentPartialSeed { partial_seed, .. } => {
+                self.partial_seed = partial_seed;
+            }
             SResourceUpdate::TorrentPeers {
                 peers,
                 availability,
@@ -349,6 +636,24 @@ impl Torrent {
             SResourceUpdate::TorrentPieces { piece_field, .. } => {
                 self.piece_field = piece_field;
             }
+            SResourceUpdate::TorrentPiecesDelta { pieces_set, .. } => {
+                self.set_pieces(&pieces_set);
+            }
+            SResourceUpdate::TorrentAnnounceIp { announce_ip, .. } => {
+                self.announce_ip = announce_ip;
+            }
+            SResourceUpdate::TorrentMaxPeers { max_peers, .. } => {
+                self.max_peers = max_peers;
+            }
+            SResourceUpdate::TorrentVerifyOnRead { verify_on_read, .. } => {
+                self.verify_on_read = verify_on_read;
+            }
+            SResourceUpdate::TorrentAllocation { allocation, .. } => {
+                self.allocation = allocation;
+            }
+            SResourceUpdate::TorrentFileOrder { file_order, .. } => {
+                self.file_order = file_order;
+            }
             SResourceUpdate::Resource(Cow::Borrowed(Resource::Torrent(t))) => *self = t.clone(),
             SResourceUpdate::Resource(Cow::Owned(Resource::Torrent(mut t))) => {
                 mem::swap(self, &mut t)
@@ -359,6 +664,20 @@ impl Torrent {
             _ => {}
         }
     }
+
+    /// Marks pieces as present in the base64 encoded piece field.
+    fn set_pieces(&mut self, pieces: &[u64]) {
+        let mut field = match BASE64_STANDARD.decode(&self.piece_field) {
+            Ok(f) => f,
+            Err(_) => return,
+        };
+        for &piece in pieces {
+            if let Some(byte) = field.get_mut((piece / 8) as usize) {
+                *byte |= 0x80 >> (piece % 8);
+            }
+        }
+        self.piece_field = BASE64_STANDARD.encode(&field);
+    }
 }
 
 #[derive(Copy, Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
@@ -373,6 +692,7 @@ pub enum Status {
     Idle,
     Seeding,
     Hashing,
+    Stalled,
     Error,
 }
 
@@ -393,6 +713,52 @@ impl Strategy {
     }
 }
 
+/// How space is reserved for torrent files.
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[serde(deny_unknown_fields)]
+pub enum Allocation {
+    /// Create every file, preallocating only those which are wanted
+    #[default]
+    Sparse,
+    /// Create and preallocate every file
+    Full,
+    /// Don't create files with priority 0, data for pieces which overlap them
+    /// is kept in a separate parts file until they're wanted
+    SkipUnwanted,
+}
+
+impl Allocation {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Allocation::Sparse => "sparse",
+            Allocation::Full => "full",
+            Allocation::SkipUnwanted => "skip_unwanted",
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+#[serde(deny_unknown_fields)]
+pub enum MoveStatus {
+    /// An identical file was already at the destination and kept
+    Adopted,
+    /// The file was moved or copied to the destination
+    Copied,
+    Failed,
+}
+
+impl MoveStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MoveStatus::Adopted => "adopted",
+            MoveStatus::Copied => "copied",
+            MoveStatus::Failed => "failed",
+        }
+    }
+}
+
 #[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
 #[serde(deny_unknown_fields)]
 pub struct Piece {
@@ -424,10 +790,16 @@ pub struct File {
     pub id: String,
     pub torrent_id: String,
     pub path: String,
+    /// Base64 of the raw path, when it isn't valid UTF-8 and `path` is lossy
+    #[serde(default)]
+    pub path_raw_b64: Option<String>,
     pub progress: f32,
     pub availability: f32,
     pub priority: u8,
     pub size: u64,
+    /// What became of the file in the torrent's last move
+    #[serde(default)]
+    pub move_status: Option<MoveStatus>,
     pub user_data: json::Value,
 }
 
@@ -440,6 +812,12 @@ impl File {
             SResourceUpdate::FileProgress { progress, .. } => {
                 self.progress = progress;
             }
+            SResourceUpdate::FileMoveStatus { move_status, .. } => {
+                self.move_status = Some(move_status);
+            }
+            SResourceUpdate::Availability { availability, .. } => {
+                self.availability = availability;
+            }
             _ => {}
         }
     }
@@ -455,6 +833,9 @@ pub struct Peer {
     pub rate_up: u64,
     pub rate_down: u64,
     pub availability: f32,
+    /// Requests which may be outstanding to the peer, sized by its latency and rate
+    #[serde(default)]
+    pub queue_depth: u16,
     pub user_data: json::Value,
 }
 
@@ -467,9 +848,12 @@ impl Peer {
                 self.rate_up = rate_up;
                 self.rate_down = rate_down;
             }
-            SResourceUpdate::PeerAvailability { availability, .. } => {
+            SResourceUpdate::Availability { availability, .. } => {
                 self.availability = availability;
             }
+            SResourceUpdate::PeerQueue { queue_depth, .. } => {
+                self.queue_depth = queue_depth;
+            }
             _ => {}
         }
     }
@@ -481,23 +865,91 @@ pub struct Tracker {
     pub id: String,
     pub torrent_id: String,
     pub url: Url,
+    pub tier: u32,
     pub last_report: DateTime<Utc>,
     pub error: Option<String>,
+    /// When the tracker will be retried after a failed announce
+    #[serde(default)]
+    pub retry_at: Option<DateTime<Utc>>,
+    /// When the tracker is next announced to
+    #[serde(default = "Utc::now")]
+    pub next_announce: DateTime<Utc>,
+    /// Swarm counts from the last successful announce
+    #[serde(default)]
+    pub seeders: Option<u32>,
+    #[serde(default)]
+    pub leechers: Option<u32>,
+    /// Interval the tracker asked for in its last successful announce
+    #[serde(default)]
+    pub last_interval: Option<u32>,
+    /// Extra announce headers, with their values redacted
+    #[serde(default)]
+    pub headers: Vec<String>,
     pub user_data: json::Value,
 }
 
 impl Tracker {
     pub fn update(&mut self, update: SResourceUpdate<'_>) {
-        if let SResourceUpdate::TrackerStatus {
-            last_report, error, ..
-        } = update
-        {
-            self.last_report = last_report;
-            self.error = error;
+        match update {
+            SResourceUpdate::TrackerStatus {
+                last_report,
+                error,
+                retry_at,
+                next_announce,
+                seeders,
+                leechers,
+                last_interval,
+                ..
+            } => {
+                self.last_report = last_report;
+                self.error = error;
+                self.retry_at = retry_at;
+                self.next_announce = next_announce;
+                self.seeders = seeders;
+                self.leechers = leechers;
+                self.last_interval = last_interval;
+            }
+            SResourceUpdate::TrackerHeaders { headers, .. } => {
+                self.headers = headers;
+            }
+            SResourceUpdate::TrackerUrl { url, .. } => {
+                self.url = url;
+            }
+            _ => {}
         }
     }
 }
 
+/// Headers which synapse sets itself on tracker requests
+const RESERVED_HEADERS: [&str; 3] = ["host", "content-length", "connection"];
+
+/// Splits a `Name: value` tracker header into its trimmed name and value, rejecting headers
+/// synapse sets itself and anything which would corrupt the request.
+pub fn parse_tracker_header(line: &str) -> Result<(&str, &str), String> {
+    let Some((name, value)) = line.split_once(':') else {
+        return Err(format!(
+            "header {line:?} is not of the form \"Name: value\""
+        ));
+    };
+    let (name, value) = (name.trim(), value.trim());
+    let token = |c: char| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c);
+    if name.is_empty() || !name.chars().all(token) {
+        return Err(format!("invalid header name {name:?}"));
+    }
+    if RESERVED_HEADERS.contains(&name.to_ascii_lowercase().as_str()) {
+        return Err(format!("header {name} can not be overridden"));
+    }
+    if value.chars().any(|c| c.is_ascii_control()) {
+        return Err(format!("header {name} contains control characters"));
+    }
+    Ok((name, value))
+}
+
+/// Formats a tracker header for display without its value, which usually holds credentials.
+pub fn redact_tracker_header(name: &str) -> String {
+    format!("{name}: <redacted>")
+}
+
 impl SResourceUpdate<'_> {
     pub fn id(&self) -> &str {
         match self {
@@ -508,17 +960,33 @@ impl SResourceUpdate<'_> {
             | SResourceUpdate::ServerTransfer { id, .. }
             | SResourceUpdate::ServerToken { id, .. }
             | SResourceUpdate::ServerSpace { id, .. }
+            | SResourceUpdate::ServerPortMapping { id, .. }
+            | SResourceUpdate::ServerReannounce { id, .. }
+            | SResourceUpdate::ServerQuota { id, .. }
+            | SResourceUpdate::ServerPieceCache { id, .. }
             | SResourceUpdate::TorrentStatus { id, .. }
             | SResourceUpdate::TorrentTransfer { id, .. }
             | SResourceUpdate::TorrentPeers { id, .. }
+            | SResourceUpdate::TorrentPartialSeed { id, .. }
             | SResourceUpdate::TorrentPicker { id, .. }
             | SResourceUpdate::TorrentPriority { id, .. }
             | SResourceUpdate::TorrentPath { id, .. }
             | SResourceUpdate::TorrentPieces { id, .. }
+            | SResourceUpdate::TorrentPiecesDelta { id, .. }
+            | SResourceUpdate::TorrentAnnounceIp { id, .. }
+            | SResourceUpdate::TorrentMaxPeers { id, .. }
+            | SResourceUpdate::TorrentVerifyOnRead { id, .. }
+            | SResourceUpdate::TorrentAllocation { id, .. }
+            | SResourceUpdate::TorrentFileOrder { id, .. }
+            | SResourceUpdate::TorrentBlockProgress { id, .. }
             | SResourceUpdate::FilePriority { id, .. }
             | SResourceUpdate::FileProgress { id, .. }
+            | SResourceUpdate::FileMoveStatus { id, .. }
             | SResourceUpdate::TrackerStatus { id, .. }
-            | SResourceUpdate::PeerAvailability { id, .. }
+            | SResourceUpdate::TrackerHeaders { id, .. }
+            | SResourceUpdate::TrackerUrl { id, .. }
+            | SResourceUpdate::PeerQueue { id, .. }
+            | SResourceUpdate::Availability { id, .. }
             | SResourceUpdate::PieceAvailable { id, .. }
             | SResourceUpdate::PieceDownloaded { id, .. } => id,
         }
@@ -676,6 +1144,9 @@ impl fmt::Display for Resource {
                 writeln!(f, "  downloaded: {} B", t.transferred_down)?;
                 writeln!(f, "  session upload: {} B", t.ses_transferred_up)?;
                 writeln!(f, "  session download: {} B", t.ses_transferred_down)?;
+                if let (Some(ip), Some(port)) = (&t.external_ip, t.external_port) {
+                    writeln!(f, "  external address: {ip}:{port}")?;
+                }
                 writeln!(f, "  started at: {}", t.started)?;
                 write!(f, "}}")?;
             }
@@ -691,6 +1162,9 @@ impl fmt::Display for Resource {
                         "Unknown (magnet)"
                     }
                 )?;
+                if let Some(ref n) = t.name_raw_b64 {
+                    writeln!(f, "  raw name: {n}")?;
+                }
                 writeln!(f, "  path: {}", t.path)?;
                 writeln!(f, "  created at: {}", t.created)?;
                 writeln!(f, "  modified at: {}", t.modified)?;
@@ -728,7 +1202,13 @@ impl fmt::Display for Resource {
                 }
                 writeln!(f, "  uploaded: {} B", t.transferred_up)?;
                 writeln!(f, "  downloaded: {} B", t.transferred_down)?;
-                writeln!(f, "  peers: {}", t.peers)?;
+                writeln!(f, "  session upload: {} B", t.ses_transferred_up)?;
+                writeln!(f, "  session download: {} B", t.ses_transferred_down)?;
+                writeln!(f, "  endgame: {}", t.endgame)?;
+                writeln!(f, "  partial seed: {}", t.partial_seed)?;
+                writeln!(f, "  peers: {}/{}", t.peers, t.max_peers)?;
+                writeln!(f, "  verify on read: {}", t.verify_on_read)?;
+                writeln!(f, "  allocation: {}", t.allocation.as_str())?;
                 writeln!(f, "  trackers: {}", t.trackers)?;
                 if let Some(s) = t.size {
                     writeln!(f, "  size: {s} B")?;
@@ -750,6 +1230,9 @@ impl fmt::Display for Resource {
                 } else {
                     writeln!(f, "  files: Unknown (magnet)")?;
                 }
+                if !t.file_order.is_empty() {
+                    writeln!(f, "  file order: {}", t.file_order.join(", "))?;
+                }
                 write!(f, "}}")?;
             }
             Resource::File(t) => {
@@ -782,6 +1265,59 @@ where
     }
 }
 
+fn deserialize_announce_ip<'de, D>(de: D) -> Result<Option<Option<IpAddr>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    match serde::Deserialize::deserialize(de)? {
+        json::Value::Null => Ok(Some(None)),
+        json::Value::String(ref s) => s
+            .parse()
+            .map(|ip| Some(Some(ip)))
+            .map_err(|_| serde::de::Error::custom("announce_ip must be an IP address")),
+        _ => Err(serde::de::Error::custom(
+            "announce_ip must be a string or null",
+        )),
+    }
+}
+
+fn deserialize_max_peers<'de, D>(de: D) -> Result<Option<Option<u16>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    match serde::Deserialize::deserialize(de)? {
+        json::Value::Null => Ok(Some(None)),
+        json::Value::Number(ref n) => n
+            .as_u64()
+            .filter(|&n| n <= u64::from(u16::MAX))
+            .map(|n| Some(Some(n as u16)))
+            .ok_or_else(|| serde::de::Error::custom("max_peers must be between 0 and 65535")),
+        _ => Err(serde::de::Error::custom(
+            "max_peers must be a number or null",
+        )),
+    }
+}
+
+fn deserialize_verify_on_read<'de, D>(de: D) -> Result<Option<Option<bool>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    match serde::Deserialize::deserialize(de)? {
+        json::Value::Null => Ok(Some(None)),
+        json::Value::Bool(b) => Ok(Some(Some(b))),
+        _ => Err(serde::de::Error::custom(
+            "verify_on_read must be a boolean or null",
+        )),
+    }
+}
+
+fn deserialize_allocation<'de, D>(de: D) -> Result<Option<Option<Allocation>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    <Option<Allocation> as serde::Deserialize>::deserialize(de).map(Some)
+}
+
 // TODO: Proc macros to remove this shit
 
 impl Queryable for Resource {
@@ -833,8 +1369,62 @@ impl Queryable for Server {
             "ses_transferred_up" => Some(Field::N(self.ses_transferred_up as i64)),
             "ses_transferred_down" => Some(Field::N(self.ses_transferred_down as i64)),
             "free_space" => Some(Field::N(self.free_space as i64)),
+            "external_ip" => Some(
+                self.external_ip
+                    .as_ref()
+                    .map(|v| Field::S(v.as_str()))
+                    .unwrap_or(FNULL),
+            ),
+            "external_port" => Some(
+                self.external_port
+                    .map(|p| Field::N(p as i64))
+                    .unwrap_or(FNULL),
+            ),
+            "external_dht_port" => Some(
+                self.external_dht_port
+                    .map(|p| Field::N(p as i64))
+                    .unwrap_or(FNULL),
+            ),
+            "reannounce_queued" => Some(
+                self.reannounce_queued
+                    .map(|n| Field::N(n as i64))
+                    .unwrap_or(FNULL),
+            ),
+            "reannounce_done" => Some(
+                self.reannounce_done
+                    .map(|n| Field::N(n as i64))
+                    .unwrap_or(FNULL),
+            ),
+            "reannounce_failed" => Some(
+                self.reannounce_failed
+                    .map(|n| Field::N(n as i64))
+                    .unwrap_or(FNULL),
+            ),
+            "reannounce_deferred" => Some(
+                self.reannounce_deferred
+                    .map(|n| Field::N(n as i64))
+                    .unwrap_or(FNULL),
+            ),
+            "quota" => Some(self.quota.map(|n| Field::N(n as i64)).unwrap_or(FNULL)),
+            "quota_used" => Some(self.quota_used.map(|n| Field::N(n as i64)).unwrap_or(FNULL)),
+            "quota_threshold" => Some(
+                self.quota_threshold
+                    .map(|n| Field::N(i64::from(n)))
+                    .unwrap_or(FNULL),
+            ),
+            "quota_days_left" => Some(
+                self.quota_days_left
+                    .map(|n| Field::N(i64::from(n)))
+                    .unwrap_or(FNULL),
+            ),
+            "piece_cache_hits" => Some(Field::N(self.piece_cache_hits as i64)),
+            "piece_cache_misses" => Some(Field::N(self.piece_cache_misses as i64)),
 
             "started" => Some(Field::D(self.started)),
+            "port_mapping_expires" => {
+                Some(self.port_mapping_expires.map(Field::D).unwrap_or(FNULL))
+            }
+            "quota_reset" => Some(self.quota_reset.map(Field::D).unwrap_or(FNULL)),
 
             _ if f.starts_with("user_data") => self.user_data.field(&f[9..]),
 
@@ -853,6 +1443,12 @@ impl Queryable for Torrent {
                     .map(|v| Field::S(v.as_str()))
                     .unwrap_or(FNULL),
             ),
+            "name_raw_b64" => Some(
+                self.name_raw_b64
+                    .as_ref()
+                    .map(|v| Field::S(v.as_str()))
+                    .unwrap_or(FNULL),
+            ),
             "private" => Some(Field::B(self.private)),
             "creator" => Some(
                 self.creator
@@ -882,11 +1478,27 @@ impl Queryable for Torrent {
             "throttle_down" => Some(self.throttle_down.map(Field::N).unwrap_or(FNULL)),
             "transferred_up" => Some(Field::N(self.transferred_up as i64)),
             "transferred_down" => Some(Field::N(self.transferred_down as i64)),
+            "ses_transferred_up" => Some(Field::N(self.ses_transferred_up as i64)),
+            "ses_transferred_down" => Some(Field::N(self.ses_transferred_down as i64)),
+            "endgame" => Some(Field::B(self.endgame)),
+            "partial_seed" => Some(Field::B(self.partial_seed)),
             "peers" => Some(Field::N(self.peers as i64)),
+            "max_peers" => Some(Field::N(self.max_peers as i64)),
+            "verify_on_read" => Some(Field::B(self.verify_on_read)),
+            "allocation" => Some(Field::S(self.allocation.as_str())),
             "trackers" => Some(Field::N(self.trackers as i64)),
             "tracker_urls" => Some(Field::V(
                 self.tracker_urls.iter().map(|url| Field::S(url)).collect(),
             )),
+            "announce_ip" => Some(
+                self.announce_ip
+                    .as_ref()
+                    .map(|v| Field::S(v.as_str()))
+                    .unwrap_or(FNULL),
+            ),
+            "file_order" => Some(Field::V(
+                self.file_order.iter().map(|id| Field::S(id)).collect(),
+            )),
             "size" => Some(self.size.map(|v| Field::N(v as i64)).unwrap_or(FNULL)),
             "pieces" => Some(self.pieces.map(|v| Field::N(v as i64)).unwrap_or(FNULL)),
             "piece_size" => Some(self.piece_size.map(|v| Field::N(v as i64)).unwrap_or(FNULL)),
@@ -933,11 +1545,23 @@ impl Queryable for File {
             "id" => Some(Field::S(&self.id)),
             "torrent_id" => Some(Field::S(&self.torrent_id)),
             "path" => Some(Field::S(&self.path)),
+            "path_raw_b64" => Some(
+                self.path_raw_b64
+                    .as_ref()
+                    .map(|v| Field::S(v.as_str()))
+                    .unwrap_or(FNULL),
+            ),
 
             "priority" => Some(Field::N(self.priority as i64)),
 
             "progress" => Some(Field::F(self.progress)),
 
+            "move_status" => Some(
+                self.move_status
+                    .map(|s| Field::S(s.as_str()))
+                    .unwrap_or(FNULL),
+            ),
+
             _ if f.starts_with("user_data") => self.user_data.field(&f[9..]),
 
             _ => None,
@@ -972,6 +1596,7 @@ impl Queryable for Tracker {
             "id" => Some(Field::S(&self.id)),
             "torrent_id" => Some(Field::S(&self.torrent_id)),
             "url" => Some(Field::S(self.url.as_str())),
+            "tier" => Some(Field::N(i64::from(self.tier))),
             "error" => Some(
                 self.error
                     .as_ref()
@@ -980,6 +1605,26 @@ impl Queryable for Tracker {
             ),
 
             "last_report" => Some(Field::D(self.last_report)),
+            "retry_at" => Some(self.retry_at.map(Field::D).unwrap_or(FNULL)),
+            "next_announce" => Some(Field::D(self.next_announce)),
+            "seeders" => Some(
+                self.seeders
+                    .map(|n| Field::N(i64::from(n)))
+                    .unwrap_or(FNULL),
+            ),
+            "leechers" => Some(
+                self.leechers
+                    .map(|n| Field::N(i64::from(n)))
+                    .unwrap_or(FNULL),
+            ),
+            "last_interval" => Some(
+                self.last_interval
+                    .map(|n| Field::N(i64::from(n)))
+                    .unwrap_or(FNULL),
+            ),
+            "headers" => Some(Field::V(
+                self.headers.iter().map(|h| Field::S(h.as_str())).collect(),
+            )),
 
             _ if f.starts_with("user_data") => self.user_data.field(&f[9..]),
 
@@ -998,6 +1643,7 @@ impl Status {
             Status::Seeding => "seeding",
             Status::Hashing => "hashing",
             Status::Magnet => "magnet",
+            Status::Stalled => "stalled",
             Status::Error => "error",
         }
     }
@@ -1038,6 +1684,22 @@ impl Default for Server {
             free_space: 0,
             download_token: "".to_owned(),
             started: Utc::now(),
+            external_ip: None,
+            external_port: None,
+            external_dht_port: None,
+            port_mapping_expires: None,
+            reannounce_queued: None,
+            reannounce_done: None,
+            reannounce_failed: None,
+            reannounce_deferred: None,
+            quota: None,
+            quota_used: None,
+            quota_threshold: None,
+            quota_reset: None,
+            quota_days_left: None,
+            duplicate_sessions: Vec::new(),
+            piece_cache_hits: 0,
+            piece_cache_misses: 0,
             user_data: json::Value::Null,
         }
     }
@@ -1048,6 +1710,7 @@ impl Default for Torrent {
         Torrent {
             id: "".to_owned(),
             name: None,
+            name_raw_b64: None,
             comment: None,
             creator: None,
             private: false,
@@ -1066,9 +1729,18 @@ impl Default for Torrent {
             throttle_down: None,
             transferred_up: 0,
             transferred_down: 0,
+            ses_transferred_up: 0,
+            ses_transferred_down: 0,
+            endgame: false,
+            partial_seed: false,
             peers: 0,
+            max_peers: 0,
+            verify_on_read: false,
+            allocation: Allocation::Sparse,
             trackers: 0,
             tracker_urls: vec![],
+            announce_ip: None,
+            file_order: vec![],
             size: None,
             pieces: None,
             piece_size: None,
@@ -1085,8 +1757,15 @@ impl Default for Tracker {
             id: "".to_owned(),
             torrent_id: "".to_owned(),
             url: Url::parse("http://my.tracker/announce").unwrap(),
+            tier: 0,
             last_report: Utc::now(),
             error: None,
+            retry_at: None,
+            next_announce: Utc::now(),
+            seeders: None,
+            leechers: None,
+            last_interval: None,
+            headers: Vec::new(),
             user_data: json::Value::Null,
         }
     }
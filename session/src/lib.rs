@@ -3,7 +3,7 @@ extern crate serde_derive;
 
 pub mod torrent {
     pub use self::current::Torrent;
-    pub use self::ver_bfbf28 as current;
+    pub use self::ver_2f9d61 as current;
 
     #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
     pub struct Bitfield {
@@ -24,15 +24,35 @@ pub mod torrent {
     /// `session_data`.
     pub fn load(session_data: &[u8], info_data: Option<&[u8]>) -> LoadResult {
         if let Some(info_data) = info_data {
-            if let Ok((session, info)) = bincode::deserialize::<ver_bfbf28::Session>(session_data)
-                .and_then(|session| {
-                    Ok((
-                        session,
-                        bincode::deserialize::<ver_bfbf28::Info>(info_data)?,
-                    ))
-                })
-            {
+            let Ok(info) = bincode::deserialize::<current::Info>(info_data) else {
+                return LoadResult::Failed;
+            };
+            if let Ok(session) = bincode::deserialize::<ver_2f9d61::Session>(session_data) {
                 LoadResult::Ok(Torrent { info, session })
+            } else if let Ok(session) = bincode::deserialize::<ver_58e0c3::Session>(session_data) {
+                LoadResult::Migrated(ver_58e0c3::Torrent { info, session }.migrate())
+            } else if let Ok(session) = bincode::deserialize::<ver_d7a35b::Session>(session_data) {
+                LoadResult::Migrated(ver_d7a35b::Torrent { info, session }.migrate())
+            } else if let Ok(session) = bincode::deserialize::<ver_e81c4d::Session>(session_data) {
+                LoadResult::Migrated(ver_e81c4d::Torrent { info, session }.migrate())
+            } else if let Ok(session) = bincode::deserialize::<ver_a41c5e::Session>(session_data) {
+                LoadResult::Migrated(ver_a41c5e::Torrent { info, session }.migrate())
+            } else if let Ok(session) = bincode::deserialize::<ver_6b0e4c::Session>(session_data) {
+                LoadResult::Migrated(ver_6b0e4c::Torrent { info, session }.migrate())
+            } else if let Ok(session) = bincode::deserialize::<ver_91d3a0::Session>(session_data) {
+                LoadResult::Migrated(ver_91d3a0::Torrent { info, session }.migrate())
+            } else if let Ok(session) = bincode::deserialize::<ver_e52d07::Session>(session_data) {
+                LoadResult::Migrated(ver_e52d07::Torrent { info, session }.migrate())
+            } else if let Ok(session) = bincode::deserialize::<ver_4b7e19::Session>(session_data) {
+                LoadResult::Migrated(ver_4b7e19::Torrent { info, session }.migrate())
+            } else if let Ok(session) = bincode::deserialize::<ver_c2a9e4::Session>(session_data) {
+                LoadResult::Migrated(ver_c2a9e4::Torrent { info, session }.migrate())
+            } else if let Ok(session) = bincode::deserialize::<ver_7d24c1::Session>(session_data) {
+                LoadResult::Migrated(ver_7d24c1::Torrent { info, session }.migrate())
+            } else if let Ok(session) = bincode::deserialize::<ver_3c1e72::Session>(session_data) {
+                LoadResult::Migrated(ver_3c1e72::Torrent { info, session }.migrate())
+            } else if let Ok(session) = bincode::deserialize::<ver_bfbf28::Session>(session_data) {
+                LoadResult::Migrated(ver_bfbf28::Torrent { info, session }.migrate())
             } else {
                 LoadResult::Failed
             }
@@ -51,9 +71,963 @@ pub mod torrent {
         }
     }
 
+    pub mod ver_2f9d61 {
+        use std::net::IpAddr;
+
+        use chrono::{DateTime, Utc};
+
+        use super::ver_58e0c3 as prev;
+        use super::Bitfield;
+
+        pub use prev::{Allocation, File, Info, Status, StatusState};
+
+        #[derive(Debug, PartialEq)]
+        pub struct Torrent {
+            pub info: Info,
+            pub session: Session,
+        }
+
+        #[derive(Deserialize, Debug, PartialEq, Serialize)]
+        pub struct Session {
+            pub announce: Option<String>,
+            pub creator: Option<String>,
+            pub comment: Option<String>,
+            pub pieces: Bitfield,
+            pub uploaded: u64,
+            pub downloaded: u64,
+            pub status: Status,
+            pub path: Option<String>,
+            pub priority: u8,
+            pub priorities: Vec<u8>,
+            pub created: DateTime<Utc>,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            /// Tracker urls grouped into announce tiers
+            pub trackers: Vec<Vec<String>>,
+            /// Random `key` sent with every tracker announce
+            pub announce_key: u32,
+            /// Extra `Name: value` headers sent with HTTP announces, by tracker url
+            pub tracker_headers: Vec<(String, Vec<String>)>,
+            /// Address reported to trackers in place of the configured one
+            pub announce_ip: Option<IpAddr>,
+            /// Blocks already written of pieces which were still downloading, by piece
+            pub partial: Vec<(u32, Bitfield)>,
+            /// Connection limit in place of the configured `max_peers_per_torrent`
+            pub max_peers: Option<u16>,
+            /// Stable RPC ids of the trackers, by url
+            pub tracker_ids: Vec<(String, String)>,
+            /// Files to download to completion one after the other, by index
+            pub file_order: Vec<usize>,
+            /// Metadata pieces received so far and the partly assembled info dictionary
+            /// of a magnet which hasn't finished fetching it
+            pub metadata: Option<(Bitfield, Vec<u8>)>,
+            /// Whether pieces are verified before being uploaded, in place of the configured
+            /// `verify_on_read`
+            pub verify_on_read: Option<bool>,
+            /// How space is reserved for the files, in place of the configured `allocation`
+            pub allocation: Option<Allocation>,
+            /// File priorities a magnet was added with, applied once its metadata arrives
+            pub preset_priorities: Vec<(usize, u8)>,
+        }
+
+        impl super::Torrent {
+            pub fn migrate(self) -> Self {
+                self
+            }
+        }
+    }
+
+    pub mod ver_58e0c3 {
+        use std::net::IpAddr;
+
+        use chrono::{DateTime, Utc};
+
+        use super::ver_2f9d61 as next;
+        use super::ver_d7a35b as prev;
+        use super::Bitfield;
+
+        pub use prev::{File, Info, Status, StatusState};
+
+        #[derive(Debug, PartialEq)]
+        pub struct Torrent {
+            pub info: Info,
+            pub session: Session,
+        }
+
+        #[derive(Deserialize, Debug, PartialEq, Serialize)]
+        pub struct Session {
+            pub announce: Option<String>,
+            pub creator: Option<String>,
+            pub comment: Option<String>,
+            pub pieces: Bitfield,
+            pub uploaded: u64,
+            pub downloaded: u64,
+            pub status: Status,
+            pub path: Option<String>,
+            pub priority: u8,
+            pub priorities: Vec<u8>,
+            pub created: DateTime<Utc>,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            /// Tracker urls grouped into announce tiers
+            pub trackers: Vec<Vec<String>>,
+            /// Random `key` sent with every tracker announce
+            pub announce_key: u32,
+            /// Extra `Name: value` headers sent with HTTP announces, by tracker url
+            pub tracker_headers: Vec<(String, Vec<String>)>,
+            /// Address reported to trackers in place of the configured one
+            pub announce_ip: Option<IpAddr>,
+            /// Blocks already written of pieces which were still downloading, by piece
+            pub partial: Vec<(u32, Bitfield)>,
+            /// Connection limit in place of the configured `max_peers_per_torrent`
+            pub max_peers: Option<u16>,
+            /// Stable RPC ids of the trackers, by url
+            pub tracker_ids: Vec<(String, String)>,
+            /// Files to download to completion one after the other, by index
+            pub file_order: Vec<usize>,
+            /// Metadata pieces received so far and the partly assembled info dictionary
+            /// of a magnet which hasn't finished fetching it
+            pub metadata: Option<(Bitfield, Vec<u8>)>,
+            /// Whether pieces are verified before being uploaded, in place of the configured
+            /// `verify_on_read`
+            pub verify_on_read: Option<bool>,
+            /// How space is reserved for the files, in place of the configured `allocation`
+            pub allocation: Option<Allocation>,
+        }
+
+        impl Torrent {
+            pub fn migrate(self) -> super::current::Torrent {
+                let s = self.session;
+                let session = next::Session {
+                    announce: s.announce,
+                    creator: s.creator,
+                    comment: s.comment,
+                    pieces: s.pieces,
+                    uploaded: s.uploaded,
+                    downloaded: s.downloaded,
+                    status: s.status,
+                    path: s.path,
+                    priority: s.priority,
+                    priorities: s.priorities,
+                    created: s.created,
+                    throttle_ul: s.throttle_ul,
+                    throttle_dl: s.throttle_dl,
+                    trackers: s.trackers,
+                    announce_key: s.announce_key,
+                    tracker_headers: s.tracker_headers,
+                    announce_ip: s.announce_ip,
+                    partial: s.partial,
+                    max_peers: s.max_peers,
+                    tracker_ids: s.tracker_ids,
+                    file_order: s.file_order,
+                    metadata: s.metadata,
+                    verify_on_read: s.verify_on_read,
+                    allocation: s.allocation,
+                    preset_priorities: Vec::new(),
+                };
+                next::Torrent {
+                    info: self.info,
+                    session,
+                }
+                .migrate()
+            }
+        }
+
+        /// How space is reserved for a torrent's files
+        #[derive(Clone, Copy, Deserialize, Debug, PartialEq, Serialize)]
+        pub enum Allocation {
+            Sparse,
+            Full,
+            SkipUnwanted,
+        }
+    }
+
+    pub mod ver_d7a35b {
+        use std::net::IpAddr;
+
+        use chrono::{DateTime, Utc};
+
+        use super::ver_58e0c3 as next;
+        use super::ver_e81c4d as prev;
+        use super::Bitfield;
+
+        pub use prev::{File, Info, Status, StatusState};
+
+        #[derive(Debug, PartialEq)]
+        pub struct Torrent {
+            pub info: Info,
+            pub session: Session,
+        }
+
+        #[derive(Deserialize, Debug, PartialEq, Serialize)]
+        pub struct Session {
+            pub announce: Option<String>,
+            pub creator: Option<String>,
+            pub comment: Option<String>,
+            pub pieces: Bitfield,
+            pub uploaded: u64,
+            pub downloaded: u64,
+            pub status: Status,
+            pub path: Option<String>,
+            pub priority: u8,
+            pub priorities: Vec<u8>,
+            pub created: DateTime<Utc>,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            /// Tracker urls grouped into announce tiers
+            pub trackers: Vec<Vec<String>>,
+            /// Random `key` sent with every tracker announce
+            pub announce_key: u32,
+            /// Extra `Name: value` headers sent with HTTP announces, by tracker url
+            pub tracker_headers: Vec<(String, Vec<String>)>,
+            /// Address reported to trackers in place of the configured one
+            pub announce_ip: Option<IpAddr>,
+            /// Blocks already written of pieces which were still downloading, by piece
+            pub partial: Vec<(u32, Bitfield)>,
+            /// Connection limit in place of the configured `max_peers_per_torrent`
+            pub max_peers: Option<u16>,
+            /// Stable RPC ids of the trackers, by url
+            pub tracker_ids: Vec<(String, String)>,
+            /// Files to download to completion one after the other, by index
+            pub file_order: Vec<usize>,
+            /// Metadata pieces received so far and the partly assembled info dictionary
+            /// of a magnet which hasn't finished fetching it
+            pub metadata: Option<(Bitfield, Vec<u8>)>,
+            /// Whether pieces are verified before being uploaded, in place of the configured
+            /// `verify_on_read`
+            pub verify_on_read: Option<bool>,
+        }
+
+        impl Torrent {
+            pub fn migrate(self) -> super::current::Torrent {
+                let s = self.session;
+                let session = next::Session {
+                    announce: s.announce,
+                    creator: s.creator,
+                    comment: s.comment,
+                    pieces: s.pieces,
+                    uploaded: s.uploaded,
+                    downloaded: s.downloaded,
+                    status: s.status,
+                    path: s.path,
+                    priority: s.priority,
+                    priorities: s.priorities,
+                    created: s.created,
+                    throttle_ul: s.throttle_ul,
+                    throttle_dl: s.throttle_dl,
+                    trackers: s.trackers,
+                    announce_key: s.announce_key,
+                    tracker_headers: s.tracker_headers,
+                    announce_ip: s.announce_ip,
+                    partial: s.partial,
+                    max_peers: s.max_peers,
+                    tracker_ids: s.tracker_ids,
+                    file_order: s.file_order,
+                    metadata: s.metadata,
+                    verify_on_read: s.verify_on_read,
+                    allocation: None,
+                };
+                next::Torrent {
+                    info: self.info,
+                    session,
+                }
+                .migrate()
+            }
+        }
+    }
+
+    pub mod ver_e81c4d {
+        use std::net::IpAddr;
+
+        use chrono::{DateTime, Utc};
+
+        use super::ver_a41c5e as prev;
+        use super::ver_d7a35b as next;
+        use super::Bitfield;
+
+        pub use prev::{File, Info, Status, StatusState};
+
+        #[derive(Debug, PartialEq)]
+        pub struct Torrent {
+            pub info: Info,
+            pub session: Session,
+        }
+
+        #[derive(Deserialize, Debug, PartialEq, Serialize)]
+        pub struct Session {
+            pub announce: Option<String>,
+            pub creator: Option<String>,
+            pub comment: Option<String>,
+            pub pieces: Bitfield,
+            pub uploaded: u64,
+            pub downloaded: u64,
+            pub status: Status,
+            pub path: Option<String>,
+            pub priority: u8,
+            pub priorities: Vec<u8>,
+            pub created: DateTime<Utc>,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            /// Tracker urls grouped into announce tiers
+            pub trackers: Vec<Vec<String>>,
+            /// Random `key` sent with every tracker announce
+            pub announce_key: u32,
+            /// Extra `Name: value` headers sent with HTTP announces, by tracker url
+            pub tracker_headers: Vec<(String, Vec<String>)>,
+            /// Address reported to trackers in place of the configured one
+            pub announce_ip: Option<IpAddr>,
+            /// Blocks already written of pieces which were still downloading, by piece
+            pub partial: Vec<(u32, Bitfield)>,
+            /// Connection limit in place of the configured `max_peers_per_torrent`
+            pub max_peers: Option<u16>,
+            /// Stable RPC ids of the trackers, by url
+            pub tracker_ids: Vec<(String, String)>,
+            /// Files to download to completion one after the other, by index
+            pub file_order: Vec<usize>,
+            /// Metadata pieces received so far and the partly assembled info dictionary
+            /// of a magnet which hasn't finished fetching it
+            pub metadata: Option<(Bitfield, Vec<u8>)>,
+        }
+
+        impl Torrent {
+            pub fn migrate(self) -> super::current::Torrent {
+                let s = self.session;
+                let session = next::Session {
+                    announce: s.announce,
+                    creator: s.creator,
+                    comment: s.comment,
+                    pieces: s.pieces,
+                    uploaded: s.uploaded,
+                    downloaded: s.downloaded,
+                    status: s.status,
+                    path: s.path,
+                    priority: s.priority,
+                    priorities: s.priorities,
+                    created: s.created,
+                    throttle_ul: s.throttle_ul,
+                    throttle_dl: s.throttle_dl,
+                    trackers: s.trackers,
+                    announce_key: s.announce_key,
+                    tracker_headers: s.tracker_headers,
+                    announce_ip: s.announce_ip,
+                    partial: s.partial,
+                    max_peers: s.max_peers,
+                    tracker_ids: s.tracker_ids,
+                    file_order: s.file_order,
+                    metadata: s.metadata,
+                    verify_on_read: None,
+                };
+                next::Torrent {
+                    info: self.info,
+                    session,
+                }
+                .migrate()
+            }
+        }
+    }
+
+    pub mod ver_a41c5e {
+        use std::net::IpAddr;
+
+        use chrono::{DateTime, Utc};
+
+        use super::ver_6b0e4c as prev;
+        use super::ver_e81c4d as next;
+        use super::Bitfield;
+
+        pub use prev::{File, Info, Status, StatusState};
+
+        #[derive(Debug, PartialEq)]
+        pub struct Torrent {
+            pub info: Info,
+            pub session: Session,
+        }
+
+        #[derive(Deserialize, Debug, PartialEq, Serialize)]
+        pub struct Session {
+            pub announce: Option<String>,
+            pub creator: Option<String>,
+            pub comment: Option<String>,
+            pub pieces: Bitfield,
+            pub uploaded: u64,
+            pub downloaded: u64,
+            pub status: Status,
+            pub path: Option<String>,
+            pub priority: u8,
+            pub priorities: Vec<u8>,
+            pub created: DateTime<Utc>,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            /// Tracker urls grouped into announce tiers
+            pub trackers: Vec<Vec<String>>,
+            /// Random `key` sent with every tracker announce
+            pub announce_key: u32,
+            /// Extra `Name: value` headers sent with HTTP announces, by tracker url
+            pub tracker_headers: Vec<(String, Vec<String>)>,
+            /// Address reported to trackers in place of the configured one
+            pub announce_ip: Option<IpAddr>,
+            /// Blocks already written of pieces which were still downloading, by piece
+            pub partial: Vec<(u32, Bitfield)>,
+            /// Connection limit in place of the configured `max_peers_per_torrent`
+            pub max_peers: Option<u16>,
+            /// Stable RPC ids of the trackers, by url
+            pub tracker_ids: Vec<(String, String)>,
+            /// Files to download to completion one after the other, by index
+            pub file_order: Vec<usize>,
+        }
+
+        impl Torrent {
+            pub fn migrate(self) -> super::current::Torrent {
+                let s = self.session;
+                let session = next::Session {
+                    announce: s.announce,
+                    creator: s.creator,
+                    comment: s.comment,
+                    pieces: s.pieces,
+                    uploaded: s.uploaded,
+                    downloaded: s.downloaded,
+                    status: s.status,
+                    path: s.path,
+                    priority: s.priority,
+                    priorities: s.priorities,
+                    created: s.created,
+                    throttle_ul: s.throttle_ul,
+                    throttle_dl: s.throttle_dl,
+                    trackers: s.trackers,
+                    announce_key: s.announce_key,
+                    tracker_headers: s.tracker_headers,
+                    announce_ip: s.announce_ip,
+                    partial: s.partial,
+                    max_peers: s.max_peers,
+                    tracker_ids: s.tracker_ids,
+                    file_order: s.file_order,
+                    metadata: None,
+                };
+                next::Torrent {
+                    info: self.info,
+                    session,
+                }
+                .migrate()
+            }
+        }
+    }
+
+    pub mod ver_6b0e4c {
+        use std::net::IpAddr;
+
+        use chrono::{DateTime, Utc};
+
+        use super::ver_91d3a0 as prev;
+        use super::ver_a41c5e as next;
+        use super::Bitfield;
+
+        pub use prev::{File, Info, Status, StatusState};
+
+        #[derive(Debug, PartialEq)]
+        pub struct Torrent {
+            pub info: Info,
+            pub session: Session,
+        }
+
+        #[derive(Deserialize, Debug, PartialEq, Serialize)]
+        pub struct Session {
+            pub announce: Option<String>,
+            pub creator: Option<String>,
+            pub comment: Option<String>,
+            pub pieces: Bitfield,
+            pub uploaded: u64,
+            pub downloaded: u64,
+            pub status: Status,
+            pub path: Option<String>,
+            pub priority: u8,
+            pub priorities: Vec<u8>,
+            pub created: DateTime<Utc>,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            /// Tracker urls grouped into announce tiers
+            pub trackers: Vec<Vec<String>>,
+            /// Random `key` sent with every tracker announce
+            pub announce_key: u32,
+            /// Extra `Name: value` headers sent with HTTP announces, by tracker url
+            pub tracker_headers: Vec<(String, Vec<String>)>,
+            /// Address reported to trackers in place of the configured one
+            pub announce_ip: Option<IpAddr>,
+            /// Blocks already written of pieces which were still downloading, by piece
+            pub partial: Vec<(u32, Bitfield)>,
+            /// Connection limit in place of the configured `max_peers_per_torrent`
+            pub max_peers: Option<u16>,
+            /// Stable RPC ids of the trackers, by url
+            pub tracker_ids: Vec<(String, String)>,
+        }
+
+        impl Torrent {
+            pub fn migrate(self) -> super::current::Torrent {
+                let s = self.session;
+                let session = next::Session {
+                    announce: s.announce,
+                    creator: s.creator,
+                    comment: s.comment,
+                    pieces: s.pieces,
+                    uploaded: s.uploaded,
+                    downloaded: s.downloaded,
+                    status: s.status,
+                    path: s.path,
+                    priority: s.priority,
+                    priorities: s.priorities,
+                    created: s.created,
+                    throttle_ul: s.throttle_ul,
+                    throttle_dl: s.throttle_dl,
+                    trackers: s.trackers,
+                    announce_key: s.announce_key,
+                    tracker_headers: s.tracker_headers,
+                    announce_ip: s.announce_ip,
+                    partial: s.partial,
+                    max_peers: s.max_peers,
+                    tracker_ids: s.tracker_ids,
+                    file_order: Vec::new(),
+                };
+                next::Torrent {
+                    info: self.info,
+                    session,
+                }
+                .migrate()
+            }
+        }
+    }
+
+    pub mod ver_91d3a0 {
+        use std::net::IpAddr;
+
+        use chrono::{DateTime, Utc};
+
+        use super::ver_6b0e4c as next;
+        use super::ver_e52d07 as prev;
+        use super::Bitfield;
+
+        pub use prev::{File, Info, Status, StatusState};
+
+        #[derive(Debug, PartialEq)]
+        pub struct Torrent {
+            pub info: Info,
+            pub session: Session,
+        }
+
+        #[derive(Deserialize, Debug, PartialEq, Serialize)]
+        pub struct Session {
+            pub announce: Option<String>,
+            pub creator: Option<String>,
+            pub comment: Option<String>,
+            pub pieces: Bitfield,
+            pub uploaded: u64,
+            pub downloaded: u64,
+            pub status: Status,
+            pub path: Option<String>,
+            pub priority: u8,
+            pub priorities: Vec<u8>,
+            pub created: DateTime<Utc>,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            /// Tracker urls grouped into announce tiers
+            pub trackers: Vec<Vec<String>>,
+            /// Random `key` sent with every tracker announce
+            pub announce_key: u32,
+            /// Extra `Name: value` headers sent with HTTP announces, by tracker url
+            pub tracker_headers: Vec<(String, Vec<String>)>,
+            /// Address reported to trackers in place of the configured one
+            pub announce_ip: Option<IpAddr>,
+            /// Blocks already written of pieces which were still downloading, by piece
+            pub partial: Vec<(u32, Bitfield)>,
+            /// Connection limit in place of the configured `max_peers_per_torrent`
+            pub max_peers: Option<u16>,
+        }
+
+        impl Torrent {
+            pub fn migrate(self) -> super::current::Torrent {
+                let s = self.session;
+                let session = next::Session {
+                    announce: s.announce,
+                    creator: s.creator,
+                    comment: s.comment,
+                    pieces: s.pieces,
+                    uploaded: s.uploaded,
+                    downloaded: s.downloaded,
+                    status: s.status,
+                    path: s.path,
+                    priority: s.priority,
+                    priorities: s.priorities,
+                    created: s.created,
+                    throttle_ul: s.throttle_ul,
+                    throttle_dl: s.throttle_dl,
+                    trackers: s.trackers,
+                    announce_key: s.announce_key,
+                    tracker_headers: s.tracker_headers,
+                    announce_ip: s.announce_ip,
+                    partial: s.partial,
+                    max_peers: s.max_peers,
+                    tracker_ids: Vec::new(),
+                };
+                next::Torrent {
+                    info: self.info,
+                    session,
+                }
+                .migrate()
+            }
+        }
+    }
+
+    pub mod ver_e52d07 {
+        use std::net::IpAddr;
+
+        use chrono::{DateTime, Utc};
+
+        use super::ver_4b7e19 as prev;
+        use super::ver_91d3a0 as next;
+        use super::Bitfield;
+
+        pub use prev::{File, Info, Status, StatusState};
+
+        #[derive(Debug, PartialEq)]
+        pub struct Torrent {
+            pub info: Info,
+            pub session: Session,
+        }
+
+        #[derive(Deserialize, Debug, PartialEq, Serialize)]
+        pub struct Session {
+            pub announce: Option<String>,
+            pub creator: Option<String>,
+            pub comment: Option<String>,
+            pub pieces: Bitfield,
+            pub uploaded: u64,
+            pub downloaded: u64,
+            pub status: Status,
+            pub path: Option<String>,
+            pub priority: u8,
+            pub priorities: Vec<u8>,
+            pub created: DateTime<Utc>,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            /// Tracker urls grouped into announce tiers
+            pub trackers: Vec<Vec<String>>,
+            /// Random `key` sent with every tracker announce
+            pub announce_key: u32,
+            /// Extra `Name: value` headers sent with HTTP announces, by tracker url
+            pub tracker_headers: Vec<(String, Vec<String>)>,
+            /// Address reported to trackers in place of the configured one
+            pub announce_ip: Option<IpAddr>,
+            /// Blocks already written of pieces which were still downloading, by piece
+            pub partial: Vec<(u32, Bitfield)>,
+        }
+
+        impl Torrent {
+            pub fn migrate(self) -> super::current::Torrent {
+                let s = self.session;
+                let session = next::Session {
+                    announce: s.announce,
+                    creator: s.creator,
+                    comment: s.comment,
+                    pieces: s.pieces,
+                    uploaded: s.uploaded,
+                    downloaded: s.downloaded,
+                    status: s.status,
+                    path: s.path,
+                    priority: s.priority,
+                    priorities: s.priorities,
+                    created: s.created,
+                    throttle_ul: s.throttle_ul,
+                    throttle_dl: s.throttle_dl,
+                    trackers: s.trackers,
+                    announce_key: s.announce_key,
+                    tracker_headers: s.tracker_headers,
+                    announce_ip: s.announce_ip,
+                    partial: s.partial,
+                    max_peers: None,
+                };
+                next::Torrent {
+                    info: self.info,
+                    session,
+                }
+                .migrate()
+            }
+        }
+    }
+
+    pub mod ver_4b7e19 {
+        use std::net::IpAddr;
+
+        use chrono::{DateTime, Utc};
+
+        use super::ver_c2a9e4 as prev;
+        use super::ver_e52d07 as next;
+        use super::Bitfield;
+
+        pub use prev::{File, Info, Status, StatusState};
+
+        #[derive(Debug, PartialEq)]
+        pub struct Torrent {
+            pub info: Info,
+            pub session: Session,
+        }
+
+        #[derive(Deserialize, Debug, PartialEq, Serialize)]
+        pub struct Session {
+            pub announce: Option<String>,
+            pub creator: Option<String>,
+            pub comment: Option<String>,
+            pub pieces: Bitfield,
+            pub uploaded: u64,
+            pub downloaded: u64,
+            pub status: Status,
+            pub path: Option<String>,
+            pub priority: u8,
+            pub priorities: Vec<u8>,
+            pub created: DateTime<Utc>,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            /// Tracker urls grouped into announce tiers
+            pub trackers: Vec<Vec<String>>,
+            /// Random `key` sent with every tracker announce
+            pub announce_key: u32,
+            /// Extra `Name: value` headers sent with HTTP announces, by tracker url
+            pub tracker_headers: Vec<(String, Vec<String>)>,
+            /// Address reported to trackers in place of the configured one
+            pub announce_ip: Option<IpAddr>,
+        }
+
+        impl Torrent {
+            pub fn migrate(self) -> super::current::Torrent {
+                let s = self.session;
+                let session = next::Session {
+                    announce: s.announce,
+                    creator: s.creator,
+                    comment: s.comment,
+                    pieces: s.pieces,
+                    uploaded: s.uploaded,
+                    downloaded: s.downloaded,
+                    status: s.status,
+                    path: s.path,
+                    priority: s.priority,
+                    priorities: s.priorities,
+                    created: s.created,
+                    throttle_ul: s.throttle_ul,
+                    throttle_dl: s.throttle_dl,
+                    trackers: s.trackers,
+                    announce_key: s.announce_key,
+                    tracker_headers: s.tracker_headers,
+                    announce_ip: s.announce_ip,
+                    partial: Vec::new(),
+                };
+                next::Torrent {
+                    info: self.info,
+                    session,
+                }
+                .migrate()
+            }
+        }
+    }
+
+    pub mod ver_c2a9e4 {
+        use chrono::{DateTime, Utc};
+
+        use super::ver_4b7e19 as next;
+        use super::ver_7d24c1 as prev;
+        use super::Bitfield;
+
+        pub use prev::{File, Info, Status, StatusState};
+
+        #[derive(Debug, PartialEq)]
+        pub struct Torrent {
+            pub info: Info,
+            pub session: Session,
+        }
+
+        #[derive(Deserialize, Debug, PartialEq, Serialize)]
+        pub struct Session {
+            pub announce: Option<String>,
+            pub creator: Option<String>,
+            pub comment: Option<String>,
+            pub pieces: Bitfield,
+            pub uploaded: u64,
+            pub downloaded: u64,
+            pub status: Status,
+            pub path: Option<String>,
+            pub priority: u8,
+            pub priorities: Vec<u8>,
+            pub created: DateTime<Utc>,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            /// Tracker urls grouped into announce tiers
+            pub trackers: Vec<Vec<String>>,
+            /// Random `key` sent with every tracker announce
+            pub announce_key: u32,
+            /// Extra `Name: value` headers sent with HTTP announces, by tracker url
+            pub tracker_headers: Vec<(String, Vec<String>)>,
+        }
+
+        impl Torrent {
+            pub fn migrate(self) -> super::current::Torrent {
+                let s = self.session;
+                let session = next::Session {
+                    announce: s.announce,
+                    creator: s.creator,
+                    comment: s.comment,
+                    pieces: s.pieces,
+                    uploaded: s.uploaded,
+                    downloaded: s.downloaded,
+                    status: s.status,
+                    path: s.path,
+                    priority: s.priority,
+                    priorities: s.priorities,
+                    created: s.created,
+                    throttle_ul: s.throttle_ul,
+                    throttle_dl: s.throttle_dl,
+                    trackers: s.trackers,
+                    announce_key: s.announce_key,
+                    tracker_headers: s.tracker_headers,
+                    announce_ip: None,
+                };
+                next::Torrent {
+                    info: self.info,
+                    session,
+                }
+                .migrate()
+            }
+        }
+    }
+
+    pub mod ver_7d24c1 {
+        use chrono::{DateTime, Utc};
+
+        use super::ver_3c1e72 as prev;
+        use super::ver_c2a9e4 as next;
+        use super::Bitfield;
+
+        pub use prev::{File, Info, Status, StatusState};
+
+        #[derive(Debug, PartialEq)]
+        pub struct Torrent {
+            pub info: Info,
+            pub session: Session,
+        }
+
+        #[derive(Deserialize, Debug, PartialEq, Serialize)]
+        pub struct Session {
+            pub announce: Option<String>,
+            pub creator: Option<String>,
+            pub comment: Option<String>,
+            pub pieces: Bitfield,
+            pub uploaded: u64,
+            pub downloaded: u64,
+            pub status: Status,
+            pub path: Option<String>,
+            pub priority: u8,
+            pub priorities: Vec<u8>,
+            pub created: DateTime<Utc>,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            /// Tracker urls grouped into announce tiers
+            pub trackers: Vec<Vec<String>>,
+            /// Random `key` sent with every tracker announce
+            pub announce_key: u32,
+        }
+
+        impl Torrent {
+            pub fn migrate(self) -> super::current::Torrent {
+                let s = self.session;
+                let session = next::Session {
+                    announce: s.announce,
+                    creator: s.creator,
+                    comment: s.comment,
+                    pieces: s.pieces,
+                    uploaded: s.uploaded,
+                    downloaded: s.downloaded,
+                    status: s.status,
+                    path: s.path,
+                    priority: s.priority,
+                    priorities: s.priorities,
+                    created: s.created,
+                    throttle_ul: s.throttle_ul,
+                    throttle_dl: s.throttle_dl,
+                    trackers: s.trackers,
+                    announce_key: s.announce_key,
+                    tracker_headers: vec![],
+                };
+                next::Torrent {
+                    info: self.info,
+                    session,
+                }
+                .migrate()
+            }
+        }
+    }
+
+    pub mod ver_3c1e72 {
+        use chrono::{DateTime, Utc};
+
+        use super::ver_7d24c1 as next;
+        use super::ver_bfbf28 as prev;
+        use super::Bitfield;
+
+        pub use prev::{File, Info, Status, StatusState};
+
+        #[derive(Debug, PartialEq)]
+        pub struct Torrent {
+            pub info: Info,
+            pub session: Session,
+        }
+
+        #[derive(Deserialize, Debug, PartialEq, Serialize)]
+        pub struct Session {
+            pub announce: Option<String>,
+            pub creator: Option<String>,
+            pub comment: Option<String>,
+            pub pieces: Bitfield,
+            pub uploaded: u64,
+            pub downloaded: u64,
+            pub status: Status,
+            pub path: Option<String>,
+            pub priority: u8,
+            pub priorities: Vec<u8>,
+            pub created: DateTime<Utc>,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            /// Tracker urls grouped into announce tiers
+            pub trackers: Vec<Vec<String>>,
+        }
+
+        impl Torrent {
+            pub fn migrate(self) -> super::current::Torrent {
+                let s = self.session;
+                let session = next::Session {
+                    announce: s.announce,
+                    creator: s.creator,
+                    comment: s.comment,
+                    pieces: s.pieces,
+                    uploaded: s.uploaded,
+                    downloaded: s.downloaded,
+                    status: s.status,
+                    path: s.path,
+                    priority: s.priority,
+                    priorities: s.priorities,
+                    created: s.created,
+                    throttle_ul: s.throttle_ul,
+                    throttle_dl: s.throttle_dl,
+                    trackers: s.trackers,
+                    announce_key: rand::random(),
+                };
+                next::Torrent {
+                    info: self.info,
+                    session,
+                }
+                .migrate()
+            }
+        }
+    }
+
     pub mod ver_bfbf28 {
         use chrono::{DateTime, Utc};
 
+        use super::ver_3c1e72 as next;
         use super::ver_fa1b6f as prev;
         use super::Bitfield;
 
@@ -98,9 +1072,31 @@ pub mod torrent {
             pub piece_idx: Vec<(usize, u64)>,
         }
 
-        impl super::Torrent {
-            pub fn migrate(self) -> Self {
-                self
+        impl Torrent {
+            pub fn migrate(self) -> super::current::Torrent {
+                let s = self.session;
+                // Tier information wasn't kept, so each tracker is tried in turn
+                let session = next::Session {
+                    announce: s.announce,
+                    creator: s.creator,
+                    comment: s.comment,
+                    pieces: s.pieces,
+                    uploaded: s.uploaded,
+                    downloaded: s.downloaded,
+                    status: s.status,
+                    path: s.path,
+                    priority: s.priority,
+                    priorities: s.priorities,
+                    created: s.created,
+                    throttle_ul: s.throttle_ul,
+                    throttle_dl: s.throttle_dl,
+                    trackers: s.trackers.into_iter().map(|url| vec![url]).collect(),
+                };
+                next::Torrent {
+                    info: self.info,
+                    session,
+                }
+                .migrate()
             }
         }
     }
@@ -146,6 +1142,7 @@ pub mod torrent {
 
         #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
         pub struct File {
+            #[serde(with = "crate::raw_path")]
             pub path: PathBuf,
             pub length: u64,
         }
@@ -168,7 +1165,7 @@ pub mod torrent {
         }
 
         impl Session {
-            pub fn migrate(self) -> next::Torrent {
+            pub fn migrate(self) -> super::current::Torrent {
                 let session = next::Session {
                     announce: self.info.announce,
                     creator: self.info.creator,
@@ -196,7 +1193,7 @@ pub mod torrent {
                     be_name: self.info.be_name,
                     piece_idx: self.info.piece_idx,
                 };
-                next::Torrent { session, info }
+                next::Torrent { session, info }.migrate()
             }
         }
     }
@@ -369,26 +1366,11 @@ pub mod torrent {
 
         impl Session {
             pub fn migrate(self) -> super::current::Torrent {
-                let mut state = next::StatusState::Complete;
-                for i in 0..self.pieces.len - 1 {
-                    if !(self.pieces.data[i as usize]) != 0 {
-                        state = next::StatusState::Incomplete;
-                        break;
-                    }
-                }
-                if !self.pieces.data.is_empty() {
-                    match (self.pieces.len % 8, *self.pieces.data.last().unwrap()) {
-                        (0, 0xFF)
-                        | (7, 0xFE)
-                        | (6, 0xFC)
-                        | (5, 0xF8)
-                        | (4, 0xF0)
-                        | (3, 0xE0)
-                        | (2, 0xC0)
-                        | (1, 0x80) => {}
-                        _ => state = next::StatusState::Incomplete,
-                    }
-                }
+                let state = if complete(&self.pieces) {
+                    next::StatusState::Complete
+                } else {
+                    next::StatusState::Incomplete
+                };
                 let paused = matches!(self.status, Status::Paused);
                 let piece_idx = generate_piece_idx(
                     self.info.hashes.len(),
@@ -432,6 +1414,19 @@ pub mod torrent {
             }
         }
 
+        /// Whether every piece is set: each whole byte is 0xFF, and a partial last byte
+        /// has exactly its pieces set.
+        fn complete(pieces: &Bitfield) -> bool {
+            let full = (pieces.len / 8) as usize;
+            let tail = (pieces.len % 8) as u32;
+            let size = full + usize::from(tail != 0);
+            if pieces.len == 0 || pieces.data.len() != size {
+                return false;
+            }
+            pieces.data[..full].iter().all(|&b| b == 0xFF)
+                && (tail == 0 || pieces.data[full] == 0xFF << (8 - tail))
+        }
+
         fn generate_piece_idx(pieces: usize, pl: u64, files: &[next::File]) -> Vec<(usize, u64)> {
             let mut piece_idx = Vec::with_capacity(pieces);
             let mut file = 0;
@@ -492,6 +1487,55 @@ pub mod torrent {
     }
 }
 
+/// Serializes paths as their raw bytes, rather than as strings which would reject
+/// names which aren't valid UTF-8. Bincode encodes both the same way, so paths
+/// written as strings load unchanged.
+mod raw_path {
+    use std::fmt;
+    use std::path::{Path, PathBuf};
+
+    use serde::de::{self, Visitor};
+    use serde::{Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(path: &Path, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_bytes(path.as_os_str().as_encoded_bytes())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<PathBuf, D::Error> {
+        d.deserialize_byte_buf(PathVisitor)
+    }
+
+    struct PathVisitor;
+
+    impl<'de> Visitor<'de> for PathVisitor {
+        type Value = PathBuf;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a path")
+        }
+
+        fn visit_str<E: de::Error>(self, v: &str) -> Result<PathBuf, E> {
+            Ok(PathBuf::from(v))
+        }
+
+        fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<PathBuf, E> {
+            self.visit_byte_buf(v.to_vec())
+        }
+
+        #[cfg(unix)]
+        fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<PathBuf, E> {
+            use std::os::unix::ffi::OsStringExt;
+
+            Ok(PathBuf::from(std::ffi::OsString::from_vec(v)))
+        }
+
+        #[cfg(not(unix))]
+        fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<PathBuf, E> {
+            Ok(PathBuf::from(String::from_utf8_lossy(&v).into_owned()))
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use chrono::DateTime;
@@ -499,6 +1543,311 @@ mod tests {
 
     use super::torrent::*;
 
+    #[test]
+    fn ver_2f9d61_deserialize() {
+        let mut torrent = ver_2f9d61_torrent_instance(0xDEAD_BEEF);
+        torrent.session.announce_ip = Some("203.0.113.7".parse().unwrap());
+        torrent.session.partial = vec![(
+            3,
+            Bitfield {
+                len: 10,
+                data: vec![0b1100_0000, 0b0100_0000].into_boxed_slice(),
+            },
+        )];
+        torrent.session.max_peers = Some(20);
+        torrent.session.tracker_ids = vec![(
+            "https://example.com:1234/tracker".to_string(),
+            "8F2C0B9D4E6A1735C0DE5B2A9E4F7D3186A0C2E1".to_string(),
+        )];
+        torrent.session.file_order = vec![2, 0];
+        torrent.session.metadata = Some((
+            Bitfield {
+                len: 3,
+                data: vec![0b1010_0000].into_boxed_slice(),
+            },
+            vec![0xAB; 40_000],
+        ));
+        torrent.session.verify_on_read = Some(true);
+        torrent.session.allocation = Some(ver_58e0c3::Allocation::SkipUnwanted);
+        torrent.session.preset_priorities = vec![(0, 5), (2, 0)];
+        let info = bincode::serialize(&torrent.info).unwrap();
+        let session = bincode::serialize(&torrent.session).unwrap();
+        let LoadResult::Ok(loaded) = load(&session, Some(&info)) else {
+            panic!("expected current version");
+        };
+        assert_eq!(loaded, torrent);
+    }
+
+    #[test]
+    fn ver_5f166d_migrate_complete() {
+        use current::StatusState;
+
+        fn state(len: u64, data: &[u8]) -> StatusState {
+            let session = ver_5f166d::Session {
+                info: ver_5f166d::Info {
+                    name: "Hello world!".to_string(),
+                    announce: String::new(),
+                    piece_len: 16384,
+                    total_len: len * 16384,
+                    hashes: vec![vec![0; 20]; len as usize],
+                    hash: [1; 20],
+                    files: vec![ver_249b1b::File {
+                        path: PathBuf::from("file1"),
+                        length: len * 16384,
+                    }],
+                    private: false,
+                    be_name: None,
+                },
+                pieces: Bitfield {
+                    len,
+                    data: data.to_vec().into_boxed_slice(),
+                },
+                uploaded: 0,
+                downloaded: 0,
+                status: ver_5f166d::Status::Seeding,
+                path: None,
+                priority: 3,
+                priorities: vec![3],
+                created: DateTime::from_timestamp(946684799, 0).unwrap(),
+                throttle_ul: None,
+                throttle_dl: None,
+            };
+            session.migrate().session.status.state
+        }
+
+        for (len, data) in [
+            (2, &[0xC0][..]),
+            (7, &[0xFE]),
+            (8, &[0xFF]),
+            (9, &[0xFF, 0x80]),
+            (16, &[0xFF, 0xFF]),
+        ] {
+            assert_eq!(state(len, data), StatusState::Complete, "{} pieces", len);
+        }
+        for (len, data) in [
+            (0, &[][..]),
+            (2, &[0x80]),
+            (2, &[0x40]),
+            // Spare bits set
+            (2, &[0xFF]),
+            (7, &[0xFC]),
+            (8, &[0x7F]),
+            (8, &[0xFE]),
+            (9, &[0xFF, 0x00]),
+            (9, &[0x7F, 0x80]),
+            (9, &[0xFF]),
+            (16, &[0xFF, 0xFE]),
+            (16, &[0xFE, 0xFF]),
+        ] {
+            assert_eq!(state(len, data), StatusState::Incomplete, "{:?}", data);
+        }
+    }
+
+    #[test]
+    fn raw_file_paths() {
+        use std::ffi::OsString;
+        use std::os::unix::ffi::OsStringExt;
+
+        // Paths which are valid UTF-8 are encoded just as they were as strings
+        let file = ver_2f9d61::File {
+            path: PathBuf::from("file1"),
+            length: 1024,
+        };
+        assert_eq!(
+            bincode::serialize(&file).unwrap(),
+            bincode::serialize(&("file1", 1024u64)).unwrap()
+        );
+
+        // Shift-JIS names survive a round trip
+        let mut torrent = ver_2f9d61_torrent_instance(0xDEAD_BEEF);
+        let sjis = b"\x83\x65\x83\x58\x83\x67/\x93\xfa\x96\x7b\x8c\xea.txt".to_vec();
+        torrent.info.files[0].path = PathBuf::from(OsString::from_vec(sjis));
+        let info = bincode::serialize(&torrent.info).unwrap();
+        let session = bincode::serialize(&torrent.session).unwrap();
+        let LoadResult::Ok(loaded) = load(&session, Some(&info)) else {
+            panic!("expected current version");
+        };
+        assert_eq!(loaded, torrent);
+    }
+
+    #[test]
+    fn ver_2f9d61_migrate_from_ver_58e0c3() {
+        let mut torrent = ver_58e0c3_torrent_instance(0xDEAD_BEEF);
+        torrent.session.allocation = Some(ver_58e0c3::Allocation::Full);
+        let info = bincode::serialize(&torrent.info).unwrap();
+        let session = bincode::serialize(&torrent.session).unwrap();
+        let LoadResult::Migrated(migrated) = load(&session, Some(&info)) else {
+            panic!("expected migration");
+        };
+        let mut expected = ver_2f9d61_torrent_instance(0xDEAD_BEEF);
+        expected.session.allocation = torrent.session.allocation;
+        assert!(expected.session.preset_priorities.is_empty());
+        assert_eq!(migrated, expected);
+    }
+
+    #[test]
+    fn ver_58e0c3_migrate_from_ver_d7a35b() {
+        let mut torrent = ver_d7a35b_torrent_instance(0xDEAD_BEEF);
+        torrent.session.verify_on_read = Some(true);
+        let info = bincode::serialize(&torrent.info).unwrap();
+        let session = bincode::serialize(&torrent.session).unwrap();
+        let LoadResult::Migrated(migrated) = load(&session, Some(&info)) else {
+            panic!("expected migration");
+        };
+        let mut expected = ver_2f9d61_torrent_instance(0xDEAD_BEEF);
+        expected.session.verify_on_read = torrent.session.verify_on_read;
+        assert_eq!(migrated, expected);
+    }
+
+    #[test]
+    fn ver_d7a35b_migrate_from_ver_e81c4d() {
+        let mut torrent = ver_e81c4d_torrent_instance(0xDEAD_BEEF);
+        torrent.session.max_peers = Some(20);
+        let info = bincode::serialize(&torrent.info).unwrap();
+        let session = bincode::serialize(&torrent.session).unwrap();
+        let LoadResult::Migrated(migrated) = load(&session, Some(&info)) else {
+            panic!("expected migration");
+        };
+        let mut expected = ver_2f9d61_torrent_instance(0xDEAD_BEEF);
+        expected.session.max_peers = torrent.session.max_peers;
+        assert_eq!(migrated, expected);
+    }
+
+    #[test]
+    fn ver_e81c4d_migrate_from_ver_a41c5e() {
+        let mut torrent = ver_a41c5e_torrent_instance(0xDEAD_BEEF);
+        torrent.session.file_order = vec![1];
+        let info = bincode::serialize(&torrent.info).unwrap();
+        let session = bincode::serialize(&torrent.session).unwrap();
+        let LoadResult::Migrated(migrated) = load(&session, Some(&info)) else {
+            panic!("expected migration");
+        };
+        let mut expected = ver_2f9d61_torrent_instance(0xDEAD_BEEF);
+        expected.session.file_order = torrent.session.file_order;
+        assert_eq!(migrated, expected);
+    }
+
+    #[test]
+    fn ver_a41c5e_migrate_from_ver_6b0e4c() {
+        let mut torrent = ver_6b0e4c_torrent_instance(0xDEAD_BEEF);
+        torrent.session.tracker_ids = vec![(
+            "https://example.com:1234/tracker".to_string(),
+            "8F2C0B9D4E6A1735C0DE5B2A9E4F7D3186A0C2E1".to_string(),
+        )];
+        let info = bincode::serialize(&torrent.info).unwrap();
+        let session = bincode::serialize(&torrent.session).unwrap();
+        let LoadResult::Migrated(migrated) = load(&session, Some(&info)) else {
+            panic!("expected migration");
+        };
+        let mut expected = ver_2f9d61_torrent_instance(0xDEAD_BEEF);
+        expected.session.tracker_ids = torrent.session.tracker_ids;
+        assert_eq!(migrated, expected);
+    }
+
+    #[test]
+    fn ver_6b0e4c_migrate_from_ver_91d3a0() {
+        let mut torrent = ver_91d3a0_torrent_instance(0xDEAD_BEEF);
+        torrent.session.max_peers = Some(20);
+        let info = bincode::serialize(&torrent.info).unwrap();
+        let session = bincode::serialize(&torrent.session).unwrap();
+        let LoadResult::Migrated(migrated) = load(&session, Some(&info)) else {
+            panic!("expected migration");
+        };
+        // Tracker ids are left for the daemon to derive from the urls
+        let mut expected = ver_2f9d61_torrent_instance(0xDEAD_BEEF);
+        expected.session.max_peers = Some(20);
+        assert_eq!(migrated, expected);
+    }
+
+    #[test]
+    fn ver_91d3a0_migrate_from_ver_e52d07() {
+        let mut torrent = ver_e52d07_torrent_instance(0xDEAD_BEEF);
+        torrent.session.partial = vec![(
+            3,
+            Bitfield {
+                len: 10,
+                data: vec![0b1100_0000, 0b0100_0000].into_boxed_slice(),
+            },
+        )];
+        let info = bincode::serialize(&torrent.info).unwrap();
+        let session = bincode::serialize(&torrent.session).unwrap();
+        let LoadResult::Migrated(migrated) = load(&session, Some(&info)) else {
+            panic!("expected migration");
+        };
+        let mut expected = ver_2f9d61_torrent_instance(0xDEAD_BEEF);
+        expected.session.partial = torrent.session.partial;
+        assert_eq!(migrated, expected);
+    }
+
+    #[test]
+    fn ver_e52d07_migrate_from_ver_4b7e19() {
+        let mut torrent = ver_4b7e19_torrent_instance(0xDEAD_BEEF);
+        let ip = Some("203.0.113.7".parse().unwrap());
+        torrent.session.announce_ip = ip;
+        let info = bincode::serialize(&torrent.info).unwrap();
+        let session = bincode::serialize(&torrent.session).unwrap();
+        let LoadResult::Migrated(migrated) = load(&session, Some(&info)) else {
+            panic!("expected migration");
+        };
+        let mut expected = ver_2f9d61_torrent_instance(0xDEAD_BEEF);
+        expected.session.announce_ip = ip;
+        assert_eq!(migrated, expected);
+    }
+
+    #[test]
+    fn ver_4b7e19_migrate_from_ver_c2a9e4() {
+        let mut torrent = ver_c2a9e4_torrent_instance(0xDEAD_BEEF);
+        let headers = vec![(
+            "https://example.com:1234/tracker".to_string(),
+            vec!["Cookie: uid=1; pass=abc".to_string()],
+        )];
+        torrent.session.tracker_headers = headers.clone();
+        let info = bincode::serialize(&torrent.info).unwrap();
+        let session = bincode::serialize(&torrent.session).unwrap();
+        let LoadResult::Migrated(migrated) = load(&session, Some(&info)) else {
+            panic!("expected migration");
+        };
+        let mut expected = ver_2f9d61_torrent_instance(0xDEAD_BEEF);
+        expected.session.tracker_headers = headers;
+        assert_eq!(migrated, expected);
+    }
+
+    #[test]
+    fn ver_c2a9e4_migrate_from_ver_7d24c1() {
+        let torrent = ver_7d24c1_torrent_instance(0xDEAD_BEEF);
+        let info = bincode::serialize(&torrent.info).unwrap();
+        let session = bincode::serialize(&torrent.session).unwrap();
+        let LoadResult::Migrated(migrated) = load(&session, Some(&info)) else {
+            panic!("expected migration");
+        };
+        assert_eq!(migrated, ver_2f9d61_torrent_instance(0xDEAD_BEEF));
+    }
+
+    #[test]
+    fn ver_7d24c1_migrate_from_ver_3c1e72() {
+        let torrent = ver_3c1e72_torrent_instance();
+        let info = bincode::serialize(&torrent.info).unwrap();
+        let session = bincode::serialize(&torrent.session).unwrap();
+        let LoadResult::Migrated(migrated) = load(&session, Some(&info)) else {
+            panic!("expected migration");
+        };
+        // The key is freshly generated, so only check that everything else carried over
+        let key = migrated.session.announce_key;
+        assert_eq!(migrated, ver_2f9d61_torrent_instance(key));
+    }
+
+    #[test]
+    fn ver_3c1e72_migrate_from_ver_bfbf28() {
+        let torrent = ver_bfbf28_torrent_instance();
+        let info = bincode::serialize(&torrent.info).unwrap();
+        let session = bincode::serialize(&torrent.session).unwrap();
+        let LoadResult::Migrated(migrated) = load(&session, Some(&info)) else {
+            panic!("expected migration");
+        };
+        let key = migrated.session.announce_key;
+        assert_eq!(migrated, ver_2f9d61_torrent_instance(key));
+    }
+
     #[test]
     fn ver_bfbf28_serialize() {
         let torrent = ver_bfbf28_torrent_instance();
@@ -522,7 +1871,9 @@ mod tests {
         let LoadResult::Migrated(torrent) = load(VER_FA1B6F_SESSION_SERIALIZATION, None) else {
             panic!("expected migration");
         };
-        assert_eq!(torrent, ver_bfbf28_torrent_instance());
+        let mut expected = ver_bfbf28_torrent_instance().migrate();
+        expected.session.announce_key = torrent.session.announce_key;
+        assert_eq!(torrent, expected);
     }
 
     #[test]
@@ -541,6 +1892,360 @@ mod tests {
         );
     }
 
+    fn ver_2f9d61_torrent_instance(announce_key: u32) -> ver_2f9d61::Torrent {
+        let torrent = ver_58e0c3_torrent_instance(announce_key);
+        let s = torrent.session;
+        ver_2f9d61::Torrent {
+            info: torrent.info,
+            session: ver_2f9d61::Session {
+                announce: s.announce,
+                creator: s.creator,
+                comment: s.comment,
+                pieces: s.pieces,
+                uploaded: s.uploaded,
+                downloaded: s.downloaded,
+                status: s.status,
+                path: s.path,
+                priority: s.priority,
+                priorities: s.priorities,
+                created: s.created,
+                throttle_ul: s.throttle_ul,
+                throttle_dl: s.throttle_dl,
+                trackers: s.trackers,
+                announce_key: s.announce_key,
+                tracker_headers: s.tracker_headers,
+                announce_ip: s.announce_ip,
+                partial: s.partial,
+                max_peers: s.max_peers,
+                tracker_ids: s.tracker_ids,
+                file_order: s.file_order,
+                metadata: s.metadata,
+                verify_on_read: s.verify_on_read,
+                allocation: s.allocation,
+                preset_priorities: Vec::new(),
+            },
+        }
+    }
+
+    fn ver_58e0c3_torrent_instance(announce_key: u32) -> ver_58e0c3::Torrent {
+        let torrent = ver_d7a35b_torrent_instance(announce_key);
+        let s = torrent.session;
+        ver_58e0c3::Torrent {
+            info: torrent.info,
+            session: ver_58e0c3::Session {
+                announce: s.announce,
+                creator: s.creator,
+                comment: s.comment,
+                pieces: s.pieces,
+                uploaded: s.uploaded,
+                downloaded: s.downloaded,
+                status: s.status,
+                path: s.path,
+                priority: s.priority,
+                priorities: s.priorities,
+                created: s.created,
+                throttle_ul: s.throttle_ul,
+                throttle_dl: s.throttle_dl,
+                trackers: s.trackers,
+                announce_key: s.announce_key,
+                tracker_headers: s.tracker_headers,
+                announce_ip: s.announce_ip,
+                partial: s.partial,
+                max_peers: s.max_peers,
+                tracker_ids: s.tracker_ids,
+                file_order: s.file_order,
+                metadata: s.metadata,
+                verify_on_read: s.verify_on_read,
+                allocation: None,
+            },
+        }
+    }
+
+    fn ver_d7a35b_torrent_instance(announce_key: u32) -> ver_d7a35b::Torrent {
+        let torrent = ver_e81c4d_torrent_instance(announce_key);
+        let s = torrent.session;
+        ver_d7a35b::Torrent {
+            info: torrent.info,
+            session: ver_d7a35b::Session {
+                announce: s.announce,
+                creator: s.creator,
+                comment: s.comment,
+                pieces: s.pieces,
+                uploaded: s.uploaded,
+                downloaded: s.downloaded,
+                status: s.status,
+                path: s.path,
+                priority: s.priority,
+                priorities: s.priorities,
+                created: s.created,
+                throttle_ul: s.throttle_ul,
+                throttle_dl: s.throttle_dl,
+                trackers: s.trackers,
+                announce_key,
+                tracker_headers: s.tracker_headers,
+                announce_ip: s.announce_ip,
+                partial: s.partial,
+                max_peers: s.max_peers,
+                tracker_ids: s.tracker_ids,
+                file_order: s.file_order,
+                metadata: s.metadata,
+                verify_on_read: None,
+            },
+        }
+    }
+
+    fn ver_e81c4d_torrent_instance(announce_key: u32) -> ver_e81c4d::Torrent {
+        let torrent = ver_a41c5e_torrent_instance(announce_key);
+        let s = torrent.session;
+        ver_e81c4d::Torrent {
+            info: torrent.info,
+            session: ver_e81c4d::Session {
+                announce: s.announce,
+                creator: s.creator,
+                comment: s.comment,
+                pieces: s.pieces,
+                uploaded: s.uploaded,
+                downloaded: s.downloaded,
+                status: s.status,
+                path: s.path,
+                priority: s.priority,
+                priorities: s.priorities,
+                created: s.created,
+                throttle_ul: s.throttle_ul,
+                throttle_dl: s.throttle_dl,
+                trackers: s.trackers,
+                announce_key,
+                tracker_headers: s.tracker_headers,
+                announce_ip: s.announce_ip,
+                partial: s.partial,
+                max_peers: s.max_peers,
+                tracker_ids: s.tracker_ids,
+                file_order: s.file_order,
+                metadata: None,
+            },
+        }
+    }
+
+    fn ver_a41c5e_torrent_instance(announce_key: u32) -> ver_a41c5e::Torrent {
+        let torrent = ver_6b0e4c_torrent_instance(announce_key);
+        let s = torrent.session;
+        ver_a41c5e::Torrent {
+            info: torrent.info,
+            session: ver_a41c5e::Session {
+                announce: s.announce,
+                creator: s.creator,
+                comment: s.comment,
+                pieces: s.pieces,
+                uploaded: s.uploaded,
+                downloaded: s.downloaded,
+                status: s.status,
+                path: s.path,
+                priority: s.priority,
+                priorities: s.priorities,
+                created: s.created,
+                throttle_ul: s.throttle_ul,
+                throttle_dl: s.throttle_dl,
+                trackers: s.trackers,
+                announce_key,
+                tracker_headers: s.tracker_headers,
+                announce_ip: s.announce_ip,
+                partial: s.partial,
+                max_peers: s.max_peers,
+                tracker_ids: s.tracker_ids,
+                file_order: Vec::new(),
+            },
+        }
+    }
+
+    fn ver_6b0e4c_torrent_instance(announce_key: u32) -> ver_6b0e4c::Torrent {
+        let torrent = ver_91d3a0_torrent_instance(announce_key);
+        let s = torrent.session;
+        ver_6b0e4c::Torrent {
+            info: torrent.info,
+            session: ver_6b0e4c::Session {
+                announce: s.announce,
+                creator: s.creator,
+                comment: s.comment,
+                pieces: s.pieces,
+                uploaded: s.uploaded,
+                downloaded: s.downloaded,
+                status: s.status,
+                path: s.path,
+                priority: s.priority,
+                priorities: s.priorities,
+                created: s.created,
+                throttle_ul: s.throttle_ul,
+                throttle_dl: s.throttle_dl,
+                trackers: s.trackers,
+                announce_key,
+                tracker_headers: s.tracker_headers,
+                announce_ip: s.announce_ip,
+                partial: s.partial,
+                max_peers: s.max_peers,
+                tracker_ids: Vec::new(),
+            },
+        }
+    }
+
+    fn ver_91d3a0_torrent_instance(announce_key: u32) -> ver_91d3a0::Torrent {
+        let torrent = ver_e52d07_torrent_instance(announce_key);
+        let s = torrent.session;
+        ver_91d3a0::Torrent {
+            info: torrent.info,
+            session: ver_91d3a0::Session {
+                announce: s.announce,
+                creator: s.creator,
+                comment: s.comment,
+                pieces: s.pieces,
+                uploaded: s.uploaded,
+                downloaded: s.downloaded,
+                status: s.status,
+                path: s.path,
+                priority: s.priority,
+                priorities: s.priorities,
+                created: s.created,
+                throttle_ul: s.throttle_ul,
+                throttle_dl: s.throttle_dl,
+                trackers: s.trackers,
+                announce_key,
+                tracker_headers: s.tracker_headers,
+                announce_ip: s.announce_ip,
+                partial: s.partial,
+                max_peers: None,
+            },
+        }
+    }
+
+    fn ver_e52d07_torrent_instance(announce_key: u32) -> ver_e52d07::Torrent {
+        let torrent = ver_4b7e19_torrent_instance(announce_key);
+        let s = torrent.session;
+        ver_e52d07::Torrent {
+            info: torrent.info,
+            session: ver_e52d07::Session {
+                announce: s.announce,
+                creator: s.creator,
+                comment: s.comment,
+                pieces: s.pieces,
+                uploaded: s.uploaded,
+                downloaded: s.downloaded,
+                status: s.status,
+                path: s.path,
+                priority: s.priority,
+                priorities: s.priorities,
+                created: s.created,
+                throttle_ul: s.throttle_ul,
+                throttle_dl: s.throttle_dl,
+                trackers: s.trackers,
+                announce_key,
+                tracker_headers: s.tracker_headers,
+                announce_ip: s.announce_ip,
+                partial: Vec::new(),
+            },
+        }
+    }
+
+    fn ver_4b7e19_torrent_instance(announce_key: u32) -> ver_4b7e19::Torrent {
+        let torrent = ver_c2a9e4_torrent_instance(announce_key);
+        let s = torrent.session;
+        ver_4b7e19::Torrent {
+            info: torrent.info,
+            session: ver_4b7e19::Session {
+                announce: s.announce,
+                creator: s.creator,
+                comment: s.comment,
+                pieces: s.pieces,
+                uploaded: s.uploaded,
+                downloaded: s.downloaded,
+                status: s.status,
+                path: s.path,
+                priority: s.priority,
+                priorities: s.priorities,
+                created: s.created,
+                throttle_ul: s.throttle_ul,
+                throttle_dl: s.throttle_dl,
+                trackers: s.trackers,
+                announce_key,
+                tracker_headers: s.tracker_headers,
+                announce_ip: None,
+            },
+        }
+    }
+
+    fn ver_c2a9e4_torrent_instance(announce_key: u32) -> ver_c2a9e4::Torrent {
+        let torrent = ver_7d24c1_torrent_instance(announce_key);
+        let s = torrent.session;
+        ver_c2a9e4::Torrent {
+            info: torrent.info,
+            session: ver_c2a9e4::Session {
+                announce: s.announce,
+                creator: s.creator,
+                comment: s.comment,
+                pieces: s.pieces,
+                uploaded: s.uploaded,
+                downloaded: s.downloaded,
+                status: s.status,
+                path: s.path,
+                priority: s.priority,
+                priorities: s.priorities,
+                created: s.created,
+                throttle_ul: s.throttle_ul,
+                throttle_dl: s.throttle_dl,
+                trackers: s.trackers,
+                announce_key,
+                tracker_headers: vec![],
+            },
+        }
+    }
+
+    fn ver_7d24c1_torrent_instance(announce_key: u32) -> ver_7d24c1::Torrent {
+        let torrent = ver_3c1e72_torrent_instance();
+        let s = torrent.session;
+        ver_7d24c1::Torrent {
+            info: torrent.info,
+            session: ver_7d24c1::Session {
+                announce: s.announce,
+                creator: s.creator,
+                comment: s.comment,
+                pieces: s.pieces,
+                uploaded: s.uploaded,
+                downloaded: s.downloaded,
+                status: s.status,
+                path: s.path,
+                priority: s.priority,
+                priorities: s.priorities,
+                created: s.created,
+                throttle_ul: s.throttle_ul,
+                throttle_dl: s.throttle_dl,
+                trackers: s.trackers,
+                announce_key,
+            },
+        }
+    }
+
+    fn ver_3c1e72_torrent_instance() -> ver_3c1e72::Torrent {
+        let torrent = ver_bfbf28_torrent_instance();
+        let s = torrent.session;
+        ver_3c1e72::Torrent {
+            info: torrent.info,
+            session: ver_3c1e72::Session {
+                announce: s.announce,
+                creator: s.creator,
+                comment: s.comment,
+                pieces: s.pieces,
+                uploaded: s.uploaded,
+                downloaded: s.downloaded,
+                status: s.status,
+                path: s.path,
+                priority: s.priority,
+                priorities: s.priorities,
+                created: s.created,
+                throttle_ul: s.throttle_ul,
+                throttle_dl: s.throttle_dl,
+                trackers: vec![vec!["https://example.com:1234/tracker".to_string()]],
+            },
+        }
+    }
+
     fn ver_bfbf28_torrent_instance() -> ver_bfbf28::Torrent {
         use ver_bfbf28::*;
 
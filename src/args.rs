@@ -8,6 +8,12 @@ use crate::log;
 pub struct Args {
     pub config: Option<String>,
     pub level: Option<log::LogLevel>,
+    /// Write a sycli profile for this instance, unless sycli is already configured
+    pub setup_cli: bool,
+    /// Serve RPC over TLS if a config is generated on first run
+    pub tls: bool,
+    /// Print how to connect to RPC and exit
+    pub print_connection: bool,
 }
 
 pub fn args() -> Args {
@@ -16,6 +22,21 @@ pub fn args() -> Args {
     opts.optflag("h", "help", "Show help message.");
     opts.optflag("d", "debug", "Enable debug logging.");
     opts.optopt("c", "config", "Use config file.", "FILE");
+    opts.optflag(
+        "",
+        "setup-cli",
+        "Write a sycli profile for connecting, unless sycli has a config.",
+    );
+    opts.optflag(
+        "",
+        "tls",
+        "Serve RPC over TLS with a self-signed certificate, if a config is generated on first run.",
+    );
+    opts.optflag(
+        "",
+        "print-connection",
+        "Print the RPC address and password, then exit.",
+    );
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
         Err(f) => {
@@ -31,6 +52,9 @@ pub fn args() -> Args {
     let mut args = Args {
         config: None,
         level: None,
+        setup_cli: matches.opt_present("setup-cli"),
+        tls: matches.opt_present("tls"),
+        print_connection: matches.opt_present("print-connection"),
     };
 
     if matches.opt_present("d") {
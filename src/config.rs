@@ -1,13 +1,19 @@
 use std::collections::HashMap;
-use std::net::{SocketAddr, ToSocketAddrs};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::path::Path;
 use std::{fs, process};
 
+use chrono::Weekday;
 use ip_network::IpNetwork;
 use ip_network_table::IpNetworkTable;
 use thiserror::Error;
 
 use crate::args;
 use crate::util::UnlimitedOrU64;
+use crate::util::http::Headers;
+use crate::util::ip_filter;
+
+pub use crate::rpc::resource::Allocation;
 
 #[derive(Debug, Error)]
 pub enum Error {
@@ -24,19 +30,27 @@ pub enum Error {
 pub struct Config {
     pub port: u16,
     pub max_dl: u32,
+    pub crash_reports: bool,
     pub trk: TrkConfig,
     pub dht: DhtConfig,
+    pub dns: DnsConfig,
     pub rpc: RpcConfig,
     pub disk: DiskConfig,
     pub net: NetConfig,
     pub peer: PeerConfig,
+    pub idle: IdleConfig,
+    pub quota: QuotaConfig,
+    pub watch_dirs: Vec<WatchDir>,
+    pub scheduler: Vec<ScheduleRule>,
     pub ip_filter: IpNetworkTable<u8>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct DhtConfig {
     pub port: u16,
     pub bootstrap_node: Option<SocketAddr>,
+    pub bootstrap_node6: Option<SocketAddr>,
+    pub read_only: bool,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -46,22 +60,34 @@ pub struct ConfigFile {
     #[serde(default = "default_max_dl")]
     pub max_dl: u32,
     #[serde(default)]
+    pub crash_reports: bool,
+    #[serde(default)]
     pub rpc: RpcConfig,
     #[serde(default)]
     pub tracker: TrkConfig,
     #[serde(default)]
     pub dht: DhtConfigFile,
     #[serde(default)]
+    pub dns: DnsConfig,
+    #[serde(default)]
     pub disk: DiskConfig,
     #[serde(default)]
     pub net: NetConfig,
     #[serde(default)]
     pub peer: PeerConfig,
+    #[serde(default)]
+    pub idle: IdleConfig,
+    #[serde(default)]
+    pub quota: QuotaConfig,
+    #[serde(default)]
+    pub watch_dirs: Vec<WatchDir>,
+    #[serde(default)]
+    pub scheduler: Vec<ScheduleRule>,
     #[serde(default = "default_ip_filter")]
     pub ip_filter: HashMap<IpNetwork, u8>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct RpcConfig {
     #[serde(default = "default_rpc_port")]
     pub port: u16,
@@ -75,12 +101,51 @@ pub struct RpcConfig {
     pub ssl_cert: String,
     #[serde(default = "default_ssl")]
     pub ssl_key: String,
+    /// Networks allowed to connect, for both RPC and downloads, empty allows any
+    #[serde(default)]
+    pub allow: Vec<IpNetwork>,
+    /// New connections accepted from one non-loopback address per `conn_window` seconds,
+    /// 0 disables the limit
+    #[serde(default = "default_conn_limit")]
+    pub conn_limit: u32,
+    #[serde(default = "default_conn_window")]
+    pub conn_window: u64,
+    /// Failed auth attempts after which an address is banned, 0 disables bans
+    #[serde(default = "default_ban_after")]
+    pub ban_after: u32,
+    /// Duration of a ban in seconds
+    #[serde(default = "default_ban_duration")]
+    pub ban_duration: u64,
+    /// Seconds a client can go without sending anything before it's pinged
+    #[serde(default = "default_ping_interval")]
+    pub ping_interval: u64,
+    /// Seconds without anything from a client after which it's disconnected, which
+    /// must be longer than `ping_interval` to leave time for the pong
+    #[serde(default = "default_conn_timeout")]
+    pub conn_timeout: u64,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct TrkConfig {
     #[serde(default = "default_trk_port")]
     pub port: u16,
+    /// Hold off on peer connections for private torrents until a tracker has accepted our
+    /// `started` announce
+    #[serde(default)]
+    pub private_announce_first: bool,
+    /// Extra `Name: value` headers sent with HTTP announces, by tracker host
+    #[serde(default)]
+    pub headers: HashMap<String, Vec<String>>,
+    /// IPv4 address sent to trackers instead of the one they see us connect from
+    #[serde(default)]
+    pub announce_ip: Option<Ipv4Addr>,
+    /// IPv6 address sent to HTTP trackers alongside any IPv4 one
+    #[serde(default)]
+    pub announce_ip6: Option<Ipv6Addr>,
+    /// Report corrupt and redundant byte counts to the trackers of public torrents too,
+    /// they're always sent for private ones
+    #[serde(default)]
+    pub report_corrupt: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -89,9 +154,30 @@ pub struct DhtConfigFile {
     pub port: u16,
     #[serde(default = "default_bootstrap_node")]
     pub bootstrap_node: Option<String>,
+    /// Node to bootstrap the IPv6 DHT from, which is only joined if this is set
+    #[serde(default)]
+    pub bootstrap_node6: Option<String>,
+    /// Ask other nodes not to add us to their routing tables, e.g. when
+    /// unreachable behind a NAT or only online briefly (BEP 43)
+    #[serde(default)]
+    pub read_only: bool,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DnsConfig {
+    /// Nameservers to query instead of those in /etc/resolv.conf
+    #[serde(default)]
+    pub servers: Vec<SocketAddr>,
+    /// Milliseconds to wait on a nameserver before trying the next one
+    #[serde(default = "default_dns_timeout")]
+    pub timeout: u64,
+    /// Resolve through the system's getaddrinfo rather than querying
+    /// nameservers directly
+    #[serde(default)]
+    pub system: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct DiskConfig {
     #[serde(default = "default_session_dir")]
     pub session: String,
@@ -99,9 +185,69 @@ pub struct DiskConfig {
     pub directory: String,
     #[serde(default = "default_validate")]
     pub validate: bool,
+    #[serde(default = "default_shutdown_timeout")]
+    pub shutdown_timeout: u64,
+    #[serde(default = "default_stall_timeout")]
+    pub stall_timeout: u64,
+    /// How space is reserved for torrent files, unless a torrent sets its own policy
+    #[serde(default)]
+    pub allocation: Allocation,
+    /// Re-hash every piece from disk once a download completes, before seeding
+    #[serde(default)]
+    pub verify_complete: bool,
+    /// Hash each piece read to serve peers, dropping it rather than uploading it if it no
+    /// longer matches
+    #[serde(default)]
+    pub verify_on_read: bool,
+    /// What to do when a torrent's files turn out to have been removed from under it
+    #[serde(default)]
+    pub on_missing: MissingFiles,
+    /// When written data is synced to disk
+    #[serde(default)]
+    pub sync: SyncPolicy,
+    /// Bytes read at once when seeding torrents which download sequentially, 0 disables it
+    #[serde(default = "default_read_ahead")]
+    pub read_ahead: usize,
+    /// Bytes of pieces kept in memory to serve blocks peers request from them, 0 disables it
+    #[serde(default = "default_piece_cache")]
+    pub piece_cache: usize,
+    /// Bytes of a piece read into the piece cache at once, rounded up to whole blocks. 0 reads
+    /// the whole piece
+    #[serde(default)]
+    pub piece_cache_window: usize,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// How eagerly writes are fsynced, trading throughput for how much downloaded data a
+/// crash can lose. Files are always synced at shutdown.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncPolicy {
+    /// Leave it to the OS
+    None,
+    /// Sync a piece's files once it has been verified
+    #[default]
+    OnPieceComplete,
+    /// Sync a file once this many bytes have been written to it since its last sync
+    EveryBytes(u64),
+    /// Sync files which have held unsynced writes for this many seconds
+    Interval(u64),
+}
+
+/// Handling of a torrent whose data can't be read back because its files were deleted or
+/// truncated outside of synapse.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MissingFiles {
+    /// Stop the torrent with a disk error
+    #[default]
+    Error,
+    /// Pause the torrent, so it can be resumed once the files are back
+    Pause,
+    /// Validate the torrent, downloading whatever is missing again
+    Recheck,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct NetConfig {
     #[serde(default = "default_max_files")]
     pub max_open_files: usize,
@@ -111,14 +257,173 @@ pub struct NetConfig {
     pub max_open_announces: usize,
     #[serde(default = "default_min_announce_interval")]
     pub min_announce_interval: u64,
+    /// Forward the peer and DHT ports through a UPnP or NAT-PMP gateway
+    #[serde(default = "default_upnp")]
+    pub upnp: bool,
+    /// Find peers on the local network through multicast announces (BEP 14)
+    #[serde(default = "default_lsd")]
+    pub lsd: bool,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct PeerConfig {
     #[serde(default = "default_prune_timeout")]
     pub prune_timeout: u64,
     #[serde(default = "default_unchoke_slots_limit")]
     pub unchoke_slots_limit: UnlimitedOrU64,
+    #[serde(default = "default_nodelay")]
+    pub nodelay: bool,
+    /// Peers a block may be requested from at once during endgame
+    #[serde(default = "default_endgame_duplicates")]
+    pub endgame_duplicates: usize,
+    /// Peer connections allowed across all torrents
+    #[serde(default = "default_max_peers_global")]
+    pub max_peers_global: usize,
+    /// Peer connections allowed per torrent, unless overridden for the torrent
+    #[serde(default = "default_max_peers_per_torrent")]
+    pub max_peers_per_torrent: u16,
+    /// Addresses whose longest matching ip_filter prefix has a weight at or below this
+    /// are blocked
+    #[serde(default)]
+    pub ip_filter_threshold: u8,
+    /// File of ranges to block, added to the ip_filter with weight 0
+    #[serde(default)]
+    pub blocklist: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IdleConfig {
+    #[serde(default = "default_idle_timeout")]
+    pub timeout: u64,
+    #[serde(default = "default_idle_max_announce_interval")]
+    pub max_announce_interval: u64,
+}
+
+/// A budget for the bytes transferred in each accounting period.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QuotaConfig {
+    /// Bytes uploaded and downloaded allowed per period, 0 disables the quota
+    #[serde(default)]
+    pub limit: u64,
+    /// Day of the month periods start on, 1-28
+    #[serde(default = "default_quota_reset_day")]
+    pub reset_day: u32,
+    /// Length of a period in days, 0 for calendar months starting on `reset_day`
+    #[serde(default)]
+    pub period_days: u32,
+    /// Percentages of the limit which are reported once crossed
+    #[serde(default = "default_quota_thresholds")]
+    pub thresholds: Vec<u8>,
+    #[serde(default)]
+    pub action: QuotaAction,
+    /// Upload and download limit in bytes/s for the throttle action
+    #[serde(default = "default_quota_throttle")]
+    pub throttle: i64,
+}
+
+/// What happens once the quota for a period has been used up, until the period resets.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QuotaAction {
+    /// Apply the quota throttle globally
+    #[default]
+    Throttle,
+    /// Pause every running torrent
+    Pause,
+}
+
+/// A directory which is scanned for new torrent files to add.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WatchDir {
+    pub path: String,
+    /// Download directory for added torrents, defaults to `disk.directory`
+    #[serde(default)]
+    pub directory: Option<String>,
+    #[serde(default)]
+    pub paused: bool,
+    /// Delete torrent files once added rather than renaming them
+    #[serde(default)]
+    pub delete: bool,
+}
+
+/// Global throttle limits which apply during a weekly time window.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScheduleRule {
+    #[serde(default = "default_schedule_days")]
+    pub days: Vec<Weekday>,
+    /// First hour of the window, 0-23
+    pub start: u32,
+    /// Hour the window ends at(exclusive), 1-24
+    pub end: u32,
+    /// Upload limit in bytes/s, unlimited if absent
+    #[serde(default)]
+    pub upload: Option<i64>,
+    /// Download limit in bytes/s, unlimited if absent
+    #[serde(default)]
+    pub download: Option<i64>,
+}
+
+impl ScheduleRule {
+    pub fn contains(&self, day: Weekday, hour: u32) -> bool {
+        self.days.contains(&day) && self.start <= hour && hour < self.end
+    }
+
+    fn overlaps(&self, other: &ScheduleRule) -> bool {
+        self.days.iter().any(|d| other.days.contains(d))
+            && self.start < other.end
+            && other.start < self.end
+    }
+}
+
+/// Checks that every scheduler window is well formed, and that no two
+/// windows cover the same hour.
+pub fn validate_scheduler(rules: &[ScheduleRule]) -> Result<(), String> {
+    for (i, rule) in rules.iter().enumerate() {
+        if rule.start >= rule.end || rule.end > 24 {
+            return Err(format!(
+                "window {}-{} must satisfy start < end <= 24",
+                rule.start, rule.end
+            ));
+        }
+        if rule.days.is_empty() {
+            return Err(format!("window {}-{} has no days", rule.start, rule.end));
+        }
+        if let Some(other) = rules[..i].iter().find(|o| o.overlaps(rule)) {
+            return Err(format!(
+                "window {}-{} overlaps window {}-{}",
+                rule.start, rule.end, other.start, other.end
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Checks that the quota period and thresholds are well formed.
+pub fn validate_quota(quota: &QuotaConfig) -> Result<(), String> {
+    if !(1..=28).contains(&quota.reset_day) {
+        return Err(format!(
+            "reset_day {} must be between 1 and 28",
+            quota.reset_day
+        ));
+    }
+    if let Some(t) = quota.thresholds.iter().find(|t| !(1..=100).contains(*t)) {
+        return Err(format!("threshold {t} must be between 1 and 100"));
+    }
+    Ok(())
+}
+
+/// Checks that clients are pinged before they'd time out.
+pub fn validate_rpc(rpc: &RpcConfig) -> Result<(), String> {
+    if rpc.ping_interval == 0 {
+        return Err("ping_interval must not be 0".to_owned());
+    }
+    if rpc.conn_timeout <= rpc.ping_interval {
+        return Err(format!(
+            "conn_timeout {} must be longer than ping_interval {}",
+            rpc.conn_timeout, rpc.ping_interval
+        ));
+    }
+    Ok(())
 }
 
 impl ConfigFile {
@@ -130,31 +435,56 @@ impl ConfigFile {
         .map_err(Error::Format)
     }
 
+    /// Reads the first config file found, exiting if it's invalid.
     pub fn try_load() -> Result<ConfigFile, Error> {
-        let args = args::args();
-        let files = [
-            args.config.as_deref().unwrap_or("./config.toml"),
-            "$XDG_CONFIG_HOME/synapse.toml",
-            "~/.config/synapse.toml",
-        ];
-        for file in &files {
-            let res = Self::load_config_file(file);
-            match res {
-                Ok(mut cfg) => {
-                    if cfg.max_dl == 0 {
-                        error!("Config max_dl must not be 0");
-                        process::exit(1);
-                    }
-                    if !cfg!(debug_assertions) && !cfg.disk.validate {
-                        error!("validation skipping can only be used in development, overriding!");
-                        cfg.disk.validate = true;
-                    }
-                    return Ok(cfg);
-                }
-                Err(Error::Format(e)) => {
-                    error!("Failed to parse config, terminating: {}", e);
+        match Self::read() {
+            Ok(mut cfg) => {
+                if let Err(e) = cfg.validate().and_then(|_| cfg.load_blocklist()) {
+                    error!("{}", e);
                     process::exit(1);
                 }
+                Ok(cfg)
+            }
+            Err(Error::Format(e)) => {
+                error!("Failed to parse config, terminating: {}", e);
+                process::exit(1);
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Reads the config file again while running, where a broken file is only reported.
+    pub fn reload() -> Result<ConfigFile, String> {
+        let mut cfg = Self::read().map_err(|e| e.to_string())?;
+        cfg.validate()?;
+        cfg.load_blocklist()?;
+        Ok(cfg)
+    }
+
+    /// Whether any of the files the config is read from exists.
+    pub fn exists() -> bool {
+        Self::files()
+            .iter()
+            .filter_map(|file| shellexpand::full(file).ok())
+            .any(|path| Path::new(path.as_ref()).exists())
+    }
+
+    /// Files the config is read from, the first found being used.
+    fn files() -> [String; 3] {
+        [
+            args::args()
+                .config
+                .unwrap_or_else(|| "./config.toml".to_owned()),
+            "$XDG_CONFIG_HOME/synapse.toml".to_owned(),
+            "~/.config/synapse.toml".to_owned(),
+        ]
+    }
+
+    fn read() -> Result<ConfigFile, Error> {
+        for file in &Self::files() {
+            match Self::load_config_file(file) {
+                Ok(cfg) => return Ok(cfg),
+                Err(Error::Format(e)) => return Err(Error::Format(e)),
                 Err(e) => {
                     debug!("Failed to load config file {}: {}", file, e);
                 }
@@ -162,6 +492,47 @@ impl ConfigFile {
         }
         Err(Error::NoConfig)
     }
+
+    /// Adds the ranges of the blocklist to the ip_filter, where prefixes given explicitly
+    /// take precedence.
+    fn load_blocklist(&mut self) -> Result<(), String> {
+        let Some(path) = &self.peer.blocklist else {
+            return Ok(());
+        };
+        let path = shellexpand::tilde(path);
+        let data = fs::read_to_string(path.as_ref())
+            .map_err(|e| format!("Failed to read blocklist {path}: {e}"))?;
+        let networks =
+            ip_filter::parse_file(&data).map_err(|e| format!("Blocklist {path}: {e}"))?;
+        info!("Loaded {} prefixes from blocklist {}", networks.len(), path);
+        for net in networks {
+            self.ip_filter.entry(net).or_insert(ip_filter::BLOCK);
+        }
+        Ok(())
+    }
+
+    fn validate(&mut self) -> Result<(), String> {
+        if self.max_dl == 0 {
+            return Err("Config max_dl must not be 0".to_owned());
+        }
+        validate_scheduler(&self.scheduler)
+            .map_err(|e| format!("Invalid scheduler config: {e}"))?;
+        validate_quota(&self.quota).map_err(|e| format!("Invalid quota config: {e}"))?;
+        validate_rpc(&self.rpc).map_err(|e| format!("Invalid rpc config: {e}"))?;
+        if let Some((host, e)) = self
+            .tracker
+            .headers
+            .iter()
+            .find_map(|(host, h)| Some((host, Headers::parse(h).err()?)))
+        {
+            return Err(format!("Invalid tracker headers for {host}: {e}"));
+        }
+        if !cfg!(debug_assertions) && !self.disk.validate {
+            error!("validation skipping can only be used in development, overriding!");
+            self.disk.validate = true;
+        }
+        Ok(())
+    }
 }
 
 impl Config {
@@ -175,15 +546,17 @@ impl Config {
         }
     }
 
+    /// Rereads the config file, for applying changes to a running client.
+    pub fn reload() -> Result<Config, String> {
+        ConfigFile::reload().map(Config::from_file)
+    }
+
     pub fn from_file(mut file: ConfigFile) -> Config {
-        let addr = file
-            .dht
-            .bootstrap_node
-            .and_then(|n| n.to_socket_addrs().ok())
-            .and_then(|mut a| a.next());
         let dht = DhtConfig {
             port: file.dht.port,
-            bootstrap_node: addr,
+            bootstrap_node: resolve_node(file.dht.bootstrap_node, SocketAddr::is_ipv4),
+            bootstrap_node6: resolve_node(file.dht.bootstrap_node6, SocketAddr::is_ipv6),
+            read_only: file.dht.read_only,
         };
         let ip_filter = {
             let mut table = IpNetworkTable::new();
@@ -195,15 +568,27 @@ impl Config {
         };
         file.disk.session = shellexpand::tilde(&file.disk.session).into();
         file.disk.directory = shellexpand::tilde(&file.disk.directory).into();
+        for dir in &mut file.watch_dirs {
+            dir.path = shellexpand::tilde(&dir.path).into();
+            if let Some(d) = &mut dir.directory {
+                *d = shellexpand::tilde(d).into();
+            }
+        }
         Config {
             port: file.port,
             max_dl: file.max_dl,
+            crash_reports: file.crash_reports,
             trk: file.tracker,
             rpc: file.rpc,
             disk: file.disk,
             net: file.net,
             peer: file.peer,
+            idle: file.idle,
+            quota: file.quota,
+            watch_dirs: file.watch_dirs,
+            scheduler: file.scheduler,
             dht,
+            dns: file.dns,
             ip_filter,
         }
     }
@@ -230,6 +615,24 @@ fn default_local() -> bool {
 fn default_auth() -> bool {
     false
 }
+fn default_conn_limit() -> u32 {
+    10
+}
+fn default_conn_window() -> u64 {
+    10
+}
+fn default_ban_after() -> u32 {
+    5
+}
+fn default_ban_duration() -> u64 {
+    10 * 60
+}
+fn default_ping_interval() -> u64 {
+    15
+}
+fn default_conn_timeout() -> u64 {
+    20
+}
 fn default_password() -> String {
     "hackme".to_owned()
 }
@@ -240,9 +643,12 @@ fn default_bootstrap_node() -> Option<String> {
     None
 }
 fn default_bootstrap_node_addr() -> Option<SocketAddr> {
-    default_bootstrap_node()
-        .and_then(|n| n.to_socket_addrs().ok())
-        .and_then(|mut a| a.next())
+    resolve_node(default_bootstrap_node(), SocketAddr::is_ipv4)
+}
+/// Resolves a `host:port` node to its first address of the family the DHT it bootstraps uses.
+fn resolve_node(node: Option<String>, family: fn(&SocketAddr) -> bool) -> Option<SocketAddr> {
+    node.and_then(|n| n.to_socket_addrs().ok())
+        .and_then(|mut a| a.find(family))
 }
 fn default_session_dir() -> String {
     shellexpand::full("$XDG_DATA_HOME/synapse")
@@ -255,6 +661,18 @@ fn default_directory_dir() -> String {
 fn default_validate() -> bool {
     true
 }
+fn default_shutdown_timeout() -> u64 {
+    10
+}
+fn default_stall_timeout() -> u64 {
+    60
+}
+fn default_read_ahead() -> usize {
+    256 * 1024
+}
+fn default_piece_cache() -> usize {
+    64 * 1024 * 1024
+}
 fn default_max_files() -> usize {
     500
 }
@@ -267,12 +685,59 @@ fn default_max_announces() -> usize {
 fn default_min_announce_interval() -> u64 {
     15 * 60
 }
+fn default_upnp() -> bool {
+    true
+}
+fn default_lsd() -> bool {
+    true
+}
 fn default_prune_timeout() -> u64 {
     15
 }
 fn default_unchoke_slots_limit() -> UnlimitedOrU64 {
     UnlimitedOrU64::new(8)
 }
+fn default_nodelay() -> bool {
+    true
+}
+fn default_endgame_duplicates() -> usize {
+    3
+}
+fn default_max_peers_global() -> usize {
+    500
+}
+fn default_max_peers_per_torrent() -> u16 {
+    50
+}
+fn default_schedule_days() -> Vec<Weekday> {
+    vec![
+        Weekday::Mon,
+        Weekday::Tue,
+        Weekday::Wed,
+        Weekday::Thu,
+        Weekday::Fri,
+        Weekday::Sat,
+        Weekday::Sun,
+    ]
+}
+fn default_dns_timeout() -> u64 {
+    1000
+}
+fn default_idle_timeout() -> u64 {
+    0
+}
+fn default_idle_max_announce_interval() -> u64 {
+    6 * 60 * 60
+}
+fn default_quota_reset_day() -> u32 {
+    1
+}
+fn default_quota_thresholds() -> Vec<u8> {
+    vec![80, 95, 100]
+}
+fn default_quota_throttle() -> i64 {
+    10 * 1024
+}
 fn default_ip_filter() -> HashMap<IpNetwork, u8> {
     HashMap::new()
 }
@@ -282,12 +747,18 @@ impl Default for Config {
         Config {
             port: default_port(),
             max_dl: default_max_dl(),
+            crash_reports: false,
             trk: Default::default(),
             rpc: Default::default(),
             disk: Default::default(),
             net: Default::default(),
             dht: Default::default(),
+            dns: Default::default(),
             peer: Default::default(),
+            idle: Default::default(),
+            quota: Default::default(),
+            watch_dirs: Vec::new(),
+            scheduler: Vec::new(),
             ip_filter: IpNetworkTable::new(),
         }
     }
@@ -302,6 +773,13 @@ impl Default for RpcConfig {
             password: default_password(),
             ssl_cert: default_ssl(),
             ssl_key: default_ssl(),
+            allow: Vec::new(),
+            conn_limit: default_conn_limit(),
+            conn_window: default_conn_window(),
+            ban_after: default_ban_after(),
+            ban_duration: default_ban_duration(),
+            ping_interval: default_ping_interval(),
+            conn_timeout: default_conn_timeout(),
         }
     }
 }
@@ -310,6 +788,11 @@ impl Default for TrkConfig {
     fn default() -> TrkConfig {
         TrkConfig {
             port: default_trk_port(),
+            private_announce_first: false,
+            headers: HashMap::new(),
+            announce_ip: None,
+            announce_ip6: None,
+            report_corrupt: false,
         }
     }
 }
@@ -319,6 +802,8 @@ impl Default for DhtConfigFile {
         DhtConfigFile {
             port: default_dht_port(),
             bootstrap_node: default_bootstrap_node(),
+            bootstrap_node6: None,
+            read_only: false,
         }
     }
 }
@@ -328,6 +813,8 @@ impl Default for DhtConfig {
         DhtConfig {
             port: default_dht_port(),
             bootstrap_node: default_bootstrap_node_addr(),
+            bootstrap_node6: None,
+            read_only: false,
         }
     }
 }
@@ -338,6 +825,16 @@ impl Default for DiskConfig {
             session: default_session_dir(),
             directory: default_directory_dir(),
             validate: default_validate(),
+            shutdown_timeout: default_shutdown_timeout(),
+            stall_timeout: default_stall_timeout(),
+            allocation: Allocation::default(),
+            verify_complete: false,
+            verify_on_read: false,
+            on_missing: MissingFiles::default(),
+            sync: SyncPolicy::default(),
+            read_ahead: default_read_ahead(),
+            piece_cache: default_piece_cache(),
+            piece_cache_window: 0,
         }
     }
 }
@@ -349,6 +846,8 @@ impl Default for NetConfig {
             max_open_sockets: default_max_sockets(),
             max_open_announces: default_max_announces(),
             min_announce_interval: default_min_announce_interval(),
+            upnp: default_upnp(),
+            lsd: default_lsd(),
         }
     }
 }
@@ -358,6 +857,44 @@ impl Default for PeerConfig {
         PeerConfig {
             prune_timeout: default_prune_timeout(),
             unchoke_slots_limit: default_unchoke_slots_limit(),
+            nodelay: default_nodelay(),
+            endgame_duplicates: default_endgame_duplicates(),
+            max_peers_global: default_max_peers_global(),
+            max_peers_per_torrent: default_max_peers_per_torrent(),
+            ip_filter_threshold: 0,
+            blocklist: None,
+        }
+    }
+}
+
+impl Default for DnsConfig {
+    fn default() -> DnsConfig {
+        DnsConfig {
+            servers: Vec::new(),
+            timeout: default_dns_timeout(),
+            system: false,
+        }
+    }
+}
+
+impl Default for IdleConfig {
+    fn default() -> IdleConfig {
+        IdleConfig {
+            timeout: default_idle_timeout(),
+            max_announce_interval: default_idle_max_announce_interval(),
+        }
+    }
+}
+
+impl Default for QuotaConfig {
+    fn default() -> QuotaConfig {
+        QuotaConfig {
+            limit: 0,
+            reset_day: default_quota_reset_day(),
+            period_days: 0,
+            thresholds: default_quota_thresholds(),
+            action: QuotaAction::default(),
+            throttle: default_quota_throttle(),
         }
     }
 }
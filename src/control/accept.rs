@@ -0,0 +1,78 @@
+use crate::PEER_ID;
+use crate::util::{MHashMap, hash_to_id};
+
+/// Finds the torrent an incoming handshake is for. Connections for torrents we don't have
+/// or which loop back to ourselves are refused before any peer state is set up for them.
+pub fn torrent(
+    hash_idx: &MHashMap<[u8; 20], usize>,
+    hash: &[u8; 20],
+    id: &[u8; 20],
+) -> Option<usize> {
+    let Some(&tid) = hash_idx.get(hash) else {
+        debug!(
+            "Refusing incoming peer, torrent {} doesn't exist",
+            hash_to_id(hash)
+        );
+        return None;
+    };
+    if id == &*PEER_ID {
+        debug!("Refusing incoming peer, it's our own connection");
+        return None;
+    }
+    Some(tid)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+    use crate::control::Control;
+    use crate::control::cio::CIO;
+    use crate::control::cio::test::TCIO;
+    use crate::torrent::{self, Info, PeerConn, Preset};
+    use crate::{PEER_ID, rpc};
+
+    fn control(dir: &std::path::Path) -> Control<TCIO> {
+        let mut config = Config::default();
+        config.disk.directory = dir.to_string_lossy().into_owned();
+        Control::test(config)
+    }
+
+    /// Accepts a connection and feeds it a handshake, returning whether it's still open.
+    fn handshake(c: &mut Control<TCIO>, hash: [u8; 20], id: [u8; 20]) -> bool {
+        let pid = c.cio.add_peer(PeerConn::test()).unwrap();
+        c.incoming.insert(pid);
+        let msg = torrent::Message::Handshake {
+            hash,
+            id,
+            rsv: [0; 8],
+        };
+        c.handle_peer_ev(pid, Ok(msg));
+        assert!(!c.incoming.contains(&pid));
+        c.cio.data().peers.contains_key(&pid)
+    }
+
+    #[test]
+    fn test_refuse_handshake() {
+        let data = tempfile::tempdir().unwrap();
+        let mut c = control(data.path());
+        let info = Info::with_name("accept");
+        c.handle_rpc_ev(rpc::Message::Torrent {
+            info: info.clone(),
+            path: None,
+            start: true,
+            import: false,
+            preset: Preset::default(),
+            client: 0,
+            serial: 0,
+        });
+        assert_eq!(c.torrents.len(), 1);
+
+        assert!(!handshake(&mut c, [1; 20], [2; 20]));
+        assert!(!handshake(&mut c, info.hash, *PEER_ID));
+        assert!(c.peers.is_empty());
+
+        // Anyone else connecting for a torrent we have is handed to it
+        assert!(handshake(&mut c, info.hash, [2; 20]));
+        assert_eq!(c.peers.values().collect::<Vec<_>>(), [&0]);
+    }
+}
@@ -1,18 +1,20 @@
 use std::cell::RefCell;
+use std::io;
 use std::net::{Ipv4Addr, SocketAddrV4, TcpListener};
 use std::rc::Rc;
 use std::sync::Arc;
-use std::{io, time};
+use std::time::{self, Instant};
 
 use amy::{self, ChannelError};
 
 use crate::config::Config;
-use crate::control::cio::{self, Error, Result};
+use crate::control::cio::{self, Error, Result, Worker};
+use crate::control::supervisor::Supervisor;
 use crate::torrent::peer::reader::RRes;
 use crate::util::UHashMap;
+use crate::util::timer::Timers;
 use crate::{disk, rpc, torrent, tracker};
 
-const POLL_INT_MS: usize = 1000;
 const PRUNE_GOAL: usize = 50;
 
 /// Amy based CIO implementation. Currently the default one used.
@@ -37,9 +39,15 @@ struct ACIOData {
     poll: amy::Poller,
     reg: amy::Registrar,
     peers: UHashMap<torrent::PeerConn>,
+    /// When each peer could first be pruned if it's done nothing by then
+    prune: Timers<cio::PID>,
     events: Vec<cio::Event>,
     chans: ACChans,
-    crashed: bool,
+    supervisor: Supervisor,
+    /// Workers which have crashed and when they will be restarted
+    failed: Vec<(Worker, Instant)>,
+    /// A worker which crashed too often to be restarted
+    gave_up: Option<(Worker, io::Error)>,
     listener: TcpListener,
     lid: usize,
 }
@@ -50,6 +58,7 @@ impl ACIO {
         poll: amy::Poller,
         reg: amy::Registrar,
         chans: ACChans,
+        supervisor: Supervisor,
     ) -> io::Result<ACIO> {
         let ip = Ipv4Addr::new(0, 0, 0, 0);
         let port = config.port;
@@ -61,11 +70,14 @@ impl ACIO {
             poll,
             reg,
             chans,
+            supervisor,
             listener,
             lid,
             peers: UHashMap::default(),
+            prune: Timers::new(),
             events: Vec::new(),
-            crashed: false,
+            failed: Vec::new(),
+            gave_up: None,
         };
 
         Ok(ACIO {
@@ -74,6 +86,12 @@ impl ACIO {
         })
     }
 
+    /// Waits for all worker threads to exit, returning whether
+    /// they shut down cleanly.
+    pub fn join_workers(&self) -> bool {
+        self.data.borrow_mut().supervisor.join()
+    }
+
     fn process_event(&self, not: amy::Notification, events: &mut Vec<cio::Event>) {
         let id = not.id;
 
@@ -124,16 +142,16 @@ impl ACIO {
             // Liveness checks
             match d.chans.disk_tx.send(disk::Request::Ping) {
                 Ok(_) => {}
-                Err(flume::SendError(_)) => d.crashed = true,
+                Err(flume::SendError(_)) => d.worker_failed(Worker::Disk),
             }
             match d.chans.rpc_tx.send(rpc::CtlMessage::Ping) {
                 Ok(_) => {}
-                Err(ChannelError::SendError(_)) => d.crashed = true,
+                Err(ChannelError::SendError(_)) => d.worker_failed(Worker::RPC),
                 Err(e) => error!("Unknown error sending to channel: {:?}", e),
             }
             match d.chans.trk_tx.send(tracker::Request::Ping) {
                 Ok(_) => {}
-                Err(ChannelError::SendError(_)) => d.crashed = true,
+                Err(ChannelError::SendError(_)) => d.worker_failed(Worker::Tracker),
                 Err(e) => error!("Unknown error sending to channel: {:?}", e),
             }
         }
@@ -179,19 +197,18 @@ impl ACIO {
 }
 
 impl cio::CIO for ACIO {
-    fn poll(&mut self, events: &mut Vec<cio::Event>) -> Result<()> {
-        {
+    fn poll(&mut self, events: &mut Vec<cio::Event>, timeout: usize) -> Result<()> {
+        let timeout = {
             let mut d = self.data.borrow_mut();
-            if d.crashed {
-                return Err(Error::Crashed);
-            }
+            d.restart_failed()?;
 
             for event in d.events.drain(..) {
                 events.push(event);
             }
-        }
+            poll_timeout(timeout, &d.failed, Instant::now())
+        };
 
-        let res = self.data.borrow_mut().poll.wait(POLL_INT_MS);
+        let res = self.data.borrow_mut().poll.wait(timeout);
 
         match res {
             Ok(evs) => {
@@ -212,18 +229,24 @@ impl cio::CIO for ACIO {
     }
 
     fn add_peer(&mut self, mut peer: torrent::PeerConn) -> Result<cio::PID> {
+        let timeout = time::Duration::from_secs(self.config.peer.prune_timeout);
         if self.data.borrow().peers.len() > self.config.net.max_open_sockets {
+            let now = Instant::now();
             let mut pruned = Vec::new();
-            for (id, peer) in &self.data.borrow().peers {
-                if peer.last_action().elapsed()
-                    > time::Duration::from_secs(self.config.peer.prune_timeout)
-                {
-                    pruned.push(*id)
-                }
-                if pruned.len() == PRUNE_GOAL {
-                    break;
+            let mut d = self.data.borrow_mut();
+            while pruned.len() < PRUNE_GOAL
+                && let Some(id) = d.prune.pop_expired(now)
+            {
+                let Some(&last) = d.peers.get(&id).map(|p| p.last_action()) else {
+                    continue;
+                };
+                if now >= last + timeout {
+                    pruned.push(id);
+                } else {
+                    d.prune.schedule(id, last + timeout);
                 }
             }
+            drop(d);
             // We couldn't even prune anything, this client must be really busy...
             // Either way just return an error
             if pruned.is_empty() {
@@ -243,7 +266,9 @@ impl cio::CIO for ACIO {
         if let Some(t) = peer.sock_mut().throttle.as_mut() {
             t.id = id
         }
-        self.data.borrow_mut().peers.insert(id, peer);
+        let mut d = self.data.borrow_mut();
+        d.peers.insert(id, peer);
+        d.prune.schedule(id, Instant::now() + timeout);
         Ok(id)
     }
 
@@ -304,27 +329,24 @@ impl cio::CIO for ACIO {
     fn msg_rpc(&mut self, msg: rpc::CtlMessage) {
         let mut d = self.data.borrow_mut();
 
-        if d.chans.rpc_tx.send(msg).is_err() && !d.crashed {
-            d.crashed = true;
-            error!("RPC thread crashed, shutting down!");
+        if d.chans.rpc_tx.send(msg).is_err() {
+            d.worker_failed(Worker::RPC);
         }
     }
 
     fn msg_trk(&mut self, msg: tracker::Request) {
         let mut d = self.data.borrow_mut();
 
-        if d.chans.trk_tx.send(msg).is_err() && !d.crashed {
-            d.crashed = true;
-            error!("tracker thread crashed, shutting down!");
+        if d.chans.trk_tx.send(msg).is_err() {
+            d.worker_failed(Worker::Tracker);
         }
     }
 
     fn msg_disk(&mut self, msg: disk::Request) {
         let mut d = self.data.borrow_mut();
 
-        if d.chans.disk_tx.send(msg).is_err() && !d.crashed {
-            d.crashed = true;
-            error!("disk thread crashed, shutting down!");
+        if d.chans.disk_tx.send(msg).is_err() {
+            d.worker_failed(Worker::Disk);
         }
     }
 
@@ -336,6 +358,12 @@ impl cio::CIO for ACIO {
             .map_err(Error::Timer)
     }
 
+    fn cancel_timer(&mut self, timer: cio::TID) {
+        if let Err(e) = self.data.borrow_mut().reg.cancel_timeout(timer) {
+            error!("Failed to cancel timer {}: {}", timer, e);
+        }
+    }
+
     fn new_handle(&self) -> Self {
         ACIO {
             config: self.config.clone(),
@@ -345,7 +373,41 @@ impl cio::CIO for ACIO {
 }
 
 impl ACIOData {
+    fn worker_failed(&mut self, worker: Worker) {
+        if self.failed.iter().any(|&(w, _)| w == worker) || self.gave_up.is_some() {
+            return;
+        }
+        error!("{} thread crashed!", worker);
+        match self.supervisor.crashed(worker, Instant::now()) {
+            Ok(at) => self.failed.push((worker, at)),
+            Err(e) => self.gave_up = Some((worker, e)),
+        }
+    }
+
+    /// Restarts crashed workers once their backoff has passed, notifying
+    /// control so that it can resend state which was lost.
+    fn restart_failed(&mut self) -> Result<()> {
+        if let Some((worker, e)) = &self.gave_up {
+            error!("Failed to restart {} thread: {}, shutting down!", worker, e);
+            return Err(Error::Crashed);
+        }
+        let now = Instant::now();
+        while let Some(i) = self.failed.iter().position(|&(_, at)| at <= now) {
+            let (worker, _) = self.failed.swap_remove(i);
+            if let Err(e) = self
+                .supervisor
+                .restart(worker, &mut self.reg, &mut self.chans)
+            {
+                error!("Failed to restart {} thread: {}, shutting down!", worker, e);
+                return Err(Error::Crashed);
+            }
+            self.events.push(cio::Event::Restarted(worker));
+        }
+        Ok(())
+    }
+
     fn remove_peer(&mut self, pid: cio::PID) {
+        self.prune.cancel(pid);
         if let Some(p) = self.peers.remove(&pid) {
             if let Err(e) = self.reg.deregister(p.sock()) {
                 error!("Failed to deregister sock: {:?}", e);
@@ -357,3 +419,34 @@ impl ACIOData {
         }
     }
 }
+
+/// Limits the wait for events to when the next crashed worker is due to be restarted.
+fn poll_timeout(timeout: usize, failed: &[(Worker, Instant)], now: Instant) -> usize {
+    failed
+        .iter()
+        .map(|&(_, at)| at.saturating_duration_since(now).as_micros().div_ceil(1000) as usize)
+        .fold(timeout, usize::min)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::poll_timeout;
+    use crate::control::cio::Worker;
+
+    #[test]
+    fn test_poll_timeout() {
+        let now = Instant::now();
+        assert_eq!(poll_timeout(5000, &[], now), 5000);
+        let failed = [
+            (Worker::Disk, now + Duration::from_millis(1500)),
+            (Worker::RPC, now + Duration::from_micros(2_000_500)),
+        ];
+        assert_eq!(poll_timeout(5000, &failed, now), 1500);
+        assert_eq!(poll_timeout(500, &failed, now), 500);
+        assert_eq!(poll_timeout(5000, &failed[1..], now), 2001);
+        // Restarts which are overdue don't wait at all
+        assert_eq!(poll_timeout(5000, &failed, now + Duration::from_secs(2)), 0);
+    }
+}
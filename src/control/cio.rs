@@ -31,6 +31,25 @@ pub type PID = usize;
 #[allow(clippy::upper_case_acronyms)]
 pub type TID = usize;
 
+/// Worker threads which control communicates with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Worker {
+    Disk,
+    #[allow(clippy::upper_case_acronyms)]
+    RPC,
+    Tracker,
+}
+
+impl std::fmt::Display for Worker {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Worker::Disk => f.write_str("disk"),
+            Worker::RPC => f.write_str("rpc"),
+            Worker::Tracker => f.write_str("tracker"),
+        }
+    }
+}
+
 pub enum Event {
     Timer(TID),
     Peer {
@@ -42,6 +61,9 @@ pub enum Event {
     Tracker(Result<tracker::Response>),
     Disk(Result<disk::Response>),
     Incoming(TcpStream),
+    /// A worker crashed and has been replaced, losing any state
+    /// and in flight requests it had.
+    Restarted(Worker),
 }
 
 /// Control IO trait used as an abstraction boundary between
@@ -49,8 +71,9 @@ pub enum Event {
 /// to be done.
 #[allow(clippy::upper_case_acronyms)]
 pub trait CIO {
-    /// Returns events for peers, timers, channels, etc.
-    fn poll(&mut self, events: &mut Vec<Event>) -> Result<()>;
+    /// Returns events for peers, timers, channels, etc, waiting up to `timeout` ms
+    /// for some to arrive.
+    fn poll(&mut self, events: &mut Vec<Event>, timeout: usize) -> Result<()>;
 
     /// Self propagate an event. Used to achieve non standard control flow.
     fn propagate(&mut self, event: Event);
@@ -85,6 +108,9 @@ pub trait CIO {
     /// Sets a timer in milliseconds
     fn set_timer(&mut self, interval: usize) -> Result<TID>;
 
+    /// Cancels a timer set with set_timer
+    fn cancel_timer(&mut self, timer: TID);
+
     /// Creates a copy of the IO object, which has the same underlying data
     fn new_handle(&self) -> Self;
 }
@@ -94,7 +120,7 @@ pub mod test {
     use super::{Event, Result, CIO, PID, TID};
     use crate::{disk, rpc, torrent, tracker};
     use std::collections::HashMap;
-    use std::sync::{Arc, Mutex};
+    use std::sync::{Arc, Mutex, MutexGuard};
 
     pub struct TCIO {
         data: Arc<Mutex<TCIOD>>,
@@ -109,6 +135,10 @@ pub mod test {
         pub trk_msgs: Vec<tracker::Request>,
         pub disk_msgs: Vec<disk::Request>,
         pub timers: usize,
+        /// Timeout of the last poll
+        pub poll_timeout: Option<usize>,
+        /// Intervals of the timers which haven't been cancelled
+        pub intervals: HashMap<TID, usize>,
         pub peer_cnt: usize,
     }
 
@@ -122,16 +152,23 @@ pub mod test {
                 trk_msgs: Vec::new(),
                 disk_msgs: Vec::new(),
                 timers: 0,
+                poll_timeout: None,
+                intervals: HashMap::new(),
                 peer_cnt: 0,
             };
             TCIO {
                 data: Arc::new(Mutex::new(d)),
             }
         }
+
+        pub fn data(&self) -> MutexGuard<'_, TCIOD> {
+            self.data.lock().unwrap()
+        }
     }
 
     impl CIO for TCIO {
-        fn poll(&mut self, _: &mut Vec<Event>) -> Result<()> {
+        fn poll(&mut self, _: &mut Vec<Event>, timeout: usize) -> Result<()> {
+            self.data.lock().unwrap().poll_timeout = Some(timeout);
             Ok(())
         }
 
@@ -187,13 +224,18 @@ pub mod test {
             d.disk_msgs.push(msg);
         }
 
-        fn set_timer(&mut self, _: usize) -> Result<TID> {
+        fn set_timer(&mut self, interval: usize) -> Result<TID> {
             let mut d = self.data.lock().unwrap();
             let timer = d.timers;
             d.timers += 1;
+            d.intervals.insert(timer, interval);
             Ok(timer)
         }
 
+        fn cancel_timer(&mut self, timer: TID) {
+            self.data.lock().unwrap().intervals.remove(&timer);
+        }
+
         fn new_handle(&self) -> Self {
             TCIO {
                 data: self.data.clone(),
@@ -0,0 +1,106 @@
+use ip_network::IpNetwork;
+use ip_network_table::IpNetworkTable;
+
+use crate::rpc::proto::message::IpFilterAction;
+use crate::util::ip_filter;
+
+/// Applies an RPC update of the blocked prefixes to the filter, returning how many are
+/// blocked afterwards. Prefixes with other weights, e.g. allowed ones, are left alone.
+pub fn update(
+    filter: &mut IpNetworkTable<u8>,
+    action: IpFilterAction,
+    networks: Vec<IpNetwork>,
+) -> usize {
+    if action == IpFilterAction::Replace {
+        filter.retain(|_, w| *w != ip_filter::BLOCK);
+    }
+    for net in networks {
+        if action == IpFilterAction::Remove {
+            if filter.exact_match(net) == Some(&ip_filter::BLOCK) {
+                filter.remove(net);
+            }
+        } else {
+            filter.insert(net, ip_filter::BLOCK);
+        }
+    }
+    filter
+        .iter()
+        .filter(|(_, w)| **w == ip_filter::BLOCK)
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::net::{TcpListener, TcpStream};
+
+    use crate::config::Config;
+    use crate::control::Control;
+    use crate::control::cio::test::TCIO;
+    use crate::rpc;
+    use crate::rpc::proto::message::IpFilterAction;
+
+    fn update(c: &mut Control<TCIO>, action: IpFilterAction, ranges: &[&str], path: Option<&str>) {
+        c.handle_rpc_ev(rpc::Message::UpdateIpFilter {
+            client: 0,
+            serial: 0,
+            action,
+            ranges: ranges.iter().map(|r| r.to_string()).collect(),
+            path: path.map(str::to_owned),
+        });
+    }
+
+    fn blocked(c: &Control<TCIO>) -> usize {
+        match c.cio.data().rpc_msgs.last() {
+            Some(rpc::CtlMessage::IpFilterUpdated { blocked, .. }) => *blocked,
+            m => panic!("unexpected response {m:?}"),
+        }
+    }
+
+    #[test]
+    fn test_filter_connections() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("filter.dat");
+        fs::write(&path, "127.0.0.0 - 127.255.255.255 , 000 , loopback\n").unwrap();
+        let path = path.to_str().unwrap();
+
+        let mut c = Control::test(Config::default());
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut clients = Vec::new();
+        let mut accept = || {
+            clients.push(TcpStream::connect(listener.local_addr().unwrap()).unwrap());
+            listener.accept().unwrap().0
+        };
+
+        update(&mut c, IpFilterAction::Add, &[], Some(path));
+        assert_eq!(blocked(&c), 1);
+        c.handle_incoming_conn(accept());
+        assert!(c.incoming.is_empty());
+
+        update(&mut c, IpFilterAction::Replace, &["10.0.0.0/8"], None);
+        assert_eq!(blocked(&c), 1);
+        c.handle_incoming_conn(accept());
+        assert_eq!(c.incoming.len(), 1);
+
+        // Peers which were already connected are dropped on the next tick
+        update(&mut c, IpFilterAction::Add, &["127.0.0.1"], None);
+        assert_eq!(blocked(&c), 2);
+        assert_eq!(c.cio.data().peers.len(), 1);
+        c.update_jobs();
+        assert!(c.cio.data().peers.is_empty());
+
+        update(
+            &mut c,
+            IpFilterAction::Remove,
+            &["127.0.0.1", "10.0.0.0/8"],
+            None,
+        );
+        assert_eq!(blocked(&c), 0);
+        update(&mut c, IpFilterAction::Add, &[], Some("/nonexistent"));
+        assert!(matches!(
+            c.cio.data().rpc_msgs.last(),
+            Some(rpc::CtlMessage::Error { .. })
+        ));
+    }
+}
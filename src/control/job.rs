@@ -1,5 +1,3 @@
-use std::collections::HashSet;
-use std::net::SocketAddr;
 use std::time;
 
 use crate::control::cio;
@@ -40,6 +38,16 @@ impl<T: cio::CIO> Job<T> for SessionUpdate {
     }
 }
 
+pub struct BlockProgressUpdate;
+
+impl<T: cio::CIO> Job<T> for BlockProgressUpdate {
+    fn update(&mut self, torrents: &mut UHashMap<Torrent<T>>) {
+        for torrent in torrents.values_mut() {
+            torrent.rpc_update_block_progress();
+        }
+    }
+}
+
 pub struct TorrentTxUpdate {
     piece_update: time::Instant,
     active: UHashMap<bool>,
@@ -84,36 +92,22 @@ impl<T: cio::CIO> Job<T> for TorrentTxUpdate {
     }
 }
 
-pub struct PEXUpdate {
-    peers: UHashMap<HashSet<SocketAddr>>,
-}
+pub struct LSDUpdate;
 
-impl PEXUpdate {
-    pub fn new() -> PEXUpdate {
-        PEXUpdate {
-            peers: UHashMap::default(),
+impl<T: cio::CIO> Job<T> for LSDUpdate {
+    fn update(&mut self, torrents: &mut UHashMap<Torrent<T>>) {
+        for torrent in torrents.values_mut() {
+            torrent.lsd_announce();
         }
     }
 }
 
+pub struct PEXUpdate;
+
 impl<T: cio::CIO> Job<T> for PEXUpdate {
     fn update(&mut self, torrents: &mut UHashMap<Torrent<T>>) {
-        for (id, torrent) in torrents.iter_mut().filter(|(_, t)| !t.info().private) {
-            if !self.peers.contains_key(id) {
-                self.peers.insert(*id, HashSet::new());
-            }
-
-            let (added, removed) = {
-                let peers: HashSet<_> = torrent.peers().values().map(|p| p.addr()).collect();
-                let prev = self.peers.get_mut(id).unwrap();
-                let mut add: Vec<_> = peers.difference(prev).cloned().collect();
-                let mut rem: Vec<_> = prev.difference(&peers).cloned().collect();
-                add.truncate(50);
-                rem.truncate(50 - add.len());
-                (add, rem)
-            };
-            torrent.update_pex(&added, &removed);
+        for torrent in torrents.values_mut() {
+            torrent.update_pex();
         }
-        self.peers.retain(|id, _| torrents.contains_key(id));
     }
 }
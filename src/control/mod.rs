@@ -1,22 +1,37 @@
-use std::net::TcpStream;
-use std::path::PathBuf;
+use std::collections::VecDeque;
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, atomic};
 use std::{fs, io, mem, process, time};
 
-use chrono::Utc;
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone, Utc};
+use ip_network_table::IpNetworkTable;
 
-use crate::config::Config;
-use crate::throttle::Throttler;
-use crate::torrent::{self, Torrent, peer};
+use crate::config::{Config, QuotaAction};
+use crate::rpc::proto::message::{
+    AddError, DiskCounters, DiskStats, ErrorCode, HistoryAction, IpFilterAction,
+};
+use crate::throttle::{self, Throttler};
+use crate::torrent::{self, Torrent, history, peer};
 use crate::util::{
-    self, FHashSet, MHashMap, UHashMap, UHashSet, hash_to_id, id_to_hash, io_err, io_err_val,
+    self, FHashSet, MHashMap, UHashMap, UHashSet, hash_to_id, id_to_hash, io_err_val, native,
     random_string,
 };
-use crate::{DL_TOKEN, SHUTDOWN, disk, rpc, stat, tracker};
+use crate::{DL_TOKEN, RELOAD, SHUTDOWN, disk, rpc, stat, tracker};
 
+mod accept;
 pub mod acio;
 pub mod cio;
+mod ip_filter;
 mod job;
+mod quota;
+mod reannounce;
+mod reload;
+mod schedule;
+mod sessions;
+pub mod supervisor;
+mod tick;
+mod watch;
 
 /// Tracker update job interval
 const TRK_JOB_SECS: u64 = 60;
@@ -26,15 +41,26 @@ const UNCHK_JOB_SECS: u64 = 15;
 const SES_JOB_SECS: u64 = 60;
 /// Interval to update RPC of transfer stats
 const TX_JOB_MS: u64 = 500;
+/// Interval to update RPC of partial piece progress
+const BLOCK_JOB_SECS: u64 = 1;
 /// Interval to check space on disk
 const SPACE_JOB_SECS: u64 = 10;
 /// Interval to send PEX updates
-const PEX_JOB_SECS: u64 = 60 * 5;
+const PEX_JOB_SECS: u64 = 60;
+/// Interval to announce torrents to the local network
+const LSD_JOB_SECS: u64 = 5 * 60;
 /// Interval to enqueue new torrents
 const ENQUEUE_JOB_SECS: u64 = 5;
-
-/// Interval to requery all jobs and execute if needed
-const JOB_INT_MS: usize = 500;
+/// Interval to scan watch directories
+const WATCH_JOB_SECS: u64 = 5;
+/// Interval to check for stalled disk I/O
+const DISK_WATCHDOG_SECS: u64 = 5;
+/// Interval to check for scheduler window boundaries
+const SCHEDULE_JOB_SECS: u64 = 30;
+/// Interval to share the global rates out between torrents
+const REBALANCE_JOB_SECS: u64 = 1;
+/// Window over which recent disk activity is reported
+const DISK_STATS_WINDOW_SECS: u64 = 60;
 
 pub struct Control<T: cio::CIO> {
     config: Arc<Config>,
@@ -51,6 +77,24 @@ pub struct Control<T: cio::CIO> {
     hash_idx: MHashMap<[u8; 20], usize>,
     data: ServerData,
     db: flume::Sender<disk::Request>,
+    /// Index of the active scheduler window, None until first evaluated
+    schedule_slot: Option<Option<usize>>,
+    /// Set by a permanent RPC throttle change, suspending the scheduler
+    throttle_pinned: bool,
+    /// Disk counter samples covering the last DISK_STATS_WINDOW_SECS, oldest first
+    disk_stats: VecDeque<(time::Instant, DiskCounters)>,
+    /// Prefix weights seeded from the config and updated over RPC
+    ip_filter: IpNetworkTable<u8>,
+    /// Set when the filter changed, so connected peers are rechecked on the next job tick
+    ip_filter_dirty: bool,
+    /// Progress of an RPC requested announce to every tracker
+    reannounce: Option<reannounce::Reannounce>,
+    /// Quota usage and threshold last sent over RPC
+    quota_sent: Option<(u64, u8)>,
+    /// Interval of the job timer, stretched while idle
+    tick: tick::Tick,
+    /// Session entries left unloaded at startup as their torrent was already loaded
+    duplicates: Vec<rpc::resource::DuplicateSession>,
 }
 
 #[derive(Serialize, Deserialize, Default)]
@@ -66,6 +110,19 @@ struct ServerData {
     free_space: u64,
     throttle_ul: Option<i64>,
     throttle_dl: Option<i64>,
+    #[serde(skip)]
+    port_mapping: tracker::PortMapping,
+    quota: quota::Usage,
+}
+
+/// Server data as saved before transfer quotas were tracked.
+#[derive(Deserialize)]
+struct ServerDataV1 {
+    id: String,
+    ul: u64,
+    dl: u64,
+    throttle_ul: Option<i64>,
+    throttle_dl: Option<i64>,
 }
 
 struct Queue {
@@ -89,6 +146,18 @@ struct JobData<T> {
     interval: time::Duration,
 }
 
+#[cfg(test)]
+impl Control<cio::test::TCIO> {
+    /// A control on a test CIO, whose disk requests are dropped.
+    pub fn test(config: Config) -> Control<cio::test::TCIO> {
+        let poll = amy::Poller::new().unwrap();
+        let throttler =
+            Throttler::new(None, None, crate::THROT_TOKS, &poll.get_registrar()).unwrap();
+        let (db, _) = flume::unbounded();
+        Control::new(Arc::new(config), cio::test::TCIO::new(), throttler, db).unwrap()
+    }
+}
+
 impl<T: cio::CIO> Control<T> {
     pub fn new(
         config: Arc<Config>,
@@ -113,17 +182,34 @@ impl<T: cio::CIO> Control<T> {
             time::Duration::from_millis(TX_JOB_MS),
         );
         jobs.add_job(
-            job::PEXUpdate::new(),
-            time::Duration::from_secs(PEX_JOB_SECS),
+            job::BlockProgressUpdate,
+            time::Duration::from_secs(BLOCK_JOB_SECS),
         );
+        jobs.add_job(job::PEXUpdate, time::Duration::from_secs(PEX_JOB_SECS));
+        jobs.add_job(job::LSDUpdate, time::Duration::from_secs(LSD_JOB_SECS));
 
         jobs.add_cjob(SpaceUpdate, time::Duration::from_secs(SPACE_JOB_SECS));
         jobs.add_cjob(EnqueueUpdate, time::Duration::from_secs(ENQUEUE_JOB_SECS));
         jobs.add_cjob(SerializeUpdate, time::Duration::from_secs(SES_JOB_SECS));
+        jobs.add_cjob(DiskWatchdog, time::Duration::from_secs(DISK_WATCHDOG_SECS));
+        jobs.add_cjob(
+            RebalanceUpdate,
+            time::Duration::from_secs(REBALANCE_JOB_SECS),
+        );
+        if !config.watch_dirs.is_empty() {
+            jobs.add_cjob(WatchUpdate, time::Duration::from_secs(WATCH_JOB_SECS));
+        }
+        if !config.scheduler.is_empty() {
+            jobs.add_cjob(ScheduleUpdate, time::Duration::from_secs(SCHEDULE_JOB_SECS));
+        }
         let job_timer = cio
-            .set_timer(JOB_INT_MS)
+            .set_timer(tick::BASE_TICK_MS)
             .map_err(|_| io_err_val("timer failure!"))?;
         let max_dl = config.max_dl;
+        let mut ip_filter = IpNetworkTable::new();
+        for (net, weight) in config.ip_filter.iter() {
+            ip_filter.insert(net, *weight);
+        }
         Ok(Control {
             config,
             throttler,
@@ -139,6 +225,15 @@ impl<T: cio::CIO> Control<T> {
             data: Default::default(),
             db,
             queue: Queue::new(max_dl as usize),
+            schedule_slot: None,
+            throttle_pinned: false,
+            disk_stats: VecDeque::new(),
+            ip_filter,
+            ip_filter_dirty: false,
+            reannounce: None,
+            quota_sent: None,
+            tick: tick::Tick::new(time::Instant::now()),
+            duplicates: Vec::new(),
         })
     }
 
@@ -148,9 +243,16 @@ impl<T: cio::CIO> Control<T> {
         }
         debug!("Initialized!");
         self.send_rpc_info();
+        if !self.config.scheduler.is_empty() {
+            self.apply_schedule(Local::now().naive_local());
+        }
+        if self.quota_throttled() {
+            let rate = Some(self.config.quota.throttle);
+            self.set_throttle(self.data.id.clone(), rate, rate);
+        }
         let mut events = Vec::with_capacity(20);
         'outer: loop {
-            if let Err(e) = self.cio.poll(&mut events) {
+            if let Err(e) = self.poll(&mut events) {
                 error!("{}", e);
                 break;
             }
@@ -160,12 +262,25 @@ impl<T: cio::CIO> Control<T> {
                 }
             }
             if SHUTDOWN.load(atomic::Ordering::SeqCst) {
+                info!("Shutting down, saving session state");
                 break;
             }
+            if RELOAD.swap(false, atomic::Ordering::SeqCst) {
+                match Config::reload() {
+                    Ok(config) => self.reload_config(config),
+                    Err(e) => error!("Failed to reload config, keeping the current one: {}", e),
+                }
+            }
         }
         self.serialize();
     }
 
+    /// Waits for events until the job timer is next due at the latest. The timers wake
+    /// the poll anyway, so this only stops the wait from adding wakeups of its own.
+    fn poll(&mut self, events: &mut Vec<cio::Event>) -> cio::Result<()> {
+        self.cio.poll(events, self.tick.interval())
+    }
+
     fn serialize(&mut self) {
         let sd = &self.config.disk.session;
         debug!("Serializing server data!");
@@ -190,7 +305,12 @@ impl<T: cio::CIO> Control<T> {
         debug!("Deserializing server data!");
         let mut pb = PathBuf::from(sd);
         pb.push("syn_data");
-        if let Ok(Ok(data)) = fs::File::open(pb).map(|mut f| bincode::deserialize_from(&mut f)) {
+        let data = fs::read(pb).ok().and_then(|d| {
+            bincode::deserialize(&d)
+                .or_else(|_| bincode::deserialize::<ServerDataV1>(&d).map(ServerData::from))
+                .ok()
+        });
+        if let Some(data) = data {
             self.data = data;
             self.throttler.set_ul_rate(self.data.throttle_ul);
             self.throttler.set_dl_rate(self.data.throttle_dl);
@@ -204,65 +324,106 @@ impl<T: cio::CIO> Control<T> {
         // migrate the data on disk to a new format and modify/write files to the session
         // directory. POSIX does not provide any guarantees about how `opendir()` and `readdir()`
         // will behave with respect to concurrent mutations.
-        let entries: Vec<_> = fs::read_dir(sd)?.collect();
+        let mut entries: Vec<_> = fs::read_dir(sd)?.collect();
+        // Which of two entries for the same torrent wins shouldn't depend on directory order
+        entries.sort_by_key(|e| e.as_ref().ok().map(fs::DirEntry::file_name));
+        let mut saved = Vec::new();
+        let mut loaded: MHashMap<[u8; 20], PathBuf> = MHashMap::default();
         for entry in entries {
-            if self.deserialize_torrent(entry).is_err() {
-                error!(
-                    "Please ensure that session data is not corrupted and not past version {}",
-                    env!("CARGO_PKG_VERSION")
-                );
-                process::exit(1);
+            let (path, s) = match sessions::read(entry) {
+                Ok(Some(entry)) => entry,
+                Ok(None) => continue,
+                Err(_) => {
+                    error!(
+                        "Please ensure that session data is not corrupted and not past version {}",
+                        env!("CARGO_PKG_VERSION")
+                    );
+                    process::exit(1);
+                }
+            };
+            if let Some(first) = loaded.get(&s.hash()) {
+                self.set_aside_duplicate(&s.hash(), first, &path);
+                continue;
             }
+            loaded.insert(s.hash(), path.clone());
+            saved.push((path, s));
+        }
+        for (path, s) in saved {
+            // The torrent is saved under its own hash from now on, which would leave a
+            // copy under any other name behind to clash with it
+            let id = hash_to_id(&s.hash());
+            if path.file_name() != Some(id.as_ref())
+                && let Err(e) = sessions::rename(&path, &id)
+            {
+                error!("Failed to rename session file {}: {}", path.display(), e);
+            }
+            self.restore_torrent(s);
+        }
+        if !self.duplicates.is_empty() {
+            error!(
+                "{} duplicate session entries were not loaded, see the messages above",
+                self.duplicates.len()
+            );
         }
         Ok(())
     }
 
-    fn deserialize_torrent(&mut self, entry: io::Result<fs::DirEntry>) -> io::Result<()> {
-        let dir = entry?;
-        // TODO: We probably should improve this heuristic with and not rely
-        // on directory entries, but this is good enough for now.
-        if dir.file_name().len() != 40 {
-            return Ok(());
-        }
-        trace!("Attempting to deserialize file {:?}", dir);
-        let mut path = dir.path();
-        let session_data = fs::read(&path)?;
-        trace!("Succesfully read session file");
-        assert!(path.set_extension("info"));
-        let info_data = match fs::read(&path) {
-            Ok(data) => Ok(Some(data)),
-            // Older versions of synapse serialized the info as part of the session state, so a
-            // missing info file is not necessarily fatal.
-            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
-            Err(e) => Err(e),
-        }?;
-        trace!("Successully read info file");
-
+    fn restore_torrent(&mut self, saved: torrent::Saved) {
         let tid = self.tid_cnt;
         let throttle = self.throttler.get_throttle(tid);
-        if let Some(t) = Torrent::deserialize(
+        let t = Torrent::restore(
             self.config.clone(),
             tid,
-            &session_data,
-            info_data.as_deref(),
+            saved,
             throttle,
             self.cio.new_handle(),
-        ) {
-            trace!("Succesfully parsed torrent file {:?}", dir.path());
-            self.hash_idx.insert(t.info().hash, tid);
-            self.tid_cnt += 1;
-            if t.status().leeching() {
-                self.queue.add(tid, t.priority());
-            }
-            self.torrents.insert(tid, t);
-        } else {
-            error!("Failed to deserialize torrent {:?}", dir.file_name());
-            return io_err("Torrent data invalid!");
+        );
+        self.hash_idx.insert(t.info().hash, tid);
+        self.tid_cnt += 1;
+        if t.status().leeching() {
+            self.queue.add(tid, t.priority());
         }
-        Ok(())
+        self.torrents.insert(tid, t);
+    }
+
+    /// Moves aside the session files of a torrent which was already loaded from `first`.
+    fn set_aside_duplicate(&mut self, hash: &[u8; 20], first: &Path, path: &Path) {
+        let id = hash_to_id(hash);
+        let quarantined = match sessions::quarantine(path) {
+            Ok(moved) => {
+                error!(
+                    "Torrent {} is in both session files {} and {}! Only the first was \
+                     loaded, the other was moved to {}",
+                    id,
+                    first.display(),
+                    path.display(),
+                    moved.display()
+                );
+                moved
+            }
+            Err(e) => {
+                error!(
+                    "Torrent {} is in both session files {} and {}! Only the first was \
+                     loaded, and the other couldn't be moved aside: {}",
+                    id,
+                    first.display(),
+                    path.display(),
+                    e
+                );
+                path.to_owned()
+            }
+        };
+        self.duplicates.push(rpc::resource::DuplicateSession {
+            infohash: id,
+            loaded: first.display().to_string(),
+            quarantined: quarantined.display().to_string(),
+        });
     }
 
     fn handle_event(&mut self, event: cio::Event) -> bool {
+        if tick::is_activity(&event) {
+            self.note_activity(time::Instant::now());
+        }
         match event {
             cio::Event::Tracker(Ok(e)) => {
                 self.handle_trk_ev(e);
@@ -285,6 +446,9 @@ impl<T: cio::CIO> Control<T> {
             cio::Event::Incoming(conn) => {
                 self.handle_incoming_conn(conn);
             }
+            cio::Event::Restarted(worker) => {
+                self.handle_restart(worker);
+            }
             cio::Event::Timer(t) => {
                 if t == self.throttler.id() {
                     let (ul, dl) = self.throttler.update();
@@ -292,13 +456,19 @@ impl<T: cio::CIO> Control<T> {
                     self.data.dl += dl;
                     self.data.session_ul += ul;
                     self.data.session_dl += dl;
+                    if self.config.quota.limit != 0 {
+                        self.data.quota.used += ul + dl;
+                    }
                     self.stat.add_ul(ul);
                     self.stat.add_dl(dl);
                 } else if t == self.throttler.fid() {
                     self.flush_blocked_peers();
                 } else if t == self.job_timer {
                     self.update_jobs();
+                    self.update_reannounce();
+                    self.update_quota(Local::now().naive_local());
                     self.update_rpc_tx();
+                    self.stretch_tick(time::Instant::now());
                 } else {
                     error!("unknown timer id {} reported", t);
                 }
@@ -310,10 +480,59 @@ impl<T: cio::CIO> Control<T> {
         false
     }
 
+    /// Stretches the job timer while nothing is happening. The throttler's timers are
+    /// stopped meanwhile, as no peer is waiting on tokens.
+    fn stretch_tick(&mut self, now: time::Instant) {
+        if !self.throttler.idle() {
+            return;
+        }
+        let stretched = self.tick.stretched();
+        let Some(interval) = self.tick.idle(now) else {
+            return;
+        };
+        debug!("Idle, stretching the tick to {} ms", interval);
+        if !stretched {
+            self.cio.cancel_timer(self.throttler.id());
+            self.cio.cancel_timer(self.throttler.fid());
+        }
+        self.set_job_timer(interval);
+    }
+
+    /// Returns the job timer, and the throttler's timers, to the base rate.
+    fn note_activity(&mut self, now: time::Instant) {
+        if !self.tick.activity(now) {
+            return;
+        }
+        debug!("Activity resumed, restoring the tick");
+        match (
+            self.cio.set_timer(throttle::URATE),
+            self.cio.set_timer(throttle::FLUSH_MS),
+        ) {
+            (Ok(id), Ok(fid)) => self.throttler.set_timers(id, fid),
+            _ => error!("Failed to restart the throttler timers!"),
+        }
+        self.set_job_timer(tick::BASE_TICK_MS);
+    }
+
+    fn set_job_timer(&mut self, interval: usize) {
+        self.cio.cancel_timer(self.job_timer);
+        match self.cio.set_timer(interval) {
+            Ok(t) => self.job_timer = t,
+            Err(e) => error!("Failed to set the job timer: {:?}", e),
+        }
+        self.update_rpc_tick();
+    }
+
     fn handle_trk_ev(&mut self, tr: tracker::Response) {
         let (id, peers) = match tr {
             tracker::Response::Tracker { tid, url, resp } => {
                 debug!("Handling tracker response for {:?}", url);
+                if let Some(r) = &mut self.reannounce
+                    && r.response(tid, &url, resp.is_ok())
+                    && r.complete()
+                {
+                    self.update_rpc_reannounce();
+                }
                 if let Some(torrent) = self.torrents.get_mut(&tid) {
                     torrent.set_tracker_response(url.as_ref(), &resp);
                     if let Ok(r) = resp {
@@ -325,25 +544,178 @@ impl<T: cio::CIO> Control<T> {
                     return;
                 }
             }
-            tracker::Response::DHT { tid, peers } | tracker::Response::PEX { tid, peers } => {
-                (tid, peers)
+            tracker::Response::DHT { tid, peers }
+            | tracker::Response::PEX { tid, peers }
+            | tracker::Response::LSD { tid, peers } => (tid, peers),
+            tracker::Response::PortMapped(m) => {
+                self.set_port_mapping(m);
+                return;
             }
         };
-        for ip in &peers {
-            trace!("Adding peer({:?})!", ip);
-            match peer::PeerConn::new_outgoing(&self.config.ip_filter, ip) {
-                Ok(peer) => {
-                    trace!("Added peer({:?})!", ip);
-                    self.add_peer(id, peer);
-                }
-                Err(e) => {
-                    trace!("Failed to add peer: {:?}", e);
-                }
+        for ip in &self.dial_order(id, peers) {
+            self.connect_peer(id, ip);
+        }
+    }
+
+    /// Orders peers to dial by their BEP 40 priority relative to our own address, highest
+    /// first, so that swarms tend to settle on the same connections. Peers of a family we
+    /// don't know our address in go last, in the order given.
+    fn dial_order(&self, id: usize, peers: Vec<SocketAddr>) -> Vec<SocketAddr> {
+        let (v4, v6) = match self.torrents.get(&id) {
+            Some(t) => t.announce_ips(),
+            None => (self.config.trk.announce_ip, self.config.trk.announce_ip6),
+        };
+        let m = &self.data.port_mapping;
+        let port = m.port.unwrap_or(self.config.port);
+        let ours = |ip: &SocketAddr| {
+            let own = match ip {
+                SocketAddr::V4(_) => v4.map(IpAddr::V4).or(m.external_ip.filter(|e| e.is_ipv4())),
+                SocketAddr::V6(_) => v6.map(IpAddr::V6).or(m.external_ip.filter(|e| e.is_ipv6())),
+            };
+            own.map(|own| SocketAddr::new(own, port))
+        };
+        let mut ranked: Vec<_> = peers
+            .into_iter()
+            .map(|ip| (ours(&ip).map(|own| util::peer_priority(&own, &ip)), ip))
+            .collect();
+        ranked.sort_by_key(|&(prio, _)| std::cmp::Reverse(prio));
+        for (prio, ip) in &ranked {
+            if let Some(prio) = prio {
+                trace!("Peer {:?} has priority {:08x}", ip, prio);
+            }
+        }
+        ranked.into_iter().map(|(_, ip)| ip).collect()
+    }
+
+    /// Publishes a new gateway mapping, re-announcing so trackers and the DHT learn of
+    /// any change to the ports peers should connect to.
+    fn set_port_mapping(&mut self, m: tracker::PortMapping) {
+        let moved =
+            (m.port, m.dht_port) != (self.data.port_mapping.port, self.data.port_mapping.dht_port);
+        self.data.port_mapping = m;
+        self.update_rpc_port_mapping();
+        if moved {
+            for torrent in self.torrents.values_mut() {
+                torrent.update_tracker();
+            }
+        }
+    }
+
+    fn connect_peer(&mut self, id: usize, ip: &SocketAddr) {
+        trace!("Adding peer({:?})!", ip);
+        if self.peers_full() || self.torrents.get(&id).is_some_and(|t| !t.peers_wanted()) {
+            return;
+        }
+        let threshold = self.config.peer.ip_filter_threshold;
+        let nodelay = self.config.peer.nodelay;
+        match peer::PeerConn::new_outgoing(&self.ip_filter, threshold, ip, nodelay) {
+            Ok(peer) => {
+                trace!("Added peer({:?})!", ip);
+                self.add_peer(id, peer);
+            }
+            Err(e) => {
+                trace!("Failed to add peer: {:?}", e);
+            }
+        }
+    }
+
+    fn update_ip_filter(
+        &mut self,
+        action: IpFilterAction,
+        ranges: &[String],
+        path: Option<&str>,
+    ) -> Result<usize, String> {
+        let mut networks = Vec::new();
+        for range in ranges {
+            networks.extend(util::ip_filter::parse_range(range)?);
+        }
+        if let Some(path) = path {
+            let data =
+                fs::read_to_string(path).map_err(|e| format!("Failed to read {path}: {e}"))?;
+            let parsed = util::ip_filter::parse_file(&data);
+            networks.extend(parsed.map_err(|e| format!("{path}: {e}"))?);
+        }
+
+        let blocked = ip_filter::update(&mut self.ip_filter, action, networks);
+        self.ip_filter_dirty = true;
+        Ok(blocked)
+    }
+
+    /// Applies the settings of a reloaded config which can change while running: the
+    /// scheduler, the ip filter and the peer limits. Anything else keeps its current
+    /// value until a restart.
+    fn reload_config(&mut self, new: Config) {
+        let old = &self.config;
+        let restart = reload::restart_needed(old, &new);
+        if !restart.is_empty() {
+            error!(
+                "Changes to {} only take effect after a restart",
+                restart.join(", ")
+            );
+        }
+        let ip_filter = reload::ip_filter(&self.ip_filter, old, &new);
+        let schedule_changed = old.scheduler != new.scheduler;
+        let mut peer = old.peer.clone();
+        peer.max_peers_global = new.peer.max_peers_global;
+        peer.max_peers_per_torrent = new.peer.max_peers_per_torrent;
+        peer.ip_filter_threshold = new.peer.ip_filter_threshold;
+        peer.blocklist = new.peer.blocklist;
+        let config = Config {
+            port: old.port,
+            max_dl: old.max_dl,
+            crash_reports: old.crash_reports,
+            trk: old.trk.clone(),
+            dht: old.dht.clone(),
+            dns: old.dns.clone(),
+            rpc: old.rpc.clone(),
+            disk: old.disk.clone(),
+            net: old.net.clone(),
+            peer,
+            idle: old.idle.clone(),
+            quota: old.quota.clone(),
+            watch_dirs: old.watch_dirs.clone(),
+            scheduler: new.scheduler,
+            ip_filter: new.ip_filter,
+        };
+        if old.scheduler.is_empty() && !config.scheduler.is_empty() {
+            self.jobs
+                .add_cjob(ScheduleUpdate, time::Duration::from_secs(SCHEDULE_JOB_SECS));
+        }
+        self.config = Arc::new(config);
+        for torrent in self.torrents.values_mut() {
+            torrent.set_config(self.config.clone());
+        }
+        self.ip_filter = ip_filter;
+        self.ip_filter_dirty = true;
+        if schedule_changed {
+            self.schedule_slot = None;
+            self.apply_schedule(Local::now().naive_local());
+        }
+        info!("Reloaded config");
+    }
+
+    /// Disconnects peers whose address has been blocked since they connected.
+    fn enforce_ip_filter(&mut self) {
+        let pids: Vec<_> = self.peers.keys().chain(&self.incoming).copied().collect();
+        for pid in pids {
+            let Some(addr) = self.cio.get_peer(pid, |p| p.sock().addr()) else {
+                continue;
+            };
+            if util::ip_filter::blocks(
+                &self.ip_filter,
+                self.config.peer.ip_filter_threshold,
+                addr.ip(),
+            ) {
+                debug!("Disconnecting peer {} blocked by ip_filter", addr.ip());
+                self.cio.remove_peer(pid);
             }
         }
     }
 
     fn update_jobs(&mut self) {
+        if mem::take(&mut self.ip_filter_dirty) {
+            self.enforce_ip_filter();
+        }
         let mut jobs = mem::replace(&mut self.jobs, JobManager::new());
         jobs.update(self);
         self.jobs = jobs;
@@ -356,13 +728,78 @@ impl<T: cio::CIO> Control<T> {
                 self.data.free_space = space;
                 self.update_rpc_space();
             }
+        } else if let disk::Response::PathAnalysis(client, serial, analysis) = resp {
+            let msg = match analysis {
+                Ok(analysis) => rpc::CtlMessage::PathAnalysis { client, analysis },
+                Err(e) => rpc::CtlMessage::Error {
+                    code: if e.kind() == io::ErrorKind::PermissionDenied {
+                        ErrorCode::PermissionDenied
+                    } else {
+                        ErrorCode::ServerError
+                    },
+                    reason: format!("Failed to analyze path: {e}"),
+                    client,
+                    serial,
+                },
+            };
+            self.cio.msg_rpc(msg);
+        } else if let disk::Response::Stats(counters) = resp {
+            self.record_disk_stats(time::Instant::now(), counters);
         } else if let Some(torrent) = self.torrents.get_mut(&resp.tid()) {
             torrent.handle_disk_resp(resp);
         }
     }
 
+    fn record_disk_stats(&mut self, now: time::Instant, counters: DiskCounters) {
+        let window = time::Duration::from_secs(DISK_STATS_WINDOW_SECS);
+        let prev = self.disk_stats.back().map(|s| s.1).unwrap_or_default();
+        if (prev.piece_cache_hits, prev.piece_cache_misses)
+            != (counters.piece_cache_hits, counters.piece_cache_misses)
+        {
+            self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+                rpc::resource::SResourceUpdate::ServerPieceCache {
+                    id: self.data.id.clone(),
+                    kind: rpc::resource::ResourceKind::Server,
+                    piece_cache_hits: counters.piece_cache_hits,
+                    piece_cache_misses: counters.piece_cache_misses,
+                },
+            ]));
+        }
+        self.disk_stats.push_back((now, counters));
+        // Keep the newest sample at least a window old so the delta always spans a full minute
+        // once enough history exists.
+        while self.disk_stats.len() > 1 && now.duration_since(self.disk_stats[1].0) >= window {
+            self.disk_stats.pop_front();
+        }
+    }
+
+    fn disk_stats(&self, serial: u64) -> DiskStats {
+        let total = self.disk_stats.back().map(|s| s.1).unwrap_or_default();
+        let earliest = self.disk_stats.front().map(|s| s.1).unwrap_or_default();
+        DiskStats {
+            serial,
+            total,
+            last_minute: total.since(&earliest),
+        }
+    }
+
+    /// Whether the global peer limit has been reached, counting connections which have yet
+    /// to handshake.
+    fn peers_full(&self) -> bool {
+        self.peers.len() + self.incoming.len() >= self.config.peer.max_peers_global
+    }
+
     fn handle_incoming_conn(&mut self, conn: TcpStream) {
-        match peer::PeerConn::new_incoming(&self.config.ip_filter, conn) {
+        if self.peers_full() {
+            debug!(
+                "Refusing incoming connection, already at the limit of {} peers",
+                self.config.peer.max_peers_global
+            );
+            return;
+        }
+        let threshold = self.config.peer.ip_filter_threshold;
+        let nodelay = self.config.peer.nodelay;
+        match peer::PeerConn::new_incoming(&self.ip_filter, threshold, conn, nodelay) {
             Ok(pconn) => match self.cio.add_peer(pconn) {
                 Ok(pid) => {
                     self.incoming.insert(pid);
@@ -385,14 +822,9 @@ impl<T: cio::CIO> Control<T> {
         match ev {
             Ok(msg) => match msg {
                 torrent::Message::Handshake { hash, id, rsv } => {
-                    debug!("Adding peer for torrent with hash {:?}!", hash_to_id(&hash));
-                    if let Some(tid) = self.hash_idx.get(&hash).cloned() {
+                    if let Some(tid) = accept::torrent(&self.hash_idx, &hash, &id) {
+                        debug!("Adding peer for torrent with hash {:?}!", hash_to_id(&hash));
                         return self.add_inc_peer(tid, pid, id, rsv);
-                    } else {
-                        error!(
-                            "Couldn't add peer, torrent {} doesn't exist",
-                            hash_to_id(&hash)
-                        );
                     }
                 }
                 // The Reader is instantiated in State::Handshake, so
@@ -414,6 +846,9 @@ impl<T: cio::CIO> Control<T> {
             {
                 p.remove(&pid);
                 torrent.update_rpc_peers();
+                for addr in torrent.take_redial() {
+                    self.connect_peer(tid, &addr);
+                }
             }
         } else if self.incoming.remove(&pid) && self.inc_handshake(pid, ev).is_err() {
             self.cio.remove_peer(pid);
@@ -425,29 +860,27 @@ impl<T: cio::CIO> Control<T> {
         self.cio.flush_peers(self.throttler.flush_ul());
     }
 
-    fn add_torrent(
+    /// Creates and enqueues a torrent, returning its RPC id.
+    fn create_torrent(
         &mut self,
         info: torrent::Info,
         path: Option<String>,
         start: bool,
         import: bool,
-        client: usize,
-        serial: u64,
-    ) {
+        preset: torrent::Preset,
+    ) -> Result<String, (AddError, String)> {
         debug!("Adding {:?}, start: {}!", info, start);
         let id = hash_to_id(&info.hash);
         if self.hash_idx.contains_key(&info.hash) {
             debug!("Tried to add torrent that already exists!");
-            self.cio.msg_rpc(rpc::CtlMessage::Error {
-                client,
-                serial,
-                reason: format!("Torrent {id} already exists"),
-            });
-            return;
+            return Err((AddError::Duplicate, format!("Torrent {id} already exists")));
         }
+        let dir = path.as_ref().unwrap_or(&self.config.disk.directory);
+        disk::check_info(Path::new(dir), &info, native::fs_info)
+            .map_err(|e| (AddError::Storage, e.to_string()))?;
         let tid = self.tid_cnt;
         let throttle = self.throttler.get_throttle(tid);
-        let t = Torrent::new(
+        let mut t = Torrent::new(
             self.config.clone(),
             tid,
             path,
@@ -457,18 +890,224 @@ impl<T: cio::CIO> Control<T> {
             start,
             import,
         );
+        t.apply_preset(preset);
+        t.set_history(sessions::read_history(
+            &Path::new(&self.config.disk.session).join(&id),
+        ));
         self.hash_idx.insert(t.info().hash, tid);
         self.tid_cnt += 1;
         self.queue.add(tid, t.priority());
         self.torrents.insert(tid, t);
-        self.cio
-            .msg_rpc(rpc::CtlMessage::Uploaded { id, client, serial })
+        Ok(id)
+    }
+
+    /// Applies the limits of the scheduler window covering `now`, or the
+    /// base rates outside of any window, whenever a boundary is crossed.
+    fn apply_schedule(&mut self, now: NaiveDateTime) {
+        let rules = &self.config.scheduler;
+        let slot = schedule::active(rules, now);
+        if self.schedule_slot == Some(slot) {
+            return;
+        }
+        self.schedule_slot = Some(slot);
+        if self.throttle_pinned || self.quota_throttled() {
+            return;
+        }
+        let (ul, dl) = match slot {
+            Some(i) => (rules[i].upload, rules[i].download),
+            None => (self.data.throttle_ul, self.data.throttle_dl),
+        };
+        if slot.is_some() {
+            info!("Entering scheduler window, throttle {:?}/{:?}", ul, dl);
+        } else {
+            info!("Leaving scheduler window, throttle {:?}/{:?}", ul, dl);
+        }
+        self.set_throttle(self.data.id.clone(), ul, dl);
+    }
+
+    /// Whether the quota has run out and its throttle is in place of the usual one.
+    fn quota_throttled(&self) -> bool {
+        let cfg = &self.config.quota;
+        cfg.limit != 0 && cfg.action == QuotaAction::Throttle && self.data.quota.exhausted()
+    }
+
+    /// Starts a new quota period once the current one is over, and acts on any thresholds
+    /// crossed since the last update.
+    fn update_quota(&mut self, now: NaiveDateTime) {
+        let config = self.config.clone();
+        let cfg = &config.quota;
+        if cfg.limit == 0 {
+            return;
+        }
+        let exhausted = self.data.quota.exhausted();
+        if self.data.quota.roll(cfg, now) {
+            info!("Transfer quota period reset");
+            if exhausted {
+                self.lift_quota(now);
+            }
+        }
+        for threshold in self.data.quota.cross(cfg) {
+            if threshold < 100 {
+                info!(
+                    "{}% of the transfer quota used ({} of {} bytes)",
+                    threshold, self.data.quota.used, cfg.limit
+                );
+                continue;
+            }
+            error!(
+                "Transfer quota of {} bytes used up, applying {:?} until {:?}",
+                cfg.limit,
+                cfg.action,
+                self.data.quota.reset_at(cfg)
+            );
+            match cfg.action {
+                QuotaAction::Throttle => {
+                    let rate = Some(cfg.throttle);
+                    self.set_throttle(self.data.id.clone(), rate, rate);
+                }
+                QuotaAction::Pause => {
+                    for torrent in self.torrents.values_mut() {
+                        if !torrent.status().paused {
+                            torrent.pause();
+                            let detail = Some("quota used up".to_owned());
+                            torrent.record(history::SYSTEM, HistoryAction::Pause, detail);
+                            self.data.quota.paused.push(torrent.rpc_id());
+                        }
+                    }
+                }
+            }
+        }
+        let q = &self.data.quota;
+        if self.quota_sent != Some((q.used, q.crossed)) {
+            self.quota_sent = Some((q.used, q.crossed));
+            self.update_rpc_quota(now);
+        }
+    }
+
+    /// Undoes the action taken when the quota ran out.
+    fn lift_quota(&mut self, now: NaiveDateTime) {
+        if self.config.quota.action == QuotaAction::Throttle {
+            if self.throttle_pinned {
+                let (ul, dl) = (self.data.throttle_ul, self.data.throttle_dl);
+                self.set_throttle(self.data.id.clone(), ul, dl);
+            } else {
+                self.schedule_slot = None;
+                self.apply_schedule(now);
+            }
+        }
+        let paused = mem::take(&mut self.data.quota.paused);
+        for torrent in self.torrents.values_mut() {
+            if torrent.status().paused && paused.contains(&torrent.rpc_id()) {
+                torrent.resume();
+                let detail = Some("quota reset".to_owned());
+                torrent.record(history::SYSTEM, HistoryAction::Resume, detail);
+            }
+        }
+    }
+
+    /// The quota fields of the server resource as of `now`.
+    fn rpc_quota(&self, now: NaiveDateTime) -> (Option<DateTime<Utc>>, Option<u32>) {
+        let Some(reset) = self.data.quota.reset_at(&self.config.quota) else {
+            return (None, None);
+        };
+        let days_left = ((reset - now).num_hours().max(0) as u32).div_ceil(24);
+        let reset = Local
+            .from_local_datetime(&reset)
+            .earliest()
+            .map(|r| r.with_timezone(&Utc));
+        (reset, Some(days_left))
+    }
+
+    fn update_rpc_quota(&mut self, now: NaiveDateTime) {
+        let (quota_reset, quota_days_left) = self.rpc_quota(now);
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            rpc::resource::SResourceUpdate::ServerQuota {
+                id: self.data.id.clone(),
+                kind: rpc::resource::ResourceKind::Server,
+                quota_used: self.data.quota.used,
+                quota_threshold: self.data.quota.crossed,
+                quota_reset,
+                quota_days_left,
+            },
+        ]));
+    }
+
+    fn set_throttle(&mut self, id: String, ul: Option<i64>, dl: Option<i64>) {
+        self.throttler.set_ul_rate(ul);
+        self.throttler.set_dl_rate(dl);
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            rpc::resource::SResourceUpdate::Throttle {
+                id,
+                kind: rpc::resource::ResourceKind::Server,
+                throttle_up: ul,
+                throttle_down: dl,
+            },
+        ]));
+    }
+
+    fn check_disk(&mut self) {
+        let timeout = time::Duration::from_secs(self.config.disk.stall_timeout);
+        let stalled: Vec<_> = self
+            .torrents
+            .values_mut()
+            .filter_map(|t| t.check_disk(timeout).then(|| t.info().name.clone()))
+            .collect();
+        if !stalled.is_empty() {
+            error!(
+                "Disk I/O stalled: no reads or writes have completed in {}s, \
+                 check that the storage for {} is responsive",
+                timeout.as_secs(),
+                stalled.join(", ")
+            );
+        }
+    }
+
+    fn scan_watch_dirs(&mut self) {
+        let config = self.config.clone();
+        for dir in &config.watch_dirs {
+            let files = match watch::pending(Path::new(&dir.path)) {
+                Ok(files) => files,
+                Err(e) => {
+                    debug!("Failed to scan watch dir {}: {}", dir.path, e);
+                    continue;
+                }
+            };
+            for file in files {
+                let res = watch::load(&file).and_then(|info| {
+                    self.create_torrent(
+                        info,
+                        dir.directory.clone(),
+                        !dir.paused,
+                        false,
+                        torrent::Preset::default(),
+                    )
+                    .map_err(|(_, reason)| reason)
+                });
+                let res = match res {
+                    Ok(id) => {
+                        info!("Added torrent {} from {}", id, file.display());
+                        if dir.delete {
+                            fs::remove_file(&file)
+                        } else {
+                            watch::mark(&file, watch::LOADED_EXT)
+                        }
+                    }
+                    Err(e) => {
+                        error!("Failed to add torrent from {}: {}", file.display(), e);
+                        watch::mark(&file, watch::FAILED_EXT)
+                    }
+                };
+                if let Err(e) = res {
+                    error!("Failed to clean up watched file {}: {}", file.display(), e);
+                }
+            }
+        }
     }
 
     fn handle_rpc_ev(&mut self, req: rpc::Message) -> bool {
         debug!("Handling rpc reqest!");
         match req {
-            rpc::Message::UpdateTorrent(u) => {
+            rpc::Message::UpdateTorrent { update: u, by } => {
                 let hash_idx = &self.hash_idx;
                 let torrents = &mut self.torrents;
                 let res = id_to_hash(&u.id)
@@ -476,9 +1115,24 @@ impl<T: cio::CIO> Control<T> {
                     .and_then(|i| torrents.get_mut(i));
                 if let Some(t) = res {
                     let old_pri = t.priority();
+                    let moved = match &u.path {
+                        Some(rpc::resource::PathUpdate::Move(p)) => Some(p.clone()),
+                        Some(rpc::resource::PathUpdate::MoveSkipFiles(p)) => {
+                            Some(format!("{p} (skipping files)"))
+                        }
+                        None => None,
+                    };
                     t.rpc_update(u);
                     let new_pri = t.priority();
-                    self.queue.modify_pri(t.id(), new_pri, old_pri);
+                    if let Some(path) = moved {
+                        t.record(&by, HistoryAction::Move, Some(path));
+                    }
+                    if new_pri != old_pri {
+                        t.record(&by, HistoryAction::Priority, Some(new_pri.to_string()));
+                    }
+                    if t.status().leeching() {
+                        self.queue.modify_pri(t.id(), new_pri, old_pri);
+                    }
                 }
             }
             rpc::Message::Torrent {
@@ -486,13 +1140,25 @@ impl<T: cio::CIO> Control<T> {
                 path,
                 start,
                 import,
+                preset,
                 client,
                 serial,
-            } => self.add_torrent(info, path, start, import, client, serial),
+            } => match self.create_torrent(info, path, start, import, preset) {
+                Ok(id) => self
+                    .cio
+                    .msg_rpc(rpc::CtlMessage::Uploaded { id, client, serial }),
+                Err((error, reason)) => self.cio.msg_rpc(rpc::CtlMessage::AddFailed {
+                    error,
+                    reason,
+                    client,
+                    serial,
+                }),
+            },
             rpc::Message::UpdateFile {
                 id,
                 torrent_id,
                 priority,
+                by,
             } => {
                 let hash_idx = &self.hash_idx;
                 let torrents = &mut self.torrents;
@@ -500,7 +1166,37 @@ impl<T: cio::CIO> Control<T> {
                     .and_then(|d| hash_idx.get(d.as_ref()))
                     .and_then(|i| torrents.get_mut(i));
                 if let Some(t) = res {
+                    let file = t.file_path(&id).unwrap_or_else(|| id.clone());
                     t.rpc_update_file(id, priority);
+                    let detail = format!("{file}: {priority}");
+                    t.record(&by, HistoryAction::FilePriority, Some(detail));
+                }
+            }
+            rpc::Message::EditTracker {
+                id,
+                torrent_id,
+                url,
+                headers,
+                by,
+            } => {
+                let hash_idx = &self.hash_idx;
+                let torrents = &mut self.torrents;
+                let res = id_to_hash(&torrent_id)
+                    .and_then(|d| hash_idx.get(d.as_ref()))
+                    .and_then(|i| torrents.get_mut(i));
+                if let Some(t) = res {
+                    let old = t.tracker_url(&id);
+                    if let Some(url) = url {
+                        t.update_tracker_url(&id, url);
+                    }
+                    if let Some(headers) = headers {
+                        t.update_tracker_headers(&id, &headers);
+                    }
+                    let detail = match (old, t.tracker_url(&id)) {
+                        (Some(old), Some(new)) if old != new => Some(format!("{old} -> {new}")),
+                        (_, new) => new,
+                    };
+                    t.record(&by, HistoryAction::EditTracker, detail);
                 }
             }
             rpc::Message::AddPeer {
@@ -512,7 +1208,9 @@ impl<T: cio::CIO> Control<T> {
                 let res = id_to_hash(&id)
                     .and_then(|d| self.hash_idx.get(d.as_ref()))
                     .cloned();
-                let pres = peer::PeerConn::new_outgoing(&self.config.ip_filter, &peer);
+                let threshold = self.config.peer.ip_filter_threshold;
+                let nodelay = self.config.peer.nodelay;
+                let pres = peer::PeerConn::new_outgoing(&self.ip_filter, threshold, &peer, nodelay);
                 if let Some(tid) = res {
                     if let Ok(pc) = pres {
                         if let Some(id) = self.add_peer_rpc(tid, pc) {
@@ -520,6 +1218,7 @@ impl<T: cio::CIO> Control<T> {
                                 .msg_rpc(rpc::CtlMessage::Pending { id, client, serial });
                         } else {
                             self.cio.msg_rpc(rpc::CtlMessage::Error {
+                                code: ErrorCode::ServerError,
                                 client,
                                 serial,
                                 reason: format!("Could not add peer {peer}"),
@@ -527,6 +1226,7 @@ impl<T: cio::CIO> Control<T> {
                         }
                     } else {
                         self.cio.msg_rpc(rpc::CtlMessage::Error {
+                            code: ErrorCode::ServerError,
                             client,
                             serial,
                             reason: format!("Could not create peer {peer}"),
@@ -534,6 +1234,7 @@ impl<T: cio::CIO> Control<T> {
                     }
                 } else {
                     self.cio.msg_rpc(rpc::CtlMessage::Error {
+                        code: ErrorCode::UnknownResource,
                         client,
                         serial,
                         reason: format!("torrent {id} does not exist"),
@@ -545,6 +1246,7 @@ impl<T: cio::CIO> Control<T> {
                 client,
                 serial,
                 tracker,
+                by,
             } => {
                 let hash_idx = &self.hash_idx;
                 let torrents = &mut self.torrents;
@@ -553,10 +1255,16 @@ impl<T: cio::CIO> Control<T> {
                 id_to_hash(&id)
                     .and_then(|d| hash_idx.get(d.as_ref()))
                     .and_then(|i| torrents.get_mut(i))
-                    .map(|t| t.add_tracker(tracker))
+                    .map(|t| {
+                        let url = tracker.as_str().to_owned();
+                        let id = t.add_tracker(tracker);
+                        t.record(&by, HistoryAction::AddTracker, Some(url));
+                        id
+                    })
                     .map(|id| cio.msg_rpc(rpc::CtlMessage::Uploaded { id, client, serial }))
                     .unwrap_or_else(|| {
                         cio.msg_rpc(rpc::CtlMessage::Error {
+                            code: ErrorCode::UnknownResource,
                             reason,
                             client,
                             serial,
@@ -567,27 +1275,25 @@ impl<T: cio::CIO> Control<T> {
                 id,
                 throttle_up,
                 throttle_down,
+                permanent,
             } => {
                 let tu = throttle_up.unwrap_or_else(|| self.throttler.ul_rate());
                 let td = throttle_down.unwrap_or_else(|| self.throttler.dl_rate());
-                self.throttler.set_ul_rate(tu);
-                self.throttler.set_dl_rate(td);
-                self.data.throttle_ul = tu;
-                self.data.throttle_dl = td;
-                self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
-                    rpc::resource::SResourceUpdate::Throttle {
-                        id,
-                        kind: rpc::resource::ResourceKind::Server,
-                        throttle_up: tu,
-                        throttle_down: td,
-                    },
-                ]));
+                // With a scheduler configured, temporary changes only last
+                // until the next window boundary and leave the base rates be.
+                if permanent || self.config.scheduler.is_empty() {
+                    self.data.throttle_ul = tu;
+                    self.data.throttle_dl = td;
+                }
+                self.throttle_pinned = permanent;
+                self.set_throttle(id, tu, td);
             }
             rpc::Message::RemoveTorrent {
                 id,
                 client,
                 serial,
                 artifacts,
+                by,
             } => {
                 let hash_idx = &mut self.hash_idx;
                 let torrents = &mut self.torrents;
@@ -596,27 +1302,34 @@ impl<T: cio::CIO> Control<T> {
                 id_to_hash(&id)
                     .and_then(|d| hash_idx.remove(d.as_ref()))
                     .and_then(|i| torrents.remove(&i))
-                    .map(|mut t| t.delete(artifacts))
+                    .map(|mut t| {
+                        info!("{:?}: removed by {}", id, by);
+                        let detail = artifacts.then(|| "with data".to_owned());
+                        t.record(&by, HistoryAction::Remove, detail);
+                        t.delete(artifacts)
+                    })
                     .map(|_| cio.msg_rpc(rpc::CtlMessage::ClientRemoved { id, client, serial }))
                     .unwrap_or_else(|| {
                         cio.msg_rpc(rpc::CtlMessage::Error {
+                            code: ErrorCode::UnknownResource,
                             client,
                             serial,
                             reason,
                         })
                     });
             }
-            rpc::Message::Pause(id) => {
+            rpc::Message::Pause { id, by } => {
                 let hash_idx = &mut self.hash_idx;
                 let torrents = &mut self.torrents;
                 if let Some(t) = id_to_hash(&id)
                     .and_then(|d| hash_idx.get(d.as_ref()))
                     .and_then(|i| torrents.get_mut(i))
                 {
-                    t.pause()
+                    t.pause();
+                    t.record(&by, HistoryAction::Pause, None);
                 }
             }
-            rpc::Message::Resume(id) => {
+            rpc::Message::Resume { id, by } => {
                 let hash_idx = &mut self.hash_idx;
                 let torrents = &mut self.torrents;
                 if let Some(t) = id_to_hash(&id)
@@ -624,6 +1337,7 @@ impl<T: cio::CIO> Control<T> {
                     .and_then(|i| torrents.get_mut(i))
                 {
                     t.resume();
+                    t.record(&by, HistoryAction::Resume, None);
                 }
             }
             rpc::Message::Validate(ids) => {
@@ -655,6 +1369,7 @@ impl<T: cio::CIO> Control<T> {
                     .map(|_| cio.msg_rpc(rpc::CtlMessage::ClientRemoved { id, client, serial }))
                     .unwrap_or_else(|| {
                         cio.msg_rpc(rpc::CtlMessage::Error {
+                            code: ErrorCode::UnknownResource,
                             client,
                             serial,
                             reason,
@@ -666,6 +1381,7 @@ impl<T: cio::CIO> Control<T> {
                 torrent_id,
                 client,
                 serial,
+                by,
             } => {
                 let hash_idx = &self.hash_idx;
                 let torrents = &mut self.torrents;
@@ -674,10 +1390,15 @@ impl<T: cio::CIO> Control<T> {
                 id_to_hash(&torrent_id)
                     .and_then(|d| hash_idx.get(d.as_ref()))
                     .and_then(|i| torrents.get_mut(i))
-                    .map(|t| t.remove_tracker(&id))
+                    .map(|t| {
+                        let url = t.tracker_url(&id);
+                        t.remove_tracker(&id);
+                        t.record(&by, HistoryAction::RemoveTracker, url);
+                    })
                     .map(|_| cio.msg_rpc(rpc::CtlMessage::ClientRemoved { id, client, serial }))
                     .unwrap_or_else(|| {
                         cio.msg_rpc(rpc::CtlMessage::Error {
+                            code: ErrorCode::UnknownResource,
                             client,
                             serial,
                             reason,
@@ -697,12 +1418,105 @@ impl<T: cio::CIO> Control<T> {
             rpc::Message::PurgeDNS => {
                 self.cio.msg_trk(tracker::Request::PurgeDNS);
             }
+            rpc::Message::ReannounceAll => {
+                self.reannounce_all();
+            }
+            rpc::Message::AnalyzePath {
+                path,
+                client,
+                serial,
+            } => {
+                let mut roots = vec![self.config.disk.directory.clone()];
+                roots.extend(
+                    self.config
+                        .watch_dirs
+                        .iter()
+                        .filter_map(|dir| dir.directory.clone()),
+                );
+                self.cio.msg_disk(disk::Request::AnalyzePath {
+                    client,
+                    serial,
+                    path,
+                    roots,
+                });
+            }
+            rpc::Message::GetMetainfo { id, client, serial } => {
+                let msg = match id_to_hash(&id)
+                    .and_then(|d| self.hash_idx.get(d.as_ref()))
+                    .and_then(|i| self.torrents.get(i))
+                {
+                    Some(t) => match t.metainfo(serial) {
+                        Some(metainfo) => rpc::CtlMessage::Metainfo { client, metainfo },
+                        None => rpc::CtlMessage::Error {
+                            code: ErrorCode::NotReady,
+                            reason: format!("torrent {id} has no metadata yet"),
+                            client,
+                            serial,
+                        },
+                    },
+                    None => rpc::CtlMessage::Error {
+                        code: ErrorCode::UnknownResource,
+                        reason: format!("torrent {id} does not exist"),
+                        client,
+                        serial,
+                    },
+                };
+                self.cio.msg_rpc(msg);
+            }
+            rpc::Message::GetHistory { id, client, serial } => {
+                let msg = match id_to_hash(&id)
+                    .and_then(|d| self.hash_idx.get(d.as_ref()))
+                    .and_then(|i| self.torrents.get(i))
+                {
+                    Some(t) => rpc::CtlMessage::History {
+                        client,
+                        history: t.history(serial),
+                    },
+                    None => rpc::CtlMessage::Error {
+                        code: ErrorCode::UnknownResource,
+                        reason: format!("torrent {id} does not exist"),
+                        client,
+                        serial,
+                    },
+                };
+                self.cio.msg_rpc(msg);
+            }
+            rpc::Message::GetDiskStats { client, serial } => {
+                let stats = self.disk_stats(serial);
+                self.cio
+                    .msg_rpc(rpc::CtlMessage::DiskStats { client, stats });
+            }
+            rpc::Message::UpdateIpFilter {
+                client,
+                serial,
+                action,
+                ranges,
+                path,
+            } => {
+                let msg = match self.update_ip_filter(action, &ranges, path.as_deref()) {
+                    Ok(blocked) => rpc::CtlMessage::IpFilterUpdated {
+                        client,
+                        serial,
+                        blocked,
+                    },
+                    Err(reason) => rpc::CtlMessage::Error {
+                        code: ErrorCode::InvalidArgument,
+                        reason,
+                        client,
+                        serial,
+                    },
+                };
+                self.cio.msg_rpc(msg);
+            }
         }
         false
     }
 
     fn add_peer_rpc(&mut self, id: usize, peer: peer::PeerConn) -> Option<String> {
         trace!("Adding peer to torrent {:?}!", id);
+        if self.peers_full() {
+            return None;
+        }
         if let Some(torrent) = self.torrents.get_mut(&id)
             && let Some(pid) = torrent.add_peer(peer)
         {
@@ -715,7 +1529,10 @@ impl<T: cio::CIO> Control<T> {
     fn add_peer(&mut self, id: usize, peer: peer::PeerConn) {
         trace!("Adding peer to torrent {:?}!", id);
         if let Some(torrent) = self.torrents.get_mut(&id) {
-            if !self.queue.active_dl.contains(&id) && !torrent.status().completed() {
+            if !self.queue.active_dl.contains(&id)
+                && !torrent.status().completed()
+                && torrent.priority() != 0
+            {
                 self.queue.add(id, torrent.priority());
                 return;
             }
@@ -734,7 +1551,10 @@ impl<T: cio::CIO> Control<T> {
     ) -> Result<(), ()> {
         trace!("Adding peer to torrent {:?}!", id);
         if let Some(torrent) = self.torrents.get_mut(&id) {
-            if !self.queue.active_dl.contains(&id) && !torrent.status().completed() {
+            if !self.queue.active_dl.contains(&id)
+                && !torrent.status().completed()
+                && torrent.priority() != 0
+            {
                 self.queue.add(id, torrent.priority());
                 return Err(());
             }
@@ -756,6 +1576,94 @@ impl<T: cio::CIO> Control<T> {
         ]));
     }
 
+    fn update_rpc_tick(&mut self) {
+        self.cio
+            .msg_rpc(rpc::CtlMessage::TickInterval(self.tick.interval() as u64));
+    }
+
+    fn update_rpc_port_mapping(&mut self) {
+        let m = &self.data.port_mapping;
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            rpc::resource::SResourceUpdate::ServerPortMapping {
+                id: self.data.id.clone(),
+                kind: rpc::resource::ResourceKind::Server,
+                external_ip: m.external_ip.map(|ip| ip.to_string()),
+                external_port: m.port,
+                external_dht_port: m.dht_port,
+                port_mapping_expires: m.expires,
+            },
+        ]));
+    }
+
+    /// Queues an announce to every tracker of every running torrent, joining any
+    /// re-announce which is already in progress.
+    fn reannounce_all(&mut self) {
+        let now = time::Instant::now();
+        let r = self
+            .reannounce
+            .get_or_insert_with(|| reannounce::Reannounce::new(now));
+        for (tid, torrent) in &self.torrents {
+            if torrent.status().stopped() {
+                continue;
+            }
+            for trk in torrent.trackers().iter() {
+                r.add(*tid, trk.url.clone());
+            }
+        }
+        info!("Re-announcing to {} trackers", r.queued());
+        self.release_reannounces();
+    }
+
+    fn update_reannounce(&mut self) {
+        match &self.reannounce {
+            // The final counts have already been published
+            Some(r) if r.complete() => {
+                self.reannounce = None;
+                self.update_rpc_reannounce();
+            }
+            Some(_) => self.release_reannounces(),
+            None => {}
+        }
+    }
+
+    fn release_reannounces(&mut self) {
+        let Some(r) = &mut self.reannounce else {
+            return;
+        };
+        let now = time::Instant::now();
+        let torrents = &mut self.torrents;
+        r.tick(now, |tid, url| {
+            let Some(torrent) = torrents.get_mut(&tid).filter(|t| !t.status().stopped()) else {
+                return reannounce::Outcome::Gone;
+            };
+            match torrent.trackers().iter().find(|trk| trk.url == *url) {
+                None => reannounce::Outcome::Gone,
+                Some(trk) if trk.min_update.is_some_and(|t| now < t) => {
+                    reannounce::Outcome::Deferred
+                }
+                Some(_) => {
+                    torrent.announce_to(url.clone());
+                    reannounce::Outcome::Sent
+                }
+            }
+        });
+        self.update_rpc_reannounce();
+    }
+
+    fn update_rpc_reannounce(&mut self) {
+        let r = self.reannounce.as_ref();
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            rpc::resource::SResourceUpdate::ServerReannounce {
+                id: self.data.id.clone(),
+                kind: rpc::resource::ResourceKind::Server,
+                reannounce_queued: r.map(|r| r.queued()),
+                reannounce_done: r.map(|r| r.done),
+                reannounce_failed: r.map(|r| r.failed),
+                reannounce_deferred: r.map(|r| r.deferred),
+            },
+        ]));
+    }
+
     fn update_rpc_tx(&mut self) {
         self.stat.tick();
         if self.stat.active() {
@@ -775,7 +1683,32 @@ impl<T: cio::CIO> Control<T> {
         }
     }
 
+    /// Brings a freshly restarted worker back up to date.
+    fn handle_restart(&mut self, worker: cio::Worker) {
+        match worker {
+            cio::Worker::RPC => {
+                self.send_rpc_info();
+                for torrent in self.torrents.values_mut() {
+                    torrent.send_rpc_info();
+                }
+            }
+            cio::Worker::Tracker => {
+                for torrent in self.torrents.values_mut() {
+                    torrent.update_tracker();
+                }
+            }
+            cio::Worker::Disk => {
+                for torrent in self.torrents.values_mut() {
+                    torrent.disk_restarted();
+                }
+            }
+        }
+    }
+
     fn send_rpc_info(&mut self) {
+        let (quota_reset, quota_days_left) = self.rpc_quota(Local::now().naive_local());
+        let quota = Some(self.config.quota.limit).filter(|&l| l != 0);
+        let disk = self.disk_stats.back().map(|s| s.1).unwrap_or_default();
         let res = rpc::resource::Resource::Server(rpc::resource::Server {
             id: self.data.id.clone(),
             rate_up: 0,
@@ -788,10 +1721,23 @@ impl<T: cio::CIO> Control<T> {
             ses_transferred_down: self.data.session_dl,
             free_space: self.data.free_space,
             started: Utc::now(),
+            external_ip: self.data.port_mapping.external_ip.map(|ip| ip.to_string()),
+            external_port: self.data.port_mapping.port,
+            external_dht_port: self.data.port_mapping.dht_port,
+            port_mapping_expires: self.data.port_mapping.expires,
             download_token: DL_TOKEN.clone(),
+            quota,
+            quota_used: quota.map(|_| self.data.quota.used),
+            quota_threshold: quota.map(|_| self.data.quota.crossed),
+            quota_reset,
+            quota_days_left,
+            duplicate_sessions: self.duplicates.clone(),
+            piece_cache_hits: disk.piece_cache_hits,
+            piece_cache_misses: disk.piece_cache_misses,
             ..Default::default()
         });
         self.cio.msg_rpc(rpc::CtlMessage::Extant(vec![res]));
+        self.update_rpc_tick();
     }
 }
 
@@ -816,6 +1762,21 @@ impl ServerData {
             free_space: 0,
             throttle_ul: Some(-1),
             throttle_dl: Some(-1),
+            port_mapping: tracker::PortMapping::default(),
+            quota: quota::Usage::default(),
+        }
+    }
+}
+
+impl From<ServerDataV1> for ServerData {
+    fn from(d: ServerDataV1) -> ServerData {
+        ServerData {
+            id: d.id,
+            ul: d.ul,
+            dl: d.dl,
+            throttle_ul: d.throttle_ul,
+            throttle_dl: d.throttle_dl,
+            ..Default::default()
         }
     }
 }
@@ -844,22 +1805,27 @@ impl Queue {
     fn modify_pri(&mut self, id: usize, pri: u8, old_pri: u8) {
         let pri = pri as usize;
         let old_pri = old_pri as usize;
-        self.inactive_dl[old_pri].remove(&id);
-        self.inactive_dl[pri].insert(id);
+        // Priority 0 torrents give up their download slot, everything else
+        // keeps its slot and is only reordered if it is still waiting.
+        if (pri == 0 && self.active_dl.remove(&id)) || self.inactive_dl[old_pri].remove(&id) {
+            self.inactive_dl[pri].insert(id);
+        }
     }
 
     fn add(&mut self, id: usize, pri: u8) {
         let pri = pri as usize;
-        if self.dl_full() {
+        if self.dl_full() || pri == 0 {
             self.inactive_dl[pri].insert(id);
         } else {
             self.active_dl.insert(id);
         }
     }
 
+    /// Moves queued torrents into free download slots, highest priority first.
+    /// Priority 0 torrents are never given a download slot.
     fn enqueue<F: FnMut(usize)>(&mut self, mut f: F) {
-        while !self.dl_full() && self.inactive_dl.iter().any(|q| !q.is_empty()) {
-            for i in (0..self.inactive_dl.len()).rev() {
+        while !self.dl_full() && self.inactive_dl[1..].iter().any(|q| !q.is_empty()) {
+            for i in (1..self.inactive_dl.len()).rev() {
                 if !self.inactive_dl[i].is_empty() {
                     let next = { *self.inactive_dl[i].iter().next().unwrap() };
                     self.inactive_dl[i].remove(&next);
@@ -917,6 +1883,7 @@ pub struct SpaceUpdate;
 impl<T: cio::CIO> CJob<T> for SpaceUpdate {
     fn update(&mut self, control: &mut Control<T>) {
         control.cio.msg_disk(disk::Request::FreeSpace);
+        control.cio.msg_disk(disk::Request::Stats);
     }
 }
 
@@ -928,7 +1895,7 @@ impl<T: cio::CIO> CJob<T> for EnqueueUpdate {
         let torrents = &mut control.torrents;
 
         queue.active_dl.retain(|tid| match torrents.get(tid) {
-            Some(t) => t.status().should_dl(),
+            Some(t) => t.should_dl(),
             None => false,
         });
         for q in &mut queue.inactive_dl {
@@ -938,6 +1905,38 @@ impl<T: cio::CIO> CJob<T> for EnqueueUpdate {
     }
 }
 
+pub struct WatchUpdate;
+
+impl<T: cio::CIO> CJob<T> for WatchUpdate {
+    fn update(&mut self, control: &mut Control<T>) {
+        control.scan_watch_dirs();
+    }
+}
+
+pub struct ScheduleUpdate;
+
+impl<T: cio::CIO> CJob<T> for ScheduleUpdate {
+    fn update(&mut self, control: &mut Control<T>) {
+        control.apply_schedule(Local::now().naive_local());
+    }
+}
+
+pub struct DiskWatchdog;
+
+impl<T: cio::CIO> CJob<T> for DiskWatchdog {
+    fn update(&mut self, control: &mut Control<T>) {
+        control.check_disk();
+    }
+}
+
+pub struct RebalanceUpdate;
+
+impl<T: cio::CIO> CJob<T> for RebalanceUpdate {
+    fn update(&mut self, control: &mut Control<T>) {
+        control.throttler.rebalance();
+    }
+}
+
 pub struct SerializeUpdate;
 
 impl<T: cio::CIO> CJob<T> for SerializeUpdate {
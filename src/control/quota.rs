@@ -0,0 +1,285 @@
+use chrono::{Datelike, Duration, Months, NaiveDate, NaiveDateTime};
+
+use crate::config::QuotaConfig;
+
+/// Transfer accounted against the quota in the current period, kept with the server data
+/// so it survives restarts.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Usage {
+    /// Start of the current period, None until the quota is first evaluated
+    pub start: Option<NaiveDateTime>,
+    pub used: u64,
+    /// Highest threshold crossed this period, as a percentage of the limit
+    pub crossed: u8,
+    /// Ids of the torrents paused on running out, which are resumed on reset
+    pub paused: Vec<String>,
+}
+
+impl Usage {
+    /// Begins a new period if `now` lies past the current one, returning whether the
+    /// usage was reset.
+    pub fn roll(&mut self, cfg: &QuotaConfig, now: NaiveDateTime) -> bool {
+        let Some(mut start) = self.start else {
+            self.start = Some(first_start(cfg, now));
+            return false;
+        };
+        if now < next_reset(cfg, start) {
+            return false;
+        }
+        while next_reset(cfg, start) <= now {
+            start = next_reset(cfg, start);
+        }
+        self.start = Some(start);
+        self.used = 0;
+        self.crossed = 0;
+        true
+    }
+
+    /// Returns the thresholds crossed since the last call, lowest first. Using up the
+    /// whole budget always counts as crossing 100%.
+    pub fn cross(&mut self, cfg: &QuotaConfig) -> Vec<u8> {
+        let mut crossed: Vec<_> = cfg
+            .thresholds
+            .iter()
+            .copied()
+            .chain(Some(100))
+            .filter(|&t| t > self.crossed && self.percent(cfg) >= u64::from(t))
+            .collect();
+        crossed.sort_unstable();
+        crossed.dedup();
+        if let Some(&t) = crossed.last() {
+            self.crossed = t;
+        }
+        crossed
+    }
+
+    /// Whether the whole budget for the period has been used.
+    pub fn exhausted(&self) -> bool {
+        self.crossed >= 100
+    }
+
+    /// When the current period ends.
+    pub fn reset_at(&self, cfg: &QuotaConfig) -> Option<NaiveDateTime> {
+        self.start.map(|s| next_reset(cfg, s))
+    }
+
+    fn percent(&self, cfg: &QuotaConfig) -> u64 {
+        (u128::from(self.used) * 100 / u128::from(cfg.limit.max(1))) as u64
+    }
+}
+
+/// Start of the period covering `now` when there is no previous one to follow on from.
+fn first_start(cfg: &QuotaConfig, now: NaiveDateTime) -> NaiveDateTime {
+    let today = now.date();
+    let day = if cfg.period_days != 0 {
+        today
+    } else if today.day() >= cfg.reset_day {
+        today.with_day(cfg.reset_day).unwrap()
+    } else {
+        let last_month = today - Months::new(1);
+        NaiveDate::from_ymd_opt(last_month.year(), last_month.month(), cfg.reset_day).unwrap()
+    };
+    day.and_hms_opt(0, 0, 0).unwrap()
+}
+
+fn next_reset(cfg: &QuotaConfig, start: NaiveDateTime) -> NaiveDateTime {
+    if cfg.period_days != 0 {
+        start + Duration::days(i64::from(cfg.period_days))
+    } else {
+        start + Months::new(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{NaiveDate, NaiveDateTime};
+
+    use super::*;
+    use crate::config::{Config, QuotaAction};
+    use crate::control::Control;
+    use crate::control::cio::CIO;
+    use crate::control::cio::test::TCIO;
+    use crate::rpc;
+    use crate::rpc::resource::SResourceUpdate;
+    use crate::torrent::{Info, Torrent};
+
+    const GB: u64 = 1_000_000_000;
+
+    fn at(month: u32, day: u32, hour: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2024, month, day)
+            .unwrap()
+            .and_hms_opt(hour, 0, 0)
+            .unwrap()
+    }
+
+    fn quota(action: QuotaAction) -> QuotaConfig {
+        QuotaConfig {
+            limit: 100 * GB,
+            reset_day: 15,
+            action,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_period_boundary() {
+        let cfg = quota(QuotaAction::Throttle);
+        let mut u = Usage::default();
+        assert!(!u.roll(&cfg, at(3, 2, 12)));
+        assert_eq!(u.start, Some(at(2, 15, 0)));
+        assert_eq!(u.reset_at(&cfg), Some(at(3, 15, 0)));
+
+        u.used = 90 * GB;
+        assert_eq!(u.cross(&cfg), [80]);
+        assert!(!u.roll(&cfg, at(3, 14, 23)));
+        assert_eq!(u.used, 90 * GB);
+
+        assert!(u.roll(&cfg, at(3, 15, 0)));
+        assert_eq!((u.used, u.crossed), (0, 0));
+        assert_eq!(u.reset_at(&cfg), Some(at(4, 15, 0)));
+
+        // Periods missed while the daemon was down are skipped over
+        assert!(u.roll(&cfg, at(6, 20, 0)));
+        assert_eq!(u.start, Some(at(6, 15, 0)));
+
+        let days = QuotaConfig {
+            period_days: 10,
+            ..cfg
+        };
+        let mut u = Usage::default();
+        u.roll(&days, at(1, 3, 8));
+        assert_eq!(u.reset_at(&days), Some(at(1, 13, 0)));
+        assert!(u.roll(&days, at(1, 25, 0)));
+        assert_eq!(u.start, Some(at(1, 23, 0)));
+    }
+
+    #[test]
+    fn test_thresholds() {
+        let cfg = quota(QuotaAction::Throttle);
+        let mut u = Usage {
+            used: 50 * GB,
+            ..Default::default()
+        };
+        assert!(u.cross(&cfg).is_empty());
+        // Several thresholds can be crossed at once
+        u.used = 96 * GB;
+        assert_eq!(u.cross(&cfg), [80, 95]);
+        assert!(u.cross(&cfg).is_empty());
+        assert!(!u.exhausted());
+        u.used = 100 * GB;
+        assert_eq!(u.cross(&cfg), [100]);
+        assert!(u.exhausted());
+
+        // Running out is reported even when 100 isn't one of the thresholds
+        let cfg = QuotaConfig {
+            thresholds: vec![50],
+            ..cfg
+        };
+        let mut u = Usage {
+            used: 120 * GB,
+            ..Default::default()
+        };
+        assert_eq!(u.cross(&cfg), [50, 100]);
+        assert!(u.exhausted());
+    }
+
+    fn control(action: QuotaAction) -> Control<TCIO> {
+        let mut config = Config::default();
+        config.disk.validate = false;
+        config.quota = quota(action);
+        let mut c = Control::test(config);
+        c.data.throttle_ul = Some(500);
+        c
+    }
+
+    fn add_torrent(c: &mut Control<TCIO>, tid: usize) {
+        let mut info = Info::with_pieces(1);
+        info.piece_idx = Info::generate_piece_idx(1, u64::from(info.piece_len), &info.files);
+        info.hash[0] = tid as u8;
+        let t = Torrent::new(
+            c.config.clone(),
+            tid,
+            None,
+            info,
+            c.throttler.get_throttle(tid),
+            c.cio.new_handle(),
+            true,
+            false,
+        );
+        c.torrents.insert(tid, t);
+    }
+
+    /// Thresholds reported in quota updates, in order.
+    fn reported(c: &Control<TCIO>) -> Vec<u8> {
+        let mut levels: Vec<u8> = Vec::new();
+        for m in &c.cio.data().rpc_msgs {
+            if let rpc::CtlMessage::Update(u) = m {
+                for u in u {
+                    if let SResourceUpdate::ServerQuota {
+                        quota_threshold, ..
+                    } = u
+                        && levels.last() != Some(quota_threshold)
+                    {
+                        levels.push(*quota_threshold);
+                    }
+                }
+            }
+        }
+        levels
+    }
+
+    #[test]
+    fn test_throttle_action() {
+        let mut c = control(QuotaAction::Throttle);
+        c.update_quota(at(3, 1, 0));
+        for used in [70, 85, 90, 97, 99, 101, 120] {
+            c.data.quota.used = used * GB;
+            c.update_quota(at(3, 2, 0));
+            if used < 101 {
+                assert_eq!(c.throttler.ul_rate(), None);
+            }
+        }
+        assert_eq!(reported(&c), [0, 80, 95, 100]);
+        assert_eq!(c.throttler.ul_rate(), Some(10 * 1024));
+        assert_eq!(c.throttler.dl_rate(), Some(10 * 1024));
+
+        // The scheduler and later usage leave the throttle alone
+        c.apply_schedule(at(3, 3, 0));
+        c.data.quota.used = 130 * GB;
+        c.update_quota(at(3, 4, 0));
+        assert_eq!(c.throttler.ul_rate(), Some(10 * 1024));
+        assert_eq!(reported(&c), [0, 80, 95, 100]);
+
+        // The base throttle is back once the period resets
+        c.update_quota(at(3, 15, 0));
+        assert_eq!(c.data.quota.used, 0);
+        assert_eq!(c.throttler.ul_rate(), Some(500));
+        assert_eq!(reported(&c), [0, 80, 95, 100, 0]);
+    }
+
+    #[test]
+    fn test_pause_action() {
+        let mut c = control(QuotaAction::Pause);
+        add_torrent(&mut c, 0);
+        add_torrent(&mut c, 1);
+        c.torrents.get_mut(&1).unwrap().pause();
+        c.update_quota(at(3, 1, 0));
+
+        c.data.quota.used = 100 * GB;
+        c.update_quota(at(3, 2, 0));
+        assert!(c.torrents[&0].status().paused);
+        assert_eq!(c.data.quota.paused.len(), 1);
+        // Torrents resumed by hand are left running
+        c.torrents.get_mut(&0).unwrap().resume();
+        c.data.quota.used = 110 * GB;
+        c.update_quota(at(3, 3, 0));
+        assert!(!c.torrents[&0].status().paused);
+
+        c.torrents.get_mut(&0).unwrap().pause();
+        c.update_quota(at(3, 15, 0));
+        // Only the torrent the quota paused is resumed
+        assert!(!c.torrents[&0].status().paused);
+        assert!(c.torrents[&1].status().paused);
+        assert!(c.data.quota.paused.is_empty());
+    }
+}
@@ -0,0 +1,323 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use url::Url;
+
+/// Forced announces sent per second across all trackers
+const RATE: u32 = 20;
+/// Forced announces which may be awaiting a response from a single tracker host
+const HOST_LIMIT: usize = 2;
+/// Announces still unanswered after this long are counted as failed
+const TIMEOUT_SECS: u64 = 90;
+
+/// What became of an announce handed to the torrent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Sent,
+    /// The tracker's min interval hasn't passed yet, so it wasn't announced to
+    Deferred,
+    /// The torrent or tracker no longer exists, or the torrent was stopped
+    Gone,
+}
+
+/// An announce to every tracker of every active torrent, released at a bounded
+/// pace and with only a few announces outstanding per tracker host.
+pub struct Reannounce {
+    pending: VecDeque<(usize, Arc<Url>)>,
+    in_flight: Vec<(usize, Arc<Url>, Instant)>,
+    tokens: u32,
+    refilled: Instant,
+    pub done: u64,
+    pub failed: u64,
+    pub deferred: u64,
+}
+
+impl Reannounce {
+    pub fn new(now: Instant) -> Reannounce {
+        Reannounce {
+            pending: VecDeque::new(),
+            in_flight: Vec::new(),
+            tokens: RATE,
+            refilled: now,
+            done: 0,
+            failed: 0,
+            deferred: 0,
+        }
+    }
+
+    /// Queues an announce, unless one to the same tracker is already outstanding.
+    pub fn add(&mut self, tid: usize, url: Arc<Url>) {
+        let queued = self.pending.iter().any(|(t, u)| *t == tid && *u == url)
+            || self
+                .in_flight
+                .iter()
+                .any(|(t, u, _)| *t == tid && *u == url);
+        if !queued {
+            self.pending.push_back((tid, url));
+        }
+    }
+
+    /// Announces which have not been answered yet, whether sent or not.
+    pub fn queued(&self) -> u64 {
+        (self.pending.len() + self.in_flight.len()) as u64
+    }
+
+    pub fn complete(&self) -> bool {
+        self.pending.is_empty() && self.in_flight.is_empty()
+    }
+
+    /// Releases as many pending announces as the pace and host limits allow, handing
+    /// each to `send`.
+    pub fn tick<F>(&mut self, now: Instant, mut send: F)
+    where
+        F: FnMut(usize, &Arc<Url>) -> Outcome,
+    {
+        let timeout = Duration::from_secs(TIMEOUT_SECS);
+        let before = self.in_flight.len();
+        self.in_flight
+            .retain(|(_, _, sent)| now.duration_since(*sent) < timeout);
+        self.failed += (before - self.in_flight.len()) as u64;
+
+        let interval = Duration::from_secs(1) / RATE;
+        let refill = (now.duration_since(self.refilled).as_nanos() / interval.as_nanos()) as u32;
+        if self.tokens + refill >= RATE {
+            self.tokens = RATE;
+            self.refilled = now;
+        } else {
+            self.tokens += refill;
+            self.refilled += interval * refill;
+        }
+
+        let mut i = 0;
+        while self.tokens > 0 && i < self.pending.len() {
+            let host = self.pending[i].1.host_str();
+            let busy = self
+                .in_flight
+                .iter()
+                .filter(|(_, u, _)| u.host_str() == host)
+                .count();
+            if busy >= HOST_LIMIT {
+                i += 1;
+                continue;
+            }
+            let (tid, url) = self.pending.remove(i).unwrap();
+            match send(tid, &url) {
+                Outcome::Sent => {
+                    self.tokens -= 1;
+                    self.in_flight.push((tid, url, now));
+                }
+                Outcome::Deferred => self.deferred += 1,
+                Outcome::Gone => self.failed += 1,
+            }
+        }
+    }
+
+    /// Records the response to an announce, returning whether it was one of ours.
+    pub fn response(&mut self, tid: usize, url: &Url, ok: bool) -> bool {
+        match self
+            .in_flight
+            .iter()
+            .position(|(t, u, _)| *t == tid && **u == *url)
+        {
+            Some(pos) => {
+                self.in_flight.swap_remove(pos);
+                if ok {
+                    self.done += 1;
+                } else {
+                    self.failed += 1;
+                }
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::control::Control;
+    use crate::control::cio::CIO;
+    use crate::control::cio::test::TCIO;
+    use crate::rpc::resource::SResourceUpdate;
+    use crate::torrent::{Info, Torrent};
+    use crate::{rpc, tracker};
+
+    fn url(host: usize) -> Arc<Url> {
+        Arc::new(Url::parse(&format!("http://tracker{host}.example.com/announce")).unwrap())
+    }
+
+    #[test]
+    fn test_pacing() {
+        let start = Instant::now();
+        let mut r = Reannounce::new(start);
+        for tid in 0..200 {
+            r.add(tid, url(tid));
+        }
+        // Re-adding an outstanding announce doesn't queue it twice
+        r.add(0, url(0));
+        assert_eq!(r.queued(), 200);
+
+        let mut sent = 0;
+        let mut now = start;
+        while !r.complete() {
+            let mut answered = Vec::new();
+            r.tick(now, |tid, url| {
+                answered.push((tid, url.clone()));
+                Outcome::Sent
+            });
+            sent += answered.len() as u32;
+            let elapsed = now.duration_since(start).as_millis() as u32;
+            assert!(
+                sent <= RATE + RATE * elapsed / 1000,
+                "{sent} sent by {elapsed}ms"
+            );
+            for (tid, url) in answered {
+                assert!(r.response(tid, &url, true));
+            }
+            now += Duration::from_millis(100);
+        }
+        // Everything went out within a tick of the pace allowing it
+        assert!(now.duration_since(start) <= Duration::from_millis(9_100));
+        assert_eq!((r.done, r.failed, r.deferred), (200, 0, 0));
+    }
+
+    #[test]
+    fn test_host_limit() {
+        let now = Instant::now();
+        let mut r = Reannounce::new(now);
+        for tid in 0..5 {
+            r.add(tid, url(0));
+        }
+        r.add(5, url(1));
+        let mut sent = Vec::new();
+        r.tick(now, |tid, _| {
+            sent.push(tid);
+            Outcome::Sent
+        });
+        assert_eq!(sent, [0, 1, 5]);
+
+        // A response frees up a slot for the host, failures count as done with it
+        assert!(r.response(1, &url(0), false));
+        assert!(!r.response(1, &url(0), true));
+        sent.clear();
+        r.tick(now, |tid, _| {
+            sent.push(tid);
+            Outcome::Sent
+        });
+        assert_eq!(sent, [2]);
+        assert_eq!(r.failed, 1);
+
+        // Unanswered announces eventually time out
+        r.tick(now + Duration::from_secs(TIMEOUT_SECS), |_, _| {
+            Outcome::Sent
+        });
+        assert_eq!(r.failed, 4);
+        r.tick(now + Duration::from_secs(2 * TIMEOUT_SECS), |_, _| {
+            Outcome::Sent
+        });
+        assert!(r.complete());
+        assert_eq!((r.done, r.failed), (0, 6));
+    }
+
+    #[test]
+    fn test_deferred() {
+        let now = Instant::now();
+        let mut r = Reannounce::new(now);
+        for tid in 0..(3 * RATE as usize) {
+            r.add(tid, url(tid));
+        }
+        // Deferred and vanished announces don't use up the pace
+        r.tick(now, |tid, _| match tid % 3 {
+            0 => Outcome::Deferred,
+            1 => Outcome::Gone,
+            _ => Outcome::Sent,
+        });
+        assert_eq!(r.queued(), RATE as u64);
+        assert_eq!(
+            (r.done, r.failed, r.deferred),
+            (0, RATE as u64, RATE as u64)
+        );
+    }
+
+    /// The last published re-announce progress.
+    fn status(c: &Control<TCIO>) -> (Option<u64>, Option<u64>, Option<u64>, Option<u64>) {
+        c.cio
+            .data()
+            .rpc_msgs
+            .iter()
+            .rev()
+            .find_map(|m| match m {
+                rpc::CtlMessage::Update(u) => u.iter().find_map(|u| match *u {
+                    SResourceUpdate::ServerReannounce {
+                        reannounce_queued,
+                        reannounce_done,
+                        reannounce_failed,
+                        reannounce_deferred,
+                        ..
+                    } => Some((
+                        reannounce_queued,
+                        reannounce_done,
+                        reannounce_failed,
+                        reannounce_deferred,
+                    )),
+                    _ => None,
+                }),
+                _ => None,
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn test_min_interval_deferred() {
+        let mut config = Config::default();
+        config.disk.validate = false;
+        let mut c = Control::test(config);
+
+        let (clamped, open) = (url(0), url(1));
+        let mut info = Info::with_pieces(1);
+        info.piece_idx = Info::generate_piece_idx(1, u64::from(info.piece_len), &info.files);
+        info.url_list = vec![vec![clamped.clone()], vec![open.clone()]];
+        let t = Torrent::new(
+            c.config.clone(),
+            0,
+            None,
+            info,
+            c.throttler.get_throttle(0),
+            c.cio.new_handle(),
+            true,
+            false,
+        );
+        c.torrents.insert(0, t);
+        let resp = tracker::TrackerResponse {
+            min_interval: Some(600),
+            ..tracker::TrackerResponse::empty()
+        };
+        c.handle_trk_ev(tracker::Response::Tracker {
+            tid: 0,
+            url: clamped.clone(),
+            resp: Ok(resp),
+        });
+        c.cio.data().trk_msgs.clear();
+
+        c.handle_rpc_ev(rpc::Message::ReannounceAll);
+        assert_eq!(c.cio.data().trk_msgs.len(), 1);
+        let in_flight = &c.reannounce.as_ref().unwrap().in_flight;
+        assert_eq!(in_flight.len(), 1);
+        assert_eq!(in_flight[0].1, open);
+        assert_eq!(status(&c), (Some(1), Some(0), Some(0), Some(1)));
+
+        c.handle_trk_ev(tracker::Response::Tracker {
+            tid: 0,
+            url: open,
+            resp: Ok(tracker::TrackerResponse::empty()),
+        });
+        assert_eq!(status(&c), (Some(0), Some(1), Some(0), Some(1)));
+        // The final counts are cleared on the following tick
+        c.update_reannounce();
+        assert_eq!(status(&c), (None, None, None, None));
+        assert!(c.reannounce.is_none());
+    }
+}
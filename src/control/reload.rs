@@ -0,0 +1,173 @@
+use ip_network_table::IpNetworkTable;
+
+use crate::config::{Config, PeerConfig};
+
+/// Rebuilds the filter for a reloaded config. Entries which came from the old config are
+/// replaced by those of the new one, while blocks added over RPC since are kept.
+pub fn ip_filter(current: &IpNetworkTable<u8>, old: &Config, new: &Config) -> IpNetworkTable<u8> {
+    let mut table = IpNetworkTable::new();
+    for (net, weight) in current.iter() {
+        if old.ip_filter.exact_match(net) != Some(weight) {
+            table.insert(net, *weight);
+        }
+    }
+    for (net, weight) in new.ip_filter.iter() {
+        table.insert(net, *weight);
+    }
+    table
+}
+
+/// Names of the settings which differ between the configs but are only read at startup.
+pub fn restart_needed(old: &Config, new: &Config) -> Vec<&'static str> {
+    // Destructured so every new setting has to be sorted into reloaded or not
+    let Config {
+        port,
+        max_dl,
+        crash_reports,
+        trk,
+        dht,
+        dns,
+        rpc,
+        disk,
+        net,
+        peer,
+        idle,
+        quota,
+        watch_dirs,
+        scheduler: _,
+        ip_filter: _,
+    } = new;
+    let PeerConfig {
+        prune_timeout,
+        unchoke_slots_limit,
+        nodelay,
+        endgame_duplicates,
+        max_peers_global: _,
+        max_peers_per_torrent: _,
+        ip_filter_threshold: _,
+        blocklist: _,
+    } = peer;
+    [
+        ("port", old.port != *port),
+        ("max_dl", old.max_dl != *max_dl),
+        ("crash_reports", old.crash_reports != *crash_reports),
+        ("tracker", old.trk != *trk),
+        ("dht", old.dht != *dht),
+        ("dns", old.dns != *dns),
+        ("rpc", old.rpc != *rpc),
+        ("disk", old.disk != *disk),
+        ("net", old.net != *net),
+        (
+            "peer.prune_timeout",
+            old.peer.prune_timeout != *prune_timeout,
+        ),
+        (
+            "peer.unchoke_slots_limit",
+            old.peer.unchoke_slots_limit != *unchoke_slots_limit,
+        ),
+        ("peer.nodelay", old.peer.nodelay != *nodelay),
+        (
+            "peer.endgame_duplicates",
+            old.peer.endgame_duplicates != *endgame_duplicates,
+        ),
+        ("idle", old.idle != *idle),
+        ("quota", old.quota != *quota),
+        ("watch_dirs", old.watch_dirs != *watch_dirs),
+    ]
+    .into_iter()
+    .filter(|(_, changed)| *changed)
+    .map(|(name, _)| name)
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Weekday;
+
+    use super::*;
+    use crate::config::ScheduleRule;
+    use crate::control::Control;
+    use crate::rpc::proto::message::IpFilterAction;
+    use crate::util::ip_filter::BLOCK;
+
+    fn filtered(config: &[(&str, u8)]) -> Config {
+        let mut c = Config::default();
+        for (net, weight) in config {
+            c.ip_filter
+                .insert(net.parse::<ip_network::IpNetwork>().unwrap(), *weight);
+        }
+        c
+    }
+
+    #[test]
+    fn test_reload_throttle() {
+        let mut c = Control::test(Config::default());
+        assert_eq!(c.throttler.ul_rate(), None);
+
+        let mut config = Config {
+            scheduler: vec![ScheduleRule {
+                days: vec![
+                    Weekday::Mon,
+                    Weekday::Tue,
+                    Weekday::Wed,
+                    Weekday::Thu,
+                    Weekday::Fri,
+                    Weekday::Sat,
+                    Weekday::Sun,
+                ],
+                start: 0,
+                end: 24,
+                upload: Some(100),
+                download: Some(200),
+            }],
+            ..Default::default()
+        };
+        config.peer.max_peers_global = 7;
+        config.peer.max_peers_per_torrent = 5;
+        c.reload_config(config);
+        assert_eq!(c.throttler.ul_rate(), Some(100));
+        assert_eq!(c.throttler.dl_rate(), Some(200));
+        assert_eq!(c.config.peer.max_peers_global, 7);
+        assert_eq!(c.config.peer.max_peers_per_torrent, 5);
+
+        // Dropping the window goes back to the base rates
+        c.reload_config(Config::default());
+        assert_eq!(c.throttler.ul_rate(), None);
+    }
+
+    #[test]
+    fn test_restart_needed() {
+        let old = Config::default();
+        let mut new = Config::default();
+        assert!(restart_needed(&old, &new).is_empty());
+
+        new.rpc.password = "hunter2".to_owned();
+        new.quota.limit = 1;
+        new.peer.nodelay = !old.peer.nodelay;
+        new.peer.max_peers_per_torrent += 1;
+        new.peer.max_peers_global += 1;
+        assert_eq!(restart_needed(&old, &new), ["rpc", "peer.nodelay", "quota"]);
+    }
+
+    #[test]
+    fn test_reload_ip_filter() {
+        let mut c = Control::test(filtered(&[("10.0.0.0/8", BLOCK)]));
+        c.update_ip_filter(IpFilterAction::Add, &["1.2.3.4".to_owned()], None)
+            .unwrap();
+
+        let mut config = filtered(&[("192.168.0.0/16", BLOCK)]);
+        config.port = c.config.port + 1;
+        c.reload_config(config);
+        let entries: Vec<_> = c
+            .ip_filter
+            .iter()
+            .map(|(n, w)| (n.to_string(), *w))
+            .collect();
+        assert_eq!(entries.len(), 2);
+        assert!(entries.contains(&("1.2.3.4/32".to_owned(), BLOCK)));
+        assert!(entries.contains(&("192.168.0.0/16".to_owned(), BLOCK)));
+        assert!(c.ip_filter_dirty);
+        // Only read at startup, so the running client keeps its port
+        assert_eq!(c.config.port, Config::default().port);
+    }
+}
@@ -0,0 +1,146 @@
+use chrono::{Datelike, NaiveDateTime, Timelike};
+
+use crate::config::ScheduleRule;
+
+/// Index of the scheduler window covering `now`, if any.
+pub fn active(rules: &[ScheduleRule], now: NaiveDateTime) -> Option<usize> {
+    rules
+        .iter()
+        .position(|r| r.contains(now.weekday(), now.hour()))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{NaiveDate, NaiveDateTime, Weekday};
+
+    use crate::config::{Config, ScheduleRule, validate_scheduler};
+    use crate::control::Control;
+    use crate::control::cio::test::TCIO;
+    use crate::rpc;
+    use crate::rpc::resource::SResourceUpdate;
+
+    fn rule(days: &[Weekday], start: u32, end: u32, rate: i64) -> ScheduleRule {
+        ScheduleRule {
+            days: days.to_vec(),
+            start,
+            end,
+            upload: Some(rate),
+            download: Some(rate * 2),
+        }
+    }
+
+    // 2024-01-01 was a Monday
+    fn at(day: u32, hour: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2024, 1, day)
+            .unwrap()
+            .and_hms_opt(hour, 30, 0)
+            .unwrap()
+    }
+
+    fn control(rules: Vec<ScheduleRule>) -> Control<TCIO> {
+        let config = Config {
+            scheduler: rules,
+            ..Default::default()
+        };
+        Control::test(config)
+    }
+
+    fn last_throttle(c: &Control<TCIO>) -> Option<(Option<i64>, Option<i64>)> {
+        let d = c.cio.data();
+        d.rpc_msgs.iter().rev().find_map(|m| match m {
+            rpc::CtlMessage::Update(u) => u.iter().find_map(|u| match u {
+                SResourceUpdate::Throttle {
+                    throttle_up,
+                    throttle_down,
+                    ..
+                } => Some((*throttle_up, *throttle_down)),
+                _ => None,
+            }),
+            _ => None,
+        })
+    }
+
+    fn update_server(c: &mut Control<TCIO>, rate: i64, permanent: bool) {
+        c.handle_rpc_ev(rpc::Message::UpdateServer {
+            id: String::new(),
+            throttle_up: Some(Some(rate)),
+            throttle_down: None,
+            permanent,
+        });
+    }
+
+    #[test]
+    fn test_boundary() {
+        let mut c = control(vec![rule(&[Weekday::Mon], 9, 17, 100)]);
+        c.data.throttle_ul = Some(500);
+        c.apply_schedule(at(1, 8));
+        assert_eq!(c.throttler.ul_rate(), Some(500));
+
+        c.apply_schedule(at(1, 9));
+        assert_eq!(c.throttler.ul_rate(), Some(100));
+        assert_eq!(c.throttler.dl_rate(), Some(200));
+        assert_eq!(last_throttle(&c), Some((Some(100), Some(200))));
+
+        // Nothing is sent until the next boundary
+        let sent = c.cio.data().rpc_msgs.len();
+        c.apply_schedule(at(1, 16));
+        assert_eq!(c.cio.data().rpc_msgs.len(), sent);
+
+        c.apply_schedule(at(1, 17));
+        assert_eq!(c.throttler.ul_rate(), Some(500));
+        assert_eq!(c.throttler.dl_rate(), None);
+        assert_eq!(last_throttle(&c), Some((Some(500), None)));
+
+        // Other days are unaffected
+        c.apply_schedule(at(2, 10));
+        assert_eq!(c.throttler.ul_rate(), Some(500));
+    }
+
+    #[test]
+    fn test_override() {
+        let mut c = control(vec![rule(&[Weekday::Mon], 9, 17, 100)]);
+        c.apply_schedule(at(1, 9));
+        update_server(&mut c, 300, false);
+        assert_eq!(c.throttler.ul_rate(), Some(300));
+        assert_eq!(last_throttle(&c), Some((Some(300), Some(200))));
+        c.apply_schedule(at(1, 12));
+        assert_eq!(c.throttler.ul_rate(), Some(300));
+
+        // Temporary overrides are dropped at the boundary
+        c.apply_schedule(at(1, 17));
+        assert_eq!(c.throttler.ul_rate(), None);
+        assert_eq!(c.data.throttle_ul, None);
+
+        // Permanent ones become the base rate and outlast windows
+        update_server(&mut c, 400, true);
+        assert_eq!(c.data.throttle_ul, Some(400));
+        c.apply_schedule(at(8, 9));
+        assert_eq!(c.throttler.ul_rate(), Some(400));
+        c.apply_schedule(at(8, 17));
+        assert_eq!(c.throttler.ul_rate(), Some(400));
+
+        // Until a temporary override hands control back to the scheduler
+        update_server(&mut c, 50, false);
+        c.apply_schedule(at(15, 9));
+        assert_eq!(c.throttler.ul_rate(), Some(100));
+        c.apply_schedule(at(15, 17));
+        assert_eq!(c.throttler.ul_rate(), Some(400));
+    }
+
+    #[test]
+    fn test_validate() {
+        let weekdays = [Weekday::Mon, Weekday::Tue, Weekday::Wed];
+        let ok = vec![
+            rule(&weekdays, 0, 8, 1),
+            rule(&weekdays, 8, 24, 2),
+            rule(&[Weekday::Sat], 4, 6, 3),
+        ];
+        assert!(validate_scheduler(&ok).is_ok());
+
+        let overlap = vec![rule(&weekdays, 0, 9, 1), rule(&[Weekday::Wed], 8, 10, 2)];
+        assert!(validate_scheduler(&overlap).is_err());
+        assert!(validate_scheduler(&[rule(&weekdays, 5, 5, 1)]).is_err());
+        assert!(validate_scheduler(&[rule(&weekdays, 20, 25, 1)]).is_err());
+        assert!(validate_scheduler(&[rule(&[], 1, 2, 1)]).is_err());
+    }
+}
@@ -0,0 +1,356 @@
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::{fs, io};
+
+use crate::torrent::Saved;
+use crate::torrent::history::History;
+use crate::util::io_err;
+
+/// Suffix appended to session files of a torrent which was already loaded from another
+pub const DUPLICATE_EXT: &str = "duplicate";
+
+/// Reads a torrent's session from a session directory entry, if the entry is one.
+pub fn read(entry: io::Result<fs::DirEntry>) -> io::Result<Option<(PathBuf, Saved)>> {
+    let dir = entry?;
+    // TODO: We probably should improve this heuristic with and not rely
+    // on directory entries, but this is good enough for now.
+    if dir.file_name().len() != 40 {
+        return Ok(None);
+    }
+    trace!("Attempting to deserialize file {:?}", dir);
+    let path = dir.path();
+    let session_data = fs::read(&path)?;
+    trace!("Succesfully read session file");
+    let info_data = match fs::read(info_path(&path)) {
+        Ok(data) => Ok(Some(data)),
+        // Older versions of synapse serialized the info as part of the session state, so a
+        // missing info file is not necessarily fatal.
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }?;
+    trace!("Successully read info file");
+    match Saved::load(&session_data, info_data.as_deref()) {
+        Some(mut saved) => {
+            let history = read_history(&path);
+            match fs::read(suffixed(&path, "metadata")) {
+                Ok(data) => saved = saved.with_metadata(data),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => error!("Failed to read metadata of {:?}: {}", dir.file_name(), e),
+            }
+            Ok(Some((path, saved.with_history(history))))
+        }
+        None => {
+            error!("Failed to deserialize torrent {:?}", dir.file_name());
+            io_err("Torrent data invalid!")
+        }
+    }
+}
+
+/// Moves a session file and its info file out of the way of loading, returning where
+/// the session file went.
+pub fn quarantine(session: &Path) -> io::Result<PathBuf> {
+    // Don't clobber what an earlier startup set aside
+    let mut moved = suffixed(session, DUPLICATE_EXT);
+    let mut n = 1;
+    while moved.exists() {
+        moved = suffixed(session, &format!("{DUPLICATE_EXT}.{n}"));
+        n += 1;
+    }
+    fs::rename(session, &moved)?;
+    for ext in ["info", "metadata", "history"] {
+        let sidecar = suffixed(session, ext);
+        if sidecar.exists() {
+            fs::rename(&sidecar, suffixed(&moved, ext))?;
+        }
+    }
+    Ok(moved)
+}
+
+/// Renames a session file and its info file, unless that would replace another torrent's.
+pub fn rename(session: &Path, name: &str) -> io::Result<()> {
+    let dest = session.with_file_name(name);
+    if dest.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} already exists", dest.display()),
+        ));
+    }
+    for ext in ["info", "metadata", "history"] {
+        let sidecar = suffixed(session, ext);
+        if sidecar.exists() {
+            fs::rename(&sidecar, suffixed(&dest, ext))?;
+        }
+    }
+    fs::rename(session, dest)
+}
+
+/// Reads the work log kept next to a session file, which is empty if there's none yet.
+pub fn read_history(session: &Path) -> History {
+    let path = suffixed(session, "history");
+    match fs::read(&path) {
+        Ok(data) => History::load(&data).unwrap_or_else(|| {
+            error!("Discarding unreadable work log {}", path.display());
+            History::default()
+        }),
+        Err(e) => {
+            if e.kind() != io::ErrorKind::NotFound {
+                error!("Failed to read work log {}: {}", path.display(), e);
+            }
+            History::default()
+        }
+    }
+}
+
+fn info_path(session: &Path) -> PathBuf {
+    suffixed(session, "info")
+}
+
+fn suffixed(path: &Path, ext: &str) -> PathBuf {
+    let mut name = OsString::from(path);
+    name.push(".");
+    name.push(ext);
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::sync::Arc;
+
+    use crate::config::Config;
+    use crate::control::Control;
+    use crate::control::cio::test::TCIO;
+    use crate::rpc::processor::Processor;
+    use crate::rpc::proto::message::{AddError, CMessage, HistoryAction, SMessage};
+    use crate::rpc::resource::{CResourceUpdate, DuplicateSession, Resource};
+    use crate::torrent::{Info, Preset};
+    use crate::util::hash_to_id;
+    use crate::{disk, rpc};
+
+    /// Writes out the session files the control asked disk to save.
+    fn save(c: &Control<TCIO>, dir: &std::path::Path) {
+        for r in c.cio.data().disk_msgs.drain(..) {
+            if let disk::Request::Serialize {
+                data,
+                hash,
+                extension,
+                ..
+            } = r
+            {
+                let name = format!("{}{}", hash_to_id(&hash), extension.unwrap_or(""));
+                fs::write(dir.join(name), data).unwrap();
+            }
+        }
+    }
+
+    fn add(serial: u64) -> rpc::Message {
+        rpc::Message::Torrent {
+            info: Info::with_name("dup"),
+            path: None,
+            start: true,
+            import: false,
+            preset: Preset::default(),
+            client: 0,
+            serial,
+        }
+    }
+
+    #[test]
+    fn test_duplicate_sessions() {
+        let session = tempfile::tempdir().unwrap();
+        let data = tempfile::tempdir().unwrap();
+        let path = |name: &str| session.path().join(name);
+        let config = || {
+            let mut config = Config::default();
+            config.disk.session = session.path().to_string_lossy().into_owned();
+            config.disk.directory = data.path().to_string_lossy().into_owned();
+            config
+        };
+
+        // Save a torrent, and a copy of it under another name which sorts first
+        let mut c = Control::test(config());
+        c.handle_rpc_ev(add(0));
+        let files: Vec<_> = c
+            .cio
+            .data()
+            .disk_msgs
+            .drain(..)
+            .filter_map(|r| match r {
+                disk::Request::Serialize {
+                    data, extension, ..
+                } => Some((data, extension.unwrap_or(""))),
+                _ => None,
+            })
+            .collect();
+        let id = hash_to_id(&Info::with_name("dup").hash);
+        let copy = "0".repeat(40);
+        for (data, ext) in files {
+            fs::write(path(&format!("{id}{ext}")), &data).unwrap();
+            fs::write(path(&format!("{copy}{ext}")), &data).unwrap();
+        }
+
+        let mut c = Control::test(config());
+        c.deserialize().unwrap();
+        assert_eq!(c.torrents.len(), 1);
+        let expected = vec![DuplicateSession {
+            infohash: id.clone(),
+            loaded: path(&copy).display().to_string(),
+            quarantined: path(&format!("{id}.duplicate")).display().to_string(),
+        }];
+        assert_eq!(c.duplicates, expected);
+        assert!(path(&format!("{id}.duplicate.info")).exists());
+        assert!(path(&format!("{id}.duplicate.metadata")).exists());
+        // The copy which was loaded takes the place of the one set aside
+        assert!(path(&id).exists());
+        assert!(path(&format!("{id}.info")).exists());
+        assert!(!path(&copy).exists());
+
+        c.send_rpc_info();
+        let reported = c.cio.data().rpc_msgs.iter().find_map(|m| match m {
+            rpc::CtlMessage::Extant(r) => r.iter().find_map(|r| match r {
+                Resource::Server(s) => Some(s.duplicate_sessions.clone()),
+                _ => None,
+            }),
+            _ => None,
+        });
+        assert_eq!(reported, Some(expected));
+
+        // Which leaves nothing to clash the next time
+        let mut c = Control::test(config());
+        c.deserialize().unwrap();
+        assert_eq!(c.torrents.len(), 1);
+        assert!(c.duplicates.is_empty());
+    }
+
+    #[test]
+    fn test_concurrent_add() {
+        let data = tempfile::tempdir().unwrap();
+        let mut config = Config::default();
+        config.disk.directory = data.path().to_string_lossy().into_owned();
+        let mut c = Control::test(config);
+
+        // Two clients adding the same torrent before either hears back
+        c.handle_rpc_ev(add(1));
+        c.handle_rpc_ev(add(2));
+        assert_eq!(c.torrents.len(), 1);
+        assert_eq!(c.hash_idx.len(), 1);
+        let d = c.cio.data();
+        let mut replies = d.rpc_msgs.iter().filter_map(|m| match m {
+            rpc::CtlMessage::Uploaded { serial, .. } => Some((*serial, None)),
+            rpc::CtlMessage::AddFailed { serial, error, .. } => Some((*serial, Some(*error))),
+            _ => None,
+        });
+        assert_eq!(replies.next(), Some((1, None)));
+        assert_eq!(replies.next(), Some((2, Some(AddError::Duplicate))));
+        assert_eq!(replies.next(), None);
+    }
+
+    #[test]
+    fn test_history() {
+        let session = tempfile::tempdir().unwrap();
+        let data = tempfile::tempdir().unwrap();
+        let config = || {
+            let mut config = Config::default();
+            config.disk.session = session.path().to_string_lossy().into_owned();
+            config.disk.directory = data.path().to_string_lossy().into_owned();
+            config
+        };
+        let id = hash_to_id(&Info::with_name("dup").hash);
+
+        let mut c = Control::test(config());
+        let (db, _jobs) = flume::unbounded();
+        let mut p = Processor::new(Arc::new(config()), db);
+        c.handle_rpc_ev(add(0));
+        for msg in c.cio.data().rpc_msgs.drain(..) {
+            p.handle_ctl(msg);
+        }
+        p.add_client(1, 0, "alice@10.0.0.1".to_owned());
+        p.add_client(2, 0, "10.0.0.2".to_owned());
+        let mut send = |c: &mut Control<TCIO>, client, msg| {
+            let (_, m) = p.handle_client(client, msg);
+            c.handle_rpc_ev(m.unwrap());
+        };
+
+        let pause = CMessage::PauseTorrent {
+            serial: 1,
+            id: id.clone(),
+        };
+        send(&mut c, 1, pause);
+        let resource = CResourceUpdate {
+            id: id.clone(),
+            priority: Some(5),
+            ..Default::default()
+        };
+        send(
+            &mut c,
+            2,
+            CMessage::UpdateResource {
+                serial: 2,
+                resource,
+            },
+        );
+        let tracker = CMessage::AddTracker {
+            serial: 3,
+            id: id.clone(),
+            uri: "http://tracker.example/announce".to_owned(),
+        };
+        send(&mut c, 2, tracker);
+        let resume = CMessage::ResumeTorrent {
+            serial: 4,
+            id: id.clone(),
+        };
+        send(&mut c, 1, resume);
+        let expected = vec![
+            ("alice@10.0.0.1", HistoryAction::Pause, None),
+            ("10.0.0.2", HistoryAction::Priority, Some("5")),
+            (
+                "10.0.0.2",
+                HistoryAction::AddTracker,
+                Some("http://tracker.example/announce"),
+            ),
+            ("alice@10.0.0.1", HistoryAction::Resume, None),
+        ];
+
+        c.cio.data().rpc_msgs.clear();
+        send(
+            &mut c,
+            2,
+            CMessage::GetHistory {
+                serial: 5,
+                id: id.clone(),
+            },
+        );
+        let msg = c.cio.data().rpc_msgs.pop().unwrap();
+        let history = match p.handle_ctl(msg).pop() {
+            Some((2, SMessage::History(h))) => h,
+            m => panic!("unexpected response {m:?}"),
+        };
+        assert_eq!((history.serial, history.id.as_str()), (5, id.as_str()));
+        let recorded: Vec<_> = history
+            .entries
+            .iter()
+            .map(|e| (e.by.as_str(), e.action, e.detail.as_deref()))
+            .collect();
+        assert_eq!(recorded, expected);
+
+        // The log outlives a restart, and the torrent itself
+        save(&c, session.path());
+        let mut c = Control::test(config());
+        c.deserialize().unwrap();
+        assert_eq!(c.torrents[&0].history(0).entries, history.entries);
+        c.handle_rpc_ev(rpc::Message::RemoveTorrent {
+            id: id.clone(),
+            client: 1,
+            serial: 6,
+            artifacts: false,
+            by: "10.0.0.2".to_owned(),
+        });
+        save(&c, session.path());
+        fs::remove_file(session.path().join(&id)).unwrap();
+        let mut c = Control::test(config());
+        c.handle_rpc_ev(add(7));
+        let entries = c.torrents[&0].history(0).entries;
+        assert_eq!(entries.len(), expected.len() + 1);
+        assert_eq!(entries.last().unwrap().action, HistoryAction::Remove);
+    }
+}
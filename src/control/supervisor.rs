@@ -0,0 +1,210 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::{io, thread};
+
+use crate::config::Config;
+use crate::control::acio::ACChans;
+use crate::control::cio::Worker;
+use crate::util::io_err_val;
+use crate::{disk, rpc, tracker};
+
+/// Number of times in a row a single worker may be restarted before
+/// we consider the process unrecoverable.
+const MAX_RESTARTS: u32 = 5;
+/// Delay before restarting a worker after its first crash, doubled
+/// for every further crash in a row
+const BACKOFF: Duration = Duration::from_secs(1);
+/// Time a worker must go without crashing for its earlier crashes
+/// to be forgotten
+const STABLE: Duration = Duration::from_secs(10 * 60);
+
+/// Crash history of a worker, deciding when it's restarted.
+#[derive(Clone, Copy, Debug, Default)]
+struct Restarts {
+    /// Crashes in a row, each less than `STABLE` after the one before
+    count: u32,
+    last: Option<Instant>,
+}
+
+impl Restarts {
+    /// Records a crash at `now`, returning when the worker should be
+    /// restarted, or None once it keeps crashing.
+    fn crashed(&mut self, now: Instant) -> Option<Instant> {
+        if self.last.is_some_and(|last| now - last >= STABLE) {
+            self.count = 0;
+        }
+        self.last = Some(now);
+        if self.count >= MAX_RESTARTS {
+            return None;
+        }
+        let delay = BACKOFF * 2u32.pow(self.count);
+        self.count += 1;
+        Some(now + delay)
+    }
+}
+
+/// Owns the worker threads used by control, restarting them if they crash.
+pub struct Supervisor {
+    config: Arc<Config>,
+    // Jobs sent to disk by other workers. The receiver is held here so that
+    // senders remain valid across disk restarts.
+    disk_tx: flume::Sender<disk::Request>,
+    disk_rx: flume::Receiver<disk::Request>,
+    disk: Option<thread::JoinHandle<bool>>,
+    rpc: Option<thread::JoinHandle<()>>,
+    trk: Option<thread::JoinHandle<()>>,
+    restarts: [Restarts; 3],
+}
+
+impl Supervisor {
+    pub fn start(config: Arc<Config>, creg: &mut amy::Registrar) -> io::Result<(Self, ACChans)> {
+        let (disk_tx, disk_rx) = flume::unbounded();
+        let (dh, dhj) = disk::start(config.clone(), creg, disk_rx.clone())?;
+        let (rh, rhj) = rpc::RPC::start(config.clone(), creg, disk_tx.clone())?;
+        let (th, thj) = tracker::Tracker::start(config.clone(), creg, disk_tx.clone())?;
+        let chans = ACChans {
+            disk_tx: dh.tx,
+            disk_rx: dh.rx,
+            rpc_tx: rh.tx,
+            rpc_rx: rh.rx,
+            trk_tx: th.tx,
+            trk_rx: th.rx,
+        };
+        let s = Supervisor {
+            config,
+            disk_tx,
+            disk_rx,
+            disk: Some(dhj),
+            rpc: Some(rhj),
+            trk: Some(thj),
+            restarts: [Restarts::default(); 3],
+        };
+        Ok((s, chans))
+    }
+
+    /// Sender for jobs to be broadcast to the disk worker.
+    pub fn disk_broadcast(&self) -> flume::Sender<disk::Request> {
+        self.disk_tx.clone()
+    }
+
+    /// Records a crash of `worker`, returning when to restart it. Fails
+    /// once the worker has exhausted its restarts.
+    pub fn crashed(&mut self, worker: Worker, now: Instant) -> io::Result<Instant> {
+        let restarts = &mut self.restarts[worker as usize];
+        let at = restarts
+            .crashed(now)
+            .ok_or_else(|| io_err_val("worker restart limit reached"))?;
+        error!(
+            "{} worker crashed, restarting in {}s ({}/{})",
+            worker,
+            (at - now).as_secs(),
+            restarts.count,
+            MAX_RESTARTS
+        );
+        Ok(at)
+    }
+
+    /// Replaces a crashed worker with a new one, swapping its channels
+    /// into `chans`.
+    pub fn restart(
+        &mut self,
+        worker: Worker,
+        creg: &mut amy::Registrar,
+        chans: &mut ACChans,
+    ) -> io::Result<()> {
+        // The old thread must be fully gone before its replacement
+        // attempts to bind the same ports.
+        match worker {
+            Worker::Disk => {
+                if let Some(h) = self.disk.take() {
+                    h.join().ok();
+                }
+                let (dh, dhj) = disk::start(self.config.clone(), creg, self.disk_rx.clone())?;
+                chans.disk_tx = dh.tx;
+                chans.disk_rx = dh.rx;
+                self.disk = Some(dhj);
+            }
+            Worker::RPC => {
+                if let Some(h) = self.rpc.take() {
+                    h.join().ok();
+                }
+                let (rh, rhj) = rpc::RPC::start(self.config.clone(), creg, self.disk_tx.clone())?;
+                chans.rpc_tx = rh.tx;
+                chans.rpc_rx = rh.rx;
+                self.rpc = Some(rhj);
+            }
+            Worker::Tracker => {
+                if let Some(h) = self.trk.take() {
+                    h.join().ok();
+                }
+                let (th, thj) =
+                    tracker::Tracker::start(self.config.clone(), creg, self.disk_tx.clone())?;
+                chans.trk_tx = th.tx;
+                chans.trk_rx = th.rx;
+                self.trk = Some(thj);
+            }
+        }
+        Ok(())
+    }
+
+    /// Waits for all workers to exit, returning whether they shut down
+    /// cleanly and all data was flushed to disk.
+    pub fn join(&mut self) -> bool {
+        let mut clean = true;
+        for h in [self.rpc.take(), self.trk.take()].into_iter().flatten() {
+            if h.join().is_err() {
+                error!("Unclean shutdown detected, terminating");
+                clean = false;
+            }
+        }
+        match self.disk.take().map(|h| h.join()) {
+            Some(Ok(true)) | None => clean,
+            Some(Ok(false)) => {
+                error!("Failed to flush all data to disk before shutdown");
+                false
+            }
+            Some(Err(_)) => {
+                error!("Unclean shutdown detected, terminating");
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let start = Instant::now();
+        let secs = |s| start + Duration::from_secs(s);
+        let mut r = Restarts::default();
+        // Each crash in a row doubles the wait
+        assert_eq!(r.crashed(secs(0)), Some(secs(1)));
+        assert_eq!(r.crashed(secs(2)), Some(secs(4)));
+        assert_eq!(r.crashed(secs(5)), Some(secs(9)));
+
+        // Running stably wipes the slate clean
+        assert_eq!(r.crashed(secs(5) + STABLE), Some(secs(6) + STABLE));
+        assert_eq!(r.count, 1);
+    }
+
+    #[test]
+    fn test_give_up() {
+        let start = Instant::now();
+        let mut r = Restarts::default();
+        let mut now = start;
+        for i in 0..MAX_RESTARTS {
+            let at = r.crashed(now).unwrap();
+            assert_eq!(at - now, BACKOFF * 2u32.pow(i));
+            now = at;
+        }
+        // The worker crashed right after each restart, so it's given up on
+        assert_eq!(r.crashed(now), None);
+        let now = now + Duration::from_secs(1);
+        assert_eq!(r.crashed(now), None);
+        // Unless it had eventually been running for a while
+        assert!(r.crashed(now + STABLE).is_some());
+    }
+}
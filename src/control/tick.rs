@@ -0,0 +1,176 @@
+use std::time::{Duration, Instant};
+
+use crate::control::cio::Event;
+use crate::disk;
+
+/// Interval (in ms) of the job timer while there's activity
+pub const BASE_TICK_MS: usize = 500;
+/// Longest interval (in ms) the job timer is stretched to while idle
+pub const MAX_TICK_MS: usize = 5000;
+/// Time without any activity before the job timer starts stretching
+const IDLE_AFTER: Duration = Duration::from_secs(10);
+
+/// Interval of the control loop's job timer, stretched while nothing is happening so an
+/// idle daemon wakes up less often.
+pub struct Tick {
+    interval: usize,
+    last_activity: Instant,
+}
+
+impl Tick {
+    pub fn new(now: Instant) -> Tick {
+        Tick {
+            interval: BASE_TICK_MS,
+            last_activity: now,
+        }
+    }
+
+    /// The current interval in ms.
+    pub fn interval(&self) -> usize {
+        self.interval
+    }
+
+    pub fn stretched(&self) -> bool {
+        self.interval > BASE_TICK_MS
+    }
+
+    /// Records activity, returning true if the interval was stretched and has gone back
+    /// to the base rate.
+    pub fn activity(&mut self, now: Instant) -> bool {
+        self.last_activity = now;
+        let stretched = self.stretched();
+        self.interval = BASE_TICK_MS;
+        stretched
+    }
+
+    /// Called on each tick, returning the new interval once there's been no activity for
+    /// a while. The interval doubles every tick up to `MAX_TICK_MS`.
+    pub fn idle(&mut self, now: Instant) -> Option<usize> {
+        if self.interval == MAX_TICK_MS || now.duration_since(self.last_activity) < IDLE_AFTER {
+            return None;
+        }
+        self.interval = (self.interval * 2).min(MAX_TICK_MS);
+        Some(self.interval)
+    }
+}
+
+/// Whether an event means the daemon has something to do. Timers and the responses to
+/// periodic disk queries are left out, as they keep coming while idle.
+pub fn is_activity(event: &Event) -> bool {
+    !matches!(
+        event,
+        Event::Timer(_)
+            | Event::Disk(Ok(disk::Response::FreeSpace(_)))
+            | Event::Disk(Ok(disk::Response::Stats(_)))
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use crate::config::Config;
+    use crate::control::Control;
+    use crate::control::cio::CIO;
+    use crate::control::cio::test::TCIO;
+    use crate::rpc;
+    use crate::throttle;
+
+    #[test]
+    fn test_stretch() {
+        let start = Instant::now();
+        let at = |ms: u64| start + Duration::from_millis(ms);
+        let mut t = Tick::new(start);
+        assert_eq!(t.idle(at(9_500)), None);
+        assert_eq!(t.interval(), BASE_TICK_MS);
+
+        // Stretches progressively once idle, up to the maximum
+        assert_eq!(t.idle(at(10_000)), Some(1000));
+        assert_eq!(t.idle(at(11_000)), Some(2000));
+        assert_eq!(t.idle(at(13_000)), Some(4000));
+        assert_eq!(t.idle(at(17_000)), Some(MAX_TICK_MS));
+        assert_eq!(t.idle(at(22_000)), None);
+        assert!(t.stretched());
+
+        // Activity snaps straight back, and idling starts over
+        assert!(t.activity(at(23_000)));
+        assert_eq!(t.interval(), BASE_TICK_MS);
+        assert!(!t.activity(at(23_100)));
+        assert_eq!(t.idle(at(33_000)), None);
+        assert_eq!(t.idle(at(33_100)), Some(1000));
+    }
+
+    fn control() -> Control<TCIO> {
+        let mut config = Config::default();
+        config.disk.validate = false;
+        let mut c = Control::test(config);
+        // The throttler's timers come from amy, so give it ones the mock knows about
+        let id = c.cio.set_timer(throttle::URATE).unwrap();
+        let fid = c.cio.set_timer(throttle::FLUSH_MS).unwrap();
+        c.throttler.set_timers(id, fid);
+        c
+    }
+
+    /// Tick intervals reported over RPC for the health query, in order.
+    fn reported(c: &Control<TCIO>) -> Vec<u64> {
+        c.cio
+            .data()
+            .rpc_msgs
+            .iter()
+            .filter_map(|m| match m {
+                rpc::CtlMessage::TickInterval(ms) => Some(*ms),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Interval the control loop last waited for events with.
+    fn poll_timeout(c: &mut Control<TCIO>) -> usize {
+        c.poll(&mut Vec::new()).unwrap();
+        c.cio.data().poll_timeout.unwrap()
+    }
+
+    #[test]
+    fn test_control_stretch() {
+        let mut c = control();
+        let now = Instant::now();
+        c.tick = Tick::new(now.checked_sub(Duration::from_secs(20)).unwrap());
+        assert_eq!(poll_timeout(&mut c), BASE_TICK_MS);
+
+        // Peers waiting on the throttler keep everything at the base rate
+        let mut throttle = c.throttler.get_throttle(0);
+        throttle.set_stalled_dl();
+        c.stretch_tick(now);
+        assert!(!c.tick.stretched());
+        c.throttler.flush_dl();
+
+        c.stretch_tick(now);
+        c.stretch_tick(now);
+        {
+            let d = c.cio.data();
+            // Only the job timer is left, at its stretched interval
+            assert_eq!(d.intervals.len(), 1);
+            assert_eq!(d.intervals[&c.job_timer], 2000);
+        }
+        // Waiting for events doesn't wake the loop any sooner
+        assert_eq!(poll_timeout(&mut c), 2000);
+
+        // Periodic disk responses don't count as activity
+        let space = Event::Disk(Ok(disk::Response::FreeSpace(0)));
+        assert!(!is_activity(&space));
+        c.handle_event(space);
+        assert!(c.tick.stretched());
+
+        c.note_activity(now);
+        {
+            let d = c.cio.data();
+            assert_eq!(d.intervals.len(), 3);
+            assert_eq!(d.intervals[&c.job_timer], BASE_TICK_MS);
+            assert_eq!(d.intervals[&c.throttler.id()], throttle::URATE);
+            assert_eq!(d.intervals[&c.throttler.fid()], throttle::FLUSH_MS);
+        }
+        assert_eq!(poll_timeout(&mut c), BASE_TICK_MS);
+        assert_eq!(reported(&c), [1000, 2000, BASE_TICK_MS as u64]);
+    }
+}
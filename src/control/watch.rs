@@ -0,0 +1,96 @@
+use std::path::{Path, PathBuf};
+use std::{fs, io};
+
+use crate::bencode;
+use crate::torrent::Info;
+
+/// Suffix appended to torrent files which were added
+pub const LOADED_EXT: &str = "loaded";
+/// Suffix appended to torrent files which could not be added
+pub const FAILED_EXT: &str = "failed";
+
+/// Lists the unprocessed torrent files in a watch directory.
+pub fn pending(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() && path.extension().is_some_and(|e| e == "torrent") {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+pub fn load(path: &Path) -> Result<Info, String> {
+    let data = fs::read(path).map_err(|e| e.to_string())?;
+    let b = bencode::decode_buf(&data).map_err(|e| format!("bad bencoded data: {e}"))?;
+    Info::from_bencode(b).map_err(str::to_owned)
+}
+
+/// Marks a torrent file as processed by appending a suffix to its name.
+pub fn mark(path: &Path, ext: &str) -> io::Result<()> {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(ext);
+    fs::rename(path, name)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use std::{fs, thread};
+
+    use crate::config::{Config, WatchDir};
+    use crate::control::cio::test::TCIO;
+    use crate::control::{CJob, Control, WatchUpdate};
+    use crate::torrent::Info;
+
+    /// Runs the watch job until `done` holds, failing once a deadline passes.
+    fn scan_until<F: Fn() -> bool>(c: &mut Control<TCIO>, done: F) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            WatchUpdate.update(c);
+            if done() {
+                return;
+            }
+            assert!(
+                Instant::now() < deadline,
+                "watch dir wasn't processed in time"
+            );
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    #[test]
+    fn test_watch_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name);
+        fs::write(path("a.torrent"), Info::test_torrent("a").encode_to_buf()).unwrap();
+        fs::write(path("b.torrent"), b"garbage").unwrap();
+        fs::write(path("c.txt"), b"ignored").unwrap();
+
+        let mut config = Config::default();
+        config.watch_dirs.push(WatchDir {
+            path: dir.path().to_string_lossy().into_owned(),
+            directory: Some(data.path().to_string_lossy().into_owned()),
+            paused: false,
+            delete: false,
+        });
+        let mut c = Control::test(config);
+
+        scan_until(&mut c, || {
+            path("a.torrent.loaded").exists() && path("b.torrent.failed").exists()
+        });
+        assert_eq!(c.torrents.len(), 1);
+        let t = c.torrents.values().next().unwrap();
+        assert!(!t.status().paused);
+        assert!(path("c.txt").exists());
+
+        // Duplicates are rejected
+        fs::write(path("d.torrent"), Info::test_torrent("a").encode_to_buf()).unwrap();
+        scan_until(&mut c, || path("d.torrent.failed").exists());
+        assert_eq!(c.torrents.len(), 1);
+    }
+}
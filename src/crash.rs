@@ -0,0 +1,83 @@
+use std::backtrace::Backtrace;
+use std::fmt::Display;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::{fs, io, panic, thread};
+
+use chrono::Local;
+
+use crate::log;
+
+/// Installs a panic hook which writes a crash report containing the
+/// backtrace and recent log messages to `dir`, then defers to the
+/// previous hook. Reports are only ever written locally.
+pub fn install(dir: PathBuf) {
+    log::record_recent();
+    let prev = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        let backtrace = Backtrace::force_capture();
+        let thread = thread::current();
+        let name = thread.name().unwrap_or("unnamed");
+        match write_report(&dir, name, info, &backtrace, &log::recent()) {
+            Ok(path) => error!(
+                "Thread {} panicked, crash report written to {}",
+                name,
+                path.display()
+            ),
+            Err(e) => error!(
+                "Thread {} panicked, failed to write crash report: {}",
+                name, e
+            ),
+        }
+        prev(info);
+    }));
+}
+
+fn write_report(
+    dir: &Path,
+    thread: &str,
+    panic: &dyn Display,
+    backtrace: &dyn Display,
+    logs: &[String],
+) -> io::Result<PathBuf> {
+    fs::create_dir_all(dir)?;
+    let now = Local::now();
+    let path = dir.join(format!(
+        "crash-{}-{}.txt",
+        now.format("%Y%m%d-%H%M%S%.3f"),
+        thread
+    ));
+    let mut f = fs::File::create(&path)?;
+    writeln!(f, "synapse {} crash report", env!("CARGO_PKG_VERSION"))?;
+    writeln!(f, "time: {}", now.to_rfc3339())?;
+    writeln!(f, "thread: {thread}")?;
+    writeln!(f, "\n{panic}")?;
+    writeln!(f, "\nbacktrace:\n{backtrace}")?;
+    writeln!(f, "\nrecent log:")?;
+    for line in logs {
+        f.write_all(line.as_bytes())?;
+    }
+    f.sync_all()?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_report() {
+        let dir = tempfile::tempdir().unwrap();
+        let crashes = dir.path().join("crashes");
+        let logs = vec!["first\n".to_owned(), "second\n".to_owned()];
+        let path = write_report(&crashes, "disk", &"oh no", &"frame 0", &logs).unwrap();
+        assert!(path.starts_with(&crashes));
+        assert!(path.to_str().unwrap().ends_with("-disk.txt"));
+
+        let report = fs::read_to_string(&path).unwrap();
+        assert!(report.contains("thread: disk"));
+        assert!(report.contains("oh no"));
+        assert!(report.contains("backtrace:\nframe 0"));
+        assert!(report.ends_with("recent log:\nfirst\nsecond\n"));
+    }
+}
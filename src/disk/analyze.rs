@@ -0,0 +1,268 @@
+use std::{fs, io, path, time};
+
+use crate::rpc::proto::message::{PathAnalysis, PieceCandidate};
+
+/// Smallest piece length which will be suggested
+const MIN_PIECE_LEN: u64 = 16 * 1024;
+/// Largest piece length which will be suggested
+const MAX_PIECE_LEN: u64 = 16 * 1024 * 1024;
+/// Upper bound on piece count we aim for, the recommendation will lie
+/// between half of this and this unless clamped by the piece length bounds.
+const TARGET_PIECES: u64 = 2000;
+/// Maximum directory depth that will be scanned
+const MAX_SCAN_DEPTH: usize = 32;
+/// Maximum amount of time that will be spent scanning a path
+const MAX_SCAN_MS: u64 = 2000;
+
+/// What a scan of a path found.
+pub struct Scan {
+    /// Sizes of all regular files found
+    pub sizes: Vec<u64>,
+    /// Whether the scan completed before hitting the depth or time bound
+    pub complete: bool,
+    /// Directories which couldn't be read and were skipped, relative to the scanned path
+    pub unreadable: Vec<path::PathBuf>,
+}
+
+/// Resolves `path`, absolute or relative to the first of the download directories `roots`,
+/// refusing anything which isn't within one of them once symlinks and `..` are followed.
+pub fn resolve(roots: &[String], path: &str) -> io::Result<path::PathBuf> {
+    let base = roots.first().map(String::as_str).unwrap_or_default();
+    let resolved = fs::canonicalize(path::Path::new(base).join(path))?;
+    let allowed = roots
+        .iter()
+        .filter_map(|root| fs::canonicalize(root).ok())
+        .any(|root| resolved.starts_with(root));
+    if !allowed {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "path is outside the download directories",
+        ));
+    }
+    Ok(resolved)
+}
+
+/// Scans the given path. Only the path itself has to be readable, directories under it
+/// which can't be read are skipped.
+pub fn scan(path: &path::Path) -> io::Result<Scan> {
+    let meta = fs::metadata(path)?;
+    let mut scan = Scan {
+        sizes: Vec::new(),
+        complete: true,
+        unreadable: Vec::new(),
+    };
+    if meta.is_file() {
+        scan.sizes.push(meta.len());
+        return Ok(scan);
+    }
+    let deadline = time::Instant::now() + time::Duration::from_millis(MAX_SCAN_MS);
+    let mut pending = vec![(path.to_path_buf(), 0)];
+    while let Some((dir, depth)) = pending.pop() {
+        if time::Instant::now() > deadline {
+            scan.complete = false;
+            break;
+        }
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if dir == path => return Err(e),
+            Err(e) => {
+                debug!("Skipping unreadable directory {:?}: {}", dir, e);
+                let rel = dir.strip_prefix(path).unwrap_or(&dir);
+                scan.unreadable.push(rel.to_path_buf());
+                continue;
+            }
+        };
+        // Entries which vanish or can't be inspected mid-scan are left out
+        for entry in entries.flatten() {
+            let Ok(ft) = entry.file_type() else {
+                continue;
+            };
+            if ft.is_file() {
+                if let Ok(meta) = entry.metadata() {
+                    scan.sizes.push(meta.len());
+                }
+            } else if ft.is_dir() {
+                if depth < MAX_SCAN_DEPTH {
+                    pending.push((entry.path(), depth + 1));
+                } else {
+                    scan.complete = false;
+                }
+            }
+        }
+    }
+    Ok(scan)
+}
+
+/// Scans `path` within the download directories `roots` and suggests a piece length for it.
+pub fn analyze(roots: &[String], path: &str, serial: u64) -> io::Result<PathAnalysis> {
+    let scan = scan(&resolve(roots, path)?)?;
+    let mut analysis = advise(serial, &scan.sizes, scan.complete);
+    analysis.unreadable = scan
+        .unreadable
+        .iter()
+        .map(|p| p.to_string_lossy().into_owned())
+        .collect();
+    Ok(analysis)
+}
+
+/// Picks the smallest power of two piece length which keeps the piece count
+/// at or below `TARGET_PIECES`, listing the resulting piece count for every
+/// candidate length.
+pub fn advise(serial: u64, sizes: &[u64], complete: bool) -> PathAnalysis {
+    let total_size = sizes.iter().sum();
+    let candidates: Vec<_> = (MIN_PIECE_LEN.trailing_zeros()..=MAX_PIECE_LEN.trailing_zeros())
+        .map(|shift| {
+            let piece_length = 1 << shift;
+            PieceCandidate {
+                piece_length,
+                pieces: u64::div_ceil(total_size, piece_length),
+            }
+        })
+        .collect();
+    let piece_length = candidates
+        .iter()
+        .find(|c| c.pieces <= TARGET_PIECES)
+        .map_or(MAX_PIECE_LEN, |c| c.piece_length);
+    PathAnalysis {
+        serial,
+        total_size,
+        file_count: sizes.len() as u64,
+        piece_length,
+        candidates,
+        complete,
+        unreadable: Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KB: u64 = 1024;
+    const MB: u64 = 1024 * KB;
+    const GB: u64 = 1024 * MB;
+
+    #[test]
+    fn test_advise_table() {
+        let table = [
+            (0, 16 * KB),
+            (1, 16 * KB),
+            (31 * MB, 16 * KB),
+            (40 * MB, 32 * KB),
+            (700 * MB, 512 * KB),
+            (GB, MB),
+            (4 * GB, 4 * MB),
+            (15 * GB, 8 * MB),
+            (50 * GB, 16 * MB),
+        ];
+        for (size, expected) in table {
+            let a = advise(0, &[size], true);
+            assert_eq!(a.piece_length, expected, "total size {}", size);
+        }
+    }
+
+    #[test]
+    fn test_advise_candidates() {
+        let a = advise(5, &[700 * MB], true);
+        assert_eq!(a.serial, 5);
+        assert_eq!(a.candidates.len(), 11);
+        assert_eq!(a.candidates[0].piece_length, 16 * KB);
+        assert_eq!(a.candidates[10].piece_length, 16 * MB);
+        let rec = a
+            .candidates
+            .iter()
+            .find(|c| c.piece_length == a.piece_length)
+            .unwrap();
+        assert!(rec.pieces > TARGET_PIECES / 2 && rec.pieces <= TARGET_PIECES);
+        for c in &a.candidates {
+            assert_eq!(c.pieces, (700 * MB).div_ceil(c.piece_length));
+        }
+    }
+
+    #[test]
+    fn test_advise_many_files() {
+        // Many small files are judged on their total size
+        let sizes = vec![100 * KB; 10_000];
+        let a = advise(0, &sizes, false);
+        assert_eq!(a.file_count, 10_000);
+        assert_eq!(a.total_size, 1_000_000 * KB);
+        assert_eq!(a.piece_length, 512 * KB);
+        assert!(!a.complete);
+
+        // A single large file dominates the recommendation
+        let mut sizes = vec![KB; 500];
+        sizes.push(8 * GB);
+        assert_eq!(advise(0, &sizes, true).piece_length, 8 * MB);
+    }
+
+    #[test]
+    fn test_scan() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a"), [0u8; 10]).unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        fs::write(dir.path().join("sub").join("b"), [0u8; 20]).unwrap();
+        let mut s = scan(dir.path()).unwrap();
+        s.sizes.sort_unstable();
+        assert_eq!(s.sizes, vec![10, 20]);
+        assert!(s.complete);
+        assert!(s.unreadable.is_empty());
+
+        let s = scan(&dir.path().join("a")).unwrap();
+        assert_eq!(s.sizes, vec![10]);
+    }
+
+    #[test]
+    fn test_scan_unreadable() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a"), [0u8; 10]).unwrap();
+        let locked = dir.path().join("locked");
+        fs::create_dir(&locked).unwrap();
+        fs::write(locked.join("b"), [0u8; 20]).unwrap();
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o000)).unwrap();
+        // Permissions don't stop root, in which case there's nothing to skip
+        let readable = fs::read_dir(&locked).is_ok();
+        let s = scan(dir.path());
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o755)).unwrap();
+        let mut s = s.unwrap();
+        s.sizes.sort_unstable();
+        if readable {
+            assert_eq!(s.sizes, vec![10, 20]);
+        } else {
+            assert_eq!(s.sizes, vec![10]);
+            assert_eq!(s.unreadable, vec![path::PathBuf::from("locked")]);
+        }
+    }
+
+    #[test]
+    fn test_resolve() {
+        let root = tempfile::tempdir().unwrap();
+        let watch = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        fs::create_dir(root.path().join("sub")).unwrap();
+        std::os::unix::fs::symlink(outside.path(), root.path().join("link")).unwrap();
+        let root_path = fs::canonicalize(root.path()).unwrap();
+        let roots = [root.path(), watch.path()].map(|p| p.to_str().unwrap().to_owned());
+
+        assert_eq!(resolve(&roots, "sub").unwrap(), root_path.join("sub"));
+        assert_eq!(resolve(&roots, "").unwrap(), root_path);
+        let abs = root.path().join("sub");
+        assert_eq!(
+            resolve(&roots, abs.to_str().unwrap()).unwrap(),
+            root_path.join("sub")
+        );
+        assert_eq!(
+            resolve(&roots, &roots[1]).unwrap(),
+            fs::canonicalize(watch.path()).unwrap()
+        );
+        let outside = outside.path().to_str().unwrap();
+        for escape in ["..", "sub/../..", "/", "link", outside] {
+            assert_eq!(
+                resolve(&roots, escape).unwrap_err().kind(),
+                io::ErrorKind::PermissionDenied,
+                "{escape}"
+            );
+        }
+    }
+}
@@ -1,11 +1,19 @@
+use std::collections::VecDeque;
 use std::ffi::OsString;
+use std::time::{Duration, Instant};
 use std::{fs, io, mem, path};
 
 use std::io::{Read, Seek, SeekFrom, Write};
 
+use crate::config::SyncPolicy;
+use crate::rpc::proto::message::DiskCounters;
 use crate::util::{MHashMap, native};
 
 const PB_LEN: usize = 256;
+/// Number of files whose read-ahead data is kept at once
+const READ_AHEAD_SLOTS: usize = 8;
+/// Number of recently verified pieces remembered, whether or not their data is cached
+const VERIFIED_SLOTS: usize = 1024;
 
 /// A simple allocation pool to reduce allocations. Currently hardcoded to hold two `PathBuf`s and
 /// one `Vec<u8>`. Use `data()` to borrow these objects; they will automatically be returned to the
@@ -19,11 +27,57 @@ pub struct BufCache {
 pub struct FileCache {
     files: MHashMap<path::PathBuf, Entry>,
     max_size: usize,
+    /// Incremented on every access, entries record its value when last used
+    clock: u64,
+    sync: SyncPolicy,
+    stats: DiskCounters,
+    /// Bytes read in one go for sequential reads, 0 to disable read-ahead
+    read_ahead: usize,
+    /// Data read past the end of recent sequential reads, most recently used last
+    ahead: VecDeque<ReadAhead>,
+    /// Windows of pieces read whole to serve the blocks peers request from them
+    pieces: MHashMap<PieceKey, CachedPiece>,
+    /// Bytes the piece cache may hold, 0 to disable it
+    piece_budget: usize,
+    piece_bytes: usize,
+    /// Pieces which matched their hash since they were last written, most recent last
+    verified: VecDeque<(usize, u32)>,
+}
+
+/// A window of a piece in the piece cache, starting `offset` bytes into the piece.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct PieceKey {
+    pub tid: usize,
+    pub piece: u32,
+    pub offset: u32,
+}
+
+struct CachedPiece {
+    last_used: u64,
+    data: Vec<u8>,
+    /// The whole piece, checked against its hash when it was read
+    verified: bool,
+}
+
+struct ReadAhead {
+    path: path::PathBuf,
+    offset: u64,
+    data: Vec<u8>,
+}
+
+impl ReadAhead {
+    fn overlaps(&self, path: &path::Path, offset: u64, len: u64) -> bool {
+        self.path == path
+            && offset < self.offset + self.data.len() as u64
+            && self.offset < offset + len
+    }
 }
 
 pub enum RequestedSize {
     WithoutFallocate(u64),
     WithFallocate(u64),
+    /// Leave the length alone, letting the file grow as it's written to
+    Unsized,
 }
 
 enum Mode {
@@ -39,9 +93,32 @@ enum State {
 
 #[derive(Debug)]
 pub struct Entry {
-    used: bool,
+    last_used: u64,
     state: State,
     file: fs::File,
+    /// Bytes written since the file was last synced
+    dirty: u64,
+    /// When the oldest unsynced write was made
+    dirty_since: Option<Instant>,
+}
+
+impl Entry {
+    fn new(file: fs::File, state: State, last_used: u64) -> Entry {
+        Entry {
+            last_used,
+            state,
+            file,
+            dirty: 0,
+            dirty_since: None,
+        }
+    }
+
+    fn sync(&mut self, stats: &mut DiskCounters) -> io::Result<()> {
+        stats.fsyncs += 1;
+        self.dirty = 0;
+        self.dirty_since = None;
+        self.file.sync_all()
+    }
 }
 
 pub struct TempPB<'a> {
@@ -112,13 +189,32 @@ impl BufCache {
 }
 
 impl FileCache {
-    pub fn new(max_size: usize) -> FileCache {
+    pub fn new(
+        max_size: usize,
+        sync: SyncPolicy,
+        read_ahead: usize,
+        piece_budget: usize,
+    ) -> FileCache {
         FileCache {
             files: MHashMap::default(),
             max_size,
+            clock: 0,
+            sync,
+            stats: DiskCounters::default(),
+            read_ahead,
+            ahead: VecDeque::new(),
+            pieces: MHashMap::default(),
+            piece_budget,
+            piece_bytes: 0,
+            verified: VecDeque::new(),
         }
     }
 
+    /// I/O counters accumulated since the cache was created.
+    pub fn stats(&self) -> DiskCounters {
+        self.stats
+    }
+
     pub fn read_file_range(
         &mut self,
         path: &path::Path,
@@ -132,9 +228,171 @@ impl FileCache {
             .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
         entry.file.seek(SeekFrom::Start(offset))?;
         entry.file.read_exact(buf)?;
+        self.stats.reads += 1;
+        self.stats.bytes_read += buf.len() as u64;
         Ok(())
     }
 
+    /// Reads like `read_file_range`, but reads past the requested range up to the
+    /// read-ahead window, so that following reads can be served from memory.
+    pub fn read_file_range_ahead(
+        &mut self,
+        path: &path::Path,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> io::Result<()> {
+        let len = buf.len() as u64;
+        if let Some(pos) = self.ahead.iter().position(|a| {
+            a.path == path && a.offset <= offset && offset + len <= a.offset + a.data.len() as u64
+        }) {
+            let ahead = self.ahead.remove(pos).unwrap();
+            let start = (offset - ahead.offset) as usize;
+            buf.copy_from_slice(&ahead.data[start..start + buf.len()]);
+            self.ahead.push_back(ahead);
+            return Ok(());
+        }
+        if self.read_ahead <= buf.len() {
+            return self.read_file_range(path, offset, buf);
+        }
+
+        self.ensure_exists(path, Mode::ReadOnly)?;
+        let entry = self
+            .files
+            .get_mut(path)
+            .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
+        // Reuse the file's previous window, or the least recently used one
+        let mut ahead = match self.ahead.iter().position(|a| a.path == path) {
+            Some(pos) => self.ahead.remove(pos).unwrap(),
+            None if self.ahead.len() >= READ_AHEAD_SLOTS => self.ahead.pop_front().unwrap(),
+            None => ReadAhead {
+                path: path::PathBuf::new(),
+                offset: 0,
+                data: Vec::new(),
+            },
+        };
+        ahead.data.resize(self.read_ahead, 0);
+        entry.file.seek(SeekFrom::Start(offset))?;
+        // The window may extend past the end of the file
+        let mut read = 0;
+        while read < ahead.data.len() {
+            match entry.file.read(&mut ahead.data[read..]) {
+                Ok(0) => break,
+                Ok(n) => read += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+            self.stats.reads += 1;
+        }
+        self.stats.bytes_read += read as u64;
+        if read < buf.len() {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        buf.copy_from_slice(&ahead.data[..buf.len()]);
+        ahead.data.truncate(read);
+        ahead.path.clear();
+        ahead.path.push(path);
+        ahead.offset = offset;
+        self.ahead.push_back(ahead);
+        Ok(())
+    }
+
+    /// Copies the block at `begin` in a piece out of the piece cache, returning whether the
+    /// window it falls in was cached. With `verified` set, only verified pieces are used.
+    pub fn read_cached_piece(
+        &mut self,
+        key: PieceKey,
+        begin: u32,
+        buf: &mut [u8],
+        verified: bool,
+    ) -> bool {
+        self.clock += 1;
+        let Some(cached) = self
+            .pieces
+            .get_mut(&key)
+            .filter(|cached| cached.verified || !verified)
+        else {
+            self.stats.piece_cache_misses += 1;
+            return false;
+        };
+        let start = (begin - key.offset) as usize;
+        let Some(data) = cached.data.get(start..start + buf.len()) else {
+            self.stats.piece_cache_misses += 1;
+            return false;
+        };
+        buf.copy_from_slice(data);
+        cached.last_used = self.clock;
+        self.stats.piece_cache_hits += 1;
+        true
+    }
+
+    /// Adds a window of a piece to the piece cache, evicting the least recently used ones
+    /// to keep within the budget.
+    pub fn cache_piece(&mut self, key: PieceKey, data: Vec<u8>, verified: bool) {
+        if data.len() > self.piece_budget {
+            return;
+        }
+        self.uncache_pieces(|k| k == &key);
+        while self.piece_bytes + data.len() > self.piece_budget {
+            let Some(&lru) = self
+                .pieces
+                .iter()
+                .min_by_key(|(_, cached)| cached.last_used)
+                .map(|(key, _)| key)
+            else {
+                break;
+            };
+            self.uncache_pieces(|k| k == &lru);
+        }
+        self.piece_bytes += data.len();
+        let last_used = self.clock;
+        self.pieces.insert(
+            key,
+            CachedPiece {
+                last_used,
+                data,
+                verified,
+            },
+        );
+    }
+
+    /// Remembers that a piece matched its hash, so reads needing it verified can trust it
+    /// until it's written to again.
+    pub fn piece_verified(&mut self, tid: usize, piece: u32) {
+        self.verified.retain(|&v| v != (tid, piece));
+        if self.verified.len() == VERIFIED_SLOTS {
+            self.verified.pop_front();
+        }
+        self.verified.push_back((tid, piece));
+    }
+
+    /// Whether a piece matched its hash and hasn't been written to since.
+    pub fn is_verified(&self, tid: usize, piece: u32) -> bool {
+        self.verified.contains(&(tid, piece))
+    }
+
+    /// Drops a piece from the piece cache, e.g. because it's being written to again.
+    pub fn invalidate_piece(&mut self, tid: usize, piece: u32) {
+        self.uncache_pieces(|k| k.tid == tid && k.piece == piece);
+        self.verified.retain(|&v| v != (tid, piece));
+    }
+
+    /// Drops every piece of a torrent from the piece cache.
+    pub fn invalidate_torrent(&mut self, tid: usize) {
+        self.uncache_pieces(|k| k.tid == tid);
+        self.verified.retain(|&(t, _)| t != tid);
+    }
+
+    fn uncache_pieces<F: Fn(&PieceKey) -> bool>(&mut self, f: F) {
+        let bytes = &mut self.piece_bytes;
+        self.pieces.retain(|k, cached| {
+            if f(k) {
+                *bytes -= cached.data.len();
+                return false;
+            }
+            true
+        });
+    }
+
     pub fn write_file_range(
         &mut self,
         path: &path::Path,
@@ -142,30 +400,99 @@ impl FileCache {
         offset: u64,
         buf: &[u8],
     ) -> io::Result<()> {
+        self.ahead
+            .retain(|a| !a.overlaps(path, offset, buf.len() as u64));
         self.ensure_exists(path, Mode::ReadWrite(size))?;
         let entry = self.files.get_mut(path).unwrap();
         entry.file.seek(SeekFrom::Start(offset))?;
         entry.file.write_all(buf)?;
+        self.stats.writes += 1;
+        self.stats.bytes_written += buf.len() as u64;
+        entry.dirty += buf.len() as u64;
+        entry.dirty_since.get_or_insert_with(Instant::now);
+        if let SyncPolicy::EveryBytes(n) = self.sync
+            && entry.dirty >= n
+        {
+            entry.sync(&mut self.stats)?;
+        }
         Ok(())
     }
 
+    /// Called once a piece overlapping the file has been verified.
+    pub fn piece_complete(&mut self, path: &path::Path) {
+        if self.sync == SyncPolicy::OnPieceComplete {
+            self.flush_file(path);
+        }
+    }
+
+    /// Syncs files whose oldest unsynced write is older than the sync interval, if one is
+    /// configured.
+    pub fn tick(&mut self, now: Instant) {
+        let SyncPolicy::Interval(secs) = self.sync else {
+            return;
+        };
+        let threshold = Duration::from_secs(secs);
+        for (path, entry) in &mut self.files {
+            if entry
+                .dirty_since
+                .is_some_and(|t| now.saturating_duration_since(t) >= threshold)
+                && let Err(e) = entry.sync(&mut self.stats)
+            {
+                error!("Failed to sync {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    /// Whether the file exists, either already open or on disk.
+    pub fn exists(&self, path: &path::Path) -> bool {
+        self.files.contains_key(path) || path.exists()
+    }
+
     pub fn remove_file(&mut self, path: &path::Path) {
         self.files.remove(path);
+        self.ahead.retain(|a| a.path != path);
     }
 
     pub fn retain<F: Fn(&path::Path) -> bool>(&mut self, f: F) {
         self.files.retain(|k, _| f(k));
+        self.ahead.retain(|a| f(&a.path));
     }
 
+    /// Syncs the file if it has been written to since it was last synced.
     pub fn flush_file(&mut self, path: &path::Path) {
-        self.files.get_mut(path).map(|e| e.file.sync_all().ok());
+        if let Some(e) = self.files.get_mut(path)
+            && e.dirty != 0
+            && let Err(err) = e.sync(&mut self.stats)
+        {
+            error!("Failed to sync {}: {}", path.display(), err);
+        }
+    }
+
+    /// Syncs every writable file, returning whether all of them succeeded.
+    pub fn flush_all(&mut self) -> bool {
+        let mut ok = true;
+        for (path, entry) in &mut self.files {
+            if let State::ReadOnly = entry.state {
+                continue;
+            }
+            if let Err(e) = entry.sync(&mut self.stats) {
+                error!("Failed to flush {}: {}", path.display(), e);
+                ok = false;
+            }
+        }
+        ok
     }
 
     // TODO: Return a ref to the entry to save some lookups
     fn ensure_exists(&mut self, path: &path::Path, mode: Mode) -> io::Result<()> {
+        self.clock += 1;
         if let Some(entry) = self.files.get_mut(path) {
+            entry.last_used = self.clock;
             match &mode {
-                Mode::ReadOnly => return Ok(()),
+                Mode::ReadOnly => {
+                    self.stats.cache_hits += 1;
+                    return Ok(());
+                }
                 Mode::ReadWrite(requested_size) => match &mut entry.state {
                     State::ReadOnly => {
                         // Evict the entry, since the opened file isn't writable and fall through
@@ -185,29 +512,18 @@ impl FileCache {
                             if !*alloc_failed {
                                 *sparse = false;
                             }
+                            count_fallocate(&mut self.stats, !*alloc_failed);
                         }
+                        self.stats.cache_hits += 1;
                         return Ok(());
                     }
                 },
             }
         }
 
+        self.stats.cache_misses += 1;
         if self.files.len() >= self.max_size {
-            // TODO: While it's unlikely, it seems possible that this might end up removing nothing
-            // from the cache. Perhaps eventual consistency here is OK?
-            let mut removal = None;
-            // We rely on random iteration order to prove us something close to a "clock hand"
-            // like algorithm
-            for (id, entry) in &mut self.files {
-                if entry.used {
-                    entry.used = false;
-                } else {
-                    removal = Some(id.clone());
-                }
-            }
-            if let Some(f) = removal {
-                self.remove_file(&f);
-            }
+            self.evict_lru();
         }
 
         self.files.insert(
@@ -215,12 +531,7 @@ impl FileCache {
             match mode {
                 Mode::ReadOnly => {
                     let file = fs::OpenOptions::new().read(true).open(path)?;
-
-                    Entry {
-                        file,
-                        used: true,
-                        state: State::ReadOnly,
-                    }
+                    Entry::new(file, State::ReadOnly, self.clock)
                 }
                 Mode::ReadWrite(requested_size) => {
                     fs::create_dir_all(path.parent().unwrap())?;
@@ -235,6 +546,7 @@ impl FileCache {
                         RequestedSize::WithFallocate(size) => {
                             if file.metadata()?.len() != size {
                                 let res = !native::fallocate(&file, size)?;
+                                count_fallocate(&mut self.stats, !res);
                                 debug!("Attempted to fallocate {:?}: success {}!", path, !res);
                                 res
                             } else {
@@ -245,24 +557,51 @@ impl FileCache {
                             file.set_len(size)?;
                             false
                         }
+                        RequestedSize::Unsized => false,
                     };
 
                     let sparse = native::is_sparse(&file)?;
 
-                    Entry {
+                    Entry::new(
                         file,
-                        used: true,
-                        state: State::ReadWrite {
+                        State::ReadWrite {
                             alloc_failed,
                             sparse,
                         },
-                    }
+                        self.clock,
+                    )
                 }
             },
         );
 
         Ok(())
     }
+
+    /// Closes the least recently used file, syncing it first if it has unsynced writes.
+    fn evict_lru(&mut self) {
+        let Some(path) = self
+            .files
+            .iter()
+            .min_by_key(|(_, entry)| entry.last_used)
+            .map(|(path, _)| path.clone())
+        else {
+            return;
+        };
+        let mut entry = self.files.remove(&path).unwrap();
+        if entry.dirty != 0
+            && let Err(e) = entry.sync(&mut self.stats)
+        {
+            error!("Failed to sync {}: {}", path.display(), e);
+        }
+    }
+}
+
+fn count_fallocate(stats: &mut DiskCounters, ok: bool) {
+    if ok {
+        stats.fallocate_ok += 1;
+    } else {
+        stats.fallocate_failed += 1;
+    }
 }
 
 impl Drop for FileCache {
@@ -288,13 +627,12 @@ mod tests {
         assert_eq!(buf.get(10).len(), 10);
     }
 
-    // TODO: Add tests for eviction?
     // TODO: Add tests with and without fallocate?
     // TODO: Add tests for delayed fallocate?
     #[test]
     fn test_read_file_range_with_nonexistent_file() {
         let tmp_dir = tempfile::tempdir().unwrap();
-        let mut cache = FileCache::new(8);
+        let mut cache = FileCache::new(8, SyncPolicy::None, 0, 0);
 
         // If the file does not exist, `read_file_range()` should not create it and no cache entry
         // should be created.
@@ -317,7 +655,7 @@ mod tests {
     #[test]
     fn test_write_file_range_with_nonexistent_file() {
         let tmp_dir = tempfile::tempdir().unwrap();
-        let mut cache = FileCache::new(8);
+        let mut cache = FileCache::new(8, SyncPolicy::None, 0, 0);
         let hello_world = "Hello world!";
 
         // In contrast, `write_file_range()` should create the file if it doesn't exist.
@@ -339,9 +677,8 @@ mod tests {
         assert_matches!(
             cache.files.get(&path),
             Some(Entry {
-                used: _,
                 state: State::ReadWrite { .. },
-                file: _
+                ..
             })
         );
 
@@ -361,9 +698,8 @@ mod tests {
         assert_matches!(
             cache.files.get(&path),
             Some(Entry {
-                used: _,
                 state: State::ReadWrite { .. },
-                file: _
+                ..
             })
         );
     }
@@ -371,7 +707,7 @@ mod tests {
     #[test]
     fn test_read_file_range_with_existing_file() {
         let tmp_dir = tempfile::tempdir().unwrap();
-        let mut cache = FileCache::new(8);
+        let mut cache = FileCache::new(8, SyncPolicy::None, 0, 0);
 
         let path = tmp_dir.path().join("file");
         assert!(fs::write(&path, b"Hello world!").is_ok());
@@ -382,9 +718,8 @@ mod tests {
         assert_matches!(
             cache.files.get(&path),
             Some(Entry {
-                used: _,
                 state: State::ReadOnly,
-                file: _
+                ..
             })
         );
 
@@ -393,9 +728,8 @@ mod tests {
         assert_matches!(
             cache.files.get(&path),
             Some(Entry {
-                used: _,
                 state: State::ReadOnly,
-                file: _
+                ..
             })
         );
     }
@@ -403,7 +737,7 @@ mod tests {
     #[test]
     fn test_write_file_range_with_existing_file() {
         let tmp_dir = tempfile::tempdir().unwrap();
-        let mut cache = FileCache::new(8);
+        let mut cache = FileCache::new(8, SyncPolicy::None, 0, 0);
 
         let path = tmp_dir.path().join("file");
         assert_matches!(
@@ -413,9 +747,8 @@ mod tests {
         assert_matches!(
             cache.files.get(&path),
             Some(Entry {
-                used: _,
                 state: State::ReadWrite { .. },
-                file: _
+                ..
             })
         );
 
@@ -426,9 +759,8 @@ mod tests {
         assert_matches!(
             cache.files.get(&path),
             Some(Entry {
-                used: _,
                 state: State::ReadWrite { .. },
-                file: _
+                ..
             })
         );
 
@@ -438,7 +770,7 @@ mod tests {
     #[test]
     fn test_read_file_range_then_write_file_range_on_existing_file() {
         let tmp_dir = tempfile::tempdir().unwrap();
-        let mut cache = FileCache::new(8);
+        let mut cache = FileCache::new(8, SyncPolicy::None, 0, 0);
 
         let path = tmp_dir.path().join("file");
         assert!(fs::write(&path, b"Hel------ld!").is_ok());
@@ -449,9 +781,8 @@ mod tests {
         assert_matches!(
             cache.files.get(&path),
             Some(Entry {
-                used: _,
                 state: State::ReadOnly,
-                file: _
+                ..
             })
         );
 
@@ -463,9 +794,8 @@ mod tests {
         assert_matches!(
             cache.files.get(&path),
             Some(Entry {
-                used: _,
                 state: State::ReadWrite { .. },
-                file: _
+                ..
             })
         );
         assert_eq!(&fs::read(&path).unwrap(), b"Hello world!");
@@ -474,7 +804,7 @@ mod tests {
     #[test]
     fn test_write_file_range_then_read_file_range_on_existing_file() {
         let tmp_dir = tempfile::tempdir().unwrap();
-        let mut cache = FileCache::new(8);
+        let mut cache = FileCache::new(8, SyncPolicy::None, 0, 0);
 
         let path = tmp_dir.path().join("file");
         assert!(fs::write(&path, b"Hel------ld!").is_ok());
@@ -485,9 +815,8 @@ mod tests {
         assert_matches!(
             cache.files.get(&path),
             Some(Entry {
-                used: _,
                 state: State::ReadWrite { .. },
-                file: _
+                ..
             })
         );
 
@@ -498,10 +827,293 @@ mod tests {
         assert_matches!(
             cache.files.get(&path),
             Some(Entry {
-                used: _,
                 state: State::ReadWrite { .. },
-                file: _
+                ..
             })
         );
     }
+
+    #[test]
+    fn test_stats() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut cache = FileCache::new(8, SyncPolicy::None, 0, 0);
+        let a = tmp_dir.path().join("a");
+        let b = tmp_dir.path().join("b");
+        assert!(fs::write(&b, b"Hello world!").is_ok());
+
+        // First write opens and allocates the file, the second reuses the open handle. Whether
+        // fallocate succeeds depends on the filesystem the test runs on.
+        assert_matches!(
+            cache.write_file_range(&a, RequestedSize::WithFallocate(12), 0, b"Hello "),
+            Ok(())
+        );
+        assert_matches!(
+            cache.write_file_range(&a, RequestedSize::WithoutFallocate(12), 6, b"world!"),
+            Ok(())
+        );
+        let mut buffer = [0; 6];
+        assert_matches!(cache.read_file_range(&a, 0, &mut buffer), Ok(()));
+        assert_matches!(cache.read_file_range(&b, 6, &mut buffer), Ok(()));
+        assert_matches!(
+            cache.read_file_range(&tmp_dir.path().join("c"), 0, &mut buffer),
+            Err(_)
+        );
+        cache.flush_file(&a);
+        // Only writable files are synced.
+        assert!(cache.flush_all());
+
+        let stats = cache.stats();
+        assert_eq!(stats.writes, 2);
+        assert_eq!(stats.bytes_written, 12);
+        assert_eq!(stats.reads, 2);
+        assert_eq!(stats.bytes_read, 12);
+        assert_eq!(stats.cache_hits, 2);
+        assert_eq!(stats.cache_misses, 3);
+        assert_eq!(stats.fallocate_ok + stats.fallocate_failed, 1);
+        assert_eq!(stats.fsyncs, 2);
+    }
+
+    #[test]
+    fn test_lru_eviction() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut cache = FileCache::new(4, SyncPolicy::None, 0, 0);
+        let paths: Vec<_> = (0..6).map(|i| tmp_dir.path().join(i.to_string())).collect();
+        for path in &paths {
+            assert!(fs::write(path, b"data").is_ok());
+        }
+        let mut buffer = [0; 4];
+        let mut read = |cache: &mut FileCache, i: usize| {
+            assert_matches!(cache.read_file_range(&paths[i], 0, &mut buffer), Ok(()));
+        };
+
+        for i in 0..5 {
+            read(&mut cache, i);
+        }
+        assert_eq!(cache.files.len(), 4);
+        assert!(!cache.files.contains_key(&paths[0]));
+
+        // Opened again on its next use, evicting the next oldest
+        read(&mut cache, 0);
+        assert!(cache.files.contains_key(&paths[0]));
+        assert!(!cache.files.contains_key(&paths[1]));
+        assert_eq!(cache.stats().cache_misses, 6);
+
+        // Using a file keeps it open, even though it was opened first
+        read(&mut cache, 2);
+        read(&mut cache, 5);
+        assert!(cache.files.contains_key(&paths[2]));
+        assert!(!cache.files.contains_key(&paths[3]));
+        assert_eq!(cache.files.len(), 4);
+        assert_eq!(cache.stats().cache_hits, 1);
+    }
+
+    #[test]
+    fn test_read_ahead() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut cache = FileCache::new(8, SyncPolicy::None, 16, 0);
+        let path = tmp_dir.path().join("file");
+        let data: Vec<u8> = (0..40).collect();
+        assert!(fs::write(&path, &data).is_ok());
+
+        let mut buffer = [0; 4];
+        for offset in (0..40).step_by(4) {
+            assert_matches!(
+                cache.read_file_range_ahead(&path, offset, &mut buffer),
+                Ok(())
+            );
+            assert_eq!(buffer[..], data[offset as usize..offset as usize + 4]);
+        }
+        // Two full windows and a short one at the end of the file
+        assert_eq!(cache.stats().reads, 3);
+        assert_eq!(cache.stats().bytes_read, 40);
+
+        // Writes replace data read ahead
+        assert_matches!(cache.read_file_range_ahead(&path, 0, &mut buffer), Ok(()));
+        assert_matches!(
+            cache.write_file_range(&path, RequestedSize::Unsized, 6, b"ab"),
+            Ok(())
+        );
+        assert_matches!(cache.read_file_range_ahead(&path, 4, &mut buffer), Ok(()));
+        assert_eq!(&buffer, &[4, 5, b'a', b'b']);
+        assert_eq!(cache.stats().reads, 5);
+
+        assert_matches!(cache.read_file_range_ahead(&path, 38, &mut buffer), Err(_));
+        // Reads larger than the window aren't buffered
+        let mut buffer = [0; 32];
+        assert_matches!(cache.read_file_range_ahead(&path, 0, &mut buffer), Ok(()));
+        assert_matches!(cache.read_file_range_ahead(&path, 0, &mut buffer), Ok(()));
+        assert_eq!(cache.stats().reads, 8);
+    }
+
+    #[test]
+    fn test_piece_cache() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut cache = FileCache::new(8, SyncPolicy::None, 0, 32);
+        let path = tmp_dir.path().join("file");
+        let data: Vec<u8> = (0..16).collect();
+        assert!(fs::write(&path, &data).is_ok());
+        let key = |piece| PieceKey {
+            tid: 1,
+            piece,
+            offset: 0,
+        };
+
+        let mut buffer = [0; 4];
+        assert!(!cache.read_cached_piece(key(0), 4, &mut buffer, false));
+        let mut piece = vec![0; 16];
+        assert_matches!(cache.read_file_range(&path, 0, &mut piece), Ok(()));
+        cache.cache_piece(key(0), piece, false);
+
+        // Hits are served without going near the file
+        fs::remove_file(&path).unwrap();
+        cache.files.clear();
+        for begin in [0, 4, 12] {
+            assert!(cache.read_cached_piece(key(0), begin, &mut buffer, false));
+            assert_eq!(buffer[..], data[begin as usize..begin as usize + 4]);
+        }
+        assert_eq!(cache.stats().reads, 1);
+        assert_eq!(cache.stats().piece_cache_hits, 3);
+        assert_eq!(cache.stats().piece_cache_misses, 1);
+        // Blocks past the end of a window aren't hits
+        assert!(!cache.read_cached_piece(key(0), 14, &mut buffer, false));
+        // Nor are unverified pieces, for reads which need them verified
+        assert!(!cache.read_cached_piece(key(0), 0, &mut buffer, true));
+
+        // The least recently used piece makes way for new ones
+        cache.cache_piece(key(1), vec![1; 16], false);
+        assert!(cache.read_cached_piece(key(0), 0, &mut buffer, false));
+        cache.cache_piece(key(2), vec![2; 16], false);
+        assert!(cache.read_cached_piece(key(0), 0, &mut buffer, false));
+        assert!(!cache.read_cached_piece(key(1), 0, &mut buffer, false));
+        assert!(cache.read_cached_piece(key(2), 0, &mut buffer, false));
+        assert_eq!(cache.piece_bytes, 32);
+        // Windows larger than the whole budget are never cached
+        cache.cache_piece(key(3), vec![3; 33], false);
+        assert!(!cache.read_cached_piece(key(3), 0, &mut buffer, false));
+        assert_eq!(cache.pieces.len(), 2);
+
+        cache.invalidate_piece(1, 2);
+        assert!(!cache.read_cached_piece(key(2), 0, &mut buffer, false));
+        cache.invalidate_torrent(1);
+        assert!(cache.pieces.is_empty());
+        assert_eq!(cache.piece_bytes, 0);
+    }
+
+    #[test]
+    fn test_verified_pieces() {
+        // Remembered even with the piece cache off
+        let mut cache = FileCache::new(8, SyncPolicy::None, 0, 0);
+        cache.piece_verified(1, 0);
+        cache.piece_verified(1, 1);
+        cache.piece_verified(2, 0);
+        assert!(cache.is_verified(1, 0) && cache.is_verified(2, 0));
+        assert!(!cache.is_verified(1, 2));
+
+        // Until written to again
+        cache.invalidate_piece(1, 0);
+        assert!(!cache.is_verified(1, 0) && cache.is_verified(1, 1));
+        cache.invalidate_torrent(1);
+        assert!(!cache.is_verified(1, 1) && cache.is_verified(2, 0));
+
+        // Only so many are remembered, the oldest going first
+        for piece in 1..=VERIFIED_SLOTS as u32 {
+            cache.piece_verified(2, piece);
+        }
+        assert_eq!(cache.verified.len(), VERIFIED_SLOTS);
+        assert!(!cache.is_verified(2, 0) && cache.is_verified(2, 1));
+    }
+
+    /// Compares serving a 64 MiB file block by block, as a sequential torrent is seeded, with
+    /// and without read-ahead. Run with `cargo test bench_read_ahead -- --ignored --nocapture`.
+    #[ignore]
+    #[test]
+    fn bench_read_ahead() {
+        const BLOCK: usize = 16_384;
+        const LEN: usize = 64 * 1024 * 1024;
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("file");
+        assert!(fs::write(&path, vec![1u8; LEN]).is_ok());
+
+        for window in [0, 256 * 1024, 1024 * 1024] {
+            let mut cache = FileCache::new(8, SyncPolicy::None, window, 0);
+            let mut buffer = [0; BLOCK];
+            let start = Instant::now();
+            for offset in (0..LEN).step_by(BLOCK) {
+                assert_matches!(
+                    cache.read_file_range_ahead(&path, offset as u64, &mut buffer),
+                    Ok(())
+                );
+            }
+            println!(
+                "Read-ahead {} KiB: {} reads, {:?}",
+                window / 1024,
+                cache.stats().reads,
+                start.elapsed()
+            );
+        }
+    }
+
+    /// Writes `writes` 4 byte blocks to a file, returning the cache and its directory.
+    fn write_blocks(sync: SyncPolicy, writes: u64) -> (FileCache, tempfile::TempDir) {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut cache = FileCache::new(8, sync, 0, 0);
+        let path = tmp_dir.path().join("file");
+        for i in 0..writes {
+            assert_matches!(
+                cache.write_file_range(&path, RequestedSize::Unsized, i * 4, b"data"),
+                Ok(())
+            );
+        }
+        (cache, tmp_dir)
+    }
+
+    #[test]
+    fn test_sync_policy() {
+        let (mut cache, dir) = write_blocks(SyncPolicy::None, 8);
+        let path = dir.path().join("file");
+        cache.piece_complete(&path);
+        cache.tick(Instant::now() + Duration::from_secs(3600));
+        assert_eq!(cache.stats().fsyncs, 0);
+
+        let (mut cache, dir) = write_blocks(SyncPolicy::OnPieceComplete, 8);
+        let path = dir.path().join("file");
+        assert_eq!(cache.stats().fsyncs, 0);
+        cache.piece_complete(&path);
+        assert_eq!(cache.stats().fsyncs, 1);
+        // Nothing new to sync
+        cache.piece_complete(&path);
+        cache.piece_complete(&dir.path().join("other"));
+        assert_eq!(cache.stats().fsyncs, 1);
+
+        let (mut cache, dir) = write_blocks(SyncPolicy::EveryBytes(10), 8);
+        assert_eq!(cache.stats().fsyncs, 2);
+        cache.piece_complete(&dir.path().join("file"));
+        assert_eq!(cache.stats().fsyncs, 2);
+    }
+
+    #[test]
+    fn test_sync_interval() {
+        let start = Instant::now();
+        let (mut cache, dir) = write_blocks(SyncPolicy::Interval(30), 2);
+        cache.tick(start + Duration::from_secs(10));
+        assert_eq!(cache.stats().fsyncs, 0);
+        cache.tick(start + Duration::from_secs(31));
+        assert_eq!(cache.stats().fsyncs, 1);
+        // Clean files aren't synced again
+        cache.tick(start + Duration::from_secs(120));
+        assert_eq!(cache.stats().fsyncs, 1);
+
+        // The interval counts from the oldest unsynced write
+        let path = dir.path().join("file");
+        let written = Instant::now();
+        assert_matches!(
+            cache.write_file_range(&path, RequestedSize::Unsized, 0, b"more"),
+            Ok(())
+        );
+        cache.tick(written + Duration::from_secs(29));
+        assert_eq!(cache.stats().fsyncs, 1);
+        cache.tick(written + Duration::from_secs(60));
+        assert_eq!(cache.stats().fsyncs, 2);
+        assert!(cache.files[&path].dirty_since.is_none());
+    }
 }
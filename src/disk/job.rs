@@ -3,19 +3,22 @@ use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::{cmp, fmt, fs, path, time};
 
-use http_range::HttpRange;
-use sha1::{Digest, Sha1};
+use http_range::{HttpRange, HttpRangeParseError};
 use sstream::SStream;
 
-use super::cache::RequestedSize;
-use super::{BufCache, FileCache, JOB_TIME_SLICE};
-use crate::buffers::Buffer;
-use crate::config::DiskConfig;
-use crate::torrent::{Info, LocIter};
-use crate::util::{hash_to_id, io_err};
+use super::cache::{PieceKey, RequestedSize, TempPB};
+use super::{BufCache, FileCache, JOB_TIME_SLICE, analyze, limits, relocate};
+use crate::buffers::{BUF_SIZE, Buffer};
+use crate::config::{Allocation, DiskConfig};
+use crate::rpc::proto::message::{DiskCounters, PathAnalysis};
+use crate::rpc::resource::MoveStatus;
+use crate::torrent::{Bitfield, Info, LocIter};
+use crate::util::{hash_to_id, native};
 
 static MP_BOUNDARY: &str = "qxyllcqgNchqyob";
-const EXDEV: i32 = 18;
+pub(super) const EXDEV: i32 = 18;
+/// Extension of the session file holding data for skipped files
+const PARTS_EXT: &str = "parts";
 
 pub struct Location {
     /// Info file index
@@ -27,8 +30,8 @@ pub struct Location {
     pub start: usize,
     /// end in the piece
     pub end: usize,
-    /// This file should be fully allocated if possible
-    pub allocate: bool,
+    /// The file has a non-zero priority
+    pub wanted: bool,
     info: Arc<Info>,
 }
 
@@ -38,12 +41,18 @@ pub enum Request {
         locations: LocIter,
         context: Ctx,
         path: Option<String>,
+        /// The torrent's allocation policy, which decides how the files are created
+        allocation: Allocation,
     },
     Read {
         data: Buffer,
         locations: LocIter,
         context: Ctx,
         path: Option<String>,
+        /// Read past the block, expecting the following ones to be requested next
+        read_ahead: bool,
+        /// Check the whole piece against its hash before serving the block
+        verify: bool,
     },
     Serialize {
         tid: usize,
@@ -64,9 +73,13 @@ pub enum Request {
     },
     Move {
         tid: usize,
+        info: Arc<Info>,
         from: String,
         to: String,
-        target: String,
+        target: PathBuf,
+        /// Next file to move when falling back to moving files one at a time
+        idx: usize,
+        moved: Vec<(usize, MoveStatus)>,
     },
     Validate {
         tid: usize,
@@ -81,6 +94,23 @@ pub enum Request {
         path: Option<String>,
         piece: u32,
     },
+    /// Reads back the blocks of a piece which were received before a restart,
+    /// to find those which actually made it to disk.
+    CheckBlocks {
+        tid: usize,
+        info: Arc<Info>,
+        path: Option<String>,
+        piece: u32,
+        blocks: Bitfield,
+    },
+    /// Creates a previously skipped file, moving over any of its data
+    /// held in the parts file.
+    Materialize {
+        tid: usize,
+        info: Arc<Info>,
+        path: Option<String>,
+        file: usize,
+    },
     WriteFile {
         data: Vec<u8>,
         path: PathBuf,
@@ -95,19 +125,82 @@ pub enum Request {
         buf_idx: usize,
     },
     FreeSpace,
+    Stats,
+    AnalyzePath {
+        client: usize,
+        serial: u64,
+        path: String,
+        /// Download directories the path must lie within, relative paths start at the first
+        roots: Vec<String>,
+    },
     Ping,
     Shutdown,
 }
 
 pub enum Response {
-    Read { context: Ctx, data: Buffer },
-    Write { context: Ctx },
-    ValidationComplete { tid: usize, invalid: Vec<u32> },
-    PieceValidated { tid: usize, piece: u32, valid: bool },
-    ValidationUpdate { tid: usize, percent: f32 },
-    Moved { tid: usize, path: String },
+    Read {
+        context: Ctx,
+        data: Buffer,
+    },
+    Write {
+        context: Ctx,
+    },
+    ValidationComplete {
+        tid: usize,
+        invalid: Vec<u32>,
+    },
+    PieceValidated {
+        tid: usize,
+        piece: u32,
+        valid: bool,
+    },
+    /// The blocks of a `CheckBlocks` request which hold data
+    BlocksChecked {
+        tid: usize,
+        piece: u32,
+        blocks: Bitfield,
+    },
+    ValidationUpdate {
+        tid: usize,
+        percent: f32,
+    },
+    MoveUpdate {
+        tid: usize,
+        percent: f32,
+    },
+    Moved {
+        tid: usize,
+        path: String,
+        /// What became of each file which is now at the destination
+        files: Vec<(usize, MoveStatus)>,
+    },
     FreeSpace(u64),
-    Error { tid: usize, err: io::Error },
+    Stats(DiskCounters),
+    /// RPC client, message serial, and result of an `AnalyzePath` request
+    PathAnalysis(usize, u64, io::Result<PathAnalysis>),
+    Error {
+        tid: usize,
+        job: JobKind,
+        err: io::Error,
+    },
+    /// A read failed because a file was deleted or truncated
+    Missing {
+        tid: usize,
+        err: io::Error,
+    },
+    /// The piece of a verified read no longer matches its hash, so the block wasn't read
+    Corrupt {
+        context: Ctx,
+    },
+}
+
+/// The kind of job a request is, telling a torrent what failed on an error
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum JobKind {
+    Read,
+    Write,
+    Move,
+    Other,
 }
 
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
@@ -127,21 +220,37 @@ pub enum JobRes {
 }
 
 impl Request {
-    pub fn write(context: Ctx, data: Buffer, locations: LocIter, path: Option<String>) -> Request {
+    pub fn write(
+        context: Ctx,
+        data: Buffer,
+        locations: LocIter,
+        path: Option<String>,
+        allocation: Allocation,
+    ) -> Request {
         Request::Write {
             context,
             data,
             locations,
             path,
+            allocation,
         }
     }
 
-    pub fn read(context: Ctx, data: Buffer, locations: LocIter, path: Option<String>) -> Request {
+    pub fn read(
+        context: Ctx,
+        data: Buffer,
+        locations: LocIter,
+        path: Option<String>,
+        read_ahead: bool,
+        verify: bool,
+    ) -> Request {
         Request::Read {
             context,
             data,
             locations,
             path,
+            read_ahead,
+            verify,
         }
     }
 
@@ -170,6 +279,24 @@ impl Request {
         }
     }
 
+    pub fn move_torrent(
+        tid: usize,
+        info: Arc<Info>,
+        from: String,
+        to: String,
+        target: PathBuf,
+    ) -> Request {
+        Request::Move {
+            tid,
+            info,
+            from,
+            to,
+            target,
+            idx: 0,
+            moved: Vec::new(),
+        }
+    }
+
     pub fn validate_piece(
         tid: usize,
         info: Arc<Info>,
@@ -184,6 +311,31 @@ impl Request {
         }
     }
 
+    pub fn check_blocks(
+        tid: usize,
+        info: Arc<Info>,
+        path: Option<String>,
+        piece: u32,
+        blocks: Bitfield,
+    ) -> Request {
+        Request::CheckBlocks {
+            tid,
+            info,
+            path,
+            piece,
+            blocks,
+        }
+    }
+
+    pub fn materialize(tid: usize, info: Arc<Info>, path: Option<String>, file: usize) -> Request {
+        Request::Materialize {
+            tid,
+            info,
+            path,
+            file,
+        }
+    }
+
     pub fn delete(
         tid: usize,
         hash: [u8; 20],
@@ -200,12 +352,36 @@ impl Request {
         }
     }
 
+    /// Serves a file over HTTP, honouring the byte ranges in the request's `Range` header.
     pub fn download(
         client: SStream,
-        mut ranges: Vec<HttpRange>,
+        range: Option<&str>,
         file_path: String,
         file_len: u64,
     ) -> Request {
+        let mut ranges = match range.map(|r| HttpRange::parse(r, file_len)) {
+            Some(Ok(ranges)) => ranges,
+            // RFC 7233 lets a malformed range be ignored, serving the whole file
+            Some(Err(HttpRangeParseError::InvalidRange)) | None => Vec::new(),
+            Some(Err(HttpRangeParseError::NoOverlap)) => {
+                let http_lines = [
+                    "HTTP/1.1 416 Range Not Satisfiable".to_owned(),
+                    format!("Content-Range: bytes */{file_len}"),
+                    "Content-Length: 0".to_owned(),
+                    "Connection: Close".to_owned(),
+                    "\r\n".to_owned(),
+                ];
+                return Request::Download {
+                    client: Box::new(client),
+                    ranges: Vec::new(),
+                    multipart: false,
+                    file_len,
+                    file_path,
+                    buf: http_lines.join("\r\n").into_bytes(),
+                    buf_idx: 0,
+                };
+            }
+        };
         let http_lines = match ranges.len() {
             0 => vec![
                 format!("HTTP/1.1 200 OK"),
@@ -289,7 +465,22 @@ impl Request {
     }
 
     pub fn concurrent(&self) -> bool {
-        !matches!(self, Request::Validate { .. })
+        !matches!(self, Request::Validate { .. } | Request::Move { .. })
+    }
+
+    /// Whether the job modifies on disk state and so should still be
+    /// completed when shutting down.
+    pub fn durable(&self) -> bool {
+        matches!(
+            self,
+            Request::Write { .. }
+                | Request::Serialize { .. }
+                | Request::WriteFile { .. }
+                | Request::PurgeCache { .. }
+                | Request::Delete { .. }
+                | Request::Move { .. }
+                | Request::Materialize { .. }
+        )
     }
 
     pub fn execute(
@@ -302,11 +493,25 @@ impl Request {
         let dd = &config.directory;
         let (mut tb, mut tpb, mut tpb2) = bc.data();
         match self {
-            Request::Ping => {}
+            Request::Ping => fc.tick(time::Instant::now()),
             Request::FreeSpace => {
                 let free_space = fs2::available_space(dd.as_str())?;
                 return Ok(JobRes::Resp(Response::FreeSpace(free_space)));
             }
+            Request::Stats => {
+                return Ok(JobRes::Resp(Response::Stats(fc.stats())));
+            }
+            Request::AnalyzePath {
+                client,
+                serial,
+                path,
+                roots,
+            } => {
+                let analysis = analyze::analyze(&roots, &path, serial);
+                return Ok(JobRes::Resp(Response::PathAnalysis(
+                    client, serial, analysis,
+                )));
+            }
             Request::WriteFile { path, data } => {
                 let p = tpb.get(path.iter());
                 p.set_extension("temp");
@@ -334,23 +539,29 @@ impl Request {
                 data,
                 locations,
                 path,
+                allocation,
             } => {
+                fc.invalidate_piece(context.tid, context.idx);
                 for loc in locations {
                     let pb = tpb.get(path.as_ref().unwrap_or(dd));
                     pb.push(loc.path());
+                    if !loc.wanted && allocation == Allocation::SkipUnwanted && !fc.exists(pb) {
+                        let parts = parts_path(&mut tpb2, config, &loc.info.hash);
+                        fc.write_file_range(
+                            parts,
+                            RequestedSize::Unsized,
+                            loc.torrent_offset(),
+                            &data[loc.start..loc.end],
+                        )?;
+                        continue;
+                    }
                     fc.write_file_range(
                         pb,
-                        if loc.allocate {
-                            RequestedSize::WithFallocate(loc.file_len)
-                        } else {
-                            RequestedSize::WithoutFallocate(loc.file_len)
-                        },
+                        requested_size(allocation, loc.wanted, loc.file_len),
                         loc.offset,
                         &data[loc.start..loc.end],
-                    )?;
-                    if loc.end - loc.start != 16_384 {
-                        fc.flush_file(pb);
-                    }
+                    )
+                    .map_err(|e| limits::classify(e, pb, native::fs_info))?;
                 }
                 return Ok(JobRes::Resp(Response::write(context)));
             }
@@ -359,71 +570,127 @@ impl Request {
                 mut data,
                 locations,
                 path,
-                ..
+                read_ahead,
+                verify,
             } => {
+                if verify {
+                    let base = path.as_deref().unwrap_or(dd);
+                    let block = &mut data[..context.length as usize];
+                    if !read_verified(config, fc, base, &mut tpb2, &context, &locations, block)? {
+                        return Ok(JobRes::Resp(Response::Corrupt { context }));
+                    }
+                    return Ok(JobRes::Resp(Response::read(context, data)));
+                }
+                // Sequential torrents are already read ahead through their files
+                if config.piece_cache != 0 && !read_ahead {
+                    let base = path.as_deref().unwrap_or(dd);
+                    let block = &mut data[..context.length as usize];
+                    if read_piece_cache(config, fc, base, &mut tpb2, &context, &locations, block) {
+                        return Ok(JobRes::Resp(Response::read(context, data)));
+                    }
+                }
                 for loc in locations {
                     let pb = tpb.get(path.as_ref().unwrap_or(dd));
                     pb.push(loc.path());
-                    fc.read_file_range(pb, loc.offset, &mut data[loc.start..loc.end])?;
+                    read_loc(
+                        config,
+                        fc,
+                        pb,
+                        &mut tpb2,
+                        &loc,
+                        &mut data[loc.start..loc.end],
+                        read_ahead,
+                    )?;
                 }
                 return Ok(JobRes::Resp(Response::read(context, data)));
             }
             Request::Move {
                 tid,
+                info,
                 from,
                 to,
                 target,
+                mut idx,
+                mut moved,
             } => {
                 let fp = tpb.get(&from);
                 let tp = tpb2.get(&to);
-                fp.push(target.clone());
-                tp.push(target);
-                error!("Attempting to move {fp:?} to {tp:?}");
-                match fs::rename(&fp, &tp) {
-                    Ok(_) => {}
-                    // Cross filesystem move, try to copy then delete
-                    Err(ref e) if e.raw_os_error() == Some(EXDEV) => {
-                        if fp.is_dir() {
-                            // `fs_extra::dir::copy()` behaves like `cp -r`, so the destination
-                            // path should not include `target`.
-                            let tp = tp.parent().unwrap();
-                            match fs_extra::dir::copy(&fp, tp, &fs_extra::dir::CopyOptions::new()) {
-                                Ok(_) => {
-                                    fs::remove_dir_all(&fp)?;
-                                }
-                                Err(e) => {
-                                    // There are a number of reasons this might fail, e.g. the
-                                    // destination might already exist. Do not try to clean up
-                                    // any state in `tp` and let the user sort it out.
-                                    error!("Dir copy failed: {:?}", e);
-                                    return io_err("Failed to copy directory across filesystems!");
-                                }
-                            }
-                        } else {
-                            match fs_extra::file::copy(
-                                &fp,
-                                &tp,
-                                &fs_extra::file::CopyOptions::new(),
-                            ) {
-                                Ok(_) => {
-                                    fs::remove_file(&fp)?;
-                                }
-                                Err(e) => {
-                                    // There are a number of reasons this might fail, e.g. the
-                                    // destination might already exist. Do not try to clean up
-                                    // any state in `tp` and let the user sort it out.
-                                    error!("File copy failed: {:?}", e);
-                                    return io_err("Failed to copy file across filesystems!");
-                                }
-                            }
+                fp.push(&target);
+                tp.push(&target);
+                // Some of the files may already be at the destination, e.g. copied there by
+                // hand, so they're moved one by one
+                if idx == 0 && !tp.exists() {
+                    debug!("Moving {fp:?} to {tp:?}");
+                    match fs::rename(&fp, &tp) {
+                        Ok(()) => {
+                            let files = (0..info.files.len())
+                                .filter(|&i| Path::new(&to).join(&info.files[i].path).exists())
+                                .map(|i| (i, MoveStatus::Copied))
+                                .collect();
+                            return Ok(JobRes::Resp(Response::moved(tid, to, files)));
+                        }
+                        // Cross filesystem move, each file is copied then deleted
+                        Err(ref e) if e.raw_os_error() == Some(EXDEV) => {}
+                        Err(e) => {
+                            error!("FS rename failed: {:?}", e);
+                            return Err(e);
                         }
                     }
-                    Err(e) => {
-                        error!("FS rename failed: {:?}", e);
-                        return Err(e);
+                }
+                let start = time::Instant::now();
+                while idx < info.files.len()
+                    && start.elapsed() < time::Duration::from_millis(JOB_TIME_SLICE)
+                {
+                    let status = relocate::move_file(
+                        &info,
+                        idx,
+                        Path::new(&from),
+                        Path::new(&to),
+                        |a, b| fs::rename(a, b),
+                    );
+                    moved.extend(status.map(|s| (idx, s)));
+                    idx += 1;
+                }
+                if idx < info.files.len() {
+                    let percent = info.file_offset(idx) as f32 / info.total_len as f32;
+                    return Ok(JobRes::Update(
+                        Request::Move {
+                            tid,
+                            info,
+                            from,
+                            to,
+                            target,
+                            idx,
+                            moved,
+                        },
+                        Response::MoveUpdate { tid, percent },
+                    ));
+                }
+                let failed = moved
+                    .iter()
+                    .filter(|(_, s)| *s == MoveStatus::Failed)
+                    .count();
+                if failed != 0 {
+                    // The torrent stays at the old path, so everything that did move goes back
+                    for &(i, status) in &moved {
+                        let res = relocate::restore(
+                            &info,
+                            i,
+                            status,
+                            Path::new(&from),
+                            Path::new(&to),
+                            |a, b| fs::rename(a, b),
+                        );
+                        if let Err(e) = res {
+                            error!("Failed to move {:?} back: {}", info.files[i].path, e);
+                        }
                     }
+                    return Err(io::Error::other(format!(
+                        "failed to move {failed} files to {to}"
+                    )));
                 }
-                return Ok(JobRes::Resp(Response::moved(tid, to)));
+                relocate::remove_empty_dirs(fp);
+                return Ok(JobRes::Resp(Response::moved(tid, to, moved)));
             }
             Request::Serialize {
                 data,
@@ -444,22 +711,28 @@ impl Request {
                 actual.push(hash_to_id(&hash) + extension);
                 fs::rename(temp, actual)?;
             }
-            Request::PurgeCache { prefix, .. } => {
+            Request::PurgeCache { tid, prefix } => {
+                fc.invalidate_torrent(tid);
                 fc.retain(|path| !path.starts_with(&prefix));
             }
             Request::Delete {
+                tid,
                 hash,
                 files,
                 path,
                 artifacts,
-                ..
             } => {
+                fc.invalidate_torrent(tid);
                 {
                     let spb = tpb.get(sd);
                     spb.push(hash_to_id(&hash));
                     fs::remove_file(&spb).ok();
                     spb.set_extension("info");
                     fs::remove_file(&spb).ok();
+                    spb.set_extension("metadata");
+                    fs::remove_file(&spb).ok();
+                    spb.set_extension(PARTS_EXT);
+                    fs::remove_file(&spb).ok();
                 }
 
                 for file in &files {
@@ -486,21 +759,95 @@ impl Request {
                 piece,
             } => {
                 let buf = tb.get(info.piece_len as usize);
-                let mut ctx = Sha1::new();
+                let mut read = true;
                 let locs = Info::piece_disk_locs(&info, piece);
                 for loc in locs {
                     let pb = tpb.get(path.as_ref().unwrap_or(dd));
                     pb.push(loc.path());
-                    fc.read_file_range(pb, loc.offset, &mut buf[loc.start..loc.end])
-                        .map(|_| ctx.update(&buf[loc.start..loc.end]))
-                        .ok();
+                    read &= read_loc(
+                        config,
+                        fc,
+                        pb,
+                        &mut tpb2,
+                        &loc,
+                        &mut buf[loc.start..loc.end],
+                        false,
+                    )
+                    .is_ok();
+                }
+                let len = info.piece_len(piece) as usize;
+                let valid = read && info.piece_valid(piece, &buf[..len]);
+                if valid {
+                    for loc in Info::piece_disk_locs(&info, piece) {
+                        let pb = tpb.get(path.as_ref().unwrap_or(dd));
+                        pb.push(loc.path());
+                        fc.piece_complete(pb);
+                    }
+                }
+                return Ok(JobRes::Resp(Response::PieceValidated { tid, piece, valid }));
+            }
+            Request::CheckBlocks {
+                tid,
+                info,
+                path,
+                piece,
+                mut blocks,
+            } => {
+                let buf = tb.get(info.piece_len as usize);
+                for loc in Info::piece_disk_locs(&info, piece) {
+                    let pb = tpb.get(path.as_ref().unwrap_or(dd));
+                    pb.push(loc.path());
+                    let data = &mut buf[loc.start..loc.end];
+                    if read_loc(config, fc, pb, &mut tpb2, &loc, data, false).is_err() {
+                        data.fill(0);
+                    }
+                }
+                let len = info.piece_len(piece) as usize;
+                let received: Vec<_> = blocks.iter().collect();
+                for block in received {
+                    let start = block as usize * 16_384;
+                    let data = &buf[start.min(len)..(start + 16_384).min(len)];
+                    // Space which was allocated but never written reads back as zeroes
+                    if data.iter().all(|&b| b == 0) {
+                        blocks.unset_bit(block);
+                    }
+                }
+                return Ok(JobRes::Resp(Response::BlocksChecked { tid, piece, blocks }));
+            }
+            Request::Materialize {
+                info, path, file, ..
+            } => {
+                let pb = tpb.get(path.as_ref().unwrap_or(dd));
+                pb.push(&info.files[file].path);
+                let parts = parts_path(&mut tpb2, config, &info.hash);
+                if fc.exists(pb) || !fc.exists(parts) {
+                    return Ok(JobRes::Done);
+                }
+                let parts_len = fs::metadata(parts)?.len();
+                let len = info.files[file].length;
+                let start = info.file_offset(file);
+                let end = start + len;
+                let piece_len = u64::from(info.piece_len);
+                // Only the first and last pieces of the file can be shared with
+                // wanted files, so nothing else could have been downloaded.
+                let head = start..cmp::min(end, (start / piece_len + 1) * piece_len);
+                let tail = cmp::max(head.end, end.saturating_sub(1) / piece_len * piece_len)..end;
+                let buf = tb.get(info.piece_len as usize);
+                for r in [head, tail] {
+                    let r = r.start..cmp::min(r.end, parts_len);
+                    if r.is_empty() {
+                        continue;
+                    }
+                    let data = &mut buf[..(r.end - r.start) as usize];
+                    fc.read_file_range(parts, r.start, data)?;
+                    fc.write_file_range(
+                        pb,
+                        RequestedSize::WithFallocate(len),
+                        r.start - start,
+                        data,
+                    )
+                    .map_err(|e| limits::classify(e, pb, native::fs_info))?;
                 }
-                let digest = ctx.finalize();
-                return Ok(JobRes::Resp(Response::PieceValidated {
-                    tid,
-                    piece,
-                    valid: digest[..] == info.hashes[piece as usize][..],
-                }));
             }
             Request::Validate {
                 tid,
@@ -509,6 +856,10 @@ impl Request {
                 mut idx,
                 mut invalid,
             } => {
+                // The files may have been changed from under us
+                if idx == 0 {
+                    fc.invalidate_torrent(tid);
+                }
                 let buf = tb.get(info.piece_len as usize);
                 let start = time::Instant::now();
 
@@ -516,7 +867,6 @@ impl Request {
                     && start.elapsed() < time::Duration::from_millis(JOB_TIME_SLICE)
                 {
                     let mut valid = true;
-                    let mut ctx = Sha1::new();
                     let locs = Info::piece_disk_locs(&info, idx);
                     for loc in locs {
                         if !valid {
@@ -524,13 +874,19 @@ impl Request {
                         }
                         let pb = tpb.get(path.as_ref().unwrap_or(dd));
                         pb.push(loc.path());
-                        valid &= fc
-                            .read_file_range(pb, loc.offset, &mut buf[loc.start..loc.end])
-                            .map(|_| ctx.update(&buf[loc.start..loc.end]))
-                            .is_ok();
+                        valid &= read_loc(
+                            config,
+                            fc,
+                            pb,
+                            &mut tpb2,
+                            &loc,
+                            &mut buf[loc.start..loc.end],
+                            false,
+                        )
+                        .is_ok();
                     }
-                    let digest = ctx.finalize();
-                    if !valid || digest[..] != info.hashes[idx as usize][..] {
+                    let len = info.piece_len(idx) as usize;
+                    if !valid || !info.piece_valid(idx, &buf[..len]) {
                         invalid.push(idx);
                     }
 
@@ -648,12 +1004,23 @@ impl Request {
         }
     }
 
+    pub fn kind(&self) -> JobKind {
+        match self {
+            Request::Read { .. } => JobKind::Read,
+            Request::Write { .. } => JobKind::Write,
+            Request::Move { .. } => JobKind::Move,
+            _ => JobKind::Other,
+        }
+    }
+
     pub fn tid(&self) -> Option<usize> {
         match self {
             Request::Read { context, .. } | Request::Write { context, .. } => Some(context.tid),
             Request::Serialize { tid, .. }
             | Request::Validate { tid, .. }
             | Request::ValidatePiece { tid, .. }
+            | Request::CheckBlocks { tid, .. }
+            | Request::Materialize { tid, .. }
             | Request::PurgeCache { tid, .. }
             | Request::Delete { tid, .. }
             | Request::Move { tid, .. } => Some(*tid),
@@ -661,7 +1028,9 @@ impl Request {
             | Request::Download { .. }
             | Request::Shutdown
             | Request::Ping
-            | Request::FreeSpace => None,
+            | Request::AnalyzePath { .. }
+            | Request::FreeSpace
+            | Request::Stats => None,
         }
     }
 }
@@ -680,7 +1049,7 @@ impl Location {
         start: u64,
         end: u64,
         info: Arc<Info>,
-        allocate: bool,
+        wanted: bool,
     ) -> Location {
         Location {
             file,
@@ -689,13 +1058,176 @@ impl Location {
             start: start as usize,
             end: end as usize,
             info,
-            allocate,
+            wanted,
         }
     }
 
     pub fn path(&self) -> &Path {
         &self.info.files[self.file].path
     }
+
+    /// Offset of this location relative to the start of the torrent.
+    pub fn torrent_offset(&self) -> u64 {
+        self.info.file_offset(self.file) + self.offset
+    }
+}
+
+fn requested_size(allocation: Allocation, wanted: bool, len: u64) -> RequestedSize {
+    if wanted || allocation == Allocation::Full {
+        RequestedSize::WithFallocate(len)
+    } else {
+        RequestedSize::WithoutFallocate(len)
+    }
+}
+
+fn parts_path<'a>(pb: &'a mut TempPB<'_>, config: &DiskConfig, hash: &[u8; 20]) -> &'a PathBuf {
+    let p = pb.get(&config.session);
+    p.push(hash_to_id(hash));
+    p.set_extension(PARTS_EXT);
+    p
+}
+
+/// Serves a block from the piece cache, reading the window of the piece holding it into the
+/// cache on a miss. Returns false if the window couldn't be read, leaving the block to be
+/// read by itself.
+fn read_piece_cache(
+    config: &DiskConfig,
+    fc: &mut FileCache,
+    base: &str,
+    parts: &mut TempPB<'_>,
+    ctx: &Ctx,
+    locations: &LocIter,
+    block: &mut [u8],
+) -> bool {
+    let info = locations.info();
+    let piece_len = info.piece_len(ctx.idx);
+    let window = match config.piece_cache_window {
+        0 => piece_len,
+        w => cmp::min(w.div_ceil(BUF_SIZE) * BUF_SIZE, piece_len as usize) as u32,
+    };
+    if window == 0 {
+        return false;
+    }
+    let key = PieceKey {
+        tid: ctx.tid,
+        piece: ctx.idx,
+        offset: ctx.begin / window * window,
+    };
+    let len = cmp::min(window, piece_len.saturating_sub(key.offset));
+    // Requests needn't be block aligned, so a block may not fit in a single window
+    if u64::from(ctx.begin) + block.len() as u64 > u64::from(key.offset + len)
+        || len as usize > config.piece_cache
+    {
+        return false;
+    }
+    if fc.read_cached_piece(key, ctx.begin, block, false) {
+        return true;
+    }
+    let mut data = vec![0; len as usize];
+    for loc in LocIter::new(info.clone(), None, ctx.idx, key.offset, len) {
+        let file = Path::new(base).join(loc.path());
+        if let Err(e) = read_loc(
+            config,
+            fc,
+            &file,
+            parts,
+            &loc,
+            &mut data[loc.start..loc.end],
+            false,
+        ) {
+            debug!("Failed to read piece {} into the cache: {}", ctx.idx, e);
+            return false;
+        }
+    }
+    let start = (ctx.begin - key.offset) as usize;
+    block.copy_from_slice(&data[start..start + block.len()]);
+    fc.cache_piece(key, data, false);
+    true
+}
+
+/// Reads a block out of its piece once the whole piece is found to match its hash,
+/// returning false if it doesn't. The verified piece is remembered and cached if it fits,
+/// so its other blocks are served without hashing it again.
+fn read_verified(
+    config: &DiskConfig,
+    fc: &mut FileCache,
+    base: &str,
+    parts: &mut TempPB<'_>,
+    ctx: &Ctx,
+    locations: &LocIter,
+    block: &mut [u8],
+) -> io::Result<bool> {
+    let info = locations.info();
+    let key = PieceKey {
+        tid: ctx.tid,
+        piece: ctx.idx,
+        offset: 0,
+    };
+    if fc.read_cached_piece(key, ctx.begin, block, true) {
+        return Ok(true);
+    }
+    if fc.is_verified(ctx.tid, ctx.idx) {
+        for loc in LocIter::new(info.clone(), None, ctx.idx, ctx.begin, block.len() as u32) {
+            let file = Path::new(base).join(loc.path());
+            read_loc(
+                config,
+                fc,
+                &file,
+                parts,
+                &loc,
+                &mut block[loc.start..loc.end],
+                false,
+            )?;
+        }
+        return Ok(true);
+    }
+    let len = info.piece_len(ctx.idx);
+    let mut data = vec![0; len as usize];
+    for loc in LocIter::new(info.clone(), None, ctx.idx, 0, len) {
+        let file = Path::new(base).join(loc.path());
+        read_loc(
+            config,
+            fc,
+            &file,
+            parts,
+            &loc,
+            &mut data[loc.start..loc.end],
+            false,
+        )?;
+    }
+    if !info.piece_valid(ctx.idx, &data) {
+        return Ok(false);
+    }
+    let start = ctx.begin as usize;
+    block.copy_from_slice(&data[start..start + block.len()]);
+    fc.piece_verified(ctx.tid, ctx.idx);
+    fc.cache_piece(key, data, true);
+    Ok(true)
+}
+
+/// Reads the data at `loc` from `file`, or the parts file if `file` was skipped. Whether it
+/// was is up to the torrent's allocation policy when it was written, so a missing file is
+/// read from the parts file whenever there is one.
+fn read_loc(
+    config: &DiskConfig,
+    fc: &mut FileCache,
+    file: &Path,
+    parts: &mut TempPB<'_>,
+    loc: &Location,
+    buf: &mut [u8],
+    read_ahead: bool,
+) -> io::Result<()> {
+    if !fc.exists(file) {
+        let p = parts_path(parts, config, &loc.info.hash);
+        if fc.exists(p) {
+            return fc.read_file_range(p, loc.torrent_offset(), buf);
+        }
+    }
+    if read_ahead {
+        fc.read_file_range_ahead(file, loc.offset, buf)
+    } else {
+        fc.read_file_range(file, loc.offset, buf)
+    }
 }
 
 impl fmt::Debug for Location {
@@ -717,12 +1249,23 @@ impl Response {
         Response::Write { context }
     }
 
-    pub fn error(tid: usize, err: io::Error) -> Response {
-        Response::Error { tid, err }
+    pub fn error(tid: usize, job: JobKind, err: io::Error) -> Response {
+        Response::Error { tid, job, err }
     }
 
-    pub fn moved(tid: usize, path: String) -> Response {
-        Response::Moved { tid, path }
+    /// Builds the response to a failed read, telling files which have gone missing apart
+    /// from other errors.
+    pub fn read_error(tid: usize, err: io::Error) -> Response {
+        match err.kind() {
+            io::ErrorKind::NotFound | io::ErrorKind::UnexpectedEof => {
+                Response::Missing { tid, err }
+            }
+            _ => Response::error(tid, JobKind::Read, err),
+        }
+    }
+
+    pub fn moved(tid: usize, path: String, files: Vec<(usize, MoveStatus)>) -> Response {
+        Response::Moved { tid, path, files }
     }
 
     pub fn validation_complete(tid: usize, invalid: Vec<u32>) -> Response {
@@ -732,13 +1275,18 @@ impl Response {
     pub fn tid(&self) -> usize {
         match self {
             Response::Read { context, .. } => context.tid,
-            Response::Write { context, .. } => context.tid,
+            Response::Write { context, .. } | Response::Corrupt { context } => context.tid,
             Response::ValidationComplete { tid, .. }
             | Response::Moved { tid, .. }
             | Response::ValidationUpdate { tid, .. }
+            | Response::MoveUpdate { tid, .. }
             | Response::PieceValidated { tid, .. }
-            | Response::Error { tid, .. } => *tid,
-            Response::FreeSpace(_) => unreachable!(),
+            | Response::BlocksChecked { tid, .. }
+            | Response::Error { tid, .. }
+            | Response::Missing { tid, .. } => *tid,
+            Response::FreeSpace(_) | Response::Stats(_) | Response::PathAnalysis(..) => {
+                unreachable!()
+            }
         }
     }
 }
@@ -0,0 +1,121 @@
+use std::io;
+use std::path::Path;
+
+use crate::torrent::Info;
+use crate::util::native::FsInfo;
+
+const EFBIG: i32 = 27;
+
+fn too_large(fs: &FsInfo, detail: &str) -> io::Error {
+    io::Error::other(format!(
+        "file too large for filesystem {}: {detail}",
+        fs.name
+    ))
+}
+
+/// Checks that every file in `info` can be stored under `dir`, using `query`
+/// to determine the filesystem. Filesystems which can't be identified are
+/// assumed to have no limit.
+pub fn check_info<F>(dir: &Path, info: &Info, query: F) -> io::Result<()>
+where
+    F: FnOnce(&Path) -> io::Result<FsInfo>,
+{
+    let Some(largest) = info.files.iter().max_by_key(|f| f.length) else {
+        return Ok(());
+    };
+    let Ok(fs) = query(dir) else {
+        return Ok(());
+    };
+    match fs.max_file_size {
+        Some(max) if largest.length > max => Err(too_large(
+            &fs,
+            &format!(
+                "{} is {} bytes, the limit is {max}",
+                largest.path.display(),
+                largest.length
+            ),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Maps errors from writing torrent data to `path` into user facing ones.
+pub fn classify<F>(err: io::Error, path: &Path, query: F) -> io::Error
+where
+    F: FnOnce(&Path) -> io::Result<FsInfo>,
+{
+    if err.raw_os_error() != Some(EFBIG) {
+        return err;
+    }
+    match query(path) {
+        Ok(fs) => too_large(&fs, &path.display().to_string()),
+        Err(_) => err,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::path::{Path, PathBuf};
+
+    use super::{EFBIG, check_info, classify};
+    use crate::torrent::Info;
+    use crate::torrent::info::File;
+    use crate::util::native::FsInfo;
+
+    fn vfat(_: &Path) -> io::Result<FsInfo> {
+        Ok(FsInfo {
+            name: "vfat".to_owned(),
+            max_file_size: Some(u64::from(u32::MAX)),
+        })
+    }
+
+    fn info(lengths: &[u64]) -> Info {
+        let mut info = Info::with_pieces(1);
+        info.files = lengths
+            .iter()
+            .enumerate()
+            .map(|(i, &length)| File {
+                path: PathBuf::from(format!("f{i}")),
+                length,
+            })
+            .collect();
+        info
+    }
+
+    #[test]
+    fn test_check_info() {
+        let dir = Path::new("/mnt/usb");
+        assert!(check_info(dir, &info(&[100, 1 << 31]), vfat).is_ok());
+
+        let err = check_info(dir, &info(&[100, 5 << 40, 1 << 33]), vfat).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "file too large for filesystem vfat: f1 is 5497558138880 bytes, the limit is 4294967295"
+        );
+
+        // Unknown filesystems and failed queries don't block anything
+        let unlimited = |_: &Path| {
+            Ok(FsInfo {
+                name: "0x1234".to_owned(),
+                max_file_size: None,
+            })
+        };
+        assert!(check_info(dir, &info(&[5 << 40]), unlimited).is_ok());
+        let failed = |_: &Path| Err(io::Error::from(io::ErrorKind::NotFound));
+        assert!(check_info(dir, &info(&[5 << 40]), failed).is_ok());
+    }
+
+    #[test]
+    fn test_classify() {
+        let path = Path::new("/mnt/usb/a.iso");
+        let err = classify(io::Error::from_raw_os_error(EFBIG), path, vfat);
+        assert_eq!(
+            err.to_string(),
+            "file too large for filesystem vfat: /mnt/usb/a.iso"
+        );
+
+        let err = classify(io::Error::from(io::ErrorKind::PermissionDenied), path, vfat);
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    }
+}
@@ -1,26 +1,35 @@
+mod analyze;
 mod cache;
 mod job;
+mod limits;
+mod relocate;
 
 pub use self::job::Ctx;
+pub use self::job::JobKind;
 pub use self::job::Location;
 pub use self::job::Request;
 pub use self::job::Response;
+pub use self::limits::check_info;
 
 use std::collections::VecDeque;
+use std::future::{Future, poll_fn};
+use std::pin::pin;
 use std::sync::Arc;
-use std::{fs, io, thread};
+use std::task::Poll;
+use std::{fs, io, thread, time};
 
 use self::cache::{BufCache, FileCache};
 use self::job::JobRes;
-use crate::config::Config;
+use crate::config::{Config, DiskConfig};
 use crate::worker;
 
 #[cfg(test)]
 mod tests;
 
-const POLL_INT_MS: usize = 1000;
 const JOB_TIME_SLICE: u64 = 150;
 
+type Executor = fn(Request, &DiskConfig, &mut FileCache, &mut BufCache) -> io::Result<JobRes>;
+
 pub struct Disk {
     config: Arc<Config>,
     worker: worker::Worker<Request, Response>,
@@ -29,6 +38,7 @@ pub struct Disk {
     active: VecDeque<Request>,
     sequential: VecDeque<Request>,
     bufs: BufCache,
+    execute: Executor,
 }
 
 impl Disk {
@@ -40,48 +50,103 @@ impl Disk {
         Disk {
             worker,
             jobs_rx,
-            files: FileCache::new(config.net.max_open_files),
+            files: FileCache::new(
+                config.net.max_open_files,
+                config.disk.sync,
+                config.disk.read_ahead,
+                config.disk.piece_cache,
+            ),
             bufs: BufCache::new(),
             active: VecDeque::new(),
             sequential: VecDeque::new(),
+            execute: Request::execute,
             config,
         }
     }
 
-    pub async fn run(&mut self) {
+    /// Processes requests until shutdown, returning whether all pending
+    /// writes were flushed to disk before the shutdown deadline.
+    pub async fn run(&mut self) -> bool {
         let sd = &self.config.disk.session;
         fs::create_dir_all(sd).unwrap();
 
-        loop {}
-        /*
-
-        while let Some(request) =  self.worker.rx.next() {
-            match self.poll.wait(POLL_INT_MS) {
-                Ok(_) => {
-                    if self.handle_events() {
-                        break;
-                    }
-                }
-                Err(e) => {
-                    error!("Failed to poll for events: {}", e);
+        loop {
+            if self.active.is_empty() {
+                match self.recv().await {
+                    Some(Request::Shutdown) | None => break,
+                    Some(r) => self.enqueue_req(r),
                 }
             }
-            if !self.active.is_empty() && self.handle_active() {
+            if self.handle_events() || self.handle_active() {
                 break;
             }
         }
-        */
 
-        // Try to finish up remaining jobs
-        for job in self.active.drain(..) {
-            if job.concurrent() {
-                job.execute(&self.config.disk, &mut self.files, &mut self.bufs)
-                    .ok();
+        self.shutdown()
+    }
+
+    /// Waits for a request from either control or the other workers.
+    /// Returns None once control has gone away.
+    async fn recv(&self) -> Option<Request> {
+        let mut ctl = pin!(self.worker.rx.recv_async());
+        let mut jobs = pin!(self.jobs_rx.recv_async());
+        poll_fn(|cx| {
+            if let Poll::Ready(r) = ctl.as_mut().poll(cx) {
+                return Poll::Ready(r.ok());
+            }
+            match jobs.as_mut().poll(cx) {
+                Poll::Ready(Ok(r)) => Poll::Ready(Some(r)),
+                _ => Poll::Pending,
+            }
+        })
+        .await
+    }
+
+    /// Finishes outstanding jobs which modify disk state, giving up once
+    /// the configured shutdown timeout elapses.
+    fn shutdown(&mut self) -> bool {
+        let deadline =
+            time::Instant::now() + time::Duration::from_secs(self.config.disk.shutdown_timeout);
+
+        // Anything sent prior to the shutdown request (e.g. final session
+        // serialization) is still sitting in the channels.
+        while let Ok(r) = self.worker.rx.try_recv() {
+            if !matches!(r, Request::Shutdown) {
+                self.enqueue_req(r);
             }
         }
+        while let Ok(r) = self.jobs_rx.try_recv() {
+            self.enqueue_req(r);
+        }
+        self.sequential.clear();
+        self.active.retain(|r| r.durable());
+
+        let mut rotate = 1;
+        while !self.active.is_empty() && time::Instant::now() < deadline {
+            self.execute_next(rotate);
+            rotate += 1;
+        }
+        let flushed = self.files.flush_all();
+        if !self.active.is_empty() {
+            error!(
+                "Shutdown deadline reached with {} disk jobs outstanding",
+                self.active.len()
+            );
+            return false;
+        }
+        flushed
     }
 
-    fn enqueue_req(&mut self, req: Request) {
+    fn enqueue_req(&mut self, mut req: Request) {
+        if let Err(e) = req.setup() {
+            match req.tid() {
+                Some(t) => {
+                    self.worker.tx.send(Response::error(t, req.kind(), e)).ok();
+                }
+                None => error!("Disk job setup failed: {}", e),
+            }
+            return;
+        }
         if req.concurrent() || !self.active.iter().any(|r| !r.concurrent()) {
             self.active.push_back(req);
         } else {
@@ -91,103 +156,99 @@ impl Disk {
 
     fn handle_active(&mut self) -> bool {
         let mut rotate = 1;
-        while let Some(j) = self.active.pop_front() {
-            let tid = j.tid();
-            let seq = !j.concurrent();
-            let mut done = false;
-            match j.execute(&self.config.disk, &mut self.files, &mut self.bufs) {
-                Ok(JobRes::Resp(r)) => {
-                    done = true;
-                    self.worker.tx.send(r).ok();
-                }
-                Ok(JobRes::Update(s, r)) => {
-                    self.worker.tx.send(r).ok();
-                    if rotate % 3 == 0 {
-                        self.active.push_back(s);
-                    } else {
-                        self.active.push_front(s);
-                    }
-                }
-                Ok(JobRes::Paused(s)) => {
-                    if rotate % 3 == 0 {
-                        self.active.push_back(s);
-                    } else {
-                        self.active.push_front(s);
-                    }
-                }
-                Ok(JobRes::Done) => {
-                    done = true;
+        while !self.active.is_empty() {
+            self.execute_next(rotate);
+            if self.handle_events() {
+                return true;
+            }
+            rotate += 1;
+        }
+        false
+    }
+
+    fn execute_next(&mut self, rotate: usize) {
+        let Some(j) = self.active.pop_front() else {
+            return;
+        };
+        let tid = j.tid();
+        let seq = !j.concurrent();
+        let kind = j.kind();
+        let mut done = false;
+        match (self.execute)(j, &self.config.disk, &mut self.files, &mut self.bufs) {
+            Ok(JobRes::Resp(r)) => {
+                done = true;
+                self.worker.tx.send(r).ok();
+            }
+            Ok(JobRes::Update(s, r)) => {
+                self.worker.tx.send(r).ok();
+                if rotate.is_multiple_of(3) {
+                    self.active.push_back(s);
+                } else {
+                    self.active.push_front(s);
                 }
-                Err(e) => {
-                    done = true;
-                    if let Some(t) = tid {
-                        self.worker.tx.send(Response::error(t, e)).ok();
-                    } else {
-                        error!("Disk job failed: {}", e);
-                    }
+            }
+            Ok(JobRes::Paused(s)) => {
+                if rotate.is_multiple_of(3) {
+                    self.active.push_back(s);
+                } else {
+                    self.active.push_front(s);
                 }
             }
-            if done
-                && seq
-                && let Some(r) = self.sequential.pop_front()
-            {
-                self.active.push_back(r);
+            Ok(JobRes::Done) => {
+                done = true;
             }
-            match self.poll.wait(0) {
-                Ok(_) => {
-                    if self.handle_events() {
-                        return true;
-                    }
-                }
-                Err(e) => {
-                    error!("Failed to poll for events: {:?}", e);
+            Err(e) => {
+                done = true;
+                if let Some(t) = tid {
+                    let resp = if kind == JobKind::Read {
+                        Response::read_error(t, e)
+                    } else {
+                        Response::error(t, kind, e)
+                    };
+                    self.worker.tx.send(resp).ok();
+                } else {
+                    error!("Disk job failed: {}", e);
                 }
             }
-            rotate += 1;
         }
-        false
+        if done
+            && seq
+            && let Some(r) = self.sequential.pop_front()
+        {
+            self.active.push_back(r);
+        }
     }
 
+    /// Enqueues any pending requests without blocking, returning true
+    /// if a shutdown was requested.
     pub fn handle_events(&mut self) -> bool {
-        loop {
-            match self.ch.recv() {
-                Ok(Request::Shutdown) => {
-                    return true;
-                }
-                Ok(mut r) => {
-                    let tid = r.tid();
-                    if let Err(e) = r.setup()
-                        && let Some(t) = tid
-                    {
-                        self.tx.send(Response::error(t, e)).ok();
-                    }
-                    self.enqueue_req(r);
-                }
-                _ => break,
+        while let Ok(r) = self.worker.rx.try_recv() {
+            if let Request::Shutdown = r {
+                return true;
             }
+            self.enqueue_req(r);
         }
-        while let Ok(mut r) = self.jobs.try_recv() {
-            if r.setup().is_err() {
-                continue;
-            }
+        while let Ok(r) = self.jobs_rx.try_recv() {
             self.enqueue_req(r);
         }
         false
     }
 }
 
+/// Starts the disk worker. Jobs from other workers are received over
+/// `jobs_rx`, which is owned by the caller so that it can outlive the
+/// worker should it need to be restarted.
 pub fn start(
     config: Arc<Config>,
     creg: &mut amy::Registrar,
+    jobs_rx: flume::Receiver<Request>,
 ) -> io::Result<(
     worker::WorkerHandle<Request, Response>,
-    flume::Sender<Request>,
-    thread::JoinHandle<()>,
+    thread::JoinHandle<bool>,
 )> {
     let (worker_handle, worker) = worker::Worker::new(creg)?;
-    let (jobs_tx, jobs_rx) = flume::unbounded::<Request>();
     let h = worker.run("disk", async move |worker| {
         Disk::new(config, worker, jobs_rx).run().await
     })?;
-    Ok((worker_handle, jobs_tx, h))
+    Ok((worker_handle, h))
 }
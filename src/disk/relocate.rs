@@ -0,0 +1,153 @@
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use super::job::EXDEV;
+use crate::rpc::resource::MoveStatus;
+use crate::torrent::Info;
+
+/// Renames a file, failing with `EXDEV` when the paths lie on different filesystems.
+pub type Rename = fn(&Path, &Path) -> io::Result<()>;
+
+/// Moves file `idx` of a torrent from the `from` directory to `to`. A file which is
+/// already present at the destination with the right contents is adopted rather than
+/// overwritten, otherwise ours is moved over it. Returns None if there was nothing to move.
+pub fn move_file(
+    info: &Info,
+    idx: usize,
+    from: &Path,
+    to: &Path,
+    rename: Rename,
+) -> Option<MoveStatus> {
+    let path = &info.files[idx].path;
+    let (src, dst) = (from.join(path), to.join(path));
+    if identical(info, idx, &src, &dst).unwrap_or(false) {
+        debug!("Adopting {:?} in place of {:?}", dst, src);
+        match fs::remove_file(&src) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                error!("Failed to remove adopted file {:?}: {}", src, e);
+            }
+            _ => {}
+        }
+        Some(MoveStatus::Adopted)
+    } else if !src.exists() {
+        // Never downloaded, there's nothing to move
+        None
+    } else {
+        match relocate(&src, &dst, rename) {
+            Ok(()) => Some(MoveStatus::Copied),
+            Err(e) => {
+                error!("Failed to move {:?} to {:?}: {}", src, dst, e);
+                Some(MoveStatus::Failed)
+            }
+        }
+    }
+}
+
+/// Puts file `idx` back in `from` after `move_file` took it to `to`. An adopted file is
+/// copied back, since it was at the destination before the move.
+pub fn restore(
+    info: &Info,
+    idx: usize,
+    status: MoveStatus,
+    from: &Path,
+    to: &Path,
+    rename: Rename,
+) -> io::Result<()> {
+    let path = &info.files[idx].path;
+    let (src, dst) = (from.join(path), to.join(path));
+    match status {
+        MoveStatus::Copied => relocate(&dst, &src, rename),
+        MoveStatus::Adopted => {
+            if let Some(parent) = src.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::copy(&dst, &src).map(|_| ())
+        }
+        MoveStatus::Failed => Ok(()),
+    }
+}
+
+/// Whether `dst` holds the contents of file `idx`. Its length must match, and the first
+/// and last pieces lying entirely within the file must hash correctly. Files too small
+/// to contain a whole piece are compared with our copy at `src` instead.
+fn identical(info: &Info, idx: usize, src: &Path, dst: &Path) -> io::Result<bool> {
+    let file = &info.files[idx];
+    let meta = fs::metadata(dst)?;
+    if !meta.is_file() || meta.len() != file.length {
+        return Ok(false);
+    }
+    let start = info.file_offset(idx);
+    let end = start + file.length;
+    let pl = u64::from(info.piece_len);
+    let first = start.div_ceil(pl);
+    // Only the final piece of the torrent may be shorter than the piece length
+    let last = if end == info.total_len {
+        u64::from(info.pieces())
+    } else {
+        end / pl
+    };
+    if first >= last {
+        return Ok(fs::read(src)? == fs::read(dst)?);
+    }
+
+    let mut f = fs::File::open(dst)?;
+    let mut buf = vec![0u8; info.piece_len as usize];
+    for piece in [first, last - 1] {
+        let len = info.piece_len(piece as u32) as usize;
+        f.seek(SeekFrom::Start(piece * pl - start))?;
+        f.read_exact(&mut buf[..len])?;
+        if !info.piece_valid(piece as u32, &buf[..len]) {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+fn relocate(src: &Path, dst: &Path, rename: Rename) -> io::Result<()> {
+    if let Some(parent) = dst.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    match rename(src, dst) {
+        Err(e) if e.raw_os_error() == Some(EXDEV) => {
+            fs::copy(src, dst)?;
+            // Only give up the original once the copy is known to be good
+            if !same_contents(src, dst)? {
+                fs::remove_file(dst).ok();
+                return Err(io::Error::other("copy differs from the original"));
+            }
+            fs::remove_file(src)
+        }
+        res => res,
+    }
+}
+
+fn same_contents(a: &Path, b: &Path) -> io::Result<bool> {
+    let (mut a, mut b) = (fs::File::open(a)?, fs::File::open(b)?);
+    if a.metadata()?.len() != b.metadata()?.len() {
+        return Ok(false);
+    }
+    let (mut abuf, mut bbuf) = (vec![0u8; 64 * 1024], vec![0u8; 64 * 1024]);
+    loop {
+        let n = a.read(&mut abuf)?;
+        if n == 0 {
+            return Ok(true);
+        }
+        b.read_exact(&mut bbuf[..n])?;
+        if abuf[..n] != bbuf[..n] {
+            return Ok(false);
+        }
+    }
+}
+
+/// Removes the directories left empty under `dir`, and `dir` itself if it ends up empty.
+pub fn remove_empty_dirs(dir: &Path) {
+    if let Ok(entries) = fs::read_dir(dir) {
+        for entry in entries.flatten() {
+            if entry.file_type().is_ok_and(|t| t.is_dir()) {
+                remove_empty_dirs(&entry.path());
+            }
+        }
+        fs::remove_dir(dir).ok();
+    }
+}
@@ -1,50 +1,79 @@
 use std::collections::HashSet;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use sha1::{Digest, Sha1};
 
 use super::*;
 use crate::buffers::{Buffer, BUF_SIZE};
-use crate::torrent::info::File;
-use crate::torrent::Info;
-use crate::{config, handle};
+use crate::torrent::info::{File, MetaVersion};
+use crate::torrent::{Bitfield, Info};
+use crate::rpc::resource::MoveStatus;
+use crate::config;
 
 struct Env {
     session_dir: tempfile::TempDir,
     data_dir: tempfile::TempDir,
     poll: amy::Poller,
-    reg: amy::Registrar,
-    handle: handle::Handle<Response, Request>,
-    jobs: amy::Sender<Request>,
-    join_handle: Option<std::thread::JoinHandle<()>>,
+    handle: worker::WorkerHandle<Request, Response>,
+    jobs: flume::Sender<Request>,
+    join_handle: Option<std::thread::JoinHandle<bool>>,
 }
 
 impl Env {
     fn new() -> Self {
+        Self::with_executor(10, Request::execute)
+    }
+
+    fn with_executor(shutdown_timeout: u64, execute: Executor) -> Self {
+        Self::with_config(shutdown_timeout, execute, |_| {})
+    }
+
+    fn with_config<F: FnOnce(&mut config::DiskConfig)>(
+        shutdown_timeout: u64,
+        execute: Executor,
+        setup: F,
+    ) -> Self {
         let session_dir = tempfile::tempdir().unwrap();
         let data_dir = tempfile::tempdir().unwrap();
+        let mut disk = config::DiskConfig {
+            session: session_dir.path().to_str().unwrap().to_string(),
+            directory: data_dir.path().to_str().unwrap().to_string(),
+            shutdown_timeout,
+            ..Default::default()
+        };
+        setup(&mut disk);
         let config = Arc::new(config::Config {
-            disk: config::DiskConfig {
-                session: session_dir.path().to_str().unwrap().to_string(),
-                directory: data_dir.path().to_str().unwrap().to_string(),
-                ..Default::default()
-            },
+            disk,
             ..Default::default()
         });
         let poll = amy::Poller::new().unwrap();
         let mut reg = poll.get_registrar();
-        let (handle, jobs, join_handle) = start(config, &mut reg).unwrap();
+        let (handle, worker) = worker::Worker::new(&mut reg).unwrap();
+        let (jobs, jobs_rx) = flume::unbounded();
+        let join_handle = worker
+            .run("disk", async move |worker| {
+                let mut disk = Disk::new(config, worker, jobs_rx);
+                disk.execute = execute;
+                disk.run().await
+            })
+            .unwrap();
         Self {
             session_dir,
             data_dir,
             poll,
-            reg,
             handle,
             jobs,
             join_handle: Some(join_handle),
         }
     }
 
-    fn join(mut self) {
-        assert_eq!(self.handle.send(Request::shutdown()), Ok(()));
-        assert_matches!(self.join_handle.take().unwrap().join(), Ok(()));
+    /// Shuts down the disk worker, returning whether it flushed everything.
+    fn join(&mut self) -> bool {
+        assert_matches!(self.handle.tx.send(Request::shutdown()), Ok(()));
+        let res = self.join_handle.take().unwrap().join();
+        assert_matches!(res, Ok(_));
+        res.unwrap()
     }
 }
 
@@ -54,6 +83,17 @@ impl Drop for Env {
     }
 }
 
+/// Simulates a disk which takes a long time to service each request.
+fn slow_execute(
+    req: Request,
+    config: &DiskConfig,
+    fc: &mut FileCache,
+    bc: &mut BufCache,
+) -> io::Result<JobRes> {
+    std::thread::sleep(Duration::from_millis(500));
+    req.execute(config, fc, bc)
+}
+
 // TODO: Add this helper to the Info impl?
 fn make_test_info(name: &str, files: &[File], piece_len: u64) -> Info {
     let total_len: u64 = files.iter().map(|f| f.length).sum();
@@ -73,6 +113,8 @@ fn make_test_info(name: &str, files: &[File], piece_len: u64) -> Info {
         be_name: None,
         piece_idx,
         url_list: vec![],
+        version: MetaVersion::V1,
+        info_bytes: Vec::new(),
     }
 }
 
@@ -113,7 +155,14 @@ fn read() {
             length: expected_data.len().try_into().unwrap(),
         }];
         let info = Arc::new(make_test_info("Test", files, piece_len));
-        let mut pending_contexts = get_contexts_for_info(&info);
+        // Each layout is read as a different torrent, since pieces are cached per torrent
+        let mut pending_contexts: HashSet<_> = get_contexts_for_info(&info)
+            .into_iter()
+            .map(|c| Ctx {
+                tid: piece_len as usize,
+                ..c
+            })
+            .collect();
         let piece_len: usize = piece_len.try_into().unwrap();
         for context in &pending_contexts {
             let locs = Info::block_disk_locs(&info, context.idx, context.begin);
@@ -123,6 +172,8 @@ fn read() {
                     Buffer::get().unwrap(),
                     locs,
                     None,
+                    false,
+                    false,
                 ))
                 .unwrap();
         }
@@ -149,7 +200,123 @@ fn read() {
     run_read_test(65_536);
     run_read_test(131_072);
 
-    env.join();
+    assert!(env.join());
+}
+
+#[test]
+fn read_missing() {
+    let mut env = Env::new();
+    let path = env.data_dir.path().join("abc");
+    let files = &[File {
+        path: path.clone(),
+        length: 65_536,
+    }];
+    let info = Arc::new(make_test_info("Test", files, 16_384));
+    let mut read_last = || {
+        let context = Ctx::new(0, 0, 3, 0, 16_384);
+        let locs = Info::block_disk_locs(&info, context.idx, context.begin);
+        env.jobs
+            .send(Request::read(context, Buffer::get().unwrap(), locs, None, false, false))
+            .unwrap();
+        env.poll.wait(1000).unwrap();
+        env.handle.rx.try_recv()
+    };
+
+    // Deleted and truncated files are told apart from other errors
+    assert_matches!(read_last(), Ok(Response::Missing { tid: 0, .. }));
+    std::fs::write(&path, b"012345678").unwrap();
+    assert_matches!(read_last(), Ok(Response::Missing { tid: 0, .. }));
+
+    assert!(env.join());
+}
+
+#[test]
+fn read_rewritten_piece() {
+    let mut env = Env::new();
+    let path = env.data_dir.path().join("abc");
+    std::fs::write(&path, vec![1u8; 65_536]).unwrap();
+    let files = &[File {
+        path: path.clone(),
+        length: 65_536,
+    }];
+    let info = Arc::new(make_test_info("Test", files, 65_536));
+    let read = |env: &mut Env, begin| {
+        let context = Ctx::new(0, 0, 0, begin, 16_384);
+        let locs = Info::block_disk_locs(&info, 0, begin);
+        env.jobs
+            .send(Request::read(context, Buffer::get().unwrap(), locs, None, false, false))
+            .unwrap();
+        env.poll.wait(1000).unwrap();
+        match env.handle.rx.try_recv() {
+            Ok(Response::Read { data, .. }) => data[..16_384].to_vec(),
+            _ => panic!(),
+        }
+    };
+
+    // The first read caches the whole piece
+    assert_eq!(read(&mut env, 0), vec![1; 16_384]);
+    std::fs::write(&path, vec![2u8; 65_536]).unwrap();
+    assert_eq!(read(&mut env, 16_384), vec![1; 16_384]);
+
+    // Until part of it is written again
+    let mut buffer = Buffer::get().unwrap();
+    buffer[..16_384].fill(3);
+    let locs = Info::block_disk_locs(&info, 0, 0);
+    env.jobs
+        .send(Request::write(
+            Ctx::new(0, 0, 0, 0, 16_384),
+            buffer,
+            locs,
+            None,
+            config::Allocation::Sparse,
+        ))
+        .unwrap();
+    env.poll.wait(1000).unwrap();
+    assert_matches!(env.handle.rx.try_recv(), Ok(Response::Write { .. }));
+    assert_eq!(read(&mut env, 0), vec![3; 16_384]);
+    assert_eq!(read(&mut env, 16_384), vec![2; 16_384]);
+
+    assert!(env.join());
+}
+
+#[test]
+fn check_blocks() {
+    let mut env = Env::new();
+    let path = env.data_dir.path().join("abc");
+    let files = &[File {
+        path: path.clone(),
+        length: 65_536,
+    }];
+    let info = Arc::new(make_test_info("Test", files, 65_536));
+    let mut check = |claimed: &[u64]| {
+        let mut blocks = Bitfield::new(4);
+        for &b in claimed {
+            blocks.set_bit(b);
+        }
+        env.jobs
+            .send(Request::check_blocks(0, info.clone(), None, 0, blocks))
+            .unwrap();
+        env.poll.wait(1000).unwrap();
+        match env.handle.rx.try_recv() {
+            Ok(Response::BlocksChecked { piece: 0, blocks, .. }) => {
+                blocks.iter().collect::<Vec<_>>()
+            }
+            _ => panic!("expected checked blocks"),
+        }
+    };
+
+    // The second block was allocated but never written
+    let mut data = vec![0u8; 65_536];
+    data[..16_384].fill(1);
+    data[32_768..].fill(2);
+    std::fs::write(&path, &data).unwrap();
+    assert_eq!(check(&[0, 1, 2]), [0, 2]);
+
+    // Nothing can be read back from a truncated file
+    std::fs::write(&path, b"").unwrap();
+    assert_eq!(check(&[0, 1, 2]), Vec::<u64>::new());
+
+    assert!(env.join());
 }
 
 #[test]
@@ -182,6 +349,7 @@ fn write() {
                     buffer,
                     locs,
                     Some(tempdir.path().to_str().unwrap().to_owned()),
+                    config::Allocation::Sparse,
                 ))
                 .unwrap();
         }
@@ -202,5 +370,624 @@ fn write() {
     run_write_test(65_536);
     run_write_test(131_072);
 
-    env.join();
+    assert!(env.join());
+}
+
+#[test]
+fn write_non_utf8_path() {
+    use std::os::unix::ffi::OsStrExt;
+
+    use crate::torrent::info::SJIS_FILE;
+
+    let mut env = Env::new();
+    let info = Arc::new(Info::with_sjis_names());
+    let data = b"0123456789".repeat(3_277);
+    let dir = env.data_dir.path().to_str().unwrap().to_owned();
+    let mut pending: HashSet<_> = write_requests(&info, &data[..info.total_len as usize], &dir)
+        .into_iter()
+        .map(|req| {
+            let Request::Write { ref context, .. } = req else { unreachable!() };
+            let context = context.clone();
+            env.jobs.send(req).unwrap();
+            context
+        })
+        .collect();
+    while !pending.is_empty() {
+        env.poll.wait(1000).unwrap();
+        while let Ok(Response::Write { context }) = env.handle.rx.try_recv() {
+            assert!(pending.remove(&context));
+        }
+    }
+
+    // The files are created with their names as given in the torrent
+    let root = env.data_dir.path().join(info.root());
+    let mut names: Vec<_> = std::fs::read_dir(&root)
+        .unwrap()
+        .map(|e| e.unwrap().file_name().as_bytes().to_vec())
+        .collect();
+    names.sort();
+    assert_eq!(names, [b"readme.txt".to_vec(), SJIS_FILE.to_vec()]);
+    let file = root.join(std::ffi::OsStr::from_bytes(SJIS_FILE));
+    assert_eq!(std::fs::read(file).unwrap(), &data[..20_000]);
+    assert!(env.join());
+}
+
+fn write_requests(info: &Arc<Info>, data: &[u8], path: &str) -> Vec<Request> {
+    let piece_len: usize = info.piece_len.try_into().unwrap();
+    get_contexts_for_info(info)
+        .into_iter()
+        .map(|context| {
+            let locs = Info::block_disk_locs(info, context.idx, context.begin);
+            let start = context.idx as usize * piece_len + context.begin as usize;
+            let length: usize = context.length.try_into().unwrap();
+            let mut buffer = Buffer::get().unwrap();
+            buffer[0..length].copy_from_slice(&data[start..start + length]);
+            Request::write(
+                context,
+                buffer,
+                locs,
+                Some(path.to_owned()),
+                config::Allocation::Sparse,
+            )
+        })
+        .collect()
+}
+
+#[test]
+fn shutdown_flushes_pending() {
+    let mut env = Env::new();
+    let expected_data = b"012345678".repeat(11_111);
+    let path = env.data_dir.path().join("abc");
+    let files = &[File {
+        path: path.clone(),
+        length: expected_data.len().try_into().unwrap(),
+    }];
+    let info = Arc::new(make_test_info("Test", files, 16_384));
+    let dir = env.data_dir.path().to_str().unwrap().to_owned();
+    for req in write_requests(&info, &expected_data, &dir) {
+        env.handle.tx.send(req).unwrap();
+    }
+    let session_file = env.session_dir.path().join("syn_data");
+    env.jobs
+        .send(Request::WriteFile {
+            path: session_file.clone(),
+            data: b"session".to_vec(),
+        })
+        .unwrap();
+
+    // Shut down without waiting for any responses; everything queued prior
+    // should still make it to disk.
+    assert!(env.join());
+    assert_eq!(expected_data, std::fs::read(&path).unwrap());
+    assert_eq!(b"session".to_vec(), std::fs::read(&session_file).unwrap());
+}
+
+#[test]
+fn shutdown_deadline() {
+    let mut env = Env::with_executor(1, slow_execute);
+    let expected_data = b"012345678".repeat(11_111);
+    let path = env.data_dir.path().join("abc");
+    let files = &[File {
+        path,
+        length: expected_data.len().try_into().unwrap(),
+    }];
+    let info = Arc::new(make_test_info("Test", files, 16_384));
+    let dir = env.data_dir.path().to_str().unwrap().to_owned();
+    let reqs = write_requests(&info, &expected_data, &dir);
+    // Enough work to far exceed the one second deadline.
+    assert!(reqs.len() * 500 > 3000);
+    for req in reqs {
+        env.handle.tx.send(req).unwrap();
+    }
+
+    let start = Instant::now();
+    assert!(!env.join());
+    assert!(start.elapsed() < Duration::from_secs(3));
+}
+
+#[test]
+fn skip_unwanted() {
+    // The torrent's policy applies, whatever the configured one is
+    let mut env = Env::with_config(10, Request::execute, |disk| {
+        disk.allocation = config::Allocation::Full;
+    });
+    let expected_data = b"012345678".repeat(5_000);
+    // The unwanted file sits entirely within the second piece
+    let files = &[
+        File {
+            path: "a".into(),
+            length: 20_000,
+        },
+        File {
+            path: "b".into(),
+            length: 10_000,
+        },
+        File {
+            path: "c".into(),
+            length: 15_000,
+        },
+    ];
+    let info = Arc::new(make_test_info("Test", files, 16_384));
+    let priorities = Arc::new(vec![3, 0, 3]);
+    let dir = env.data_dir.path().to_str().unwrap().to_owned();
+    let mut pending_contexts = get_contexts_for_info(&info);
+    for context in &pending_contexts {
+        let locs = Info::block_disk_locs_pri(&info, &priorities, context.idx, context.begin);
+        let start = context.idx as usize * 16_384 + context.begin as usize;
+        let length: usize = context.length.try_into().unwrap();
+        let mut buffer = Buffer::get().unwrap();
+        buffer[0..length].copy_from_slice(&expected_data[start..start + length]);
+        env.jobs
+            .send(Request::write(
+                context.clone(),
+                buffer,
+                locs,
+                Some(dir.clone()),
+                config::Allocation::SkipUnwanted,
+            ))
+            .unwrap();
+    }
+    while !pending_contexts.is_empty() {
+        env.poll.wait(1000).unwrap();
+        match env.handle.rx.try_recv() {
+            Ok(Response::Write { context }) => {
+                assert!(pending_contexts.remove(&context));
+            }
+            Ok(Response::Error { err, .. }) => panic!("{err}"),
+            _ => panic!(),
+        }
+    }
+    let data_dir = env.data_dir.path().to_owned();
+    let data_path = |name| data_dir.join(name);
+    assert!(!data_path("b").exists());
+    assert_eq!(
+        expected_data[..20_000],
+        std::fs::read(data_path("a")).unwrap()
+    );
+    assert_eq!(
+        expected_data[30_000..],
+        std::fs::read(data_path("c")).unwrap()
+    );
+
+    // Reads of the skipped portion come from the parts file
+    let mut pending_contexts = get_contexts_for_info(&info);
+    for context in &pending_contexts {
+        let locs = Info::block_disk_locs(&info, context.idx, context.begin);
+        env.jobs
+            .send(Request::read(
+                context.clone(),
+                Buffer::get().unwrap(),
+                locs,
+                Some(dir.clone()),
+                false,
+                false,
+            ))
+            .unwrap();
+    }
+    while !pending_contexts.is_empty() {
+        env.poll.wait(1000).unwrap();
+        match env.handle.rx.try_recv() {
+            Ok(Response::Read { context, data }) => {
+                assert!(pending_contexts.remove(&context));
+                let start = context.idx as usize * 16_384 + context.begin as usize;
+                let length: usize = context.length.try_into().unwrap();
+                assert_eq!(expected_data[start..start + length], data[0..length]);
+            }
+            _ => panic!(),
+        }
+    }
+
+    env.jobs
+        .send(Request::materialize(0, info.clone(), Some(dir.clone()), 1))
+        .unwrap();
+    assert!(env.join());
+    assert_eq!(
+        expected_data[20_000..30_000],
+        std::fs::read(data_path("b")).unwrap()
+    );
+}
+
+/// Builds the info of a torrent named Test holding the given files, with real piece hashes.
+fn hashed_info(files: &[(&str, &[u8])], piece_len: u64) -> Info {
+    let meta: Vec<_> = files
+        .iter()
+        .map(|(name, data)| File {
+            path: Path::new("Test").join(name),
+            length: data.len().try_into().unwrap(),
+        })
+        .collect();
+    let mut info = make_test_info("Test", &meta, piece_len);
+    let data: Vec<u8> = files.iter().flat_map(|(_, d)| d.iter().copied()).collect();
+    info.hashes = data
+        .chunks(piece_len.try_into().unwrap())
+        .map(|piece| Sha1::digest(piece).to_vec())
+        .collect();
+    info
+}
+
+fn place(dir: &Path, files: &[(&str, &[u8])]) {
+    for (name, data) in files {
+        let path = dir.join("Test").join(name);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, data).unwrap();
+    }
+}
+
+fn move_torrent(env: &mut Env, info: Info, to: &Path) -> Vec<(usize, MoveStatus)> {
+    env.jobs
+        .send(Request::move_torrent(
+            0,
+            Arc::new(info),
+            env.data_dir.path().to_str().unwrap().to_owned(),
+            to.to_str().unwrap().to_owned(),
+            "Test".into(),
+        ))
+        .unwrap();
+    env.poll.wait(1000).unwrap();
+    match env.handle.rx.try_recv() {
+        Ok(Response::Moved { files, .. }) => files,
+        _ => panic!("expected move"),
+    }
+}
+
+#[test]
+fn validate_piece() {
+    let mut env = Env::new();
+    let a = b"0123456789".repeat(2_000);
+    let b = b"abcdefghij".repeat(2_000);
+    // Piece 1 spans both files
+    let files: &[(&str, &[u8])] = &[("a", &a), ("b", &b)];
+    let info = Arc::new(hashed_info(files, 16_384));
+    place(env.data_dir.path(), files);
+    let mut validate = |piece: u32| {
+        env.jobs
+            .send(Request::validate_piece(0, info.clone(), None, piece))
+            .unwrap();
+        env.poll.wait(1000).unwrap();
+        match env.handle.rx.try_recv() {
+            Ok(Response::PieceValidated { piece: p, valid, .. }) if p == piece => valid,
+            _ => panic!("expected piece validation"),
+        }
+    };
+    assert!(validate(0));
+    assert!(validate(1));
+    assert!(validate(2));
+
+    let mut corrupt = b.clone();
+    corrupt[100] ^= 0xff;
+    place(env.data_dir.path(), &[("b", &corrupt)]);
+    assert!(validate(0));
+    assert!(!validate(1));
+    assert!(validate(2));
+
+    assert!(env.join());
+}
+
+#[test]
+fn read_verified() {
+    let mut env = Env::new();
+    let a = b"0123456789".repeat(2_000);
+    let b = b"abcdefghij".repeat(2_000);
+    let files: &[(&str, &[u8])] = &[("a", &a), ("b", &b)];
+    let info = Arc::new(hashed_info(files, 32_768));
+    let data = [&a[..], &b[..]].concat();
+    place(env.data_dir.path(), files);
+    let read = |env: &mut Env, piece: u32, begin: u32| {
+        let length = info.block_len(piece, begin);
+        let context = Ctx::new(0, 0, piece, begin, length);
+        let locs = Info::block_disk_locs(&info, piece, begin);
+        env.jobs
+            .send(Request::read(context, Buffer::get().unwrap(), locs, None, false, true))
+            .unwrap();
+        env.poll.wait(1000).unwrap();
+        match env.handle.rx.try_recv() {
+            Ok(Response::Read { data, .. }) => Some(data[..length as usize].to_vec()),
+            Ok(Response::Corrupt { context }) => {
+                assert_eq!((context.idx, context.begin), (piece, begin));
+                None
+            }
+            _ => panic!("expected a read"),
+        }
+    };
+    assert_eq!(read(&mut env, 0, 0).unwrap(), &data[..16_384]);
+
+    // The rest of a verified piece comes from the cache, without hashing it again
+    let mut corrupt = a.clone();
+    corrupt[16_484] ^= 0xff;
+    place(env.data_dir.path(), &[("a", &corrupt)]);
+    assert_eq!(read(&mut env, 0, 16_384).unwrap(), &data[16_384..32_768]);
+
+    // Nothing of a piece which doesn't match is served
+    let mut corrupt = b.clone();
+    corrupt[15_000] ^= 0xff;
+    place(env.data_dir.path(), &[("b", &corrupt)]);
+    assert_eq!(read(&mut env, 1, 0), None);
+
+    assert!(env.join());
+}
+
+#[test]
+fn read_verified_uncached() {
+    let mut env = Env::with_config(10, Request::execute, |disk| disk.piece_cache = 0);
+    let content = b"0123456789".repeat(4_000);
+    let files: &[(&str, &[u8])] = &[("a", &content)];
+    let info = Arc::new(hashed_info(files, 32_768));
+    place(env.data_dir.path(), files);
+    let mut read = |begin: u32| {
+        let context = Ctx::new(0, 0, 0, begin, 16_384);
+        let locs = Info::block_disk_locs(&info, 0, begin);
+        env.jobs
+            .send(Request::read(context, Buffer::get().unwrap(), locs, None, false, true))
+            .unwrap();
+        env.poll.wait(1000).unwrap();
+        match env.handle.rx.try_recv() {
+            Ok(Response::Read { data, .. }) => {
+                let start = begin as usize;
+                assert_eq!(data[..16_384], content[start..start + 16_384]);
+            }
+            _ => panic!("expected a read"),
+        }
+        env.jobs.send(Request::Stats).unwrap();
+        env.poll.wait(1000).unwrap();
+        match env.handle.rx.try_recv() {
+            Ok(Response::Stats(stats)) => stats.bytes_read,
+            _ => panic!("expected stats"),
+        }
+    };
+    // The whole piece is read to check it the first time
+    assert_eq!(read(0), 32_768);
+    // But not for its other blocks, with no cache to keep it in
+    assert_eq!(read(16_384), 32_768 + 16_384);
+    assert_eq!(read(0), 32_768 + 2 * 16_384);
+
+    assert!(env.join());
+}
+
+#[test]
+fn move_adopts_identical() {
+    let mut env = Env::new();
+    let dest = tempfile::tempdir().unwrap();
+    let data = b"012345678".repeat(5_000);
+    let files: &[(&str, &[u8])] = &[("a", &data)];
+    place(env.data_dir.path(), files);
+    place(dest.path(), files);
+
+    let info = hashed_info(files, 16_384);
+    assert_eq!(
+        move_torrent(&mut env, info, dest.path()),
+        [(0, MoveStatus::Adopted)]
+    );
+    assert!(!env.data_dir.path().join("Test").exists());
+    assert_eq!(std::fs::read(dest.path().join("Test/a")).unwrap(), data);
+
+    assert!(env.join());
+}
+
+#[test]
+fn move_replaces_different() {
+    let mut env = Env::new();
+    let dest = tempfile::tempdir().unwrap();
+    let data = b"012345678".repeat(5_000);
+    let files: &[(&str, &[u8])] = &[("a", &data)];
+    place(env.data_dir.path(), files);
+    // Same size, but not the file we have
+    place(dest.path(), &[("a", &vec![0u8; data.len()])]);
+
+    let info = hashed_info(files, 16_384);
+    assert_eq!(
+        move_torrent(&mut env, info, dest.path()),
+        [(0, MoveStatus::Copied)]
+    );
+    assert!(!env.data_dir.path().join("Test").exists());
+    assert_eq!(std::fs::read(dest.path().join("Test/a")).unwrap(), data);
+
+    assert!(env.join());
+}
+
+#[test]
+fn move_mixed() {
+    let mut env = Env::new();
+    let dest = tempfile::tempdir().unwrap();
+    let data = b"012345678".repeat(10_000);
+    // c is too small to hold a whole piece, so it's compared byte for byte
+    let (a, b, c, d) = (
+        &data[..40_000],
+        &data[40_000..80_000],
+        &data[80_000..80_100],
+        &data[80_100..85_100],
+    );
+    let files: &[(&str, &[u8])] = &[("a", a), ("b", b), ("c", c), ("sub/d", d)];
+    place(env.data_dir.path(), files);
+    let mut other = b.to_vec();
+    other[20_000] ^= 1;
+    place(dest.path(), &[("a", a), ("b", &other), ("c", c)]);
+
+    let info = hashed_info(files, 16_384);
+    assert_eq!(
+        move_torrent(&mut env, info, dest.path()),
+        [
+            (0, MoveStatus::Adopted),
+            (1, MoveStatus::Copied),
+            (2, MoveStatus::Adopted),
+            (3, MoveStatus::Copied),
+        ]
+    );
+    assert!(!env.data_dir.path().join("Test").exists());
+    for (name, data) in files {
+        assert_eq!(
+            &std::fs::read(dest.path().join("Test").join(name)).unwrap(),
+            data
+        );
+    }
+
+    assert!(env.join());
+}
+
+#[test]
+fn move_renames() {
+    let mut env = Env::new();
+    let dest = tempfile::tempdir().unwrap();
+    let data = b"012345678".repeat(5_000);
+    let files: &[(&str, &[u8])] = &[("a", &data[..20_000]), ("sub/b", &data[20_000..])];
+    place(env.data_dir.path(), files);
+
+    let info = hashed_info(files, 16_384);
+    assert_eq!(
+        move_torrent(&mut env, info, dest.path()),
+        [(0, MoveStatus::Copied), (1, MoveStatus::Copied)]
+    );
+    assert!(!env.data_dir.path().join("Test").exists());
+    for (name, data) in files {
+        assert_eq!(
+            &std::fs::read(dest.path().join("Test").join(name)).unwrap(),
+            data
+        );
+    }
+
+    assert!(env.join());
+}
+
+#[test]
+fn move_rolls_back_on_failure() {
+    let mut env = Env::new();
+    let dest = tempfile::tempdir().unwrap();
+    let data = b"012345678".repeat(5_000);
+    let files: &[(&str, &[u8])] = &[("a", &data[..20_000]), ("b", &data[20_000..])];
+    place(env.data_dir.path(), files);
+    // A directory in the way of the second file
+    std::fs::create_dir_all(dest.path().join("Test/b/c")).unwrap();
+
+    let info = hashed_info(files, 16_384);
+    env.jobs
+        .send(Request::move_torrent(
+            0,
+            Arc::new(info),
+            env.data_dir.path().to_str().unwrap().to_owned(),
+            dest.path().to_str().unwrap().to_owned(),
+            "Test".into(),
+        ))
+        .unwrap();
+    env.poll.wait(1000).unwrap();
+    match env.handle.rx.try_recv() {
+        Ok(Response::Error {
+            job: JobKind::Move, ..
+        }) => {}
+        r => panic!("expected the move to fail, got {r:?}"),
+    }
+    // The file which did move is back with the other
+    for (name, data) in files {
+        assert_eq!(
+            &std::fs::read(env.data_dir.path().join("Test").join(name)).unwrap(),
+            data
+        );
+    }
+    assert!(!dest.path().join("Test/a").exists());
+
+    assert!(env.join());
+}
+
+#[test]
+fn move_copy_fallback() {
+    fn exdev(_: &Path, _: &Path) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(job::EXDEV))
+    }
+
+    let from = tempfile::tempdir().unwrap();
+    let to = tempfile::tempdir().unwrap();
+    let data = b"012345678".repeat(5_000);
+    let files: &[(&str, &[u8])] = &[("a", &data[..20_000]), ("sub/b", &data[20_000..])];
+    place(from.path(), files);
+
+    let info = hashed_info(files, 16_384);
+    for idx in 0..files.len() {
+        assert_eq!(
+            relocate::move_file(&info, idx, from.path(), to.path(), exdev),
+            Some(MoveStatus::Copied)
+        );
+    }
+    for (name, data) in files {
+        assert!(!from.path().join("Test").join(name).exists());
+        assert_eq!(
+            &std::fs::read(to.path().join("Test").join(name)).unwrap(),
+            data
+        );
+    }
+    // And can be put back
+    for idx in 0..files.len() {
+        relocate::restore(&info, idx, MoveStatus::Copied, from.path(), to.path(), exdev).unwrap();
+    }
+    for (name, data) in files {
+        assert!(!to.path().join("Test").join(name).exists());
+        assert_eq!(
+            &std::fs::read(from.path().join("Test").join(name)).unwrap(),
+            data
+        );
+    }
+    // Files which were never downloaded are skipped
+    std::fs::remove_file(from.path().join("Test/a")).unwrap();
+    assert_eq!(
+        relocate::move_file(&info, 0, from.path(), to.path(), exdev),
+        None
+    );
+}
+
+/// Serves a 100 byte file over a local connection, returning the raw response.
+fn download(env: &mut Env, range: Option<&str>) -> String {
+    use std::io::Read;
+    use std::net::{TcpListener, TcpStream};
+
+    let path = env.data_dir.path().join("dl");
+    let data: Vec<u8> = (0..100).map(|i| b'a' + i % 26).collect();
+    std::fs::write(&path, data).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let conn = sstream::SStream::from_plain(listener.accept().unwrap().0).unwrap();
+    let file_path = path.to_str().unwrap().to_owned();
+    env.jobs
+        .send(Request::download(conn, range, file_path, 100))
+        .unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut resp = String::new();
+    client.read_to_string(&mut resp).unwrap();
+    resp
+}
+
+#[test]
+fn download_range() {
+    let mut env = Env::new();
+    let resp = download(&mut env, Some("bytes=10-19"));
+    assert!(resp.starts_with("HTTP/1.1 206 Partial Content\r\n"));
+    assert!(resp.contains("\r\nContent-Range: bytes 10-19/100\r\n"));
+    assert!(resp.contains("\r\nContent-Length: 10\r\n"));
+    assert!(resp.ends_with("\r\n\r\nklmnopqrst"));
+
+    // Open ended and suffix ranges run to the end of the file
+    let resp = download(&mut env, Some("bytes=95-"));
+    assert!(resp.contains("\r\nContent-Range: bytes 95-99/100\r\n"));
+    assert!(resp.ends_with("\r\n\r\nrstuv"));
+    let resp = download(&mut env, Some("bytes=-3"));
+    assert!(resp.contains("\r\nContent-Range: bytes 97-99/100\r\n"));
+    assert!(resp.ends_with("\r\n\r\ntuv"));
+
+    // Malformed ranges are ignored
+    let resp = download(&mut env, Some("bytes=20-10"));
+    assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(resp.contains("\r\nAccept-Ranges: bytes\r\n"));
+    assert!(resp.contains("\r\nContent-Length: 100\r\n"));
+
+    assert!(env.join());
+}
+
+#[test]
+fn download_unsatisfiable_range() {
+    let mut env = Env::new();
+    let resp = download(&mut env, Some("bytes=100-"));
+    assert_eq!(
+        resp,
+        "HTTP/1.1 416 Range Not Satisfiable\r\nContent-Range: bytes */100\r\n\
+         Content-Length: 0\r\nConnection: Close\r\n\r\n"
+    );
+    assert!(env.join());
 }
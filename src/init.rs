@@ -1,10 +1,13 @@
+use std::path::Path;
 use std::sync::{Arc, atomic, mpsc};
 use std::{io, process, thread};
 
 use crate::config::Config;
 use crate::control::acio;
-use crate::{SHUTDOWN, THROT_TOKS};
-use crate::{args, control, disk, log, rpc, throttle, tracker};
+use crate::control::cio::CIO;
+use crate::control::supervisor::Supervisor;
+use crate::{RELOAD, SHUTDOWN, THROT_TOKS};
+use crate::{args, control, crash, log, throttle};
 
 pub fn init(args: args::Args) -> Result<(), ()> {
     if let Some(level) = args.level {
@@ -25,17 +28,21 @@ pub fn init(args: args::Args) -> Result<(), ()> {
 }
 
 pub fn run(config: Arc<Config>) -> Result<(), ()> {
+    if config.crash_reports {
+        crash::install(Path::new(&config.disk.session).join("crashes"));
+    }
     match init_threads(config) {
-        Ok(threads) => {
-            for thread in threads {
-                if thread.join().is_err() {
-                    error!("Unclean shutdown detected, terminating");
-                    return Err(());
-                }
+        Ok(control) => match control.join() {
+            Ok(true) => {
+                info!("Shutdown complete");
+                Ok(())
             }
-            info!("Shutdown complete");
-            Ok(())
-        }
+            Ok(false) => Err(()),
+            Err(_) => {
+                error!("Unclean shutdown detected, terminating");
+                Err(())
+            }
+        },
         Err(e) => {
             error!("Couldn't initialize synapse: {}", e);
             Err(())
@@ -43,28 +50,21 @@ pub fn run(config: Arc<Config>) -> Result<(), ()> {
     }
 }
 
-fn init_threads(config: Arc<Config>) -> io::Result<Vec<thread::JoinHandle<()>>> {
+/// Starts all threads, returning a handle to the control thread which
+/// yields whether every worker shut down cleanly.
+fn init_threads(config: Arc<Config>) -> io::Result<thread::JoinHandle<bool>> {
     let cpoll = amy::Poller::new()?;
     let mut creg = cpoll.get_registrar();
-    let (dh, disk_broadcast, dhj) = disk::start(config.clone(), &mut creg)?;
-    let (rh, rhj) = rpc::RPC::start(config.clone(), &mut creg, disk_broadcast.clone())?;
-    let (th, thj) = tracker::Tracker::start(config.clone(), &mut creg, disk_broadcast.clone())?;
-    let chans = acio::ACChans {
-        disk_tx: dh.tx,
-        disk_rx: dh.rx,
-        rpc_tx: rh.tx,
-        rpc_rx: rh.rx,
-        trk_tx: th.tx,
-        trk_rx: th.rx,
-    };
+    let (supervisor, chans) = Supervisor::start(config.clone(), &mut creg)?;
     let (tx, rx) = mpsc::channel();
-    let cdb = disk_broadcast.clone();
+    let cdb = supervisor.disk_broadcast();
     let chj = thread::Builder::new()
         .name("control".to_string())
         .spawn(move || {
             let throttler = throttle::Throttler::new(None, None, THROT_TOKS, &creg).unwrap();
-            let acio = acio::ACIO::new(config.clone(), cpoll, creg, chans)
+            let acio = acio::ACIO::new(config.clone(), cpoll, creg, chans, supervisor)
                 .expect("Could not initialize IO");
+            let workers = acio.new_handle();
             match control::Control::new(config, acio, throttler, cdb) {
                 Ok(mut c) => {
                     tx.send(Ok(())).unwrap();
@@ -72,16 +72,25 @@ fn init_threads(config: Arc<Config>) -> io::Result<Vec<thread::JoinHandle<()>>>
                 }
                 Err(e) => {
                     tx.send(Err(e)).unwrap();
+                    return false;
                 }
             }
+            workers.join_workers()
         })
         .unwrap();
     rx.recv().unwrap()?;
 
-    Ok(vec![chj, dhj, rhj, thj])
+    Ok(chj)
 }
 
 fn init_signals() -> Result<(), ctrlc::Error> {
+    // Writes to a closed socket must fail with EPIPE rather than kill the process. Rust's
+    // runtime normally does this already, but don't rely on how we were started.
+    unsafe {
+        libc::signal(libc::SIGPIPE, libc::SIG_IGN);
+        libc::signal(libc::SIGTERM, on_sigterm as *const () as libc::sighandler_t);
+        libc::signal(libc::SIGHUP, on_sighup as *const () as libc::sighandler_t);
+    }
     ctrlc::set_handler(move || {
         if SHUTDOWN.load(atomic::Ordering::SeqCst) {
             info!("Terminating process!");
@@ -92,3 +101,17 @@ fn init_signals() -> Result<(), ctrlc::Error> {
         }
     })
 }
+
+/// Shuts down cleanly on SIGTERM as on an interrupt, so service managers stopping synapse
+/// don't lose session state. Logging isn't async-signal-safe, so nothing is logged here.
+extern "C" fn on_sigterm(_: libc::c_int) {
+    if SHUTDOWN.load(atomic::Ordering::SeqCst) {
+        process::abort();
+    }
+    SHUTDOWN.store(true, atomic::Ordering::SeqCst);
+}
+
+/// Asks the control thread to reload the config file.
+extern "C" fn on_sighup(_: libc::c_int) {
+    RELOAD.store(true, atomic::Ordering::SeqCst);
+}
@@ -1,4 +1,10 @@
+use std::collections::VecDeque;
 use std::fmt;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Number of recent log lines retained for crash reports
+const RECENT_LINES: usize = 200;
 
 #[derive(PartialEq, PartialOrd)]
 pub enum LogLevel {
@@ -21,12 +27,64 @@ impl fmt::Display for LogLevel {
 
 pub static mut LEVEL: LogLevel = LogLevel::Info;
 
+static RECORD: AtomicBool = AtomicBool::new(false);
+static RECENT: Mutex<Recent> = Mutex::new(Recent::new(RECENT_LINES));
+
+/// Ring buffer of the last `cap` log lines.
+struct Recent {
+    lines: VecDeque<String>,
+    cap: usize,
+}
+
+impl Recent {
+    const fn new(cap: usize) -> Recent {
+        Recent {
+            lines: VecDeque::new(),
+            cap,
+        }
+    }
+
+    fn push(&mut self, msg: &[u8]) {
+        if self.lines.len() >= self.cap {
+            self.lines.pop_front();
+        }
+        self.lines
+            .push_back(String::from_utf8_lossy(msg).into_owned());
+    }
+
+    fn lines(&self) -> Vec<String> {
+        self.lines.iter().cloned().collect()
+    }
+}
+
 pub fn log_init(level: LogLevel) {
     unsafe {
         LEVEL = level;
     }
 }
 
+/// Starts retaining the most recently emitted log lines.
+pub fn record_recent() {
+    RECORD.store(true, Ordering::Relaxed);
+}
+
+#[doc(hidden)]
+pub fn record(msg: &[u8]) {
+    if !RECORD.load(Ordering::Relaxed) {
+        return;
+    }
+    RECENT.lock().unwrap_or_else(|e| e.into_inner()).push(msg);
+}
+
+/// Returns the retained log lines, oldest first. This doesn't block so that
+/// it's safe to call from a panic hook.
+pub fn recent() -> Vec<String> {
+    match RECENT.try_lock() {
+        Ok(recent) => recent.lines(),
+        Err(_) => Vec::new(),
+    }
+}
+
 #[macro_export]
 macro_rules! trace(
     ($fmt:expr) => {
@@ -88,6 +146,7 @@ macro_rules! log(
                 let stderr = std::io::stderr();
                 let mut handle = stderr.lock();
                 handle.write_all(&msg).ok();
+                $crate::log::record(&msg);
             }
         }
     };
@@ -107,7 +166,28 @@ macro_rules! log(
                 let stderr = std::io::stderr();
                 let mut handle = stderr.lock();
                 handle.write_all(&msg).ok();
+                $crate::log::record(&msg);
             }
         }
     };
 );
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recent_bounded() {
+        let mut recent = Recent::new(RECENT_LINES);
+        for i in 0..RECENT_LINES + 10 {
+            recent.push(format!("line {i}\n").as_bytes());
+        }
+        let lines = recent.lines();
+        assert_eq!(lines.len(), RECENT_LINES);
+        assert_eq!(lines[0], "line 10\n");
+        assert_eq!(
+            lines.last().unwrap(),
+            &format!("line {}\n", RECENT_LINES + 9)
+        );
+    }
+}
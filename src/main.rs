@@ -26,10 +26,12 @@ mod args;
 mod buffers;
 mod config;
 mod control;
+mod crash;
 mod disk;
 mod handle;
 mod init;
 mod rpc;
+mod setup;
 mod socket;
 mod stat;
 mod throttle;
@@ -50,6 +52,8 @@ pub use crate::protocol::UT_PEX_ID;
 pub const THROT_TOKS: usize = 2 * 1024 * 1024;
 
 pub static SHUTDOWN: atomic::AtomicBool = atomic::AtomicBool::new(false);
+/// Set on SIGHUP, when the config file should be reloaded
+pub static RELOAD: atomic::AtomicBool = atomic::AtomicBool::new(false);
 
 lazy_static! {
     pub static ref PEER_ID: [u8; 20] = {
@@ -72,7 +76,17 @@ lazy_static! {
 
 fn main() {
     let args = args::args();
+    if !args.print_connection {
+        setup::first_run(&args);
+    }
     let config = Arc::new(config::Config::load());
+    if args.print_connection {
+        setup::print_connection(&config.rpc);
+        process::exit(0);
+    }
+    if args.setup_cli {
+        setup::setup_cli(&config.rpc);
+    }
     match init::init(args) {
         Ok(()) => {}
         Err(()) => {
@@ -1,11 +1,14 @@
 use std::io::{self, Write};
+use std::net::IpAddr;
 use std::sync::Arc;
-use std::{mem, result, str, time};
+use std::time::{Duration, Instant};
+use std::{mem, result, str};
 
 use base64::prelude::{BASE64_STANDARD, Engine};
 use sstream::SStream;
 use url::Url;
 
+use super::deflate::{self, Deflate};
 use super::proto::message::{SMessage, Version};
 use super::proto::ws::{Frame, Message, Opcode};
 use super::reader::Reader;
@@ -21,37 +24,101 @@ pub struct Client {
     r: Reader,
     w: Writer,
     buf: FragBuf,
-    last_action: time::Instant,
+    heartbeat: Heartbeat,
+    /// Set if permessage-deflate was negotiated
+    deflate: Option<Deflate>,
 }
 
 pub struct Incoming {
     config: Arc<Config>,
     pub conn: SStream,
+    pub ip: IpAddr,
     key: Option<String>,
+    /// Minor RPC version advertised in the upgrade request
+    pub minor: u16,
+    /// Username given with basic auth in the upgrade request
+    user: Option<String>,
+    deflate: Option<deflate::Params>,
     buf: [u8; 1024],
     pos: usize,
-    last_action: time::Instant,
+    last_action: Instant,
 }
 
 pub enum IncomingStatus {
     Incomplete,
     Upgrade,
-    Transfer { data: Vec<u8>, token: String },
-    DL { id: String, range: Option<String> },
+    Transfer {
+        data: Vec<u8>,
+        token: String,
+    },
+    DL {
+        id: String,
+        range: Option<String>,
+    },
+    /// A password or download token was wrong, and the connection has been answered
+    Unauthorized,
 }
 
+/// A data message being reassembled, and whether it's compressed.
 enum FragBuf {
     None,
-    Text(Vec<u8>),
-    Binary(Vec<u8>),
+    Text(Vec<u8>, bool),
+    Binary(Vec<u8>, bool),
 }
 
-const CONN_TIMEOUT: u64 = 20;
-const CONN_PING: u64 = 15;
+/// Keeps track of when a client was last heard from, to ping it once it's been quiet
+/// for a while and drop it if that goes unanswered.
+struct Heartbeat {
+    interval: Duration,
+    timeout: Duration,
+    last_seen: Instant,
+    /// When the outstanding ping was sent
+    pinged: Option<Instant>,
+    seq: u32,
+}
+
+impl Heartbeat {
+    fn new(config: &RpcConfig, now: Instant) -> Heartbeat {
+        Heartbeat {
+            interval: Duration::from_secs(config.ping_interval),
+            timeout: Duration::from_secs(config.conn_timeout),
+            last_seen: now,
+            pinged: None,
+            seq: 0,
+        }
+    }
+
+    fn seen(&mut self, now: Instant) {
+        self.last_seen = now;
+        self.pinged = None;
+    }
+
+    fn should_ping(&self, now: Instant) -> bool {
+        self.pinged.is_none() && now.saturating_duration_since(self.last_seen) >= self.interval
+    }
+
+    /// Marks a ping as sent, returning its payload.
+    fn ping(&mut self, now: Instant) -> Vec<u8> {
+        self.pinged = Some(now);
+        self.seq = self.seq.wrapping_add(1);
+        self.seq.to_be_bytes().to_vec()
+    }
+
+    /// Whether the client has been quiet for the full timeout. A ping sent late, e.g.
+    /// because the cleanup timer fired just before the ping interval passed, is still
+    /// given as long to be answered as one sent on time.
+    fn is_dead(&self, now: Instant) -> bool {
+        let grace = self.timeout.saturating_sub(self.interval);
+        now.saturating_duration_since(self.last_seen) >= self.timeout
+            && self
+                .pinged
+                .is_some_and(|at| now.saturating_duration_since(at) >= grace)
+    }
+}
 
 impl Client {
     pub fn read(&mut self) -> Result<Option<Frame>> {
-        self.last_action = time::Instant::now();
+        self.heartbeat.seen(Instant::now());
         loop {
             match self.read_frame()? {
                 Ok(f) => return Ok(Some(f)),
@@ -75,7 +142,11 @@ impl Client {
         if m.opcode().is_other() {
             return Err(Error::BadPayload("Non standard opcodes unsupported!"));
         }
-        if m.extensions() {
+        // RSV1 marks the first frame of a compressed message, once that's negotiated
+        let compressed = Deflate::compressed(&m)
+            && self.deflate.is_some()
+            && matches!(m.opcode(), Opcode::Text | Opcode::Binary);
+        if m.extensions() && !(compressed && m.header & 0x30 == 0) {
             return Err(Error::BadPayload("Connection should not contain RSV bits!"));
         }
         match m.opcode() {
@@ -84,7 +155,7 @@ impl Client {
                 return Err(Error::Complete);
             }
             Opcode::Text | Opcode::Binary | Opcode::Continuation => {
-                if let Some(f) = self.buf.process(m)? {
+                if let Some(f) = self.buf.process(m, self.deflate.as_mut())? {
                     #[cfg(feature = "autobahn")]
                     self.send(f)?;
                     #[cfg(not(feature = "autobahn"))]
@@ -95,7 +166,7 @@ impl Client {
                 self.send_msg(Message::pong(m.data))?;
             }
             Opcode::Pong => {
-                self.last_action = time::Instant::now();
+                self.heartbeat.seen(Instant::now());
             }
             _ => {}
         }
@@ -107,7 +178,11 @@ impl Client {
     }
 
     pub fn send(&mut self, f: Frame) -> Result<()> {
-        self.send_msg(f.into())
+        let mut msg = f.into();
+        if let Some(d) = self.deflate.as_mut() {
+            d.compress_msg(&mut msg);
+        }
+        self.send_msg(msg)
     }
 
     fn send_msg(&mut self, msg: Message) -> Result<()> {
@@ -115,12 +190,19 @@ impl Client {
         self.write()
     }
 
-    pub fn timed_out(&mut self) -> bool {
-        if self.last_action.elapsed().as_secs() > CONN_TIMEOUT {
-            return true;
-        }
-        self.last_action.elapsed().as_secs() > CONN_PING
-            && self.send_msg(Message::ping(vec![0xDE, 0xAD])).is_err()
+    /// Whether the client has been quiet long enough to be pinged, and hasn't been yet.
+    pub fn should_ping(&self, now: Instant) -> bool {
+        self.heartbeat.should_ping(now)
+    }
+
+    pub fn ping(&mut self, now: Instant) -> Result<()> {
+        let payload = self.heartbeat.ping(now);
+        self.send_msg(Message::ping(payload))
+    }
+
+    /// Whether the client has gone without answering a ping for too long.
+    pub fn is_dead(&self, now: Instant) -> bool {
+        self.heartbeat.is_dead(now)
     }
 }
 
@@ -135,12 +217,15 @@ impl From<Incoming> for Client {
         let magic = incoming.key.unwrap() + "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
         let digest = sha1_hash(magic.as_bytes());
         let accept = BASE64_STANDARD.encode(digest.as_ref());
-        let lines = [
+        let mut lines = vec![
             "HTTP/1.1 101 Switching Protocols".into(),
             "Connection: upgrade".into(),
             "Upgrade: websocket".into(),
             format!("Sec-WebSocket-Accept: {accept}"),
         ];
+        if let Some(params) = incoming.deflate {
+            lines.push(format!("Sec-WebSocket-Extensions: {}", params.response()));
+        }
         let data = lines.join("\r\n") + "\r\n\r\n";
         // Ignore error, it'll pop up again anyways
         incoming.conn.write_all(data.as_bytes()).ok();
@@ -149,8 +234,9 @@ impl From<Incoming> for Client {
             r: Reader::new(),
             w: Writer::new(),
             buf: FragBuf::None,
+            heartbeat: Heartbeat::new(&incoming.config.rpc, Instant::now()),
+            deflate: incoming.deflate.map(Deflate::new),
             conn: incoming.conn,
-            last_action: time::Instant::now(),
         };
 
         c.send(Frame::Text(
@@ -168,21 +254,33 @@ impl From<Incoming> for SStream {
 }
 
 impl Incoming {
-    pub fn new(config: Arc<Config>, conn: SStream) -> Incoming {
+    pub fn new(config: Arc<Config>, conn: SStream, ip: IpAddr) -> Incoming {
         Incoming {
             config,
             conn,
+            ip,
             buf: [0; 1024],
             pos: 0,
-            last_action: time::Instant::now(),
+            last_action: Instant::now(),
             key: None,
+            minor: 0,
+            user: None,
+            deflate: None,
+        }
+    }
+
+    /// Who the client is, as recorded in torrent work logs.
+    pub fn identity(&self) -> String {
+        match &self.user {
+            Some(user) => format!("{user}@{}", self.ip),
+            None => self.ip.to_string(),
         }
     }
 
     /// Result indicates if the Incoming connection is
     /// valid to be upgraded into a Client
     pub fn readable(&mut self) -> io::Result<IncomingStatus> {
-        self.last_action = time::Instant::now();
+        self.last_action = Instant::now();
         loop {
             match aread(&mut self.buf[self.pos..], &mut self.conn) {
                 // TODO: Consider more
@@ -208,7 +306,7 @@ impl Incoming {
     }
 
     pub fn timed_out(&self) -> bool {
-        self.last_action.elapsed().as_secs() > CONN_TIMEOUT
+        self.last_action.elapsed() >= Duration::from_secs(self.config.rpc.conn_timeout)
     }
 
     fn process_incoming(&mut self) -> io::Result<Option<IncomingStatus>> {
@@ -224,25 +322,34 @@ impl Incoming {
                 match validate_upgrade(&self.config.rpc, &req) {
                     Ok(k) => {
                         self.key = Some(k);
+                        self.minor = client_minor(&req);
+                        self.user = client_user(&req);
+                        self.deflate = client_deflate(&req);
                         return Ok(Some(IncomingStatus::Upgrade));
                     }
                     Err(true) => {
                         self.conn.write_all(&UNAUTH_HTTP_RESP).ok();
-                        return Err(io::ErrorKind::InvalidData.into());
+                        return Ok(Some(IncomingStatus::Unauthorized));
                     }
                     Err(false) => {}
                 }
                 if let Some(token) = validate_tx(&req) {
-                    Ok(Some(IncomingStatus::Transfer {
+                    return Ok(Some(IncomingStatus::Transfer {
                         data: self.buf[idx..self.pos].to_owned(),
                         token,
-                    }))
-                } else if let Some((id, range)) = validate_dl(&self.config.rpc, &req) {
-                    Ok(Some(IncomingStatus::DL { id, range }))
-                } else {
-                    // Ignore error, we're DCing anyways
-                    self.conn.write_all(&EMPTY_HTTP_RESP).ok();
-                    Err(io::ErrorKind::InvalidData.into())
+                    }));
+                }
+                match validate_dl(&self.config.rpc, &req) {
+                    Ok((id, range)) => Ok(Some(IncomingStatus::DL { id, range })),
+                    Err(true) => {
+                        self.conn.write_all(&EMPTY_HTTP_RESP).ok();
+                        Ok(Some(IncomingStatus::Unauthorized))
+                    }
+                    Err(false) => {
+                        // Ignore error, we're DCing anyways
+                        self.conn.write_all(&EMPTY_HTTP_RESP).ok();
+                        Err(io::ErrorKind::InvalidData.into())
+                    }
                 }
             }
             Err(_) => Err(io::ErrorKind::InvalidData.into()),
@@ -251,88 +358,84 @@ impl Incoming {
 }
 
 impl FragBuf {
-    fn process(&mut self, msg: Message) -> Result<Option<Frame>> {
+    fn process(&mut self, msg: Message, deflate: Option<&mut Deflate>) -> Result<Option<Frame>> {
         let fin = msg.fin();
+        let compressed = Deflate::compressed(&msg);
         let s = mem::replace(self, FragBuf::None);
         *self = match (s, msg.opcode()) {
-            (FragBuf::None, Opcode::Text) => FragBuf::Text(msg.data),
-            (FragBuf::None, Opcode::Binary) => FragBuf::Binary(msg.data),
+            (FragBuf::None, Opcode::Text) => FragBuf::Text(msg.data, compressed),
+            (FragBuf::None, Opcode::Binary) => FragBuf::Binary(msg.data, compressed),
             (FragBuf::None, Opcode::Continuation) => {
                 return Err(Error::BadPayload("Invalid continuation frame"));
             }
-            (FragBuf::Text(mut b), Opcode::Continuation) => {
+            (FragBuf::Text(mut b, c), Opcode::Continuation) => {
                 b.extend(msg.data);
-                FragBuf::Text(b)
+                FragBuf::Text(b, c)
             }
-            (FragBuf::Binary(mut b), Opcode::Continuation) => {
+            (FragBuf::Binary(mut b, c), Opcode::Continuation) => {
                 b.extend(msg.data);
-                FragBuf::Binary(b)
+                FragBuf::Binary(b, c)
             }
-            (FragBuf::Text(_), Opcode::Text)
-            | (FragBuf::Text(_), Opcode::Binary)
-            | (FragBuf::Binary(_), Opcode::Text)
-            | (FragBuf::Binary(_), Opcode::Binary) => {
+            (FragBuf::Text(..), Opcode::Text)
+            | (FragBuf::Text(..), Opcode::Binary)
+            | (FragBuf::Binary(..), Opcode::Text)
+            | (FragBuf::Binary(..), Opcode::Binary) => {
                 return Err(Error::BadPayload("Expected continuation of data frame"));
             }
             _ => return Ok(None),
         };
-        if fin {
-            match mem::replace(self, FragBuf::None) {
-                FragBuf::Text(b) => Ok(Some(Frame::Text(
-                    String::from_utf8(b).map_err(Error::InvalidUtf8)?,
-                ))),
-                FragBuf::Binary(b) => Ok(Some(Frame::Binary(b))),
-                FragBuf::None => unreachable!(),
-            }
-        } else {
-            Ok(None)
+        if !fin {
+            return Ok(None);
+        }
+        let inflate = |b, c| match deflate {
+            Some(d) if c => d.decompress(b),
+            _ => Ok(b),
+        };
+        match mem::replace(self, FragBuf::None) {
+            FragBuf::Text(b, c) => Ok(Some(Frame::Text(
+                String::from_utf8(inflate(b, c)?).map_err(Error::InvalidUtf8)?,
+            ))),
+            FragBuf::Binary(b, c) => Ok(Some(Frame::Binary(inflate(b, c)?))),
+            FragBuf::None => unreachable!(),
         }
     }
 }
 
+/// Parses a download request, the error being true if it was refused for a bad token.
 fn validate_dl(
     config: &RpcConfig,
     req: &httparse::Request<'_, '_>,
-) -> Option<(String, Option<String>)> {
-    req.path
+) -> result::Result<(String, Option<String>), bool> {
+    let url = req
+        .path
         .and_then(|path| Url::parse(&format!("http://localhost{path}")).ok())
-        .and_then(|url| {
-            let id = if url.path().contains("/dl/") {
-                url.path_segments()
-                    .unwrap()
-                    .next_back()
-                    .map(|v| v.to_owned())
-            } else {
-                return None;
-            };
-            if config.auth {
-                let pw = url
-                    .query_pairs()
-                    .find(|(k, _)| k == "token")
-                    .map(|(_, v)| format!("{v}"))
-                    .and_then(|p| BASE64_STANDARD.decode(&p).ok())
-                    .map(|p| {
-                        p.as_ref()
-                            == sha1_hash(
-                                format!("{}{}", id.as_deref().unwrap_or(""), *DL_TOKEN).as_bytes(),
-                            )
-                    })
-                    .unwrap_or(false);
-                if !pw {
-                    return None;
-                }
-            }
-            id
-        })
-        .map(|id| {
-            let range = req
-                .headers
-                .iter()
-                .find(|header| header.name.to_lowercase() == "range")
-                .and_then(|header| str::from_utf8(header.value).ok())
-                .map(str::to_owned);
-            (id, range)
-        })
+        .filter(|url| url.path().contains("/dl/"))
+        .ok_or(false)?;
+    let id = url
+        .path_segments()
+        .unwrap()
+        .next_back()
+        .map(|v| v.to_owned())
+        .ok_or(false)?;
+    if config.auth {
+        let valid = url
+            .query_pairs()
+            .find(|(k, _)| k == "token")
+            .map(|(_, v)| format!("{v}"))
+            .and_then(|p| BASE64_STANDARD.decode(&p).ok())
+            .map(|p| p.as_ref() == sha1_hash(format!("{}{}", id, *DL_TOKEN).as_bytes()))
+            .unwrap_or(false);
+        if !valid {
+            return Err(true);
+        }
+    }
+    let range = req
+        .headers
+        .iter()
+        .find(|header| header.name.to_lowercase() == "range")
+        .and_then(|header| str::from_utf8(header.value).ok())
+        .map(str::to_owned);
+    Ok((id, range))
 }
 
 // TODO: We're not really checking HTTP semantics here, might be worth
@@ -353,6 +456,44 @@ fn validate_tx(req: &httparse::Request<'_, '_>) -> Option<String> {
     None
 }
 
+/// The permessage-deflate parameters to accept, if the client offered any we support.
+fn client_deflate(req: &httparse::Request<'_, '_>) -> Option<deflate::Params> {
+    let offers: Vec<_> = req
+        .headers
+        .iter()
+        .filter(|h| h.name.eq_ignore_ascii_case("sec-websocket-extensions"))
+        .filter_map(|h| str::from_utf8(h.value).ok())
+        .collect();
+    deflate::negotiate(&offers.join(","))
+}
+
+/// The minor RPC version a client advertises through the minor query parameter, which
+/// predates the parameter if it's absent.
+fn client_minor(req: &httparse::Request<'_, '_>) -> u16 {
+    req.path
+        .and_then(|path| Url::parse(&format!("http://localhost{path}")).ok())
+        .and_then(|url| {
+            url.query_pairs()
+                .find(|(k, _)| k == "minor")
+                .and_then(|(_, v)| v.parse().ok())
+        })
+        .unwrap_or(0)
+}
+
+fn client_user(req: &httparse::Request<'_, '_>) -> Option<String> {
+    let header = req
+        .headers
+        .iter()
+        .find(|h| h.name.eq_ignore_ascii_case("authorization"))?;
+    let value = str::from_utf8(header.value).ok()?;
+    if !value.to_lowercase().starts_with("basic ") {
+        return None;
+    }
+    let auth = String::from_utf8(BASE64_STANDARD.decode(&value[6..]).ok()?).ok()?;
+    let (user, _) = auth.split_once(':')?;
+    (!user.is_empty()).then(|| user.to_owned())
+}
+
 fn validate_upgrade(
     config: &RpcConfig,
     req: &httparse::Request<'_, '_>,
@@ -428,3 +569,106 @@ fn validate_upgrade(
         Err(false)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use crate::config::validate_rpc;
+
+    #[test]
+    fn test_heartbeat() {
+        let start = Instant::now();
+        let at = |s: u64| start + Duration::from_secs(s);
+        let mut hb = Heartbeat::new(&RpcConfig::default(), start);
+        assert!(!hb.should_ping(at(14)));
+        assert!(hb.should_ping(at(15)));
+        let first = hb.ping(at(15));
+        assert!(!hb.should_ping(at(16)));
+        assert_ne!(hb.ping(at(16)), first);
+
+        // A client which keeps answering is never dropped
+        let mut hb = Heartbeat::new(&RpcConfig::default(), start);
+        for s in (0..600).step_by(2) {
+            if hb.should_ping(at(s)) {
+                hb.ping(at(s));
+            } else if hb.pinged.is_some() {
+                hb.seen(at(s));
+            }
+            assert!(!hb.is_dead(at(s)));
+        }
+
+        // A late ping is still given the full grace period
+        let mut hb = Heartbeat::new(&RpcConfig::default(), start);
+        assert!(!hb.is_dead(at(19)));
+        hb.ping(at(19));
+        assert!(!hb.is_dead(at(23)));
+        assert!(hb.is_dead(at(24)));
+
+        let config = RpcConfig {
+            ping_interval: 5,
+            conn_timeout: 8,
+            ..Default::default()
+        };
+        let mut hb = Heartbeat::new(&config, start);
+        assert!(hb.should_ping(at(5)));
+        hb.ping(at(5));
+        assert!(!hb.is_dead(at(7)));
+        assert!(hb.is_dead(at(8)));
+
+        assert!(validate_rpc(&config).is_ok());
+        let config = RpcConfig {
+            ping_interval: 20,
+            ..Default::default()
+        };
+        assert!(validate_rpc(&config).is_err());
+    }
+
+    /// Encodes a masked client frame.
+    fn frame(header: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        let mut data = vec![header];
+        if payload.len() < 126 {
+            data.push(0x80 | payload.len() as u8);
+        } else {
+            data.push(0x80 | 126);
+            data.extend((payload.len() as u16).to_be_bytes());
+        }
+        data.extend(mask);
+        data.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        data
+    }
+
+    #[test]
+    fn test_compressed_frames() {
+        let params = deflate::negotiate("permessage-deflate").unwrap();
+        let (mut server, mut client) = (Deflate::new(params), Deflate::new(params));
+        let text = "{\"type\":\"UPDATE_RESOURCES\",\"resources\":[]}".repeat(20);
+        let mut msg = Message::text(text.clone());
+        client.compress_msg(&mut msg);
+        assert!(Deflate::compressed(&msg) && msg.data.len() < text.len());
+
+        // Once whole, and split over a continuation frame
+        let (head, tail) = msg.data.split_at(msg.data.len() / 2);
+        let mut wire = frame(msg.header, &msg.data);
+        wire.extend(frame(0x40 | 0x01, head));
+        wire.extend(frame(0x80, tail));
+        // Later messages build on the earlier ones' context
+        let mut msg = Message::text(text.clone());
+        client.compress_msg(&mut msg);
+        wire.extend(frame(msg.header, &msg.data));
+
+        let mut r = Reader::new();
+        let mut buf = FragBuf::None;
+        let mut input = &wire[..];
+        let mut frames = Vec::new();
+        while !input.is_empty() {
+            let m = r.read(&mut input).unwrap().unwrap();
+            if let Some(Frame::Text(t)) = buf.process(m, Some(&mut server)).unwrap() {
+                frames.push(t);
+            }
+        }
+        assert_eq!(frames, [text.clone(), text.clone(), text]);
+    }
+}
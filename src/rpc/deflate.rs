@@ -0,0 +1,201 @@
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+
+use super::proto::ws::{MAX_MSG_BYTES, Message};
+use super::{Error, Result};
+
+/// Trailer of a sync flush, which RFC 7692 drops from compressed messages
+const TRAILER: [u8; 4] = [0x00, 0x00, 0xFF, 0xFF];
+/// Messages shorter than this aren't worth compressing
+const MIN_COMPRESS_LEN: usize = 256;
+const RSV1: u8 = 0x40;
+
+/// Parameters of an accepted permessage-deflate offer.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Params {
+    server_no_context_takeover: bool,
+    client_no_context_takeover: bool,
+}
+
+/// Picks the first permessage-deflate offer in a `Sec-WebSocket-Extensions` header we
+/// can honour. Offers restricting our window below the 32KiB flate2 always uses are
+/// passed over.
+pub fn negotiate(header: &str) -> Option<Params> {
+    header.split(',').find_map(|offer| {
+        let mut parts = offer.split(';').map(str::trim);
+        if parts.next() != Some("permessage-deflate") {
+            return None;
+        }
+        let mut params = Params::default();
+        for param in parts {
+            let (name, value) = match param.split_once('=') {
+                Some((n, v)) => (n.trim(), Some(v.trim().trim_matches('"'))),
+                None => (param, None),
+            };
+            match (name, value) {
+                ("server_no_context_takeover", None) => params.server_no_context_takeover = true,
+                ("client_no_context_takeover", None) => params.client_no_context_takeover = true,
+                ("server_max_window_bits", Some("15")) => {}
+                // We always inflate with the largest window, so any client window works
+                ("client_max_window_bits", _) => {}
+                _ => return None,
+            }
+        }
+        Some(params)
+    })
+}
+
+impl Params {
+    /// The `Sec-WebSocket-Extensions` value accepting the offer.
+    pub fn response(&self) -> String {
+        let mut resp = "permessage-deflate".to_owned();
+        if self.server_no_context_takeover {
+            resp.push_str("; server_no_context_takeover");
+        }
+        if self.client_no_context_takeover {
+            resp.push_str("; client_no_context_takeover");
+        }
+        resp
+    }
+}
+
+/// Compression state of a connection which negotiated permessage-deflate.
+pub struct Deflate {
+    params: Params,
+    compress: Compress,
+    decompress: Decompress,
+}
+
+impl Deflate {
+    pub fn new(params: Params) -> Deflate {
+        Deflate {
+            params,
+            compress: Compress::new(Compression::default(), false),
+            decompress: Decompress::new(false),
+        }
+    }
+
+    /// Whether a frame's RSV1 bit marks it as compressed.
+    pub fn compressed(msg: &Message) -> bool {
+        msg.header & RSV1 != 0
+    }
+
+    /// Compresses a text message in place if it's long enough to benefit.
+    pub fn compress_msg(&mut self, msg: &mut Message) {
+        if msg.data.len() < MIN_COMPRESS_LEN || !msg.fin() {
+            return;
+        }
+        msg.data = self.compress(&msg.data);
+        msg.len = msg.data.len() as u64;
+        msg.header |= RSV1;
+    }
+
+    fn compress(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len() / 2 + 64);
+        let start = self.compress.total_in();
+        loop {
+            let consumed = (self.compress.total_in() - start) as usize;
+            self.compress
+                .compress_vec(&data[consumed..], &mut out, FlushCompress::Sync)
+                .expect("deflate can't fail on valid input");
+            let consumed = (self.compress.total_in() - start) as usize;
+            // The flush is done once it stops filling the buffer
+            if consumed == data.len() && out.len() < out.capacity() {
+                break;
+            }
+            out.reserve(out.capacity());
+        }
+        if out.ends_with(&TRAILER) {
+            out.truncate(out.len() - TRAILER.len());
+        }
+        if self.params.server_no_context_takeover {
+            self.compress.reset();
+        }
+        out
+    }
+
+    /// Inflates a reassembled message, refusing to grow it past the message size limit.
+    pub fn decompress(&mut self, mut data: Vec<u8>) -> Result<Vec<u8>> {
+        data.extend_from_slice(&TRAILER);
+        let mut out = Vec::with_capacity(data.len() * 4);
+        let start = self.decompress.total_in();
+        loop {
+            let consumed = (self.decompress.total_in() - start) as usize;
+            let produced = out.len();
+            let status = self
+                .decompress
+                .decompress_vec(&data[consumed..], &mut out, FlushDecompress::Sync)
+                .map_err(|_| Error::BadPayload("Invalid compressed payload!"))?;
+            let consumed = (self.decompress.total_in() - start) as usize;
+            if out.len() as u64 > MAX_MSG_BYTES {
+                return Err(Error::BadPayload("Decompressed message too long!"));
+            }
+            if status == Status::StreamEnd || (consumed == data.len() && out.len() < out.capacity())
+            {
+                break;
+            }
+            if out.len() == produced && out.len() < out.capacity() {
+                return Err(Error::BadPayload("Truncated compressed payload!"));
+            }
+            out.reserve(out.capacity());
+        }
+        if self.params.client_no_context_takeover {
+            self.decompress.reset(false);
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(negotiate("x-webkit-deflate-frame"), None);
+        let p = negotiate("permessage-deflate; client_max_window_bits").unwrap();
+        assert_eq!(p.response(), "permessage-deflate");
+        // A window we can't honour skips to the next offer
+        let p = negotiate(
+            "permessage-deflate; server_max_window_bits=10, \
+             permessage-deflate; server_no_context_takeover; client_no_context_takeover",
+        )
+        .unwrap();
+        assert_eq!(
+            p.response(),
+            "permessage-deflate; server_no_context_takeover; client_no_context_takeover"
+        );
+        assert_eq!(
+            negotiate("permessage-deflate; server_max_window_bits=10"),
+            None
+        );
+        assert_eq!(negotiate("permessage-deflate; mystery"), None);
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        for params in [
+            Params::default(),
+            negotiate("permessage-deflate; server_no_context_takeover; client_no_context_takeover")
+                .unwrap(),
+        ] {
+            let (mut server, mut client) = (Deflate::new(params), Deflate::new(params));
+            let text = "{\"type\":\"UPDATE_RESOURCES\"}".repeat(100);
+            // Later messages can refer back to earlier ones unless context takeover is off
+            for _ in 0..3 {
+                let data = server.compress(text.as_bytes());
+                assert!(data.len() < text.len() / 4);
+                assert_eq!(client.decompress(data).unwrap(), text.as_bytes());
+            }
+        }
+
+        // RFC 7692 7.2.3.1: "Hello" compressed in a single block
+        let mut d = Deflate::new(Params::default());
+        let hello = vec![0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00];
+        assert_eq!(d.decompress(hello).unwrap(), b"Hello");
+        assert!(d.decompress(vec![0xff; 8]).is_err());
+
+        let bomb =
+            Deflate::new(Params::default()).compress(&vec![b'a'; MAX_MSG_BYTES as usize + 1]);
+        assert!(Deflate::new(Params::default()).decompress(bomb).is_err());
+    }
+}
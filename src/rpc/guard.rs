@@ -0,0 +1,207 @@
+use std::net::IpAddr;
+
+use chrono::{DateTime, Duration, Utc};
+use ip_network::IpNetwork;
+
+use crate::config::RpcConfig;
+use crate::util::FHashMap;
+
+/// Why a connection was turned away at accept time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rejection {
+    NotAllowed,
+    RateLimited,
+    Banned,
+}
+
+/// Screens connections to the RPC listener by source address: an optional allowlist,
+/// a cap on new connections per address and window, and temporary bans for addresses
+/// which keep failing auth. Local clients aren't rate limited, as UIs and scripts on the
+/// same machine commonly open a connection per request.
+pub struct Guard {
+    allow: Vec<IpNetwork>,
+    conn_limit: u32,
+    conn_window: Duration,
+    ban_after: u32,
+    ban_duration: Duration,
+    /// Start of the current window and connections accepted in it
+    conns: FHashMap<IpAddr, (DateTime<Utc>, u32)>,
+    /// Failed attempts and the time of the latest one
+    failures: FHashMap<IpAddr, (u32, DateTime<Utc>)>,
+    bans: FHashMap<IpAddr, DateTime<Utc>>,
+}
+
+impl Guard {
+    pub fn new(config: &RpcConfig) -> Guard {
+        Guard {
+            allow: config.allow.clone(),
+            conn_limit: config.conn_limit,
+            conn_window: Duration::seconds(config.conn_window as i64),
+            ban_after: config.ban_after,
+            ban_duration: Duration::seconds(config.ban_duration as i64),
+            conns: FHashMap::default(),
+            failures: FHashMap::default(),
+            bans: FHashMap::default(),
+        }
+    }
+
+    /// Checks a newly accepted connection from `ip`, counting it against the address's
+    /// rate limit if it's let through.
+    pub fn accept(&mut self, ip: IpAddr, now: DateTime<Utc>) -> Result<(), Rejection> {
+        if !self.allow.is_empty() && !self.allow.iter().any(|net| net.contains(ip)) {
+            return Err(Rejection::NotAllowed);
+        }
+        if self.bans.get(&ip).is_some_and(|&until| until > now) {
+            return Err(Rejection::Banned);
+        }
+        if self.conn_limit == 0 || ip.to_canonical().is_loopback() {
+            return Ok(());
+        }
+        let window = self.conns.entry(ip).or_insert((now, 0));
+        if now - window.0 >= self.conn_window {
+            *window = (now, 0);
+        }
+        if window.1 >= self.conn_limit {
+            return Err(Rejection::RateLimited);
+        }
+        window.1 += 1;
+        Ok(())
+    }
+
+    /// Records a failed auth attempt, returning true if it got `ip` banned.
+    pub fn auth_failed(&mut self, ip: IpAddr, now: DateTime<Utc>) -> bool {
+        if self.ban_after == 0 {
+            return false;
+        }
+        let failures = self.failures.entry(ip).or_insert((0, now));
+        // Failures are forgotten after a ban's worth of good behaviour
+        if now - failures.1 >= self.ban_duration {
+            failures.0 = 0;
+        }
+        *failures = (failures.0 + 1, now);
+        if failures.0 < self.ban_after {
+            return false;
+        }
+        self.failures.remove(&ip);
+        self.bans.insert(ip, now + self.ban_duration);
+        true
+    }
+
+    pub fn auth_succeeded(&mut self, ip: IpAddr) {
+        self.failures.remove(&ip);
+    }
+
+    /// Banned addresses and when their bans expire.
+    pub fn bans(&self, now: DateTime<Utc>) -> Vec<(IpAddr, DateTime<Utc>)> {
+        let mut bans: Vec<_> = self
+            .bans
+            .iter()
+            .filter(|&(_, &until)| until > now)
+            .map(|(&ip, &until)| (ip, until))
+            .collect();
+        bans.sort();
+        bans
+    }
+
+    /// Drops expired bans and state which no longer affects any address.
+    pub fn cleanup(&mut self, now: DateTime<Utc>) {
+        let (window, duration) = (self.conn_window, self.ban_duration);
+        self.bans.retain(|_, until| *until > now);
+        self.conns.retain(|_, (start, _)| now - *start < window);
+        self.failures.retain(|_, (_, last)| now - *last < duration);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use chrono::{Duration, Utc};
+
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn guard(allow: &[&str]) -> Guard {
+        let config = RpcConfig {
+            allow: allow.iter().map(|n| n.parse().unwrap()).collect(),
+            ..Default::default()
+        };
+        Guard::new(&config)
+    }
+
+    #[test]
+    fn test_allowlist() {
+        let now = Utc::now();
+        let mut g = guard(&["127.0.0.0/8", "192.168.1.0/24", "fd00::/8"]);
+        assert_eq!(g.accept(ip("127.0.0.1"), now), Ok(()));
+        assert_eq!(g.accept(ip("192.168.1.20"), now), Ok(()));
+        assert_eq!(g.accept(ip("fd12::1"), now), Ok(()));
+        assert_eq!(g.accept(ip("192.168.2.1"), now), Err(Rejection::NotAllowed));
+        assert_eq!(g.accept(ip("2001:db8::1"), now), Err(Rejection::NotAllowed));
+
+        let mut g = guard(&[]);
+        assert_eq!(g.accept(ip("203.0.113.9"), now), Ok(()));
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let now = Utc::now();
+        let mut g = guard(&[]);
+        let (a, b) = (ip("10.0.0.1"), ip("10.0.0.2"));
+        for _ in 0..10 {
+            assert_eq!(g.accept(a, now), Ok(()));
+        }
+        assert_eq!(
+            g.accept(a, now + Duration::seconds(9)),
+            Err(Rejection::RateLimited)
+        );
+        // Other addresses have their own budget
+        assert_eq!(g.accept(b, now), Ok(()));
+        assert_eq!(g.accept(a, now + Duration::seconds(10)), Ok(()));
+
+        g.cleanup(now + Duration::seconds(30));
+        assert!(g.conns.is_empty());
+
+        // Local clients aren't limited
+        for local in ["127.0.0.1", "::1", "::ffff:127.0.0.1"] {
+            for _ in 0..20 {
+                assert_eq!(g.accept(ip(local), now), Ok(()));
+            }
+        }
+        assert!(g.conns.is_empty());
+    }
+
+    #[test]
+    fn test_auth_ban() {
+        let now = Utc::now();
+        let at = |s| now + Duration::seconds(s);
+        let mut g = guard(&[]);
+        let (a, b) = (ip("10.0.0.1"), ip("10.0.0.2"));
+        for s in 0..4 {
+            assert!(!g.auth_failed(a, at(s)));
+        }
+        // A success wipes the slate clean
+        g.auth_succeeded(a);
+        for s in 0..4 {
+            assert!(!g.auth_failed(a, at(s)));
+        }
+        assert!(g.auth_failed(a, at(4)));
+        assert_eq!(g.accept(a, at(5)), Err(Rejection::Banned));
+        assert_eq!(g.accept(b, at(5)), Ok(()));
+        assert_eq!(g.bans(at(5)), [(a, at(604))]);
+
+        // The ban expires on its own
+        assert_eq!(g.accept(a, at(604)), Ok(()));
+        assert!(g.bans(at(604)).is_empty());
+        g.cleanup(at(604));
+        assert!(g.bans.is_empty());
+
+        // Failures spread out over a long time don't add up
+        for s in 0..10 {
+            assert!(!g.auth_failed(b, at(1000 + s * 600)));
+        }
+    }
+}
@@ -1,25 +1,29 @@
 mod client;
+mod deflate;
 mod errors;
-mod processor;
+mod guard;
+pub mod processor;
 pub mod proto;
 mod reader;
 mod transfer;
 mod writer;
 
 use std::io::Write;
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener};
 use std::sync::Arc;
+use std::time::Instant;
 use std::{io, result, str, thread};
 
-use http_range::HttpRange;
+use chrono::Utc;
 use rustls::pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject};
 use sstream::SStream;
 use url::Url;
 
 use self::client::{Client, Incoming, IncomingStatus};
 pub use self::errors::{Error, Result};
+use self::guard::Guard;
 use self::processor::{Processor, TransferKind};
-use self::proto::message::{self, SMessage};
+use self::proto::message::{self, CMessage, SMessage};
 pub use self::proto::resource;
 use self::proto::ws;
 use self::transfer::{TransferResult, Transfers};
@@ -30,7 +34,6 @@ use crate::handle;
 use crate::torrent;
 use crate::util::UHashMap;
 
-const POLL_INT_MS: usize = 1000;
 const CLEANUP_INT_MS: usize = 2000;
 
 lazy_static! {
@@ -65,14 +68,6 @@ lazy_static! {
         ];
         lines.join("\r\n").into_bytes()
     };
-    pub static ref BAD_HTTP_RANGE: Vec<u8> = {
-        let lines = [
-            format!("HTTP/1.1 {} {}", 416, "Requested Range Not Satisfiable"),
-            format!("Connection: {}", "Close"),
-            "\r\n".to_string(),
-        ];
-        lines.join("\r\n").into_bytes()
-    };
 }
 
 #[derive(Debug)]
@@ -91,6 +86,14 @@ pub enum CtlMessage {
         serial: u64,
     },
     Error {
+        code: message::ErrorCode,
+        reason: String,
+        client: usize,
+        serial: u64,
+    },
+    /// Adding a torrent failed, answered as ADD_TORRENT_FAILED to ADD_TORRENT requests
+    AddFailed {
+        error: message::AddError,
         reason: String,
         client: usize,
         serial: u64,
@@ -100,31 +103,75 @@ pub enum CtlMessage {
         client: usize,
         serial: u64,
     },
+    PathAnalysis {
+        client: usize,
+        analysis: message::PathAnalysis,
+    },
+    Metainfo {
+        client: usize,
+        metainfo: message::Metainfo,
+    },
+    DiskStats {
+        client: usize,
+        stats: message::DiskStats,
+    },
+    History {
+        client: usize,
+        history: message::History,
+    },
+    IpFilterUpdated {
+        client: usize,
+        serial: u64,
+        blocked: usize,
+    },
+    /// The control loop's current tick interval, reported in HEALTH
+    TickInterval(u64),
     Ping,
     Shutdown,
 }
 
 #[derive(Debug)]
 pub enum Message {
-    UpdateTorrent(resource::CResourceUpdate),
+    /// Messages which change a torrent carry `by`, the identity of the client recorded
+    /// in the torrent's work log
+    UpdateTorrent {
+        update: resource::CResourceUpdate,
+        by: String,
+    },
     UpdateServer {
         id: String,
         throttle_up: Option<Option<i64>>,
         throttle_down: Option<Option<i64>>,
+        permanent: bool,
     },
     UpdateFile {
         id: String,
         torrent_id: String,
         priority: u8,
+        by: String,
+    },
+    EditTracker {
+        id: String,
+        torrent_id: String,
+        url: Option<Url>,
+        headers: Option<Vec<String>>,
+        by: String,
     },
     RemoveTorrent {
         id: String,
         client: usize,
         serial: u64,
         artifacts: bool,
+        by: String,
+    },
+    Pause {
+        id: String,
+        by: String,
+    },
+    Resume {
+        id: String,
+        by: String,
     },
-    Pause(String),
-    Resume(String),
     Validate(Vec<String>),
     AddPeer {
         id: String,
@@ -143,6 +190,7 @@ pub enum Message {
         client: usize,
         serial: u64,
         tracker: Url,
+        by: String,
     },
     UpdateTracker {
         id: String,
@@ -153,6 +201,7 @@ pub enum Message {
         torrent_id: String,
         client: usize,
         serial: u64,
+        by: String,
     },
     Torrent {
         info: torrent::Info,
@@ -161,8 +210,36 @@ pub enum Message {
         path: Option<String>,
         start: bool,
         import: bool,
+        preset: torrent::Preset,
     },
     PurgeDNS,
+    ReannounceAll,
+    AnalyzePath {
+        path: String,
+        client: usize,
+        serial: u64,
+    },
+    GetMetainfo {
+        id: String,
+        client: usize,
+        serial: u64,
+    },
+    GetDiskStats {
+        client: usize,
+        serial: u64,
+    },
+    GetHistory {
+        id: String,
+        client: usize,
+        serial: u64,
+    },
+    UpdateIpFilter {
+        client: usize,
+        serial: u64,
+        action: message::IpFilterAction,
+        ranges: Vec<String>,
+        path: Option<String>,
+    },
 }
 
 #[allow(clippy::upper_case_acronyms)]
@@ -175,14 +252,16 @@ pub struct RPC {
     server_config: Option<Arc<rustls::ServerConfig>>,
     lid: usize,
     cleanup: usize,
+    guard: Guard,
     processor: Processor,
     transfers: Transfers,
     clients: UHashMap<Client>,
     incoming: UHashMap<Incoming>,
     disk: flume::Sender<disk::Request>,
+    tick_interval: u64,
 }
 
-fn load_certs<'a>(filename: &str) -> io::Result<Vec<CertificateDer<'a>>> {
+pub fn load_certs<'a>(filename: &str) -> io::Result<Vec<CertificateDer<'a>>> {
     let certs = CertificateDer::pem_file_iter(filename)
         .map_err(io::Error::other)?
         .collect::<result::Result<Vec<_>, _>>()
@@ -190,7 +269,7 @@ fn load_certs<'a>(filename: &str) -> io::Result<Vec<CertificateDer<'a>>> {
     Ok(certs)
 }
 
-fn load_private_key<'a>(filename: &str) -> io::Result<PrivateKeyDer<'a>> {
+pub fn load_private_key<'a>(filename: &str) -> io::Result<PrivateKeyDer<'a>> {
     let keys = PrivateKeyDer::pem_file_iter(filename)
         .map_err(io::Error::other)?
         .collect::<result::Result<Vec<_>, _>>()
@@ -260,11 +339,13 @@ impl RPC {
                 listener,
                 lid,
                 cleanup,
+                guard: Guard::new(&config.rpc),
                 clients: UHashMap::default(),
                 incoming: UHashMap::default(),
                 processor: Processor::new(config, db),
                 transfers: Transfers::new(),
                 server_config,
+                tick_interval: 0,
             }
             .run()
         })?;
@@ -274,7 +355,8 @@ impl RPC {
     pub fn run(&mut self) {
         debug!("Running RPC!");
         loop {
-            let res = match self.poll.wait(POLL_INT_MS) {
+            // Nothing is done periodically besides cleanup, whose timer wakes the poll
+            let res = match self.poll.wait(CLEANUP_INT_MS) {
                 Ok(res) => res,
                 Err(e) => {
                     error!("Failed to poll for events: {}", e);
@@ -302,6 +384,7 @@ impl RPC {
         while let Ok(m) = self.ch.recv() {
             match m {
                 CtlMessage::Ping => continue,
+                CtlMessage::TickInterval(ms) => self.tick_interval = ms,
                 CtlMessage::Shutdown => return true,
                 m => {
                     let msgs: Vec<_> = {
@@ -341,6 +424,7 @@ impl RPC {
                 serial,
                 start,
                 import,
+                preset,
             } => {
                 debug!("Got torrent via HTTP transfer!");
                 if self.reg.deregister(&conn).is_err() {
@@ -357,6 +441,7 @@ impl RPC {
                                     path,
                                     start,
                                     import,
+                                    preset,
                                     client,
                                     serial,
                                 })
@@ -370,10 +455,11 @@ impl RPC {
                             self.clients.get_mut(&client).map(|c| {
                                 c.send(ws::Frame::Text(
                                     serde_json::to_string(&SMessage::TransferFailed(
-                                        message::Error {
-                                            serial: Some(serial),
-                                            reason: format!("Invalid torrent file uploaded, {e}."),
-                                        },
+                                        message::Error::new(
+                                            Some(serial),
+                                            message::ErrorCode::TransferFailed,
+                                            format!("Invalid torrent file uploaded, {e}."),
+                                        ),
                                     ))
                                     .unwrap(),
                                 ))
@@ -384,12 +470,15 @@ impl RPC {
                         error!("Failed to decode BE data: {}!", e);
                         self.clients.get_mut(&client).map(|c| {
                             c.send(ws::Frame::Text(
-                                serde_json::to_string(&SMessage::TransferFailed(message::Error {
-                                    serial: Some(serial),
-                                    reason: format!(
-                                        "Invalid torrent file uploaded, bad bencoded data: {e}."
+                                serde_json::to_string(&SMessage::TransferFailed(
+                                    message::Error::new(
+                                        Some(serial),
+                                        message::ErrorCode::TransferFailed,
+                                        format!(
+                                            "Invalid torrent file uploaded, bad bencoded data: {e}."
+                                        ),
                                     ),
-                                }))
+                                ))
                                 .unwrap(),
                             ))
                         });
@@ -419,8 +508,13 @@ impl RPC {
     fn handle_accept(&mut self) {
         loop {
             match self.listener.accept() {
-                Ok((conn, ip)) => {
-                    debug!("Accepted new connection from {:?}!", ip);
+                Ok((conn, addr)) => {
+                    // Dropping the connection closes it before any TLS handshake
+                    if let Err(r) = self.guard.accept(addr.ip(), Utc::now()) {
+                        debug!("Rejected connection from {}: {:?}", addr, r);
+                        continue;
+                    }
+                    debug!("Accepted new connection from {:?}!", addr);
                     let id = self.reg.register(&conn, amy::Event::Both);
                     let conn = if let Some(server_config) = &self.server_config {
                         SStream::from_ssl(conn, server_config)
@@ -429,7 +523,7 @@ impl RPC {
                     };
                     if let (Ok(id), Ok(conn)) = (id, conn) {
                         self.incoming
-                            .insert(id, Incoming::new(self.config.clone(), conn));
+                            .insert(id, Incoming::new(self.config.clone(), conn, addr.ip()));
                     }
                 }
                 Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
@@ -447,6 +541,8 @@ impl RPC {
             match i.readable() {
                 Ok(IncomingStatus::Upgrade) => {
                     debug!("Succesfully upgraded conn");
+                    self.guard.auth_succeeded(i.ip);
+                    self.processor.add_client(id, i.minor, i.identity());
                     self.clients.insert(id, i.into());
                 }
                 Ok(IncomingStatus::Incomplete) => {
@@ -463,6 +559,7 @@ impl RPC {
                                 size,
                                 start,
                                 import,
+                                preset,
                             },
                         )) => {
                             debug!("Torrent transfer initiated");
@@ -476,6 +573,7 @@ impl RPC {
                                 size,
                                 start,
                                 import,
+                                preset,
                             );
                             // Since a succesful result means the buffer hasn't been flushed,
                             // immediatly attempt to handle the transfer as if it was ready
@@ -486,6 +584,7 @@ impl RPC {
                         }
                         None => {
                             error!("Transfer used invalid token");
+                            self.auth_failed(i.ip);
                             // TODO: Handle downloads and other uploads
                         }
                     }
@@ -499,24 +598,19 @@ impl RPC {
                             return;
                         }
 
-                        let ranges = match range.map(|r| HttpRange::parse(&r, size)) {
-                            Some(Ok(parsed_ranges)) => parsed_ranges,
-                            Some(Err(_)) => {
-                                debug!("Ranges {} invalid, stopping DL", id);
-                                conn.write_all(&BAD_HTTP_RANGE).ok();
-                                return;
-                            }
-                            None => vec![],
-                        };
                         debug!("Initiating DL");
                         self.disk
-                            .send(disk::Request::download(conn, ranges, path, size))
+                            .send(disk::Request::download(conn, range.as_deref(), path, size))
                             .ok();
                     } else {
                         debug!("ID {} invalid, stopping DL", id);
                         conn.write_all(&EMPTY_HTTP_RESP).ok();
                     }
                 }
+                Ok(IncomingStatus::Unauthorized) => {
+                    debug!("Incoming conn failed auth");
+                    self.auth_failed(i.ip);
+                }
                 Err(e) => {
                     debug!("Incoming ws upgrade failed: {}", e);
                 }
@@ -524,6 +618,28 @@ impl RPC {
         }
     }
 
+    fn auth_failed(&mut self, ip: IpAddr) {
+        if self.guard.auth_failed(ip, Utc::now()) {
+            info!("Banning {} after repeated auth failures", ip);
+        }
+    }
+
+    fn health(&self, serial: u64) -> message::Health {
+        let now = Utc::now();
+        message::Health {
+            serial,
+            // The client asking has been taken out of the map while it's handled
+            clients: self.clients.len() + 1,
+            bans: self
+                .guard
+                .bans(now)
+                .into_iter()
+                .map(|(ip, expires)| message::Ban { ip, expires })
+                .collect(),
+            tick_interval: self.tick_interval,
+        }
+    }
+
     fn handle_conn(&mut self, not: amy::Notification) {
         if let Some(mut c) = self.clients.remove(&not.id) {
             if not.event.readable() {
@@ -560,6 +676,14 @@ impl RPC {
 
     fn process_frame(&mut self, id: usize, c: &mut Client, data: &str) -> result::Result<(), ()> {
         match serde_json::from_str(data) {
+            Ok(CMessage::GetHealth { serial }) => {
+                let msg = SMessage::Health(self.health(serial));
+                if c.send(ws::Frame::Text(serde_json::to_string(&msg).unwrap()))
+                    .is_err()
+                {
+                    return Err(());
+                }
+            }
             Ok(m) => {
                 let (msgs, rm) = self.processor.handle_client(id, m);
                 if let Some(m) = rm {
@@ -575,10 +699,11 @@ impl RPC {
             }
             Err(e) => {
                 if e.is_syntax() || e.is_eof() {
-                    let msg = SMessage::InvalidSchema(message::Error {
-                        serial: None,
-                        reason: format!("JSON decode error: {e}"),
-                    });
+                    let msg = SMessage::InvalidSchema(message::Error::new(
+                        None,
+                        message::ErrorCode::InvalidSchema,
+                        format!("JSON decode error: {e}"),
+                    ));
                     if c.send(ws::Frame::Text(serde_json::to_string(&msg).unwrap()))
                         .is_err()
                     {}
@@ -595,10 +720,11 @@ impl RPC {
                         Err(_) => None,
                     };
 
-                    let msg = SMessage::InvalidSchema(message::Error {
+                    let msg = SMessage::InvalidSchema(message::Error::new(
                         serial,
-                        reason: format!("Invalid message format: {e}"),
-                    });
+                        message::ErrorCode::InvalidSchema,
+                        format!("Invalid message format: {e}"),
+                    ));
                     if c.send(ws::Frame::Text(serde_json::to_string(&msg).unwrap()))
                         .is_err()
                     {
@@ -612,14 +738,17 @@ impl RPC {
 
     fn cleanup(&mut self) {
         self.processor.remove_expired_tokens();
+        self.guard.cleanup(Utc::now());
         let processor = &mut self.processor;
+        let now = Instant::now();
         self.clients.retain(|id, client| {
-            let res = client.timed_out();
-            if res {
+            let dead =
+                client.is_dead(now) || (client.should_ping(now) && client.ping(now).is_err());
+            if dead {
                 info!("client {} timed out", id);
                 processor.remove_client(*id);
             }
-            !res
+            !dead
         });
         self.incoming.retain(|_, inc| !inc.timed_out());
         for (_conn, id, err) in self.transfers.cleanup() {
@@ -1,25 +1,32 @@
 use std::borrow::Cow;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs::OpenOptions;
 use std::io::Read;
 use std::path::Path;
 use std::sync::Arc;
 
 use crate::rpc_lib;
+use base64::prelude::{BASE64_STANDARD, Engine};
 use chrono::{DateTime, Duration, Utc};
 use serde_json as json;
 use url::Url;
 
-use super::proto::criterion::{self, Criterion, Operation};
-use super::proto::message::{CMessage, Error, SMessage};
-use super::proto::resource::{Resource, ResourceKind, SResourceUpdate, merge_json};
+use super::proto::criterion::{self, CompiledCriterion, Criterion, Operation};
+use super::proto::message::{AddError, CMessage, Error, ErrorCode, SMessage};
+use super::proto::resource::{
+    Resource, ResourceKind, SResourceUpdate, Strategy, merge_json, parse_tracker_header,
+};
 use super::{CtlMessage, Message};
+use crate::bencode;
 use crate::config::Config;
 use crate::disk;
+use crate::torrent::Preset;
 use crate::torrent::info::Info;
-use crate::util::{FHashMap, FHashSet, MHashSet, SHashMap, random_string};
+use crate::util::{FHashMap, FHashSet, MHashSet, SHashMap, UHashMap, hash_to_id, random_string};
 
 const USER_DATA_FILE: &str = "rpc_user_data";
+/// Highest priority a file can be added with
+const MAX_FILE_PRIORITY: u8 = 5;
 type RpcDiskFmt = SHashMap<Vec<u8>>;
 
 // TODO: Figure out a way to reduce allocations
@@ -30,6 +37,8 @@ type RpcDiskFmt = SHashMap<Vec<u8>>;
 pub struct Processor {
     config: Arc<Config>,
     subs: SHashMap<FHashSet<usize>>,
+    /// Clients subscribed to each torrent's block progress
+    block_subs: SHashMap<FHashSet<usize>>,
     filter_subs: FHashMap<(usize, u64), Filter>,
     resources: SHashMap<Resource>,
     // Index by resource kind
@@ -39,11 +48,17 @@ pub struct Processor {
     tokens: SHashMap<BearerToken>,
     db: flume::Sender<disk::Request>,
     user_data: SHashMap<json::Value>,
+    /// Minor RPC version each client advertised when connecting
+    minors: UHashMap<u16>,
+    /// Identity of each client, recorded with the torrent actions it requests
+    identities: UHashMap<String>,
+    /// ADD_TORRENT requests waiting on control, by client and serial
+    adding: FHashSet<(usize, u64)>,
 }
 
 struct Filter {
     kind: ResourceKind,
-    criteria: Vec<Criterion>,
+    criteria: Vec<CompiledCriterion>,
 }
 
 struct BearerToken {
@@ -60,6 +75,7 @@ pub enum TransferKind {
         path: Option<String>,
         start: bool,
         import: bool,
+        preset: Preset,
     },
     UploadFiles {
         size: u64,
@@ -110,6 +126,7 @@ impl Processor {
         Processor {
             config,
             subs: SHashMap::default(),
+            block_subs: SHashMap::default(),
             filter_subs: FHashMap::default(),
             resources: SHashMap::default(),
             tokens: SHashMap::default(),
@@ -117,9 +134,21 @@ impl Processor {
             kinds: vec![MHashSet::default(); 6],
             db,
             user_data,
+            minors: UHashMap::default(),
+            identities: UHashMap::default(),
+            adding: FHashSet::default(),
         }
     }
 
+    pub fn add_client(&mut self, client: usize, minor: u16, identity: String) {
+        self.minors.insert(client, minor);
+        self.identities.insert(client, identity);
+    }
+
+    fn identity(&self, client: usize) -> String {
+        self.identities.get(&client).cloned().unwrap_or_default()
+    }
+
     pub fn remove_expired_tokens(&mut self) {
         self.tokens.retain(|_, tok| tok.expiration > Utc::now())
     }
@@ -170,10 +199,14 @@ impl Processor {
                     if let Some(r) = self.resources.get(&id) {
                         resources.push(SResourceUpdate::Resource(Cow::Borrowed(r)));
                     } else {
-                        resp.push(SMessage::UnknownResource(Error {
-                            serial: Some(serial),
-                            reason: format!("unknown resource id {id}"),
-                        }));
+                        resp.push(SMessage::UnknownResource(
+                            Error::new(
+                                Some(serial),
+                                ErrorCode::UnknownResource,
+                                format!("unknown resource id {id}"),
+                            )
+                            .with_detail(id),
+                        ));
                     }
                 }
                 resp.push(SMessage::UpdateResources {
@@ -181,17 +214,31 @@ impl Processor {
                     resources,
                 });
             }
-            CMessage::Subscribe { serial, ids } => {
+            CMessage::Subscribe {
+                serial,
+                ids,
+                block_progress,
+            } => {
                 let mut resources = Vec::new();
                 for id in ids {
                     if let Some(r) = self.resources.get(&id) {
+                        if block_progress && r.kind() == ResourceKind::Torrent {
+                            self.block_subs
+                                .entry(id.clone())
+                                .or_default()
+                                .insert(client);
+                        }
                         resources.push(SResourceUpdate::Resource(Cow::Borrowed(r)));
                         self.subs.get_mut(&id).map(|s| s.insert(client));
                     } else {
-                        resp.push(SMessage::UnknownResource(Error {
-                            serial: Some(serial),
-                            reason: format!("unknown resource id {id}"),
-                        }));
+                        resp.push(SMessage::UnknownResource(
+                            Error::new(
+                                Some(serial),
+                                ErrorCode::UnknownResource,
+                                format!("unknown resource id {id}"),
+                            )
+                            .with_detail(id),
+                        ));
                     }
                 }
                 resp.push(SMessage::UpdateResources {
@@ -202,6 +249,7 @@ impl Processor {
             CMessage::Unsubscribe { ids, .. } => {
                 for id in ids {
                     self.subs.get_mut(&id).map(|s| s.remove(&client));
+                    self.block_subs.get_mut(&id).map(|s| s.remove(&client));
                 }
             }
             CMessage::UpdateResource {
@@ -233,7 +281,27 @@ impl Processor {
 
                 match self.resources.get(&resource.id) {
                     Some(Resource::Torrent(_)) => {
-                        rmsg = Some(Message::UpdateTorrent(resource));
+                        let unknown = resource.file_order.iter().flatten().find(|id| {
+                            !matches!(
+                                self.resources.get(id.as_str()),
+                                Some(Resource::File(f)) if f.torrent_id == resource.id
+                            )
+                        });
+                        if let Some(id) = unknown {
+                            resp.push(SMessage::InvalidRequest(
+                                Error::new(
+                                    Some(serial),
+                                    ErrorCode::InvalidArgument,
+                                    format!("{id} is not a file of the torrent"),
+                                )
+                                .with_detail(id.clone()),
+                            ));
+                        } else {
+                            rmsg = Some(Message::UpdateTorrent {
+                                update: resource,
+                                by: self.identity(client),
+                            });
+                        }
                     }
                     Some(Resource::File(f)) => {
                         // TODO: Validate other fields(make sure they're not present)
@@ -242,6 +310,29 @@ impl Processor {
                                 id: resource.id,
                                 torrent_id: f.torrent_id.to_owned(),
                                 priority: p,
+                                by: self.identity(client),
+                            });
+                        }
+                    }
+                    Some(Resource::Tracker(t)) => {
+                        if let Err(reason) = resource
+                            .headers
+                            .iter()
+                            .flatten()
+                            .try_for_each(|h| parse_tracker_header(h).map(drop))
+                        {
+                            resp.push(SMessage::InvalidRequest(Error::new(
+                                Some(serial),
+                                ErrorCode::InvalidArgument,
+                                reason,
+                            )));
+                        } else if resource.headers.is_some() || resource.url.is_some() {
+                            rmsg = Some(Message::EditTracker {
+                                id: resource.id,
+                                torrent_id: t.torrent_id.to_owned(),
+                                url: resource.url,
+                                headers: resource.headers,
+                                by: self.identity(client),
                             });
                         }
                     }
@@ -250,14 +341,19 @@ impl Processor {
                             id: resource.id,
                             throttle_up: resource.throttle_up,
                             throttle_down: resource.throttle_down,
+                            permanent: resource.throttle_permanent.unwrap_or(false),
                         });
                     }
                     Some(_) => {}
                     None => {
-                        resp.push(SMessage::UnknownResource(Error {
-                            serial: Some(serial),
-                            reason: format!("unknown resource id {}", resource.id),
-                        }));
+                        resp.push(SMessage::UnknownResource(
+                            Error::new(
+                                Some(serial),
+                                ErrorCode::UnknownResource,
+                                format!("unknown resource id {}", resource.id),
+                            )
+                            .with_detail(resource.id),
+                        ));
                     }
                 }
             }
@@ -272,6 +368,7 @@ impl Processor {
                         client,
                         serial,
                         artifacts: artifacts.unwrap_or(false),
+                        by: self.identity(client),
                     });
                 }
                 Some(Resource::Tracker(t)) => {
@@ -280,6 +377,7 @@ impl Processor {
                         torrent_id: t.torrent_id.to_owned(),
                         client,
                         serial,
+                        by: self.identity(client),
                     });
                 }
                 Some(Resource::Peer(p)) => {
@@ -291,16 +389,21 @@ impl Processor {
                     });
                 }
                 Some(_) => {
-                    resp.push(SMessage::InvalidResource(Error {
-                        serial: Some(serial),
-                        reason: "Only torrents, trackers, and peers may be removed".into(),
-                    }));
+                    resp.push(SMessage::InvalidResource(Error::new(
+                        Some(serial),
+                        ErrorCode::InvalidResource,
+                        "Only torrents, trackers, and peers may be removed",
+                    )));
                 }
                 None => {
-                    resp.push(SMessage::UnknownResource(Error {
-                        serial: Some(serial),
-                        reason: format!("unknown resource id {id}"),
-                    }));
+                    resp.push(SMessage::UnknownResource(
+                        Error::new(
+                            Some(serial),
+                            ErrorCode::UnknownResource,
+                            format!("unknown resource id {id}"),
+                        )
+                        .with_detail(id),
+                    ));
                 }
             },
             CMessage::FilterSubscribe {
@@ -308,39 +411,25 @@ impl Processor {
                 kind,
                 criteria,
             } => {
+                let criteria = match criteria.into_iter().map(Criterion::compile).collect() {
+                    Ok(criteria) => criteria,
+                    Err(reason) => {
+                        resp.push(SMessage::InvalidRequest(Error::new(
+                            Some(serial),
+                            ErrorCode::InvalidCriterion,
+                            reason,
+                        )));
+                        return (resp, rmsg);
+                    }
+                };
                 let torrent_idx = &self.torrent_idx;
                 let kinds = &self.kinds;
-                let rkind = &self.kinds[kind as usize];
                 let resources = &self.resources;
 
-                let get_matching = |f: &Filter| {
-                    let mut added = HashSet::new();
-                    let crit_res = f
-                        .criteria
-                        .iter()
-                        .find(|c| c.field == "torrent_id" && c.op == Operation::Eq)
-                        .and_then(|c| match &c.value {
-                            criterion::Value::S(s) => Some(s),
-                            _ => None,
-                        })
-                        .and_then(|id| torrent_idx.get(id));
-
-                    if let Some(t) = crit_res {
-                        for id in rkind.intersection(t) {
-                            let r = resources.get(id).unwrap();
-                            if f.matches(r, torrent_idx, kinds, resources) {
-                                added.insert(Cow::Borrowed(r.id()));
-                            }
-                        }
-                    } else {
-                        for id in rkind.iter() {
-                            let r = resources.get(id).unwrap();
-                            if f.matches(r, torrent_idx, kinds, resources) {
-                                added.insert(Cow::Borrowed(r.id()));
-                            }
-                        }
-                    }
-                    added
+                let get_matching = |f: &Filter| -> HashSet<_> {
+                    f.matching(torrent_idx, kinds, resources)
+                        .map(|r| Cow::Borrowed(r.id()))
+                        .collect()
                 };
 
                 let f = Filter { criteria, kind };
@@ -372,28 +461,86 @@ impl Processor {
             CMessage::FilterUnsubscribe { filter_serial, .. } => {
                 self.filter_subs.remove(&(client, filter_serial));
             }
+            CMessage::QueryResources {
+                serial,
+                kind,
+                criteria,
+                offset,
+                limit,
+                fields,
+            } => {
+                let criteria = match criteria.into_iter().map(Criterion::compile).collect() {
+                    Ok(criteria) => criteria,
+                    Err(reason) => {
+                        resp.push(SMessage::InvalidRequest(Error::new(
+                            Some(serial),
+                            ErrorCode::InvalidCriterion,
+                            reason,
+                        )));
+                        return (resp, rmsg);
+                    }
+                };
+                let f = Filter { criteria, kind };
+                let mut matching: Vec<_> = f
+                    .matching(&self.torrent_idx, &self.kinds, &self.resources)
+                    .collect();
+                // Sorted so consecutive pages neither overlap nor skip resources
+                matching.sort_unstable_by(|a, b| a.id().cmp(b.id()));
+                let total_matched = matching.len();
+                let resources = matching
+                    .into_iter()
+                    .skip(offset)
+                    .take(limit.unwrap_or(usize::MAX))
+                    .map(|r| project(r, fields.as_deref()))
+                    .collect();
+                resp.push(SMessage::ResourcesQueried {
+                    serial,
+                    total_matched,
+                    resources,
+                });
+            }
 
             CMessage::PauseTorrent { serial, id } => match self.resources.get(&id) {
-                Some(&Resource::Torrent(_)) => rmsg = Some(Message::Pause(id)),
-                Some(_) => resp.push(SMessage::InvalidResource(Error {
-                    serial: Some(serial),
-                    reason: "Only torrents can be paused".to_owned(),
-                })),
-                None => resp.push(SMessage::UnknownResource(Error {
-                    serial: Some(serial),
-                    reason: format!("Unknown resource {id}"),
-                })),
+                Some(&Resource::Torrent(_)) => {
+                    rmsg = Some(Message::Pause {
+                        id,
+                        by: self.identity(client),
+                    })
+                }
+                Some(_) => resp.push(SMessage::InvalidResource(Error::new(
+                    Some(serial),
+                    ErrorCode::InvalidResource,
+                    "Only torrents can be paused",
+                ))),
+                None => resp.push(SMessage::UnknownResource(
+                    Error::new(
+                        Some(serial),
+                        ErrorCode::UnknownResource,
+                        format!("Unknown resource {id}"),
+                    )
+                    .with_detail(id),
+                )),
             },
             CMessage::ResumeTorrent { serial, id } => match self.resources.get(&id) {
-                Some(&Resource::Torrent(_)) => rmsg = Some(Message::Resume(id)),
-                Some(_) => resp.push(SMessage::InvalidResource(Error {
-                    serial: Some(serial),
-                    reason: "Only torrents can be resumed".to_owned(),
-                })),
-                None => resp.push(SMessage::UnknownResource(Error {
-                    serial: Some(serial),
-                    reason: format!("Unknown resource {id}"),
-                })),
+                Some(&Resource::Torrent(_)) => {
+                    rmsg = Some(Message::Resume {
+                        id,
+                        by: self.identity(client),
+                    })
+                }
+                Some(_) => resp.push(SMessage::InvalidResource(Error::new(
+                    Some(serial),
+                    ErrorCode::InvalidResource,
+                    "Only torrents can be resumed",
+                ))),
+                None => resp.push(SMessage::UnknownResource(
+                    Error::new(
+                        Some(serial),
+                        ErrorCode::UnknownResource,
+                        format!("Unknown resource {id}"),
+                    )
+                    .with_detail(id),
+                )),
             },
             CMessage::AddPeer { serial, id, ip } => match self.resources.get(&id) {
                 Some(&Resource::Torrent(_)) => match ip.parse() {
@@ -405,19 +552,28 @@ impl Processor {
                             peer,
                         })
                     }
-                    Err(_) => resp.push(SMessage::InvalidRequest(Error {
-                        serial: Some(serial),
-                        reason: format!("Invalid peer IP address: {ip}"),
-                    })),
+                    Err(_) => resp.push(SMessage::InvalidRequest(
+                        Error::new(
+                            Some(serial),
+                            ErrorCode::InvalidArgument,
+                            format!("Invalid peer IP address: {ip}"),
+                        )
+                        .with_detail(ip),
+                    )),
                 },
-                Some(_) => resp.push(SMessage::InvalidResource(Error {
-                    serial: Some(serial),
-                    reason: "ADD_PEER not used with torrent".to_owned(),
-                })),
-                None => resp.push(SMessage::UnknownResource(Error {
-                    serial: Some(serial),
-                    reason: format!("Unknown resource {id}"),
-                })),
+                Some(_) => resp.push(SMessage::InvalidResource(Error::new(
+                    Some(serial),
+                    ErrorCode::InvalidResource,
+                    "ADD_PEER not used with torrent",
+                ))),
+                None => resp.push(SMessage::UnknownResource(
+                    Error::new(
+                        Some(serial),
+                        ErrorCode::UnknownResource,
+                        format!("Unknown resource {id}"),
+                    )
+                    .with_detail(id),
+                )),
             },
             CMessage::AddTracker { serial, id, uri } => match self.resources.get(&id) {
                 Some(&Resource::Torrent(_)) => match Url::parse(&uri) {
@@ -427,21 +583,31 @@ impl Processor {
                             client,
                             serial,
                             tracker,
+                            by: self.identity(client),
                         })
                     }
-                    Err(_) => resp.push(SMessage::InvalidRequest(Error {
-                        serial: Some(serial),
-                        reason: format!("Invalid tracker URI: {uri}"),
-                    })),
+                    Err(_) => resp.push(SMessage::InvalidRequest(
+                        Error::new(
+                            Some(serial),
+                            ErrorCode::InvalidArgument,
+                            format!("Invalid tracker URI: {uri}"),
+                        )
+                        .with_detail(uri),
+                    )),
                 },
-                Some(_) => resp.push(SMessage::InvalidResource(Error {
-                    serial: Some(serial),
-                    reason: "ADD_TRACKER not used with torrent".to_owned(),
-                })),
-                None => resp.push(SMessage::UnknownResource(Error {
-                    serial: Some(serial),
-                    reason: format!("Unknown resource {id}"),
-                })),
+                Some(_) => resp.push(SMessage::InvalidResource(Error::new(
+                    Some(serial),
+                    ErrorCode::InvalidResource,
+                    "ADD_TRACKER not used with torrent",
+                ))),
+                None => resp.push(SMessage::UnknownResource(
+                    Error::new(
+                        Some(serial),
+                        ErrorCode::UnknownResource,
+                        format!("Unknown resource {id}"),
+                    )
+                    .with_detail(id),
+                )),
             },
             CMessage::UpdateTracker { serial, id } => match self.resources.get(&id) {
                 Some(Resource::Tracker(t)) => {
@@ -450,30 +616,40 @@ impl Processor {
                         torrent_id: t.torrent_id.clone(),
                     })
                 }
-                Some(_) => resp.push(SMessage::InvalidResource(Error {
-                    serial: Some(serial),
-                    reason: "UPDATE_TRACKER not used with tracker".to_owned(),
-                })),
-                None => resp.push(SMessage::UnknownResource(Error {
-                    serial: Some(serial),
-                    reason: format!("Unknown resource {id}"),
-                })),
+                Some(_) => resp.push(SMessage::InvalidResource(Error::new(
+                    Some(serial),
+                    ErrorCode::InvalidResource,
+                    "UPDATE_TRACKER not used with tracker",
+                ))),
+                None => resp.push(SMessage::UnknownResource(
+                    Error::new(
+                        Some(serial),
+                        ErrorCode::UnknownResource,
+                        format!("Unknown resource {id}"),
+                    )
+                    .with_detail(id),
+                )),
             },
             CMessage::ValidateResources { serial, mut ids } => {
                 ids.retain(|id| match self.resources.get(id) {
                     Some(&Resource::Torrent(_)) => true,
                     Some(_) => {
-                        resp.push(SMessage::InvalidResource(Error {
-                            serial: Some(serial),
-                            reason: "Only torrents can be validated".to_owned(),
-                        }));
+                        resp.push(SMessage::InvalidResource(Error::new(
+                            Some(serial),
+                            ErrorCode::InvalidResource,
+                            "Only torrents can be validated",
+                        )));
                         false
                     }
                     None => {
-                        resp.push(SMessage::UnknownResource(Error {
-                            serial: Some(serial),
-                            reason: format!("Unknown resource {id}"),
-                        }));
+                        resp.push(SMessage::UnknownResource(
+                            Error::new(
+                                Some(serial),
+                                ErrorCode::UnknownResource,
+                                format!("Unknown resource {id}"),
+                            )
+                            .with_detail(id),
+                        ));
                         false
                     }
                 });
@@ -485,41 +661,126 @@ impl Processor {
                 path,
                 start,
                 import,
-            } => {
-                resp.push(self.new_transfer(
-                    client,
-                    serial,
-                    TransferKind::UploadTorrent {
-                        size,
-                        path,
-                        start,
-                        import,
-                    },
-                ));
-            }
+                file_priorities,
+                strategy,
+            } => match preset(file_priorities, strategy) {
+                Ok(preset) => {
+                    resp.push(self.new_transfer(
+                        client,
+                        serial,
+                        TransferKind::UploadTorrent {
+                            size,
+                            path,
+                            start,
+                            import,
+                            preset,
+                        },
+                    ));
+                }
+                Err(reason) => {
+                    resp.push(SMessage::InvalidRequest(Error::new(
+                        Some(serial),
+                        ErrorCode::InvalidArgument,
+                        reason,
+                    )));
+                }
+            },
             CMessage::UploadMagnet {
                 serial,
                 uri,
                 path,
                 start,
-            } => match Info::from_magnet(&uri) {
-                Ok(info) => {
+                file_priorities,
+                strategy,
+            } => match preset(file_priorities, strategy).and_then(|preset| {
+                Info::from_magnet(&uri)
+                    .map(|info| (info, preset))
+                    .map_err(|e| format!("Invalid magnet: {e}"))
+            }) {
+                Ok((info, preset)) => {
                     rmsg = Some(Message::Torrent {
                         info,
                         path,
                         start,
                         import: false,
+                        preset,
                         client,
                         serial,
                     })
                 }
-                Err(e) => {
-                    resp.push(SMessage::InvalidRequest(Error {
-                        serial: Some(serial),
-                        reason: format!("Invalid magnet: {e}"),
-                    }));
+                Err(reason) => {
+                    resp.push(SMessage::InvalidRequest(Error::new(
+                        Some(serial),
+                        ErrorCode::InvalidArgument,
+                        reason,
+                    )));
                 }
             },
+            CMessage::AddTorrent {
+                serial,
+                torrent,
+                magnet,
+                path,
+                start,
+                import,
+                file_priorities,
+                strategy,
+            } => {
+                let preset = match preset(file_priorities, strategy) {
+                    Ok(preset) => preset,
+                    Err(reason) => {
+                        resp.push(SMessage::InvalidRequest(Error::new(
+                            Some(serial),
+                            ErrorCode::InvalidArgument,
+                            reason,
+                        )));
+                        return (resp, rmsg);
+                    }
+                };
+                let info =
+                    match (torrent, magnet) {
+                        (Some(torrent), None) => Some(parse_torrent(&torrent)),
+                        (None, Some(uri)) => Some(Info::from_magnet(&uri).map_err(|e| {
+                            (AddError::InvalidMetainfo, format!("Invalid magnet: {e}"))
+                        })),
+                        _ => None,
+                    };
+                match info {
+                    Some(Ok(info)) if self.resources.contains_key(&hash_to_id(&info.hash)) => {
+                        resp.push(SMessage::AddTorrentFailed {
+                            serial,
+                            error: AddError::Duplicate,
+                            reason: format!("Torrent {} already exists", hash_to_id(&info.hash)),
+                        });
+                    }
+                    Some(Ok(info)) => {
+                        self.adding.insert((client, serial));
+                        rmsg = Some(Message::Torrent {
+                            info,
+                            path,
+                            start,
+                            import,
+                            preset,
+                            client,
+                            serial,
+                        });
+                    }
+                    Some(Err((error, reason))) => {
+                        resp.push(SMessage::AddTorrentFailed {
+                            serial,
+                            error,
+                            reason,
+                        });
+                    }
+                    None => {
+                        resp.push(SMessage::InvalidRequest(Error::new(
+                            Some(serial),
+                            ErrorCode::InvalidArgument,
+                            "Exactly one of torrent or magnet must be given",
+                        )));
+                    }
+                }
+            }
             CMessage::UploadFiles { serial, size, path } => {
                 resp.push(self.new_transfer(
                     client,
@@ -530,6 +791,71 @@ impl Processor {
             CMessage::PurgeDns { .. } => {
                 rmsg = Some(Message::PurgeDNS);
             }
+            CMessage::ReannounceAll { .. } => {
+                rmsg = Some(Message::ReannounceAll);
+            }
+            CMessage::AnalyzePath { serial, path } => {
+                rmsg = Some(Message::AnalyzePath {
+                    path,
+                    client,
+                    serial,
+                });
+            }
+            CMessage::GetMetainfo { serial, id } => match self.resources.get(&id) {
+                Some(&Resource::Torrent(_)) => {
+                    rmsg = Some(Message::GetMetainfo { id, client, serial });
+                }
+                Some(_) => resp.push(SMessage::InvalidResource(Error::new(
+                    Some(serial),
+                    ErrorCode::InvalidResource,
+                    "GET_METAINFO not used with torrent",
+                ))),
+                None => resp.push(SMessage::UnknownResource(
+                    Error::new(
+                        Some(serial),
+                        ErrorCode::UnknownResource,
+                        format!("Unknown resource {id}"),
+                    )
+                    .with_detail(id),
+                )),
+            },
+            CMessage::GetDiskStats { serial } => {
+                rmsg = Some(Message::GetDiskStats { client, serial });
+            }
+            CMessage::GetHistory { serial, id } => match self.resources.get(&id) {
+                Some(&Resource::Torrent(_)) => {
+                    rmsg = Some(Message::GetHistory { id, client, serial });
+                }
+                Some(_) => resp.push(SMessage::InvalidResource(Error::new(
+                    Some(serial),
+                    ErrorCode::InvalidResource,
+                    "GET_HISTORY not used with torrent",
+                ))),
+                None => resp.push(SMessage::UnknownResource(
+                    Error::new(
+                        Some(serial),
+                        ErrorCode::UnknownResource,
+                        format!("Unknown resource {id}"),
+                    )
+                    .with_detail(id),
+                )),
+            },
+            // Answered by the RPC loop, which owns the connection guard
+            CMessage::GetHealth { .. } => {}
+            CMessage::UpdateIpFilter {
+                serial,
+                action,
+                ranges,
+                path,
+            } => {
+                rmsg = Some(Message::UpdateIpFilter {
+                    client,
+                    serial,
+                    action,
+                    ranges,
+                    path,
+                });
+            }
         }
         (resp, rmsg)
     }
@@ -583,6 +909,37 @@ impl Processor {
             CtlMessage::Update(updates) => {
                 let mut clients = HashMap::new();
                 for update in updates {
+                    if let SResourceUpdate::TorrentBlockProgress { .. } = update {
+                        for c in self.block_subs.get(update.id()).into_iter().flatten() {
+                            clients
+                                .entry(*c)
+                                .or_insert_with(Vec::new)
+                                .push(update.clone());
+                        }
+                        continue;
+                    }
+                    if let SResourceUpdate::TorrentPiecesDelta { .. } = update {
+                        let Some(res) = self.resources.get_mut(update.id()) else {
+                            continue;
+                        };
+                        res.update(update.clone());
+                        // Clients which don't know about deltas get the whole field instead
+                        let full = SResourceUpdate::TorrentPieces {
+                            id: update.id().to_owned(),
+                            kind: ResourceKind::Torrent,
+                            piece_field: res.as_torrent().piece_field.clone(),
+                        };
+                        for c in self.subs.get(update.id()).into_iter().flatten() {
+                            let minor = self.minors.get(c).copied().unwrap_or(0);
+                            let u = if minor >= rpc_lib::PIECES_DELTA_MINOR {
+                                update.clone()
+                            } else {
+                                full.clone()
+                            };
+                            clients.entry(*c).or_insert_with(Vec::new).push(u);
+                        }
+                        continue;
+                    }
                     for c in self.subs.get(update.id()).unwrap().iter() {
                         if !clients.contains_key(c) {
                             clients.insert(*c, Vec::new());
@@ -622,6 +979,7 @@ impl Processor {
                         self.serialize();
                     }
                     self.kinds[r.kind() as usize].remove(&id);
+                    self.block_subs.remove(&id);
                     // If this resource is part of a torrent, remove from index,
                     // if we haven't removed the entire torrent already.
                     // Otherwise, attempt to remove the resource itself from the
@@ -652,45 +1010,92 @@ impl Processor {
                 ));
             }
             CtlMessage::Uploaded { id, serial, client } => {
+                let added = self.adding.remove(&(client, serial));
                 if let Some(r) = self.resources.get(&id) {
-                    msgs.push((
-                        client,
+                    let msg = if added {
+                        SMessage::TorrentAdded {
+                            serial,
+                            infohash: id,
+                            resource: r.clone(),
+                        }
+                    } else {
                         SMessage::ResourcesExtant {
                             serial,
                             ids: vec![Cow::Borrowed(r.id())],
-                        },
-                    ))
+                        }
+                    };
+                    msgs.push((client, msg))
                 } else {
                     debug!("Failed to get resource uploaded: {}!", id);
                 }
             }
             CtlMessage::Error {
+                code,
                 reason,
                 serial,
                 client,
             } => {
                 msgs.push((
                     client,
-                    SMessage::InvalidRequest(Error {
-                        serial: Some(serial),
-                        reason,
-                    }),
+                    SMessage::InvalidRequest(Error::new(Some(serial), code, reason)),
                 ));
             }
+            CtlMessage::AddFailed {
+                error,
+                reason,
+                serial,
+                client,
+            } => {
+                let msg = if self.adding.remove(&(client, serial)) {
+                    SMessage::AddTorrentFailed {
+                        serial,
+                        error,
+                        reason,
+                    }
+                } else {
+                    SMessage::InvalidRequest(Error::new(Some(serial), error.into(), reason))
+                };
+                msgs.push((client, msg));
+            }
             CtlMessage::Pending { id, serial, client } => {
                 msgs.push((client, SMessage::ResourcePending { serial, id }));
             }
+            CtlMessage::PathAnalysis { client, analysis } => {
+                msgs.push((client, SMessage::PathAnalysis(analysis)));
+            }
+            CtlMessage::Metainfo { client, metainfo } => {
+                msgs.push((client, SMessage::Metainfo(metainfo)));
+            }
+            CtlMessage::DiskStats { client, stats } => {
+                msgs.push((client, SMessage::DiskStats(stats)));
+            }
+            CtlMessage::History { client, history } => {
+                msgs.push((client, SMessage::History(history)));
+            }
+            CtlMessage::IpFilterUpdated {
+                client,
+                serial,
+                blocked,
+            } => {
+                msgs.push((client, SMessage::IpFilterUpdated { serial, blocked }));
+            }
             CtlMessage::Ping => unreachable!("ping must be handled before rpc processor"),
+            CtlMessage::TickInterval(_) => {
+                unreachable!("tick interval must be handled before rpc processor")
+            }
             CtlMessage::Shutdown => unreachable!("shutdown must be handled before rpc processor"),
         }
         msgs
     }
 
     pub fn remove_client(&mut self, client: usize) {
-        for sub in self.subs.values_mut() {
+        for sub in self.subs.values_mut().chain(self.block_subs.values_mut()) {
             sub.remove(&client);
         }
         self.filter_subs.retain(|&(c, _), _| c != client);
+        self.minors.remove(&client);
+        self.identities.remove(&client);
+        self.adding.retain(|&(c, _)| c != client);
     }
 
     /// Produces a map of the form Map<(Client ID, Serial), messages)>.
@@ -756,6 +1161,33 @@ impl Processor {
 }
 
 impl Filter {
+    /// Returns every resource which matches the filter.
+    pub fn matching<'s, 'a: 's>(
+        &'s self,
+        tidx: &'a SHashMap<MHashSet<String>>,
+        kidx: &'a Vec<MHashSet<String>>,
+        resources: &'a SHashMap<Resource>,
+    ) -> impl Iterator<Item = &'a Resource> + 's {
+        // Resources of a single torrent can be found through its index
+        let ids = self
+            .criteria
+            .iter()
+            .map(|c| &c.criterion)
+            .find(|c| c.field == "torrent_id" && c.op == Operation::Eq)
+            .and_then(|c| match &c.value {
+                criterion::Value::S(s) => Some(s),
+                _ => None,
+            })
+            .and_then(|id| tidx.get(id));
+        let rkind = &kidx[self.kind as usize];
+        let ids: Box<dyn Iterator<Item = &'a String>> = match ids {
+            Some(t) => Box::new(rkind.intersection(t)),
+            None => Box::new(rkind.iter()),
+        };
+        ids.map(|id| resources.get(id).unwrap())
+            .filter(move |r| self.matches(r, tidx, kidx, resources))
+    }
+
     pub fn matches(
         &self,
         r: &Resource,
@@ -802,3 +1234,383 @@ impl Filter {
         })
     }
 }
+
+/// Serializes a resource, keeping only the given fields along with its `id` and `type`.
+fn project(r: &Resource, fields: Option<&[String]>) -> json::Value {
+    let mut value = json::to_value(r).unwrap_or_default();
+    if let (Some(fields), json::Value::Object(map)) = (fields, &mut value) {
+        map.retain(|k, _| k == "id" || k == "type" || fields.contains(k));
+    }
+    value
+}
+
+/// Builds the preset a torrent is added with, checking its file priorities.
+fn preset(
+    file_priorities: BTreeMap<usize, u8>,
+    strategy: Option<Strategy>,
+) -> Result<Preset, String> {
+    if let Some((file, p)) = file_priorities
+        .iter()
+        .find(|&(_, &p)| p > MAX_FILE_PRIORITY)
+    {
+        return Err(format!(
+            "Priority {p} of file {file} is above the maximum of {MAX_FILE_PRIORITY}"
+        ));
+    }
+    Ok(Preset {
+        file_priorities,
+        strategy,
+    })
+}
+
+/// Decodes a base64 encoded .torrent file into its info.
+fn parse_torrent(torrent: &str) -> Result<Info, (AddError, String)> {
+    let data = BASE64_STANDARD
+        .decode(torrent)
+        .map_err(|e| (AddError::InvalidBencode, format!("Invalid base64: {e}")))?;
+    let b = bencode::decode_buf(&data)
+        .map_err(|e| (AddError::InvalidBencode, format!("Invalid bencode: {e}")))?;
+    let info = b.as_dict().and_then(|d| d.get(b"info".as_ref()));
+    if info.and_then(|i| i.as_dict()).is_none() {
+        return Err((
+            AddError::NoInfo,
+            "Torrent has no info dictionary".to_owned(),
+        ));
+    }
+    Info::from_bencode(b).map_err(|e| (AddError::InvalidMetainfo, format!("Invalid torrent: {e}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc_lib::resource::{Piece, Torrent};
+    use crate::torrent::Bitfield;
+
+    fn query(
+        p: &mut Processor,
+        criteria: Vec<Criterion>,
+        offset: usize,
+        limit: Option<usize>,
+        fields: Option<Vec<String>>,
+    ) -> (usize, Vec<json::Value>) {
+        let msg = CMessage::QueryResources {
+            serial: 0,
+            kind: ResourceKind::Piece,
+            criteria,
+            offset,
+            limit,
+            fields,
+        };
+        match p.handle_client(0, msg).0.pop() {
+            Some(SMessage::ResourcesQueried {
+                total_matched,
+                resources,
+                ..
+            }) => (total_matched, resources),
+            m => panic!("unexpected response {m:?}"),
+        }
+    }
+
+    #[test]
+    fn test_query_pages() {
+        let (db, _) = flume::unbounded();
+        let mut p = Processor::new(Arc::new(Config::default()), db);
+        let pieces = (0..1000u64)
+            .map(|i| {
+                Resource::Piece(Piece {
+                    // Scrambled, so ID order differs from insertion and index order
+                    id: format!("{:08x}", i.wrapping_mul(2_654_435_761) % (1 << 32)),
+                    torrent_id: "t".to_owned(),
+                    downloaded: i % 2 == 0,
+                    index: i as u32,
+                    ..Default::default()
+                })
+            })
+            .collect();
+        p.handle_ctl(CtlMessage::Extant(pieces));
+
+        let downloaded = || {
+            vec![Criterion {
+                field: "downloaded".to_owned(),
+                op: Operation::Eq,
+                value: criterion::Value::B(true),
+            }]
+        };
+        let mut ids = Vec::new();
+        for offset in (0..).step_by(64) {
+            let (total, page) = query(&mut p, downloaded(), offset, Some(64), None);
+            assert_eq!(total, 500);
+            if page.is_empty() {
+                break;
+            }
+            for r in page {
+                assert_eq!(r["downloaded"], true);
+                ids.push(r["id"].as_str().unwrap().to_owned());
+            }
+        }
+        assert_eq!(ids.len(), 500);
+        assert!(ids.windows(2).all(|w| w[0] < w[1]));
+
+        let (total, all) = query(&mut p, vec![], 990, None, None);
+        assert_eq!((total, all.len()), (1000, 10));
+
+        let (_, page) = query(&mut p, vec![], 0, Some(1), Some(vec!["index".to_owned()]));
+        let keys: Vec<_> = page[0].as_object().unwrap().keys().cloned().collect();
+        assert_eq!(keys, ["id", "index", "type"]);
+    }
+
+    #[test]
+    fn test_error_codes() {
+        let (db, _) = flume::unbounded();
+        let mut p = Processor::new(Arc::new(Config::default()), db);
+        let get = CMessage::GetResources {
+            serial: 3,
+            ids: vec!["missing".to_owned()],
+        };
+        let (resp, _) = p.handle_client(0, get);
+        let err = resp[0].error().unwrap();
+        assert_eq!(err.code, ErrorCode::UnknownResource);
+        assert_eq!(err.detail.as_deref(), Some("missing"));
+        let v = json::to_value(&resp[0]).unwrap();
+        assert_eq!(v["type"], "UNKNOWN_RESOURCE");
+        assert_eq!(v["code"], "unknown_resource");
+        assert_eq!(v["serial"], 3);
+
+        let bad = vec![Criterion {
+            field: "name".to_owned(),
+            op: Operation::Matches,
+            value: criterion::Value::S("(".to_owned()),
+        }];
+        let (resp, _) = p.handle_client(
+            0,
+            CMessage::QueryResources {
+                serial: 4,
+                kind: ResourceKind::Torrent,
+                criteria: bad,
+                offset: 0,
+                limit: None,
+                fields: None,
+            },
+        );
+        assert_eq!(resp[0].error().unwrap().code, ErrorCode::InvalidCriterion);
+    }
+
+    #[test]
+    fn test_pieces_delta() {
+        let (db, _) = flume::unbounded();
+        let mut p = Processor::new(Arc::new(Config::default()), db);
+        let mut pieces = Bitfield::new(20);
+        let torrent = Torrent {
+            id: "t".to_owned(),
+            piece_field: pieces.b64(),
+            ..Default::default()
+        };
+        p.handle_ctl(CtlMessage::Extant(vec![Resource::Torrent(torrent)]));
+        // An old client and one which understands deltas
+        p.add_client(0, 0, String::new());
+        p.add_client(1, rpc_lib::PIECES_DELTA_MINOR, String::new());
+        for client in 0..2 {
+            let sub = CMessage::Subscribe {
+                serial: 0,
+                ids: vec!["t".to_owned()],
+                block_progress: false,
+            };
+            p.handle_client(client, sub);
+        }
+
+        for set in [vec![0, 9], vec![19], vec![3, 4, 5, 9]] {
+            for &piece in &set {
+                pieces.set_bit(piece);
+            }
+            let msgs = p.handle_ctl(CtlMessage::Update(vec![
+                SResourceUpdate::TorrentPiecesDelta {
+                    id: "t".to_owned(),
+                    kind: ResourceKind::Torrent,
+                    pieces_set: set.clone(),
+                },
+            ]));
+            assert_eq!(msgs.len(), 2);
+            for (client, msg) in msgs {
+                let SMessage::UpdateResources { resources, .. } = msg else {
+                    panic!("unexpected message {msg:?}");
+                };
+                match (client, &resources[..]) {
+                    (0, [SResourceUpdate::TorrentPieces { piece_field, .. }]) => {
+                        assert_eq!(*piece_field, pieces.b64());
+                    }
+                    (1, [SResourceUpdate::TorrentPiecesDelta { pieces_set, .. }]) => {
+                        assert_eq!(*pieces_set, set);
+                    }
+                    r => panic!("unexpected update {r:?}"),
+                }
+            }
+            // The cached field matches a full snapshot after each delta
+            assert_eq!(p.resources["t"].as_torrent().piece_field, pieces.b64());
+        }
+    }
+    /// A base64 encoded single file torrent and its id.
+    fn torrent_file(name: &str) -> (String, String) {
+        let data = Info::test_torrent(name).encode_to_buf();
+        let id = hash_to_id(&parse_torrent(&BASE64_STANDARD.encode(&data)).unwrap().hash);
+        (BASE64_STANDARD.encode(data), id)
+    }
+
+    fn add(
+        p: &mut Processor,
+        serial: u64,
+        torrent: String,
+    ) -> (Vec<SMessage<'_>>, Option<Message>) {
+        p.handle_client(
+            0,
+            CMessage::AddTorrent {
+                serial,
+                torrent: Some(torrent),
+                magnet: None,
+                path: None,
+                start: true,
+                import: false,
+                file_priorities: BTreeMap::new(),
+                strategy: None,
+            },
+        )
+    }
+
+    #[test]
+    fn test_add_torrent_duplicate() {
+        let (db, _) = flume::unbounded();
+        let mut p = Processor::new(Arc::new(Config::default()), db);
+        let (existing, id) = torrent_file("existing");
+        let torrent = Torrent {
+            id: id.clone(),
+            ..Default::default()
+        };
+        p.handle_ctl(CtlMessage::Extant(vec![Resource::Torrent(torrent)]));
+
+        // Known torrents are rejected without going through control
+        let (resp, rmsg) = add(&mut p, 1, existing);
+        assert!(rmsg.is_none());
+        match &resp[..] {
+            [
+                SMessage::AddTorrentFailed {
+                    serial: 1,
+                    error: AddError::Duplicate,
+                    reason,
+                },
+            ] => {
+                assert!(reason.contains(&id))
+            }
+            r => panic!("unexpected response {r:?}"),
+        }
+
+        // Control can still find a duplicate, e.g. when the same torrent is added twice
+        // before the first is reported
+        let (new, id) = torrent_file("new");
+        let (resp, rmsg) = add(&mut p, 2, new.clone());
+        assert!(resp.is_empty());
+        assert!(matches!(rmsg, Some(Message::Torrent { serial: 2, .. })));
+        assert!(add(&mut p, 3, new).1.is_some());
+        let msgs = p.handle_ctl(CtlMessage::Extant(vec![Resource::Torrent(Torrent {
+            id: id.clone(),
+            ..Default::default()
+        })]));
+        assert!(msgs.is_empty());
+        let msgs = p.handle_ctl(CtlMessage::Uploaded {
+            id: id.clone(),
+            client: 0,
+            serial: 2,
+        });
+        match &msgs[..] {
+            [
+                (
+                    0,
+                    SMessage::TorrentAdded {
+                        serial: 2,
+                        infohash,
+                        resource,
+                    },
+                ),
+            ] => {
+                assert_eq!(*infohash, id);
+                assert_eq!(resource.id(), id);
+            }
+            m => panic!("unexpected messages {m:?}"),
+        }
+        let msgs = p.handle_ctl(CtlMessage::AddFailed {
+            error: AddError::Duplicate,
+            reason: "duplicate".to_owned(),
+            client: 0,
+            serial: 3,
+        });
+        assert!(matches!(
+            &msgs[..],
+            [(
+                0,
+                SMessage::AddTorrentFailed {
+                    serial: 3,
+                    error: AddError::Duplicate,
+                    ..
+                }
+            )]
+        ));
+        assert!(p.adding.is_empty());
+    }
+
+    #[test]
+    fn test_add_torrent_invalid() {
+        let (db, _) = flume::unbounded();
+        let mut p = Processor::new(Arc::new(Config::default()), db);
+        let cases = [
+            ("not base64!".to_owned(), AddError::InvalidBencode),
+            (BASE64_STANDARD.encode("d4:infod"), AddError::InvalidBencode),
+            (BASE64_STANDARD.encode("d4:spami1ee"), AddError::NoInfo),
+            (BASE64_STANDARD.encode("d4:infoi1ee"), AddError::NoInfo),
+            (
+                BASE64_STANDARD.encode("d4:infod4:name1:aee"),
+                AddError::InvalidMetainfo,
+            ),
+        ];
+        for (serial, (torrent, expected)) in cases.into_iter().enumerate() {
+            let (resp, rmsg) = add(&mut p, serial as u64, torrent);
+            assert!(rmsg.is_none());
+            match &resp[..] {
+                [SMessage::AddTorrentFailed { error, .. }] => assert_eq!(*error, expected),
+                r => panic!("unexpected response {r:?}"),
+            }
+        }
+
+        // Exactly one of the torrent and a magnet link must be given
+        let (resp, _) = p.handle_client(
+            0,
+            CMessage::AddTorrent {
+                serial: 0,
+                torrent: None,
+                magnet: None,
+                path: None,
+                start: true,
+                import: false,
+                file_priorities: BTreeMap::new(),
+                strategy: None,
+            },
+        );
+        assert!(matches!(&resp[..], [SMessage::InvalidRequest(_)]));
+
+        // As must file priorities be in range
+        let (torrent, _) = torrent_file("priorities");
+        let (resp, rmsg) = p.handle_client(
+            0,
+            CMessage::AddTorrent {
+                serial: 0,
+                torrent: Some(torrent),
+                magnet: None,
+                path: None,
+                start: true,
+                import: false,
+                file_priorities: BTreeMap::from([(0, 6)]),
+                strategy: None,
+            },
+        );
+        assert!(rmsg.is_none());
+        assert!(matches!(&resp[..], [SMessage::InvalidRequest(_)]));
+        assert!(p.adding.is_empty());
+    }
+}
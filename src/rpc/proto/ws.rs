@@ -3,7 +3,7 @@ use std::io;
 
 // Since we never do large transfers of WS itself this should be
 // reasonable
-const MAX_MSG_BYTES: u64 = 5 * 1000 * 1000;
+pub const MAX_MSG_BYTES: u64 = 5 * 1000 * 1000;
 
 #[derive(Debug)]
 pub enum Frame {
@@ -4,8 +4,9 @@ use std::time;
 use sstream::SStream;
 
 use super::EMPTY_HTTP_RESP;
-use super::proto::message::Error;
+use super::proto::message::{Error, ErrorCode};
 
+use crate::torrent::Preset;
 use crate::util::{IOR, UHashMap, aread};
 
 pub struct Transfers {
@@ -17,6 +18,7 @@ pub enum TransferResult {
         conn: SStream,
         start: bool,
         import: bool,
+        preset: Preset,
         data: Vec<u8>,
         path: Option<String>,
         client: usize,
@@ -38,6 +40,7 @@ struct TorrentTx {
     buf: Vec<u8>,
     start: bool,
     import: bool,
+    preset: Preset,
     path: Option<String>,
     last_action: time::Instant,
 }
@@ -62,6 +65,7 @@ impl Transfers {
         size: u64,
         start: bool,
         import: bool,
+        preset: Preset,
     ) {
         let pos = data.len();
         // Given that this requires an authenticated connection
@@ -78,6 +82,7 @@ impl Transfers {
                 path,
                 start,
                 import,
+                preset,
                 last_action: time::Instant::now(),
             },
         );
@@ -91,7 +96,7 @@ impl Transfers {
         match self.torrents.get_mut(&id).map(|tx| tx.readable()) {
             Some(Ok(true)) => {
                 let mut tx = self.torrents.remove(&id).unwrap();
-                if tx.conn.write(&EMPTY_HTTP_RESP).is_err() {
+                if tx.conn.write_all(&EMPTY_HTTP_RESP).is_err() {
                     // Do nothing, we got the data, so who cares.
                 }
 
@@ -103,6 +108,7 @@ impl Transfers {
                     serial: tx.serial,
                     start: tx.start,
                     import: tx.import,
+                    preset: tx.preset,
                 }
             }
             Some(Ok(false)) => TransferResult::Incomplete,
@@ -111,10 +117,7 @@ impl Transfers {
                 TransferResult::Error {
                     conn: tx.conn,
                     client: tx.client,
-                    err: Error {
-                        serial: Some(tx.serial),
-                        reason: e.to_owned(),
-                    },
+                    err: Error::new(Some(tx.serial), ErrorCode::TransferFailed, e),
                 }
             }
             None => TransferResult::Incomplete,
@@ -134,10 +137,7 @@ impl Transfers {
             res.push((
                 tx.conn,
                 id,
-                Error {
-                    serial: Some(tx.serial),
-                    reason: "Timeout".to_owned(),
-                },
+                Error::new(Some(tx.serial), ErrorCode::TransferFailed, "Timeout"),
             ));
         }
         res
@@ -35,7 +35,7 @@ impl Writer {
                 WR::Complete => {
                     self.next_msg();
                     if self.state.idle() {
-                        return Ok(());
+                        return Writer::flush(w);
                     }
                 }
                 WR::Incomplete => {}
@@ -44,6 +44,18 @@ impl Writer {
         }
     }
 
+    /// TLS streams can accept a message while its records are still buffered, push those
+    /// out too.
+    fn flush<W: io::Write>(w: &mut W) -> io::Result<()> {
+        loop {
+            match w.flush() {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                res => return res,
+            }
+        }
+    }
+
     fn do_write<W: io::Write>(&mut self, w: &mut W) -> io::Result<WR> {
         match self.state {
             State::Idle => Ok(WR::Complete),
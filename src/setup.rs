@@ -0,0 +1,294 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use crate::args::Args;
+use crate::config::{ConfigFile, RpcConfig};
+use crate::util;
+
+/// Config written on first run, with the RPC settings filled in
+const TEMPLATE: &str = include_str!("../example_config.toml");
+const CONFIG_FILE: &str = "synapse.toml";
+const CERT_FILE: &str = "synapse-cert.pem";
+const KEY_FILE: &str = "synapse-key.pem";
+const SYCLI_FILE: &str = "sycli.toml";
+const PASSWORD_LEN: usize = 24;
+
+/// Address and password a client connects to RPC with.
+pub struct Connection {
+    pub url: String,
+    pub password: String,
+}
+
+impl Connection {
+    pub fn new(rpc: &RpcConfig) -> Connection {
+        let scheme = if rpc.ssl_cert.is_empty() { "ws" } else { "wss" };
+        Connection {
+            url: format!("{scheme}://localhost:{}", rpc.port),
+            password: rpc.password.clone(),
+        }
+    }
+
+    fn tls(&self) -> bool {
+        self.url.starts_with("wss:")
+    }
+
+    /// A sycli config with the connection as its default profile.
+    fn sycli_profile(&self) -> String {
+        format!(
+            "[default]\nserver = {}\npassword = {}\n",
+            quoted(&self.url),
+            quoted(&self.password)
+        )
+    }
+
+    fn print(&self, dir: &Path) {
+        println!("RPC URL:  {}", self.url);
+        println!("Password: {}", self.password);
+        if self.tls() {
+            println!("sycli only trusts certificates issued by a public CA, so can't connect");
+            println!("while RPC uses the self-signed one.");
+        } else {
+            let path = dir.join(SYCLI_FILE);
+            let profile = self.sycli_profile().replace('\n', "\\n");
+            println!("Set up sycli with:");
+            println!(
+                "  [ -e \"{0}\" ] || printf '{1}' > \"{0}\"",
+                path.display(),
+                profile
+            );
+        }
+    }
+}
+
+/// Writes a config on the first run of synapse, when none exists yet, and prints how to
+/// connect to it. If it can't be written, synapse runs with the default settings.
+pub fn first_run(args: &Args) {
+    if args.config.is_some() || ConfigFile::exists() {
+        return;
+    }
+    let dir = config_dir();
+    match generate(&dir, args.tls) {
+        Ok(conn) => {
+            println!("Wrote a new config to {}", dir.join(CONFIG_FILE).display());
+            conn.print(&dir);
+        }
+        Err(e) => {
+            eprintln!(
+                "Failed to write a config to {}, using the defaults: {}",
+                dir.display(),
+                e
+            );
+        }
+    }
+}
+
+/// Prints how to connect to RPC with the config in use.
+pub fn print_connection(rpc: &RpcConfig) {
+    Connection::new(rpc).print(&config_dir());
+}
+
+/// Writes a sycli config for connecting to RPC, unless sycli already has one.
+pub fn setup_cli(rpc: &RpcConfig) {
+    let dir = config_dir();
+    let path = dir.join(SYCLI_FILE);
+    match write_sycli(&dir, &Connection::new(rpc)) {
+        Ok(()) => println!("Wrote a sycli profile to {}", path.display()),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            println!(
+                "sycli is already set up in {}, leaving it as is",
+                path.display()
+            );
+        }
+        Err(e) => eprintln!(
+            "Failed to write a sycli profile to {}: {}",
+            path.display(),
+            e
+        ),
+    }
+}
+
+/// Directory the config is written to, which sycli also reads its config from.
+fn config_dir() -> PathBuf {
+    match shellexpand::full("$XDG_CONFIG_HOME") {
+        Ok(dir) if !dir.is_empty() => PathBuf::from(dir.as_ref()),
+        _ => PathBuf::from(shellexpand::tilde("~/.config").as_ref()),
+    }
+}
+
+/// Writes a config to `dir` with auth by a random password and, if `tls` is set, a
+/// self-signed certificate for RPC. Nothing already in `dir` is overwritten, and if any
+/// file can't be written the ones written before it are removed again, so a later run
+/// can start over.
+fn generate(dir: &Path, tls: bool) -> io::Result<Connection> {
+    fs::create_dir_all(dir)?;
+    let mut written = Vec::new();
+    let res = write_config(dir, tls, &mut written);
+    if res.is_err() {
+        for path in written {
+            fs::remove_file(path).ok();
+        }
+    }
+    res
+}
+
+/// Writes the files of `generate`, adding each to `written` once it's complete.
+fn write_config(dir: &Path, tls: bool, written: &mut Vec<PathBuf>) -> io::Result<Connection> {
+    let (cert, key) = if tls {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()])
+            .map_err(io::Error::other)?;
+        let cert_path = dir.join(CERT_FILE);
+        let key_path = dir.join(KEY_FILE);
+        write_new(&key_path, &cert.signing_key.serialize_pem(), 0o600)?;
+        written.push(key_path.clone());
+        write_new(&cert_path, &cert.cert.pem(), 0o644)?;
+        written.push(cert_path.clone());
+        (
+            cert_path.display().to_string(),
+            key_path.display().to_string(),
+        )
+    } else {
+        (String::new(), String::new())
+    };
+    let password = util::random_string(PASSWORD_LEN);
+    let config = fill_template(&[
+        ("auth", "true".to_owned()),
+        ("password", quoted(&password)),
+        ("ssl_cert", quoted(&cert)),
+        ("ssl_key", quoted(&key)),
+    ]);
+    write_new(&dir.join(CONFIG_FILE), &config, 0o600)?;
+    written.push(dir.join(CONFIG_FILE));
+    let rpc = RpcConfig {
+        password,
+        ssl_cert: cert,
+        ssl_key: key,
+        ..Default::default()
+    };
+    Ok(Connection::new(&rpc))
+}
+
+fn write_sycli(dir: &Path, conn: &Connection) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    write_new(&dir.join(SYCLI_FILE), &conn.sycli_profile(), 0o600)
+}
+
+/// The example config with the first `key = ...` line of each setting replaced.
+fn fill_template(settings: &[(&str, String)]) -> String {
+    let mut lines: Vec<_> = TEMPLATE.lines().map(str::to_owned).collect();
+    for (key, value) in settings {
+        let prefix = format!("{key} = ");
+        if let Some(line) = lines.iter_mut().find(|l| l.starts_with(&prefix)) {
+            *line = format!("{prefix}{value}");
+        }
+    }
+    lines.join("\n") + "\n"
+}
+
+fn quoted(s: &str) -> String {
+    toml::Value::from(s).to_string()
+}
+
+/// Creates a file with the given permissions, failing if it already exists. A file which
+/// can't be written in full is removed.
+fn write_new(path: &Path, data: &str, mode: u32) -> io::Result<()> {
+    let mut f = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(mode)
+        .open(path)?;
+    f.write_all(data.as_bytes()).inspect_err(|_| {
+        fs::remove_file(path).ok();
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+    use crate::rpc::{load_certs, load_private_key};
+
+    fn mode(path: PathBuf) -> u32 {
+        fs::metadata(path).unwrap().permissions().mode() & 0o777
+    }
+
+    fn read_config(dir: &Path) -> ConfigFile {
+        toml::from_str(&fs::read_to_string(dir.join(CONFIG_FILE)).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_first_run() {
+        let home = tempfile::tempdir().unwrap();
+        let dir = home.path().join(".config");
+        let conn = generate(&dir, false).unwrap();
+        assert_eq!(conn.url, "ws://localhost:8412");
+        assert_eq!(conn.password.len(), PASSWORD_LEN);
+        assert_eq!(mode(dir.join(CONFIG_FILE)), 0o600);
+        let config = read_config(&dir);
+        assert!(config.rpc.auth);
+        assert_eq!(config.rpc.password, conn.password);
+        assert_eq!(
+            (config.rpc.ssl_cert.as_str(), config.rpc.ssl_key.as_str()),
+            ("", "")
+        );
+
+        write_sycli(&dir, &conn).unwrap();
+        assert_eq!(mode(dir.join(SYCLI_FILE)), 0o600);
+        let sycli: HashMap<String, HashMap<String, String>> =
+            toml::from_str(&fs::read_to_string(dir.join(SYCLI_FILE)).unwrap()).unwrap();
+        assert_eq!(sycli["default"]["server"], conn.url);
+        assert_eq!(sycli["default"]["password"], conn.password);
+
+        // Later runs leave what's there alone
+        let data = fs::read(dir.join(CONFIG_FILE)).unwrap();
+        assert!(generate(&dir, false).is_err());
+        assert!(write_sycli(&dir, &Connection::new(&RpcConfig::default())).is_err());
+        assert_eq!(fs::read(dir.join(CONFIG_FILE)).unwrap(), data);
+        assert_eq!(read_config(&dir).rpc.password, conn.password);
+    }
+
+    #[test]
+    fn test_first_run_cleanup() {
+        let home = tempfile::tempdir().unwrap();
+        let dir = home.path().join(".config");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(CONFIG_FILE), "port = 1").unwrap();
+
+        // The key and certificate written before the config failed are removed again
+        assert!(generate(&dir, true).is_err());
+        assert!(!dir.join(KEY_FILE).exists());
+        assert!(!dir.join(CERT_FILE).exists());
+        assert_eq!(
+            fs::read_to_string(dir.join(CONFIG_FILE)).unwrap(),
+            "port = 1"
+        );
+
+        fs::remove_file(dir.join(CONFIG_FILE)).unwrap();
+        generate(&dir, true).unwrap();
+    }
+
+    #[test]
+    fn test_first_run_tls() {
+        let home = tempfile::tempdir().unwrap();
+        let dir = home.path().join(".config");
+        let conn = generate(&dir, true).unwrap();
+        assert_eq!(conn.url, "wss://localhost:8412");
+        assert_eq!(mode(dir.join(CONFIG_FILE)), 0o600);
+        assert_eq!(mode(dir.join(KEY_FILE)), 0o600);
+
+        let config = read_config(&dir);
+        assert_eq!(config.rpc.password, conn.password);
+        assert_eq!(Path::new(&config.rpc.ssl_cert), dir.join(CERT_FILE));
+        assert_eq!(Path::new(&config.rpc.ssl_key), dir.join(KEY_FILE));
+        rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(
+                load_certs(&config.rpc.ssl_cert).unwrap(),
+                load_private_key(&config.rpc.ssl_key).unwrap(),
+            )
+            .unwrap();
+    }
+}
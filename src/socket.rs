@@ -43,11 +43,17 @@ impl Socket {
 
     #[cfg(test)]
     pub fn empty() -> Socket {
+        Socket::empty_at("127.0.0.1:0".parse().unwrap())
+    }
+
+    /// An unconnected socket which claims to be connected to `addr`.
+    #[cfg(test)]
+    pub fn empty_at(addr: SocketAddr) -> Socket {
         let conn = TcpBuilder::new_v4().unwrap().to_tcp_stream().unwrap();
         Socket {
             conn,
             throttle: None,
-            addr: "127.0.0.1:0".parse().unwrap(),
+            addr,
         }
     }
 
@@ -55,6 +61,10 @@ impl Socket {
         self.addr
     }
 
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        self.conn.set_nodelay(nodelay)
+    }
+
     pub fn from_stream(conn: TcpStream) -> io::Result<Socket> {
         conn.set_nonblocking(true)?;
         let addr = conn.peer_addr()?;
@@ -1,6 +1,8 @@
 use std::time;
 
 const ALPHA: f64 = 0.8;
+/// Minimum interval (in ms) between samples
+const MIN_TICK_MS: f64 = 1.0;
 
 #[derive(Debug)]
 #[allow(clippy::upper_case_acronyms)]
@@ -45,17 +47,33 @@ impl EMA {
         self.dl += amnt;
     }
 
+    /// Starts the next sample now, for an average which wasn't ticked while idle.
+    pub fn resume(&mut self) {
+        self.updated = time::Instant::now();
+    }
+
     pub fn tick(&mut self) {
+        let now = time::Instant::now();
+        if self.update(now.duration_since(self.updated)) {
+            self.updated = now;
+        }
+    }
+
+    /// Folds the bytes transferred over the elapsed interval into the average.
+    /// Intervals too short to measure are skipped, leaving the bytes to be
+    /// counted on the next tick rather than producing a spurious spike.
+    fn update(&mut self, elapsed: time::Duration) -> bool {
+        // Put everything in terms of milliseconds
+        let dur = elapsed.as_secs_f64() * 1000.0;
+        if dur < MIN_TICK_MS {
+            return false;
+        }
         self.accum_ul = (ALPHA * self.ul as f64) + (1.0 - ALPHA) * self.accum_ul;
         self.accum_dl = (ALPHA * self.dl as f64) + (1.0 - ALPHA) * self.accum_dl;
         self.ul = 0;
         self.dl = 0;
-        // Put everything in terms of milliseconds
-        let elapsed = self.updated.elapsed();
-        let dur =
-            (elapsed.as_secs() * 1000) as f64 + f64::from(elapsed.subsec_nanos()) / 1_000_000.0;
         self.accum_time = (ALPHA * dur) + (1.0 - ALPHA) * self.accum_time;
-        self.updated = time::Instant::now();
+        true
     }
 }
 
@@ -81,4 +99,37 @@ mod tests {
 
         assert!((s.avg_ul() as i64 - 10000).abs() < 8000);
     }
+
+    #[test]
+    fn test_ema_synthetic() {
+        let mut s = EMA::new();
+        for _ in 0..20 {
+            s.add_dl(50_000);
+            assert!(s.update(time::Duration::from_millis(500)));
+        }
+        // 50KB every 500ms should converge on 100KB/s
+        assert!((s.avg_dl() as i64 - 100_000).abs() < 1000);
+        assert_eq!(s.avg_ul(), 0);
+
+        // A short burst shouldn't cause the rate to jump wildly
+        s.add_dl(60_000);
+        assert!(s.update(time::Duration::from_millis(500)));
+        assert!(s.avg_dl() < 130_000);
+
+        for _ in 0..20 {
+            assert!(s.update(time::Duration::from_millis(500)));
+        }
+        assert!(!s.active());
+        assert_eq!(s.avg_dl(), 0);
+    }
+
+    #[test]
+    fn test_ema_zero_duration() {
+        let mut s = EMA::new();
+        s.add_ul(1000);
+        assert!(!s.update(time::Duration::from_millis(0)));
+        assert_eq!(s.avg_ul(), 0);
+        assert!(s.update(time::Duration::from_millis(100)));
+        assert!(s.avg_ul() > 0);
+    }
 }
@@ -1,7 +1,9 @@
 use amy::Registrar;
 use std::cell::RefCell;
 use std::collections::HashSet;
-use std::rc::Rc;
+use std::mem;
+use std::rc::{Rc, Weak};
+use std::time::{Duration, Instant};
 
 /// Creates a throttler from which sub throttles may be created.
 /// Note that all created throttle's have a lifetime tied to the
@@ -12,9 +14,25 @@ pub struct Throttler {
     fid: usize,
     dl_data: Rc<RefCell<ThrottleData>>,
     ul_data: Rc<RefCell<ThrottleData>>,
+    /// Per-torrent tiers drawing on the global buckets, which the global rates are
+    /// shared out between
+    tiers: RefCell<Vec<Tier>>,
+    rebalanced: Instant,
 }
 
-const URATE: usize = 15;
+struct Tier {
+    ul: Weak<RefCell<ThrottleData>>,
+    dl: Weak<RefCell<ThrottleData>>,
+}
+
+/// Interval (in ms) of the timer which refills the tokens
+pub const URATE: usize = 15;
+/// Interval (in ms) of the timer which flushes blocked peers
+pub const FLUSH_MS: usize = 50;
+/// Highest torrent priority; throttles at this level may drain the shared bucket completely.
+pub const MAX_PRIORITY: u8 = 5;
+/// Window of the global rate (in ms) held back from throttles below `MAX_PRIORITY`.
+const RESERVE_MS: usize = 250;
 
 impl Throttler {
     /// Creates a new throttler and sets two timers on reg,
@@ -27,7 +45,7 @@ impl Throttler {
         reg: &Registrar,
     ) -> Option<Throttler> {
         let id = reg.set_interval(URATE).ok()?;
-        let fid = reg.set_interval(50).ok()?;
+        let fid = reg.set_interval(FLUSH_MS).ok()?;
         let ut = ThrottleData::new(ul_rate, max_tokens);
         let dt = ThrottleData::new(dl_rate, max_tokens);
         Some(Throttler {
@@ -35,6 +53,8 @@ impl Throttler {
             fid,
             ul_data: Rc::new(RefCell::new(ut)),
             dl_data: Rc::new(RefCell::new(dt)),
+            tiers: RefCell::new(Vec::new()),
+            rebalanced: Instant::now(),
         })
     }
 
@@ -45,21 +65,46 @@ impl Throttler {
     }
 
     pub fn get_throttle(&self, id: usize) -> Throttle {
+        let ul_tier = Rc::new(RefCell::new(ThrottleData::new(
+            None,
+            self.ul_data.borrow().max_tokens,
+        )));
+        let dl_tier = Rc::new(RefCell::new(ThrottleData::new(
+            None,
+            self.dl_data.borrow().max_tokens,
+        )));
+        self.tiers.borrow_mut().push(Tier {
+            ul: Rc::downgrade(&ul_tier),
+            dl: Rc::downgrade(&dl_tier),
+        });
         Throttle {
             ul_data: self.ul_data.clone(),
-            ul_tier: Rc::new(RefCell::new(ThrottleData::new(
-                None,
-                self.ul_data.borrow().max_tokens,
-            ))),
+            ul_tier,
             dl_data: self.dl_data.clone(),
-            dl_tier: Rc::new(RefCell::new(ThrottleData::new(
-                None,
-                self.dl_data.borrow().max_tokens,
-            ))),
+            dl_tier,
             id,
         }
     }
 
+    /// Shares the global rates out between the torrents which used up their allowance
+    /// since the last call, weighted by priority. Whatever the others left unused is
+    /// lent to them, up to each torrent's own rate.
+    pub fn rebalance(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.rebalanced);
+        self.rebalanced = now;
+        self.rebalance_over(elapsed);
+    }
+
+    fn rebalance_over(&mut self, elapsed: Duration) {
+        let mut tiers = self.tiers.borrow_mut();
+        tiers.retain(|t| t.ul.strong_count() > 0);
+        let ul: Vec<_> = tiers.iter().filter_map(|t| t.ul.upgrade()).collect();
+        let dl: Vec<_> = tiers.iter().filter_map(|t| t.dl.upgrade()).collect();
+        share(self.ul_data.borrow().rate, &ul, elapsed);
+        share(self.dl_data.borrow().rate, &dl, elapsed);
+    }
+
     pub fn ul_rate(&mut self) -> Option<i64> {
         self.ul_data.borrow().rate
     }
@@ -84,6 +129,17 @@ impl Throttler {
         self.fid
     }
 
+    /// Replaces the ids of the refill and flush timers after they were set again.
+    pub fn set_timers(&mut self, id: usize, fid: usize) {
+        self.id = id;
+        self.fid = fid;
+    }
+
+    /// Whether no throttle is waiting on tokens, so the timers can stop for a while.
+    pub fn idle(&self) -> bool {
+        self.ul_data.borrow().throttled.is_empty() && self.dl_data.borrow().throttled.is_empty()
+    }
+
     pub fn flush_ul(&mut self) -> Vec<usize> {
         let mut ul_data = self.ul_data.borrow_mut();
         ul_data.throttled.drain().collect()
@@ -98,15 +154,25 @@ impl Throttler {
 struct ThrottleData {
     rate: Option<i64>,
     tokens: usize,
+    /// Byte-microseconds left over from the last refill, less than a whole token
+    carry: u128,
+    refilled: Instant,
     epoch: usize,
     max_tokens: usize,
     last_used: u64,
+    priority: u8,
     throttled: HashSet<usize>,
+    /// Part of the global rate lent to a torrent's tier by the last rebalance
+    share: Option<i64>,
+    /// Bytes used since the last rebalance
+    used: u64,
+    /// Whether the tier ran out of tokens since the last rebalance
+    saturated: bool,
 }
 
 /// Throttle mechanism based on the token bucket algorithm.
-/// Expected to be called every millisecond, and operates on
-/// a KB/s rate scale.
+/// Tokens are refilled according to the time elapsed since the last
+/// refill, so the timer driving it may be stretched or stopped.
 #[derive(Clone)]
 pub struct Throttle {
     pub id: usize,
@@ -128,22 +194,25 @@ impl Throttle {
     }
 
     pub fn get_bytes_dl(&mut self, amnt: usize) -> Result<(), ()> {
-        while self.dl_tier.borrow().epoch != self.dl_data.borrow().epoch {
-            self.dl_tier.borrow_mut().add_tokens();
-        }
+        let epoch = self.dl_data.borrow().epoch;
+        self.dl_tier.borrow_mut().catch_up(epoch);
         if self.dl_rate() == Some(-1) {
-            self.dl_tier.borrow_mut().last_used += amnt as u64;
-            self.dl_data.borrow_mut().last_used += amnt as u64;
+            self.dl_tier.borrow_mut().spend(amnt);
+            self.dl_data.borrow_mut().spend(amnt);
             return Ok(());
         }
-        let pres = self.dl_data.borrow_mut().get_tokens(amnt);
+        let priority = self.dl_tier.borrow().priority;
+        let reserve = self.dl_data.borrow().reserve(priority);
+        let pres = self.dl_data.borrow_mut().get_tokens_reserved(amnt, reserve);
         if pres.is_err() {
+            self.dl_tier.borrow_mut().saturated = true;
             self.dl_data.borrow_mut().throttled.insert(self.id);
             return Err(());
         }
 
         let res = self.dl_tier.borrow_mut().get_tokens(amnt);
         if res.is_err() {
+            self.dl_tier.borrow_mut().saturated = true;
             self.dl_data.borrow_mut().restore_tokens(amnt);
             self.dl_data.borrow_mut().throttled.insert(self.id);
             return Err(());
@@ -152,22 +221,25 @@ impl Throttle {
     }
 
     pub fn get_bytes_ul(&mut self, amnt: usize) -> Result<(), ()> {
-        while self.ul_tier.borrow().epoch != self.ul_data.borrow().epoch {
-            self.ul_tier.borrow_mut().add_tokens();
-        }
+        let epoch = self.ul_data.borrow().epoch;
+        self.ul_tier.borrow_mut().catch_up(epoch);
         if self.ul_rate() == Some(-1) {
-            self.ul_tier.borrow_mut().last_used += amnt as u64;
-            self.ul_data.borrow_mut().last_used += amnt as u64;
+            self.ul_tier.borrow_mut().spend(amnt);
+            self.ul_data.borrow_mut().spend(amnt);
             return Ok(());
         }
-        let pres = self.ul_data.borrow_mut().get_tokens(amnt);
+        let priority = self.ul_tier.borrow().priority;
+        let reserve = self.ul_data.borrow().reserve(priority);
+        let pres = self.ul_data.borrow_mut().get_tokens_reserved(amnt, reserve);
         if pres.is_err() {
+            self.ul_tier.borrow_mut().saturated = true;
             self.ul_data.borrow_mut().throttled.insert(self.id);
             return Err(());
         }
 
         let res = self.ul_tier.borrow_mut().get_tokens(amnt);
         if res.is_err() {
+            self.ul_tier.borrow_mut().saturated = true;
             self.ul_data.borrow_mut().restore_tokens(amnt);
             self.ul_data.borrow_mut().throttled.insert(self.id);
             return Err(());
@@ -175,6 +247,13 @@ impl Throttle {
         Ok(())
     }
 
+    /// Sets the priority used to weight this throttle's share of the global bucket.
+    pub fn set_priority(&mut self, priority: u8) {
+        let priority = priority.min(MAX_PRIORITY);
+        self.ul_tier.borrow_mut().priority = priority;
+        self.dl_tier.borrow_mut().priority = priority;
+    }
+
     pub fn set_stalled_dl(&mut self) {
         self.dl_data.borrow_mut().throttled.insert(self.id);
     }
@@ -218,57 +297,117 @@ impl ThrottleData {
     fn new(rate: Option<i64>, max_tokens: usize) -> ThrottleData {
         ThrottleData {
             tokens: 0,
+            carry: 0,
+            refilled: Instant::now(),
             rate,
             max_tokens,
             throttled: HashSet::with_capacity(0),
             last_used: 0,
+            priority: MAX_PRIORITY,
             epoch: 0,
+            share: None,
+            used: 0,
+            saturated: false,
         }
     }
 
     /// Adds some amount of tokens back.
     fn restore_tokens(&mut self, amnt: usize) {
         self.last_used -= amnt as u64;
+        self.used = self.used.saturating_sub(amnt as u64);
         self.tokens += amnt;
     }
 
-    /// This method must be called every URATE milliseconds and returns
-    /// (self.last_used) * 1000/URATE - the bits/s, clearing self.last_used
+    fn spend(&mut self, amnt: usize) {
+        self.last_used += amnt as u64;
+        self.used += amnt as u64;
+    }
+
+    /// Rate the bucket fills at, which is the configured rate capped by any share of the
+    /// global rate.
+    fn limit(&self) -> Option<i64> {
+        match (self.rate, self.share) {
+            (Some(r), Some(s)) if r >= 0 => Some(r.min(s)),
+            (None, s) => s,
+            (r, _) => r,
+        }
+    }
+
+    /// Refills the tokens for the time elapsed since the last refill, returning the
+    /// bytes used in the meantime and clearing them.
     fn add_tokens(&mut self) -> u64 {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled);
+        self.refilled = now;
+        self.refill(elapsed)
+    }
+
+    fn refill(&mut self, elapsed: Duration) -> u64 {
         self.epoch = self.epoch.wrapping_add(1);
         let drained = self.last_used;
         self.last_used = 0;
-        self.tokens += if let Some(r) = self.rate {
-            if r > 0 {
-                (r as usize * URATE) / 1000
-            } else {
-                0
+        match self.limit() {
+            Some(r) if r > 0 => {
+                let earned = r as u128 * elapsed.as_micros() + self.carry;
+                self.carry = earned % 1_000_000;
+                let earned = (earned / 1_000_000).min(self.max_tokens as u128) as usize;
+                self.tokens = self.tokens.saturating_add(earned);
             }
-        } else {
-            0
-        };
+            _ => self.carry = 0,
+        }
         if self.tokens >= self.max_tokens {
             self.tokens = self.max_tokens;
+            self.carry = 0;
         }
         drained
     }
 
+    /// Refills a torrent's tier once for all the time since it last caught up with the
+    /// shared bucket at `epoch`.
+    fn catch_up(&mut self, epoch: usize) {
+        if self.epoch != epoch {
+            self.add_tokens();
+            self.epoch = epoch;
+        }
+    }
+
+    /// Amount of tokens which a throttle of the given priority must leave
+    /// in the bucket, so that higher priority torrents get first pick of the
+    /// available bandwidth.
+    fn reserve(&self, priority: u8) -> usize {
+        match self.rate {
+            Some(r) if r > 0 => {
+                let window = (r as usize * RESERVE_MS) / 1000;
+                let reserve = window * usize::from(MAX_PRIORITY - priority.min(MAX_PRIORITY))
+                    / usize::from(MAX_PRIORITY);
+                reserve.min(self.max_tokens / 2)
+            }
+            _ => 0,
+        }
+    }
+
     /// Attempt to extract amnt tokens from the throttler.
     fn get_tokens(&mut self, amnt: usize) -> Result<(), ()> {
-        match self.rate {
+        self.get_tokens_reserved(amnt, 0)
+    }
+
+    /// Attempt to extract amnt tokens from the throttler, failing if fewer than
+    /// reserve tokens would remain afterwards.
+    fn get_tokens_reserved(&mut self, amnt: usize, reserve: usize) -> Result<(), ()> {
+        match self.limit() {
             None => {
-                self.last_used += amnt as u64;
+                self.spend(amnt);
                 Ok(())
             }
             Some(i) if i < 0 => {
-                self.last_used += amnt as u64;
+                self.spend(amnt);
                 Ok(())
             }
             Some(_) => {
-                if amnt > self.tokens {
+                if amnt + reserve > self.tokens {
                     Err(())
                 } else {
-                    self.last_used += amnt as u64;
+                    self.spend(amnt);
                     self.tokens -= amnt;
                     Ok(())
                 }
@@ -276,3 +415,168 @@ impl ThrottleData {
         }
     }
 }
+
+/// Splits a global rate between tiers. Tiers which had enough are charged for what they
+/// used and otherwise left alone; the rest of the rate is divided between the saturated
+/// ones by priority, giving any whose own rate is lower just that.
+fn share(rate: Option<i64>, tiers: &[Rc<RefCell<ThrottleData>>], elapsed: Duration) {
+    let mut avail = rate.filter(|&r| r > 0);
+    let mut contending = Vec::new();
+    for tier in tiers {
+        let mut t = tier.borrow_mut();
+        let used = mem::take(&mut t.used);
+        let saturated = mem::replace(&mut t.saturated, false);
+        t.share = None;
+        let Some(a) = avail.as_mut() else {
+            continue;
+        };
+        if t.rate.is_some_and(|r| r < 0) {
+            // Exempt from the global rate entirely
+        } else if saturated {
+            contending.push(tier);
+        } else {
+            let used = u128::from(used) * 1_000_000 / elapsed.as_micros().max(1);
+            *a = a.saturating_sub(used as i64).max(0);
+        }
+    }
+    let Some(mut avail) = avail else {
+        return;
+    };
+
+    let weight = |t: &Rc<RefCell<ThrottleData>>| i64::from(t.borrow().priority) + 1;
+    while !contending.is_empty() {
+        let total: i64 = contending.iter().map(|t| weight(t)).sum();
+        let (capped, rest): (Vec<_>, Vec<_>) = contending.into_iter().partition(|t| {
+            t.borrow()
+                .rate
+                .is_some_and(|r| r <= avail * weight(t) / total)
+        });
+        if capped.is_empty() {
+            for t in rest {
+                let share = avail * weight(t) / total;
+                t.borrow_mut().share = Some(share);
+            }
+            break;
+        }
+        for t in capped {
+            avail -= t.borrow().rate.unwrap();
+        }
+        contending = rest;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_priority_reserve() {
+        let mut data = ThrottleData::new(Some(100_000), 1_000_000);
+        data.refill(Duration::from_millis(1500));
+        assert_eq!(data.reserve(MAX_PRIORITY), 0);
+        assert!(data.reserve(1) > data.reserve(4));
+        assert_eq!(data.reserve(0), 25_000);
+
+        let tokens = data.tokens;
+        let (low, high) = (data.reserve(1), data.reserve(5));
+        assert!(data.get_tokens_reserved(tokens - 1000, low).is_err());
+        assert!(data.get_tokens_reserved(tokens - 1000, high).is_ok());
+    }
+
+    #[test]
+    fn test_refill_elapsed() {
+        // Refilling once after a long gap gives as many tokens as refilling every tick
+        let mut ticked = ThrottleData::new(Some(10_000), 1_000_000);
+        for _ in 0..200 {
+            ticked.refill(Duration::from_millis(URATE as u64));
+        }
+        let mut stretched = ThrottleData::new(Some(10_000), 1_000_000);
+        stretched.refill(Duration::from_millis(200 * URATE as u64));
+        assert_eq!(ticked.tokens, 30_000);
+        assert_eq!(stretched.tokens, ticked.tokens);
+
+        // Fractions of a token carry over rather than being lost each tick
+        let mut slow = ThrottleData::new(Some(10), 1_000_000);
+        for _ in 0..100 {
+            slow.refill(Duration::from_millis(10));
+        }
+        assert_eq!(slow.tokens, 10);
+
+        // The bucket still can't overflow however long the gap
+        stretched.refill(Duration::from_secs(3600));
+        assert_eq!(stretched.tokens, 1_000_000);
+    }
+
+    fn tier(rate: Option<i64>, priority: u8) -> Rc<RefCell<ThrottleData>> {
+        let mut data = ThrottleData::new(rate, 1_000_000);
+        data.priority = priority;
+        Rc::new(RefCell::new(data))
+    }
+
+    #[test]
+    fn test_share() {
+        let sec = Duration::from_secs(1);
+        let (a, b, c) = (tier(None, 5), tier(None, 2), tier(Some(10_000), 5));
+        let tiers = [a.clone(), b.clone(), c.clone()];
+
+        // What b leaves unused goes to a, as c can't take more than its own rate
+        a.borrow_mut().saturated = true;
+        b.borrow_mut().used = 20_000;
+        c.borrow_mut().saturated = true;
+        share(Some(100_000), &tiers, sec);
+        assert_eq!(a.borrow().limit(), Some(70_000));
+        assert_eq!(b.borrow().limit(), None);
+        assert_eq!(c.borrow().limit(), Some(10_000));
+
+        // Contending torrents split the rest by priority
+        a.borrow_mut().saturated = true;
+        b.borrow_mut().saturated = true;
+        c.borrow_mut().used = 10_000;
+        share(Some(100_000), &tiers, sec);
+        assert_eq!(a.borrow().limit(), Some(60_000));
+        assert_eq!(b.borrow().limit(), Some(30_000));
+        assert_eq!(c.borrow().limit(), Some(10_000));
+
+        // Nothing is held back without a global rate
+        a.borrow_mut().saturated = true;
+        share(None, &tiers, sec);
+        assert!(tiers.iter().all(|t| t.borrow().share.is_none()));
+    }
+
+    #[test]
+    fn test_rebalance() {
+        let poll = amy::Poller::new().unwrap();
+        let mut throttler =
+            Throttler::new(None, Some(100_000), 1_000_000, &poll.get_registrar()).unwrap();
+        let mut busy = throttler.get_throttle(0);
+        let mut light = throttler.get_throttle(1);
+        throttler
+            .ul_data
+            .borrow_mut()
+            .refill(Duration::from_secs(1));
+
+        // A torrent with few peers uses a little, the other wants all it can get
+        assert!(light.get_bytes_ul(16_384).is_ok());
+        assert!(busy.get_bytes_ul(70_000).is_ok());
+        assert!(busy.get_bytes_ul(70_000).is_err());
+        throttler.rebalance_over(Duration::from_secs(1));
+        assert_eq!(busy.ul_tier.borrow().limit(), Some(100_000 - 16_384));
+        assert_eq!(light.ul_tier.borrow().limit(), None);
+
+        // Once lent, the share is what the busy torrent's tier fills at
+        busy.ul_tier.borrow_mut().refill(Duration::from_secs(1));
+        assert_eq!(busy.ul_tier.borrow().tokens, 100_000 - 16_384);
+
+        // Tiers of dropped torrents are forgotten
+        drop(light);
+        throttler.rebalance_over(Duration::from_secs(1));
+        assert_eq!(throttler.tiers.borrow().len(), 1);
+    }
+
+    #[test]
+    fn test_unlimited_reserve() {
+        let mut data = ThrottleData::new(None, 1_000_000);
+        assert_eq!(data.reserve(0), 0);
+        assert!(data.get_tokens_reserved(16_384, data.reserve(0)).is_ok());
+    }
+}
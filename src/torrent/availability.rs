@@ -0,0 +1,122 @@
+use crate::torrent::Bitfield;
+
+/// Tracks the number of pieces held across all connected peers, allowing
+/// the swarm availability to be read without walking every peer bitfield.
+#[derive(Clone, Debug, Default)]
+pub struct Availability {
+    held: u64,
+    /// Number of peers holding each piece
+    copies: Vec<u32>,
+}
+
+impl Availability {
+    pub fn new() -> Availability {
+        Availability::default()
+    }
+
+    /// Recounts from scratch using the given peer bitfields.
+    pub fn reset<'a, I: IntoIterator<Item = &'a Bitfield>>(&mut self, peers: I) {
+        self.held = 0;
+        self.copies.clear();
+        for pieces in peers {
+            self.held += pieces.set();
+            self.add(pieces);
+        }
+    }
+
+    /// Accounts for a peer's bitfield being replaced wholesale.
+    pub fn replace(&mut self, prev: &Bitfield, cur: &Bitfield) {
+        self.held = (self.held + cur.set()).saturating_sub(prev.set());
+        self.sub(prev);
+        self.add(cur);
+    }
+
+    /// Accounts for a peer announcing a single new piece.
+    pub fn have(&mut self, piece: u32) {
+        self.held += 1;
+        let piece = piece as usize;
+        if self.copies.len() <= piece {
+            self.copies.resize(piece + 1, 0);
+        }
+        self.copies[piece] += 1;
+    }
+
+    /// Accounts for a peer leaving the swarm.
+    pub fn remove(&mut self, pieces: &Bitfield) {
+        self.held = self.held.saturating_sub(pieces.set());
+        self.sub(pieces);
+    }
+
+    /// Number of connected peers holding `piece`.
+    pub fn copies(&self, piece: u32) -> u32 {
+        self.copies.get(piece as usize).copied().unwrap_or(0)
+    }
+
+    fn add(&mut self, pieces: &Bitfield) {
+        if self.copies.len() < pieces.len() as usize {
+            self.copies.resize(pieces.len() as usize, 0);
+        }
+        for piece in pieces.iter() {
+            self.copies[piece as usize] += 1;
+        }
+    }
+
+    fn sub(&mut self, pieces: &Bitfield) {
+        for piece in pieces.iter() {
+            if let Some(c) = self.copies.get_mut(piece as usize) {
+                *c = c.saturating_sub(1);
+            }
+        }
+    }
+
+    /// Average number of complete copies of a torrent with the given
+    /// piece count which are visible among connected peers.
+    pub fn value(&self, pieces: u64) -> f32 {
+        if pieces == 0 {
+            return 0.;
+        }
+        (self.held as f64 / pieces as f64) as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Availability;
+    use crate::torrent::Bitfield;
+
+    fn field(len: u64, set: &[u64]) -> Bitfield {
+        let mut b = Bitfield::new(len);
+        for &i in set {
+            b.set_bit(i);
+        }
+        b
+    }
+
+    #[test]
+    fn test_availability() {
+        let peers = [field(4, &[0, 1, 2, 3]), field(4, &[0, 1]), field(4, &[3])];
+        let mut a = Availability::new();
+        for p in &peers {
+            a.replace(&Bitfield::new(4), p);
+        }
+        assert_eq!(a.value(4), 1.75);
+        assert_eq!(a.copies(0), 2);
+        assert_eq!(a.copies(2), 1);
+
+        // A have from the third peer brings it to 2 pieces
+        a.have(2);
+        assert_eq!(a.value(4), 2.);
+        assert_eq!(a.copies(2), 2);
+
+        a.remove(&peers[0]);
+        assert_eq!(a.value(4), 1.);
+        assert_eq!(a.copies(0), 1);
+        assert_eq!(a.copies(2), 1);
+
+        a.reset(&peers[1..]);
+        assert_eq!(a.value(4), 0.75);
+        assert_eq!(a.copies(2), 0);
+        assert_eq!(a.copies(9), 0);
+        assert_eq!(a.value(0), 0.);
+    }
+}
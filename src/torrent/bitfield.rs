@@ -34,7 +34,7 @@ impl Bitfield {
         };
         let res = Bitfield::I {
             len,
-            set: i.iter().count() as u64,
+            set: i.count_ones(),
             data: i.into_data(),
         };
         if res.complete() {
@@ -215,6 +215,87 @@ impl Bitfield {
         false
     }
 
+    /// Pieces set in both fields.
+    pub fn and(&self, other: &Bitfield) -> Bitfield {
+        self.combine(other, |a, b| a & b)
+    }
+
+    /// Pieces set in either field.
+    #[cfg(test)]
+    pub fn or(&self, other: &Bitfield) -> Bitfield {
+        self.combine(other, |a, b| a | b)
+    }
+
+    /// Pieces set in this field but not in `other`.
+    pub fn andnot(&self, other: &Bitfield) -> Bitfield {
+        self.combine(other, |a, b| a & !b)
+    }
+
+    /// Whether any piece is set in this field but not in `other`, without building the
+    /// difference.
+    pub fn has_any_andnot(&self, other: &Bitfield) -> bool {
+        let words = protocol::Bitfield::bytes(self).div_ceil(8);
+        (0..words).any(|i| self.word(i) & !other.word(i) != 0)
+    }
+
+    /// Counts the set bits from the data itself, rather than the running count.
+    pub fn count_ones(&self) -> u64 {
+        let words = protocol::Bitfield::bytes(self).div_ceil(8);
+        (0..words)
+            .map(|i| u64::from(self.word(i).count_ones()))
+            .sum()
+    }
+
+    /// Applies `op` to the fields 64 bits at a time. The result has the length of this
+    /// field, and `other` is treated as unset past its end.
+    fn combine<F: Fn(u64, u64) -> u64>(&self, other: &Bitfield, op: F) -> Bitfield {
+        let size = protocol::Bitfield::bytes(self);
+        let mut data = Vec::with_capacity(size.next_multiple_of(8));
+        let mut set = 0;
+        for i in 0..size.div_ceil(8) {
+            let word = op(self.word(i), other.word(i)) & self.mask(i);
+            set += u64::from(word.count_ones());
+            data.extend_from_slice(&word.to_be_bytes());
+        }
+        data.truncate(size);
+        let len = self.len();
+        if len > 0 && set == len {
+            Bitfield::C { len }
+        } else {
+            Bitfield::I {
+                len,
+                data: data.into_boxed_slice(),
+                set,
+            }
+        }
+    }
+
+    /// The `i`th 64 bit word of the field, most significant bit first, with spare
+    /// bits past the end unset.
+    fn word(&self, i: usize) -> u64 {
+        let start = i * 8;
+        let mut buf = [0; 8];
+        match self {
+            Bitfield::I { data, .. } => {
+                let end = data.len().min(start + 8);
+                if start < end {
+                    buf[..end - start].copy_from_slice(&data[start..end]);
+                }
+            }
+            Bitfield::C { .. } => buf = [0xff; 8],
+        }
+        u64::from_be_bytes(buf) & self.mask(i)
+    }
+
+    /// Bits of the `i`th word which lie within the field.
+    fn mask(&self, i: usize) -> u64 {
+        match self.len().saturating_sub(i as u64 * 64) {
+            0 => 0,
+            bits if bits >= 64 => u64::MAX,
+            bits => !(u64::MAX >> bits),
+        }
+    }
+
     pub fn b64(&self) -> String {
         BASE64_STANDARD.encode(self.data())
     }
@@ -439,6 +520,75 @@ mod tests {
         assert!(pf1.usable(&pf2));
     }
 
+    fn bits(bf: &Bitfield) -> Vec<bool> {
+        (0..bf.len()).map(|i| bf.has_bit(i)).collect()
+    }
+
+    #[test]
+    fn test_set_ops() {
+        // Spare bits set past the end of the field, as a peer may send
+        let a = Bitfield::from(
+            &[0b1010_1100, 0xff, 0xff, 0x0f, 0xa5, 0x5a, 0xff, 0x00, 0xf7],
+            70,
+        );
+        let mut b = Bitfield::new(70);
+        for i in (0..70).step_by(3) {
+            b.set_bit(i);
+        }
+        let (a_bits, b_bits) = (bits(&a), bits(&b));
+        let check = |res: Bitfield, op: fn(bool, bool) -> bool| {
+            assert_eq!(res.len(), 70);
+            let expected: Vec<_> = a_bits
+                .iter()
+                .zip(&b_bits)
+                .map(|(&x, &y)| op(x, y))
+                .collect();
+            assert_eq!(bits(&res), expected);
+            assert_eq!(res.set(), expected.iter().filter(|&&x| x).count() as u64);
+            assert_eq!(res.count_ones(), res.set());
+            // Spare bits stay unset
+            if let Bitfield::I { data, .. } = res {
+                assert_eq!(data.len(), 9);
+                assert_eq!(data[8] & 0x03, 0);
+            }
+        };
+        check(a.and(&b), |x, y| x && y);
+        check(a.or(&b), |x, y| x || y);
+        check(a.andnot(&b), |x, y| x && !y);
+        check(b.andnot(&a), |x, y| y && !x);
+        assert!(a.has_any_andnot(&b) && b.has_any_andnot(&a));
+        assert!(!a.has_any_andnot(&a.or(&b)));
+        assert_eq!(a.count_ones(), a.set());
+        assert_eq!(a.count_ones(), a.iter().count() as u64);
+    }
+
+    #[test]
+    fn test_set_ops_complete() {
+        let c = Bitfield::from(&[0xff, 0xff], 13);
+        assert_matches!(c, Bitfield::C { .. });
+        assert_eq!(c.count_ones(), 13);
+        let mut i = Bitfield::new(13);
+        i.set_bit(0);
+        i.set_bit(12);
+
+        assert_matches!(i.or(&c), Bitfield::C { len: 13 });
+        assert_eq!(bits(&c.and(&i)), bits(&i));
+        let missing = c.andnot(&i);
+        assert_matches!(missing, Bitfield::I { set: 11, .. });
+        assert!(!missing.has_bit(0) && !missing.has_bit(12));
+        assert_eq!(i.andnot(&c).count_ones(), 0);
+        assert!(!i.has_any_andnot(&c) && c.has_any_andnot(&i));
+
+        // A shorter field counts as unset past its end
+        let short = Bitfield::from(&[0xff], 5);
+        assert_eq!(
+            c.andnot(&short).iter().collect::<Vec<_>>(),
+            (5..13).collect::<Vec<_>>()
+        );
+        assert_eq!(short.or(&c).len(), 5);
+        assert_eq!(Bitfield::new(0).or(&c).count_ones(), 0);
+    }
+
     #[test]
     fn test_iter() {
         let mut pf = Bitfield::new(10);
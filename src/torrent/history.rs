@@ -0,0 +1,76 @@
+use std::collections::VecDeque;
+
+use chrono::Utc;
+
+use crate::rpc::proto::message::{HistoryAction, HistoryEntry};
+
+/// Most entries kept per torrent, older ones are dropped
+const MAX_ENTRIES: usize = 256;
+/// Identity recorded for actions synapse takes by itself
+pub const SYSTEM: &str = "system";
+
+/// Significant actions taken on a torrent and who took them. It's persisted in a sidecar
+/// of the session file, which is left behind when the torrent is removed.
+#[derive(Debug, Default)]
+pub struct History {
+    entries: VecDeque<HistoryEntry>,
+}
+
+impl History {
+    pub fn load(data: &[u8]) -> Option<History> {
+        let entries: Vec<HistoryEntry> = bincode::deserialize(data).ok()?;
+        let mut history = History::default();
+        history.entries.extend(entries);
+        history.trim();
+        Some(history)
+    }
+
+    pub fn record(&mut self, by: &str, action: HistoryAction, detail: Option<String>) {
+        self.entries.push_back(HistoryEntry {
+            time: Utc::now(),
+            by: by.to_owned(),
+            action,
+            detail,
+        });
+        self.trim();
+    }
+
+    pub fn entries(&self) -> Vec<HistoryEntry> {
+        self.entries.iter().cloned().collect()
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        bincode::serialize(&self.entries).expect("Serialization failed!")
+    }
+
+    fn trim(&mut self) {
+        while self.entries.len() > MAX_ENTRIES {
+            self.entries.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_history() {
+        let mut h = History::default();
+        for i in 0..MAX_ENTRIES + 2 {
+            h.record("10.0.0.1", HistoryAction::Priority, Some(i.to_string()));
+        }
+        h.record(SYSTEM, HistoryAction::Pause, None);
+
+        let h = History::load(&h.serialize()).unwrap();
+        let entries = h.entries();
+        assert_eq!(entries.len(), MAX_ENTRIES);
+        assert_eq!(entries[0].detail.as_deref(), Some("3"));
+        let last = entries.last().unwrap();
+        assert_eq!(
+            (last.by.as_str(), last.action),
+            (SYSTEM, HistoryAction::Pause)
+        );
+        assert!(History::load(b"garbage").is_none());
+    }
+}
@@ -1,5 +1,7 @@
 use std::collections::BTreeMap;
-use std::path::PathBuf;
+use std::ffi::OsString;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Component, Path, PathBuf};
 use std::sync::Arc;
 use std::{cmp, fmt, mem};
 
@@ -8,7 +10,18 @@ use url::Url;
 
 use crate::bencode::BEncode;
 use crate::disk;
-use crate::util::{hash_to_id, id_to_hash, sha1_hash};
+use crate::util::{FHashSet, hash_to_id, id_to_hash, sha1_hash, sha256_hash};
+
+/// Size of the blocks hashed into the leaves of v2 merkle trees
+const V2_BLOCK: usize = 16_384;
+/// Longest file name most filesystems allow, in bytes
+const MAX_NAME_LEN: usize = 255;
+/// Device names Windows reserves, with or without an extension
+const RESERVED_NAMES: [&[u8]; 22] = [
+    b"CON", b"PRN", b"AUX", b"NUL", b"COM1", b"COM2", b"COM3", b"COM4", b"COM5", b"COM6", b"COM7",
+    b"COM8", b"COM9", b"LPT1", b"LPT2", b"LPT3", b"LPT4", b"LPT5", b"LPT6", b"LPT7", b"LPT8",
+    b"LPT9",
+];
 
 #[derive(Clone)]
 pub struct Info {
@@ -24,11 +37,37 @@ pub struct Info {
     pub hash: [u8; 20],
     pub files: Vec<File>,
     pub private: bool,
-    /// Name field from the info dictionary, if any
+    /// Name field from the info dictionary, if any. `name` is a lossy conversion of
+    /// it, which won't match the name on disk when it isn't valid UTF-8.
     pub be_name: Option<Vec<u8>>,
     /// Maps piece idx -> file idx + file offset
     pub piece_idx: Vec<(usize, u64)>,
     pub url_list: Vec<Vec<Arc<Url>>>,
+    /// Metadata format, which decides how pieces are hashed
+    pub version: MetaVersion,
+    /// The info dictionary as it was parsed, empty for magnets and restored torrents.
+    /// Unlike `to_bencode` this keeps file names as they are in the torrent rather
+    /// than as they were made safe to create.
+    pub info_bytes: Vec<u8>,
+}
+
+/// Metadata format of a torrent.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MetaVersion {
+    V1,
+    /// v1 metadata with an equivalent v2 file tree, whose v1 piece hashes are used
+    Hybrid,
+    /// v2 only (BEP 52), pieces are checked against the merkle trees of their files
+    V2,
+}
+
+/// Hash function piece hashes were made with.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PieceHash {
+    /// v1 torrents, including the v1 half of hybrid v1/v2 torrents
+    Sha1,
+    /// v2 torrents (BEP 52)
+    Sha256,
 }
 
 impl fmt::Debug for Info {
@@ -55,6 +94,8 @@ impl fmt::Debug for Info {
 
 #[derive(Clone, Debug)]
 pub struct File {
+    /// Path relative to the download directory, made of the raw bytes of the torrent's
+    /// path, so names which aren't valid UTF-8 survive
     pub path: PathBuf,
     pub length: u64,
 }
@@ -68,8 +109,9 @@ impl File {
             d.remove(b"length".as_ref()),
         ) {
             (Some(v), None, Some(l)) => {
+                let name = path_part(v).ok_or("Path must be a valid string.")?;
                 let f = File {
-                    path: PathBuf::from(v.into_string().ok_or("Path must be a valid string.")?),
+                    path: PathBuf::from(sanitize(name)?),
                     length: l.into_int().ok_or("File length must be a valid int")? as u64,
                 };
                 Ok(f)
@@ -77,10 +119,12 @@ impl File {
             (None, Some(path), Some(l)) => {
                 let mut p = PathBuf::new();
                 for dir in path.into_list().ok_or("File path should be a list")? {
-                    p.push(
-                        dir.into_string()
-                            .ok_or("File path parts should be strings")?,
-                    );
+                    p.push(sanitize(
+                        path_part(dir).ok_or("File path parts should be strings")?,
+                    )?);
+                }
+                if p.as_os_str().is_empty() {
+                    return Err("File path must not be empty");
                 }
                 let f = File {
                     path: p,
@@ -148,6 +192,8 @@ impl Info {
             be_name: None,
             piece_idx: vec![],
             url_list: vec![url_list],
+            version: MetaVersion::V1,
+            info_bytes: Vec::new(),
         })
     }
 
@@ -155,6 +201,52 @@ impl Info {
         !self.hashes.is_empty()
     }
 
+    /// Which hash function the pieces are checked with.
+    pub fn piece_hash(&self) -> PieceHash {
+        match self.version {
+            MetaVersion::V1 | MetaVersion::Hybrid => PieceHash::Sha1,
+            MetaVersion::V2 => PieceHash::Sha256,
+        }
+    }
+
+    /// Whether `data` hashes to piece `idx`.
+    pub fn piece_valid(&self, idx: u32, data: &[u8]) -> bool {
+        let expected = &self.hashes[idx as usize][..];
+        match self.piece_hash() {
+            PieceHash::Sha1 => sha1_hash(data)[..] == *expected,
+            PieceHash::Sha256 => {
+                // v2 pieces hold a single file, the padding after it isn't part of the hash
+                let (file, offset) = self.piece_idx[idx as usize];
+                let flen = self.files[file].length;
+                let len = cmp::min(data.len() as u64, flen - offset) as usize;
+                // Files of one piece are hashed by their own root, which has only as many
+                // leaves as they need
+                let leaves = if flen <= u64::from(self.piece_len) {
+                    len.div_ceil(V2_BLOCK).next_power_of_two()
+                } else {
+                    self.piece_len as usize / V2_BLOCK
+                };
+                merkle_root(&data[..len], leaves)[..] == *expected
+            }
+        }
+    }
+
+    /// The torrent's top level file or directory in the download directory. Unlike
+    /// `name` this is exactly what's on disk.
+    pub fn root(&self) -> PathBuf {
+        match self.files.first().and_then(|f| f.path.components().next()) {
+            Some(Component::Normal(root)) => PathBuf::from(root),
+            _ => PathBuf::from(&self.name),
+        }
+    }
+
+    /// Whether `info_bytes` are the info dictionary of this torrent.
+    pub fn matches(&self, info_bytes: &[u8]) -> bool {
+        info_hash(self.version, info_bytes) == self.hash
+    }
+
+    /// Rebuilds a v1 info dictionary from the parsed fields. It only hashes to the
+    /// infohash if no file names had to be changed, so `info_bytes` is preferred.
     pub fn to_bencode(&self) -> BEncode {
         let mut info = BTreeMap::new();
         if let Some(ref n) = self.be_name {
@@ -178,23 +270,20 @@ impl Info {
                 BEncode::Int(self.files[0].length as i64),
             );
         } else {
+            // Paths are stored under the torrent's directory, which is its name
             let files = self
                 .files
                 .iter()
                 .map(|f| {
                     let mut fb = BTreeMap::new();
                     fb.insert(b"length".to_vec(), BEncode::Int(f.length as i64));
-                    fb.insert(
-                        b"path".to_vec(),
-                        BEncode::String(
-                            f.path
-                                .clone()
-                                .into_os_string()
-                                .into_string()
-                                .unwrap()
-                                .into_bytes(),
-                        ),
-                    );
+                    let path = f
+                        .path
+                        .components()
+                        .skip(1)
+                        .map(|c| BEncode::String(c.as_os_str().as_bytes().to_vec()))
+                        .collect();
+                    fb.insert(b"path".to_vec(), BEncode::List(path));
                     BEncode::Dict(fb)
                 })
                 .collect();
@@ -214,7 +303,6 @@ impl Info {
             .and_then(|(mut d, mut i)| {
                 let mut info_bytes = Vec::new();
                 BEncode::Dict(i.clone()).encode(&mut info_bytes).unwrap();
-                let hash = sha1_hash(&info_bytes);
 
                 let announce = d
                     .remove(b"announce".as_ref())
@@ -224,28 +312,54 @@ impl Info {
                 let creator = d
                     .remove(b"created by".as_ref())
                     .and_then(|b| b.into_string());
+                // Hybrid torrents carry a v2 file tree alongside the v1 file list and
+                // pieces, which are what we use. v2-only torrents have no v1 pieces, their
+                // hashes come from the file tree and piece layers instead.
+                let version = match i.get(b"meta version".as_ref()).map(|v| v.as_int().copied()) {
+                    None | Some(Some(1)) => MetaVersion::V1,
+                    Some(Some(2)) if i.contains_key(b"pieces".as_ref()) => MetaVersion::Hybrid,
+                    Some(Some(2)) => MetaVersion::V2,
+                    _ => return Err("Unsupported meta version"),
+                };
+                let hash = info_hash(version, &info_bytes);
+                let tree = match version {
+                    MetaVersion::V1 => Vec::new(),
+                    MetaVersion::Hybrid | MetaVersion::V2 => {
+                        let tree = i
+                            .get(b"file tree".as_ref())
+                            .ok_or("v2 torrents must have a file tree")?;
+                        let mut files = Vec::new();
+                        file_tree(tree, &mut PathBuf::new(), &mut files)?;
+                        files
+                    }
+                };
+                let pad_len = pad_len(&i);
+
                 let pl = i
                     .remove(b"piece length".as_ref())
                     .and_then(|i| i.into_int())
                     .ok_or("Info must specify piece length")? as u64;
-                let hashes = i
-                    .remove(b"pieces".as_ref())
-                    .and_then(|p| p.into_bytes())
-                    .and_then(|p| {
-                        let mut v = Vec::new();
-                        let mut s = &p[..];
-                        while s.len() >= 20 {
-                            let mut next = vec![0u8; 20];
-                            next.clone_from_slice(&s[..20]);
-                            v.push(next);
-                            s = &s[20..];
-                        }
-                        if !s.is_empty() {
-                            return None;
-                        }
-                        Some(v)
-                    })
-                    .ok_or("Info must provide valid hashes")?;
+                let hashes = if version == MetaVersion::V2 {
+                    Vec::new()
+                } else {
+                    i.remove(b"pieces".as_ref())
+                        .and_then(|p| p.into_bytes())
+                        .and_then(|p| {
+                            let mut v = Vec::new();
+                            let mut s = &p[..];
+                            while s.len() >= 20 {
+                                let mut next = vec![0u8; 20];
+                                next.clone_from_slice(&s[..20]);
+                                v.push(next);
+                                s = &s[20..];
+                            }
+                            if !s.is_empty() {
+                                return None;
+                            }
+                            Some(v)
+                        })
+                        .ok_or("Info must provide valid hashes")?
+                };
 
                 let private = if let Some(v) = i.remove(b"private".as_ref()) {
                     v.into_int()
@@ -269,28 +383,26 @@ impl Info {
                     None
                 };
 
-                let files = parse_bencode_files(i)?;
-                let name = if files.is_empty() {
-                    files[0]
-                        .path
-                        .clone()
-                        .into_os_string()
-                        .into_string()
-                        .map_err(|_| "Only UTF8 paths are accepted")?
-                } else if !files[0].path.has_root() {
-                    let mut piter = files[0].path.components();
-                    piter
-                        .next()
-                        .unwrap()
-                        .as_os_str()
-                        .to_os_string()
-                        .into_string()
-                        .map_err(|_| "Only UTF8 paths are accepted")?
-                } else {
-                    unreachable!()
+                let tree_len: u64 = tree.iter().map(|f| f.length).sum();
+                let (files, hashes) = match version {
+                    MetaVersion::V1 | MetaVersion::Hybrid => (parse_bencode_files(i)?, hashes),
+                    MetaVersion::V2 => {
+                        let name = be_name.as_deref().ok_or("v2 torrents must have a name")?;
+                        let layers = d
+                            .remove(b"piece layers".as_ref())
+                            .and_then(BEncode::into_dict)
+                            .unwrap_or_default();
+                        v2_files(name, tree, pl, &layers)?
+                    }
                 };
+                // Both single and multi file torrents must have a name to get this far
+                let name =
+                    String::from_utf8_lossy(be_name.as_deref().unwrap_or_default()).into_owned();
 
                 let total_len = files.iter().map(|f| f.length).sum();
+                if version == MetaVersion::Hybrid && tree_len + pad_len != total_len {
+                    return Err("v1 and v2 files of hybrid torrent differ");
+                }
                 let piece_idx = Info::generate_piece_idx(hashes.len(), pl, &files);
 
                 let url_list: Vec<_> = d
@@ -325,6 +437,8 @@ impl Info {
                     be_name,
                     piece_idx,
                     url_list,
+                    version,
+                    info_bytes,
                 })
             })
     }
@@ -344,6 +458,30 @@ impl Info {
         piece_idx
     }
 
+    /// A torrent named Test holding files of the given lengths.
+    #[cfg(test)]
+    pub fn with_files(piece_len: u32, lengths: &[u64]) -> Info {
+        let files: Vec<_> = lengths
+            .iter()
+            .enumerate()
+            .map(|(i, &length)| File {
+                path: PathBuf::from(format!("Test/{i}")),
+                length,
+            })
+            .collect();
+        let total_len: u64 = lengths.iter().sum();
+        let pieces = total_len.div_ceil(u64::from(piece_len)) as usize;
+        Info {
+            name: String::from("Test"),
+            piece_len,
+            total_len,
+            hashes: vec![vec![0u8]; pieces],
+            piece_idx: Info::generate_piece_idx(pieces, u64::from(piece_len), &files),
+            files,
+            ..Info::with_pieces(0)
+        }
+    }
+
     #[cfg(test)]
     pub fn with_pieces(pieces: usize) -> Info {
         Info {
@@ -366,9 +504,56 @@ impl Info {
             be_name: None,
             piece_idx: vec![],
             url_list: vec![],
+            version: MetaVersion::V1,
+            info_bytes: Vec::new(),
         }
     }
 
+    /// A two piece torrent with Shift-JIS names, `テスト/日本語.txt` and `テスト/readme.txt`.
+    #[cfg(test)]
+    pub fn with_sjis_names() -> Info {
+        let file = |path: &[&[u8]], length| {
+            let mut f = BTreeMap::new();
+            f.insert(b"length".to_vec(), BEncode::Int(length));
+            let path = path.iter().map(|p| BEncode::String(p.to_vec())).collect();
+            f.insert(b"path".to_vec(), BEncode::List(path));
+            BEncode::Dict(f)
+        };
+        let mut info = BTreeMap::new();
+        info.insert(b"name".to_vec(), BEncode::String(SJIS_NAME.to_vec()));
+        info.insert(b"piece length".to_vec(), BEncode::Int(16_384));
+        info.insert(b"pieces".to_vec(), BEncode::String(vec![0; 40]));
+        info.insert(
+            b"files".to_vec(),
+            BEncode::List(vec![
+                file(&[SJIS_FILE], 20_000),
+                file(&[b"readme.txt"], 12_768),
+            ]),
+        );
+        let mut torrent = BTreeMap::new();
+        torrent.insert(b"info".to_vec(), BEncode::Dict(info));
+        Info::from_bencode(BEncode::Dict(torrent)).unwrap()
+    }
+
+    /// A single file, single piece torrent of 100 bytes.
+    #[cfg(test)]
+    pub fn test_torrent(name: &str) -> BEncode {
+        let mut info = BTreeMap::new();
+        info.insert(b"name".to_vec(), BEncode::from_str(name));
+        info.insert(b"piece length".to_vec(), BEncode::Int(16_384));
+        info.insert(b"pieces".to_vec(), BEncode::String(vec![0; 20]));
+        info.insert(b"length".to_vec(), BEncode::Int(100));
+        let mut torrent = BTreeMap::new();
+        torrent.insert(b"info".to_vec(), BEncode::Dict(info));
+        BEncode::Dict(torrent)
+    }
+
+    /// The parsed `test_torrent` of the given name.
+    #[cfg(test)]
+    pub fn with_name(name: &str) -> Info {
+        Info::from_bencode(Info::test_torrent(name)).unwrap()
+    }
+
     #[cfg(test)]
     pub fn with_pieces_scale(pieces: u32, scale: u32) -> Info {
         Info {
@@ -385,6 +570,8 @@ impl Info {
             be_name: None,
             piece_idx: vec![],
             url_list: vec![],
+            version: MetaVersion::V1,
+            info_bytes: Vec::new(),
         }
     }
 
@@ -419,6 +606,30 @@ impl Info {
         self.hashes.len() as u32
     }
 
+    /// Offset of the start of a file relative to the start of the torrent.
+    pub fn file_offset(&self, file: usize) -> u64 {
+        self.files[..file].iter().map(|f| f.length).sum()
+    }
+
+    /// The files piece `idx` overlaps, in order, with how many of its bytes lie in each.
+    /// Zero length files are skipped, as no piece holds any of their data.
+    pub fn piece_files(&self, idx: u32) -> impl Iterator<Item = (usize, u64)> + '_ {
+        let (mut file, mut offset) = self.piece_idx[idx as usize];
+        let mut left = u64::from(self.piece_len(idx));
+        std::iter::from_fn(move || {
+            while left != 0 && file < self.files.len() {
+                let len = (self.files[file].length - offset).min(left);
+                file += 1;
+                offset = 0;
+                if len != 0 {
+                    left -= len;
+                    return Some((file - 1, len));
+                }
+            }
+            None
+        })
+    }
+
     /// Calculates the file offsets for a given block at index/begin
     pub fn block_disk_locs(info: &Arc<Info>, index: u32, begin: u32) -> LocIter {
         let len = info.block_len(index, begin);
@@ -490,6 +701,10 @@ impl LocIter {
             state: LocIterState::P(p),
         }
     }
+
+    pub fn info(&self) -> &Arc<Info> {
+        &self.info
+    }
 }
 
 impl Iterator for LocIter {
@@ -546,21 +761,240 @@ impl Iterator for LocIter {
     }
 }
 
+/// `テスト` and `日本語.txt` in Shift-JIS
+#[cfg(test)]
+pub const SJIS_NAME: &[u8] = b"\x83\x65\x83\x58\x83\x67";
+#[cfg(test)]
+pub const SJIS_FILE: &[u8] = b"\x93\xfa\x96\x7b\x8c\xea.txt";
+
+/// Infohash of an info dictionary. v2 ones are SHA-256, truncated to fit wherever v1
+/// ones go.
+fn info_hash(version: MetaVersion, info_bytes: &[u8]) -> [u8; 20] {
+    match version {
+        MetaVersion::V1 | MetaVersion::Hybrid => sha1_hash(info_bytes),
+        MetaVersion::V2 => {
+            let mut hash = [0; 20];
+            hash.copy_from_slice(&sha256_hash(info_bytes)[..20]);
+            hash
+        }
+    }
+}
+
+/// A path component from a bencoded string, kept as raw bytes.
+fn path_part(b: BEncode) -> Option<OsString> {
+    b.into_bytes().map(OsString::from_vec)
+}
+
+/// Checks a path component from the metadata stays within the torrent's directory and
+/// can be created, renaming it if it's a device name on Windows.
+fn sanitize(part: OsString) -> Result<OsString, &'static str> {
+    let name = part.as_bytes();
+    if name.is_empty() || name == b"." || name == b".." {
+        return Err("File path parts must not be empty, . or ..");
+    }
+    if name.contains(&0) {
+        return Err("File path parts must not contain NUL bytes");
+    }
+    if name.contains(&b'/') {
+        return Err("File path parts must not contain /");
+    }
+    let stem = name.split(|&b| b == b'.').next().unwrap_or_default();
+    let part = if RESERVED_NAMES.iter().any(|r| stem.eq_ignore_ascii_case(r)) {
+        OsString::from_vec([stem, b"_", &name[stem.len()..]].concat())
+    } else {
+        part
+    };
+    if part.len() > MAX_NAME_LEN {
+        return Err("File path parts must be at most 255 bytes");
+    }
+    Ok(part)
+}
+
+/// Gives each file whose path an earlier file already has a path of its own, by
+/// numbering it, e.g. `a.txt` becomes `a.1.txt`.
+fn dedup_paths(files: &mut [File]) {
+    let mut taken: FHashSet<_> = files.iter().map(|f| f.path.clone()).collect();
+    let mut seen = FHashSet::default();
+    for file in files {
+        if seen.insert(file.path.clone()) {
+            continue;
+        }
+        let path = (1..)
+            .map(|n| numbered(&file.path, n))
+            .find(|p| !taken.contains(p))
+            .unwrap();
+        taken.insert(path.clone());
+        seen.insert(path.clone());
+        file.path = path;
+    }
+}
+
+fn numbered(path: &Path, n: usize) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().as_bytes();
+    // Leading dots are part of the name rather than an extension
+    let ext = name
+        .iter()
+        .rposition(|&b| b == b'.')
+        .filter(|&i| i > 0)
+        .unwrap_or(name.len());
+    let name = [&name[..ext], format!(".{n}").as_bytes(), &name[ext..]].concat();
+    path.with_file_name(OsString::from_vec(name))
+}
+
+/// A file of a v2 file tree, with its path relative to the tree's root.
+struct TreeFile {
+    path: PathBuf,
+    length: u64,
+    /// Root of the file's merkle tree, empty files have none
+    root: Option<Vec<u8>>,
+}
+
+/// Collects the files of a v2 file tree, in order.
+fn file_tree(
+    tree: &BEncode,
+    path: &mut PathBuf,
+    files: &mut Vec<TreeFile>,
+) -> Result<(), &'static str> {
+    let tree = tree.as_dict().ok_or("File tree must be a dictionary")?;
+    for (name, node) in tree {
+        // Files are nodes with an empty key, holding the file's length and merkle root
+        if !name.is_empty() {
+            path.push(sanitize(OsString::from_vec(name.clone()))?);
+            file_tree(node, path, files)?;
+            path.pop();
+            continue;
+        }
+        if path.as_os_str().is_empty() {
+            return Err("File tree entry must have a name");
+        }
+        let file = node
+            .as_dict()
+            .ok_or("File tree entry must be a dictionary")?;
+        let flen = file
+            .get(b"length".as_ref())
+            .and_then(BEncode::as_int)
+            .filter(|&&l| l >= 0)
+            .ok_or("File tree entry must have a length")?;
+        let root = file
+            .get(b"pieces root".as_ref())
+            .and_then(BEncode::as_bytes);
+        if *flen != 0 && root.is_none_or(|r| r.len() != 32) {
+            return Err("File tree entry must have a 32 byte pieces root");
+        }
+        files.push(TreeFile {
+            path: path.clone(),
+            length: *flen as u64,
+            root: root.filter(|_| *flen != 0).cloned(),
+        });
+    }
+    Ok(())
+}
+
+/// Lays out the files of a v2-only torrent and gathers their piece hashes. Every file
+/// starts a new piece, so the gaps between them are filled with padding files, as hybrid
+/// torrents list explicitly.
+fn v2_files(
+    name: &[u8],
+    tree: Vec<TreeFile>,
+    pl: u64,
+    layers: &BTreeMap<Vec<u8>, BEncode>,
+) -> Result<(Vec<File>, Vec<Vec<u8>>), &'static str> {
+    if !pl.is_power_of_two() || pl < V2_BLOCK as u64 {
+        return Err("v2 piece length must be a power of two of at least 16 KiB");
+    }
+    // A lone file at the top of the tree is the whole torrent, anything else goes in a
+    // directory named after it
+    let dir = match &tree[..] {
+        [f] if f.path.components().count() == 1 => None,
+        _ => Some(PathBuf::from(sanitize(OsString::from_vec(name.to_vec()))?)),
+    };
+    let last = tree.iter().rposition(|f| f.length != 0);
+    let mut files = Vec::new();
+    let mut hashes = Vec::new();
+    for (i, f) in tree.into_iter().enumerate() {
+        if f.length > pl {
+            let layer = f
+                .root
+                .as_ref()
+                .and_then(|r| layers.get(r))
+                .and_then(BEncode::as_bytes)
+                .ok_or("v2 torrents must have piece layers for files longer than a piece")?;
+            if layer.len() as u64 != f.length.div_ceil(pl) * 32 {
+                return Err("Piece layer doesn't match the length of its file");
+            }
+            hashes.extend(layer.chunks(32).map(<[u8]>::to_vec));
+        } else if let Some(root) = f.root {
+            hashes.push(root);
+        }
+        let pad = (pl - f.length % pl) % pl;
+        let path = match &dir {
+            Some(dir) => dir.join(&f.path),
+            None => f.path,
+        };
+        files.push(File {
+            path,
+            length: f.length,
+        });
+        if let Some(dir) = &dir
+            && pad != 0
+            && last.is_some_and(|l| i < l)
+        {
+            files.push(File {
+                path: dir.join(".pad").join(pad.to_string()),
+                length: pad,
+            });
+        }
+    }
+    dedup_paths(&mut files);
+    Ok((files, hashes))
+}
+
+/// Root of the BEP 52 merkle tree over the 16 KiB blocks of `data`, padded with zero
+/// hashes to `leaves` leaves.
+fn merkle_root(data: &[u8], leaves: usize) -> [u8; 32] {
+    let mut layer: Vec<_> = data.chunks(V2_BLOCK).map(sha256_hash).collect();
+    layer.resize(cmp::max(leaves, layer.len()).max(1), [0; 32]);
+    while layer.len() > 1 {
+        layer = layer
+            .chunks(2)
+            .map(|pair| sha256_hash(&pair.concat()))
+            .collect();
+    }
+    layer[0]
+}
+
+/// Total length of the BEP 47 padding files in a multifile info dictionary.
+fn pad_len(info: &BTreeMap<Vec<u8>, BEncode>) -> u64 {
+    info.get(b"files".as_ref())
+        .and_then(BEncode::as_list)
+        .into_iter()
+        .flatten()
+        .filter_map(BEncode::as_dict)
+        .filter(|f| {
+            f.get(b"attr".as_ref())
+                .and_then(BEncode::as_bytes)
+                .is_some_and(|a| a.contains(&b'p'))
+        })
+        .filter_map(|f| f.get(b"length".as_ref()).and_then(BEncode::as_int))
+        .map(|&l| l as u64)
+        .sum()
+}
+
 fn parse_bencode_files(mut data: BTreeMap<Vec<u8>, BEncode>) -> Result<Vec<File>, &'static str> {
     match data.remove(b"files".as_ref()).and_then(|l| l.into_list()) {
         Some(fs) => {
-            let mut path = PathBuf::new();
-            path.push(
+            let path = PathBuf::from(sanitize(
                 data.remove(b"name".as_ref())
-                    .and_then(|v| v.into_string())
+                    .and_then(path_part)
                     .ok_or("Multifile mode must have a name field")?,
-            );
+            )?);
             let mut files = Vec::new();
             for f in fs {
                 let mut file = File::from_bencode(f)?;
                 file.path = path.join(file.path);
                 files.push(file);
             }
+            dedup_paths(&mut files);
             Ok(files)
         }
         None => File::from_bencode(BEncode::Dict(data)).map(|f| vec![f]),
@@ -590,6 +1024,302 @@ mod tests {
         assert_eq!(info.block_len(pieces, 16_384), end % 16_384);
     }
 
+    #[test]
+    fn non_utf8_names() {
+        let info = Info::with_sjis_names();
+        assert_eq!(info.name, "\u{FFFD}e\u{FFFD}X\u{FFFD}g");
+        assert_eq!(info.be_name.as_deref(), Some(SJIS_NAME));
+        assert_eq!(info.root().as_os_str().as_bytes(), SJIS_NAME);
+        let path = [SJIS_NAME, b"/", SJIS_FILE].concat();
+        assert_eq!(info.files[0].path.as_os_str().as_bytes(), &path[..]);
+
+        // Re-encoding gives back the same info dictionary
+        let encoded = info.to_bencode().encode_to_buf();
+        assert_eq!(sha1_hash(&encoded), info.hash);
+    }
+
+    fn with_paths(name: &[u8], paths: &[&[&[u8]]]) -> Result<Info, &'static str> {
+        let files = paths
+            .iter()
+            .map(|path| {
+                let path = path.iter().map(|p| BEncode::String(p.to_vec())).collect();
+                let mut f = BTreeMap::new();
+                f.insert(b"length".to_vec(), BEncode::Int(16_384));
+                f.insert(b"path".to_vec(), BEncode::List(path));
+                BEncode::Dict(f)
+            })
+            .collect();
+        let mut info = BTreeMap::new();
+        info.insert(b"name".to_vec(), BEncode::String(name.to_vec()));
+        info.insert(b"piece length".to_vec(), BEncode::Int(16_384));
+        info.insert(b"pieces".to_vec(), BEncode::String(vec![0; 20]));
+        info.insert(b"files".to_vec(), BEncode::List(files));
+        let mut torrent = BTreeMap::new();
+        torrent.insert(b"info".to_vec(), BEncode::Dict(info));
+        Info::from_bencode(BEncode::Dict(torrent))
+    }
+
+    fn paths(info: &Info) -> Vec<&str> {
+        info.files
+            .iter()
+            .map(|f| f.path.to_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn unsafe_paths() {
+        let long = [b'a'; 256];
+        for (name, path) in [
+            // Traversal
+            (b"t".as_ref(), [b"..".as_ref(), b".bashrc"].as_ref()),
+            (b"..", &[b".bashrc"]),
+            (b"t", &[b"a", b".", b"b"]),
+            (b"t", &[b"../../.bashrc"]),
+            // Absolute
+            (b"/etc", &[b"passwd"]),
+            (b"t", &[b"/etc/passwd"]),
+            (b"t", &[b"a/b"]),
+            // NUL bytes
+            (b"t", &[b"a\0.txt"]),
+            (b"t\0", &[b"a"]),
+            // Empty, or too long to create
+            (b"t", &[b"a", b""]),
+            (b"t", &[]),
+            (b"t", &[&long]),
+        ] {
+            assert!(with_paths(name, &[path]).is_err(), "{path:?}");
+        }
+        assert_eq!(
+            with_paths(b"t", &[&[b"a/../../b"]]).unwrap_err(),
+            "File path parts must not contain /"
+        );
+        assert!(with_paths(b"t", &[&[&long[1..]]]).is_ok());
+
+        let mut single = BTreeMap::new();
+        single.insert(b"name".to_vec(), BEncode::String(b"../x".to_vec()));
+        single.insert(b"length".to_vec(), BEncode::Int(1));
+        assert!(File::from_bencode(BEncode::Dict(single)).is_err());
+    }
+
+    #[test]
+    fn reserved_names() {
+        let info = with_paths(
+            b"aux",
+            &[
+                &[b"CON"],
+                &[b"nul.txt"],
+                &[b"dir", b"Lpt1.tar.gz"],
+                &[b"CONSOLE"],
+                &[b"com10"],
+                &[b"con_"],
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            paths(&info),
+            [
+                "aux_/CON_",
+                "aux_/nul_.txt",
+                "aux_/dir/Lpt1_.tar.gz",
+                "aux_/CONSOLE",
+                "aux_/com10",
+                "aux_/con_"
+            ]
+        );
+    }
+
+    #[test]
+    fn duplicate_paths() {
+        let info = with_paths(
+            b"t",
+            &[
+                &[b"a.txt"],
+                &[b"a.txt"],
+                &[b"a.1.txt"],
+                &[b"a.txt"],
+                &[b"dir", b".hidden"],
+                &[b"dir", b".hidden"],
+                &[b"b"],
+                &[b"b"],
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            paths(&info),
+            [
+                "t/a.txt",
+                "t/a.2.txt",
+                "t/a.1.txt",
+                "t/a.3.txt",
+                "t/dir/.hidden",
+                "t/dir/.hidden.1",
+                "t/b",
+                "t/b.1"
+            ]
+        );
+        // The info dictionary is kept as it was for peers fetching the metadata
+        assert!(info.matches(&info.info_bytes));
+        assert!(!info.matches(&info.to_bencode().encode_to_buf()));
+    }
+
+    #[test]
+    fn meta_versions() {
+        let dict = |entries: Vec<(&[u8], BEncode)>| {
+            BEncode::Dict(entries.into_iter().map(|(k, v)| (k.to_vec(), v)).collect())
+        };
+        let torrent = |version: Option<i64>, pieces: bool, tree_len: i64| {
+            let tree = dict(vec![(
+                b"a",
+                dict(vec![(
+                    b"",
+                    dict(vec![
+                        (b"length", BEncode::Int(tree_len)),
+                        (b"pieces root", BEncode::String(vec![1; 32])),
+                    ]),
+                )]),
+            )]);
+            let mut info = vec![
+                (b"name".as_ref(), BEncode::from_str("a")),
+                (b"piece length", BEncode::Int(16_384)),
+                (b"length", BEncode::Int(20_000)),
+                (b"file tree", tree),
+            ];
+            if let Some(v) = version {
+                info.push((b"meta version", BEncode::Int(v)));
+            }
+            if pieces {
+                info.push((b"pieces", BEncode::String(vec![0; 40])));
+            }
+            Info::from_bencode(dict(vec![(b"info", dict(info))]))
+        };
+
+        let info = torrent(Some(2), true, 20_000).unwrap();
+        assert_eq!(info.version, MetaVersion::Hybrid);
+        assert_eq!(info.piece_hash(), PieceHash::Sha1);
+        assert_eq!(info.total_len, 20_000);
+        assert_eq!(torrent(None, true, 0).unwrap().version, MetaVersion::V1);
+        assert!(torrent(Some(2), true, 19_999).is_err());
+        // The file is longer than a piece, so its hashes are in the piece layers
+        assert_eq!(
+            torrent(Some(2), false, 20_000).unwrap_err(),
+            "v2 torrents must have piece layers for files longer than a piece"
+        );
+        assert!(torrent(Some(3), true, 20_000).is_err());
+    }
+
+    #[test]
+    fn piece_hash_algorithms() {
+        let data = b"0123456789".repeat(2_000);
+        let mut info = Info::with_pieces(1);
+        info.total_len = data.len() as u64;
+        info.hashes = vec![sha1_hash(&data).to_vec()];
+        assert_eq!(info.piece_hash(), PieceHash::Sha1);
+        assert!(info.piece_valid(0, &data));
+        assert!(!info.piece_valid(0, &data[1..]));
+
+        // A v2 piece is the merkle root of its 16 KiB blocks
+        info.version = MetaVersion::V2;
+        info.piece_len = 32_768;
+        info.files[0].length = data.len() as u64;
+        info.piece_idx = vec![(0, 0)];
+        let leaves = [sha256_hash(&data[..16_384]), sha256_hash(&data[16_384..])];
+        info.hashes = vec![sha256_hash(&leaves.concat()).to_vec()];
+        assert_eq!(info.piece_hash(), PieceHash::Sha256);
+        assert!(info.piece_valid(0, &data));
+        assert!(!info.piece_valid(0, &data[1..]));
+    }
+
+    #[test]
+    fn v2_torrent() {
+        let dict = |entries: Vec<(&[u8], BEncode)>| {
+            BEncode::Dict(entries.into_iter().map(|(k, v)| (k.to_vec(), v)).collect())
+        };
+        let leaf = |data: &[u8]| sha256_hash(data);
+        let node = |l: [u8; 32], r: [u8; 32]| sha256_hash(&[l, r].concat());
+        // a spans two 32 KiB pieces, the second only partly. b fits in one.
+        let a = b"0123456789".repeat(4_000);
+        let b = b"abcdefghij".repeat(2_000);
+        let a_pieces = [
+            node(leaf(&a[..16_384]), leaf(&a[16_384..32_768])),
+            node(leaf(&a[32_768..]), [0; 32]),
+        ];
+        let a_root = node(a_pieces[0], a_pieces[1]);
+        let b_root = node(leaf(&b[..16_384]), leaf(&b[16_384..]));
+        let entry = |len: usize, root: [u8; 32]| {
+            dict(vec![(
+                b"",
+                dict(vec![
+                    (b"length", BEncode::Int(len as i64)),
+                    (b"pieces root", BEncode::String(root.to_vec())),
+                ]),
+            )])
+        };
+        let tree = dict(vec![
+            (b"a", entry(a.len(), a_root)),
+            (b"dir", dict(vec![(b"b", entry(b.len(), b_root))])),
+        ]);
+        let info = dict(vec![
+            (b"name", BEncode::from_str("Test")),
+            (b"piece length", BEncode::Int(32_768)),
+            (b"meta version", BEncode::Int(2)),
+            (b"file tree", tree),
+        ]);
+        let layers = dict(vec![(&a_root[..], BEncode::String(a_pieces.concat()))]);
+        let info_hash = sha256_hash(&info.encode_to_buf());
+        let info =
+            Info::from_bencode(dict(vec![(b"info", info), (b"piece layers", layers)])).unwrap();
+
+        assert_eq!(info.version, MetaVersion::V2);
+        assert_eq!(info.hash[..], info_hash[..20]);
+        assert_eq!(info.name, "Test");
+        // b starts on a new piece, after padding
+        let files: Vec<_> = info
+            .files
+            .iter()
+            .map(|f| (f.path.to_str().unwrap(), f.length))
+            .collect();
+        assert_eq!(
+            files,
+            [
+                ("Test/a", 40_000),
+                ("Test/.pad/25536", 25_536),
+                ("Test/dir/b", 20_000)
+            ]
+        );
+        assert_eq!(info.pieces(), 3);
+        assert_eq!(
+            info.hashes,
+            [a_pieces[0], a_pieces[1], b_root].map(|h| h.to_vec())
+        );
+
+        // Padding read along with the end of a isn't part of its hash
+        let mut end = a[32_768..].to_vec();
+        end.resize(32_768, 0);
+        assert!(info.piece_valid(0, &a[..32_768]));
+        assert!(info.piece_valid(1, &end));
+        assert!(info.piece_valid(2, &b));
+        assert!(!info.piece_valid(1, &a[..32_768]));
+        let mut corrupt = b.clone();
+        corrupt[20_000 - 1] ^= 1;
+        assert!(!info.piece_valid(2, &corrupt));
+    }
+
+    #[test]
+    fn piece_files() {
+        // The middle piece straddles the end of a, all of b, an empty file and part of c
+        let info = Info::with_files(16_384, &[20_000, 1_000, 0, 20_000]);
+        assert_eq!(info.pieces(), 3);
+        let files = |p| info.piece_files(p).collect::<Vec<_>>();
+        assert_eq!(files(0), [(0, 16_384)]);
+        assert_eq!(files(1), [(0, 3_616), (1, 1_000), (3, 11_768)]);
+        assert_eq!(files(2), [(3, 8_232)]);
+
+        // Pieces ending exactly on a file boundary don't spill into the next file
+        let info = Info::with_files(16_384, &[16_384, 100]);
+        assert_eq!(info.piece_files(0).collect::<Vec<_>>(), [(0, 16_384)]);
+        assert_eq!(info.piece_files(1).collect::<Vec<_>>(), [(1, 100)]);
+    }
+
     #[test]
     fn generate_piece_idx() {
         let f = vec![File {
@@ -1,20 +1,27 @@
+mod availability;
 pub mod bitfield;
 mod choker;
+pub mod history;
 pub mod info;
 pub mod peer;
 mod picker;
+mod tiers;
 
 use std::borrow::Cow;
-use std::collections::{BTreeMap, VecDeque};
+use std::collections::BTreeMap;
 use std::fmt;
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
 use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 use crate::bencode::BEncode;
+use base64::prelude::{BASE64_STANDARD, Engine};
 use byteorder::{BigEndian, ByteOrder};
 use chrono::{DateTime, Utc};
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4};
 use url::Url;
 
 pub use self::bitfield::Bitfield;
@@ -22,21 +29,39 @@ pub use self::info::{Info, LocIter};
 pub use self::peer::Message;
 pub use self::peer::{Peer, PeerConn};
 pub use self::picker::Block;
+pub use self::tiers::Tiers;
 
+use self::availability::Availability;
+use self::history::History;
 use self::picker::Picker;
 use crate::buffers::Buffer;
-use crate::config::Config;
+use crate::config::{Allocation, Config, MissingFiles};
 use crate::control::cio;
+use crate::rpc::proto::message;
 use crate::rpc::resource::{self, Resource, SResourceUpdate};
 use crate::session::torrent::current::Session;
-use crate::throttle::Throttle;
+use crate::throttle::{MAX_PRIORITY, Throttle};
 use crate::tracker::{self, TrackerResponse};
-use crate::util::{FHashSet, UHashMap};
-use crate::{EXT_PROTO, UT_META_ID, UT_PEX_ID, bencode, disk, rpc, util};
+use crate::util::http::Headers;
+use crate::util::timer::Timers;
+use crate::util::{FHashMap, FHashSet, UHashMap, native};
+use crate::{UT_META_ID, UT_PEX_ID, bencode, disk, rpc, util};
 use crate::{session, stat};
 
 const MAX_INFO_BYTES: i64 = 100 * 1000 * 1000;
-const MAX_PEERS: usize = 50;
+const MAX_KNOWN_PEERS: usize = 200;
+/// Seconds to wait before retrying a tracker which couldn't be reached, doubled for
+/// every consecutive failure up to the minimum announce interval.
+const ANNOUNCE_RETRY_BASE: u64 = 30;
+/// `added.f` flag of a PEX peer which is a seed
+const PEX_SEED: u8 = 0x02;
+/// `added.f` flag of a PEX peer which accepts incoming connections
+const PEX_OUTGOING: u8 = 0x10;
+/// Maximum number of partially downloaded pieces kept across restarts
+const MAX_PARTIAL: usize = 64;
+/// How long a peer choking us must go without transferring anything before it can be
+/// dropped for a new one at the peer limit
+const PEER_REPLACE_IDLE: Duration = Duration::from_secs(60);
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum TrackerStatus {
@@ -53,20 +78,51 @@ pub struct Torrent<T: cio::CIO> {
     config: Arc<Config>,
     id: usize,
     pieces: Bitfield,
+    /// Piece field as of the last RPC update, which deltas are computed against
+    rpc_pieces: Bitfield,
     validating: FHashSet<u32>,
     info: Arc<Info>,
     cio: T,
     uploaded: u64,
+    /// Bytes of blocks which were written out, less those of pieces which failed validation
     downloaded: u64,
+    /// `uploaded` and `downloaded` since the daemon started, these aren't persisted
+    ses_uploaded: u64,
+    ses_downloaded: u64,
+    /// Bytes received which didn't count towards `downloaded`, e.g. duplicate blocks from
+    /// endgame or pieces which failed validation
     wasted: u64,
+    /// Bytes of pieces which failed validation, included in `wasted`
+    corrupt: u64,
     stat: stat::EMA,
     files: Files,
     priority: u8,
     priorities: Arc<Vec<u8>>,
     throttle: Throttle,
-    trackers: VecDeque<Tracker>,
+    trackers: Tiers,
     peers: UHashMap<Peer<T>>,
+    /// Peers being dropped to make room for new ones, no longer counted against the limit
+    evicting: FHashSet<usize>,
     leechers: FHashSet<usize>,
+    /// Peers sending or receiving data, whose rates are averaged each tick
+    transferring: FHashSet<usize>,
+    /// When the requests outstanding to each peer will have stalled
+    stalls: Timers<usize>,
+    /// When each peer will have gone the idle timeout without transferring anything
+    idle_peers: Timers<usize>,
+    /// Addresses of peers we're not connected to, but may connect to later
+    known_peers: FHashMap<SocketAddr, PeerSource>,
+    /// Known peers which should be connected to
+    redial: Vec<SocketAddr>,
+    availability: Availability,
+    /// Reads and writes sent to disk which haven't been responded to
+    disk_pending: usize,
+    /// Last time disk made progress on this torrent's reads and writes
+    disk_progress: Instant,
+    /// Blocks received while the data is being moved, and whether each completed its piece
+    held_writes: Vec<(u32, u32, Buffer, bool)>,
+    /// Blocks requested by peers while the data is being moved, by peer id
+    held_reads: Vec<(usize, u32, u32, Buffer)>,
     picker: Picker,
     status: Status,
     choker: choker::Choker,
@@ -83,7 +139,93 @@ pub struct Torrent<T: cio::CIO> {
     // yet recieved the size of the info-dictionary.
     // Some(i): We need to download i pieces to complete the info-dictionary.
     info_idx: Option<usize>,
+    // Which 16KiB pieces of the info-dictionary have been received while it's downloading.
+    info_have: Bitfield,
     created: DateTime<Utc>,
+    // Last time any data was transferred in the swarm.
+    last_active: Instant,
+    // Number of announces made since the torrent went dormant, used
+    // to exponentially back off the announce interval.
+    idle_announces: u32,
+    // Sent as the `key` of tracker announces so trackers can recognize us across IP changes.
+    announce_key: u32,
+    // Address reported to trackers instead of the configured announce_ip(6) of its family.
+    announce_ip: Option<IpAddr>,
+    // Peer connection limit in place of the configured max_peers_per_torrent.
+    max_peers: Option<u16>,
+    // Whether pieces are hashed before being uploaded, in place of the configured verify_on_read.
+    verify_on_read: Option<bool>,
+    // How space is reserved for the files, in place of the configured allocation.
+    allocation: Option<Allocation>,
+    // Files downloaded one after the other ahead of the rest, until all are complete.
+    file_order: Vec<usize>,
+    // Whether any tracker has responded successfully to an announce since we were loaded.
+    tracker_ok: bool,
+    // File priorities a magnet was added with, applied once its metadata arrives.
+    preset_priorities: BTreeMap<usize, u8>,
+    // Work log of significant actions, persisted apart from the session.
+    history: History,
+}
+
+/// File priorities and picker strategy a torrent is added with.
+#[derive(Clone, Debug, Default)]
+pub struct Preset {
+    /// Priorities by file index, files left out keep the default
+    pub file_priorities: BTreeMap<usize, u8>,
+    pub strategy: Option<resource::Strategy>,
+}
+
+/// A torrent's session read back from disk, ready to be restored.
+pub struct Saved {
+    torrent: session::torrent::Torrent,
+    /// Whether it was saved in an older format
+    migrated: bool,
+    session_hash: blake3::Hash,
+    history: History,
+    /// The info dictionary as it was received
+    metadata: Option<Vec<u8>>,
+}
+
+impl Saved {
+    pub fn load(session_data: &[u8], info_data: Option<&[u8]>) -> Option<Saved> {
+        let (migrated, torrent) = match session::torrent::load(session_data, info_data) {
+            session::torrent::LoadResult::Ok(torrent) => (false, torrent),
+            session::torrent::LoadResult::Migrated(torrent) => (true, torrent),
+            session::torrent::LoadResult::Failed => return None,
+        };
+        debug!("Torrent data deserialized!");
+        Some(Saved {
+            torrent,
+            migrated,
+            session_hash: blake3::hash(session_data),
+            history: History::default(),
+            metadata: None,
+        })
+    }
+
+    /// Attaches the work log read from the session's sidecar.
+    pub fn with_history(self, history: History) -> Saved {
+        Saved { history, ..self }
+    }
+
+    /// Attaches the info dictionary read from the session's sidecar.
+    pub fn with_metadata(self, metadata: Vec<u8>) -> Saved {
+        Saved {
+            metadata: Some(metadata),
+            ..self
+        }
+    }
+
+    pub fn hash(&self) -> [u8; 20] {
+        self.torrent.info.hash
+    }
+}
+
+/// Where an address in the known peer pool was learned from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PeerSource {
+    /// Alternate address from a connected peer's extension handshake
+    ExtHint,
 }
 
 #[derive(Clone, Debug)]
@@ -92,6 +234,10 @@ pub struct Status {
     pub validating: Option<f32>,
     pub error: Option<String>,
     pub state: StatusState,
+    /// Disk reads or writes have not completed within the stall timeout
+    pub disk_stalled: bool,
+    /// Progress of moving the data to a new path
+    pub moving: Option<f32>,
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -103,15 +249,29 @@ pub enum StatusState {
 }
 
 pub struct Tracker {
+    /// RPC id, which stays the same when the url is edited
+    pub id: String,
     pub url: Arc<Url>,
+    /// Announce tier, lower tiers are tried first
+    pub tier: usize,
     pub status: TrackerStatus,
     pub last_announce: DateTime<Utc>,
     pub update: Option<Instant>,
+    /// The tracker's `min interval` has not yet passed since the last announce
+    pub min_update: Option<Instant>,
+    /// Consecutive announces which failed to reach the tracker
+    pub failures: u32,
+    /// When the tracker will be retried after a failed announce
+    pub retry_at: Option<DateTime<Utc>>,
+    /// Extra headers sent with HTTP announces
+    pub headers: Headers,
 }
 
 struct Files {
     done: Vec<u64>,
     dirty: FHashSet<usize>,
+    /// Availability of each file as last reported
+    availability: Vec<f32>,
 }
 
 impl Status {
@@ -135,7 +295,7 @@ impl Status {
     }
 
     pub fn should_dl(&self) -> bool {
-        self.leeching() && !self.stopped() && self.validating.is_none()
+        self.leeching() && !self.stopped() && self.validating.is_none() && self.moving.is_none()
     }
 
     pub fn as_rpc(&self, ul: u64, dl: u64) -> rpc::resource::Status {
@@ -148,6 +308,9 @@ impl Status {
         if self.error.is_some() {
             return rpc::resource::Status::Error;
         }
+        if self.disk_stalled {
+            return rpc::resource::Status::Stalled;
+        }
 
         match self.state {
             StatusState::Incomplete | StatusState::Import => {
@@ -174,6 +337,7 @@ impl Files {
         let mut f = Files {
             done: vec![0; info.files.len()],
             dirty: FHashSet::default(),
+            availability: vec![0.; info.files.len()],
         };
         f.rebuild(info, pieces);
         f
@@ -185,8 +349,8 @@ impl Files {
         }
 
         for p in pieces.iter() {
-            for loc in Info::piece_disk_locs(info, p as u32) {
-                self.done[loc.file] += (loc.end - loc.start) as u64;
+            for (file, len) in info.piece_files(p as u32) {
+                self.done[file] += len;
             }
         }
 
@@ -196,9 +360,9 @@ impl Files {
     }
 
     fn update(&mut self, info: &Arc<Info>, piece: u32) {
-        for loc in Info::piece_disk_locs(info, piece) {
-            self.done[loc.file] += (loc.end - loc.start) as u64;
-            self.dirty.insert(loc.file);
+        for (file, len) in info.piece_files(piece) {
+            self.done[file] += len;
+            self.dirty.insert(file);
         }
     }
 
@@ -209,6 +373,45 @@ impl Files {
         }
         res
     }
+
+    /// Average number of copies of each file held by connected peers, weighing every
+    /// piece by how much of the file it holds.
+    fn availability(info: &Info, avail: &Availability) -> Vec<f32> {
+        let mut copies = vec![0u64; info.files.len()];
+        for piece in 0..info.pieces() {
+            let c = u64::from(avail.copies(piece));
+            if c == 0 {
+                continue;
+            }
+            for (file, len) in info.piece_files(piece) {
+                copies[file] += c * len;
+            }
+        }
+        copies
+            .into_iter()
+            .zip(&info.files)
+            .map(|(c, f)| {
+                if f.length == 0 {
+                    0.
+                } else {
+                    (c as f64 / f.length as f64) as f32
+                }
+            })
+            .collect()
+    }
+
+    /// Files whose availability changed since it was last reported.
+    fn flush_availability(&mut self, info: &Info, avail: &Availability) -> Vec<(usize, f32)> {
+        let cur = Files::availability(info, avail);
+        let changed = cur
+            .iter()
+            .enumerate()
+            .filter(|&(i, a)| self.availability.get(i) != Some(a))
+            .map(|(i, &a)| (i, a))
+            .collect();
+        self.availability = cur;
+        changed
+    }
 }
 
 impl<T: cio::CIO> Torrent<T> {
@@ -216,7 +419,7 @@ impl<T: cio::CIO> Torrent<T> {
         config: Arc<Config>,
         id: usize,
         path: Option<String>,
-        info: Info,
+        mut info: Info,
         throttle: Throttle,
         cio: T,
         start: bool,
@@ -235,6 +438,8 @@ impl<T: cio::CIO> Torrent<T> {
             } else {
                 StatusState::Incomplete
             },
+            disk_stalled: false,
+            moving: None,
         };
         let priorities = Arc::new(vec![3; info.files.len()]);
         let info_idx = if info.complete() {
@@ -243,36 +448,20 @@ impl<T: cio::CIO> Torrent<T> {
             status.state = StatusState::Magnet;
             Some(usize::MAX)
         };
-        let info_bytes = if info_idx.is_none() {
-            info.to_bencode().encode_to_buf()
-        } else {
-            vec![]
+        let info_bytes = match info_idx {
+            Some(_) => vec![],
+            // Peers fetching the metadata must get exactly what the infohash was taken of
+            None if !info.info_bytes.is_empty() => mem::take(&mut info.info_bytes),
+            None => info.to_bencode().encode_to_buf(),
         };
         let info = Arc::new(info);
-        let picker = Picker::new(&info, &pieces, &priorities);
-
-        let mut trackers = VecDeque::with_capacity(1);
-        if !info.url_list.is_empty() {
-            for (i, list) in info.url_list.iter().enumerate() {
-                for (j, _) in list.iter().enumerate() {
-                    let tracker = Tracker {
-                        status: TrackerStatus::Updating,
-                        update: None,
-                        last_announce: Utc::now(),
-                        url: Arc::clone(&info.url_list[i][j]),
-                    };
-                    trackers.push_back(tracker);
-                }
-            }
-        } else if let Some(ref announce) = info.announce {
-            let tracker = Tracker {
-                status: TrackerStatus::Updating,
-                update: None,
-                last_announce: Utc::now(),
-                url: announce.clone(),
-            };
-            trackers.push_back(tracker);
-        }
+        let picker = Picker::new(&info, &pieces, &priorities, config.peer.endgame_duplicates);
+
+        let trackers = if !info.url_list.is_empty() {
+            Tiers::new(info.url_list.iter().cloned())
+        } else {
+            Tiers::new(info.announce.iter().map(|url| [url.clone()]))
+        };
 
         let files = Files::new(&info, &pieces);
 
@@ -282,6 +471,7 @@ impl<T: cio::CIO> Torrent<T> {
             info,
             path,
             peers,
+            rpc_pieces: pieces.clone(),
             pieces,
             validating: FHashSet::default(),
             picker,
@@ -289,11 +479,25 @@ impl<T: cio::CIO> Torrent<T> {
             priorities,
             uploaded: 0,
             downloaded: 0,
+            ses_uploaded: 0,
+            ses_downloaded: 0,
             wasted: 0,
+            corrupt: 0,
             files,
             stat: stat::EMA::new(),
             cio,
+            evicting: FHashSet::default(),
             leechers,
+            transferring: FHashSet::default(),
+            stalls: Timers::new(),
+            idle_peers: Timers::new(),
+            known_peers: FHashMap::default(),
+            redial: Vec::new(),
+            availability: Availability::new(),
+            disk_pending: 0,
+            held_writes: Vec::new(),
+            held_reads: Vec::new(),
+            disk_progress: Instant::now(),
             throttle,
             trackers,
             choker: choker::Choker::new(config.peer.unchoke_slots_limit),
@@ -302,8 +506,21 @@ impl<T: cio::CIO> Torrent<T> {
             status,
             info_bytes,
             info_idx,
+            info_have: Bitfield::new(0),
             created: Utc::now(),
+            last_active: Instant::now(),
+            idle_announces: 0,
+            announce_key: rand::random(),
+            announce_ip: None,
+            max_peers: None,
+            verify_on_read: None,
+            allocation: None,
+            file_order: Vec::new(),
+            tracker_ok: false,
+            preset_priorities: BTreeMap::new(),
+            history: History::default(),
         };
+        t.throttle.set_priority(t.priority);
         t.start(true);
         if import {
             t.cio.msg_disk(disk::Request::validate_piece(
@@ -322,23 +539,43 @@ impl<T: cio::CIO> Torrent<T> {
         t
     }
 
+    #[cfg(test)]
     pub fn deserialize(
         config: Arc<Config>,
         id: usize,
         session_data: &[u8],
         info_data: Option<&[u8]>,
-        mut throttle: Throttle,
+        throttle: Throttle,
         cio: T,
     ) -> Option<Torrent<T>> {
-        let (migrated, d) = match session::torrent::load(session_data, info_data) {
-            session::torrent::LoadResult::Ok(torrent) => (false, torrent),
-            session::torrent::LoadResult::Migrated(torrent) => (true, torrent),
-            session::torrent::LoadResult::Failed => return None,
-        };
-        debug!("Torrent data deserialized!");
+        let saved = Saved::load(session_data, info_data)?;
+        Some(Torrent::restore(config, id, saved, throttle, cio))
+    }
+
+    /// Starts a torrent from its saved session.
+    pub fn restore(
+        config: Arc<Config>,
+        id: usize,
+        saved: Saved,
+        mut throttle: Throttle,
+        cio: T,
+    ) -> Torrent<T> {
+        let Saved {
+            torrent: d,
+            migrated,
+            session_hash,
+            history,
+            metadata,
+        } = saved;
         let peers = UHashMap::default();
         let leechers = FHashSet::default();
 
+        // The info file doesn't record the version, but only v2 hashes are 32 bytes. Hybrid
+        // torrents are checked just like v1 ones, so they can pass as v1.
+        let version = match d.info.hashes.first().map(Vec::len) {
+            Some(32) => info::MetaVersion::V2,
+            _ => info::MetaVersion::V1,
+        };
         let info = Arc::new(Info {
             name: d.info.name,
             announce: d
@@ -364,46 +601,74 @@ impl<T: cio::CIO> Torrent<T> {
             be_name: d.info.be_name,
             piece_idx: d.info.piece_idx,
             url_list: vec![],
+            version,
+            info_bytes: Vec::new(),
         });
 
-        let info_idx = if info.complete() {
+        let mut info_idx = if info.complete() {
             None
         } else {
             Some(usize::MAX)
         };
-        let info_bytes = if info_idx.is_none() {
-            info.to_bencode().encode_to_buf()
-        } else {
-            vec![]
+        let mut info_bytes = match metadata {
+            _ if info_idx.is_some() => vec![],
+            Some(data) if info.matches(&data) => data,
+            Some(_) => {
+                error!("Ignoring saved metadata which doesn't match the infohash");
+                info.to_bencode().encode_to_buf()
+            }
+            // Saved before the metadata was kept
+            None => info.to_bencode().encode_to_buf(),
         };
+        let mut info_have = Bitfield::new(0);
+        // Carry on with a metadata fetch interrupted by the restart
+        if let (Some(_), Some((have, data))) = (info_idx, d.session.metadata) {
+            let last_idx = data.len().saturating_sub(1) / 16_384;
+            let have = Bitfield::from(&have.data, have.len);
+            if !data.is_empty()
+                && data.len() as i64 <= MAX_INFO_BYTES
+                && have.len() == last_idx as u64 + 1
+                && !have.complete()
+            {
+                info_idx = Some(last_idx);
+                info_bytes = data;
+                info_have = have;
+            }
+        }
         let pieces = Bitfield::from(&d.session.pieces.data, d.session.pieces.len);
-        let picker = picker::Picker::new(&info, &pieces, &d.session.priorities);
+        let picker = picker::Picker::new(
+            &info,
+            &pieces,
+            &d.session.priorities,
+            config.peer.endgame_duplicates,
+        );
         throttle.set_ul_rate(d.session.throttle_ul);
         throttle.set_dl_rate(d.session.throttle_dl);
-
-        let mut trackers: VecDeque<_> = d
-            .session
-            .trackers
-            .into_iter()
-            .filter_map(|url| Url::parse(&url).ok())
-            .map(|url| Tracker {
-                status: TrackerStatus::Updating,
-                update: None,
-                last_announce: Utc::now(),
-                url: Arc::new(url),
-            })
-            .collect();
-
-        if trackers.is_empty()
-            && let Some(ref announce) = info.announce
-        {
-            let tracker = Tracker {
-                status: TrackerStatus::Updating,
-                update: None,
-                last_announce: Utc::now(),
-                url: announce.clone(),
-            };
-            trackers.push_back(tracker);
+        throttle.set_priority(d.session.priority);
+
+        let mut trackers = Tiers::new(d.session.trackers.into_iter().map(|tier| {
+            tier.into_iter()
+                .filter_map(|url| Url::parse(&url).ok())
+                .map(Arc::new)
+                .collect::<Vec<_>>()
+        }));
+        if trackers.is_empty() {
+            trackers = Tiers::new(info.announce.iter().map(|url| [url.clone()]));
+        }
+        for (url, lines) in d.session.tracker_headers {
+            let tracker = Url::parse(&url).ok().and_then(|u| trackers.find_mut(&u));
+            match (tracker, Headers::parse(&lines)) {
+                (Some(t), Ok(headers)) => t.headers = headers,
+                (Some(_), Err(e)) => error!("Dropping invalid headers of tracker {}: {}", url, e),
+                (None, _) => {}
+            }
+        }
+        // Trackers saved before ids were kept get the ids they used to be derived from
+        let mut ids: FHashMap<_, _> = d.session.tracker_ids.into_iter().collect();
+        for trk in trackers.iter_mut() {
+            trk.id = ids
+                .remove(trk.url.as_str())
+                .unwrap_or_else(|| util::trk_rpc_id(&info.hash, &trk.url));
         }
 
         let files = Files::new(&info, &pieces);
@@ -413,23 +678,38 @@ impl<T: cio::CIO> Torrent<T> {
             id,
             info,
             peers,
+            rpc_pieces: pieces.clone(),
             pieces,
             validating: FHashSet::default(),
             picker,
             uploaded: d.session.uploaded,
             downloaded: d.session.downloaded,
+            ses_uploaded: 0,
+            ses_downloaded: 0,
             wasted: 0,
+            corrupt: 0,
             files,
             stat: stat::EMA::new(),
             priorities: Arc::new(d.session.priorities),
-            priority: d.session.priority,
+            priority: d.session.priority.min(MAX_PRIORITY),
             cio,
+            evicting: FHashSet::default(),
             leechers,
+            transferring: FHashSet::default(),
+            stalls: Timers::new(),
+            idle_peers: Timers::new(),
+            known_peers: FHashMap::default(),
+            redial: Vec::new(),
+            availability: Availability::new(),
+            disk_pending: 0,
+            held_writes: Vec::new(),
+            held_reads: Vec::new(),
+            disk_progress: Instant::now(),
             throttle,
             trackers,
             choker: choker::Choker::new(config.peer.unchoke_slots_limit),
             dirty: false,
-            dirty_hash: Some(blake3::hash(session_data)),
+            dirty_hash: Some(session_hash),
             status: Status {
                 paused: d.session.status.paused,
                 validating: None,
@@ -439,12 +719,33 @@ impl<T: cio::CIO> Torrent<T> {
                     session::torrent::current::StatusState::Incomplete => StatusState::Incomplete,
                     session::torrent::current::StatusState::Complete => StatusState::Complete,
                 },
+                disk_stalled: false,
+                moving: None,
             },
             path: d.session.path,
             info_bytes,
             info_idx,
+            info_have,
             created: d.session.created,
+            last_active: Instant::now(),
+            idle_announces: 0,
+            announce_key: d.session.announce_key,
+            announce_ip: d.session.announce_ip,
+            max_peers: d.session.max_peers,
+            verify_on_read: d.session.verify_on_read,
+            allocation: d.session.allocation.map(|a| match a {
+                session::torrent::current::Allocation::Sparse => Allocation::Sparse,
+                session::torrent::current::Allocation::Full => Allocation::Full,
+                session::torrent::current::Allocation::SkipUnwanted => Allocation::SkipUnwanted,
+            }),
+            file_order: Vec::new(),
+            tracker_ok: false,
+            preset_priorities: d.session.preset_priorities.into_iter().collect(),
+            history,
         };
+        let files = d.session.file_order.into_iter();
+        t.file_order = files.filter(|&f| f < t.info.files.len()).collect();
+        t.picker.set_order(&t.file_order, &t.info);
         if migrated {
             t.serialize_info();
             t.serialize_session();
@@ -455,9 +756,10 @@ impl<T: cio::CIO> Torrent<T> {
         if d.session.status.validating {
             t.validate();
         } else {
+            t.check_partial(d.session.partial);
             t.announce_start();
         }
-        Some(t)
+        t
     }
 
     pub fn serialize_session_if_dirty(&mut self) {
@@ -505,15 +807,81 @@ impl<T: cio::CIO> Torrent<T> {
             created: self.created,
             throttle_ul: self.throttle.ul_rate(),
             throttle_dl: self.throttle.dl_rate(),
-            trackers: self
-                .trackers
+            trackers: self.trackers.urls(),
+            announce_key: self.announce_key,
+            tracker_headers: self.trackers.headers(),
+            announce_ip: self.announce_ip,
+            partial: self.partial_pieces(),
+            max_peers: self.max_peers,
+            tracker_ids: self.trackers.ids(),
+            file_order: self.file_order.clone(),
+            metadata: match self.info_idx {
+                Some(idx) if idx != usize::MAX => {
+                    let have = session::torrent::Bitfield {
+                        len: self.info_have.len(),
+                        data: self.info_have.data(),
+                    };
+                    Some((have, self.info_bytes.clone()))
+                }
+                _ => None,
+            },
+            verify_on_read: self.verify_on_read,
+            allocation: self.allocation.map(|a| match a {
+                Allocation::Sparse => session::torrent::current::Allocation::Sparse,
+                Allocation::Full => session::torrent::current::Allocation::Full,
+                Allocation::SkipUnwanted => session::torrent::current::Allocation::SkipUnwanted,
+            }),
+            preset_priorities: self
+                .preset_priorities
                 .iter()
-                .map(|trk| trk.url.as_str().to_owned())
+                .map(|(f, p)| (*f, *p))
                 .collect(),
         };
         bincode::serialize(&d).expect("Serialization failed!")
     }
 
+    /// The received blocks of pieces still being downloaded, favouring the most complete.
+    fn partial_pieces(&self) -> Vec<(u32, session::torrent::Bitfield)> {
+        let mut partial: Vec<_> = self
+            .picker
+            .partial()
+            .into_iter()
+            .filter(|(piece, _)| !self.pieces.has_bit(u64::from(*piece)))
+            .collect();
+        if partial.len() > MAX_PARTIAL {
+            partial.sort_by_key(|(piece, blocks)| (std::cmp::Reverse(blocks.set()), *piece));
+            partial.truncate(MAX_PARTIAL);
+            partial.sort_unstable_by_key(|(piece, _)| *piece);
+        }
+        partial
+            .into_iter()
+            .map(|(piece, blocks)| {
+                let blocks = session::torrent::Bitfield {
+                    len: blocks.len(),
+                    data: blocks.data(),
+                };
+                (piece, blocks)
+            })
+            .collect()
+    }
+
+    /// Has the disk confirm the blocks received before the last shutdown, which are
+    /// restored once they're found to be there.
+    fn check_partial(&mut self, partial: Vec<(u32, session::torrent::Bitfield)>) {
+        for (piece, blocks) in partial {
+            if piece >= self.info.pieces() || self.pieces.has_bit(u64::from(piece)) {
+                continue;
+            }
+            self.cio.msg_disk(disk::Request::check_blocks(
+                self.id,
+                self.info.clone(),
+                self.path.clone(),
+                piece,
+                Bitfield::from(&blocks.data, blocks.len),
+            ));
+        }
+    }
+
     fn serialize_info(&mut self) {
         let info = session::torrent::current::Info {
             name: self.info.name.clone(),
@@ -543,6 +911,14 @@ impl<T: cio::CIO> Torrent<T> {
             self.info.hash,
             Some(".info"),
         ));
+        if self.info_idx.is_none() {
+            self.cio.msg_disk(disk::Request::serialize(
+                self.id,
+                self.info_bytes.clone(),
+                self.info.hash,
+                Some(".metadata"),
+            ));
+        }
     }
 
     fn serialize_session(&mut self) {
@@ -562,6 +938,85 @@ impl<T: cio::CIO> Torrent<T> {
         util::hash_to_id(&self.info.hash)
     }
 
+    /// Adds an action taken by `by` to the work log, and saves it.
+    pub fn record(&mut self, by: &str, action: message::HistoryAction, detail: Option<String>) {
+        debug!("{:?}: {:?} {:?} by {}", self.rpc_id(), action, detail, by);
+        self.history.record(by, action, detail);
+        self.cio.msg_disk(disk::Request::serialize(
+            self.id,
+            self.history.serialize(),
+            self.info.hash,
+            Some(".history"),
+        ));
+    }
+
+    /// Continues the work log left behind when the torrent was last removed.
+    pub fn set_history(&mut self, history: History) {
+        self.history = history;
+    }
+
+    pub fn history(&self, serial: u64) -> message::History {
+        message::History {
+            serial,
+            id: self.rpc_id(),
+            entries: self.history.entries(),
+        }
+    }
+
+    /// Path of the file with the given RPC id, as shown in the work log.
+    pub fn file_path(&self, rpc_id: &str) -> Option<String> {
+        self.info
+            .files
+            .iter()
+            .find(|f| util::file_rpc_id(&self.info.hash, &f.path) == rpc_id)
+            .map(|f| f.path.to_string_lossy().into_owned())
+    }
+
+    /// Url of the tracker with the given RPC id.
+    pub fn tracker_url(&self, rpc_id: &str) -> Option<String> {
+        self.trackers
+            .find_id(rpc_id)
+            .map(|t| t.url.as_str().to_owned())
+    }
+
+    /// The torrent's metainfo, or None if it's a magnet still awaiting metadata.
+    pub fn metainfo(&self, serial: u64) -> Option<message::Metainfo> {
+        if self.status.magnet() {
+            return None;
+        }
+        let files = self
+            .info
+            .files
+            .iter()
+            .map(|f| message::MetainfoFile {
+                path: f
+                    .path
+                    .iter()
+                    .map(|c| c.to_string_lossy().into_owned())
+                    .collect(),
+                length: f.length,
+            })
+            .collect();
+        Some(message::Metainfo {
+            serial,
+            hash: self.rpc_id(),
+            name: self.info.name.clone(),
+            name_raw: self
+                .info
+                .be_name
+                .as_ref()
+                .map(|n| BASE64_STANDARD.encode(n)),
+            piece_length: self.info.piece_len,
+            pieces: BASE64_STANDARD.encode(self.info.hashes.concat()),
+            files,
+            private: self.info.private,
+            announce: self.info.announce.as_ref().map(|u| u.as_str().to_owned()),
+            announce_list: self.trackers.urls(),
+            creator: self.info.creator.clone(),
+            comment: self.info.comment.clone(),
+        })
+    }
+
     pub fn delete(&mut self, artifacts: bool) {
         debug!("Sending file deletion request!");
         let mut files = Vec::new();
@@ -589,32 +1044,42 @@ impl<T: cio::CIO> Torrent<T> {
         self.priority
     }
 
+    /// Whether or not the torrent should request pieces from peers.
+    /// Torrents at priority 0 keep seeding what they have, but never download.
+    pub fn should_dl(&self) -> bool {
+        self.status.should_dl() && self.priority != 0
+    }
+
     pub fn set_tracker_response(&mut self, url: &Url, resp: &tracker::Result<TrackerResponse>) {
         let mut time = Instant::now();
-        let mut empty = false;
         match *resp {
             Ok(ref r) => {
-                if let Some(tracker) = self.trackers.iter_mut().find(|t| &*t.url == url) {
+                let interval = self.announce_interval(r.interval.max(r.min_interval.unwrap_or(0)));
+                if let Some(tracker) = self.trackers.find_mut(url) {
                     debug!(
                         "Got valid response for {}, peers: {}",
                         tracker.url,
                         r.peers.len()
                     );
-                    time += Duration::from_secs(u64::from(r.interval));
+                    time += interval;
                     tracker.status = TrackerStatus::Ok {
                         seeders: r.seeders,
                         leechers: r.leechers,
                         interval: r.interval,
                     };
                     tracker.update = Some(time);
+                    tracker.min_update = r
+                        .min_interval
+                        .map(|i| Instant::now() + Duration::from_secs(u64::from(i)));
+                    tracker.failures = 0;
+                    tracker.retry_at = None;
                     tracker.last_announce = Utc::now();
-                    if r.peers.is_empty() {
-                        empty = true;
-                    }
                 }
+                self.trackers.succeeded(url);
+                self.tracker_ok = true;
             }
             Err(tracker::Error::TrackerError(ref s)) => {
-                if let Some(tracker) = self.trackers.iter_mut().find(|t| &*t.url == url) {
+                if let Some(tracker) = self.trackers.find_mut(url) {
                     debug!("Got tracker level error for {}", tracker.url);
                     info!(
                         "Got tracker error for {} from {}: {}",
@@ -622,17 +1087,28 @@ impl<T: cio::CIO> Torrent<T> {
                         tracker.url,
                         s
                     );
-                    time += Duration::from_secs(self.config.net.min_announce_interval);
+                    let delay = Duration::from_secs(self.config.net.min_announce_interval);
+                    time += delay;
                     tracker.update = Some(time);
+                    tracker.failures = 0;
+                    tracker.retry_at = chrono::Duration::from_std(delay)
+                        .ok()
+                        .map(|d| Utc::now() + d);
                     tracker.status = TrackerStatus::Failure(s.clone());
                     tracker.last_announce = Utc::now();
                 }
             }
             Err(ref e) => {
-                if let Some(tracker) = self.trackers.iter_mut().find(|t| &*t.url == url) {
+                if let Some(tracker) = self.trackers.find_mut(url) {
                     error!("Failed to query tracker {}: {}", tracker.url, e);
-                    time += Duration::from_secs(self.config.net.min_announce_interval);
+                    tracker.failures += 1;
+                    let delay =
+                        retry_delay(tracker.failures, self.config.net.min_announce_interval);
+                    time += delay;
                     tracker.update = Some(time);
+                    tracker.retry_at = chrono::Duration::from_std(delay)
+                        .ok()
+                        .map(|d| Utc::now() + d);
                     let reason = format!("Couldn't contact tracker: {e}");
                     tracker.status = TrackerStatus::Failure(reason);
                     tracker.last_announce = Utc::now();
@@ -640,24 +1116,50 @@ impl<T: cio::CIO> Torrent<T> {
             }
         }
 
-        if (resp.is_err() || empty)
-            && self.trackers.iter().any(|t| &*t.url == url)
-            && let Some(front) = self.trackers.pop_front()
-        {
-            self.trackers.push_back(front);
-            self.try_update_tracker();
+        // Fall back to the next tracker immediately, once all have failed
+        // the first is retried after its backoff
+        if resp.is_err() && self.trackers.failed(url) {
+            self.update_tracker();
         }
         self.update_rpc_tracker();
     }
 
+    /// Whether the torrent is a seed which has not transferred any data
+    /// within the configured idle timeout.
+    /// When a peer which transfers nothing from now on will have gone idle.
+    fn idle_deadline(&self) -> Instant {
+        Instant::now() + Duration::from_secs(self.config.idle.timeout)
+    }
+
+    fn dormant(&self) -> bool {
+        let timeout = self.config.idle.timeout;
+        timeout != 0
+            && self.complete()
+            && self.last_active.elapsed() >= Duration::from_secs(timeout)
+    }
+
+    /// Computes the delay until the next announce given the tracker's interval,
+    /// doubling it for every announce made while dormant.
+    fn announce_interval(&mut self, interval: u32) -> Duration {
+        let interval = Duration::from_secs(u64::from(interval));
+        if !self.dormant() {
+            return interval;
+        }
+        let cap = Duration::from_secs(self.config.idle.max_announce_interval);
+        let backoff = interval.saturating_mul(1 << self.idle_announces.min(16));
+        self.idle_announces += 1;
+        backoff.min(cap).max(interval)
+    }
+
     pub fn try_update_tracker(&mut self) {
         if self.status.stopped() {
             return;
         }
-        if let Some(end) = self.trackers.front().and_then(|t| t.update) {
+        if let Some(end) = self.trackers.current().and_then(|t| t.update) {
             debug!("Updating tracker at interval!");
             let cur = Instant::now();
             if cur >= end {
+                self.trackers.rewind();
                 self.update_tracker();
             }
         } else {
@@ -670,11 +1172,21 @@ impl<T: cio::CIO> Torrent<T> {
             return;
         }
         if let Some(req) = tracker::Request::interval(self) {
-            self.cio.msg_trk(req);
+            self.send_announce(req);
         }
         self.dht_announce();
     }
 
+    /// Sends an announce to the current tracker, giving it until the
+    /// minimum announce interval to respond before it's retried.
+    fn send_announce(&mut self, req: tracker::Request) {
+        let deadline = Instant::now() + Duration::from_secs(self.config.net.min_announce_interval);
+        if let Some(trk) = self.trackers.current_mut() {
+            trk.update = Some(deadline);
+        }
+        self.cio.msg_trk(req);
+    }
+
     pub fn remove_peer(&mut self, rpc_id: &str) {
         let ih = &self.info.hash;
         let cio = &mut self.cio;
@@ -688,57 +1200,88 @@ impl<T: cio::CIO> Torrent<T> {
     }
 
     pub fn add_tracker(&mut self, url: Url) -> String {
-        let id = util::trk_rpc_id(&self.info.hash, &url);
-        self.trackers.push_front(Tracker {
-            status: TrackerStatus::Updating,
-            update: None,
-            last_announce: Utc::now(),
-            url: Arc::new(url),
-        });
-        {
-            let trk = &self.trackers[0];
-            let res = vec![resource::Resource::Tracker(resource::Tracker {
-                id: id.clone(),
-                torrent_id: self.rpc_id(),
-                url: trk.url.as_ref().clone(),
-                last_report: trk.last_announce,
-                error: None,
-                ..Default::default()
-            })];
-            self.cio.msg_rpc(rpc::CtlMessage::Extant(res));
-        }
+        let idx = self.trackers.add(url);
+        let trk = self.trackers.get(idx).expect("tracker was just added");
+        let id = trk.id.clone();
+        let res = vec![self.rpc_tracker(trk)];
+        self.cio.msg_rpc(rpc::CtlMessage::Extant(res));
         self.announce_start();
         id
     }
 
     pub fn remove_tracker(&mut self, rpc_id: &str) {
-        let ih = &self.info.hash;
-        let mut res = None;
-        for (idx, tracker) in self.trackers.iter().enumerate() {
-            if util::trk_rpc_id(ih, &tracker.url) == rpc_id {
-                res = Some(idx);
-                self.cio
-                    .msg_rpc(rpc::CtlMessage::Removed(vec![rpc_id.to_owned()]));
-                break;
-            }
-        }
-
-        if let Some(idx) = res {
+        if let Some(idx) = self.trackers.position_id(rpc_id) {
             self.trackers.remove(idx);
+            self.cio
+                .msg_rpc(rpc::CtlMessage::Removed(vec![rpc_id.to_owned()]));
         }
     }
 
     pub fn update_tracker_req(&mut self, rpc_id: &str) {
-        if let Some(req) = self
-            .trackers
-            .iter()
-            .find(|trk| util::trk_rpc_id(&self.info.hash, &trk.url) == rpc_id)
-            .and_then(|trk| tracker::Request::custom(self, trk.url.clone()))
-        {
+        let Some(trk) = self.trackers.find_id(rpc_id) else {
+            return;
+        };
+        if trk.min_update.is_some_and(|t| Instant::now() < t) {
+            debug!("Not announcing to {} before its min interval", trk.url);
+            return;
+        }
+        self.announce_to(trk.url.clone());
+    }
+
+    /// Announces to one of the torrent's trackers right away.
+    pub fn announce_to(&mut self, url: Arc<Url>) {
+        if let Some(req) = tracker::Request::custom(self, url) {
             self.cio.msg_trk(req)
         }
     }
 
+    /// Points a tracker at a new url, keeping its id, headers and tier.
+    pub fn update_tracker_url(&mut self, rpc_id: &str, url: Url) {
+        if self.trackers.position(&url).is_some() {
+            error!(
+                "Not moving tracker {} to {}, which is already a tracker",
+                rpc_id, url
+            );
+            return;
+        }
+        let Some(trk) = self.trackers.find_id_mut(rpc_id) else {
+            return;
+        };
+        debug!("Moving tracker {} to {}", trk.url, url);
+        trk.url = Arc::new(url.clone());
+        trk.status = TrackerStatus::Updating;
+        trk.update = None;
+        trk.min_update = None;
+        trk.failures = 0;
+        trk.retry_at = None;
+        self.dirty = true;
+        self.cio
+            .msg_rpc(rpc::CtlMessage::Update(vec![SResourceUpdate::TrackerUrl {
+                id: rpc_id.to_owned(),
+                kind: resource::ResourceKind::Tracker,
+                url,
+            }]));
+    }
+
+    pub fn update_tracker_headers(&mut self, rpc_id: &str, lines: &[String]) {
+        let Some(trk) = self.trackers.find_id_mut(rpc_id) else {
+            return;
+        };
+        if let Err(e) = trk.headers.update(lines) {
+            error!("Ignoring headers for {}: {}", trk.url, e);
+            return;
+        }
+        let headers = trk.headers.redacted();
+        self.dirty = true;
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            SResourceUpdate::TrackerHeaders {
+                id: rpc_id.to_owned(),
+                kind: resource::ResourceKind::Tracker,
+                headers,
+            },
+        ]));
+    }
+
     pub fn get_throttle(&self, id: usize) -> Throttle {
         self.throttle.new_sibling(id)
     }
@@ -747,39 +1290,286 @@ impl<T: cio::CIO> Torrent<T> {
         self.id
     }
 
-    pub fn uploaded(&self) -> u64 {
-        self.uploaded
+    pub fn announce_key(&self) -> u32 {
+        self.announce_key
     }
 
-    pub fn downloaded(&self) -> u64 {
-        self.downloaded
+    /// The addresses to report to trackers, if any.
+    pub fn announce_ips(&self) -> (Option<Ipv4Addr>, Option<Ipv6Addr>) {
+        match self.announce_ip {
+            Some(IpAddr::V4(ip)) => (Some(ip), self.config.trk.announce_ip6),
+            Some(IpAddr::V6(ip)) => (self.config.trk.announce_ip, Some(ip)),
+            None => (self.config.trk.announce_ip, self.config.trk.announce_ip6),
+        }
     }
 
-    pub fn info(&self) -> &Info {
-        &self.info
+    pub fn set_announce_ip(&mut self, ip: Option<IpAddr>) {
+        self.announce_ip = ip;
+        self.dirty = true;
+        let id = self.rpc_id();
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            SResourceUpdate::TorrentAnnounceIp {
+                id,
+                kind: resource::ResourceKind::Torrent,
+                announce_ip: ip.map(|ip| ip.to_string()),
+            },
+        ]));
     }
 
-    pub fn trackers(&self) -> &VecDeque<Tracker> {
-        &self.trackers
+    /// The number of peers which may be connected at once.
+    pub fn peer_limit(&self) -> u16 {
+        self.max_peers
+            .unwrap_or(self.config.peer.max_peers_per_torrent)
     }
 
-    pub fn handle_disk_resp(&mut self, resp: disk::Response) {
-        match resp {
-            disk::Response::Read { context, data } => {
-                trace!("Received piece from disk, uploading!");
-                if let Some(peer) = self.peers.get_mut(&context.pid) {
-                    let p = Message::piece(context.idx, context.begin, context.length, data);
-                    // This may not be 100% accurate, but close enough for now.
-                    self.uploaded += u64::from(context.length);
-                    self.stat.add_ul(u64::from(context.length));
-                    self.dirty = true;
-                    peer.send_message(p);
-                }
+    /// Whether the torrent has as many peers as it's allowed.
+    pub fn peers_full(&self) -> bool {
+        self.peers.len() - self.evicting.len() >= usize::from(self.peer_limit())
+    }
+
+    /// Whether a new peer would be accepted, possibly in place of an existing one.
+    pub fn peers_wanted(&self) -> bool {
+        self.peers_allowed() && (!self.peers_full() || self.replaceable_peer().is_some())
+    }
+
+    /// The peer to drop in favour of a new one: of those choking us which haven't
+    /// transferred anything in a while, the one idle the longest.
+    fn replaceable_peer(&self) -> Option<usize> {
+        self.peers
+            .values()
+            .filter(|p| {
+                p.choked()
+                    && p.get_tx_rates() == (0, 0)
+                    && p.idle_time() >= PEER_REPLACE_IDLE
+                    && !self.evicting.contains(&p.id())
+            })
+            .max_by_key(|p| (p.idle_time(), std::cmp::Reverse(p.id())))
+            .map(|p| p.id())
+    }
+
+    /// Makes room for a new peer, disconnecting a useless one if the torrent is full.
+    fn make_room(&mut self) -> bool {
+        if !self.peers_full() {
+            return true;
+        }
+        let Some(pid) = self.replaceable_peer() else {
+            debug!(
+                "{}: Rejecting peer, already at the limit of {}",
+                self.rpc_id(),
+                self.peer_limit()
+            );
+            return false;
+        };
+        debug!("{}: Replacing idle peer {}", self.rpc_id(), pid);
+        self.evicting.insert(pid);
+        self.cio.remove_peer(pid);
+        true
+    }
+
+    pub fn set_max_peers(&mut self, max_peers: Option<u16>) {
+        self.max_peers = max_peers;
+        self.dirty = true;
+        self.send_max_peers();
+    }
+
+    /// Switches to a reloaded config. Peers above a lowered limit are dropped at the next
+    /// unchoke round.
+    pub fn set_config(&mut self, config: Arc<Config>) {
+        let max_peers = self.peer_limit();
+        self.config = config;
+        if self.peer_limit() != max_peers {
+            self.send_max_peers();
+        }
+    }
+
+    fn send_max_peers(&mut self) {
+        let id = self.rpc_id();
+        let max_peers = self.peer_limit();
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            SResourceUpdate::TorrentMaxPeers {
+                id,
+                kind: resource::ResourceKind::Torrent,
+                max_peers,
+            },
+        ]));
+    }
+
+    /// Whether pieces are hashed from disk before any of their blocks are uploaded.
+    fn verify_on_read(&self) -> bool {
+        self.verify_on_read
+            .unwrap_or(self.config.disk.verify_on_read)
+    }
+
+    pub fn set_verify_on_read(&mut self, verify: Option<bool>) {
+        self.verify_on_read = verify;
+        self.dirty = true;
+        let id = self.rpc_id();
+        let verify_on_read = self.verify_on_read();
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            SResourceUpdate::TorrentVerifyOnRead {
+                id,
+                kind: resource::ResourceKind::Torrent,
+                verify_on_read,
+            },
+        ]));
+    }
+
+    /// How space is reserved for the torrent's files.
+    fn allocation(&self) -> Allocation {
+        self.allocation.unwrap_or(self.config.disk.allocation)
+    }
+
+    pub fn set_allocation(&mut self, allocation: Option<Allocation>) {
+        let prev = self.allocation();
+        self.allocation = allocation;
+        self.dirty = true;
+        let allocation = self.allocation();
+        // Files which were skipped get created as they're written to from now on, so
+        // whatever of them is only in the parts file has to be moved over first
+        if prev == Allocation::SkipUnwanted && allocation != Allocation::SkipUnwanted {
+            for (i, _) in self.priorities.iter().enumerate().filter(|(_, p)| **p == 0) {
+                self.cio.msg_disk(disk::Request::materialize(
+                    self.id,
+                    self.info.clone(),
+                    self.path.clone(),
+                    i,
+                ));
             }
-            disk::Response::Write { context: _ } => { /* TODO: implement */ }
-            disk::Response::Moved { path, .. } => {
+        }
+        let id = self.rpc_id();
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            SResourceUpdate::TorrentAllocation {
+                id,
+                kind: resource::ResourceKind::Torrent,
+                allocation,
+            },
+        ]));
+    }
+
+    /// Has the given files downloaded to completion in order before anything else.
+    pub fn set_file_order(&mut self, mut order: Vec<usize>) {
+        let mut seen = FHashSet::default();
+        order.retain(|&f| seen.insert(f));
+        self.file_order = order;
+        self.picker.set_order(&self.file_order, &self.info);
+        self.dirty = true;
+        self.rpc_update_file_order();
+        self.check_file_order();
+        self.request_all();
+    }
+
+    /// Drops the file order once every file in it is complete, or not wanted.
+    fn check_file_order(&mut self) {
+        let done = self
+            .file_order
+            .iter()
+            .all(|&f| self.priorities[f] == 0 || self.files.done[f] == self.info.files[f].length);
+        if done && !self.file_order.is_empty() {
+            debug!("{}: Files downloaded in order", self.rpc_id());
+            self.file_order.clear();
+            self.picker.set_order(&[], &self.info);
+            self.dirty = true;
+            self.rpc_update_file_order();
+        }
+    }
+
+    fn rpc_update_file_order(&mut self) {
+        let id = self.rpc_id();
+        let file_order = self.file_order_ids();
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            SResourceUpdate::TorrentFileOrder {
+                id,
+                kind: resource::ResourceKind::Torrent,
+                file_order,
+            },
+        ]));
+    }
+
+    fn file_order_ids(&self) -> Vec<String> {
+        self.file_order
+            .iter()
+            .map(|&f| util::file_rpc_id(&self.info.hash, &self.info.files[f].path))
+            .collect()
+    }
+
+    pub fn uploaded(&self) -> u64 {
+        self.uploaded
+    }
+
+    pub fn downloaded(&self) -> u64 {
+        self.downloaded
+    }
+
+    /// Bytes downloaded in pieces which failed validation.
+    pub fn corrupt(&self) -> u64 {
+        self.corrupt
+    }
+
+    /// Bytes downloaded which were thrown away for any reason other than failing validation.
+    pub fn redundant(&self) -> u64 {
+        self.wasted - self.corrupt
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn info(&self) -> &Info {
+        &self.info
+    }
+
+    pub fn trackers(&self) -> &Tiers {
+        &self.trackers
+    }
+
+    pub fn handle_disk_resp(&mut self, resp: disk::Response) {
+        if let disk::Response::Read { .. }
+        | disk::Response::Corrupt { .. }
+        | disk::Response::Write { .. }
+        | disk::Response::Error {
+            job: disk::JobKind::Read | disk::JobKind::Write,
+            ..
+        }
+        | disk::Response::Missing { .. } = resp
+        {
+            self.disk_completed();
+        }
+        match resp {
+            disk::Response::Read { context, data } => {
+                trace!("Received piece from disk, uploading!");
+                // Reads which finish after the peer was choked or the torrent
+                // stopped are dropped rather than sent.
+                if let Some(peer) = self.peers.get_mut(&context.pid)
+                    && !peer.choking()
+                    && !self.status.stopped()
+                {
+                    // Upload is accounted for once the writer actually sends it
+                    let p = Message::piece(context.idx, context.begin, context.length, data);
+                    peer.send_message(p);
+                    if self.transferring.insert(context.pid) {
+                        peer.resume_tick();
+                    }
+                }
+            }
+            // The peer's request is dropped, it gets no data for a piece we no longer have
+            disk::Response::Corrupt { context } => self.drop_corrupt_piece(context.idx),
+            disk::Response::Write { context: _ } => { /* TODO: implement */ }
+            disk::Response::MoveUpdate { percent, .. } => {
+                self.status.moving = Some(percent);
+            }
+            disk::Response::Moved { path, files, .. } => {
                 debug!("Moved torrent!");
                 self.set_path_skip_files(path);
+                self.finish_move();
+                let updates = files
+                    .into_iter()
+                    .map(|(idx, move_status)| SResourceUpdate::FileMoveStatus {
+                        id: util::file_rpc_id(&self.info.hash, &self.info.files[idx].path),
+                        kind: resource::ResourceKind::File,
+                        move_status,
+                    })
+                    .collect();
+                self.cio.msg_rpc(rpc::CtlMessage::Update(updates));
             }
             disk::Response::PieceValidated { piece, valid, .. } => {
                 self.validating.remove(&piece);
@@ -797,10 +1587,12 @@ impl<T: cio::CIO> Torrent<T> {
                     self.announce_start();
                     self.files.rebuild(&self.info, &self.pieces);
                     self.update_rpc_transfer();
+                    self.dirty = true;
                     return;
                 }
                 if valid {
                     self.pieces.set_bit(u64::from(piece));
+                    self.dirty = true;
                     // Tell all relevant peers we got the piece
                     let m = Message::Have(piece);
                     for pid in &self.leechers {
@@ -811,10 +1603,13 @@ impl<T: cio::CIO> Torrent<T> {
                         }
                     }
                     self.files.update(&self.info, piece);
+                    self.check_file_order();
                     self.check_complete();
                 } else {
                     // TODO: trace down the bad peer and block it
                     debug!("Invalid piece downloaded!");
+                    self.discard_piece(piece);
+                    self.corrupt += u64::from(self.info.piece_len(piece));
                     self.picker.invalidate_piece(piece);
                     if !self.stat.active() {
                         self.request_all();
@@ -845,7 +1640,9 @@ impl<T: cio::CIO> Torrent<T> {
                             }
                         }
                     }
+                    self.picker.done();
                     self.set_finished();
+                    self.dirty = true;
                 } else {
                     // If this is an initialization hash, start the torrent
                     // immediatly.
@@ -878,23 +1675,102 @@ impl<T: cio::CIO> Torrent<T> {
                         self.request_all();
                     }
                     self.status.state = StatusState::Incomplete;
+                    self.dirty = true;
                 }
                 // update the RPC stats once done
                 self.files.rebuild(&self.info, &self.pieces);
+                self.check_file_order();
                 self.update_rpc_transfer();
                 self.rpc_update_pieces();
                 self.announce_status();
             }
-            disk::Response::Error { err, .. } => {
-                error!("Disk error: {:?}", err);
-                self.status.error = Some(format!("{err}"));
-                self.announce_status();
-                for piece in self.validating.drain() {
-                    self.picker.invalidate_piece(piece);
-                    self.pieces.unset_bit(u64::from(piece));
+            disk::Response::BlocksChecked { piece, blocks, .. } => {
+                if self.pieces.has_bit(u64::from(piece)) || !self.picker.restore(piece, &blocks) {
+                    return;
+                }
+                debug!(
+                    "{:?}: Resuming piece {} with {} blocks on disk",
+                    self.rpc_id(),
+                    piece,
+                    blocks.set()
+                );
+                if blocks.complete() {
+                    // Shut down while the piece was being validated
+                    self.cio.msg_disk(disk::Request::validate_piece(
+                        self.id,
+                        self.info.clone(),
+                        self.path.clone(),
+                        piece,
+                    ));
+                    self.validating.insert(piece);
+                } else {
+                    self.request_all();
                 }
             }
-            disk::Response::FreeSpace(_) => unreachable!(),
+            disk::Response::Error { job, err, .. } => {
+                self.disk_error(err);
+                if job == disk::JobKind::Move {
+                    self.finish_move();
+                }
+            }
+            disk::Response::Missing { err, .. } => match self.config.disk.on_missing {
+                MissingFiles::Error => self.disk_error(err),
+                MissingFiles::Pause => {
+                    if !self.status.paused {
+                        info!("{:?}: Files missing ({}), pausing", self.rpc_id(), err);
+                        self.pause();
+                        self.record(
+                            history::SYSTEM,
+                            message::HistoryAction::Pause,
+                            Some("files missing".to_owned()),
+                        );
+                    }
+                }
+                MissingFiles::Recheck => {
+                    if self.status.validating.is_none() {
+                        info!("{:?}: Files missing ({}), rechecking", self.rpc_id(), err);
+                        self.validate();
+                    }
+                }
+            },
+            disk::Response::FreeSpace(_)
+            | disk::Response::Stats(_)
+            | disk::Response::PathAnalysis(..) => unreachable!(),
+        }
+    }
+
+    /// Forgets a piece which was found not to match its hash when read to be uploaded, so
+    /// it's no longer offered to peers and is downloaded again if any of it is wanted.
+    fn drop_corrupt_piece(&mut self, piece: u32) {
+        // Other blocks of the piece may have been queued for reading before it was found
+        if !self.pieces.has_bit(u64::from(piece)) {
+            return;
+        }
+        error!(
+            "{:?}: Piece {} no longer matches its hash on disk, dropping it",
+            self.rpc_id(),
+            piece
+        );
+        self.pieces.unset_bit(u64::from(piece));
+        self.picker.invalidate_piece(piece);
+        self.files.rebuild(&self.info, &self.pieces);
+        self.dirty = true;
+        self.update_rpc_transfer();
+        self.rpc_update_pieces();
+        if self.status.state == StatusState::Complete {
+            self.check_complete();
+        } else {
+            self.request_all();
+        }
+    }
+
+    fn disk_error(&mut self, err: io::Error) {
+        error!("Disk error: {:?}", err);
+        self.status.error = Some(format!("{err}"));
+        self.announce_status();
+        for piece in self.validating.drain() {
+            self.picker.invalidate_piece(piece);
+            self.pieces.unset_bit(u64::from(piece));
         }
     }
 
@@ -912,17 +1788,28 @@ impl<T: cio::CIO> Torrent<T> {
         }
 
         if complete {
-            if self.status.state != StatusState::Complete {
-                self.status.state = StatusState::Complete;
-                self.picker.done();
-                self.set_finished();
-                self.serialize_session();
+            if self.status.state == StatusState::Complete {
+                return;
+            }
+            if self.config.disk.verify_complete {
+                // The torrent is finished once the check comes back clean
+                if self.status.validating.is_none() {
+                    info!("Torrent {} downloaded, verifying", self.rpc_id());
+                    self.validate();
+                }
+                return;
             }
+            self.status.state = StatusState::Complete;
+            self.picker.done();
+            self.set_finished();
+            self.serialize_session();
         } else if self.status.state == StatusState::Complete {
+            let partial = self.partial_seed();
             self.status.state = StatusState::Incomplete;
-            let seq = self.picker.is_sequential();
-            self.picker = Picker::new(&self.info, &self.pieces, &self.priorities);
-            self.change_picker(seq);
+            if partial {
+                self.update_upload_only();
+            }
+            self.reset_picker();
             self.announce_status();
             self.announce_start();
             self.request_all();
@@ -931,15 +1818,22 @@ impl<T: cio::CIO> Torrent<T> {
     /// Signal that we've downloaded and verified the torrent
     fn set_finished(&mut self) {
         info!("Torrent {} completed!", self.rpc_id());
-        debug!("Wasted: {} MiB", (self.wasted * 16_384) / (1024 * 1024));
+        debug!(
+            "Wasted: {} MiB, {} MiB corrupt",
+            self.wasted / (1024 * 1024),
+            self.corrupt / (1024 * 1024)
+        );
         if let Some(req) = tracker::Request::completed(self) {
-            self.cio.msg_trk(req);
+            self.send_announce(req);
         }
         // Order here is important, if we're in an idle status,
         // rpc updates don't occur.
         self.update_rpc_transfer();
         self.status.state = StatusState::Complete;
         self.announce_status();
+        if self.partial_seed() {
+            self.update_upload_only();
+        }
 
         // Remove all seeding peers.
         let leechers = &self.leechers;
@@ -957,10 +1851,8 @@ impl<T: cio::CIO> Torrent<T> {
         // Due to how we do validation updates, we should tell peers we now have every single piece
         for pid in leechers {
             if let Some(peer) = self.peers.get_mut(pid) {
-                for i in 0..self.pieces.len() {
-                    if !peer.pieces().has_bit(i) {
-                        peer.send_message(Message::Have(i as u32));
-                    }
+                for i in self.pieces.andnot(peer.pieces()).iter() {
+                    peer.send_message(Message::Have(i as u32));
                 }
             }
         }
@@ -972,6 +1864,9 @@ impl<T: cio::CIO> Torrent<T> {
         match evt {
             Ok(mut msg) => {
                 if peer.handle_msg(&mut msg).is_ok() && self.handle_msg(msg, &mut peer).is_ok() {
+                    if let Some(at) = peer.stall_deadline() {
+                        self.stalls.schedule(pid, at);
+                    }
                     self.peers.insert(pid, peer);
                     return Ok(());
                 } else {
@@ -997,39 +1892,59 @@ impl<T: cio::CIO> Torrent<T> {
         Err(())
     }
 
+    /// Our extension handshake (BEP 10), which also tells peers whether we're only
+    /// uploading (BEP 21).
+    fn ext_handshake(&self) -> Message {
+        let mut ed = BTreeMap::new();
+        let mut m = BTreeMap::new();
+
+        m.insert(
+            b"ut_metadata".to_vec(),
+            bencode::BEncode::Int(i64::from(UT_META_ID)),
+        );
+        if !self.info.private {
+            m.insert(
+                b"ut_pex".to_vec(),
+                bencode::BEncode::Int(i64::from(UT_PEX_ID)),
+            );
+        }
+
+        ed.insert(b"m".to_vec(), bencode::BEncode::Dict(m));
+        ed.insert(
+            b"metadata_size".to_vec(),
+            bencode::BEncode::Int(self.info_bytes.len() as i64),
+        );
+        if self.partial_seed() {
+            ed.insert(b"upload_only".to_vec(), bencode::BEncode::Int(1));
+        }
+        let payload = bencode::BEncode::Dict(ed).encode_to_buf();
+        Message::Extension { id: 0, payload }
+    }
+
+    /// Sends the extension handshake again after becoming or ceasing to be a partial seed.
+    fn update_upload_only(&mut self) {
+        let msg = self.ext_handshake();
+        for peer in self.peers.values_mut() {
+            if peer.supports_extended() {
+                peer.send_message(msg.clone());
+            }
+        }
+    }
+
     pub fn handle_msg(&mut self, msg: Message, peer: &mut Peer<T>) -> Result<(), ()> {
         trace!("Received {:?} from peer", msg);
         match msg {
-            Message::Handshake { rsv, .. } => {
-                if (rsv[EXT_PROTO.0] & EXT_PROTO.1) != 0 {
-                    let mut ed = BTreeMap::new();
-                    let mut m = BTreeMap::new();
-
-                    m.insert(
-                        b"ut_metadata".to_vec(),
-                        bencode::BEncode::Int(i64::from(UT_META_ID)),
-                    );
-                    if !self.info.private {
-                        m.insert(
-                            b"ut_pex".to_vec(),
-                            bencode::BEncode::Int(i64::from(UT_PEX_ID)),
-                        );
-                    }
-
-                    ed.insert(b"m".to_vec(), bencode::BEncode::Dict(m));
-                    ed.insert(
-                        b"metadata_size".to_vec(),
-                        bencode::BEncode::Int(self.info_bytes.len() as i64),
-                    );
-                    let payload = bencode::BEncode::Dict(ed).encode_to_buf();
-
-                    peer.send_message(Message::Extension { id: 0, payload });
+            Message::Handshake { .. } => {
+                if peer.supports_extended() {
+                    peer.send_message(self.ext_handshake());
                 }
             }
             Message::Extension { id, payload } => {
                 self.handle_ext(id, payload, peer)?;
             }
-            Message::Bitfield(_) => {
+            Message::Bitfield(prev) => {
+                // The peer swaps its previous bitfield into the message
+                self.availability.replace(&prev, peer.pieces());
                 if self.pieces.usable(peer.pieces()) && self.status.validating.is_none() {
                     peer.interested();
                 }
@@ -1044,6 +1959,7 @@ impl<T: cio::CIO> Torrent<T> {
                 }
             }
             Message::Have(idx) => {
+                self.availability.have(idx);
                 if self.info.complete() {
                     self.picker.piece_available(idx);
                 }
@@ -1059,7 +1975,7 @@ impl<T: cio::CIO> Torrent<T> {
                 }
             }
             Message::Unchoke => {
-                if self.status.should_dl() && self.info.complete() {
+                if self.should_dl() && self.info.complete() {
                     Torrent::make_requests(peer, &mut self.picker, &self.info);
                 }
             }
@@ -1069,26 +1985,32 @@ impl<T: cio::CIO> Torrent<T> {
                 data,
                 length,
             } => {
+                if self.transferring.insert(peer.id()) {
+                    peer.resume_tick();
+                }
                 // Ignore a piece we already have, this could happen from endgame
                 if self.pieces.has_bit(u64::from(index)) || self.validating.contains(&index) {
-                    self.wasted += 1;
+                    self.wasted += u64::from(length);
                     return Ok(());
                 }
 
                 // Even though we have the data, if we are stopped we shouldn't use the disk
                 // regardless.
                 if self.status.stopped() || self.status.completed() {
+                    self.wasted += u64::from(length);
                     return Ok(());
                 }
 
                 // The length doesn't match what it should be
                 if self.info.block_len(index, begin) != length {
+                    self.wasted += u64::from(length);
                     return Err(());
                 }
 
                 // We already have this block, don't do anything with it, could happen
                 // from endgame
                 if self.picker.have_block(Block::new(index, begin)) {
+                    self.wasted += u64::from(length);
                     return Ok(());
                 }
 
@@ -1109,26 +2031,18 @@ impl<T: cio::CIO> Torrent<T> {
                 let piece_done = if let Ok(r) = pr {
                     r
                 } else {
+                    self.wasted += u64::from(length);
                     return Ok(());
                 };
 
                 self.dirty = true;
-                self.write_piece(index, begin, data);
+                self.store_block(index, begin, data, piece_done);
 
                 self.downloaded += u64::from(length);
+                self.ses_downloaded += u64::from(length);
                 self.stat.add_dl(u64::from(length));
 
-                if piece_done {
-                    self.cio.msg_disk(disk::Request::validate_piece(
-                        self.id,
-                        self.info.clone(),
-                        self.path.clone(),
-                        index,
-                    ));
-                    self.validating.insert(index);
-                }
-
-                if self.status.should_dl() {
+                if self.should_dl() {
                     Torrent::make_requests(peer, &mut self.picker, &self.info);
                 }
             }
@@ -1168,6 +2082,9 @@ impl<T: cio::CIO> Torrent<T> {
 
     fn handle_ext(&mut self, id: u8, payload: Vec<u8>, peer: &mut Peer<T>) -> Result<(), ()> {
         if id == 0 {
+            if let Some(addr) = peer.ext_hint() {
+                self.add_known_peer(addr, PeerSource::ExtHint);
+            }
             let b = bencode::decode_buf(&payload).map_err(|_| ())?;
             let mut d = b.into_dict().ok_or(())?;
             let m = d
@@ -1184,32 +2101,29 @@ impl<T: cio::CIO> Torrent<T> {
                         debug!("UT metadata size is 0");
                         return Err(());
                     }
-                    if size % 16_384 == 0 {
-                        self.info_idx = Some(size as usize / 16_384 - 1);
-                    } else {
-                        self.info_idx = Some(size as usize / 16_384);
-                    }
                     if size > MAX_INFO_BYTES {
                         debug!("UT metadata too large, {} MBs", size / (1000 * 1000));
                         return Err(());
                     }
+                    let last_idx = if size % 16_384 == 0 {
+                        size as usize / 16_384 - 1
+                    } else {
+                        size as usize / 16_384
+                    };
+                    self.info_idx = Some(last_idx);
                     self.info_bytes.resize(size as usize, 0u8);
+                    self.info_have = Bitfield::new(last_idx as u64 + 1);
                 }
                 if !self.info.complete() {
-                    // Request the first index chunk to see if they have it
-                    let mut respb = BTreeMap::new();
-                    respb.insert(b"msg_type".to_vec(), bencode::BEncode::Int(0));
-                    respb.insert(b"piece".to_vec(), bencode::BEncode::Int(0));
-                    let payload = bencode::BEncode::Dict(respb).encode_to_buf();
+                    // Request the first missing index chunk to see if they have it
                     let utm_id = if let Some(i) = peer.exts().ut_meta {
                         i
                     } else {
                         return Err(());
                     };
-                    peer.send_message(Message::Extension {
-                        id: utm_id,
-                        payload,
-                    });
+                    let probe = self.missing_info_pieces().next().unwrap_or(0);
+                    peer.set_meta_probe(probe);
+                    peer.send_message(meta_request(utm_id, probe));
                 }
             }
         } else if id == UT_META_ID {
@@ -1318,42 +2232,24 @@ impl<T: cio::CIO> Torrent<T> {
                         };
                         (self.info_bytes[piece_len * 16_384..piece_len * 16_384 + size])
                             .copy_from_slice(&payload[data_idx..]);
-                        if piece_len == last_idx {
-                            let mut b = BTreeMap::new();
-                            let bni = bencode::decode_buf(&self.info_bytes).map_err(|_| ())?;
-                            b.insert(
-                                b"announce".to_vec(),
-                                bencode::BEncode::String(
-                                    self.info
-                                        .announce
-                                        .as_ref()
-                                        .map(|u| u.as_str())
-                                        .unwrap_or("")
-                                        .as_bytes()
-                                        .to_vec(),
-                                ),
-                            );
-                            b.insert(b"info".to_vec(), bni);
-                            let ni =
-                                Info::from_bencode(bencode::BEncode::Dict(b)).map_err(|_| ())?;
-                            if ni.hash == self.info.hash {
+                        self.info_have.set_bit(piece_len as u64);
+                        self.dirty = true;
+                        if self.info_have.complete() {
+                            if let Some(ni) = self.assembled_info() {
                                 debug!("Magnet file acquired succesfully!");
                                 self.info_idx = None;
+                                self.info_have = Bitfield::new(0);
                                 self.info = Arc::new(ni);
                                 self.magnet_complete();
                             } else {
+                                // There's no telling which piece was bad, so start over
+                                self.info_have = Bitfield::new(self.info_have.len());
                                 return Err(());
                             }
-                        } else if piece_len == 0 {
-                            for i in 1..=last_idx {
-                                let mut respb = BTreeMap::new();
-                                respb.insert(b"msg_type".to_vec(), bencode::BEncode::Int(0));
-                                respb.insert(b"piece".to_vec(), bencode::BEncode::Int(i as i64));
-                                let payload = bencode::BEncode::Dict(respb).encode_to_buf();
-                                peer.send_message(Message::Extension {
-                                    id: utm_id,
-                                    payload,
-                                });
+                        } else if peer.meta_probe_answered(piece_len) {
+                            let missing: Vec<_> = self.missing_info_pieces().collect();
+                            for i in missing {
+                                peer.send_message(meta_request(utm_id, i));
                             }
                         }
                     }
@@ -1364,8 +2260,6 @@ impl<T: cio::CIO> Torrent<T> {
                 }
             }
         } else if id == UT_PEX_ID {
-            const PEX_SEED: u8 = 0x02;
-            const PEX_OUTGOING: u8 = 0x10;
             if peer.exts().ut_pex.is_none() {
                 return Ok(());
             }
@@ -1409,6 +2303,7 @@ impl<T: cio::CIO> Torrent<T> {
     /// Periodically called to update peers, choking the slowest one and
     /// optimistically unchoking a new peer
     pub fn update_unchoked(&mut self) {
+        self.prune_excess_peers();
         if self.complete() {
             self.choker.update_download(&mut self.peers)
         } else {
@@ -1416,6 +2311,38 @@ impl<T: cio::CIO> Torrent<T> {
         };
     }
 
+    /// Disconnects the least useful peers of a torrent above its peer limit. Peers
+    /// transferring the least with us go first, and of those the ones we're choking.
+    fn prune_excess_peers(&mut self) {
+        let excess =
+            (self.peers.len() - self.evicting.len()).saturating_sub(usize::from(self.peer_limit()));
+        if excess == 0 {
+            return;
+        }
+        let complete = self.complete();
+        let mut ranked: Vec<_> = self
+            .peers
+            .values()
+            .filter(|p| !self.evicting.contains(&p.id()))
+            .map(|p| {
+                let (ul, dl) = p.get_tx_rates();
+                let rate = if complete { ul } else { dl };
+                (rate, !p.choking(), std::cmp::Reverse(p.idle_time()), p.id())
+            })
+            .collect();
+        ranked.sort_unstable();
+        debug!(
+            "{}: Disconnecting {} peers above the limit of {}",
+            self.rpc_id(),
+            excess,
+            self.peer_limit()
+        );
+        for (.., pid) in ranked.into_iter().take(excess) {
+            self.evicting.insert(pid);
+            self.cio.remove_peer(pid);
+        }
+    }
+
     pub fn rpc_update(&mut self, u: rpc::proto::resource::CResourceUpdate) {
         if u.throttle_up.is_some() || u.throttle_down.is_some() {
             let tu = u.throttle_up.unwrap_or_else(|| self.throttle.ul_rate());
@@ -1439,6 +2366,35 @@ impl<T: cio::CIO> Torrent<T> {
             None => {}
         }
 
+        if let Some(ip) = u.announce_ip {
+            self.set_announce_ip(ip);
+        }
+
+        if let Some(max_peers) = u.max_peers {
+            self.set_max_peers(max_peers);
+        }
+
+        if let Some(verify) = u.verify_on_read {
+            self.set_verify_on_read(verify);
+        }
+
+        if let Some(allocation) = u.allocation {
+            self.set_allocation(allocation);
+        }
+
+        if let Some(ids) = u.file_order {
+            let order = ids
+                .iter()
+                .filter_map(|id| {
+                    self.info
+                        .files
+                        .iter()
+                        .position(|f| util::file_rpc_id(&self.info.hash, &f.path) == *id)
+                })
+                .collect();
+            self.set_file_order(order);
+        }
+
         if let Some(user_data) = u.user_data {
             let id = self.rpc_id();
             self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
@@ -1451,11 +2407,58 @@ impl<T: cio::CIO> Torrent<T> {
         }
     }
 
+    /// Applies the file priorities and strategy the torrent was added with. This has to
+    /// happen before it has any peers, so no pieces are requested under the defaults.
+    pub fn apply_preset(&mut self, preset: Preset) {
+        match preset.strategy {
+            Some(resource::Strategy::Rarest) => self.change_picker(false),
+            Some(resource::Strategy::Sequential) => self.change_picker(true),
+            None => {}
+        }
+        if self.info_idx.is_some() {
+            self.preset_priorities = preset.file_priorities;
+            self.dirty = true;
+            return;
+        }
+        if preset.file_priorities.is_empty() {
+            return;
+        }
+        let mut updates = Vec::new();
+        for (file, priority) in preset.file_priorities {
+            let Some(f) = self.info.files.get(file) else {
+                debug!(
+                    "{:?}: no file {} to set the priority of",
+                    self.rpc_id(),
+                    file
+                );
+                continue;
+            };
+            Arc::make_mut(&mut self.priorities)[file] = priority;
+            updates.push(resource::SResourceUpdate::FilePriority {
+                id: util::file_rpc_id(&self.info.hash, &f.path),
+                kind: resource::ResourceKind::File,
+                priority,
+            });
+        }
+        self.picker.set_priorities(&self.priorities, &self.info);
+        self.check_complete();
+        self.dirty = true;
+        self.cio.msg_rpc(rpc::CtlMessage::Update(updates));
+    }
+
     pub fn rpc_update_file(&mut self, id: String, priority: u8) {
         for (i, f) in self.info.files.iter().enumerate() {
             let fid = util::file_rpc_id(&self.info.hash, f.path.as_path());
             if fid == id {
-                Arc::make_mut(&mut self.priorities)[i] = priority;
+                let prev = mem::replace(&mut Arc::make_mut(&mut self.priorities)[i], priority);
+                if prev == 0 && priority != 0 && self.allocation() == Allocation::SkipUnwanted {
+                    self.cio.msg_disk(disk::Request::materialize(
+                        self.id,
+                        self.info.clone(),
+                        self.path.clone(),
+                        i,
+                    ));
+                }
             }
         }
 
@@ -1475,23 +2478,69 @@ impl<T: cio::CIO> Torrent<T> {
         ]));
     }
 
+    /// Sends the pieces completed since the last update, or the whole piece field if
+    /// some have been lost since, e.g. by failing validation.
     pub fn rpc_update_pieces(&mut self) {
         let id = self.rpc_id();
-        let piece_field = self.pieces.b64();
-        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+        let lost = self.rpc_pieces.len() != self.pieces.len()
+            || self.rpc_pieces.and(&self.pieces).set() != self.rpc_pieces.set();
+        let update = if lost {
             resource::SResourceUpdate::TorrentPieces {
                 id,
                 kind: resource::ResourceKind::Torrent,
-                piece_field,
-            },
-        ]));
+                piece_field: self.pieces.b64(),
+            }
+        } else {
+            let pieces_set: Vec<_> = self.pieces.andnot(&self.rpc_pieces).iter().collect();
+            if pieces_set.is_empty() {
+                return;
+            }
+            resource::SResourceUpdate::TorrentPiecesDelta {
+                id,
+                kind: resource::ResourceKind::Torrent,
+                pieces_set,
+            }
+        };
+        self.rpc_pieces = self.pieces.clone();
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![update]));
+    }
+
+    /// Sends the block progress of pieces which changed since the last call.
+    pub fn rpc_update_block_progress(&mut self) {
+        let progress = self.picker.take_progress();
+        if progress.is_empty() {
+            return;
+        }
+        let id = self.rpc_id();
+        let updates = progress
+            .into_iter()
+            .map(|(piece, blocks_done, blocks_total)| {
+                resource::SResourceUpdate::TorrentBlockProgress {
+                    id: id.clone(),
+                    kind: resource::ResourceKind::Torrent,
+                    piece,
+                    blocks_done,
+                    blocks_total,
+                }
+            })
+            .collect();
+        self.cio.msg_rpc(rpc::CtlMessage::Update(updates));
     }
 
     fn start(&mut self, serialize: bool) {
         debug!("Starting torrent");
-        // Update RPC of the torrent, tracker, files, and peers
+        self.rpc_extant();
+        if serialize {
+            self.serialize_info();
+            self.serialize_session();
+        }
+    }
+
+    /// Update RPC of the torrent, tracker, and files
+    fn rpc_extant(&mut self) {
         let mut resources = Vec::new();
         resources.push(self.rpc_info());
+        self.rpc_pieces = self.pieces.clone();
         resources.extend(self.rpc_trk_info());
         if self.info_idx.is_none() {
             resources.extend(self.rpc_rel_info());
@@ -1500,20 +2549,59 @@ impl<T: cio::CIO> Torrent<T> {
         if self.info_idx.is_none() {
             self.update_rpc_transfer();
         }
-        if serialize {
-            self.serialize_info();
-            self.serialize_session();
+    }
+
+    /// Sends all resources associated with the torrent, including its peers.
+    /// Used when the RPC worker has lost its state.
+    pub fn send_rpc_info(&mut self) {
+        self.rpc_extant();
+        for peer in self.peers.values_mut() {
+            peer.send_rpc_info();
         }
     }
 
+    /// Recovers from the disk worker being restarted. Any pieces pending
+    /// validation are redownloaded, and an interrupted full validation is
+    /// started again.
+    pub fn disk_restarted(&mut self) {
+        // Outstanding jobs were lost along with the old worker
+        self.disk_pending = 0;
+        if self.status.disk_stalled {
+            self.status.disk_stalled = false;
+            self.announce_status();
+        }
+        for piece in mem::take(&mut self.validating) {
+            self.discard_piece(piece);
+            self.picker.invalidate_piece(piece);
+            self.pieces.unset_bit(u64::from(piece));
+        }
+        if self.status.validating.is_some() {
+            self.validate();
+        }
+        if self.status.moving.is_some() {
+            self.finish_move();
+        }
+    }
+
+    /// Moves a downloaded piece which has to be fetched again from `downloaded` to `wasted`.
+    /// Its blocks were counted as they arrived.
+    fn discard_piece(&mut self, piece: u32) {
+        let len = u64::from(self.info.piece_len(piece));
+        self.downloaded = self.downloaded.saturating_sub(len);
+        self.ses_downloaded = self.ses_downloaded.saturating_sub(len);
+        self.wasted += len;
+        self.dirty = true;
+    }
+
     fn announce_start(&mut self) {
         if self.status.stopped() {
             return;
         }
         if let Some(req) = tracker::Request::started(self) {
-            self.cio.msg_trk(req);
+            self.send_announce(req);
         }
         self.dht_announce();
+        self.lsd_announce();
     }
 
     fn dht_announce(&mut self) {
@@ -1531,10 +2619,29 @@ impl<T: cio::CIO> Torrent<T> {
         }
     }
 
+    /// Announces the torrent to the local network. Private torrents stick to their
+    /// trackers.
+    pub fn lsd_announce(&mut self) {
+        if self.status.stopped() || self.info.private || !self.config.net.lsd {
+            return;
+        }
+        self.cio
+            .msg_trk(tracker::Request::LSDAnnounce(tracker::GetPeers {
+                id: self.id,
+                hash: self.info.hash,
+            }));
+    }
+
     pub fn complete(&self) -> bool {
         self.status.completed()
     }
 
+    /// Whether every wanted piece is downloaded, but some files aren't wanted and so the
+    /// torrent isn't whole.
+    pub fn partial_seed(&self) -> bool {
+        self.complete() && !self.pieces.complete()
+    }
+
     fn set_throttle(&mut self, ul: Option<i64>, dl: Option<i64>) {
         self.throttle.set_ul_rate(ul);
         self.throttle.set_dl_rate(dl);
@@ -1549,45 +2656,103 @@ impl<T: cio::CIO> Torrent<T> {
         ]));
     }
 
+    /// The info-dictionary made up of the received metadata pieces, if it matches our hash.
+    fn assembled_info(&self) -> Option<Info> {
+        let mut b = BTreeMap::new();
+        let bni = bencode::decode_buf(&self.info_bytes).ok()?;
+        b.insert(
+            b"announce".to_vec(),
+            bencode::BEncode::String(
+                self.info
+                    .announce
+                    .as_ref()
+                    .map(|u| u.as_str())
+                    .unwrap_or("")
+                    .as_bytes()
+                    .to_vec(),
+            ),
+        );
+        b.insert(b"info".to_vec(), bni);
+        let mut ni = Info::from_bencode(bencode::BEncode::Dict(b)).ok()?;
+        // They're already in `info_bytes`
+        ni.info_bytes = Vec::new();
+        (ni.hash == self.info.hash).then_some(ni)
+    }
+
+    /// Metadata pieces still to be received, once the metadata size is known.
+    fn missing_info_pieces(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.info_have.len())
+            .filter(|&i| !self.info_have.has_bit(i))
+            .map(|i| i as usize)
+    }
+
     fn magnet_complete(&mut self) {
         self.status.state = StatusState::Incomplete;
+        if let Err(e) = disk::check_info(self.dir().as_ref(), &self.info, native::fs_info) {
+            self.status.error = Some(e.to_string());
+        }
         self.announce_status();
         self.pieces = Bitfield::new(u64::from(self.info.pieces()));
-        self.priorities = Arc::new(vec![3; self.info.files.len()]);
+        let mut priorities = vec![3; self.info.files.len()];
+        for (file, priority) in mem::take(&mut self.preset_priorities) {
+            if let Some(p) = priorities.get_mut(file) {
+                *p = priority;
+            }
+        }
+        self.priorities = Arc::new(priorities);
         for peer in self.peers.values_mut() {
             if peer.magnet_complete(&self.info).is_err() {
                 self.cio.remove_peer(peer.id());
             }
         }
+        self.availability
+            .reset(self.peers.values().map(|p| p.pieces()));
 
         let resources = self.rpc_rel_info();
         self.cio.msg_rpc(rpc::CtlMessage::Extant(resources));
         let update = self.rpc_info();
+        self.rpc_pieces = self.pieces.clone();
         self.cio
             .msg_rpc(rpc::CtlMessage::Update(vec![SResourceUpdate::Resource(
                 Cow::Owned(update),
             )]));
+        self.serialize_info();
         self.serialize_session();
 
-        let seq = self.picker.is_sequential();
-        self.picker = Picker::new(&self.info, &self.pieces, &self.priorities);
-        self.change_picker(seq);
+        self.reset_picker();
         self.files = Files::new(&self.info, &self.pieces);
         self.validate();
     }
 
+    /// Directory the torrent's data is stored in.
+    fn dir(&self) -> &str {
+        self.path.as_ref().unwrap_or(&self.config.disk.directory)
+    }
+
     fn set_path(&mut self, path: String) {
-        let from = if let Some(ref p) = self.path {
-            p.clone()
-        } else {
-            self.config.disk.directory.clone()
-        };
-        self.cio.msg_disk(disk::Request::Move {
-            tid: self.id,
+        if self.status.moving.is_some() {
+            error!(
+                "{:?}: can't move to {}, already moving",
+                self.rpc_id(),
+                path
+            );
+            return;
+        }
+        if let Err(e) = disk::check_info(path.as_ref(), &self.info, native::fs_info) {
+            error!("{:?}: can't move to {}: {}", self.rpc_id(), path, e);
+            self.status.error = Some(e.to_string());
+            self.announce_status();
+            return;
+        }
+        let from = self.dir().to_owned();
+        self.status.moving = Some(0.0);
+        self.cio.msg_disk(disk::Request::move_torrent(
+            self.id,
+            self.info.clone(),
             from,
-            to: path,
-            target: self.info.name.clone(),
-        });
+            path,
+            self.info.root(),
+        ));
     }
 
     fn set_path_skip_files(&mut self, path: String) {
@@ -1596,12 +2761,13 @@ impl<T: cio::CIO> Torrent<T> {
         } else {
             self.config.disk.directory.clone()
         });
-        old_path.push(&self.info.name);
+        old_path.push(self.info.root());
         self.cio.msg_disk(disk::Request::PurgeCache {
             tid: self.id,
             prefix: old_path,
         });
         self.path = Some(path.clone());
+        self.dirty = true;
         self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
             resource::SResourceUpdate::TorrentPath {
                 id: self.rpc_id(),
@@ -1612,7 +2778,14 @@ impl<T: cio::CIO> Torrent<T> {
     }
 
     fn set_priority(&mut self, priority: u8) {
+        let priority = priority.min(MAX_PRIORITY);
+        let resume = self.priority == 0 && priority != 0;
         self.priority = priority;
+        self.throttle.set_priority(priority);
+        self.dirty = true;
+        if resume {
+            self.request_all();
+        }
         let id = self.rpc_id();
         self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
             resource::SResourceUpdate::TorrentPriority {
@@ -1643,6 +2816,7 @@ impl<T: cio::CIO> Torrent<T> {
         Resource::Torrent(resource::Torrent {
             id: self.rpc_id(),
             name,
+            name_raw_b64: self.info.be_name.as_deref().and_then(raw_b64),
             size,
             // TODO: Properly add this
             path: self
@@ -1668,8 +2842,17 @@ impl<T: cio::CIO> Torrent<T> {
             throttle_down: self.throttle.dl_rate(),
             transferred_up: self.uploaded,
             transferred_down: self.downloaded,
+            ses_transferred_up: self.ses_uploaded,
+            ses_transferred_down: self.ses_downloaded,
+            endgame: self.picker.endgame(),
+            partial_seed: self.partial_seed(),
             peers: 0,
+            max_peers: self.peer_limit(),
+            verify_on_read: self.verify_on_read(),
+            allocation: self.allocation(),
             trackers: self.trackers.len() as u8,
+            announce_ip: self.announce_ip.map(|ip| ip.to_string()),
+            file_order: self.file_order_ids(),
             pieces,
             piece_size,
             piece_field: self.pieces.b64(),
@@ -1689,11 +2872,12 @@ impl<T: cio::CIO> Torrent<T> {
         }
 
         for p in self.pieces.iter() {
-            for loc in Info::piece_disk_locs(&self.info, p as u32) {
-                files[loc.file].0 += loc.end - loc.start;
+            for (file, len) in self.info.piece_files(p as u32) {
+                files[file].0 += len;
             }
         }
 
+        let availability = Files::availability(&self.info, &self.availability);
         for (i, (done, total)) in files.into_iter().enumerate() {
             let id = util::file_rpc_id(&self.info.hash, &self.info.files[i].path);
             let progress = if self.priorities[i] != 0 {
@@ -1704,10 +2888,11 @@ impl<T: cio::CIO> Torrent<T> {
             r.push(resource::Resource::File(resource::File {
                 id,
                 torrent_id: self.rpc_id(),
-                availability: 0.,
+                availability: availability[i],
                 progress,
                 priority: self.priorities[i],
                 path: self.info.files[i].path.to_string_lossy().into_owned(),
+                path_raw_b64: raw_b64(self.info.files[i].path.as_os_str().as_bytes()),
                 size: total,
                 ..Default::default()
             }))
@@ -1725,18 +2910,30 @@ impl<T: cio::CIO> Torrent<T> {
                     return None;
                 }
                 seen_urls.insert(trk.url.as_str());
-                Some(resource::Resource::Tracker(resource::Tracker {
-                    id: util::trk_rpc_id(&self.info.hash, &trk.url),
-                    torrent_id: self.rpc_id(),
-                    url: trk.url.as_ref().clone(),
-                    last_report: trk.last_announce,
-                    error: None,
-                    ..Default::default()
-                }))
+                Some(self.rpc_tracker(trk))
             })
             .collect()
     }
 
+    fn rpc_tracker(&self, trk: &Tracker) -> resource::Resource {
+        let (seeders, leechers, last_interval) = trk.swarm();
+        resource::Resource::Tracker(resource::Tracker {
+            id: trk.id.clone(),
+            torrent_id: self.rpc_id(),
+            url: trk.url.as_ref().clone(),
+            tier: trk.tier as u32,
+            last_report: trk.last_announce,
+            error: trk.error(),
+            retry_at: trk.retry_at,
+            next_announce: trk.next_announce(),
+            seeders,
+            leechers,
+            last_interval,
+            headers: trk.headers.redacted(),
+            ..Default::default()
+        })
+    }
+
     pub fn send_rpc_removal(&mut self) {
         let mut r = Vec::new();
         r.push(self.rpc_id());
@@ -1750,7 +2947,7 @@ impl<T: cio::CIO> Torrent<T> {
                 continue;
             }
             seen_urls.insert(tracker.url.as_str());
-            r.push(util::trk_rpc_id(&self.info.hash, &tracker.url));
+            r.push(tracker.id.clone());
         }
         self.cio.msg_rpc(rpc::CtlMessage::Removed(r));
     }
@@ -1775,41 +2972,114 @@ impl<T: cio::CIO> Torrent<T> {
     }
 
     fn availability(&self) -> f32 {
-        if self.leechers.len() != self.peers.len() {
-            return 1.0;
-        }
         if self.status.magnet() {
             return 0.0;
         }
-        let mut peers_have = FHashSet::default();
-        for peer in self.peers.values() {
-            for piece in peer.pieces().iter() {
-                peers_have.insert(piece);
-            }
-            if peers_have.len() as u64 == self.pieces.len() {
-                return 1.0;
-            }
-        }
-        peers_have.len() as f32 / self.pieces.len() as f32
+        self.availability.value(self.pieces.len())
     }
 
     /// Resets the last upload/download statistics, adjusting the internal
     /// status if nothing has been uploaded/downloaded in the interval.
     pub fn tick(&mut self) -> bool {
+        let now = Instant::now();
+        let (mut written, mut capacity, mut active) = (0, 0, false);
+        // Idle peers have no rates to average, so only those transferring are ticked
+        self.transferring.retain(|pid| {
+            let Some(peer) = self.peers.get_mut(pid) else {
+                return false;
+            };
+            written += peer.take_written();
+            capacity += peer.request_capacity();
+            let peer_active = peer.tick();
+            active |= peer_active;
+            // Pieces may still be on their way to peers we're uploading to
+            peer_active || !peer.choking()
+        });
+        self.add_uploaded(written);
         self.stat.tick();
-        let mut active = self.stat.active();
-        self.picker.tick();
+        active |= self.stat.active();
+        self.picker.tick(capacity);
+        while let Some(pid) = self.stalls.pop_expired(now) {
+            if let Some(at) = self.peers.get_mut(&pid).and_then(|p| p.check_stall(now)) {
+                self.stalls.schedule(pid, at);
+            }
+        }
 
-        for peer in self.peers.values_mut() {
-            active |= peer.tick();
+        if active {
+            self.last_active = Instant::now();
+            if self.idle_announces != 0 {
+                debug!("{} is no longer dormant, resuming announces", self.rpc_id());
+                self.idle_announces = 0;
+                self.update_tracker();
+            }
+        } else if self.dormant() {
+            self.prune_idle_peers(now);
         }
         active
     }
 
+    /// Disconnects the peers which have not transferred data within the idle timeout.
+    fn prune_idle_peers(&mut self, now: Instant) {
+        let timeout = Duration::from_secs(self.config.idle.timeout);
+        while let Some(pid) = self.idle_peers.pop_expired(now) {
+            let Some(peer) = self.peers.get(&pid) else {
+                continue;
+            };
+            let idle = peer.idle_time();
+            if idle >= timeout {
+                trace!("Disconnecting idle peer {:?}", peer);
+                self.cio.remove_peer(pid);
+            } else {
+                self.idle_peers.schedule(pid, now + (timeout - idle));
+            }
+        }
+    }
+
+    /// Accounts for piece data which peer writers have sent.
+    fn add_uploaded(&mut self, amnt: u64) {
+        if amnt != 0 {
+            self.uploaded += amnt;
+            self.ses_uploaded += amnt;
+            self.stat.add_ul(amnt);
+            self.dirty = true;
+        }
+    }
+
     pub fn get_last_tx_rate(&self) -> (u64, u64) {
         (self.stat.avg_ul(), self.stat.avg_dl())
     }
 
+    /// Writes a downloaded block, validating its piece if it was the last one missing.
+    /// Nothing is written while the data is being moved.
+    fn store_block(&mut self, index: u32, begin: u32, data: Buffer, piece_done: bool) {
+        if self.status.moving.is_some() {
+            self.held_writes.push((index, begin, data, piece_done));
+            return;
+        }
+        self.write_piece(index, begin, data);
+        if piece_done {
+            self.cio.msg_disk(disk::Request::validate_piece(
+                self.id,
+                self.info.clone(),
+                self.path.clone(),
+                index,
+            ));
+            self.validating.insert(index);
+        }
+    }
+
+    /// Writes out and reads the blocks held back during a move, once it has finished or failed.
+    fn finish_move(&mut self) {
+        self.status.moving = None;
+        for (index, begin, data, piece_done) in mem::take(&mut self.held_writes) {
+            self.store_block(index, begin, data, piece_done);
+        }
+        for (pid, index, begin, data) in mem::take(&mut self.held_reads) {
+            self.request_read(pid, index, begin, data);
+        }
+        self.request_all();
+    }
+
     /// Writes a piece of torrent info, with piece index idx,
     /// piece offset begin, piece length of len, and data bytes.
     /// The disk send handle is also provided.
@@ -1817,26 +3087,81 @@ impl<T: cio::CIO> Torrent<T> {
         let locs = Info::block_disk_locs_pri(&self.info, &self.priorities, index, begin);
         // pid and len are ignored for write contexts
         let ctx = disk::Ctx::new(0, self.id, index, begin, 0);
-        self.cio
-            .msg_disk(disk::Request::write(ctx, data, locs, self.path.clone()));
+        self.disk_issued();
+        let allocation = self.allocation();
+        self.cio.msg_disk(disk::Request::write(
+            ctx,
+            data,
+            locs,
+            self.path.clone(),
+            allocation,
+        ));
     }
 
-    /// Issues a read request of the given torrent
+    /// Issues a read request of the given torrent, held back while the data is being moved.
     fn request_read(&mut self, id: usize, index: u32, begin: u32, data: Buffer) {
+        if self.status.moving.is_some() {
+            self.held_reads.push((id, index, begin, data));
+            return;
+        }
         let locs = Info::block_disk_locs(&self.info, index, begin);
         let len = self.info.block_len(index, begin);
         let ctx = disk::Ctx::new(id, self.id, index, begin, len);
-        self.cio
-            .msg_disk(disk::Request::read(ctx, data, locs, self.path.clone()));
+        self.disk_issued();
+        let read_ahead = self.sequential();
+        let verify = self.verify_on_read();
+        self.cio.msg_disk(disk::Request::read(
+            ctx,
+            data,
+            locs,
+            self.path.clone(),
+            read_ahead,
+            verify,
+        ));
+    }
+
+    fn disk_issued(&mut self) {
+        if self.disk_pending == 0 {
+            self.disk_progress = Instant::now();
+        }
+        self.disk_pending += 1;
+    }
+
+    fn disk_completed(&mut self) {
+        self.disk_pending = self.disk_pending.saturating_sub(1);
+        self.disk_progress = Instant::now();
+        if self.status.disk_stalled {
+            info!("{:?}: disk I/O resumed after stall", self.rpc_id());
+            self.status.disk_stalled = false;
+            self.announce_status();
+        }
+    }
+
+    /// Marks the torrent as stalled if it has reads or writes outstanding
+    /// and the disk worker hasn't completed any of them within `timeout`.
+    /// Returns true if the torrent just became stalled.
+    pub fn check_disk(&mut self, timeout: Duration) -> bool {
+        if self.status.disk_stalled
+            || self.disk_pending == 0
+            || self.disk_progress.elapsed() < timeout
+        {
+            return false;
+        }
+        self.status.disk_stalled = true;
+        self.announce_status();
+        true
     }
 
     fn make_requests_pid(&mut self, pid: usize) {
-        if self.status.should_dl() {
+        if self.should_dl() {
             let peer = self
                 .peers
                 .get_mut(&pid)
                 .expect("Expected peer id not present");
             Torrent::make_requests(peer, &mut self.picker, &self.info);
+            if let Some(at) = peer.stall_deadline() {
+                self.stalls.schedule(pid, at);
+            }
         }
     }
 
@@ -1858,11 +3183,17 @@ impl<T: cio::CIO> Torrent<T> {
         }
     }
 
+    /// Whether peer connections may be made, which for private torrents can be held back until
+    /// a tracker has accepted an announce.
+    pub fn peers_allowed(&self) -> bool {
+        self.tracker_ok || !(self.info.private && self.config.trk.private_announce_first)
+    }
+
     pub fn add_peer(&mut self, conn: PeerConn) -> Option<usize> {
-        if self.peers.len() >= MAX_PEERS {
+        if !self.peers_allowed() {
             return None;
         }
-        if self.peers.values().any(|p| p.addr() == conn.sock().addr()) {
+        if self.peers.values().any(|p| p.addr() == conn.sock().addr()) || !self.make_room() {
             return None;
         }
         if let Ok(pid) = self.cio.add_peer(conn)
@@ -1871,6 +3202,7 @@ impl<T: cio::CIO> Torrent<T> {
             if self.info_idx.is_none() {
                 self.picker.add_peer(&p);
             }
+            self.idle_peers.schedule(pid, self.idle_deadline());
             self.peers.insert(pid, p);
             return Some(pid);
         }
@@ -1878,16 +3210,27 @@ impl<T: cio::CIO> Torrent<T> {
     }
 
     pub fn add_inc_peer(&mut self, pid: usize, id: [u8; 20], rsv: [u8; 8]) -> Option<usize> {
+        if !self.peers_allowed() {
+            debug!(
+                "{:?}: Rejecting peer before tracker announce",
+                self.rpc_id()
+            );
+            return None;
+        }
         if let Some(addr) = self.cio.get_peer(pid, |pconn| pconn.sock().addr())
             && self.peers.values().any(|p| p.addr() == addr)
         {
             return None;
         }
+        if !self.make_room() {
+            return None;
+        }
         if let Ok(p) = Peer::new(self.config.dht.port, pid, self, Some(id), Some(rsv)) {
             debug!("{:?}: Adding peer {:?}!", self.rpc_id(), pid);
             if self.info_idx.is_none() {
                 self.picker.add_peer(&p);
             }
+            self.idle_peers.schedule(pid, self.idle_deadline());
             self.peers.insert(pid, p);
             return Some(pid);
         }
@@ -1898,11 +3241,16 @@ impl<T: cio::CIO> Torrent<T> {
         let id = self.rpc_id();
         self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
             SResourceUpdate::TorrentStatus {
-                id,
+                id: id.clone(),
                 kind: resource::ResourceKind::Torrent,
                 error: self.status.error.clone(),
                 status: self.status.as_rpc(self.stat.avg_ul(), self.stat.avg_dl()),
             },
+            SResourceUpdate::TorrentPartialSeed {
+                id,
+                kind: resource::ResourceKind::Torrent,
+                partial_seed: self.partial_seed(),
+            },
         ]));
     }
 
@@ -1924,16 +3272,17 @@ impl<T: cio::CIO> Torrent<T> {
             .trackers
             .iter()
             .map(|tracker| {
-                let id = util::trk_rpc_id(&self.info.hash, &tracker.url);
-                let error = match tracker.status {
-                    TrackerStatus::Failure(ref r) => Some(r.clone()),
-                    _ => None,
-                };
+                let (seeders, leechers, last_interval) = tracker.swarm();
                 SResourceUpdate::TrackerStatus {
-                    id,
+                    id: tracker.id.clone(),
                     kind: resource::ResourceKind::Tracker,
                     last_report: tracker.last_announce,
-                    error,
+                    error: tracker.error(),
+                    retry_at: tracker.retry_at,
+                    next_announce: tracker.next_announce(),
+                    seeders,
+                    leechers,
+                    last_interval,
                 }
             })
             .collect();
@@ -1952,7 +3301,10 @@ impl<T: cio::CIO> Torrent<T> {
             rate_down,
             transferred_up: self.uploaded,
             transferred_down: self.downloaded,
+            ses_transferred_up: self.ses_uploaded,
+            ses_transferred_down: self.ses_downloaded,
             progress,
+            endgame: self.picker.endgame(),
         });
 
         for (pid, p) in &mut self.peers {
@@ -1982,14 +3334,39 @@ impl<T: cio::CIO> Torrent<T> {
                 progress,
             });
         }
+        for (idx, availability) in self
+            .files
+            .flush_availability(&self.info, &self.availability)
+        {
+            updates.push(SResourceUpdate::Availability {
+                id: util::file_rpc_id(&self.info.hash, &self.info.files[idx].path),
+                kind: resource::ResourceKind::File,
+                availability,
+            });
+        }
         self.announce_status();
         self.cio.msg_rpc(rpc::CtlMessage::Update(updates));
     }
 
     fn cleanup_peer(&mut self, peer: &mut Peer<T>) {
         trace!("Removing {:?}!", peer);
+        // Try to keep in touch with the peer via its other address family
+        if let Some(addr) = peer.ext_hint()
+            && self.known_peers.remove(&addr).is_some()
+            && !self.status.stopped()
+            && !self.peers.values().any(|p| p.addr() == addr)
+        {
+            debug!("Redialing {:?} via ext-hint {}", peer, addr);
+            self.redial.push(addr);
+        }
+        self.add_uploaded(peer.take_written());
         self.choker.remove_peer(peer, &mut self.peers);
         self.leechers.remove(&peer.id());
+        self.transferring.remove(&peer.id());
+        self.stalls.cancel(peer.id());
+        self.idle_peers.cancel(peer.id());
+        self.evicting.remove(&peer.id());
+        self.availability.remove(peer.pieces());
         if self.info.complete() {
             self.picker.remove_peer(peer);
         }
@@ -2003,6 +3380,9 @@ impl<T: cio::CIO> Torrent<T> {
                 self.cio.msg_trk(req);
             }
             self.status.paused = true;
+            for peer in self.peers.values_mut() {
+                peer.cancel_uploads();
+            }
             self.announce_status();
         }
     }
@@ -2016,13 +3396,14 @@ impl<T: cio::CIO> Torrent<T> {
             if self.status.paused {
                 debug!("Sending started request to trk");
                 if let Some(req) = tracker::Request::started(self) {
-                    self.cio.msg_trk(req);
+                    self.send_announce(req);
                 }
                 self.status.paused = false;
             }
             self.request_all();
             self.announce_status();
             self.dht_announce();
+            self.lsd_announce();
         }
     }
 
@@ -2036,52 +3417,47 @@ impl<T: cio::CIO> Torrent<T> {
         self.announce_status();
     }
 
-    pub fn num_peers(&self) -> usize {
-        self.peers.len()
+    fn add_known_peer(&mut self, addr: SocketAddr, source: PeerSource) {
+        if self.known_peers.len() < MAX_KNOWN_PEERS || self.known_peers.contains_key(&addr) {
+            self.known_peers.insert(addr, source);
+        }
     }
 
-    pub fn peers(&self) -> &UHashMap<Peer<T>> {
-        &self.peers
+    /// Returns addresses which control should connect to on behalf of the torrent.
+    pub fn take_redial(&mut self) -> Vec<SocketAddr> {
+        mem::take(&mut self.redial)
     }
 
-    pub fn update_pex(&mut self, added: &[SocketAddr], removed: &[SocketAddr]) {
-        let mut a = vec![];
-        let mut a6 = vec![];
-        let mut r = vec![];
-        let mut r6 = vec![];
-        for addr in added {
-            match &addr {
-                SocketAddr::V4(addr) => {
-                    a.extend(&addr.ip().octets());
-                }
-                SocketAddr::V6(addr) => {
-                    a6.extend(&addr.ip().octets());
-                }
-            }
-        }
-        for addr in removed {
-            match &addr {
-                SocketAddr::V4(addr) => {
-                    r.extend(&addr.ip().octets());
-                }
-                SocketAddr::V6(addr) => {
-                    r6.extend(&addr.ip().octets());
-                }
-            }
-        }
-        let mut dict = BTreeMap::new();
-        dict.insert(b"added".to_vec(), BEncode::String(a));
-        dict.insert(b"added6".to_vec(), BEncode::String(a6));
-        dict.insert(b"removed".to_vec(), BEncode::String(r));
-        dict.insert(b"removed6".to_vec(), BEncode::String(r6));
-        let payload = BEncode::Dict(dict).encode_to_buf();
+    pub fn num_peers(&self) -> usize {
+        self.peers.len()
+    }
 
+    /// Tells each peer which negotiated PEX about the peers it hasn't heard of yet and those
+    /// which have since disconnected. Peers of private torrents must only come from trackers.
+    pub fn update_pex(&mut self) {
+        if self.info.private {
+            return;
+        }
+        let connected: Vec<_> = self
+            .peers
+            .values()
+            .filter_map(|p| {
+                let seed = if p.pieces().complete() || p.upload_only() {
+                    PEX_SEED
+                } else {
+                    0
+                };
+                p.listen_addr().map(|addr| (addr, PEX_OUTGOING | seed))
+            })
+            .collect();
         for peer in self.peers.values_mut() {
-            if let Some(id) = peer.exts().ut_pex {
-                peer.send_message(Message::Extension {
-                    id,
-                    payload: payload.clone(),
-                });
+            let Some(id) = peer.exts().ut_pex else {
+                continue;
+            };
+            let (added, dropped) = peer.pex_delta(&connected);
+            if !added.is_empty() || !dropped.is_empty() {
+                let payload = pex_payload(&added, &dropped);
+                peer.send_message(Message::Extension { id, payload });
             }
         }
     }
@@ -2114,6 +3490,20 @@ impl<T: cio::CIO> Torrent<T> {
         self.peers.keys().cloned().collect()
     }
 
+    /// Replaces the picker with a fresh one over the current pieces, keeping its strategy
+    /// and file order.
+    fn reset_picker(&mut self) {
+        let seq = self.picker.is_sequential();
+        self.picker = Picker::new(
+            &self.info,
+            &self.pieces,
+            &self.priorities,
+            self.config.peer.endgame_duplicates,
+        );
+        self.picker.set_order(&self.file_order, &self.info);
+        self.change_picker(seq);
+    }
+
     pub fn change_picker(&mut self, sequential: bool) {
         debug!("Swapping pickers!");
         let prev_seq = self.picker.is_sequential();
@@ -2173,3 +3563,1495 @@ impl<T: cio::CIO> Drop for Torrent<T> {
         self.send_rpc_removal();
     }
 }
+
+/// A ut_metadata request for a piece of the info-dictionary (BEP 9).
+fn meta_request(id: u8, piece: usize) -> Message {
+    let mut d = BTreeMap::new();
+    d.insert(b"msg_type".to_vec(), bencode::BEncode::Int(0));
+    d.insert(b"piece".to_vec(), bencode::BEncode::Int(piece as i64));
+    Message::Extension {
+        id,
+        payload: bencode::BEncode::Dict(d).encode_to_buf(),
+    }
+}
+
+/// Encodes a ut_pex message, with compact addresses split by family (BEP 11).
+fn pex_payload(added: &[(SocketAddr, u8)], dropped: &[SocketAddr]) -> Vec<u8> {
+    let mut dict = BTreeMap::new();
+    for (v4, suffix) in [(true, ""), (false, "6")] {
+        let (mut a, mut f, mut d) = (vec![], vec![], vec![]);
+        for (addr, flags) in added.iter().filter(|(addr, _)| addr.is_ipv4() == v4) {
+            a.extend(util::addr_to_bytes(addr));
+            f.push(*flags);
+        }
+        for addr in dropped.iter().filter(|addr| addr.is_ipv4() == v4) {
+            d.extend(util::addr_to_bytes(addr));
+        }
+        dict.insert(format!("added{suffix}").into_bytes(), BEncode::String(a));
+        dict.insert(format!("added{suffix}.f").into_bytes(), BEncode::String(f));
+        dict.insert(format!("dropped{suffix}").into_bytes(), BEncode::String(d));
+    }
+    BEncode::Dict(dict).encode_to_buf()
+}
+
+/// Base64 of a name which isn't valid UTF-8, so clients can recover what a lossy
+/// conversion of it lost.
+fn raw_b64(name: &[u8]) -> Option<String> {
+    match std::str::from_utf8(name) {
+        Ok(_) => None,
+        Err(_) => Some(BASE64_STANDARD.encode(name)),
+    }
+}
+
+/// Delay before retrying a tracker after its `failures`th consecutive failure.
+fn retry_delay(failures: u32, cap: u64) -> Duration {
+    let backoff = ANNOUNCE_RETRY_BASE.saturating_mul(1 << failures.saturating_sub(1).min(16));
+    Duration::from_secs(backoff.min(cap))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::io;
+    use std::sync::Arc;
+    use std::sync::atomic::Ordering;
+    use std::time::{Duration, Instant};
+
+    use url::Url;
+
+    use base64::prelude::{BASE64_STANDARD, Engine};
+
+    use super::{
+        Availability, Bitfield, Block, Files, Info, Message, MissingFiles, PEER_REPLACE_IDLE, Peer,
+        PeerConn, Preset, Saved, Session, StatusState, Torrent, cio, info,
+    };
+    use crate::THROT_TOKS;
+    use crate::bencode::{self, BEncode};
+    use crate::buffers::Buffer;
+    use crate::config::{Allocation, Config};
+    use crate::control::cio::CIO;
+    use crate::control::cio::test::TCIO;
+    use crate::disk;
+    use crate::rpc::CtlMessage;
+    use crate::rpc::resource::{CResourceUpdate, Resource, SResourceUpdate, Strategy};
+    use crate::throttle::Throttler;
+    use crate::tracker;
+    use crate::{UT_META_ID, util};
+
+    const BLOCK: u64 = 16_384;
+
+    fn torrent() -> Torrent<TCIO> {
+        torrent_with(config(), TCIO::new())
+    }
+
+    fn config() -> Config {
+        let mut config = Config::default();
+        config.disk.validate = false;
+        config
+    }
+
+    fn torrent_with(config: Config, cio: TCIO) -> Torrent<TCIO> {
+        torrent_from(Info::with_pieces(2), config, cio)
+    }
+
+    fn torrent_from(mut info: Info, config: Config, cio: TCIO) -> Torrent<TCIO> {
+        let pieces = info.pieces() as usize;
+        info.piece_idx = Info::generate_piece_idx(pieces, u64::from(info.piece_len), &info.files);
+        info.announce = Some(Arc::new(
+            Url::parse("http://tracker.example.org/announce").unwrap(),
+        ));
+        let poll = amy::Poller::new().unwrap();
+        let throttler = Throttler::new(None, None, THROT_TOKS, &poll.get_registrar()).unwrap();
+        Torrent::new(
+            Arc::new(config),
+            0,
+            None,
+            info,
+            throttler.get_throttle(0),
+            cio,
+            true,
+            false,
+        )
+    }
+
+    /// Loads a torrent back from the session and info it saves, as after a restart. The
+    /// restored torrent gets a new TCIO.
+    fn reload(t: &mut Torrent<TCIO>) -> Torrent<TCIO> {
+        let cio = t.cio.new_handle();
+        cio.data().disk_msgs.clear();
+        t.serialize_info();
+        t.serialize_session();
+        let (mut session, mut info) = (None, None);
+        for req in cio.data().disk_msgs.drain(..) {
+            match req {
+                disk::Request::Serialize {
+                    data,
+                    extension: None,
+                    ..
+                } => session = Some(data),
+                disk::Request::Serialize {
+                    data,
+                    extension: Some(".info"),
+                    ..
+                } => info = Some(data),
+                _ => {}
+            }
+        }
+        let poll = amy::Poller::new().unwrap();
+        let throttler = Throttler::new(None, None, THROT_TOKS, &poll.get_registrar()).unwrap();
+        Torrent::deserialize(
+            t.config.clone(),
+            0,
+            &session.unwrap(),
+            info.as_deref(),
+            throttler.get_throttle(0),
+            TCIO::new(),
+        )
+        .unwrap()
+    }
+
+    fn piece(index: u32) -> Message {
+        Message::Piece {
+            index,
+            begin: 0,
+            length: BLOCK as u32,
+            data: Buffer::get().unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_duplicate_blocks() {
+        let mut t = torrent();
+        let mut pieces = Bitfield::new(2);
+        pieces.set_bit(0);
+        pieces.set_bit(1);
+        let mut peer = Peer::test_from_pieces(0, pieces);
+        t.picker.add_peer(&peer);
+
+        // Unrequested blocks are dropped
+        t.handle_msg(piece(0), &mut peer).unwrap();
+        assert_eq!((t.downloaded, t.wasted), (0, BLOCK));
+
+        let mut picked: Vec<_> = (0..2).filter_map(|_| t.picker.pick(&mut peer)).collect();
+        picked.sort_by_key(|b| b.index);
+        assert_eq!(picked, [Block::new(0, 0), Block::new(1, 0)]);
+        t.handle_msg(piece(0), &mut peer).unwrap();
+        assert_eq!((t.downloaded, t.wasted), (BLOCK, BLOCK));
+
+        // The piece is being validated, so a second copy from endgame is wasted
+        t.handle_msg(piece(0), &mut peer).unwrap();
+        assert_eq!((t.downloaded, t.wasted), (BLOCK, 2 * BLOCK));
+
+        // As is one which is already held
+        t.handle_disk_resp(disk::Response::PieceValidated {
+            tid: 0,
+            piece: 0,
+            valid: true,
+        });
+        t.handle_msg(piece(0), &mut peer).unwrap();
+        assert_eq!((t.downloaded, t.wasted), (BLOCK, 3 * BLOCK));
+    }
+
+    #[test]
+    fn test_rejected_blocks() {
+        let mut t = torrent();
+        let mut pieces = Bitfield::new(2);
+        pieces.set_bit(0);
+        let mut peer = Peer::test_from_pieces(0, pieces);
+        t.picker.add_peer(&peer);
+
+        // Blocks of the wrong length disconnect the peer
+        let bad = Message::Piece {
+            index: 0,
+            begin: 0,
+            length: 10,
+            data: Buffer::get().unwrap(),
+        };
+        assert!(t.handle_msg(bad, &mut peer).is_err());
+        assert_eq!((t.downloaded, t.wasted), (0, 10));
+
+        assert_eq!(t.picker.pick(&mut peer), Some(Block::new(0, 0)));
+        t.handle_msg(piece(0), &mut peer).unwrap();
+        assert_eq!((t.downloaded, t.wasted), (BLOCK, 10));
+
+        // A piece which fails its hash check has to be downloaded again
+        t.handle_disk_resp(disk::Response::PieceValidated {
+            tid: 0,
+            piece: 0,
+            valid: false,
+        });
+        assert_eq!((t.downloaded, t.wasted), (0, BLOCK + 10));
+        assert!(!t.pieces.has_bit(0));
+
+        assert_eq!(t.picker.pick(&mut peer), Some(Block::new(0, 0)));
+        t.handle_msg(piece(0), &mut peer).unwrap();
+        assert_eq!((t.downloaded, t.wasted), (BLOCK, BLOCK + 10));
+    }
+
+    fn validated(t: &mut Torrent<TCIO>, piece: u32) {
+        t.handle_disk_resp(disk::Response::PieceValidated {
+            tid: 0,
+            piece,
+            valid: true,
+        });
+    }
+
+    #[test]
+    fn test_shutdown_saves_pieces() {
+        let cio = TCIO::new();
+        let mut t = torrent_with(config(), cio.new_handle());
+        cio.data().disk_msgs.clear();
+        validated(&mut t, 1);
+        assert!(t.dirty);
+        // What control does for each torrent on shutting down
+        t.serialize_session_if_dirty();
+        let saved = cio.data().disk_msgs.iter().any(|req| {
+            matches!(
+                req,
+                disk::Request::Serialize {
+                    extension: None,
+                    ..
+                }
+            )
+        });
+        assert!(saved, "the session wasn't saved");
+
+        let restored = reload(&mut t);
+        assert!(restored.pieces.has_bit(1));
+        assert!(!restored.pieces.has_bit(0));
+    }
+
+    fn completed_announces(cio: &TCIO) -> usize {
+        let msgs = &mut cio.data().trk_msgs;
+        let n = msgs
+            .iter()
+            .filter(|r| format!("{r:?}").contains("Completed"))
+            .count();
+        msgs.clear();
+        n
+    }
+
+    #[test]
+    fn test_verify_complete() {
+        let mut config = config();
+        config.disk.verify_complete = true;
+        let cio = TCIO::new();
+        let mut t = torrent_with(config, cio.new_handle());
+        cio.data().disk_msgs.clear();
+
+        validated(&mut t, 0);
+        validated(&mut t, 1);
+        assert_eq!(t.status.state, StatusState::Incomplete);
+        assert_eq!(completed_announces(&cio), 0);
+        let checks = |cio: &TCIO| {
+            cio.data()
+                .disk_msgs
+                .drain(..)
+                .filter(|r| matches!(r, disk::Request::Validate { .. }))
+                .count()
+        };
+        assert_eq!(checks(&cio), 1);
+
+        // Corrupt data is downloaded again rather than seeded
+        t.handle_disk_resp(disk::Response::ValidationComplete {
+            tid: 0,
+            invalid: vec![1],
+        });
+        assert_eq!(t.status.state, StatusState::Incomplete);
+        assert!(t.pieces.has_bit(0) && !t.pieces.has_bit(1));
+        assert_eq!(completed_announces(&cio), 0);
+
+        validated(&mut t, 1);
+        assert_eq!(checks(&cio), 1);
+        t.handle_disk_resp(disk::Response::ValidationComplete {
+            tid: 0,
+            invalid: vec![],
+        });
+        assert_eq!(t.status.state, StatusState::Complete);
+        assert_eq!(completed_announces(&cio), 1);
+    }
+
+    #[test]
+    fn test_verify_on_read() {
+        let mut config = config();
+        config.disk.verify_on_read = true;
+        let cio = TCIO::new();
+        let mut t = torrent_with(config, cio.new_handle());
+        validated(&mut t, 0);
+        validated(&mut t, 1);
+        assert_eq!(t.status.state, StatusState::Complete);
+        let pid = t.add_peer(PeerConn::test_at("10.0.0.1:6881")).unwrap();
+        t.peers.get_mut(&pid).unwrap().unchoke();
+        let request = |index| Message::Request {
+            index,
+            begin: 0,
+            length: BLOCK as u32,
+        };
+        let read = |cio: &TCIO| match cio.data().disk_msgs.pop() {
+            Some(disk::Request::Read {
+                context, verify, ..
+            }) => (context, verify),
+            _ => panic!("expected a read"),
+        };
+
+        t.peer_ev(pid, Ok(request(1))).unwrap();
+        let (context, verify) = read(&cio);
+        assert!(verify);
+        cio.data().peer_msgs.clear();
+        t.handle_disk_resp(disk::Response::Corrupt { context });
+        assert!(
+            !cio.data()
+                .peer_msgs
+                .iter()
+                .any(|(_, m)| matches!(m, Message::Piece { .. }))
+        );
+        // The piece is no longer offered, and is downloaded again
+        assert!(t.pieces.has_bit(0) && !t.pieces.has_bit(1));
+        assert_eq!(t.status.state, StatusState::Incomplete);
+        let mut seeder = Peer::test_from_pieces(1, Bitfield::from(&[0xFF], 2));
+        t.picker.add_peer(&seeder);
+        assert_eq!(t.picker.pick(&mut seeder), Some(Block::new(1, 0)));
+
+        // Torrents can opt out, which is persisted
+        t.rpc_update(CResourceUpdate {
+            id: t.rpc_id(),
+            verify_on_read: Some(Some(false)),
+            ..Default::default()
+        });
+        t.peer_ev(pid, Ok(request(0))).unwrap();
+        assert!(!read(&cio).1);
+        let session: Session = bincode::deserialize(&t.serialized_session_data()).unwrap();
+        assert_eq!(session.verify_on_read, Some(false));
+        t.rpc_update(CResourceUpdate {
+            id: t.rpc_id(),
+            verify_on_read: Some(None),
+            ..Default::default()
+        });
+        match t.rpc_info() {
+            Resource::Torrent(r) => assert!(r.verify_on_read),
+            r => panic!("unexpected resource {r:?}"),
+        }
+    }
+
+    #[test]
+    fn test_allocation_override() {
+        let cio = TCIO::new();
+        let mut t = torrent_with(config(), cio.new_handle());
+        let written = |t: &mut Torrent<TCIO>| {
+            t.write_piece(0, 0, Buffer::get().unwrap());
+            match cio.data().disk_msgs.pop() {
+                Some(disk::Request::Write { allocation, .. }) => allocation,
+                _ => panic!("expected a write"),
+            }
+        };
+        assert_eq!(written(&mut t), Allocation::Sparse);
+
+        t.rpc_update(CResourceUpdate {
+            id: t.rpc_id(),
+            allocation: Some(Some(Allocation::SkipUnwanted)),
+            ..Default::default()
+        });
+        assert_eq!(written(&mut t), Allocation::SkipUnwanted);
+        let session: Session = bincode::deserialize(&t.serialized_session_data()).unwrap();
+        assert_eq!(
+            session.allocation,
+            Some(crate::session::torrent::current::Allocation::SkipUnwanted)
+        );
+
+        // Going back to the configured policy moves skipped files into place
+        let cio = TCIO::new();
+        let mut t = torrent_with(config(), cio.new_handle());
+        t.set_allocation(Some(Allocation::SkipUnwanted));
+        Arc::make_mut(&mut t.priorities)[0] = 0;
+        cio.data().disk_msgs.clear();
+        t.rpc_update(CResourceUpdate {
+            id: t.rpc_id(),
+            allocation: Some(None),
+            ..Default::default()
+        });
+        assert!(matches!(
+            cio.data().disk_msgs[..],
+            [disk::Request::Materialize { file: 0, .. }]
+        ));
+        match t.rpc_info() {
+            Resource::Torrent(r) => assert_eq!(r.allocation, Allocation::Sparse),
+            r => panic!("unexpected resource {r:?}"),
+        }
+    }
+
+    #[test]
+    fn test_complete_without_verify() {
+        let cio = TCIO::new();
+        let mut t = torrent_with(config(), cio.new_handle());
+        validated(&mut t, 0);
+        validated(&mut t, 1);
+        assert_eq!(t.status.state, StatusState::Complete);
+        assert_eq!(completed_announces(&cio), 1);
+        assert!(
+            !cio.data()
+                .disk_msgs
+                .iter()
+                .any(|r| matches!(r, disk::Request::Validate { .. }))
+        );
+    }
+
+    #[test]
+    fn test_pex_payload() {
+        let addr = |a: &str| a.parse().unwrap();
+        let added = [
+            (addr("10.0.0.1:6881"), 0x10),
+            (addr("[2001:db8::1]:6881"), 0x10),
+            (addr("10.0.0.2:6882"), 0x12),
+        ];
+        let payload = super::pex_payload(&added, &[addr("10.0.0.3:6883")]);
+        assert_eq!(
+            &payload[..],
+            &b"d5:added12:\x0a\x00\x00\x01\x1a\xe1\x0a\x00\x00\x02\x1a\xe27:added.f2:\x10\x12\
+               6:added618:\x20\x01\x0d\xb8\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x1a\xe1\
+               8:added6.f1:\x10\
+               7:dropped6:\x0a\x00\x00\x03\x1a\xe38:dropped60:e"[..]
+        );
+    }
+
+    #[test]
+    fn test_pex_update() {
+        let pex = |cio: &TCIO, pid| -> Vec<_> {
+            let d = cio.data();
+            d.peer_msgs
+                .iter()
+                .filter(|(p, _)| *p == pid)
+                .map(|(_, m)| match m {
+                    Message::Extension { id: 3, payload } => payload.clone(),
+                    m => panic!("unexpected message {m:?}"),
+                })
+                .collect()
+        };
+        let cio = TCIO::new();
+        let mut t = torrent_with(config(), cio.new_handle());
+        let peers = [
+            Peer::test_pex(cio.new_handle(), "10.0.0.1:6881", true, Some(3)),
+            // Incoming, so there's no address to pass on
+            Peer::test_pex(cio.new_handle(), "10.0.0.2:50000", false, Some(3)),
+            // Without ut_pex, so it isn't sent anything
+            Peer::test_pex(cio.new_handle(), "10.0.0.3:6883", true, None),
+        ];
+        let pids: Vec<_> = peers.iter().map(Peer::id).collect();
+        for peer in peers {
+            t.peers.insert(peer.id(), peer);
+        }
+        cio.data().peer_msgs.clear();
+
+        t.update_pex();
+        assert_eq!(
+            pex(&cio, pids[0]),
+            [
+                b"d5:added6:\x0a\x00\x00\x03\x1a\xe37:added.f1:\x106:added60:8:added6.f0:\
+                7:dropped0:8:dropped60:e"
+                    .to_vec()
+            ]
+        );
+        assert_eq!(pex(&cio, pids[1]).len(), 1);
+        assert!(pex(&cio, pids[2]).is_empty());
+
+        // Only changes are sent from then on
+        cio.data().peer_msgs.clear();
+        t.update_pex();
+        assert!(cio.data().peer_msgs.is_empty());
+        t.peers.remove(&pids[2]);
+        t.update_pex();
+        assert_eq!(
+            pex(&cio, pids[0]),
+            [b"d5:added0:7:added.f0:6:added60:8:added6.f0:\
+                7:dropped6:\x0a\x00\x00\x03\x1a\xe38:dropped60:e"
+                .to_vec()]
+        );
+
+        // Peers of private torrents only come from their trackers
+        cio.data().peer_msgs.clear();
+        Arc::make_mut(&mut t.info).private = true;
+        let peer = Peer::test_pex(cio.new_handle(), "10.0.0.4:6884", true, None);
+        t.peers.insert(peer.id(), peer);
+        t.update_pex();
+        assert!(cio.data().peer_msgs.is_empty());
+    }
+
+    #[test]
+    fn test_missing_files() {
+        let seeding = |on_missing| {
+            let mut config = config();
+            config.disk.on_missing = on_missing;
+            let cio = TCIO::new();
+            let mut t = torrent_with(config, cio.new_handle());
+            validated(&mut t, 0);
+            validated(&mut t, 1);
+            assert_eq!(t.status.state, StatusState::Complete);
+            cio.data().disk_msgs.clear();
+            for _ in 0..2 {
+                t.handle_disk_resp(disk::Response::Missing {
+                    tid: 0,
+                    err: io::ErrorKind::NotFound.into(),
+                });
+            }
+            (t, cio)
+        };
+
+        let (t, _) = seeding(MissingFiles::Error);
+        assert!(t.status.error.is_some());
+        assert!(!t.status.paused);
+
+        let (t, _) = seeding(MissingFiles::Pause);
+        assert!(t.status.error.is_none());
+        assert!(t.status.paused);
+
+        // Only one recheck is started however many reads fail
+        let (mut t, cio) = seeding(MissingFiles::Recheck);
+        assert!(t.status.error.is_none() && !t.status.paused);
+        let checks = cio
+            .data()
+            .disk_msgs
+            .iter()
+            .filter(|r| matches!(r, disk::Request::Validate { .. }))
+            .count();
+        assert_eq!(checks, 1);
+        t.handle_disk_resp(disk::Response::ValidationComplete {
+            tid: 0,
+            invalid: vec![1],
+        });
+        assert_eq!(t.status.state, StatusState::Incomplete);
+        assert!(t.pieces.has_bit(0) && !t.pieces.has_bit(1));
+    }
+
+    #[test]
+    fn test_move_holds_reads() {
+        let dir = tempfile::tempdir().unwrap();
+        let cio = TCIO::new();
+        let mut t = torrent_with(config(), cio.new_handle());
+        validated(&mut t, 0);
+        let pid = t.add_peer(PeerConn::test_at("10.0.0.1:6881")).unwrap();
+        t.peers.get_mut(&pid).unwrap().unchoke();
+        t.set_path(dir.path().to_string_lossy().into_owned());
+        assert!(t.status.moving.is_some());
+        let reads = |cio: &TCIO| {
+            cio.data()
+                .disk_msgs
+                .iter()
+                .filter(|r| matches!(r, disk::Request::Read { .. }))
+                .count()
+        };
+        let request = Message::Request {
+            index: 0,
+            begin: 0,
+            length: BLOCK as u32,
+        };
+        t.peer_ev(pid, Ok(request)).unwrap();
+        assert_eq!(reads(&cio), 0);
+
+        // Other jobs failing doesn't end the move
+        let err = |job| disk::Response::error(0, job, io::ErrorKind::Other.into());
+        t.handle_disk_resp(err(disk::JobKind::Write));
+        assert!(t.status.moving.is_some());
+        assert_eq!(reads(&cio), 0);
+
+        // The move failing does, and the held read goes ahead
+        t.handle_disk_resp(err(disk::JobKind::Move));
+        assert!(t.status.moving.is_none());
+        assert_eq!(reads(&cio), 1);
+    }
+
+    #[test]
+    fn test_disk_stall() {
+        let mut t = torrent();
+        let minute = Duration::from_secs(60);
+        // Nothing outstanding is never a stall
+        assert!(!t.check_disk(Duration::ZERO));
+
+        t.write_piece(0, 0, Buffer::get().unwrap());
+        t.request_read(0, 1, 0, Buffer::get().unwrap());
+        assert_eq!(t.disk_pending, 2);
+        assert!(!t.check_disk(minute));
+        assert!(t.check_disk(Duration::ZERO));
+        assert!(t.status.disk_stalled);
+        // A stall is only reported once
+        assert!(!t.check_disk(Duration::ZERO));
+
+        // Failures of jobs which weren't counted don't count as progress
+        let err = |job| disk::Response::error(0, job, io::ErrorKind::Other.into());
+        t.handle_disk_resp(err(disk::JobKind::Other));
+        assert_eq!(t.disk_pending, 2);
+        assert!(t.status.disk_stalled);
+
+        let context = disk::Ctx::new(0, 0, 0, 0, 0);
+        t.handle_disk_resp(disk::Response::write(context));
+        assert_eq!(t.disk_pending, 1);
+        assert!(!t.status.disk_stalled);
+        assert!(!t.check_disk(minute));
+
+        t.handle_disk_resp(err(disk::JobKind::Read));
+        assert_eq!(t.disk_pending, 0);
+        assert!(!t.check_disk(Duration::ZERO));
+    }
+
+    #[test]
+    fn test_endgame_cancel() {
+        let cio = TCIO::new();
+        let mut t = torrent_from(Info::with_pieces(1), config(), cio.new_handle());
+        let mut peers: Vec<_> = (0..3)
+            .map(|_| {
+                let mut peer = Peer::test_with_tcio(cio.new_handle());
+                peer.pieces_mut().set_bit(0);
+                t.picker.add_peer(&peer);
+                peer
+            })
+            .collect();
+        let block = t.picker.pick(&mut peers[0]).unwrap();
+        assert_eq!(t.picker.pick(&mut peers[1]), None);
+        t.picker.tick(2);
+        assert!(t.picker.endgame());
+        assert_eq!(t.picker.pick(&mut peers[1]), Some(block));
+        assert_eq!(t.picker.pick(&mut peers[2]), Some(block));
+
+        // Everyone but the peer which delivered the block is told not to bother
+        let mut winner = peers.remove(1);
+        for peer in peers {
+            t.peers.insert(peer.id(), peer);
+        }
+        t.handle_msg(piece(block.index), &mut winner).unwrap();
+        let mut cancelled: Vec<_> = cio
+            .data()
+            .peer_msgs
+            .iter()
+            .filter(|(_, m)| matches!(m, Message::Cancel { .. }))
+            .map(|(pid, _)| *pid)
+            .collect();
+        cancelled.sort_unstable();
+        assert_eq!(cancelled, [0, 2]);
+    }
+
+    /// The ids and raw paths of a torrent's files as reported over RPC.
+    fn rpc_files(t: &Torrent<TCIO>) -> Vec<(String, String, Option<String>)> {
+        t.rpc_rel_info()
+            .into_iter()
+            .filter_map(|r| match r {
+                Resource::File(f) => Some((f.id, f.path, f.path_raw_b64)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_non_utf8_names() {
+        let cio = TCIO::new();
+        let mut t = torrent_from(Info::with_sjis_names(), config(), cio.new_handle());
+        let Resource::Torrent(r) = t.rpc_info() else {
+            panic!("expected torrent");
+        };
+        assert_eq!(r.name.as_deref(), Some(t.info.name.as_str()));
+        let raw = BASE64_STANDARD
+            .decode(r.name_raw_b64.as_ref().unwrap())
+            .unwrap();
+        assert_eq!(raw, info::SJIS_NAME);
+        assert!(
+            Resource::Torrent(r)
+                .to_string()
+                .contains("  name: \u{FFFD}e")
+        );
+
+        let files = rpc_files(&t);
+        let raw = BASE64_STANDARD
+            .decode(files[0].2.as_ref().unwrap())
+            .unwrap();
+        assert_eq!(raw, [info::SJIS_NAME, b"/", info::SJIS_FILE].concat());
+        assert!(files[0].1.ends_with("\u{FFFD}\u{FFFD}.txt"));
+        // The directory alone is enough to need the raw path
+        let raw = BASE64_STANDARD
+            .decode(files[1].2.as_ref().unwrap())
+            .unwrap();
+        assert_eq!(raw, [info::SJIS_NAME, b"/readme.txt"].concat());
+        assert_ne!(files[0].0, files[1].0);
+
+        // Ids and paths come back the same after a restart
+        let restored = reload(&mut t);
+        assert_eq!(rpc_files(&restored), files);
+        assert_eq!(restored.info.root(), t.info.root());
+    }
+
+    #[test]
+    fn test_renamed_files_metadata() {
+        let file = |name: &[u8]| {
+            let mut f = BTreeMap::new();
+            f.insert(b"length".to_vec(), BEncode::Int(8_192));
+            f.insert(
+                b"path".to_vec(),
+                BEncode::List(vec![BEncode::String(name.to_vec())]),
+            );
+            BEncode::Dict(f)
+        };
+        let mut info = BTreeMap::new();
+        info.insert(b"name".to_vec(), BEncode::String(b"aux".to_vec()));
+        info.insert(b"piece length".to_vec(), BEncode::Int(16_384));
+        info.insert(b"pieces".to_vec(), BEncode::String(vec![0; 20]));
+        let files = vec![file(b"a"), file(b"a")];
+        info.insert(b"files".to_vec(), BEncode::List(files));
+        let mut torrent = BTreeMap::new();
+        torrent.insert(b"info".to_vec(), BEncode::Dict(info));
+        let info = Info::from_bencode(BEncode::Dict(torrent)).unwrap();
+        assert_eq!(info.files[1].path.to_str(), Some("aux_/a.1"));
+        let original = info.info_bytes.clone();
+        assert!(!info.matches(&info.to_bencode().encode_to_buf()));
+
+        // Peers fetching the metadata get the names from the torrent, not those on disk
+        let cio = TCIO::new();
+        let mut t = torrent_from(info, config(), cio.new_handle());
+        assert_eq!(t.info_bytes, original);
+        assert!(t.info.info_bytes.is_empty());
+
+        // Also after a restart
+        cio.data().disk_msgs.clear();
+        t.serialize_info();
+        t.serialize_session();
+        let (mut session, mut info, mut metadata) = (None, None, None);
+        for req in cio.data().disk_msgs.drain(..) {
+            if let disk::Request::Serialize {
+                data, extension, ..
+            } = req
+            {
+                match extension {
+                    None => session = Some(data),
+                    Some(".info") => info = Some(data),
+                    Some(".metadata") => metadata = Some(data),
+                    Some(ext) => panic!("unexpected {ext}"),
+                }
+            }
+        }
+        assert_eq!(metadata.as_ref(), Some(&original));
+        let (session, info) = (session.unwrap(), info.unwrap());
+        let poll = amy::Poller::new().unwrap();
+        let throttler = Throttler::new(None, None, THROT_TOKS, &poll.get_registrar()).unwrap();
+        let restore = |metadata| {
+            let saved = Saved::load(&session, Some(&info)).unwrap();
+            Torrent::restore(
+                Arc::new(config()),
+                0,
+                saved.with_metadata(metadata),
+                throttler.get_throttle(0),
+                TCIO::new(),
+            )
+        };
+        let restored = restore(metadata.unwrap());
+        assert_eq!(restored.info_bytes, original);
+        assert_eq!(restored.info.files[1].path.to_str(), Some("aux_/a.1"));
+
+        // Metadata which isn't the torrent's is ignored
+        let restored = restore(b"d4:name3:auxe".to_vec());
+        let rebuilt = restored.info.to_bencode().encode_to_buf();
+        assert_eq!(restored.info_bytes, rebuilt);
+    }
+
+    #[test]
+    fn test_disconnect_before_write() {
+        let cio = TCIO::new();
+        let mut t = torrent_with(config(), cio.new_handle());
+        validated(&mut t, 0);
+        let pid = t.add_peer(PeerConn::test_at("10.0.0.1:6881")).unwrap();
+        let written = |amnt| {
+            cio.new_handle()
+                .get_peer(pid, |c| {
+                    c.upload_counter().fetch_add(amnt, Ordering::Relaxed)
+                })
+                .unwrap();
+        };
+        let piece = || disk::Response::Read {
+            context: disk::Ctx::new(pid, 0, 0, 0, BLOCK as u32),
+            data: Buffer::get().unwrap(),
+        };
+        t.peers.get_mut(&pid).unwrap().unchoke();
+
+        // The test socket is never connected, so queued pieces aren't counted
+        t.handle_disk_resp(piece());
+        t.tick();
+        assert_eq!(t.uploaded, 0);
+        assert_eq!(t.peers.get_mut(&pid).unwrap().flush().0, 0);
+
+        // Only what the connection writes out is
+        written(1000);
+        t.tick();
+        assert_eq!(t.uploaded, 1000);
+        assert_eq!(t.peers.get_mut(&pid).unwrap().flush().0, 1000);
+
+        // Including what was written just before the peer went away
+        t.handle_disk_resp(piece());
+        written(500);
+        cio.remove_peer(pid);
+        assert!(t.peer_ev(pid, Err(cio::Error::Full)).is_err());
+        assert_eq!((t.uploaded, t.ses_uploaded), (1500, 1500));
+    }
+
+    #[test]
+    fn test_prune_idle_peers() {
+        let cio = TCIO::new();
+        let mut c = config();
+        c.idle.timeout = 60;
+        let mut t = torrent_with(c, cio.new_handle());
+        let idle = t.add_peer(PeerConn::test_at("10.0.0.1:6881")).unwrap();
+        let busy = t.add_peer(PeerConn::test_at("10.0.0.2:6881")).unwrap();
+        t.peers
+            .get_mut(&idle)
+            .unwrap()
+            .set_idle(Duration::from_secs(120));
+        t.peers
+            .get_mut(&busy)
+            .unwrap()
+            .set_idle(Duration::from_secs(30));
+
+        // Nothing is due before the timeout has passed
+        t.prune_idle_peers(Instant::now());
+        assert_eq!(cio.data().peers.len(), 2);
+
+        // Only the peer which went idle is dropped, the other is checked again once it could be
+        let now = Instant::now() + Duration::from_secs(60);
+        t.prune_idle_peers(now);
+        assert!(!cio.data().peers.contains_key(&idle));
+        assert!(cio.data().peers.contains_key(&busy));
+        assert!(t.idle_peers.pop_expired(now).is_none());
+        assert_eq!(
+            t.idle_peers.pop_expired(now + Duration::from_secs(30)),
+            Some(busy)
+        );
+    }
+
+    #[test]
+    fn test_session_counters() {
+        let cio = TCIO::new();
+        let mut t = torrent_from(Info::with_pieces(2), config(), cio.new_handle());
+        let mut peer = Peer::test_from_pieces(0, Bitfield::from(&[0xC0], 2));
+        t.picker.add_peer(&peer);
+        let block = t.picker.pick(&mut peer).unwrap();
+        t.handle_msg(piece(block.index), &mut peer).unwrap();
+        t.add_uploaded(100);
+        assert_eq!((t.uploaded, t.downloaded), (100, BLOCK));
+        assert_eq!((t.ses_uploaded, t.ses_downloaded), (100, BLOCK));
+
+        let t = reload(&mut t);
+        // Lifetime counters survive a restart, session ones start over
+        assert_eq!((t.uploaded, t.downloaded), (100, BLOCK));
+        assert_eq!((t.ses_uploaded, t.ses_downloaded), (0, 0));
+        match t.rpc_info() {
+            Resource::Torrent(r) => {
+                assert_eq!((r.transferred_up, r.transferred_down), (100, BLOCK));
+                assert_eq!((r.ses_transferred_up, r.ses_transferred_down), (0, 0));
+            }
+            r => panic!("unexpected resource {r:?}"),
+        }
+    }
+
+    #[test]
+    fn test_tracker_ids() {
+        let cio = TCIO::new();
+        let mut t = torrent_with(config(), cio.new_handle());
+        let id = t.trackers.iter().next().unwrap().id.clone();
+        let url = Url::parse("http://tracker.example.org/announce?passkey=new").unwrap();
+
+        // Editing the url keeps the id
+        cio.data().rpc_msgs.clear();
+        t.update_tracker_url(&id, url.clone());
+        match &cio.data().rpc_msgs[..] {
+            [CtlMessage::Update(u)] => match &u[..] {
+                [SResourceUpdate::TrackerUrl { id: i, url: u, .. }] => {
+                    assert_eq!((i, u), (&id, &url))
+                }
+                u => panic!("unexpected update {u:?}"),
+            },
+            m => panic!("unexpected messages {m:?}"),
+        }
+        assert_eq!(t.trackers.find_id(&id).map(|trk| &*trk.url), Some(&url));
+
+        let poll = amy::Poller::new().unwrap();
+        let throttler = Throttler::new(None, None, THROT_TOKS, &poll.get_registrar()).unwrap();
+        let restart = |t: &mut Torrent<TCIO>, legacy: bool| {
+            cio.data().disk_msgs.clear();
+            t.serialize_info();
+            let info = cio.data().disk_msgs.drain(..).find_map(|req| match req {
+                disk::Request::Serialize { data, .. } => Some(data),
+                _ => None,
+            });
+            let mut session: Session = bincode::deserialize(&t.serialized_session_data()).unwrap();
+            if legacy {
+                session.tracker_ids.clear();
+            }
+            Torrent::deserialize(
+                Arc::new(config()),
+                0,
+                &bincode::serialize(&session).unwrap(),
+                info.as_deref(),
+                throttler.get_throttle(0),
+                cio.new_handle(),
+            )
+            .unwrap()
+        };
+
+        // The id survives a restart
+        let restored = restart(&mut t, false);
+        assert_eq!(restored.trackers.ids(), [(url.to_string(), id)]);
+
+        // Sessions saved without ids derive them from the url, as ids used to be, and keep
+        // them from then on
+        let derived = util::trk_rpc_id(&t.info.hash, &url);
+        let mut migrated = restart(&mut t, true);
+        assert_eq!(
+            migrated.trackers.ids(),
+            [(url.to_string(), derived.clone())]
+        );
+        migrated.update_tracker_url(&derived, Url::parse("http://example.com/a").unwrap());
+        let restored = restart(&mut migrated, false);
+        assert_eq!(
+            restored.trackers.find_id(&derived).unwrap().url.as_str(),
+            "http://example.com/a"
+        );
+    }
+
+    #[test]
+    fn test_file_availability() {
+        // b is smaller than a piece and lies entirely within piece 1
+        let info = Arc::new(Info::with_files(16_384, &[20_000, 1_000, 20_000]));
+        let field = |set: &[u64]| {
+            let mut b = Bitfield::new(3);
+            for &i in set {
+                b.set_bit(i);
+            }
+            b
+        };
+        let mut avail = Availability::new();
+        avail.reset(&[field(&[0, 1]), field(&[0])]);
+        let mut files = Files::new(&info, &Bitfield::new(3));
+
+        let a = (2. * 16_384. + 3_616.) / 20_000.;
+        let c = 11_768. / 20_000.;
+        assert_eq!(
+            files.flush_availability(&info, &avail),
+            [(0, a), (1, 1.), (2, c)]
+        );
+        assert!(files.flush_availability(&info, &avail).is_empty());
+
+        // Only the files overlapping the piece change
+        avail.have(2);
+        assert_eq!(
+            files.flush_availability(&info, &avail),
+            [(2, c + 8_232. / 20_000.)]
+        );
+
+        // Progress counts the bytes of each file in the completed pieces
+        files.update(&info, 1);
+        let mut done = files.flush();
+        done.sort_unstable();
+        assert_eq!(done, [(0, 3_616), (1, 1_000), (2, 11_768)]);
+    }
+
+    #[test]
+    fn test_pieces_delta() {
+        let cio = TCIO::new();
+        let mut t = torrent_from(Info::with_pieces(10), config(), cio.new_handle());
+        let sent = |t: &mut Torrent<TCIO>| {
+            cio.data().rpc_msgs.clear();
+            t.rpc_update_pieces();
+            match &cio.data().rpc_msgs[..] {
+                [] => None,
+                [CtlMessage::Update(u)] => Some(u[0].clone()),
+                m => panic!("unexpected messages {m:?}"),
+            }
+        };
+        t.pieces.set_bit(2);
+        t.pieces.set_bit(7);
+        match sent(&mut t) {
+            Some(SResourceUpdate::TorrentPiecesDelta { pieces_set, .. }) => {
+                assert_eq!(pieces_set, [2, 7])
+            }
+            u => panic!("unexpected update {u:?}"),
+        }
+        // Nothing is sent while no pieces change
+        assert!(sent(&mut t).is_none());
+
+        // Losing a piece needs the whole field, even if others completed too
+        t.pieces.unset_bit(2);
+        t.pieces.set_bit(3);
+        match sent(&mut t) {
+            Some(SResourceUpdate::TorrentPieces { piece_field, .. }) => {
+                assert_eq!(piece_field, t.pieces.b64())
+            }
+            u => panic!("unexpected update {u:?}"),
+        }
+        t.pieces.set_bit(2);
+        match sent(&mut t) {
+            Some(SResourceUpdate::TorrentPiecesDelta { pieces_set, .. }) => {
+                assert_eq!(pieces_set, [2])
+            }
+            u => panic!("unexpected update {u:?}"),
+        }
+    }
+
+    #[test]
+    fn test_peer_limit() {
+        let cio = TCIO::new();
+        let mut config = config();
+        config.peer.max_peers_per_torrent = 3;
+        let mut t = torrent_with(config, cio.new_handle());
+        let add = |t: &mut Torrent<TCIO>, i: u8| {
+            let pid = cio
+                .new_handle()
+                .add_peer(PeerConn::test_at(&format!("10.0.0.{i}:6881")))
+                .unwrap();
+            t.add_inc_peer(pid, [i; 20], [0; 8])
+        };
+        let pids: Vec<_> = (0..3).map(|i| add(&mut t, i).unwrap()).collect();
+        // Incoming and outgoing peers are both turned away at the limit
+        assert_eq!(add(&mut t, 3), None);
+        assert_eq!(t.add_peer(PeerConn::test_at("10.0.0.4:6881")), None);
+        assert_eq!(t.peers.len(), 3);
+
+        // Lowering the limit disconnects the peers we're choking first
+        t.peers.get_mut(&pids[1]).unwrap().unchoke();
+        cio.data().rpc_msgs.clear();
+        t.rpc_update(CResourceUpdate {
+            id: t.rpc_id(),
+            max_peers: Some(Some(1)),
+            ..Default::default()
+        });
+        match &cio.data().rpc_msgs[..] {
+            [CtlMessage::Update(u)] => match &u[..] {
+                [SResourceUpdate::TorrentMaxPeers { max_peers: 1, .. }] => {}
+                u => panic!("unexpected update {u:?}"),
+            },
+            m => panic!("unexpected messages {m:?}"),
+        }
+        t.update_unchoked();
+        let connected: Vec<_> = pids
+            .iter()
+            .filter(|pid| cio.data().peers.contains_key(pid))
+            .collect();
+        assert_eq!(connected, [&pids[1]]);
+
+        // The override is persisted, and clearing it restores the configured limit
+        let session: Session = bincode::deserialize(&t.serialized_session_data()).unwrap();
+        assert_eq!(session.max_peers, Some(1));
+        t.rpc_update(CResourceUpdate {
+            id: t.rpc_id(),
+            max_peers: Some(None),
+            ..Default::default()
+        });
+        match t.rpc_info() {
+            Resource::Torrent(r) => assert_eq!(r.max_peers, 3),
+            r => panic!("unexpected resource {r:?}"),
+        }
+
+        // Which follows a reloaded config
+        let mut reloaded = self::config();
+        reloaded.peer.max_peers_per_torrent = 5;
+        cio.data().rpc_msgs.clear();
+        t.set_config(Arc::new(reloaded));
+        assert_eq!(t.peer_limit(), 5);
+        match &cio.data().rpc_msgs[..] {
+            [CtlMessage::Update(u)] => match &u[..] {
+                [SResourceUpdate::TorrentMaxPeers { max_peers: 5, .. }] => {}
+                u => panic!("unexpected update {u:?}"),
+            },
+            m => panic!("unexpected messages {m:?}"),
+        }
+    }
+
+    #[test]
+    fn test_file_order() {
+        let cio = TCIO::new();
+        // Pieces 0-2 hold file 0, 2-4 file 1 and 4-5 file 2
+        let info = Info::with_files(16_384, &[40_960, 32_768, 24_576]);
+        let mut t = torrent_from(info, config(), cio.new_handle());
+        let ids: Vec<_> = t
+            .info
+            .files
+            .iter()
+            .map(|f| util::file_rpc_id(&t.info.hash, &f.path))
+            .collect();
+        let orders = |cio: &TCIO| {
+            let mut orders = Vec::new();
+            for m in &cio.data().rpc_msgs {
+                if let CtlMessage::Update(u) = m {
+                    for u in u {
+                        if let SResourceUpdate::TorrentFileOrder { file_order, .. } = u {
+                            orders.push(file_order.clone());
+                        }
+                    }
+                }
+            }
+            orders
+        };
+        t.rpc_update(CResourceUpdate {
+            id: t.rpc_id(),
+            file_order: Some(vec![ids[1].clone(), ids[0].clone(), ids[1].clone()]),
+            ..Default::default()
+        });
+        assert_eq!(t.file_order, [1, 0]);
+        let session: Session = bincode::deserialize(&t.serialized_session_data()).unwrap();
+        assert_eq!(session.file_order, [1, 0]);
+
+        // The order stays until every file in it is complete
+        for piece in [2, 3, 4] {
+            validated(&mut t, piece);
+        }
+        assert_eq!(t.file_order, [1, 0]);
+        validated(&mut t, 0);
+        validated(&mut t, 1);
+        assert!(t.file_order.is_empty());
+        let cleared: Vec<String> = Vec::new();
+        assert_eq!(
+            orders(&cio),
+            [vec![ids[1].clone(), ids[0].clone()], cleared]
+        );
+    }
+
+    #[test]
+    fn test_preset_priorities() {
+        let cio = TCIO::new();
+        // Pieces 0-1 hold file 0 and 2-3 file 1
+        let info = Info::with_files(16_384, &[32_768, 32_768]);
+        let mut t = torrent_from(info.clone(), config(), cio.new_handle());
+        t.apply_preset(Preset {
+            file_priorities: BTreeMap::from([(0, 0), (5, 1)]),
+            strategy: Some(Strategy::Sequential),
+        });
+        assert_eq!(*t.priorities, [0, 3]);
+        assert!(t.picker.is_sequential());
+
+        cio.data().disk_msgs.clear();
+        let mut peer = Peer::test_from_pieces(0, Bitfield::from(&[0xF0], 4));
+        t.picker.add_peer(&peer);
+        while let Some(block) = t.picker.pick(&mut peer) {
+            t.handle_msg(piece(block.index), &mut peer).unwrap();
+        }
+        let mut written = Vec::new();
+        for req in cio.data().disk_msgs.drain(..) {
+            if let disk::Request::Write { locations, .. } = req {
+                written.extend(locations.map(|loc| loc.file));
+            }
+        }
+        assert_eq!(written, [1, 1]);
+
+        // Magnets hold on to the priorities until the metadata arrives
+        let magnet = format!(
+            "magnet:?xt=urn:btih:{}",
+            util::hash_to_id(&[1; 20]).to_lowercase()
+        );
+        let mut t = torrent_from(Info::from_magnet(&magnet).unwrap(), config(), TCIO::new());
+        t.apply_preset(Preset {
+            file_priorities: BTreeMap::from([(1, 5)]),
+            strategy: None,
+        });
+        assert!(t.priorities.is_empty());
+        // Including across a restart
+        let mut t = reload(&mut t);
+        assert_eq!(t.preset_priorities, BTreeMap::from([(1, 5)]));
+        t.info = Arc::new(info);
+        t.info_idx = None;
+        t.magnet_complete();
+        assert_eq!(*t.priorities, [3, 5]);
+        assert!(t.preset_priorities.is_empty());
+    }
+
+    #[test]
+    fn test_partial_seed() {
+        let cio = TCIO::new();
+        // Pieces 0-1 hold file 0 and 2-3 file 1
+        let info = Info::with_files(16_384, &[32_768, 32_768]);
+        let mut t = torrent_from(info, config(), cio.new_handle());
+        t.apply_preset(Preset {
+            file_priorities: BTreeMap::from([(1, 0)]),
+            strategy: None,
+        });
+        let announces = |cio: &TCIO| -> Vec<String> {
+            let msgs = &mut cio.data().trk_msgs;
+            msgs.drain(..).map(|r| format!("{r:?}")).collect()
+        };
+        announces(&cio);
+
+        validated(&mut t, 0);
+        validated(&mut t, 1);
+        assert!(t.complete());
+        assert!(t.partial_seed());
+        let sent = announces(&cio);
+        assert!(sent.iter().any(|r| r.contains("Paused")));
+        assert!(!sent.iter().any(|r| r.contains("Completed")));
+        let interval = tracker::Request::interval(&t).unwrap();
+        assert!(format!("{interval:?}").contains("Paused"));
+        let ext = |t: &Torrent<TCIO>| match t.ext_handshake() {
+            Message::Extension { payload, .. } => payload,
+            _ => unreachable!(),
+        };
+        assert!(
+            ext(&t)
+                .windows(b"11:upload_onlyi1e".len())
+                .any(|w| w == b"11:upload_onlyi1e")
+        );
+
+        // Once the rest is wanted it's announced as usual
+        t.apply_preset(Preset {
+            file_priorities: BTreeMap::from([(1, 3)]),
+            strategy: None,
+        });
+        assert!(!t.partial_seed());
+        let interval = tracker::Request::interval(&t).unwrap();
+        assert!(!format!("{interval:?}").contains("Paused"));
+        assert!(
+            !ext(&t)
+                .windows(b"upload_only".len())
+                .any(|w| w == b"upload_only")
+        );
+    }
+
+    #[test]
+    fn test_peer_replacement() {
+        let cio = TCIO::new();
+        let mut config = config();
+        config.peer.max_peers_per_torrent = 2;
+        let mut t = torrent_with(config, cio.new_handle());
+        let add = |t: &mut Torrent<TCIO>, i: u8| {
+            let pid = cio
+                .new_handle()
+                .add_peer(PeerConn::test_at(&format!("10.0.0.{i}:6881")))
+                .unwrap();
+            t.add_inc_peer(pid, [i; 20], [0; 8])
+        };
+        let (a, b) = (add(&mut t, 0).unwrap(), add(&mut t, 1).unwrap());
+        // Peers which just connected are given a chance
+        assert_eq!(add(&mut t, 2), None);
+        assert!(!t.peers_wanted());
+
+        // Only a peer choking us which has gone quiet makes way for a new one
+        t.peer_ev(b, Ok(Message::Unchoke)).unwrap();
+        for pid in [a, b] {
+            t.peers
+                .get_mut(&pid)
+                .unwrap()
+                .set_idle(PEER_REPLACE_IDLE * 2);
+        }
+        assert!(t.peers_wanted());
+        let c = t.add_peer(PeerConn::test_at("10.0.0.3:6881")).unwrap();
+        assert!(!cio.data().peers.contains_key(&a));
+        assert!(cio.data().peers.contains_key(&b));
+        assert_eq!(add(&mut t, 4), None);
+
+        // The replaced peer is forgotten once its removal comes back
+        assert!(t.peer_ev(a, Err(cio::Error::Request)).is_err());
+        let mut pids: Vec<_> = t.peers.keys().copied().collect();
+        pids.sort_unstable();
+        assert_eq!(pids, [b, c]);
+        assert!(t.peers_full());
+        assert!(t.evicting.is_empty());
+    }
+
+    #[test]
+    fn test_bitfield_size() {
+        let cio = TCIO::new();
+        let mut t = torrent_with(config(), cio.new_handle());
+        // Bitfields are read off the wire a whole number of bytes long
+        let mut bitfield = |data: &[u8]| {
+            let pid = t.add_peer(PeerConn::test()).unwrap();
+            let pieces = Bitfield::from(data, data.len() as u64 * 8);
+            t.peer_ev(pid, Ok(Message::Bitfield(pieces))).unwrap();
+            let connected = cio.data().peers.contains_key(&pid);
+            let peer = t.peers.remove(&pid).unwrap();
+            connected.then(|| peer.pieces().iter().collect::<Vec<_>>())
+        };
+
+        assert_eq!(bitfield(&[0b0100_0000]), Some(vec![1]));
+        assert_eq!(bitfield(&[0b1100_0000]), Some(vec![0, 1]));
+        // Spare bits must be clear
+        assert_eq!(bitfield(&[0b1110_0000]), None);
+        assert_eq!(bitfield(&[0xFF]), None);
+        // And the length must match the piece count
+        assert_eq!(bitfield(&[0b1100_0000, 0]), None);
+        assert_eq!(bitfield(&[]), None);
+    }
+
+    #[test]
+    fn test_resume_partial_piece() {
+        // Two pieces of four blocks each
+        let mut info = Info::with_pieces(2);
+        info.piece_len = 4 * BLOCK as u32;
+        info.total_len = 8 * BLOCK;
+        info.files[0].length = info.total_len;
+        let cio = TCIO::new();
+        let mut t = torrent_from(info, config(), cio.new_handle());
+        let mut peer = Peer::test_from_pieces(0, Bitfield::from(&[0xFF], 2));
+        t.picker.add_peer(&peer);
+
+        // Receive the first half of a piece
+        let first = t.picker.pick(&mut peer).unwrap();
+        let second = t.picker.pick(&mut peer).unwrap();
+        let piece = first.index;
+        assert_eq!(
+            (first, second),
+            (Block::new(piece, 0), Block::new(piece, 16_384))
+        );
+        for block in [first, second] {
+            let msg = Message::Piece {
+                index: piece,
+                begin: block.offset,
+                length: BLOCK as u32,
+                data: Buffer::get().unwrap(),
+            };
+            t.handle_msg(msg, &mut peer).unwrap();
+        }
+
+        let mut t = reload(&mut t);
+        let cio = t.cio.new_handle();
+
+        // The disk confirms the blocks are there before they're counted
+        let checks: Vec<_> = cio
+            .data()
+            .disk_msgs
+            .drain(..)
+            .filter_map(|r| match r {
+                disk::Request::CheckBlocks { piece, blocks, .. } => Some((piece, blocks)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(checks.len(), 1);
+        let (checked, blocks) = checks.into_iter().next().unwrap();
+        assert_eq!(
+            (checked, blocks.iter().collect::<Vec<_>>()),
+            (piece, vec![0, 1])
+        );
+        t.handle_disk_resp(disk::Response::BlocksChecked {
+            tid: 0,
+            piece,
+            blocks,
+        });
+
+        // Only the missing half of the piece is requested, ahead of the other piece
+        let mut peer = Peer::test_from_pieces(1, Bitfield::from(&[0xFF], 2));
+        t.picker.add_peer(&peer);
+        let picked: Vec<_> = std::iter::from_fn(|| t.picker.pick(&mut peer)).collect();
+        let mut resumed = picked[..2].to_vec();
+        resumed.sort();
+        assert_eq!(
+            resumed,
+            [Block::new(piece, 32_768), Block::new(piece, 49_152)]
+        );
+        assert!(picked[2..].iter().all(|b| b.index != piece));
+        assert_eq!(picked.len(), 6);
+    }
+
+    #[test]
+    fn test_resume_magnet() {
+        // Metadata of four 16KiB pieces, the last one short
+        let mut d = BTreeMap::new();
+        d.insert(b"name".to_vec(), BEncode::String(b"resumed".to_vec()));
+        d.insert(b"piece length".to_vec(), BEncode::Int(BLOCK as i64));
+        d.insert(b"length".to_vec(), BEncode::Int(2500 * BLOCK as i64));
+        d.insert(b"pieces".to_vec(), BEncode::String(vec![7; 2500 * 20]));
+        let metadata = BEncode::Dict(d).encode_to_buf();
+        assert_eq!(metadata.len().div_ceil(BLOCK as usize), 4);
+        let hash = util::sha1_hash(&metadata);
+        let magnet = format!(
+            "magnet:?xt=urn:btih:{}&dn=resumed&tr=http://tracker.example.org/announce",
+            util::hash_to_id(&hash).to_lowercase()
+        );
+
+        // Connects a peer with the metadata, returning it and the pieces it was asked for
+        let connect = |t: &mut Torrent<TCIO>, cio: &TCIO, i: u8| {
+            let peer = Peer::test_pex(cio.new_handle(), &format!("10.0.0.{i}:6881"), true, None);
+            let pid = peer.id();
+            t.peers.insert(pid, peer);
+            let payload = format!(
+                "d1:md11:ut_metadatai3ee13:metadata_sizei{}ee",
+                metadata.len()
+            );
+            let msg = Message::Extension {
+                id: 0,
+                payload: payload.into_bytes(),
+            };
+            t.peer_ev(pid, Ok(msg)).unwrap();
+            pid
+        };
+        let requested = |cio: &TCIO| -> Vec<i64> {
+            let msgs = cio.data().peer_msgs.drain(..).collect::<Vec<_>>();
+            msgs.into_iter()
+                .filter_map(|(_, m)| match m {
+                    Message::Extension { id: 3, payload } => {
+                        let mut d = bencode::decode_buf(&payload).unwrap().into_dict().unwrap();
+                        d.remove(b"piece".as_ref()).and_then(BEncode::into_int)
+                    }
+                    _ => None,
+                })
+                .collect()
+        };
+        let send = |t: &mut Torrent<TCIO>, pid, piece: usize| {
+            let mut payload = format!(
+                "d8:msg_typei1e5:piecei{piece}e10:total_sizei{}ee",
+                metadata.len()
+            )
+            .into_bytes();
+            let start = piece * BLOCK as usize;
+            payload.extend_from_slice(&metadata[start..metadata.len().min(start + BLOCK as usize)]);
+            let msg = Message::Extension {
+                id: UT_META_ID,
+                payload,
+            };
+            t.peer_ev(pid, Ok(msg)).unwrap();
+        };
+
+        let cio = TCIO::new();
+        let mut t = torrent_from(
+            Info::from_magnet(&magnet).unwrap(),
+            config(),
+            cio.new_handle(),
+        );
+        let pid = connect(&mut t, &cio, 1);
+        assert_eq!(requested(&cio), [0]);
+        send(&mut t, pid, 0);
+        assert_eq!(requested(&cio), [1, 2, 3]);
+        send(&mut t, pid, 2);
+        assert!(requested(&cio).is_empty());
+
+        // What control does for each torrent on shutting down
+        t.serialize_session_if_dirty();
+        let saved = cio.data().disk_msgs.iter().any(|req| {
+            matches!(
+                req,
+                disk::Request::Serialize {
+                    extension: None,
+                    ..
+                }
+            )
+        });
+        assert!(saved, "the session wasn't saved");
+
+        let mut t = reload(&mut t);
+        let cio = t.cio.new_handle();
+        assert_eq!(t.status.state, StatusState::Magnet);
+        assert_eq!(t.info.name, "resumed");
+        assert_eq!(t.trackers.urls(), [["http://tracker.example.org/announce"]]);
+
+        // Only the missing pieces are asked for, first as a probe and then all together
+        let pid = connect(&mut t, &cio, 2);
+        assert_eq!(requested(&cio), [1]);
+        send(&mut t, pid, 1);
+        assert_eq!(requested(&cio), [3]);
+        cio.data().disk_msgs.clear();
+        send(&mut t, pid, 3);
+        assert!(t.info.complete());
+        assert_eq!(t.info.hash, hash);
+        assert_eq!(t.info.pieces(), 2500);
+        assert_ne!(t.status.state, StatusState::Magnet);
+        // The fetched info is saved for the next start, along with the metadata itself
+        let saved = |ext: &str| {
+            cio.data().disk_msgs.iter().find_map(|r| match r {
+                disk::Request::Serialize {
+                    data,
+                    extension: Some(e),
+                    ..
+                } if *e == ext => Some(data.clone()),
+                _ => None,
+            })
+        };
+        assert!(saved(".info").is_some());
+        assert!(t.info.matches(&saved(".metadata").unwrap()));
+    }
+}
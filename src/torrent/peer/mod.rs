@@ -1,8 +1,10 @@
 pub mod reader;
 pub mod writer;
 
-use std::net::SocketAddr;
 use std::net::TcpStream;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::{cmp, fmt, io, mem, time};
 
 use ip_network_table::IpNetworkTable;
@@ -19,8 +21,8 @@ use crate::stat;
 use crate::throttle::Throttle;
 use crate::torrent::{Bitfield, Info, Torrent};
 use crate::tracker;
-use crate::util;
-use crate::{DHT_EXT, PEER_ID};
+use crate::util::{self, FHashSet};
+use crate::{DHT_EXT, EXT_PROTO, PEER_ID};
 
 #[derive(Debug, Error)]
 pub enum Error {
@@ -43,8 +45,16 @@ pub enum Error {
 type Result<T> = std::result::Result<T, Error>;
 
 const INIT_MAX_QUEUE: u16 = 5;
-const MAX_QUEUE_CAP: u16 = 600;
-const IP_FILTER_BLOCK: u8 = 0;
+/// Bounds on the requests which may be outstanding to a peer
+const MIN_QUEUE: u16 = 4;
+const MAX_QUEUE: u16 = 512;
+/// Size of the blocks requested from peers
+const BLOCK_SIZE: f64 = 16_384.;
+/// Time without a block arriving for outstanding requests after which the peer's queue is
+/// halved
+const STALL_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+/// Most peers added or dropped in a single PEX message
+const PEX_MAX: usize = 50;
 
 pub mod message {
     use crate::buffers;
@@ -70,16 +80,43 @@ pub struct Peer<T: cio::CIO> {
     /// Maximum number of requests that can be queued
     /// at a time.
     max_queue: u16,
+    /// Moving average of the time the peer takes to answer a request sent while none were
+    /// outstanding, so it isn't inflated by the requests queued ahead of it
+    latency: Option<time::Duration>,
+    /// When the request being timed was sent
+    latency_probe: Option<time::Instant>,
+    /// Last time a requested block arrived, or requests were sent with none outstanding
+    last_block: time::Instant,
     pieces_updated: bool,
     tid: usize,
     downloaded: u32,
+    /// Piece bytes written since the choker last compared peers
     uploaded: u32,
+    /// Piece bytes actually written to the peer, shared with its connection
+    written: Arc<AtomicU64>,
     stat: stat::EMA,
+    /// Last time data was transferred to or from the peer
+    last_active: time::Instant,
     addr: SocketAddr,
     t_hash: [u8; 20],
     cid: Option<[u8; 20]>,
+    /// Reserved bytes from the peer's handshake, advertising the extensions it supports
     rsv: Option<[u8; 8]>,
     ext_ids: ExtIDs,
+    /// Alternate address the peer advertised in its extension handshake
+    ext_hint: Option<SocketAddr>,
+    /// Whether we connected to the peer, so it's known to accept connections at `addr`
+    outgoing: bool,
+    /// Port the peer listens on, from its extension handshake
+    listen_port: Option<u16>,
+    /// Whether the peer said it's only uploading, as a partial seed does (BEP 21)
+    upload_only: bool,
+    /// Addresses the peer has been told about over PEX and not since told were dropped
+    pex_sent: FHashSet<SocketAddr>,
+    /// Whether the peer's view of our IP has been reported
+    voted: bool,
+    /// Metadata piece first asked of the peer, whose arrival shows it has the metadata
+    meta_probe: Option<usize>,
     pub rank: usize,
 }
 
@@ -115,14 +152,12 @@ impl PeerConn {
 
     #[cfg(test)]
     pub fn test() -> PeerConn {
-        let writer = Writer::new();
-        let reader = Reader::new();
-        PeerConn {
-            last_action: time::Instant::now(),
-            sock: Socket::empty(),
-            writer,
-            reader,
-        }
+        PeerConn::new(Socket::empty())
+    }
+
+    #[cfg(test)]
+    pub fn test_at(addr: &str) -> PeerConn {
+        PeerConn::new(Socket::empty_at(addr.parse().unwrap()))
     }
 
     pub fn sock(&self) -> &Socket {
@@ -139,8 +174,13 @@ impl PeerConn {
 
     /// Creates a new "outgoing" peer, which acts as a client.
     /// Once created, set_torrent should be called.
-    pub fn new_outgoing(ip_filter: &IpNetworkTable<u8>, ip: &SocketAddr) -> io::Result<PeerConn> {
-        if let Some((_, &IP_FILTER_BLOCK)) = ip_filter.longest_match(ip.ip()) {
+    pub fn new_outgoing(
+        ip_filter: &IpNetworkTable<u8>,
+        threshold: u8,
+        ip: &SocketAddr,
+        nodelay: bool,
+    ) -> io::Result<PeerConn> {
+        if util::ip_filter::blocks(ip_filter, threshold, ip.ip()) {
             let msg = format!(
                 "Outgoing connection to peer {} blocked by ip_filter",
                 ip.ip()
@@ -148,19 +188,28 @@ impl PeerConn {
             debug!("{msg}");
             return Err(io::Error::new(io::ErrorKind::PermissionDenied, msg));
         }
-        Ok(PeerConn::new(Socket::new(ip)?))
+        let sock = Socket::new(ip)?;
+        sock.set_nodelay(nodelay)?;
+        Ok(PeerConn::new(sock))
     }
 
     /// Creates a peer where we are acting as the server.
     /// Once the handshake is received, set_torrent should be called.
-    pub fn new_incoming(ip_filter: &IpNetworkTable<u8>, sock: TcpStream) -> io::Result<PeerConn> {
+    pub fn new_incoming(
+        ip_filter: &IpNetworkTable<u8>,
+        threshold: u8,
+        sock: TcpStream,
+        nodelay: bool,
+    ) -> io::Result<PeerConn> {
         let peer_ip = sock.peer_addr()?.ip();
-        if let Some((_, &IP_FILTER_BLOCK)) = ip_filter.longest_match(peer_ip) {
+        if util::ip_filter::blocks(ip_filter, threshold, peer_ip) {
             let msg = format!("Incoming connection from peer {peer_ip} blocked by ip_filter");
             debug!("{msg}");
             return Err(io::Error::new(io::ErrorKind::PermissionDenied, msg));
         }
-        Ok(PeerConn::new(Socket::from_stream(sock)?))
+        let sock = Socket::from_stream(sock)?;
+        sock.set_nodelay(nodelay)?;
+        Ok(PeerConn::new(sock))
     }
 
     pub fn writable(&mut self) -> io::Result<()> {
@@ -180,6 +229,11 @@ impl PeerConn {
     pub fn set_throttle(&mut self, throt: Throttle) {
         self.sock.throttle = Some(throt);
     }
+
+    /// Returns a handle to the count of piece bytes written to the socket.
+    pub fn upload_counter(&self) -> Arc<AtomicU64> {
+        self.writer.upload_counter()
+    }
 }
 
 impl Status {
@@ -208,19 +262,32 @@ impl Peer<cio::test::TCIO> {
             local_status: Status::new(),
             uploaded,
             downloaded,
+            written: Arc::default(),
             stat: stat::EMA::new(),
+            last_active: time::Instant::now(),
             addr: "127.0.0.1:0".parse().unwrap(),
             cio: cio::test::TCIO::new(),
             queued,
             max_queue: queued,
+            latency: None,
+            latency_probe: None,
+            last_block: time::Instant::now(),
             pieces,
             piece_cache: Vec::new(),
             piece_count,
             tid: 0,
             t_hash: [0u8; 20],
-            rsv: None,
+            // Test peers support every extension we do
+            rsv: Some([0, 0, 0, 0, 0, EXT_PROTO.1, 0, DHT_EXT.1]),
             cid: None,
             ext_ids: ExtIDs::new(),
+            ext_hint: None,
+            outgoing: true,
+            listen_port: None,
+            upload_only: false,
+            pex_sent: FHashSet::default(),
+            voted: false,
+            meta_probe: None,
             pieces_updated: false,
             rank: 0,
         }
@@ -238,11 +305,27 @@ impl Peer<cio::test::TCIO> {
         use crate::control::cio::CIO;
 
         let conn = PeerConn::test();
+        let written = conn.upload_counter();
         let id = cio.add_peer(conn).unwrap();
         let mut peer = Peer::test(id, 0, 0, 0, Bitfield::new(4));
+        peer.written = written;
         peer.cio = cio;
         peer
     }
+
+    /// A peer at `addr` which negotiated PEX, if `ut_pex` is set.
+    pub fn test_pex(
+        cio: cio::test::TCIO,
+        addr: &str,
+        outgoing: bool,
+        ut_pex: Option<u8>,
+    ) -> Peer<cio::test::TCIO> {
+        let mut peer = Peer::test_with_tcio(cio);
+        peer.addr = addr.parse().unwrap();
+        peer.outgoing = outgoing;
+        peer.ext_ids.ut_pex = ut_pex;
+        peer
+    }
 }
 
 impl<T: cio::CIO> Peer<T> {
@@ -254,7 +337,7 @@ impl<T: cio::CIO> Peer<T> {
         rsv: Option<[u8; 8]>,
     ) -> cio::Result<Peer<T>> {
         let throttle = t.get_throttle(0);
-        let addr = Peer::setup_conn(&mut t.cio, id, throttle)?;
+        let (addr, written) = Peer::setup_conn(&mut t.cio, id, throttle)?;
         let mut p = Peer {
             dht_port,
             id,
@@ -263,10 +346,15 @@ impl<T: cio::CIO> Peer<T> {
             local_status: Status::new(),
             uploaded: 0,
             downloaded: 0,
+            written,
             stat: stat::EMA::new(),
+            last_active: time::Instant::now(),
             cio: t.cio.new_handle(),
             queued: 0,
             max_queue: INIT_MAX_QUEUE,
+            latency: None,
+            latency_probe: None,
+            last_block: time::Instant::now(),
             pieces: Bitfield::new(t.info.hashes.len() as u64),
             piece_cache: Vec::new(),
             piece_count: 0,
@@ -275,6 +363,14 @@ impl<T: cio::CIO> Peer<T> {
             rsv,
             cid,
             ext_ids: ExtIDs::new(),
+            ext_hint: None,
+            // Incoming peers have sent their handshake by now
+            outgoing: cid.is_none(),
+            listen_port: None,
+            upload_only: false,
+            pex_sent: FHashSet::default(),
+            voted: false,
+            meta_probe: None,
             pieces_updated: false,
             rank: t.num_peers(),
         };
@@ -286,12 +382,16 @@ impl<T: cio::CIO> Peer<T> {
         Ok(p)
     }
 
-    fn setup_conn(cio: &mut T, pid: usize, throttle: Throttle) -> cio::Result<SocketAddr> {
-        if let Some(addr) = cio.get_peer(pid, |pconn| {
+    fn setup_conn(
+        cio: &mut T,
+        pid: usize,
+        throttle: Throttle,
+    ) -> cio::Result<(SocketAddr, Arc<AtomicU64>)> {
+        if let Some(res) = cio.get_peer(pid, |pconn| {
             pconn.set_throttle(throttle);
-            pconn.sock().addr()
+            (pconn.sock().addr(), pconn.upload_counter())
         }) {
-            Ok(addr)
+            Ok(res)
         } else {
             debug!("pid {} not found", pid);
             Err(cio::Error::NoSuchPeer(pid))
@@ -312,14 +412,73 @@ impl<T: cio::CIO> Peer<T> {
         self.cid.is_some()
     }
 
+    pub fn ext_hint(&self) -> Option<SocketAddr> {
+        self.ext_hint
+    }
+
     pub fn exts(&self) -> &ExtIDs {
         &self.ext_ids
     }
 
+    /// Records the metadata piece requested to find out whether the peer has the metadata.
+    pub fn set_meta_probe(&mut self, piece: usize) {
+        self.meta_probe = Some(piece);
+    }
+
+    /// Whether `piece` answers the metadata probe, which is then cleared.
+    pub fn meta_probe_answered(&mut self, piece: usize) -> bool {
+        if self.meta_probe == Some(piece) {
+            self.meta_probe = None;
+            return true;
+        }
+        false
+    }
+
     pub fn addr(&self) -> SocketAddr {
         self.addr
     }
 
+    /// Whether the peer announced it's only uploading.
+    pub fn upload_only(&self) -> bool {
+        self.upload_only
+    }
+
+    /// Returns the address other peers can connect to this one at, if it's known.
+    pub fn listen_addr(&self) -> Option<SocketAddr> {
+        match self.listen_port {
+            Some(port) => Some(SocketAddr::new(self.addr.ip(), port)),
+            None if self.outgoing => Some(self.addr),
+            None => None,
+        }
+    }
+
+    /// Picks which of the `connected` peers to tell this peer about over PEX, along with
+    /// those it was told about which have since dropped, and records them as sent.
+    pub fn pex_delta(
+        &mut self,
+        connected: &[(SocketAddr, u8)],
+    ) -> (Vec<(SocketAddr, u8)>, Vec<SocketAddr>) {
+        let own = self.listen_addr();
+        let added: Vec<_> = connected
+            .iter()
+            .filter(|(addr, _)| Some(*addr) != own && !self.pex_sent.contains(addr))
+            .take(PEX_MAX)
+            .copied()
+            .collect();
+        let dropped: Vec<_> = self
+            .pex_sent
+            .iter()
+            .filter(|addr| !connected.iter().any(|(a, _)| a == *addr))
+            .take(PEX_MAX)
+            .copied()
+            .collect();
+        for addr in &dropped {
+            self.pex_sent.remove(addr);
+        }
+        self.pex_sent.extend(added.iter().map(|(addr, _)| *addr));
+        (added, dropped)
+    }
+
     pub fn pieces(&self) -> &Bitfield {
         &self.pieces
     }
@@ -337,6 +496,19 @@ impl<T: cio::CIO> Peer<T> {
         self.id
     }
 
+    /// Returns the piece bytes written to the peer since the last call, counting them
+    /// towards its upload rate.
+    pub fn take_written(&mut self) -> u64 {
+        let written = self.written.swap(0, Ordering::Relaxed);
+        if written != 0 {
+            self.uploaded = self
+                .uploaded
+                .saturating_add(u32::try_from(written).unwrap_or(u32::MAX));
+            self.stat.add_ul(written);
+        }
+        written
+    }
+
     pub fn flush(&mut self) -> (u32, u32) {
         (
             mem::replace(&mut self.uploaded, 0),
@@ -348,22 +520,30 @@ impl<T: cio::CIO> Peer<T> {
         self.stat.active()
     }
 
+    /// Duration since data was last transferred to or from the peer.
+    pub fn idle_time(&self) -> time::Duration {
+        self.last_active.elapsed()
+    }
+
+    /// Restarts rate sampling of a peer which sat idle without being ticked.
+    pub fn resume_tick(&mut self) {
+        self.stat.resume();
+    }
+
+    /// Folds the data transferred since the last tick into the peer's rates, returning
+    /// whether it's still active.
     pub fn tick(&mut self) -> bool {
         self.stat.tick();
-        if !self.stat.active() {
+        let active = self.stat.active();
+        if !active {
             return false;
         }
-        let dl = self.stat.avg_dl();
-        let rate = (dl / 1024) as u16;
-        // Taken from rtorrent's pipeline calculation
-        let nmq = if rate < 20 { rate + 2 } else { rate / 5 + 18 };
-        // Clamp between -15 / +50 for queue len changes
-        self.max_queue = cmp::min(
-            cmp::max(nmq, self.max_queue.saturating_sub(15)),
-            self.max_queue + 50,
-        );
-        // Keep it under the max cap
-        self.max_queue = cmp::min(self.max_queue, MAX_QUEUE_CAP);
+        self.last_active = time::Instant::now();
+        let max_queue = self.max_queue;
+        self.resize_queue(self.stat.avg_dl());
+        if self.max_queue != max_queue {
+            self.send_rpc_queue();
+        }
         if self.pieces_updated {
             self.pieces_updated = false;
             self.send_rpc_update();
@@ -371,10 +551,81 @@ impl<T: cio::CIO> Peer<T> {
         true
     }
 
+    /// Sizes the queue to twice the bandwidth-delay product of the peer, given its download
+    /// rate in bytes/sec. Doubling it lets a peer the queue is holding back show it can
+    /// go faster, while one which can't settles at a steady depth.
+    fn resize_queue(&mut self, rate: u64) {
+        let Some(latency) = self.latency else {
+            return;
+        };
+        let bdp = rate as f64 * latency.as_secs_f64() / BLOCK_SIZE;
+        self.max_queue = ((2. * bdp) as u16).clamp(MIN_QUEUE, MAX_QUEUE);
+    }
+
+    /// When the requests outstanding to the peer will have stalled, if there are any.
+    pub fn stall_deadline(&self) -> Option<time::Instant> {
+        (self.queued != 0).then(|| self.last_block + STALL_TIMEOUT)
+    }
+
+    /// Halves the queue of a peer which has left requests unanswered for too long,
+    /// returning when to check again if any are still outstanding.
+    pub fn check_stall(&mut self, now: time::Instant) -> Option<time::Instant> {
+        if self.queued == 0 {
+            return None;
+        }
+        // Choked peers have dropped our requests rather than stalled
+        if self.remote_status.choked {
+            return Some(now + STALL_TIMEOUT);
+        }
+        if now.duration_since(self.last_block) >= STALL_TIMEOUT {
+            debug!(
+                "Peer {} stalled with {} requests outstanding",
+                self.addr, self.queued
+            );
+            let max_queue = self.max_queue;
+            self.max_queue = cmp::max(self.max_queue / 2, MIN_QUEUE);
+            self.last_block = now;
+            if self.max_queue != max_queue {
+                self.send_rpc_queue();
+            }
+        }
+        self.stall_deadline()
+    }
+
+    fn block_received(&mut self, now: time::Instant) {
+        self.last_block = now;
+        if let Some(sent) = self.latency_probe.take() {
+            let sample = now.duration_since(sent);
+            self.latency = Some(match self.latency {
+                Some(avg) => (avg * 7 + sample) / 8,
+                None => sample,
+            });
+        }
+    }
+
+    /// Whether the peer is choking us.
+    pub fn choked(&self) -> bool {
+        self.remote_status.choked
+    }
+
+    #[cfg(test)]
+    pub fn set_idle(&mut self, idle: time::Duration) {
+        self.last_active = time::Instant::now().checked_sub(idle).unwrap();
+    }
+
     pub fn get_tx_rates(&self) -> (u64, u64) {
         (self.stat.avg_ul(), self.stat.avg_dl())
     }
 
+    /// Requests which may be outstanding to the peer, none while it's choking us.
+    pub fn request_capacity(&self) -> usize {
+        if self.remote_status.choked {
+            0
+        } else {
+            usize::from(self.max_queue)
+        }
+    }
+
     pub fn queue_reqs(&mut self) -> Option<u16> {
         if self.remote_status.choked || self.queued > self.max_queue.saturating_sub(16) {
             None
@@ -387,17 +638,16 @@ impl<T: cio::CIO> Peer<T> {
     pub fn handle_msg(&mut self, msg: &mut Message) -> Result<()> {
         match *msg {
             Message::Handshake { rsv, id, .. } => {
-                if (rsv[DHT_EXT.0] & DHT_EXT.1) != 0 {
-                    self.send_message(Message::Port(self.dht_port));
-                }
                 self.rsv = Some(rsv);
                 self.cid = Some(id);
+                self.send_message(Message::Port(self.dht_port));
                 self.send_rpc_info();
             }
             Message::Piece { length, .. } => {
                 self.stat.add_dl(u64::from(length));
                 self.downloaded += 1;
                 self.queued -= 1;
+                self.block_received(time::Instant::now());
             }
             Message::Request { .. } => {
                 if self.local_status.choked {
@@ -477,6 +727,43 @@ impl<T: cio::CIO> Peer<T> {
                         .remove(b"ut_pex".as_ref())
                         .and_then(|v| v.into_int())
                         .map(|v| v as u8);
+
+                    let listen_port = d
+                        .remove(b"p".as_ref())
+                        .and_then(|v| v.into_int())
+                        .and_then(|p| u16::try_from(p).ok())
+                        .filter(|&p| p != 0);
+                    if listen_port.is_some() {
+                        self.listen_port = listen_port;
+                    }
+                    if let Some(upload_only) =
+                        d.remove(b"upload_only".as_ref()).and_then(|v| v.into_int())
+                    {
+                        self.upload_only = upload_only != 0;
+                    }
+                    let port = listen_port.unwrap_or(self.addr.port());
+                    // Only an address in the other family is of any use
+                    let alt_key: &[u8] = if self.addr.is_ipv4() {
+                        b"ipv6"
+                    } else {
+                        b"ipv4"
+                    };
+                    if self.ext_hint.is_none()
+                        && let Some(ip) = d.remove(alt_key).and_then(ext_ip)
+                        && ip.is_ipv4() != self.addr.is_ipv4()
+                        && !ip.is_unspecified()
+                    {
+                        self.ext_hint = Some(SocketAddr::new(ip, port));
+                    }
+                    if !self.voted
+                        && let Some(ip) = d.remove(b"yourip".as_ref()).and_then(ext_ip)
+                    {
+                        self.voted = true;
+                        self.cio.msg_trk(tracker::Request::ExternalIp {
+                            voter: self.addr.ip(),
+                            ip,
+                        });
+                    }
                 }
             }
         }
@@ -485,6 +772,11 @@ impl<T: cio::CIO> Peer<T> {
 
     pub fn request_piece(&mut self, idx: u32, offset: u32, len: u32) {
         let m = Message::request(idx, offset, len);
+        if self.queued == 0 {
+            let now = time::Instant::now();
+            self.latency_probe = Some(now);
+            self.last_block = now;
+        }
         self.queued += 1;
         self.send_message(m);
     }
@@ -492,10 +784,22 @@ impl<T: cio::CIO> Peer<T> {
     pub fn choke(&mut self) {
         if !self.local_status.choked {
             self.local_status.choked = true;
+            self.cancel_uploads();
             self.send_message(Message::Choke);
         }
     }
 
+    /// Whether we're currently choking the peer.
+    pub fn choking(&self) -> bool {
+        self.local_status.choked
+    }
+
+    /// Drops piece sends still queued for the peer, freeing their buffers.
+    pub fn cancel_uploads(&mut self) {
+        self.cio
+            .get_peer(self.id, |conn| conn.writer.cancel_pieces());
+    }
+
     pub fn unchoke(&mut self) {
         if self.local_status.choked {
             self.local_status.choked = false;
@@ -510,15 +814,37 @@ impl<T: cio::CIO> Peer<T> {
         }
     }
 
+    /// Whether the peer set the given reserved bit in its handshake.
+    fn supports(&self, (byte, bit): (usize, u8)) -> bool {
+        self.rsv.is_some_and(|rsv| rsv[byte] & bit != 0)
+    }
+
+    /// Whether the peer understands the extension protocol (BEP 10).
+    pub fn supports_extended(&self) -> bool {
+        self.supports(EXT_PROTO)
+    }
+
+    /// Whether the peer runs a DHT node, and so wants our DHT port (BEP 5).
+    pub fn supports_dht(&self) -> bool {
+        self.supports(DHT_EXT)
+    }
+
+    /// Sends a message to the peer, dropping extension and DHT port messages if it
+    /// didn't advertise support for them, as some clients disconnect over them.
     pub fn send_message(&mut self, msg: Message) {
-        if let Message::Piece { length, .. } = msg {
-            self.uploaded += 1;
-            self.stat.add_ul(u64::from(length));
+        let supported = match msg {
+            Message::Extension { .. } => self.supports_extended(),
+            Message::Port(_) => self.supports_dht(),
+            _ => true,
+        };
+        if !supported {
+            trace!("Not sending {:?} to peer {}, unsupported", msg, self.id);
+            return;
         }
         self.cio.msg_peer(self.id, msg);
     }
 
-    fn send_rpc_info(&mut self) {
+    pub fn send_rpc_info(&mut self) {
         if let Some(cid) = self.cid {
             let id = util::peer_rpc_id(&self.t_hash, self.id as u64);
             self.cio
@@ -531,6 +857,7 @@ impl<T: cio::CIO> Peer<T> {
                         rate_up: 0,
                         rate_down: 0,
                         availability: self.piece_count as f32 / self.pieces.len() as f32,
+                        queue_depth: self.max_queue,
                         ..Default::default()
                     },
                 )]));
@@ -541,7 +868,7 @@ impl<T: cio::CIO> Peer<T> {
         if self.cid.is_some() {
             let id = util::peer_rpc_id(&self.t_hash, self.id as u64);
             self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
-                resource::SResourceUpdate::PeerAvailability {
+                resource::SResourceUpdate::Availability {
                     id,
                     kind: resource::ResourceKind::Peer,
                     availability: self.piece_count as f32 / self.pieces.len() as f32,
@@ -550,6 +877,19 @@ impl<T: cio::CIO> Peer<T> {
         }
     }
 
+    fn send_rpc_queue(&mut self) {
+        if self.cid.is_some() {
+            let id = util::peer_rpc_id(&self.t_hash, self.id as u64);
+            self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+                resource::SResourceUpdate::PeerQueue {
+                    id,
+                    kind: resource::ResourceKind::Peer,
+                    queue_depth: self.max_queue,
+                },
+            ]));
+        }
+    }
+
     pub fn send_rpc_removal(&mut self) {
         if self.ready() {
             self.cio
@@ -577,6 +917,19 @@ impl<T: cio::CIO> fmt::Debug for Peer<T> {
     }
 }
 
+/// Decodes a compact address from an extension handshake, which
+/// must be exactly 4 or 16 bytes.
+fn ext_ip(v: bencode::BEncode) -> Option<IpAddr> {
+    let b = v.into_bytes()?;
+    if let Ok(b) = <[u8; 4]>::try_from(&b[..]) {
+        Some(IpAddr::from(b))
+    } else if let Ok(b) = <[u8; 16]>::try_from(&b[..]) {
+        Some(IpAddr::from(b))
+    } else {
+        None
+    }
+}
+
 impl ExtIDs {
     fn new() -> ExtIDs {
         ExtIDs {
@@ -588,10 +941,16 @@ impl ExtIDs {
 
 #[cfg(test)]
 mod tests {
-    use super::Peer;
+    use super::{MAX_QUEUE, MIN_QUEUE, Peer, STALL_TIMEOUT};
+    use crate::bencode::BEncode;
     use crate::buffers::Buffer;
     use crate::control::cio::{CIO, test};
     use crate::torrent::Message;
+    use crate::tracker;
+    use crate::{DHT_EXT, EXT_PROTO};
+    use std::collections::BTreeMap;
+    use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+    use std::time::Duration;
 
     #[test]
     fn test_cancel() {
@@ -633,4 +992,202 @@ mod tests {
         assert_eq!(wq[0], p1);
         assert_eq!(wq[1], p3);
     }
+
+    #[test]
+    fn test_choke_cancels_pieces() {
+        let mut tcio = test::TCIO::new();
+        let mut peer = Peer::test_with_tcio(tcio.new_handle());
+        peer.unchoke();
+        for i in 0..3 {
+            peer.send_message(Message::Piece {
+                index: i,
+                begin: 0,
+                data: Buffer::get().unwrap(),
+                length: 16_384,
+            });
+        }
+        peer.send_message(Message::Have(1));
+
+        peer.choke();
+        let wq = tcio
+            .get_peer(peer.id, |p| p.writer.write_queue.clone())
+            .unwrap();
+        assert_eq!(wq.len(), 2);
+        assert_eq!(wq[0], Message::Have(1));
+        assert_eq!(wq[1], Message::Choke);
+        assert_eq!(peer.flush().0, 0);
+    }
+
+    #[test]
+    fn test_reserved_bits() {
+        let sent = |rsv: [u8; 8]| {
+            let mut tcio = test::TCIO::new();
+            let mut peer = Peer::test_with_tcio(tcio.new_handle());
+            // Occupies the writer, so everything after is left in the queue
+            peer.send_message(Message::KeepAlive);
+            let mut hs = Message::Handshake {
+                rsv,
+                hash: [0; 20],
+                id: [1; 20],
+            };
+            peer.handle_msg(&mut hs).unwrap();
+            peer.send_message(Message::Extension {
+                id: 0,
+                payload: vec![],
+            });
+            peer.send_message(Message::Have(1));
+            tcio.get_peer(peer.id, |p| p.writer.write_queue.clone())
+                .unwrap()
+        };
+
+        let mut rsv = [0u8; 8];
+        assert_eq!(sent(rsv), [Message::Have(1)]);
+        rsv[EXT_PROTO.0] |= EXT_PROTO.1;
+        assert_eq!(
+            sent(rsv),
+            [
+                Message::Extension {
+                    id: 0,
+                    payload: vec![]
+                },
+                Message::Have(1)
+            ]
+        );
+        rsv = [0u8; 8];
+        rsv[DHT_EXT.0] |= DHT_EXT.1;
+        assert_eq!(sent(rsv), [Message::Port(0), Message::Have(1)]);
+    }
+
+    fn ext_handshake(peer: &mut Peer<test::TCIO>, keys: &[(&[u8], BEncode)]) {
+        let mut d = BTreeMap::new();
+        d.insert(b"m".to_vec(), BEncode::Dict(BTreeMap::new()));
+        for (k, v) in keys {
+            d.insert(k.to_vec(), v.clone());
+        }
+        let payload = BEncode::Dict(d).encode_to_buf();
+        let mut msg = Message::Extension { id: 0, payload };
+        peer.handle_msg(&mut msg).unwrap();
+    }
+
+    #[test]
+    fn test_ext_hint() {
+        let tcio = test::TCIO::new();
+        let mut peer = Peer::test_with_tcio(tcio.new_handle());
+        let v6: Ipv6Addr = "2001:db8::1".parse().unwrap();
+
+        // Same family and malformed addresses are ignored
+        ext_handshake(
+            &mut peer,
+            &[
+                (b"ipv4", BEncode::String(vec![10, 0, 0, 1])),
+                (b"ipv6", BEncode::String(vec![0; 5])),
+            ],
+        );
+        assert_eq!(peer.ext_hint(), None);
+
+        ext_handshake(
+            &mut peer,
+            &[
+                (b"ipv6", BEncode::String(v6.octets().to_vec())),
+                (b"p", BEncode::Int(6881)),
+            ],
+        );
+        assert_eq!(peer.ext_hint(), Some(SocketAddr::new(v6.into(), 6881)));
+
+        // Only one hint is accepted per peer
+        let other: Ipv6Addr = "2001:db8::2".parse().unwrap();
+        ext_handshake(
+            &mut peer,
+            &[(b"ipv6", BEncode::String(other.octets().to_vec()))],
+        );
+        assert_eq!(peer.ext_hint(), Some(SocketAddr::new(v6.into(), 6881)));
+    }
+
+    #[test]
+    fn test_ext_yourip() {
+        let tcio = test::TCIO::new();
+        let mut peer = Peer::test_with_tcio(tcio.new_handle());
+
+        ext_handshake(&mut peer, &[(b"yourip", BEncode::String(vec![1, 2, 3]))]);
+        assert!(tcio.data().trk_msgs.is_empty());
+
+        ext_handshake(&mut peer, &[(b"yourip", BEncode::String(vec![1, 2, 3, 4]))]);
+        ext_handshake(&mut peer, &[(b"yourip", BEncode::String(vec![5, 6, 7, 8]))]);
+        let msgs = &tcio.data().trk_msgs;
+        assert_eq!(msgs.len(), 1);
+        match msgs[0] {
+            tracker::Request::ExternalIp { voter, ip } => {
+                assert_eq!(voter, peer.addr().ip());
+                assert_eq!(ip, IpAddr::from([1, 2, 3, 4]));
+            }
+            ref r => panic!("unexpected request {:?}", r),
+        }
+    }
+
+    /// Depth of the queue of a peer which answers requests after `latency` and downloads
+    /// at `rate` bytes/sec.
+    fn pipeline_depth(latency: Duration, rate: u64) -> u16 {
+        let mut peer = Peer::test_with_tcio(test::TCIO::new());
+        for _ in 0..8 {
+            peer.request_piece(0, 0, 16_384);
+            let sent = peer.latency_probe.unwrap();
+            peer.queued -= 1;
+            peer.block_received(sent + latency);
+            peer.resize_queue(rate);
+        }
+        peer.max_queue
+    }
+
+    #[test]
+    fn test_pipeline_depth() {
+        let fast = pipeline_depth(Duration::from_millis(50), 8 << 20);
+        let slow = pipeline_depth(Duration::from_secs(1), 32 << 10);
+        assert_eq!(fast, 51);
+        assert_eq!(slow, MIN_QUEUE);
+        assert_eq!(
+            pipeline_depth(Duration::from_millis(500), 100 << 20),
+            MAX_QUEUE
+        );
+
+        // A single slow response only nudges the average
+        let mut peer = Peer::test_with_tcio(test::TCIO::new());
+        for latency in [50, 50, 850] {
+            peer.request_piece(0, 0, 16_384);
+            let sent = peer.latency_probe.unwrap();
+            peer.queued -= 1;
+            peer.block_received(sent + Duration::from_millis(latency));
+        }
+        assert_eq!(peer.latency, Some(Duration::from_millis(150)));
+    }
+
+    #[test]
+    fn test_pipeline_stall() {
+        let mut peer = Peer::test_with_tcio(test::TCIO::new());
+        peer.remote_status.choked = false;
+        peer.max_queue = 64;
+        peer.check_stall(peer.last_block + STALL_TIMEOUT * 2);
+        assert_eq!(peer.max_queue, 64);
+
+        peer.request_piece(0, 0, 16_384);
+        let start = peer.last_block;
+        peer.check_stall(start + STALL_TIMEOUT / 2);
+        assert_eq!(peer.max_queue, 64);
+        peer.check_stall(start + STALL_TIMEOUT);
+        assert_eq!(peer.max_queue, 32);
+        // Each further timeout without a response halves it again
+        peer.check_stall(start + STALL_TIMEOUT);
+        assert_eq!(peer.max_queue, 32);
+        for _ in 2..8 {
+            let last = peer.last_block;
+            peer.check_stall(last + STALL_TIMEOUT);
+        }
+        assert_eq!(peer.max_queue, MIN_QUEUE);
+
+        // Choked peers have dropped our requests rather than stalled
+        peer.max_queue = 64;
+        peer.remote_status.choked = true;
+        let last = peer.last_block;
+        peer.check_stall(last + STALL_TIMEOUT);
+        assert_eq!(peer.max_queue, 64);
+    }
 }
@@ -128,7 +128,7 @@ impl Reader {
                             5 => {
                                 let mlen = BigEndian::read_u32(&self.prefix[0..4]);
                                 if mlen as usize > BUF_SIZE {
-                                    // we'll check the exact length later
+                                    // The peer checks it against the torrent's piece count
                                     return RRes::Err(io::Error::other(format!(
                                         "Invalid bitfield length {mlen}"
                                     )));
@@ -1,30 +1,38 @@
 use std::collections::VecDeque;
 use std::io::{self, ErrorKind, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use crate::buffers::Buffer;
 use crate::torrent::peer::Message;
 use crate::util::io_err;
 
+/// Upper bound on the bytes of consecutive small messages (requests, haves,
+/// etc.) which are coalesced into a single write.
+const MAX_BATCH: usize = 512;
+
 pub struct Writer {
     // Needed so that the peer can filter out cancel'd messages.
     // The state of this isn't critical to any invariants of the Writer
     // so it shouldn't be an issue
     pub write_queue: VecDeque<Message>,
     blocks_written: usize,
+    /// Piece payload bytes written to the connection, drained by the torrent
+    uploaded: Arc<AtomicU64>,
     writable: bool,
     state: WriteState,
+    /// Encoded small messages for the `WritingBatch` state
+    batch: Vec<u8>,
 }
 
 enum WriteState {
     Idle,
-    WritingMsg {
-        data: [u8; 17],
-        len: u8,
-        idx: u8,
+    WritingBatch {
+        idx: u16,
     },
     WritingOther {
         data: Vec<u8>,
-        idx: u16,
+        idx: usize,
     },
     WritingPiece {
         prefix: [u8; 17],
@@ -40,9 +48,15 @@ impl Writer {
             write_queue: VecDeque::new(),
             state: WriteState::Idle,
             blocks_written: 0,
+            uploaded: Arc::new(AtomicU64::new(0)),
+            batch: Vec::with_capacity(MAX_BATCH),
         }
     }
 
+    pub fn upload_counter(&self) -> Arc<AtomicU64> {
+        self.uploaded.clone()
+    }
+
     pub fn writable<W: Write>(&mut self, conn: &mut W) -> io::Result<()> {
         self.writable = true;
         self.write(conn)
@@ -50,7 +64,7 @@ impl Writer {
 
     pub fn write_message<W: Write>(&mut self, msg: Message, conn: &mut W) -> io::Result<()> {
         if let WriteState::Idle = self.state {
-            self.setup_write(msg);
+            self.setup_write(msg)?;
         } else {
             self.write_queue.push_back(msg);
         }
@@ -61,31 +75,58 @@ impl Writer {
         }
     }
 
-    fn setup_write(&mut self, msg: Message) {
+    /// Drops every queued piece message.
+    /// A piece which is already partway out on the wire is left to finish.
+    pub fn cancel_pieces(&mut self) {
+        self.write_queue
+            .retain(|m| !matches!(m, Message::Piece { .. }));
+    }
+
+    fn setup_write(&mut self, msg: Message) -> io::Result<()> {
         self.state = if !msg.is_special() {
-            let mut buf = [0; 17];
-            let len = msg.len();
-            // Should never go wrong
-            msg.encode(&mut buf).unwrap();
+            let mut prefix = [0; 17];
+            if let Message::Piece { .. } = msg {
+                encode(&msg, &mut prefix)?;
+            }
             match msg {
                 Message::Piece { data, .. } => WriteState::WritingPiece {
-                    prefix: buf,
+                    prefix,
                     data,
                     idx: 0,
                 },
-                _ => WriteState::WritingMsg {
-                    data: buf,
-                    len: len as u8,
-                    idx: 0,
-                },
+                _ => {
+                    self.batch.clear();
+                    self.append_batch(&msg);
+                    // Pull in whatever small messages would be written next,
+                    // so they go out in one segment rather than several.
+                    while let Some(next) = self.write_queue.back() {
+                        if !Writer::batchable(next) || self.batch.len() + next.len() > MAX_BATCH {
+                            break;
+                        }
+                        let next = self.write_queue.pop_back().unwrap();
+                        self.append_batch(&next);
+                    }
+                    WriteState::WritingBatch { idx: 0 }
+                }
             }
         } else {
             // TODO: Acquire from buffer
             let mut buf = vec![0; msg.len()];
-            // Should never go wrong
-            msg.encode(&mut buf).unwrap();
+            encode(&msg, &mut buf)?;
             WriteState::WritingOther { data: buf, idx: 0 }
         };
+        Ok(())
+    }
+
+    fn batchable(msg: &Message) -> bool {
+        !msg.is_special() && !matches!(msg, Message::Piece { .. })
+    }
+
+    fn append_batch(&mut self, msg: &Message) {
+        let start = self.batch.len();
+        self.batch.resize(start + msg.len(), 0);
+        // Should never go wrong
+        msg.encode(&mut self.batch[start..]).unwrap();
     }
 
     fn write<W: Write>(&mut self, conn: &mut W) -> io::Result<()> {
@@ -95,14 +136,15 @@ impl Writer {
         loop {
             match self.write_(conn) {
                 Ok(true) => {
-                    if let Some(msg) = self.write_queue.pop_back() {
-                        self.setup_write(msg);
-                    } else {
-                        self.state = WriteState::Idle;
-                        break;
+                    self.state = WriteState::Idle;
+                    match self.write_queue.pop_back() {
+                        Some(msg) => self.setup_write(msg)?,
+                        None => break,
                     }
                 }
                 Ok(false) => {}
+                // The position is only advanced by successful writes, so just try again
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
                 Err(e) => {
                     if e.kind() == ErrorKind::WouldBlock
                         || e.kind() == ErrorKind::NotConnected
@@ -121,17 +163,13 @@ impl Writer {
     fn write_<W: Write>(&mut self, conn: &mut W) -> io::Result<bool> {
         match self.state {
             WriteState::Idle => Ok(false),
-            WriteState::WritingMsg {
-                ref data,
-                ref len,
-                ref mut idx,
-            } => {
-                let amnt = conn.write(&data[(*idx as usize)..(*len as usize)])?;
+            WriteState::WritingBatch { ref mut idx } => {
+                let amnt = conn.write(&self.batch[(*idx as usize)..])?;
                 if amnt == 0 {
                     return io_err("EOF");
                 }
-                *idx += amnt as u8;
-                if idx == len {
+                *idx += amnt as u16;
+                if *idx as usize == self.batch.len() {
                     Ok(true)
                 } else {
                     self.writable = false;
@@ -159,6 +197,7 @@ impl Writer {
                 if amnt == 0 {
                     return io_err("EOF");
                 }
+                self.uploaded.fetch_add(amnt as u64, Ordering::Relaxed);
                 // piece should never exceed u16 size
                 *idx += amnt as u16;
                 if *idx == (13 + data.len()) as u16 {
@@ -173,12 +212,12 @@ impl Writer {
                 ref data,
                 ref mut idx,
             } => {
-                let amnt = conn.write(&data[(*idx as usize)..])?;
+                let amnt = conn.write(&data[*idx..])?;
                 if amnt == 0 {
                     return io_err("EOF");
                 }
-                *idx += amnt as u16;
-                if *idx == data.len() as u16 {
+                *idx += amnt;
+                if *idx == data.len() {
                     Ok(true)
                 } else {
                     self.writable = false;
@@ -189,11 +228,21 @@ impl Writer {
     }
 }
 
+/// Encodes a message of variable length. Failing means something built a message the
+/// length prefix can't describe, which is a bug, so the peer is disconnected rather
+/// than sent a corrupt stream.
+fn encode(msg: &Message, buf: &mut [u8]) -> io::Result<()> {
+    msg.encode(buf)
+        .inspect_err(|e| error!("Refusing to send {:?}: {}", msg, e))
+}
+
 #[cfg(test)]
 mod tests {
     use super::Writer;
     use crate::buffers::Buffer;
     use crate::torrent::peer::Message;
+    use std::io::{self, Write};
+    use std::sync::atomic::Ordering;
 
     #[test]
     fn test_write_keepalive() {
@@ -302,6 +351,78 @@ mod tests {
         }
     }
 
+    /// Accepts at most `budget` bytes before blocking
+    #[derive(Default)]
+    struct Limited {
+        data: Vec<u8>,
+        budget: usize,
+        writes: usize,
+    }
+
+    impl Write for Limited {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.budget == 0 {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "blocked"));
+            }
+            let amnt = buf.len().min(self.budget);
+            self.budget -= amnt;
+            self.writes += 1;
+            self.data.extend_from_slice(&buf[..amnt]);
+            Ok(amnt)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_write_piece_uploaded() {
+        let mut w = Writer::new();
+        let uploaded = w.upload_counter();
+        let mut conn = Limited {
+            budget: 13 + 1000,
+            ..Default::default()
+        };
+        let m = Message::Piece {
+            index: 1,
+            begin: 0,
+            length: 16_384,
+            data: Buffer::get().unwrap(),
+        };
+        w.write_message(m, &mut conn).unwrap();
+        // Only the written part of the payload is counted
+        assert_eq!(uploaded.load(Ordering::Relaxed), 1000);
+
+        conn.budget = usize::MAX;
+        w.writable(&mut conn).unwrap();
+        assert_eq!(uploaded.load(Ordering::Relaxed), 16_384);
+        assert_eq!(conn.data.len(), 13 + 16_384);
+    }
+
+    #[test]
+    fn test_write_batch() {
+        let mut w = Writer::new();
+        let mut conn = Limited::default();
+        let req = |index| Message::Request {
+            index,
+            begin: 0,
+            length: 16_384,
+        };
+        w.write_message(Message::Interested, &mut conn).unwrap();
+        w.write_message(Message::Have(1), &mut conn).unwrap();
+        w.write_message(req(1), &mut conn).unwrap();
+        w.write_message(req(2), &mut conn).unwrap();
+        assert_eq!(w.write_queue.len(), 3);
+
+        // Messages queued while blocked go out in a single write
+        conn.budget = usize::MAX;
+        w.writable(&mut conn).unwrap();
+        assert_eq!(conn.writes, 2);
+        assert_eq!(conn.data.len(), 5 + 17 + 17 + 9);
+        assert!(w.write_queue.is_empty());
+    }
+
     #[test]
     fn test_write_cancel() {
         let mut w = Writer::new();
@@ -331,4 +452,152 @@ mod tests {
         w.writable(&mut &mut buf[..]).unwrap();
         assert_eq!(buf[..], abuf[..])
     }
+
+    #[test]
+    fn test_write_flaky() {
+        use crate::torrent::peer::reader::{RRes, Reader};
+        use crate::util::Flaky;
+        use std::os::unix::net::UnixStream;
+
+        let (a, b) = UnixStream::pair().unwrap();
+        a.set_nonblocking(true).unwrap();
+        b.set_nonblocking(true).unwrap();
+        let (mut tx, mut rx) = (Flaky::new(a, 3), Flaky::new(b, 4));
+        let mut w = Writer::new();
+        let mut r = Reader::new();
+
+        let hs = Message::Handshake {
+            rsv: [0; 8],
+            hash: [1; 20],
+            id: [2; 20],
+        };
+        w.write_message(hs, &mut tx).unwrap();
+        for i in 0..200u32 {
+            w.write_message(Message::Have(i), &mut tx).unwrap();
+            if i % 25 == 0 {
+                let mut data = Buffer::get().unwrap();
+                data.iter_mut().for_each(|b| *b = i as u8);
+                let m = Message::Piece {
+                    index: i,
+                    begin: 0,
+                    length: 16_384,
+                    data,
+                };
+                w.write_message(m, &mut tx).unwrap();
+            }
+        }
+
+        let (mut haves, mut pieces) = (Vec::new(), Vec::new());
+        let mut handshake = false;
+        while haves.len() < 200 || pieces.len() < 8 {
+            w.writable(&mut tx).unwrap();
+            loop {
+                match r.readable(&mut rx) {
+                    RRes::Success(Message::Handshake { hash, id, .. }) => {
+                        assert_eq!((hash, id), ([1; 20], [2; 20]));
+                        handshake = true;
+                    }
+                    RRes::Success(Message::Have(i)) => haves.push(i),
+                    RRes::Success(Message::Piece { index, data, .. }) => {
+                        assert!(data.iter().all(|&b| b == index as u8));
+                        pieces.push(index);
+                    }
+                    RRes::Success(m) => panic!("unexpected message {m:?}"),
+                    RRes::Blocked => break,
+                    RRes::Err(e) => panic!("read failed: {e}"),
+                    RRes::Stalled => panic!("no buffers"),
+                }
+            }
+        }
+        assert!(handshake);
+        haves.sort_unstable();
+        pieces.sort_unstable();
+        assert_eq!(haves, (0..200).collect::<Vec<_>>());
+        assert_eq!(pieces, (0..200).step_by(25).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_oversize_rejected() {
+        use crate::protocol::MAX_MSG_LEN;
+        use crate::torrent::Bitfield;
+
+        let mut w = Writer::new();
+        let mut out = Vec::new();
+        let ext = Message::Extension {
+            id: 1,
+            payload: vec![0; MAX_MSG_LEN as usize - 1],
+        };
+        let err = w.write_message(ext, &mut out).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        let pf = Bitfield::new(u64::from(MAX_MSG_LEN) * 8);
+        let err = w
+            .write_message(Message::Bitfield(pf), &mut out)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        // Lengths which would wrap around the prefix are refused too
+        let piece = Message::Piece {
+            index: 0,
+            begin: 0,
+            length: u32::MAX - 8,
+            data: Buffer::get().unwrap(),
+        };
+        let err = w.write_message(piece, &mut out).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        // Nothing was written, and the writer is still usable
+        assert!(out.is_empty());
+        w.write_message(Message::KeepAlive, &mut out).unwrap();
+        assert_eq!(out, [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_max_len_roundtrip() {
+        use crate::buffers::BUF_SIZE;
+        use crate::protocol::MAX_MSG_LEN;
+        use crate::torrent::Bitfield;
+        use crate::torrent::peer::reader::{RRes, Reader};
+
+        let mut w = Writer::new();
+        let mut out = Vec::new();
+        let hs = Message::Handshake {
+            rsv: [0; 8],
+            hash: [1; 20],
+            id: [2; 20],
+        };
+        let payload: Vec<u8> = (0..MAX_MSG_LEN - 2).map(|i| i as u8).collect();
+        let ext = Message::Extension {
+            id: 3,
+            payload: payload.clone(),
+        };
+        // The largest bitfield the reader accepts
+        let mut pf = Bitfield::new((BUF_SIZE as u64 - 1) * 8);
+        pf.set_bit(7);
+        for m in [hs, ext, Message::Bitfield(pf.clone())] {
+            w.write_message(m, &mut out).unwrap();
+        }
+        assert_eq!(out.len(), 68 + 4 + MAX_MSG_LEN as usize + 4 + BUF_SIZE);
+
+        let mut r = Reader::new();
+        let mut data = &out[..];
+        assert!(matches!(
+            r.readable(&mut data),
+            RRes::Success(Message::Handshake { .. })
+        ));
+        match r.readable(&mut data) {
+            RRes::Success(Message::Extension { id, payload: p }) => {
+                assert_eq!(id, 3);
+                assert!(p == payload);
+            }
+            m => panic!("unexpected result {m:?}"),
+        }
+        match r.readable(&mut data) {
+            RRes::Success(Message::Bitfield(b)) => {
+                assert_eq!(b.len(), pf.len());
+                assert!(b.has_bit(7) && !b.has_bit(8));
+            }
+            m => panic!("unexpected result {m:?}"),
+        }
+    }
 }
@@ -1,10 +1,12 @@
 use std::collections::HashMap;
+use std::ops::Range;
 use std::sync::Arc;
-use std::time;
+use std::{mem, time};
 
 use crate::control::cio;
 use crate::torrent::{Bitfield, Info, Peer};
 use crate::util::FHashSet;
+use crate::util::timer::Timers;
 
 mod rarest;
 mod sequential;
@@ -33,9 +35,25 @@ pub struct Picker {
     picker: PickerKind,
     /// Piece priorities
     priorities: Vec<u8>,
+    /// Pieces whose completed block count changed since the last `take_progress`
+    progressed: FHashSet<u32>,
+    /// When each active request is considered stalled
+    timeouts: Timers<Block>,
+    /// Whether the blocks left fit in the requests our peers could have outstanding,
+    /// in which case blocks in flight are also requested from other peers
+    endgame: bool,
+    /// Peers a block may be requested from at once during endgame
+    max_dups: usize,
+    /// Blocks of wanted pieces which haven't been requested yet, kept up to date so
+    /// checking for endgame doesn't go over every piece
+    unrequested: usize,
+    /// Pieces of the files to download one after the other before anything else, in order
+    order: Vec<Range<u32>>,
+    /// Entries of `order` before this one are known to be done
+    order_pos: usize,
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct Block {
     pub index: u32,
     pub offset: u32,
@@ -59,26 +77,29 @@ struct Downloading {
     requested: Vec<Request>,
 }
 
-/// A request to a peer and the time it was initiated.
+/// A request to a peer.
 #[derive(Clone, Debug)]
 struct Request {
     rank: usize,
-    requested_at: time::Instant,
-    reqd_from: [usize; MAX_DUP_REQS],
-    num_reqd: usize,
+    /// Every peer the block is outstanding from, all but the one delivering it are
+    /// sent a cancel
+    reqd_from: Vec<usize>,
 }
 
-const MAX_DUP_REQS: usize = 3;
 const MAX_PC_SIZE: usize = 50;
 const MAX_DL_REREQ: usize = 150;
+/// Peers a block may be outstanding from at once after its requests stalled. Further
+/// requests wait for one of them to deliver it or disconnect.
+const MAX_BLOCK_REQS: usize = 8;
 const REQ_TIMEOUT: u64 = 10;
 
 impl Picker {
     /// Creates a new picker, which will select over
     /// the given pieces. The algorithm used for selection
     /// will vary based on the current swarm state, but
-    /// will default to rarest first.
-    pub fn new(info: &Arc<Info>, pieces: &Bitfield, priorities: &[u8]) -> Picker {
+    /// will default to rarest first. In endgame a block is requested
+    /// from at most `max_dups` peers at once.
+    pub fn new(info: &Arc<Info>, pieces: &Bitfield, priorities: &[u8], max_dups: usize) -> Picker {
         let scale = info.piece_len / 16_384;
         let picker = rarest::Picker::new(pieces);
         let last_piece = info.pieces().saturating_sub(1);
@@ -109,6 +130,13 @@ impl Picker {
             stalled: FHashSet::default(),
             priorities: vec![3; info.pieces() as usize],
             blocks,
+            progressed: FHashSet::default(),
+            timeouts: Timers::new(),
+            endgame: false,
+            max_dups,
+            unrequested: 0,
+            order: Vec::new(),
+            order_pos: 0,
         };
         picker.set_priorities(priorities, info);
         picker
@@ -119,24 +147,34 @@ impl Picker {
         matches!(self.picker, PickerKind::Sequential(_))
     }
 
+    pub fn endgame(&self) -> bool {
+        self.endgame
+    }
+
     pub fn done(&mut self) {
+        self.endgame = false;
         self.downloading = HashMap::with_capacity(0);
         self.blocks = vec![];
         self.stalled = FHashSet::default();
+        self.progressed = FHashSet::default();
+        self.timeouts = Timers::new();
+        self.unrequested = 0;
     }
 
-    pub fn tick(&mut self) {
+    /// Expires stalled requests and enters or leaves endgame, given the number of
+    /// requests which may be outstanding across all peers unchoking us.
+    pub fn tick(&mut self, capacity: usize) {
+        let endgame = !self.blocks.is_empty() && self.remaining() < capacity;
+        if endgame != self.endgame {
+            debug!("Endgame {}", if endgame { "started" } else { "ended" });
+            self.endgame = endgame;
+        }
+
         let mut expired = 0;
-        for (block, req) in &mut self.downloading {
-            let reqd = self.blocks[block.index as usize].0;
-            let _fully_reqd = reqd == self.scale as usize
-                || (block.index == self.last_piece && reqd == self.last_piece_scale as usize);
-            let deadline = (REQ_TIMEOUT as isize
-                + (3 - self.priorities[block.index as usize] as isize))
-                as u64;
-            if req.requested_at.elapsed().as_secs() >= deadline && !self.stalled.contains(block) {
+        let now = time::Instant::now();
+        while let Some(block) = self.timeouts.pop_expired(now) {
+            if self.downloading.contains_key(&block) && self.stalled.insert(block) {
                 expired += 1;
-                self.stalled.insert(*block);
             }
         }
         if expired != 0 {
@@ -156,29 +194,94 @@ impl Picker {
     pub fn pick<T: cio::CIO>(&mut self, peer: &mut Peer<T>) -> Option<Block> {
         if !self.stalled.is_empty() {
             let block = self.stalled.iter().cloned().find(|b| {
+                let req = &self.downloading[b];
                 peer.pieces().has_bit(u64::from(b.index))
-                    && !self.downloading[b].has_peer(peer.id())
+                    && req.reqd_from.len() < MAX_BLOCK_REQS
+                    && !req.has_peer(peer.id())
             });
             if let Some(b) = block {
                 self.stalled.remove(&b);
+                // The earlier requests timed out, so this doesn't count against the
+                // duplicate cap, but they're kept so they can be cancelled
                 if let Some(req) = self.downloading.get_mut(&b) {
-                    req.force_rereq(peer.id(), peer.rank);
+                    req.rereq(peer.id(), peer.rank);
                 }
+                self.requested(b);
                 return Some(b);
             }
         }
 
+        if let Some(target) = self.target() {
+            let piece = target.clone().find(|&idx| {
+                !self.unpicked.has_bit(u64::from(idx))
+                    && self.priorities[idx as usize] != 0
+                    && peer.pieces().has_bit(u64::from(idx))
+            });
+            return match piece {
+                Some(p) => Some(self.pick_piece(p, peer.id(), peer.rank)),
+                // Nothing else may be picked until the file is done, so its blocks in
+                // flight are shared out as in endgame
+                None => self.pick_dl(peer, Some(target)),
+            };
+        }
+
+        // Nothing the peer has is yet to be picked
+        if !peer.pieces().has_any_andnot(&self.unpicked) {
+            return self.pick_dl(peer, None);
+        }
         let piece = match self.picker {
             PickerKind::Sequential(ref mut p) => p.pick(peer),
             PickerKind::Rarest(ref mut p) => p.pick(peer),
         };
         piece
             .map(|p| self.pick_piece(p, peer.id(), peer.rank))
-            .or_else(|| self.pick_dl(peer))
+            .or_else(|| self.pick_dl(peer, None))
+    }
+
+    /// Sets the files to download to completion one after the other, ahead of the rest.
+    /// A piece spanning two of the files counts towards the one listed first.
+    pub fn set_order(&mut self, files: &[usize], info: &Info) {
+        self.order = files
+            .iter()
+            .filter(|&&f| info.files[f].length != 0)
+            .map(|&f| {
+                let start = info.file_offset(f);
+                let end = start + info.files[f].length;
+                let pl = u64::from(info.piece_len);
+                (start / pl) as u32..end.div_ceil(pl) as u32
+            })
+            .collect();
+        self.order_pos = 0;
+    }
+
+    /// The pieces of the first ordered file not done yet, if any.
+    fn target(&mut self) -> Option<Range<u32>> {
+        while let Some(r) = self.order.get(self.order_pos) {
+            if !r.clone().all(|idx| self.piece_done(idx)) {
+                return Some(r.clone());
+            }
+            self.order_pos += 1;
+        }
+        None
+    }
+
+    /// Whether every block of a piece has been received, or it isn't wanted.
+    fn piece_done(&self, idx: u32) -> bool {
+        if self.priorities[idx as usize] == 0 {
+            return true;
+        }
+        self.unpicked.has_bit(u64::from(idx))
+            && self
+                .blocks
+                .get(idx as usize)
+                .is_none_or(|&(picked, done)| picked == done)
     }
 
     /// Picks a block from a given piece for a peer
     fn pick_piece(&mut self, piece: u32, id: usize, rank: usize) -> Block {
+        if self.priorities[piece as usize] != 0 {
+            self.unrequested -= 1;
+        }
         self.blocks[piece as usize].0 += 1;
         let amnt = self.blocks[piece as usize].0;
         let offset = (amnt - 1) as u32 * 16_384;
@@ -196,18 +299,35 @@ impl Picker {
             offset,
         };
         self.downloading.insert(block, Request::new(id, rank));
+        self.requested(block);
         block
     }
 
-    /// Attempts to pick the highest priority piece in the dl q
-    fn pick_dl<T: cio::CIO>(&mut self, peer: &Peer<T>) -> Option<Block> {
-        self.downloading
+    /// Attempts to pick the least requested block in the dl q, only
+    /// done in endgame or when restricted to the given pieces.
+    fn pick_dl<T: cio::CIO>(
+        &mut self,
+        peer: &Peer<T>,
+        within: Option<Range<u32>>,
+    ) -> Option<Block> {
+        if !self.endgame && within.is_none() {
+            return None;
+        }
+        let max_dups = self.max_dups;
+        let block = self
+            .downloading
             .iter_mut()
-            .filter(|(_, req)| req.num_reqd < MAX_DUP_REQS && !req.has_peer(peer.id()))
+            .filter(|(b, req)| {
+                req.reqd_from.len() < max_dups
+                    && !req.has_peer(peer.id())
+                    && within.as_ref().is_none_or(|r| {
+                        r.contains(&b.index) && peer.pieces().has_bit(u64::from(b.index))
+                    })
+            })
             .take(MAX_DL_REREQ)
             .fold(None, |c: Option<(&Block, &mut Request)>, this| match &c {
                 Some(min) => {
-                    if this.1.num_reqd < min.1.num_reqd {
+                    if this.1.reqd_from.len() < min.1.reqd_from.len() {
                         Some(this)
                     } else {
                         c
@@ -218,7 +338,16 @@ impl Picker {
             .map(|(block, req)| {
                 req.rereq(peer.id(), peer.rank);
                 *block
-            })
+            })?;
+        self.requested(block);
+        Some(block)
+    }
+
+    /// Restarts the timeout of a block which was just requested from a peer.
+    fn requested(&mut self, block: Block) {
+        let secs = REQ_TIMEOUT as isize + (3 - self.priorities[block.index as usize] as isize);
+        let deadline = time::Instant::now() + time::Duration::from_secs(secs as u64);
+        self.timeouts.schedule(block, deadline);
     }
 
     /// Marks a block as completed. Returns a result indicating if the block
@@ -226,26 +355,116 @@ impl Picker {
     /// if the block is complete.
     pub fn completed<F: FnMut(usize)>(&mut self, b: Block, mut cancel: F) -> Result<bool, ()> {
         self.stalled.remove(&b);
+        self.timeouts.cancel(b);
         let dl = self.downloading.remove(&b);
         let dl = match dl {
             Some(dl) => dl,
             None => return Err(()),
         };
-        for peer in dl.reqd_from.iter() {
-            cancel(*peer);
+        for &peer in &dl.reqd_from {
+            cancel(peer);
         }
 
         self.blocks[b.index as usize].1 += 1;
+        self.progressed.insert(b.index);
         let amnt = self.blocks[b.index as usize].1;
-        if amnt == self.scale as usize
-            || (b.index == self.last_piece && amnt == self.last_piece_scale as usize)
-        {
-            Ok(true)
+        Ok(amnt == self.piece_blocks(b.index) as usize)
+    }
+
+    /// Number of blocks of wanted pieces which haven't been received yet.
+    fn remaining(&self) -> usize {
+        self.unrequested + self.downloading.len()
+    }
+
+    fn count_unrequested(&mut self) {
+        self.unrequested = (0..self.priorities.len() as u32)
+            .map(|idx| self.piece_unrequested(idx))
+            .sum();
+    }
+
+    /// Blocks of a piece counted in `unrequested`.
+    fn piece_unrequested(&self, idx: u32) -> usize {
+        if self.unpicked.has_bit(u64::from(idx)) || self.priorities[idx as usize] == 0 {
+            return 0;
+        }
+        self.blocks
+            .get(idx as usize)
+            .map_or(0, |&(picked, _)| self.piece_blocks(idx) as usize - picked)
+    }
+
+    fn piece_blocks(&self, idx: u32) -> u32 {
+        if idx == self.last_piece {
+            self.last_piece_scale
         } else {
-            Ok(false)
+            self.scale
         }
     }
 
+    /// Returns the `(piece, blocks done, blocks total)` of every piece
+    /// which gained blocks or was reset since the last call.
+    pub fn take_progress(&mut self) -> Vec<(u32, u32, u32)> {
+        let progressed = mem::take(&mut self.progressed);
+        let mut progress: Vec<_> = progressed
+            .into_iter()
+            .filter_map(|idx| {
+                let done = self.blocks.get(idx as usize)?.1 as u32;
+                Some((idx, done, self.piece_blocks(idx)))
+            })
+            .collect();
+        progress.sort_unstable();
+        progress
+    }
+
+    /// Returns the blocks received so far of every piece which was started but not
+    /// validated yet, indexed by block, ordered by piece.
+    pub fn partial(&self) -> Vec<(u32, Bitfield)> {
+        let mut partial: Vec<_> = self
+            .blocks
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, done))| *done > 0)
+            .map(|(idx, &(picked, _))| {
+                let idx = idx as u32;
+                let mut blocks = Bitfield::new(u64::from(self.piece_blocks(idx)));
+                for i in 0..picked as u32 {
+                    if !self.downloading.contains_key(&Block::new(idx, i * 16_384)) {
+                        blocks.set_bit(u64::from(i));
+                    }
+                }
+                (idx, blocks)
+            })
+            .collect();
+        partial.sort_unstable_by_key(|(idx, _)| *idx);
+        partial
+    }
+
+    /// Marks the given blocks of a piece as already received, so only the rest are
+    /// picked. Pieces which have been picked since the picker was created are left as
+    /// is, returning false.
+    pub fn restore(&mut self, idx: u32, done: &Bitfield) -> bool {
+        let total = self.piece_blocks(idx);
+        let count = done.iter().filter(|&b| b < u64::from(total)).count();
+        match self.blocks.get(idx as usize) {
+            Some((0, 0)) if !self.unpicked.has_bit(u64::from(idx)) && count > 0 => {}
+            _ => return false,
+        }
+        match self.picker {
+            PickerKind::Sequential(ref mut p) => p.completed(idx),
+            PickerKind::Rarest(ref mut p) => p.completed(idx),
+        }
+        self.unrequested -= self.piece_unrequested(idx);
+        self.unpicked.set_bit(u64::from(idx));
+        self.blocks[idx as usize] = (total as usize, count);
+        self.progressed.insert(idx);
+        // The missing blocks are handed out like stalled requests, ahead of new pieces
+        for i in (0..total).filter(|&i| !done.has_bit(u64::from(i))) {
+            let block = Block::new(idx, i * 16_384);
+            self.downloading.insert(block, Request::unrequested());
+            self.stalled.insert(block);
+        }
+        true
+    }
+
     pub fn have_block(&mut self, b: Block) -> bool {
         !self.downloading.contains_key(&b)
     }
@@ -258,9 +477,16 @@ impl Picker {
         }
         if self.blocks.is_empty() {
             self.blocks = vec![(0, 0); self.priorities.len()];
+            self.count_unrequested();
         }
+        if self.blocks[idx as usize].1 > 0 {
+            self.progressed.insert(idx);
+        }
+        self.unrequested -= self.piece_unrequested(idx);
         self.blocks[idx as usize] = (0, 0);
         self.unpicked.unset_bit(u64::from(idx));
+        self.unrequested += self.piece_unrequested(idx);
+        self.order_pos = 0;
     }
 
     pub fn piece_available(&mut self, idx: u32) {
@@ -286,15 +512,7 @@ impl Picker {
         }
 
         for req in self.downloading.values_mut() {
-            if let Some((idx, _)) = req
-                .reqd_from
-                .iter()
-                .enumerate()
-                .find(|&(_, id)| *id == peer.id())
-            {
-                req.num_reqd -= 1;
-                req.reqd_from[idx] = req.reqd_from[req.num_reqd];
-            }
+            req.reqd_from.retain(|&id| id != peer.id());
         }
     }
 
@@ -313,6 +531,7 @@ impl Picker {
         self.unapply_priorities();
         self.priorities = generate_piece_pri(pri, info);
         self.apply_priorities();
+        self.count_unrequested();
     }
 
     pub fn apply_priorities(&mut self) {
@@ -381,6 +600,7 @@ impl Picker {
             &Arc::new(info.clone()),
             pieces,
             &vec![3u8; info.files.len()],
+            3,
         )
     }
 
@@ -389,6 +609,7 @@ impl Picker {
             &Arc::new(info.clone()),
             pieces,
             &vec![3u8; info.files.len()],
+            3,
         );
         p.change_picker(true);
         p
@@ -403,31 +624,23 @@ impl Block {
 
 impl Request {
     fn new(peer: usize, rank: usize) -> Request {
-        let mut reqd_from = [0; MAX_DUP_REQS];
-        reqd_from[0] = peer;
         Request {
             rank,
-            requested_at: time::Instant::now(),
-            reqd_from,
-            num_reqd: 1,
+            reqd_from: vec![peer],
         }
     }
 
-    fn rereq(&mut self, peer: usize, rank: usize) {
-        self.rank = rank;
-        self.reqd_from[self.num_reqd] = peer;
-        self.num_reqd += 1;
-        self.requested_at = time::Instant::now();
+    /// A block which must be downloaded, but hasn't been requested from anyone yet.
+    fn unrequested() -> Request {
+        Request {
+            rank: 0,
+            reqd_from: Vec::new(),
+        }
     }
 
-    fn force_rereq(&mut self, peer: usize, rank: usize) {
-        if self.num_reqd < MAX_DUP_REQS {
-            self.rereq(peer, rank);
-        } else {
-            self.reqd_from[0] = peer;
-            self.requested_at = time::Instant::now();
-            self.rank = rank;
-        }
+    fn rereq(&mut self, peer: usize, rank: usize) {
+        self.rank = rank;
+        self.reqd_from.push(peer);
     }
 
     fn has_peer(&self, peer: usize) -> bool {
@@ -273,3 +273,175 @@ fn test_seq_picker() {
 
     assert_eq!(p.pick(&mut peer), Some(Block::new(5, 0)));
 }
+
+#[test]
+fn test_block_progress() {
+    let mut info = Info::with_pieces(12);
+    info.piece_len *= 4;
+    info.hashes.truncate(3);
+    info.piece_idx = Info::generate_piece_idx(3, info.piece_len as u64, &info.files);
+    let mut p = Picker::new_sequential(&info, &Bitfield::new(3));
+    let mut pb = Bitfield::new(3);
+    for i in 0..3 {
+        pb.set_bit(i);
+    }
+    let mut peer = TPeer::test_from_pieces(0, pb);
+    for _ in 0..8 {
+        assert!(p.pick(&mut peer).is_some());
+    }
+    assert!(p.take_progress().is_empty());
+
+    let block = |i, b: u32| Block::new(i, b * 16_384);
+    assert_eq!(p.completed(block(0, 0), |_| {}), Ok(false));
+    assert_eq!(p.completed(block(0, 1), |_| {}), Ok(false));
+    assert_eq!(p.completed(block(1, 3), |_| {}), Ok(false));
+    assert_eq!(p.take_progress(), vec![(0, 2, 4), (1, 1, 4)]);
+    // Nothing changed since the last call
+    assert!(p.take_progress().is_empty());
+
+    assert_eq!(p.completed(block(0, 2), |_| {}), Ok(false));
+    assert_eq!(p.completed(block(0, 3), |_| {}), Ok(true));
+    assert_eq!(p.take_progress(), vec![(0, 4, 4)]);
+
+    // Pieces which fail validation are reported as reset
+    p.invalidate_piece(1);
+    assert_eq!(p.take_progress(), vec![(1, 0, 4)]);
+    p.invalidate_piece(2);
+    assert!(p.take_progress().is_empty());
+}
+
+#[test]
+fn test_endgame_dup_cap() {
+    let mut info = Info::with_pieces(4);
+    info.piece_idx = Info::generate_piece_idx(4, info.piece_len as u64, &info.files);
+    let mut p = Picker::new_sequential(&info, &Bitfield::new(4));
+    let mut peers: Vec<_> = (0..6)
+        .map(|id| TPeer::test_from_pieces(id, Bitfield::from(&[0xF0], 4)))
+        .collect();
+    let picked: Vec<_> = std::iter::from_fn(|| p.pick(&mut peers[0])).collect();
+    assert_eq!(picked.len(), 4);
+
+    // Blocks in flight aren't requested again until the rest fit in the pipelines
+    assert_eq!(p.pick(&mut peers[1]), None);
+    p.tick(4);
+    assert!(!p.endgame());
+    assert_eq!(p.pick(&mut peers[1]), None);
+    p.tick(5);
+    assert!(p.endgame());
+
+    let mut requests = HashMap::new();
+    for peer in &mut peers[1..] {
+        while let Some(block) = p.pick(peer) {
+            *requests.entry(block).or_insert(1) += 1;
+        }
+    }
+    assert_eq!(requests.len(), 4);
+    assert!(requests.values().all(|&n| n == 3));
+
+    // Every other requester of a block is cancelled once it arrives
+    let mut cancelled = Vec::new();
+    assert_eq!(p.completed(picked[0], |id| cancelled.push(id)), Ok(true));
+    cancelled.sort_unstable();
+    assert_eq!(cancelled, [0, 1, 2]);
+
+    // A peer dropping out frees up its slots for another
+    p.remove_peer(&peers[1]);
+    assert_eq!(std::iter::from_fn(|| p.pick(&mut peers[3])).count(), 3);
+    assert!(p.pick(&mut peers[4]).is_none());
+}
+
+#[test]
+fn test_file_order() {
+    // Pieces 0-2 hold file 0, 2-4 file 1 and 4-5 file 2
+    let info = Info::with_files(16_384, &[40_960, 32_768, 24_576]);
+    let mut p = Picker::new_rarest(&info, &Bitfield::new(6));
+    p.set_order(&[2, 0], &info);
+    let mut peers: Vec<_> = (0..2)
+        .map(|id| TPeer::test_from_pieces(id, Bitfield::from(&[0xFC], 6)))
+        .collect();
+    let block = |i| Block::new(i, 0);
+
+    let picked: Vec<_> = std::iter::from_fn(|| p.pick(&mut peers[0])).collect();
+    assert_eq!(picked, [block(4), block(5)]);
+    // Nothing past the file is picked before it's done, other peers share its requests
+    let dup = p.pick(&mut peers[1]).unwrap();
+    assert!(picked.contains(&dup));
+    for b in picked {
+        assert_eq!(p.completed(b, |_| {}), Ok(true));
+    }
+    assert_eq!(p.pick(&mut peers[0]), Some(block(0)));
+
+    // A file earlier in the order which loses a piece is back in front
+    p.invalidate_piece(5);
+    assert_eq!(p.pick(&mut peers[0]), Some(block(5)));
+    p.completed(block(5), |_| {}).unwrap();
+
+    // The piece shared with file 1 comes with file 0
+    let picked: Vec<_> = std::iter::from_fn(|| p.pick(&mut peers[0])).collect();
+    assert_eq!(picked, [block(1), block(2)]);
+    for b in [block(0), block(1), block(2)] {
+        p.completed(b, |_| {}).unwrap();
+    }
+
+    // The rest is picked as usual once the listed files are done
+    assert_eq!(p.pick(&mut peers[0]), Some(block(3)));
+    assert_eq!(p.pick(&mut peers[0]), None);
+}
+
+#[test]
+fn test_stalled_rereq_cap() {
+    let mut info = Info::with_pieces(1);
+    info.piece_idx = Info::generate_piece_idx(1, info.piece_len as u64, &info.files);
+    let mut p = Picker::new_sequential(&info, &Bitfield::new(1));
+    let mut peers: Vec<_> = (0..=super::MAX_BLOCK_REQS)
+        .map(|id| TPeer::test_from_pieces(id, Bitfield::from(&[0x80], 1)))
+        .collect();
+    let block = p.pick(&mut peers[0]).unwrap();
+
+    // Every time the block stalls it's requested from one more peer, up to the cap
+    for peer in &mut peers[1..super::MAX_BLOCK_REQS] {
+        p.stalled.insert(block);
+        assert_eq!(p.pick(peer), Some(block));
+    }
+    p.stalled.insert(block);
+    assert_eq!(p.pick(&mut peers[super::MAX_BLOCK_REQS]), None);
+    // Until one of them disconnects
+    p.remove_peer(&peers[0]);
+    assert_eq!(p.pick(&mut peers[super::MAX_BLOCK_REQS]), Some(block));
+}
+
+#[test]
+fn test_remaining_count() {
+    let mut info = Info::with_pieces(12);
+    info.piece_len *= 4;
+    info.hashes.truncate(3);
+    info.piece_idx = Info::generate_piece_idx(3, info.piece_len as u64, &info.files);
+    let mut p = Picker::new_sequential(&info, &Bitfield::new(3));
+    let mut peer = TPeer::test_from_pieces(0, Bitfield::from(&[0xE0], 3));
+    let block = |i, b: u32| Block::new(i, b * 16_384);
+    // The running count must match counting over every piece
+    let check = |p: &Picker, remaining| {
+        let unrequested: usize = (0..3).map(|idx| p.piece_unrequested(idx)).sum();
+        assert_eq!(p.unrequested, unrequested);
+        assert_eq!(p.remaining(), remaining);
+    };
+    check(&p, 12);
+
+    for _ in 0..5 {
+        p.pick(&mut peer).unwrap();
+    }
+    check(&p, 12);
+    p.completed(block(0, 0), |_| {}).unwrap();
+    p.completed(block(0, 1), |_| {}).unwrap();
+    check(&p, 10);
+
+    assert!(p.restore(2, &Bitfield::from(&[0xC0], 4)));
+    check(&p, 8);
+    p.completed(block(0, 2), |_| {}).unwrap();
+    p.completed(block(0, 3), |_| {}).unwrap();
+    check(&p, 6);
+    p.invalidate_piece(0);
+    check(&p, 10);
+    p.tick(11);
+    assert!(p.endgame());
+}
@@ -0,0 +1,300 @@
+use std::slice;
+use std::sync::Arc;
+use std::time::Instant;
+
+use chrono::{DateTime, Utc};
+use url::Url;
+
+use super::{Tracker, TrackerStatus};
+use crate::util;
+use crate::util::http::Headers;
+
+/// A torrent's trackers, grouped into the announce tiers of BEP 12.
+/// Trackers are kept ordered by tier, and within a tier by preference.
+/// Announces start at the first tier and only move on to the next once
+/// every tracker in the current one has failed.
+pub struct Tiers {
+    trackers: Vec<Tracker>,
+    /// Index of the tracker announces are currently sent to
+    cur: usize,
+}
+
+impl Tiers {
+    pub fn new<I, T>(tiers: I) -> Tiers
+    where
+        I: IntoIterator<Item = T>,
+        T: IntoIterator<Item = Arc<Url>>,
+    {
+        let mut trackers = Vec::new();
+        for (tier, urls) in tiers.into_iter().enumerate() {
+            trackers.extend(urls.into_iter().map(|url| Tracker::new(url, tier)));
+        }
+        Tiers { trackers, cur: 0 }
+    }
+
+    /// The tracker which should receive the next announce.
+    pub fn current(&self) -> Option<&Tracker> {
+        self.trackers.get(self.cur)
+    }
+
+    pub fn current_mut(&mut self) -> Option<&mut Tracker> {
+        self.trackers.get_mut(self.cur)
+    }
+
+    pub fn iter(&self) -> slice::Iter<'_, Tracker> {
+        self.trackers.iter()
+    }
+
+    pub fn iter_mut(&mut self) -> slice::IterMut<'_, Tracker> {
+        self.trackers.iter_mut()
+    }
+
+    pub fn len(&self) -> usize {
+        self.trackers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.trackers.is_empty()
+    }
+
+    pub fn get(&self, idx: usize) -> Option<&Tracker> {
+        self.trackers.get(idx)
+    }
+
+    pub fn find_mut(&mut self, url: &Url) -> Option<&mut Tracker> {
+        self.trackers.iter_mut().find(|t| &*t.url == url)
+    }
+
+    pub fn position(&self, url: &Url) -> Option<usize> {
+        self.trackers.iter().position(|t| &*t.url == url)
+    }
+
+    pub fn find_id(&self, id: &str) -> Option<&Tracker> {
+        self.trackers.iter().find(|t| t.id == id)
+    }
+
+    pub fn find_id_mut(&mut self, id: &str) -> Option<&mut Tracker> {
+        self.trackers.iter_mut().find(|t| t.id == id)
+    }
+
+    pub fn position_id(&self, id: &str) -> Option<usize> {
+        self.trackers.iter().position(|t| t.id == id)
+    }
+
+    /// Promotes the tracker to the front of its tier after it responded,
+    /// making it current.
+    pub fn succeeded(&mut self, url: &Url) {
+        let Some(idx) = self.position(url) else {
+            return;
+        };
+        let tier = self.trackers[idx].tier;
+        let front = self
+            .trackers
+            .iter()
+            .position(|t| t.tier == tier)
+            .unwrap_or(idx);
+        self.trackers[front..=idx].rotate_right(1);
+        self.cur = front;
+    }
+
+    /// Moves past the current tracker if it was the one which failed.
+    /// Returns true if there is another tracker left to try, otherwise
+    /// the next announce will start over from the first tier.
+    pub fn failed(&mut self, url: &Url) -> bool {
+        if self.current().is_none_or(|t| &*t.url != url) {
+            return false;
+        }
+        self.cur += 1;
+        if self.cur < self.trackers.len() {
+            true
+        } else {
+            self.cur = 0;
+            false
+        }
+    }
+
+    /// Starts the next announce from the first tier.
+    pub fn rewind(&mut self) {
+        self.cur = 0;
+    }
+
+    /// Adds a tracker to the front of the first tier, returning its index.
+    pub fn add(&mut self, url: Url) -> usize {
+        let tier = self.trackers.first().map(|t| t.tier).unwrap_or(0);
+        self.trackers.insert(0, Tracker::new(Arc::new(url), tier));
+        self.cur = 0;
+        0
+    }
+
+    pub fn remove(&mut self, idx: usize) {
+        self.trackers.remove(idx);
+        if self.cur > idx || self.cur >= self.trackers.len() {
+            self.cur = self.cur.saturating_sub(1);
+        }
+    }
+
+    /// The tracker urls grouped by tier, in announce order.
+    pub fn urls(&self) -> Vec<Vec<String>> {
+        let mut tiers: Vec<Vec<String>> = Vec::new();
+        let mut prev = None;
+        for t in &self.trackers {
+            if prev != Some(t.tier) {
+                tiers.push(Vec::new());
+                prev = Some(t.tier);
+            }
+            if let Some(tier) = tiers.last_mut() {
+                tier.push(t.url.as_str().to_owned());
+            }
+        }
+        tiers
+    }
+
+    /// The id of each tracker, by url.
+    pub fn ids(&self) -> Vec<(String, String)> {
+        self.trackers
+            .iter()
+            .map(|t| (t.url.as_str().to_owned(), t.id.clone()))
+            .collect()
+    }
+
+    /// The extra announce headers of each tracker which has any, by url.
+    pub fn headers(&self) -> Vec<(String, Vec<String>)> {
+        self.trackers
+            .iter()
+            .filter(|t| !t.headers.is_empty())
+            .map(|t| (t.url.as_str().to_owned(), t.headers.lines()))
+            .collect()
+    }
+}
+
+impl Tracker {
+    fn new(url: Arc<Url>, tier: usize) -> Tracker {
+        Tracker {
+            id: util::random_rpc_id(),
+            url,
+            tier,
+            status: TrackerStatus::Updating,
+            last_announce: Utc::now(),
+            update: None,
+            min_update: None,
+            failures: 0,
+            retry_at: None,
+            headers: Headers::default(),
+        }
+    }
+
+    /// When the tracker is next announced to, now if an announce is due or in flight.
+    pub fn next_announce(&self) -> DateTime<Utc> {
+        let now = Utc::now();
+        self.update
+            .and_then(|at| {
+                chrono::Duration::from_std(at.saturating_duration_since(Instant::now())).ok()
+            })
+            .map_or(now, |wait| now + wait)
+    }
+
+    /// Seeders, leechers and interval reported by the last successful announce.
+    pub fn swarm(&self) -> (Option<u32>, Option<u32>, Option<u32>) {
+        match self.status {
+            TrackerStatus::Ok {
+                seeders,
+                leechers,
+                interval,
+            } => (Some(seeders), Some(leechers), Some(interval)),
+            _ => (None, None, None),
+        }
+    }
+
+    pub fn error(&self) -> Option<String> {
+        match self.status {
+            TrackerStatus::Failure(ref r) => Some(r.clone()),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use url::Url;
+
+    use super::Tiers;
+
+    fn url(s: &str) -> Url {
+        Url::parse(&format!("http://{s}/announce")).unwrap()
+    }
+
+    fn tiers(t: &[&[&str]]) -> Tiers {
+        Tiers::new(
+            t.iter()
+                .map(|tier| tier.iter().map(|s| Arc::new(url(s))).collect::<Vec<_>>()),
+        )
+    }
+
+    fn current(t: &Tiers) -> &str {
+        t.current().unwrap().url.host_str().unwrap()
+    }
+
+    #[test]
+    fn test_fallback() {
+        let mut t = tiers(&[&["a", "b"], &["c", "d"]]);
+        assert_eq!(current(&t), "a");
+
+        // A dead first tier falls through to the second
+        assert!(t.failed(&url("a")));
+        assert_eq!(current(&t), "b");
+        assert!(t.failed(&url("b")));
+        assert_eq!(current(&t), "c");
+        assert!(t.failed(&url("c")));
+        assert_eq!(current(&t), "d");
+
+        // The responding tracker is promoted within its tier only
+        t.succeeded(&url("d"));
+        assert_eq!(current(&t), "d");
+        assert_eq!(t.urls(), tiers(&[&["a", "b"], &["d", "c"]]).urls());
+
+        // Subsequent announces begin at the first tier again
+        t.rewind();
+        assert_eq!(current(&t), "a");
+        assert!(t.failed(&url("a")));
+        assert!(t.failed(&url("b")));
+        assert_eq!(current(&t), "d");
+
+        // Once every tracker fails, the next announce starts over
+        assert!(t.failed(&url("d")));
+        assert!(!t.failed(&url("c")));
+        assert_eq!(current(&t), "a");
+    }
+
+    #[test]
+    fn test_failed_other() {
+        let mut t = tiers(&[&["a"], &["b"]]);
+        // Failures from trackers announced to manually don't advance
+        assert!(!t.failed(&url("b")));
+        assert_eq!(current(&t), "a");
+    }
+
+    #[test]
+    fn test_add_remove() {
+        let mut t = tiers(&[&["a"], &["b", "c"]]);
+        assert!(t.failed(&url("a")));
+        assert!(t.failed(&url("b")));
+        t.remove(0);
+        assert_eq!(current(&t), "c");
+        t.add(url("d"));
+        assert_eq!(current(&t), "d");
+        assert_eq!(
+            t.urls(),
+            vec![vec![
+                url("d").to_string(),
+                url("b").to_string(),
+                url("c").to_string()
+            ]]
+        );
+        t.remove(2);
+        t.remove(1);
+        t.remove(0);
+        assert!(t.current().is_none());
+    }
+}
@@ -1,10 +1,12 @@
 use std::fs::OpenOptions;
 use std::io::{self, Read};
-use std::net::{SocketAddr, UdpSocket};
-use std::path::Path;
+use std::iter;
+use std::net::{Ipv6Addr, SocketAddr, UdpSocket};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time;
 
+use net2::UdpBuilder;
 use num_bigint::BigUint;
 
 use crate::config::Config;
@@ -20,19 +22,31 @@ const BUCKET_MAX: usize = 8;
 const MAX_BUCKETS: usize = 512;
 const VERSION: &str = "SY";
 const SESSION_FILE: &str = "dht_data";
+const SESSION_FILE6: &str = "dht_data6";
 const MIN_BOOTSTRAP_BKTS: usize = 32;
 const TX_TIMEOUT_SECS: i64 = 20;
 
 pub struct Manager {
-    config: Arc<Config>,
-    id: usize,
-    table: rt::RoutingTable,
+    dht: Dht,
+    /// The IPv6 DHT, which has its own routing table (BEP 32)
+    dht6: Option<Dht>,
     dht_flush: time::Instant,
-    sock: UdpSocket,
+    /// Port given in announce_peer, the external one if it's forwarded
+    announce_port: u16,
     buf: Vec<u8>,
     db: flume::Sender<disk::Request>,
 }
 
+/// A routing table and the socket its nodes are reached over.
+struct Dht {
+    id: usize,
+    table: rt::RoutingTable,
+    sock: UdpSocket,
+    session: PathBuf,
+    /// Whether queries are flagged so other nodes don't route to us (BEP 43)
+    read_only: bool,
+}
+
 impl Manager {
     pub fn new(
         config: Arc<Config>,
@@ -40,16 +54,119 @@ impl Manager {
         db: flume::Sender<disk::Request>,
     ) -> io::Result<Manager> {
         let sock = UdpSocket::bind(("0.0.0.0", config.dht.port))?;
-        sock.set_nonblocking(true)?;
-        let id = reg.register(&sock, amy::Event::Read)?;
+        let dht = Dht::new(sock, reg, &config, SESSION_FILE, config.dht.bootstrap_node)?;
         // Turn off DHT if no bootstrap is specified.
         if config.dht.bootstrap_node.is_none() {
-            reg.deregister(&sock)?;
+            reg.deregister(&dht.sock)?;
         }
+        let dht6 = match config.dht.bootstrap_node6 {
+            Some(node) => {
+                let sock = UdpBuilder::new_v6()?
+                    .only_v6(true)?
+                    .bind((Ipv6Addr::UNSPECIFIED, config.dht.port))?;
+                Some(Dht::new(sock, reg, &config, SESSION_FILE6, Some(node))?)
+            }
+            None => None,
+        };
 
-        let p = Path::new(&config.disk.session[..]).join(SESSION_FILE);
+        Ok(Manager {
+            announce_port: config.dht.port,
+            dht,
+            dht6,
+            db,
+            buf: vec![0u8; 500],
+            dht_flush: time::Instant::now(),
+        })
+    }
+
+    pub fn init(&mut self) {
+        debug!("Initializing DHT nodes!");
+        for dht in self.dhts() {
+            for (q, a) in dht.table.init() {
+                dht.send_req(q, a);
+            }
+        }
+    }
+
+    pub fn contains(&self, id: usize) -> bool {
+        self.dht.id == id || self.dht6.as_ref().is_some_and(|d| d.id == id)
+    }
+
+    pub fn readable(&mut self, id: usize) -> Vec<tracker::Response> {
+        let buf = &mut self.buf;
+        match self.dht6.as_mut() {
+            Some(dht) if dht.id == id => dht.readable(buf),
+            _ => self.dht.readable(buf),
+        }
+    }
+
+    pub fn get_peers(&mut self, tid: usize, hash: [u8; 20]) {
+        for dht in self.dhts() {
+            for (req, a) in dht.table.get_peers(tid, hash) {
+                dht.send_req(req, a);
+            }
+        }
+    }
+
+    pub fn add_addr(&mut self, addr: SocketAddr) {
+        let dht = if addr.is_ipv4() {
+            Some(&mut self.dht)
+        } else {
+            self.dht6.as_mut()
+        };
+        if let Some(dht) = dht {
+            dht.table.add_addr(addr);
+        }
+    }
+
+    pub fn set_announce_port(&mut self, port: u16) {
+        self.announce_port = port;
+    }
+
+    pub fn announce(&mut self, hash: [u8; 20]) {
+        let port = self.announce_port;
+        for dht in self.dhts() {
+            for (req, a) in dht.table.announce(hash, port) {
+                dht.send_req(req, a);
+            }
+        }
+    }
+
+    pub fn tick(&mut self) {
+        if self.dht_flush.elapsed() > time::Duration::from_secs(60) {
+            for dht in iter::once(&self.dht).chain(&self.dht6) {
+                let data = dht.table.serialize();
+                let path = dht.session.clone();
+                self.db.send(disk::Request::WriteFile { data, path }).ok();
+            }
+            self.dht_flush = time::Instant::now();
+        }
+        for dht in self.dhts() {
+            for (req, a) in dht.table.tick() {
+                dht.send_req(req, a);
+            }
+        }
+    }
+
+    fn dhts(&mut self) -> impl Iterator<Item = &mut Dht> {
+        iter::once(&mut self.dht).chain(&mut self.dht6)
+    }
+}
+
+impl Dht {
+    fn new(
+        sock: UdpSocket,
+        reg: &amy::Registrar,
+        config: &Config,
+        session: &str,
+        bootstrap_node: Option<SocketAddr>,
+    ) -> io::Result<Dht> {
+        sock.set_nonblocking(true)?;
+        let id = reg.register(&sock, amy::Event::Read)?;
+
+        let session = Path::new(&config.disk.session[..]).join(session);
         let mut data = Vec::new();
-        if let Ok(mut f) = OpenOptions::new().read(true).open(&p) {
+        if let Ok(mut f) = OpenOptions::new().read(true).open(&session) {
             f.read_to_end(&mut data)?;
         }
         let mut table = if let Some(t) = rt::RoutingTable::deserialize(&data[..]) {
@@ -58,54 +175,40 @@ impl Manager {
             info!("DHT table could not be read from disk, creating new table!");
             rt::RoutingTable::new()
         };
+        let read_only = config.dht.read_only;
         if !table.is_bootstrapped() {
-            info!(
-                "Attempting DHT bootstrap with node: {:?}!",
-                config.dht.bootstrap_node
-            );
-            if let Some(addr) = config.dht.bootstrap_node {
-                let (msg, _) = table.add_addr(addr);
+            info!("Attempting DHT bootstrap with node: {:?}!", bootstrap_node);
+            if let Some(addr) = bootstrap_node {
+                let (mut msg, _) = table.add_addr(addr);
+                msg.read_only = read_only;
                 let _bootstrap_result = sock.send_to(&msg.encode(), addr);
             }
         }
 
-        Ok(Manager {
-            config,
+        Ok(Dht {
+            id,
             table,
             sock,
-            id,
-            db,
-            buf: vec![0u8; 500],
-            dht_flush: time::Instant::now(),
+            session,
+            read_only,
         })
     }
 
-    pub fn init(&mut self) {
-        debug!("Initializing DHT nodes!");
-        for (q, a) in self.table.init() {
-            self.send_msg(&q.encode(), a);
-        }
-    }
-
-    pub fn id(&self) -> usize {
-        self.id
-    }
-
-    pub fn readable(&mut self) -> Vec<tracker::Response> {
+    fn readable(&mut self, buf: &mut [u8]) -> Vec<tracker::Response> {
         let mut resps = Vec::new();
         loop {
-            match self.sock.recv_from(&mut self.buf[..]) {
+            match self.sock.recv_from(buf) {
                 Ok((v, addr)) => {
                     trace!("Processing msg from {}", addr);
-                    if let Ok(req) = proto::Request::decode(&self.buf[..v]) {
+                    if let Ok(req) = proto::Request::decode(&buf[..v]) {
                         let resp = self.table.handle_req(req, addr).encode();
                         self.send_msg(&resp, addr);
-                    } else if let Ok(resp) = proto::Response::decode(&self.buf[..v]) {
+                    } else if let Ok(resp) = proto::Response::decode(&buf[..v]) {
                         match self.table.handle_resp(resp, addr) {
                             Ok(r) => resps.push(r),
                             Err(q) => {
                                 for (req, a) in q {
-                                    self.send_msg(&req.encode(), a);
+                                    self.send_req(req, a);
                                 }
                             }
                         }
@@ -129,32 +232,9 @@ impl Manager {
         resps
     }
 
-    pub fn get_peers(&mut self, tid: usize, hash: [u8; 20]) {
-        for (req, a) in self.table.get_peers(tid, hash) {
-            self.send_msg(&req.encode(), a);
-        }
-    }
-
-    pub fn add_addr(&mut self, addr: SocketAddr) {
-        self.table.add_addr(addr);
-    }
-
-    pub fn announce(&mut self, hash: [u8; 20]) {
-        for (req, a) in self.table.announce(hash, self.config.dht.port) {
-            self.send_msg(&req.encode(), a);
-        }
-    }
-
-    pub fn tick(&mut self) {
-        if self.dht_flush.elapsed() > time::Duration::from_secs(60) {
-            let data = self.table.serialize();
-            let path = Path::new(&self.config.disk.session[..]).join(SESSION_FILE);
-            self.db.send(disk::Request::WriteFile { data, path }).ok();
-            self.dht_flush = time::Instant::now();
-        }
-        for (req, a) in self.table.tick() {
-            self.send_msg(&req.encode(), a);
-        }
+    fn send_req(&mut self, mut req: proto::Request, addr: SocketAddr) {
+        req.read_only = self.read_only;
+        self.send_msg(&req.encode(), addr);
     }
 
     fn send_msg(&mut self, msg: &[u8], addr: SocketAddr) {
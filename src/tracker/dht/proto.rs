@@ -9,6 +9,11 @@ use thiserror::Error;
 
 type Result<T> = std::result::Result<T, DecodeError>;
 
+/// Length of a compact IPv4 node, its ID followed by a compact address
+const NODE_LEN: usize = 26;
+/// Length of a compact IPv6 node, as sent in `nodes6` (BEP 32)
+const NODE6_LEN: usize = 38;
+
 #[derive(Debug, Error)]
 pub enum DecodeError {
     #[error("failed to decode bencode: {0}")]
@@ -25,6 +30,8 @@ pub enum DecodeError {
 pub struct Request {
     pub transaction: Vec<u8>,
     pub version: Option<String>,
+    /// Set by nodes which shouldn't be added to routing tables (BEP 43)
+    pub read_only: bool,
     pub kind: RequestKind,
 }
 
@@ -93,6 +100,7 @@ impl Request {
         Request {
             transaction,
             version: Some(VERSION.to_owned()),
+            read_only: false,
             kind: RequestKind::Ping(id),
         }
     }
@@ -101,6 +109,7 @@ impl Request {
         Request {
             transaction,
             version: Some(VERSION.to_owned()),
+            read_only: false,
             kind: RequestKind::FindNode { id, target },
         }
     }
@@ -109,6 +118,7 @@ impl Request {
         Request {
             transaction,
             version: Some(VERSION.to_owned()),
+            read_only: false,
             kind: RequestKind::GetPeers { id, hash },
         }
     }
@@ -123,6 +133,7 @@ impl Request {
         Request {
             transaction,
             version: Some(VERSION.to_owned()),
+            read_only: false,
             kind: RequestKind::AnnouncePeer {
                 id,
                 hash,
@@ -140,6 +151,9 @@ impl Request {
         if let Some(v) = self.version {
             b.insert(b"v".to_vec(), BEncode::from_str(&v));
         }
+        if self.read_only {
+            b.insert(b"ro".to_vec(), BEncode::Int(1));
+        }
         match self.kind {
             RequestKind::Ping(id) => {
                 b.insert(b"q".to_vec(), BEncode::from_str("ping"));
@@ -202,6 +216,10 @@ impl Request {
             .and_then(|b| b.into_bytes())
             .ok_or(DecodeError::MissingKey("`t`"))?;
         let version = d.remove(b"v".as_ref()).and_then(|b| b.into_string());
+        let read_only = d
+            .remove(b"ro".as_ref())
+            .and_then(|b| b.into_int())
+            .is_some_and(|ro| ro == 1);
         let y = d
             .remove(b"y".as_ref())
             .and_then(|b| b.into_string())
@@ -295,6 +313,7 @@ impl Request {
         Ok(Request {
             transaction,
             version,
+            read_only,
             kind,
         })
     }
@@ -356,11 +375,7 @@ impl Response {
                 args.insert(b"id".to_vec(), BEncode::String(id.to_bytes_be()));
             }
             ResponseKind::FindNode { id, nodes } => {
-                let mut data = Vec::new();
-                for node in nodes {
-                    data.extend(node.to_bytes())
-                }
-                args.insert(b"nodes".to_vec(), BEncode::String(data));
+                encode_nodes(&mut args, nodes);
                 args.insert(b"id".to_vec(), BEncode::String(id.to_bytes_be()));
             }
             ResponseKind::GetPeers {
@@ -373,15 +388,10 @@ impl Response {
                 args.insert(b"token".to_vec(), BEncode::String(token));
                 let mut values_b = Vec::new();
                 for addr in values {
-                    values_b.push(BEncode::String(addr_to_bytes(&addr).to_vec()));
+                    values_b.push(BEncode::String(addr_to_bytes(&addr)));
                 }
                 args.insert(b"values".to_vec(), BEncode::List(values_b));
-
-                let mut nodes_b = Vec::new();
-                for node in nodes {
-                    nodes_b.extend(node.to_bytes())
-                }
-                args.insert(b"nodes".to_vec(), BEncode::String(nodes_b));
+                encode_nodes(&mut args, nodes);
             }
             ResponseKind::Error(e) => {
                 let mut err = Vec::new();
@@ -476,34 +486,23 @@ impl Response {
                     if let Some(addrs) = r.remove(b"values".as_ref()).and_then(|b| b.into_list()) {
                         for addr in addrs {
                             if let Some(data) = addr.into_bytes()
-                                && data.len() == 6
+                                && (data.len() == 6 || data.len() == 18)
                             {
                                 values.push(bytes_to_addr(&data));
                             }
                         }
                     }
-                    let mut nodes = Vec::new();
-                    if let Some(ns) = r.remove(b"nodes".as_ref()).and_then(|b| b.into_bytes()) {
-                        for n in ns.chunks(26) {
-                            if n.len() == 26 {
-                                nodes.push(Node::new(n));
-                            }
-                        }
-                    }
                     ResponseKind::GetPeers {
                         id,
                         token,
-                        nodes,
+                        nodes: decode_nodes(&mut r),
                         values,
                     }
-                } else if let Some(ns) = r.remove(b"nodes".as_ref()).and_then(|b| b.into_bytes()) {
-                    let mut nodes = Vec::new();
-                    for n in ns.chunks(26) {
-                        if n.len() == 26 {
-                            nodes.push(Node::new(n));
-                        }
+                } else if r.contains_key(b"nodes".as_ref()) || r.contains_key(b"nodes6".as_ref()) {
+                    ResponseKind::FindNode {
+                        id,
+                        nodes: decode_nodes(&mut r),
                     }
-                    ResponseKind::FindNode { id, nodes }
                 } else {
                     ResponseKind::ID(id)
                 };
@@ -518,7 +517,35 @@ impl Response {
     }
 }
 
+/// Adds nodes to a response, split by address family into `nodes` and `nodes6`. The latter is
+/// left out if empty so responses to IPv4 only nodes are unchanged.
+fn encode_nodes(args: &mut BTreeMap<Vec<u8>, BEncode>, nodes: Vec<Node>) {
+    let (mut nodes_b, mut nodes6_b) = (Vec::new(), Vec::new());
+    for node in nodes {
+        if node.addr.is_ipv4() {
+            nodes_b.extend(node.to_bytes());
+        } else {
+            nodes6_b.extend(node.to_bytes());
+        }
+    }
+    args.insert(b"nodes".to_vec(), BEncode::String(nodes_b));
+    if !nodes6_b.is_empty() {
+        args.insert(b"nodes6".to_vec(), BEncode::String(nodes6_b));
+    }
+}
+
+fn decode_nodes(r: &mut BTreeMap<Vec<u8>, BEncode>) -> Vec<Node> {
+    let mut nodes = Vec::new();
+    for (key, len) in [(&b"nodes"[..], NODE_LEN), (&b"nodes6"[..], NODE6_LEN)] {
+        if let Some(ns) = r.remove(key).and_then(|b| b.into_bytes()) {
+            nodes.extend(ns.chunks_exact(len).map(Node::new));
+        }
+    }
+    nodes
+}
+
 impl Node {
+    /// Reads a compact node, whose address family is given by its length.
     pub fn new(data: &[u8]) -> Node {
         let id = BigUint::from_bytes_be(&data[0..20]);
         Node {
@@ -528,15 +555,19 @@ impl Node {
     }
 
     pub fn to_bytes(&self) -> Vec<u8> {
-        let mut data = self.id.to_bytes_be();
-        data.extend_from_slice(&addr_to_bytes(&self.addr)[..]);
+        // IDs with leading zero bytes still take up 20
+        let id = self.id.to_bytes_be();
+        let mut data = vec![0u8; 20usize.saturating_sub(id.len())];
+        data.extend(id);
+        data.extend(addr_to_bytes(&self.addr));
         data
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{Request, Response};
+    use super::{ID, Request, Response};
+    use crate::bencode::{self, BEncode};
 
     struct DhtProtoTest;
 
@@ -558,6 +589,24 @@ mod tests {
         }
     }
 
+    fn top_level(encoded: &[u8]) -> std::collections::BTreeMap<Vec<u8>, BEncode> {
+        bencode::decode_buf(encoded).unwrap().into_dict().unwrap()
+    }
+
+    #[test]
+    fn test_read_only() {
+        let id = ID::from_bytes_be(&[0xAA; 20]);
+        let req = Request::ping(b"aa".to_vec(), id.clone());
+        assert!(!top_level(&req.encode()).contains_key(b"ro".as_ref()));
+
+        let mut req = Request::find_node(b"aa".to_vec(), id.clone(), id);
+        req.read_only = true;
+        let encoded = req.encode();
+        let d = top_level(&encoded);
+        assert_eq!(d.get(b"ro".as_ref()).and_then(BEncode::as_int), Some(&1));
+        assert!(Request::decode(&encoded).unwrap().read_only);
+    }
+
     #[test]
     fn test_diff() {
         let mut t = DhtProtoTest;
@@ -1,14 +1,23 @@
 use super::{BUCKET_MAX, ID, MAX_BUCKETS, MIN_BOOTSTRAP_BKTS, TX_TIMEOUT_SECS, proto};
-use crate::tracker;
+use crate::{tracker, util};
 use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Duration, Utc};
 use num_bigint::BigUint;
 use rand::{self, RngExt};
 use std::collections::HashMap;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::{cmp, mem};
 
 const MAX_SEARCH_DEPTH: u8 = 5;
+/// Length of the tokens handed out in get_peers responses
+const TOKEN_LEN: usize = 8;
+/// How often the token secret is replaced. Tokens made with the previous secret are
+/// still accepted, so a token stays valid for 5 to 10 minutes.
+const TOKEN_ROTATION_MINS: i64 = 5;
+/// Most peers stored for a single infohash
+const MAX_TORRENT_PEERS: usize = 200;
+/// Most peers stored over all infohashes
+const MAX_STORED_PEERS: usize = 20_000;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct RoutingTable {
@@ -21,6 +30,15 @@ pub struct RoutingTable {
     transactions: HashMap<u32, Transaction>,
     torrents: HashMap<[u8; 20], Torrent>,
     bootstrapping: bool,
+    // Tokens are an HMAC of the requester's IP, the secrets are never saved
+    #[serde(skip, default = "secret")]
+    secret: [u8; 20],
+    #[serde(skip, default = "secret")]
+    prev_secret: [u8; 20],
+    /// Number of peers stored over all of `torrents`
+    stored_peers: usize,
+    /// Incremented on each announce, to order stored peers by recency
+    announce_seq: u64,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -41,9 +59,18 @@ enum TransactionKind {
     },
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 struct Torrent {
-    peers: Vec<(ID, SocketAddr)>,
+    /// Announced peers, least recently announced first
+    peers: Vec<StoredPeer>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct StoredPeer {
+    /// ID of the node which announced the peer
+    node: ID,
+    addr: SocketAddr,
+    seq: u64,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -60,8 +87,6 @@ pub struct Node {
     state: NodeState,
     addr: SocketAddr,
     last_updated: DateTime<Utc>,
-    token: Vec<u8>,
-    prev_token: Vec<u8>,
     rem_token: Option<Vec<u8>>,
 }
 
@@ -90,6 +115,10 @@ impl RoutingTable {
             transactions: HashMap::new(),
             torrents: HashMap::new(),
             bootstrapping: true,
+            secret: secret(),
+            prev_secret: secret(),
+            stored_peers: 0,
+            announce_seq: 0,
         }
     }
 
@@ -193,46 +222,40 @@ impl RoutingTable {
                         proto::ErrorResponse::Protocol("Unregistered peer!".to_owned()),
                     );
                 }
-                {
-                    let node = self.get_node_mut(&id);
-                    if !node.token_valid(&token) {
-                        return proto::Response::error(
-                            req.transaction,
-                            proto::ErrorResponse::Protocol("Bad token!".to_owned()),
-                        );
-                    }
-                    node.update();
+                if !self.token_valid(&token, addr.ip()) {
+                    return proto::Response::error(
+                        req.transaction,
+                        proto::ErrorResponse::Protocol("Bad token!".to_owned()),
+                    );
                 }
-                self.torrents
-                    .entry(hash)
-                    .or_insert(Torrent { peers: Vec::new() });
+                self.get_node_mut(&id).update();
                 if !implied_port {
                     addr.set_port(port);
                 }
-                self.torrents.get_mut(&hash).unwrap().peers.push((id, addr));
+                self.store_peer(hash, id, addr);
                 proto::Response::id(req.transaction, self.id.clone())
             }
             proto::RequestKind::GetPeers { id, hash } => {
-                if !self.contains_id(&id) {
+                // Read only nodes get an answer, but are kept out of the table
+                if !req.read_only && !self.contains_id(&id) {
                     let n = Node::new(id.clone(), addr);
                     if self.add_node(n).is_err() {
                         // This will be processed immediately after.
                     }
                 }
-                let token = if !self.contains_id(&id) {
+                if !req.read_only && !self.contains_id(&id) {
                     return proto::Response::error(
                         req.transaction,
                         proto::ErrorResponse::Protocol("Unregistered peer!".to_owned()),
                     );
-                } else {
-                    self.get_node(&id).token.clone()
-                };
+                }
+                let token = make_token(&self.secret, addr.ip());
                 if let Some(t) = self.torrents.get(&hash) {
                     proto::Response::peers(
                         req.transaction,
                         self.id.clone(),
                         token,
-                        t.peers.iter().map(|p| p.1).collect(),
+                        t.peers.iter().map(|p| p.addr).collect(),
                     )
                 } else {
                     let mut nodes = Vec::new();
@@ -301,7 +324,11 @@ impl RoutingTable {
                     return Err(reqs);
                 }
                 self.get_node_mut(id1).update();
-                for node in nodes.drain(..) {
+                // Nodes of the other address family belong in the other table
+                for node in nodes
+                    .drain(..)
+                    .filter(|n| n.addr.is_ipv4() == addr.is_ipv4())
+                {
                     if !self.contains_id(&node.id) {
                         let id = node.id.clone();
                         let addr = node.addr;
@@ -366,7 +393,10 @@ impl RoutingTable {
                 }
 
                 if depth < MAX_SEARCH_DEPTH {
-                    for node in nodes.drain(..) {
+                    for node in nodes
+                        .drain(..)
+                        .filter(|n| n.addr.is_ipv4() == addr.is_ipv4())
+                    {
                         let id = node.id.clone();
                         let addr = node.addr;
                         if !self.contains_id(&node.id) {
@@ -419,14 +449,10 @@ impl RoutingTable {
             Utc::now().signed_duration_since(tx.created).num_seconds() < TX_TIMEOUT_SECS
         });
 
-        let dur = Utc::now().signed_duration_since(self.last_token_refresh);
-        let tok_refresh = dur.num_minutes() > 5;
+        self.rotate_secret(Utc::now());
 
         for bucket in &mut self.buckets {
             for node in &mut bucket.nodes {
-                if tok_refresh {
-                    node.new_token();
-                }
                 let dur = Utc::now().signed_duration_since(node.last_updated);
                 if dur.num_minutes() > 15 {
                     if node.good() {
@@ -448,14 +474,65 @@ impl RoutingTable {
         let buckets = &self.buckets;
         self.torrents.retain(|_, t| {
             t.peers.retain(|p| {
-                let idx = RoutingTable::bucket_idx_(&p.0, buckets);
-                buckets[idx].contains(&p.0)
+                let idx = RoutingTable::bucket_idx_(&p.node, buckets);
+                buckets[idx].contains(&p.node)
             });
             !t.peers.is_empty()
         });
+        self.stored_peers = self.torrents.values().map(|t| t.peers.len()).sum();
         reqs
     }
 
+    /// Replaces the token secret once it's been in use for `TOKEN_ROTATION_MINS`.
+    fn rotate_secret(&mut self, now: DateTime<Utc>) {
+        if now.signed_duration_since(self.last_token_refresh)
+            < Duration::minutes(TOKEN_ROTATION_MINS)
+        {
+            return;
+        }
+        self.prev_secret = mem::replace(&mut self.secret, secret());
+        self.last_token_refresh = now;
+    }
+
+    fn token_valid(&self, token: &[u8], ip: IpAddr) -> bool {
+        token == make_token(&self.secret, ip) || token == make_token(&self.prev_secret, ip)
+    }
+
+    /// Stores a peer announced for `hash`, evicting the least recently announced peer of
+    /// the infohash or of all of them when full.
+    fn store_peer(&mut self, hash: [u8; 20], node: ID, addr: SocketAddr) {
+        self.announce_seq += 1;
+        let t = self.torrents.entry(hash).or_default();
+        if let Some(i) = t.peers.iter().position(|p| p.addr == addr) {
+            t.peers.remove(i);
+            self.stored_peers -= 1;
+        } else if t.peers.len() >= MAX_TORRENT_PEERS {
+            t.peers.remove(0);
+            self.stored_peers -= 1;
+        }
+        t.peers.push(StoredPeer {
+            node,
+            addr,
+            seq: self.announce_seq,
+        });
+        self.stored_peers += 1;
+
+        if self.stored_peers > MAX_STORED_PEERS {
+            let oldest = self
+                .torrents
+                .iter()
+                .min_by_key(|(_, t)| t.peers[0].seq)
+                .map(|(hash, _)| *hash)
+                .unwrap();
+            let t = self.torrents.get_mut(&oldest).unwrap();
+            t.peers.remove(0);
+            if t.peers.is_empty() {
+                self.torrents.remove(&oldest);
+            }
+            self.stored_peers -= 1;
+        }
+    }
+
     pub fn serialize(&self) -> Vec<u8> {
         bincode::serialize(self).unwrap()
     }
@@ -464,13 +541,12 @@ impl RoutingTable {
         self.buckets.len() >= MIN_BOOTSTRAP_BKTS
     }
 
-    /// Send a bogus get_peers query and internally refresh our token.
+    /// Sends a bogus get_peers query to every node, to get tokens for announcing to them.
     fn refresh_tokens(&mut self) -> Vec<(proto::Request, SocketAddr)> {
         let mut nodes: Vec<proto::Node> = Vec::new();
-        for bucket in &mut self.buckets {
-            for node in &mut bucket.nodes {
-                node.new_token();
-                nodes.push((&*node).into());
+        for bucket in &self.buckets {
+            for node in &bucket.nodes {
+                nodes.push(node.into());
             }
         }
 
@@ -651,15 +727,12 @@ impl Bucket {
 
 impl Node {
     fn new(id: ID, addr: SocketAddr) -> Node {
-        let token = Node::create_token();
         Node {
             id,
             state: NodeState::Bad,
             addr,
             last_updated: Utc::now(),
-            prev_token: token.clone(),
             rem_token: None,
-            token,
         }
     }
 
@@ -672,24 +745,6 @@ impl Node {
         matches!(self.state, NodeState::Good)
     }
 
-    fn new_token(&mut self) {
-        let new_prev = mem::replace(&mut self.token, Node::create_token());
-        self.prev_token = new_prev;
-    }
-
-    fn token_valid(&self, token: &[u8]) -> bool {
-        token == &self.token[..] || token == &self.prev_token[..]
-    }
-
-    fn create_token() -> Vec<u8> {
-        let mut tok = Vec::new();
-        let mut rng = rand::rng();
-        for _ in 0..20 {
-            tok.push(rng.random::<u8>());
-        }
-        tok
-    }
-
     fn update(&mut self) {
         self.state = NodeState::Good;
         self.last_updated = Utc::now();
@@ -711,6 +766,18 @@ impl From<&Node> for proto::Node {
     }
 }
 
+fn secret() -> [u8; 20] {
+    rand::rng().random()
+}
+
+fn make_token(secret: &[u8; 20], ip: IpAddr) -> Vec<u8> {
+    let ip = match ip.to_canonical() {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+    util::hmac_sha1(secret, &ip)[..TOKEN_LEN].to_vec()
+}
+
 /// creates an ID of value 2^(pow)
 fn id_from_pow(pow: usize) -> ID {
     let mut id = [0u8; 21];
@@ -723,8 +790,14 @@ fn id_from_pow(pow: usize) -> ID {
 
 #[cfg(test)]
 mod tests {
-    use super::{Bucket, Node, RoutingTable, id_from_pow};
+    use super::{
+        Bucket, MAX_STORED_PEERS, MAX_TORRENT_PEERS, Node, RoutingTable, TOKEN_ROTATION_MINS,
+        id_from_pow,
+    };
+    use crate::tracker::dht::proto;
+    use chrono::Duration;
     use num_bigint::BigUint;
+    use std::net::SocketAddr;
 
     #[test]
     fn test_id_from_pow() {
@@ -758,4 +831,116 @@ mod tests {
         assert_eq!(rt.buckets[0].nodes.len(), 0);
         assert_eq!(rt.buckets[1].nodes.len(), 8);
     }
+
+    #[test]
+    fn test_read_only_get_peers() {
+        let mut rt = RoutingTable::new();
+        let addr = "127.0.0.1:6881".parse().unwrap();
+        let mut req = proto::Request::get_peers(b"aaaa".to_vec(), id_from_pow(100), [0; 20]);
+        req.read_only = true;
+        match rt.handle_req(req, addr).kind {
+            proto::ResponseKind::FindNode { .. } | proto::ResponseKind::GetPeers { .. } => {}
+            k => panic!("unexpected response {:?}", k),
+        }
+        assert!(!rt.contains_id(&id_from_pow(100)));
+
+        let req = proto::Request::get_peers(b"aaaa".to_vec(), id_from_pow(100), [0; 20]);
+        rt.handle_req(req, addr);
+        assert!(rt.contains_id(&id_from_pow(100)));
+    }
+
+    fn get_token(rt: &mut RoutingTable, addr: SocketAddr) -> Vec<u8> {
+        let req = proto::Request::get_peers(b"aaaa".to_vec(), id_from_pow(100), [0; 20]);
+        match rt.handle_req(req, addr).kind {
+            proto::ResponseKind::GetPeers { token, .. } => token,
+            k => panic!("unexpected response {:?}", k),
+        }
+    }
+
+    fn announce(rt: &mut RoutingTable, addr: SocketAddr, token: Vec<u8>) -> bool {
+        let req = proto::Request::announce(b"aaaa".to_vec(), id_from_pow(100), [0; 20], token, 1);
+        match rt.handle_req(req, addr).kind {
+            proto::ResponseKind::ID(_) => true,
+            proto::ResponseKind::Error(proto::ErrorResponse::Protocol(_)) => false,
+            k => panic!("unexpected response {:?}", k),
+        }
+    }
+
+    #[test]
+    fn test_token_rotation() {
+        let mut rt = RoutingTable::new();
+        let addr = "10.0.0.1:6881".parse().unwrap();
+        let token = get_token(&mut rt, addr);
+        assert!(announce(&mut rt, addr, token.clone()));
+        // Tokens are tied to the IP they were given to
+        assert!(!announce(
+            &mut rt,
+            "10.0.0.2:6881".parse().unwrap(),
+            token.clone()
+        ));
+        assert_eq!(get_token(&mut rt, "10.0.0.1:51413".parse().unwrap()), token);
+
+        // The secret isn't rotated early
+        let start = rt.last_token_refresh;
+        let rotate = |rt: &mut RoutingTable, mins: i64| {
+            rt.rotate_secret(start + Duration::minutes(mins));
+        };
+        rotate(&mut rt, TOKEN_ROTATION_MINS - 1);
+        assert_eq!(get_token(&mut rt, addr), token);
+
+        // The previous secret is still accepted across a rotation, but not two
+        rotate(&mut rt, TOKEN_ROTATION_MINS);
+        let next = get_token(&mut rt, addr);
+        assert_ne!(next, token);
+        assert!(announce(&mut rt, addr, token.clone()));
+        rotate(&mut rt, 2 * TOKEN_ROTATION_MINS);
+        assert!(!announce(&mut rt, addr, token));
+        assert!(announce(&mut rt, addr, next));
+    }
+
+    #[test]
+    fn test_peer_eviction() {
+        let mut rt = RoutingTable::new();
+        let addr = |i: usize| SocketAddr::from(([10, (i >> 16) as u8, (i >> 8) as u8, i as u8], 1));
+        let stored = |rt: &RoutingTable, hash: [u8; 20]| -> Vec<SocketAddr> {
+            rt.torrents[&hash].peers.iter().map(|p| p.addr).collect()
+        };
+        for i in 0..MAX_TORRENT_PEERS {
+            rt.store_peer([0; 20], id_from_pow(100), addr(i));
+        }
+        // Announcing again makes a peer the most recent rather than adding it twice
+        rt.store_peer([0; 20], id_from_pow(100), addr(0));
+        assert_eq!(stored(&rt, [0; 20]).len(), MAX_TORRENT_PEERS);
+        assert_eq!(stored(&rt, [0; 20])[MAX_TORRENT_PEERS - 1], addr(0));
+        // So the least recently announced is evicted once an infohash is full
+        rt.store_peer([0; 20], id_from_pow(100), addr(MAX_TORRENT_PEERS));
+        let peers = stored(&rt, [0; 20]);
+        assert_eq!(peers.len(), MAX_TORRENT_PEERS);
+        assert_eq!(peers[0], addr(2));
+        assert_eq!(
+            peers[MAX_TORRENT_PEERS - 2..],
+            [addr(0), addr(MAX_TORRENT_PEERS)]
+        );
+
+        // Filling up the other infohashes evicts the oldest peers of all
+        let mut hash = [0; 20];
+        let mut i = MAX_TORRENT_PEERS;
+        while rt.stored_peers < MAX_STORED_PEERS {
+            i += 1;
+            hash[..8].copy_from_slice(&(i / MAX_TORRENT_PEERS).to_be_bytes());
+            rt.store_peer(hash, id_from_pow(100), addr(i));
+        }
+        rt.store_peer([1; 20], id_from_pow(100), addr(0));
+        assert_eq!(rt.stored_peers, MAX_STORED_PEERS);
+        assert_eq!(stored(&rt, [0; 20])[0], addr(3));
+        // Re-announcing doesn't take up any more room
+        rt.store_peer([1; 20], id_from_pow(100), addr(0));
+        assert_eq!(stored(&rt, [0; 20])[0], addr(3));
+        rt.store_peer([1; 20], id_from_pow(100), addr(1));
+        assert_eq!(stored(&rt, [0; 20])[0], addr(4));
+        assert_eq!(
+            rt.stored_peers,
+            rt.torrents.values().map(|t| t.peers.len()).sum::<usize>()
+        );
+    }
 }
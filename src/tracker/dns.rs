@@ -1,8 +1,14 @@
 use std::io;
-use std::net::{IpAddr, UdpSocket};
+use std::net::{IpAddr, ToSocketAddrs, UdpSocket};
+use std::thread;
+use std::time::Duration;
 
+use crate::config::DnsConfig;
 use crate::tracker::{Error, Result};
 
+/// Number of threads blocking on getaddrinfo when using the system resolver
+const SYSTEM_THREADS: usize = 4;
+
 #[derive(Debug)]
 pub struct QueryResponse {
     pub id: usize,
@@ -10,29 +16,121 @@ pub struct QueryResponse {
 }
 
 pub struct Resolver {
-    pub id: usize,
-    pub res: adns::Resolver,
-    pub sock: UdpSocket,
+    id: usize,
+    backend: Backend,
+}
+
+enum Backend {
+    Dns {
+        res: adns::Resolver,
+        sock: UdpSocket,
+    },
+    System {
+        jobs: flume::Sender<(usize, String)>,
+        done: amy::Receiver<adns::Response>,
+    },
 }
 
 impl Resolver {
-    pub fn new(reg: &amy::Registrar) -> io::Result<Resolver> {
+    pub fn new(reg: &mut amy::Registrar, config: &DnsConfig) -> io::Result<Resolver> {
+        if config.system {
+            return Resolver::system(reg);
+        }
         let sock = UdpSocket::bind("0.0.0.0:0")?;
         sock.set_nonblocking(true)?;
         let id = reg.register(&sock, amy::Event::Read)?;
+        let mut res = if config.servers.is_empty() {
+            adns::Resolver::from_resolv()?
+        } else {
+            adns::Resolver::new(&config.servers)
+        };
+        res.set_timeout(Duration::from_millis(config.timeout));
 
         Ok(Resolver {
             id,
-            sock,
-            res: adns::Resolver::from_resolv()?,
+            backend: Backend::Dns { res, sock },
+        })
+    }
+
+    fn system(reg: &mut amy::Registrar) -> io::Result<Resolver> {
+        let (jobs, jobs_rx) = flume::unbounded::<(usize, String)>();
+        let (done_tx, done) = reg.channel()?;
+        for _ in 0..SYSTEM_THREADS {
+            let jobs = jobs_rx.clone();
+            let done = done_tx.clone();
+            thread::Builder::new()
+                .name("dns".to_owned())
+                .spawn(move || {
+                    for (id, host) in jobs.iter() {
+                        let result = lookup(&host).ok_or(adns::Error::NotFound);
+                        if done.send(adns::Response { id, result }).is_err() {
+                            break;
+                        }
+                    }
+                })?;
+        }
+        Ok(Resolver {
+            id: done.get_id(),
+            backend: Backend::System { jobs, done },
         })
     }
 
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
     pub fn new_query(&mut self, id: usize, host: &str) -> io::Result<Option<IpAddr>> {
-        self.res.query(&mut self.sock, id, host)
+        match &mut self.backend {
+            Backend::Dns { res, sock } => res.query(sock, id, host),
+            Backend::System { jobs, .. } => {
+                if let Ok(ip) = host.parse() {
+                    return Ok(Some(ip));
+                }
+                jobs.send((id, host.to_owned()))
+                    .map_err(|_| io::Error::other("dns threads exited"))?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Collects answers which have arrived since the last call.
+    pub fn read<F: FnMut(QueryResponse)>(&mut self, mut f: F) -> io::Result<()> {
+        match &mut self.backend {
+            Backend::Dns { res, sock } => res.read(sock, |r| f(r.into())),
+            Backend::System { done, .. } => {
+                while let Ok(r) = done.try_recv() {
+                    f(r.into());
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Retries or fails queries which have gone unanswered for too long.
+    pub fn tick<F: FnMut(QueryResponse)>(&mut self, mut f: F) -> io::Result<()> {
+        match &mut self.backend {
+            Backend::Dns { res, sock } => res.tick(sock, |r| f(r.into())),
+            Backend::System { .. } => Ok(()),
+        }
+    }
+
+    pub fn purge(&mut self) {
+        if let Backend::Dns { res, .. } = &mut self.backend {
+            res.purge();
+        }
     }
 }
 
+/// Resolves a host through getaddrinfo, preferring IPv4 like the DNS path.
+fn lookup(host: &str) -> Option<IpAddr> {
+    let addrs: Vec<_> = (host, 0).to_socket_addrs().ok()?.map(|a| a.ip()).collect();
+    addrs
+        .iter()
+        .find(|ip| ip.is_ipv4())
+        .or_else(|| addrs.first())
+        .copied()
+}
+
 impl From<adns::Response> for QueryResponse {
     fn from(resp: adns::Response) -> Self {
         QueryResponse {
@@ -53,6 +53,12 @@ pub enum Error {
     ResponseNoInterval,
     #[error("failed to parse error in UDP response: {0}")]
     UdpResponseInvalid(#[source] std::io::Error),
+    #[error("port mapping failed: {0}")]
+    PortMapping(String),
+    #[error("UPnP gateway error {0}: {1}")]
+    UpnpFault(u32, String),
+    #[error("NAT-PMP gateway error {0}")]
+    NatPmpResult(u16),
     #[error("connection failed")]
     Connection,
     #[error("bad state transition")]
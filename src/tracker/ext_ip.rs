@@ -0,0 +1,93 @@
+use std::collections::VecDeque;
+use std::net::IpAddr;
+
+use crate::util::FHashMap;
+
+/// Maximum number of distinct voters remembered, the oldest
+/// votes are discarded first.
+const MAX_VOTERS: usize = 64;
+/// Minimum number of agreeing votes before an address is trusted.
+const MIN_VOTES: usize = 2;
+
+/// Tally of what peers report our external IP to be (via `yourip`).
+/// Each voter IP only gets a single, most recent, vote.
+#[derive(Default)]
+pub struct Votes {
+    order: VecDeque<IpAddr>,
+    votes: FHashMap<IpAddr, IpAddr>,
+}
+
+impl Votes {
+    pub fn new() -> Votes {
+        Votes::default()
+    }
+
+    /// Records that `voter` observed us as `ip`, returning true
+    /// if this changed the consensus.
+    pub fn vote(&mut self, voter: IpAddr, ip: IpAddr) -> bool {
+        let prev = self.external();
+        if self.votes.insert(voter, ip).is_none() {
+            self.order.push_back(voter);
+            if self.order.len() > MAX_VOTERS
+                && let Some(old) = self.order.pop_front()
+            {
+                self.votes.remove(&old);
+            }
+        }
+        self.external() != prev
+    }
+
+    /// The most commonly reported address, if enough peers agree on it.
+    pub fn external(&self) -> Option<IpAddr> {
+        let mut counts = FHashMap::default();
+        for ip in self.votes.values() {
+            *counts.entry(*ip).or_insert(0) += 1;
+        }
+        counts
+            .into_iter()
+            .filter(|&(_, c)| c >= MIN_VOTES)
+            .max_by_key(|&(ip, c)| (c, ip))
+            .map(|(ip, _)| ip)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MAX_VOTERS, Votes};
+    use std::net::IpAddr;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_votes() {
+        let mut v = Votes::new();
+        assert!(!v.vote(ip("10.0.0.1"), ip("1.2.3.4")));
+        assert_eq!(v.external(), None);
+        assert!(v.vote(ip("10.0.0.2"), ip("1.2.3.4")));
+        assert_eq!(v.external(), Some(ip("1.2.3.4")));
+
+        // Repeated votes from the same peer only count once
+        for _ in 0..10 {
+            v.vote(ip("10.0.0.3"), ip("5.6.7.8"));
+        }
+        assert_eq!(v.external(), Some(ip("1.2.3.4")));
+        v.vote(ip("10.0.0.4"), ip("5.6.7.8"));
+        v.vote(ip("10.0.0.5"), ip("5.6.7.8"));
+        assert_eq!(v.external(), Some(ip("5.6.7.8")));
+    }
+
+    #[test]
+    fn test_votes_bounded() {
+        let mut v = Votes::new();
+        v.vote(ip("10.0.0.1"), ip("1.2.3.4"));
+        v.vote(ip("10.0.0.2"), ip("1.2.3.4"));
+        for i in 0..MAX_VOTERS as u32 {
+            v.vote(IpAddr::from((0x0b00_0000 + i).to_be_bytes()), ip("::1"));
+        }
+        assert_eq!(v.votes.len(), MAX_VOTERS);
+        assert!(!v.votes.contains_key(&ip("10.0.0.1")));
+        assert_eq!(v.external(), Some(ip("::1")));
+    }
+}
@@ -1,6 +1,7 @@
-mod reader;
-mod writer;
+pub(super) mod reader;
+pub(super) mod writer;
 
+use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
@@ -11,9 +12,11 @@ use url::Url;
 
 use self::reader::{ReadRes, Reader};
 use self::writer::Writer;
+use crate::PEER_ID;
 use crate::tracker::{self, Announce, Error, Response, Result, TrackerResponse, dns};
+use crate::util::http::Headers;
+use crate::util::timer::Timers;
 use crate::util::{UHashMap, http};
-use crate::{PEER_ID, bencode};
 
 const TIMEOUT_MS: u64 = 5_000;
 
@@ -21,6 +24,9 @@ pub struct Handler {
     reg: amy::Registrar,
     peer_port: u16,
     connections: UHashMap<Tracker>,
+    /// Extra announce headers from the config, by tracker host
+    host_headers: HashMap<String, Headers>,
+    timeouts: Timers<usize>,
 }
 
 enum Event {
@@ -32,7 +38,6 @@ enum Event {
 struct Tracker {
     torrent: usize,
     url: Arc<Url>,
-    last_updated: Instant,
     redirect: bool,
     state: TrackerState,
 }
@@ -107,7 +112,7 @@ impl TrackerState {
             ) => match writer.writable(&mut sock)? {
                 Some(()) => {
                     debug!("Tracker write completed, beginning read");
-                    let r = Reader::new();
+                    let r = Reader::bencode();
                     Ok(TrackerState::Reading { sock, reader: r }.next(Event::Readable)?)
                 }
                 None => Ok(TrackerState::Writing { sock, writer }),
@@ -119,18 +124,13 @@ impl TrackerState {
                 },
                 _,
             ) => match reader.readable(&mut sock)? {
-                ReadRes::Done(data) => {
-                    // Some trackers incorrectly include trailing characters in the response.
-                    let content = bencode::decode_buf_first(&data).map_err(|e| {
-                        let data = std::str::from_utf8(&data)
-                            .map_or_else(|_| format!("{data:?}"), str::to_string);
-                        Error::ResponseInvalidBencode(data, e)
-                    })?;
+                ReadRes::Decoded(content) => {
                     let resp = TrackerResponse::from_bencode(content)?;
                     Ok(TrackerState::Complete(resp))
                 }
                 ReadRes::Redirect(l) => Ok(TrackerState::Redirect(l)),
                 ReadRes::None => Ok(TrackerState::Reading { sock, reader }),
+                ReadRes::Done(_) => Err(Error::BadStateTransition),
             },
             (s @ TrackerState::ResolvingDNS { .. }, _) => Ok(s),
             _ => Err(Error::BadStateTransition),
@@ -139,14 +139,28 @@ impl TrackerState {
 }
 
 impl Handler {
-    pub fn new(reg: &amy::Registrar, peer_port: u16) -> io::Result<Handler> {
+    pub fn new(
+        reg: &amy::Registrar,
+        peer_port: u16,
+        host_headers: &HashMap<String, Vec<String>>,
+    ) -> io::Result<Handler> {
         Ok(Handler {
             reg: reg.clone(),
             peer_port,
             connections: UHashMap::default(),
+            // These are validated when the config is loaded
+            host_headers: host_headers
+                .iter()
+                .filter_map(|(host, h)| Some((host.to_ascii_lowercase(), Headers::parse(h).ok()?)))
+                .collect(),
+            timeouts: Timers::new(),
         })
     }
 
+    pub fn set_peer_port(&mut self, port: u16) {
+        self.peer_port = port;
+    }
+
     pub fn active_requests(&self) -> usize {
         self.connections.len()
     }
@@ -163,7 +177,8 @@ impl Handler {
         let id = resp.id;
         debug!("Received a DNS resp for {:?}", id);
         let resp = if let Some(trk) = self.connections.get_mut(&id) {
-            trk.last_updated = Instant::now();
+            self.timeouts
+                .schedule(id, Instant::now() + Duration::from_millis(TIMEOUT_MS));
             match trk.state.handle(Event::DNSResolved(resp)) {
                 Ok(_) => None,
                 Err(e) => Some(Response::Tracker {
@@ -176,14 +191,15 @@ impl Handler {
             None
         };
         if resp.is_some() {
-            self.connections.remove(&id);
+            self.remove(id);
         }
         resp
     }
 
     pub fn writable(&mut self, id: usize) -> Option<Response> {
         let resp = if let Some(trk) = self.connections.get_mut(&id) {
-            trk.last_updated = Instant::now();
+            self.timeouts
+                .schedule(id, Instant::now() + Duration::from_millis(TIMEOUT_MS));
             match trk.state.handle(Event::Writable) {
                 Ok(_) => None,
                 Err(e) => Some(Response::Tracker {
@@ -196,7 +212,7 @@ impl Handler {
             None
         };
         if resp.is_some() {
-            self.connections.remove(&id);
+            self.remove(id);
         }
         resp
     }
@@ -204,7 +220,8 @@ impl Handler {
     pub fn readable(&mut self, id: usize, dns: &mut dns::Resolver) -> Option<Response> {
         let mut loc = None;
         let mut resp = if let Some(trk) = self.connections.get_mut(&id) {
-            trk.last_updated = Instant::now();
+            self.timeouts
+                .schedule(id, Instant::now() + Duration::from_millis(TIMEOUT_MS));
             match trk.state.handle(Event::Readable) {
                 Ok(HTTPRes::Complete(r)) => {
                     debug!("Announce response received for {:?} succesfully", id);
@@ -230,11 +247,11 @@ impl Handler {
         };
 
         if resp.is_some() {
-            self.connections.remove(&id);
+            self.remove(id);
         }
 
         if let Some((l, old)) = loc {
-            let trk = self.connections.remove(&id).unwrap();
+            let trk = self.remove(id).unwrap();
             // Disallow 2 levels of redirection
             if trk.redirect {
                 resp = Some(Response::Tracker {
@@ -294,10 +311,9 @@ impl Handler {
             .register(&sock, amy::Event::Both)
             .map_err(Error::Registrar)?;
         let port = url.port().unwrap_or(80);
-        self.connections.insert(
+        self.insert(
             id,
             Tracker {
-                last_updated: Instant::now(),
                 redirect: true,
                 torrent,
                 url: original_url,
@@ -317,53 +333,102 @@ impl Handler {
         Ok(())
     }
 
+    fn announce_request(&self, req: &Announce, host: &str) -> Vec<u8> {
+        let mut http_req = Vec::with_capacity(512);
+        let num_want = req.num_want.map(|nw| nw.to_string());
+        let key = format!("{:08x}", req.key);
+        let event = match req.event {
+            Some(tracker::Event::Started) => Some("started"),
+            Some(tracker::Event::Stopped) => Some("stopped"),
+            Some(tracker::Event::Completed) => Some("completed"),
+            Some(tracker::Event::Paused) => Some("paused"),
+            None => None,
+        };
+        let (ip, ipv6) = (
+            req.ip.map(|ip| ip.to_string()),
+            req.ipv6.map(|ip| ip.to_string()),
+        );
+        let (uploaded, downloaded, left, port) = (
+            req.uploaded.to_string(),
+            req.downloaded.to_string(),
+            req.left.to_string(),
+            self.peer_port.to_string(),
+        );
+        let (corrupt, redundant) = (
+            req.corrupt.map(|c| c.to_string()),
+            req.redundant.map(|r| r.to_string()),
+        );
+        let mut builder = http::RequestBuilder::new("GET", req.url.path(), req.url.query());
+        builder
+            .query("info_hash", &req.hash)
+            .query("peer_id", &PEER_ID[..])
+            .query("uploaded", uploaded.as_bytes())
+            .query("downloaded", downloaded.as_bytes())
+            .query("left", left.as_bytes())
+            .query_opt("corrupt", corrupt.as_ref().map(|c| c.as_bytes()))
+            .query_opt("redundant", redundant.as_ref().map(|r| r.as_bytes()))
+            .query("compact", b"1")
+            .query("no_peer_id", b"1")
+            .query("port", port.as_bytes())
+            .query("key", key.as_bytes())
+            .query_opt("numwant", num_want.as_ref().map(|nw| nw.as_bytes()))
+            .query_opt("event", event.map(|e| e.as_bytes()))
+            .query_opt("ip", ip.as_ref().map(|ip| ip.as_bytes()))
+            .query_opt("ipv6", ipv6.as_ref().map(|ip| ip.as_bytes()));
+        // Headers set on the torrent's tracker take precedence over those configured for the
+        // host, and either may replace the default user agent
+        let host_headers = self
+            .host_headers
+            .get(host)
+            .into_iter()
+            .flat_map(|h| h.iter());
+        for (name, value) in req.headers.iter().chain(host_headers) {
+            if !builder.has_header(name) {
+                builder.header(name, value);
+            }
+        }
+        if !builder.has_header("User-agent") {
+            builder.header("User-agent", concat!("synapse/", env!("CARGO_PKG_VERSION")));
+        }
+        builder.header("Connection", "close").header("Host", host);
+        builder.encode(&mut http_req);
+        http_req
+    }
+
     pub fn tick(&mut self) -> Vec<Response> {
         let mut resps = Vec::new();
-        self.connections.retain(|id, trk| {
-            if trk.last_updated.elapsed() > Duration::from_millis(TIMEOUT_MS) {
+        let now = Instant::now();
+        while let Some(id) = self.timeouts.pop_expired(now) {
+            if let Some(trk) = self.connections.remove(&id) {
                 debug!("Announce {:?} timed out", id);
                 resps.push(Response::Tracker {
                     tid: trk.torrent,
-                    url: trk.url.clone(),
+                    url: trk.url,
                     resp: Err(Error::Timeout),
                 });
-                false
-            } else {
-                true
             }
-        });
+        }
         resps
     }
 
+    fn insert(&mut self, id: usize, trk: Tracker) {
+        self.connections.insert(id, trk);
+        self.timeouts
+            .schedule(id, Instant::now() + Duration::from_millis(TIMEOUT_MS));
+    }
+
+    fn remove(&mut self, id: usize) -> Option<Tracker> {
+        self.timeouts.cancel(id);
+        self.connections.remove(&id)
+    }
+
     pub fn new_announce(&mut self, req: Announce, dns: &mut dns::Resolver) -> Result<()> {
         debug!("Received a new announce req for {:?}", req.url);
         let host = req
             .url
             .host_str()
             .ok_or_else(|| Error::UrlNoHost(req.url.as_ref().clone().into()))?;
-
-        let mut http_req = Vec::with_capacity(512);
-        let num_want = req.num_want.map(|nw| nw.to_string());
-        let event = match req.event {
-            Some(tracker::Event::Started) => Some("started"),
-            Some(tracker::Event::Stopped) => Some("stopped"),
-            Some(tracker::Event::Completed) => Some("completed"),
-            None => None,
-        };
-        http::RequestBuilder::new("GET", req.url.path(), req.url.query())
-            .query("info_hash", &req.hash)
-            .query("peer_id", &PEER_ID[..])
-            .query("uploaded", req.uploaded.to_string().as_bytes())
-            .query("downloaded", req.downloaded.to_string().as_bytes())
-            .query("left", req.left.to_string().as_bytes())
-            .query("compact", b"1")
-            .query("port", self.peer_port.to_string().as_bytes())
-            .query_opt("numwant", num_want.as_ref().map(|nw| nw.as_bytes()))
-            .query_opt("event", event.map(|e| e.as_bytes()))
-            .header("User-agent", concat!("synapse/", env!("CARGO_PKG_VERSION")))
-            .header("Connection", "close")
-            .header("Host", host)
-            .encode(&mut http_req);
+        let http_req = self.announce_request(&req, host);
 
         let port = req
             .url
@@ -382,11 +447,10 @@ impl Handler {
             .reg
             .register(&sock, amy::Event::Both)
             .map_err(Error::Registrar)?;
-        self.connections.insert(
+        self.insert(
             id,
             Tracker {
                 url: req.url.clone(),
-                last_updated: Instant::now(),
                 torrent: req.id,
                 state: TrackerState::new(sock, http_req, port),
                 redirect: false,
@@ -405,3 +469,248 @@ impl Handler {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    use url::Url;
+
+    use super::Handler;
+    use super::reader::{ReadRes, Reader};
+    use crate::bencode;
+    use crate::tracker::{self, Announce};
+    use crate::util::http::Headers;
+
+    fn announce(url: &str, headers: &[&str]) -> Announce {
+        Announce {
+            id: 0,
+            url: Arc::new(Url::parse(url).unwrap()),
+            hash: [0; 20],
+            uploaded: 0,
+            downloaded: 0,
+            left: 0,
+            num_want: None,
+            event: None,
+            key: 0,
+            headers: Headers::parse(headers).unwrap(),
+            ip: None,
+            ipv6: None,
+            corrupt: None,
+            redundant: None,
+        }
+    }
+
+    fn request_line(handler: &Handler, req: &Announce) -> String {
+        let host = req.url.host_str().unwrap();
+        let http_req = String::from_utf8(handler.announce_request(req, host)).unwrap();
+        http_req.split("\r\n").next().unwrap().to_owned()
+    }
+
+    fn request_headers(handler: &Handler, req: &Announce) -> Vec<String> {
+        let host = req.url.host_str().unwrap();
+        let http_req = String::from_utf8(handler.announce_request(req, host)).unwrap();
+        http_req
+            .split("\r\n")
+            .skip(1)
+            .filter(|l| !l.is_empty())
+            .map(str::to_owned)
+            .collect()
+    }
+
+    #[test]
+    fn test_announce_headers() {
+        let poll = amy::Poller::new().unwrap();
+        let mut host_headers = HashMap::new();
+        host_headers.insert(
+            "tracker.example.org".to_owned(),
+            vec!["Cookie: uid=1".to_owned(), "X-Client: synapse".to_owned()],
+        );
+        let handler = Handler::new(&poll.get_registrar(), 16384, &host_headers).unwrap();
+
+        let req = announce(
+            "http://tracker.example.org/announce",
+            &["cookie: uid=2", "User-Agent: custom"],
+        );
+        assert_eq!(
+            request_headers(&handler, &req),
+            [
+                "cookie: uid=2",
+                "User-Agent: custom",
+                "X-Client: synapse",
+                "Connection: close",
+                "Host: tracker.example.org",
+            ]
+        );
+
+        let req = announce("http://other.example.org/announce", &[]);
+        assert_eq!(
+            request_headers(&handler, &req),
+            [
+                concat!("User-agent: synapse/", env!("CARGO_PKG_VERSION")),
+                "Connection: close",
+                "Host: other.example.org",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_announce_ip() {
+        let poll = amy::Poller::new().unwrap();
+        let handler = Handler::new(&poll.get_registrar(), 16384, &HashMap::new()).unwrap();
+
+        let mut req = announce("http://tracker.example.org/announce", &[]);
+        let line = request_line(&handler, &req);
+        assert!(!line.contains("ip="));
+
+        req.ip = Some("203.0.113.7".parse().unwrap());
+        let line = request_line(&handler, &req);
+        assert!(line.contains("&ip=203%2E0%2E113%2E7"));
+        assert!(!line.contains("ipv6="));
+
+        req.ipv6 = Some("2001:db8::7".parse().unwrap());
+        let line = request_line(&handler, &req);
+        assert!(line.contains("&ip=203%2E0%2E113%2E7"));
+        assert!(line.contains("&ipv6=2001%3Adb8%3A%3A7"));
+    }
+
+    /// Takes the parameter names out of a request line, in order.
+    fn query_keys(line: &str) -> Vec<String> {
+        let query = line.split(' ').nth(1).unwrap().split_once('?').unwrap().1;
+        query
+            .split('&')
+            .map(|p| p.split('=').next().unwrap().to_owned())
+            .collect()
+    }
+
+    #[test]
+    fn test_announce_corrupt() {
+        let poll = amy::Poller::new().unwrap();
+        let handler = Handler::new(&poll.get_registrar(), 16384, &HashMap::new()).unwrap();
+
+        let mut req = announce("http://tracker.example.org/announce?passkey=abc", &[]);
+        let line = request_line(&handler, &req);
+        assert_eq!(
+            query_keys(&line),
+            [
+                "passkey",
+                "info_hash",
+                "peer_id",
+                "uploaded",
+                "downloaded",
+                "left",
+                "compact",
+                "no_peer_id",
+                "port",
+                "key"
+            ]
+        );
+        // Interval announces leave the event out rather than sending it empty
+        assert!(!line.contains("event"));
+
+        req.corrupt = Some(32768);
+        req.redundant = Some(0);
+        req.event = Some(tracker::Event::Started);
+        let line = request_line(&handler, &req);
+        assert_eq!(
+            query_keys(&line),
+            [
+                "passkey",
+                "info_hash",
+                "peer_id",
+                "uploaded",
+                "downloaded",
+                "left",
+                "corrupt",
+                "redundant",
+                "compact",
+                "no_peer_id",
+                "port",
+                "key",
+                "event"
+            ]
+        );
+        assert!(line.contains("&left=0&corrupt=32768&redundant=0&compact=1&no_peer_id=1&"));
+        assert!(line.contains("&event=started"));
+
+        req.event = Some(tracker::Event::Paused);
+        assert!(request_line(&handler, &req).contains("&event=paused"));
+    }
+
+    #[test]
+    fn test_announce_key() {
+        let poll = amy::Poller::new().unwrap();
+        let handler = Handler::new(&poll.get_registrar(), 16384, &HashMap::new()).unwrap();
+
+        let mut req = announce("http://tracker.example.org/announce", &[]);
+        req.key = 0xdead_beef;
+        assert!(request_line(&handler, &req).contains("&key=deadbeef"));
+        // Small keys keep their width, so the tracker sees the same string each time
+        req.key = 0xba;
+        assert!(request_line(&handler, &req).contains("&key=000000ba"));
+    }
+
+    /// Yields a response a byte at a time, blocking between each.
+    struct Trickle<'a> {
+        data: &'a [u8],
+        blocked: bool,
+    }
+
+    impl std::io::Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.blocked = !self.blocked;
+            if self.blocked {
+                return Err(std::io::ErrorKind::WouldBlock.into());
+            }
+            let n = self.data.len().min(buf.len()).min(1);
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data = &self.data[n..];
+            Ok(n)
+        }
+    }
+
+    fn read_response(body: &[u8]) -> tracker::Result<bencode::BEncode> {
+        let mut resp = b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\n\r\n".to_vec();
+        resp.extend_from_slice(body);
+        let mut conn = Trickle {
+            data: &resp,
+            blocked: false,
+        };
+        let mut reader = Reader::bencode();
+        loop {
+            match reader.readable(&mut conn)? {
+                ReadRes::Decoded(b) => return Ok(b),
+                ReadRes::None => {}
+                _ => panic!("unexpected read result"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_read_incremental() {
+        let body = b"d8:intervali1800e5:peers6:\x7f\x00\x00\x01\x1a\xe1e";
+        assert_eq!(
+            read_response(body).unwrap(),
+            bencode::decode_buf(body).unwrap()
+        );
+        // Trailing garbage is never read
+        let mut trailing = body.to_vec();
+        trailing.extend_from_slice(b"\r\n<html>");
+        assert_eq!(
+            read_response(&trailing).unwrap(),
+            bencode::decode_buf(body).unwrap()
+        );
+        assert!(matches!(
+            read_response(b"d8:interval"),
+            Err(tracker::Error::ResponseInvalidBencode(ref d, bencode::BError::EOF)) if d == "d8:interval"
+        ));
+        assert!(matches!(
+            read_response(b"<html>"),
+            Err(tracker::Error::ResponseInvalidBencode(
+                _,
+                bencode::BError::InvalidChar(b'<')
+            ))
+        ));
+    }
+}
@@ -1,18 +1,29 @@
 use std::io;
 use std::mem;
 
+use crate::bencode::{self, BEncode, Decoded, Decoder};
+
 use crate::tracker::errors::{Error, Result};
 use crate::util::{IOR, aread};
 
+/// Initial size of the read buffer
+const BUF_LEN: usize = 75;
+/// Amount of a decoded body kept to describe it when it turns out to be invalid
+const PREFIX_LEN: usize = 256;
+
 pub struct Reader {
     data: Vec<u8>,
     idx: usize,
     state: ReadState,
+    /// Decodes the body as it arrives rather than buffering all of it
+    decoder: Option<Decoder>,
+    prefix: Vec<u8>,
 }
 
 pub enum ReadRes {
     None,
     Done(Vec<u8>),
+    Decoded(BEncode),
     Redirect(String),
 }
 
@@ -24,9 +35,20 @@ enum ReadState {
 impl Reader {
     pub fn new() -> Reader {
         Reader {
-            data: vec![0; 75],
+            data: vec![0; BUF_LEN],
             idx: 0,
             state: ReadState::Header,
+            decoder: None,
+            prefix: Vec::new(),
+        }
+    }
+
+    /// Creates a reader which decodes a bencoded body incrementally, yielding
+    /// `ReadRes::Decoded`.
+    pub fn bencode() -> Reader {
+        Reader {
+            decoder: Some(Decoder::new()),
+            ..Reader::new()
         }
     }
 
@@ -35,11 +57,14 @@ impl Reader {
             match aread(&mut self.data[self.idx..], conn) {
                 IOR::Complete => {
                     self.idx = self.data.len();
-                    let new_len = (self.idx as f32 * 1.5) as usize;
-                    self.data.resize(new_len, 0u8);
                     if let Some(result) = self.process_data()? {
                         return Ok(result);
                     }
+                    // Decoded bodies are consumed as they're read, so only grow when needed
+                    if self.idx == self.data.len() {
+                        let new_len = (self.idx as f32 * 1.5) as usize;
+                        self.data.resize(new_len.max(BUF_LEN), 0u8);
+                    }
                 }
                 IOR::Incomplete(a) => {
                     self.idx += a;
@@ -49,11 +74,7 @@ impl Reader {
                 }
                 IOR::Blocked => return Ok(ReadRes::None),
                 IOR::EOF => match self.state {
-                    ReadState::Body => {
-                        let mut data = mem::replace(&mut self.data, Vec::with_capacity(0));
-                        data.truncate(self.idx);
-                        return Ok(ReadRes::Done(data));
-                    }
+                    ReadState::Body => return self.finish(),
                     _ => return Err(Error::Eof),
                 },
                 IOR::Err(e) => {
@@ -66,11 +87,7 @@ impl Reader {
                     // EOF should be safe.
                     return if e.kind() == io::ErrorKind::UnexpectedEof {
                         match self.state {
-                            ReadState::Body => {
-                                let mut data = mem::replace(&mut self.data, Vec::with_capacity(0));
-                                data.truncate(self.idx);
-                                Ok(ReadRes::Done(data))
-                            }
+                            ReadState::Body => self.finish(),
                             _ => Err(Error::Read(e)),
                         }
                     } else {
@@ -81,6 +98,22 @@ impl Reader {
         }
     }
 
+    /// Handles the end of the body.
+    fn finish(&mut self) -> Result<ReadRes> {
+        if self.decoder.is_some() {
+            // Truncated, or the decoder would have completed
+            return Err(self.invalid(bencode::BError::EOF));
+        }
+        let mut data = mem::take(&mut self.data);
+        data.truncate(self.idx);
+        Ok(ReadRes::Done(data))
+    }
+
+    fn invalid(&self, e: bencode::BError) -> Error {
+        let data = String::from_utf8_lossy(&self.prefix).into_owned();
+        Error::ResponseInvalidBencode(data, e)
+    }
+
     fn process_data(&mut self) -> Result<Option<ReadRes>> {
         let mut header_done = None;
         match self.state {
@@ -121,6 +154,22 @@ impl Reader {
             self.data = body;
             self.state = ReadState::Body;
         }
+        if let (ReadState::Body, Some(decoder)) = (&self.state, &mut self.decoder) {
+            let chunk = &self.data[..self.idx];
+            let keep = PREFIX_LEN
+                .saturating_sub(self.prefix.len())
+                .min(chunk.len());
+            self.prefix.extend_from_slice(&chunk[..keep]);
+            let res = decoder.feed(chunk);
+            self.idx = 0;
+            // Some trackers incorrectly include trailing characters in the response, these are
+            // left unread.
+            match res {
+                Ok(Decoded::Done(b)) => return Ok(Some(ReadRes::Decoded(b))),
+                Ok(Decoded::NeedMore) => {}
+                Err(e) => return Err(self.invalid(e)),
+            }
+        }
         Ok(None)
     }
 }
@@ -11,22 +11,25 @@ impl Writer {
         Writer { data, idx: 0 }
     }
 
+    /// Writes as much of the request as the connection accepts, returning once all of it
+    /// has been written.
     pub fn writable<W: io::Write>(&mut self, conn: &mut W) -> Result<Option<()>> {
-        match conn.write(&self.data[self.idx..]) {
-            Ok(0) => Err(Error::Eof),
-            Ok(v) if self.idx + v == self.data.len() => Ok(Some(())),
-            Ok(v) => {
-                self.idx += v;
-                Ok(None)
-            }
-            Err(e) => {
-                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::NotConnected
-                    // EPIPE can occur on WSL
-                    || e.kind() == io::ErrorKind::BrokenPipe
-                {
-                    Ok(None)
-                } else {
-                    Err(Error::Write(e))
+        loop {
+            match conn.write(&self.data[self.idx..]) {
+                Ok(0) => return Err(Error::Eof),
+                Ok(v) if self.idx + v == self.data.len() => return Ok(Some(())),
+                Ok(v) => self.idx += v,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    return if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::NotConnected
+                        // EPIPE can occur on WSL
+                        || e.kind() == io::ErrorKind::BrokenPipe
+                    {
+                        Ok(None)
+                    } else {
+                        Err(Error::Write(e))
+                    };
                 }
             }
         }
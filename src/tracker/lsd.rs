@@ -0,0 +1,314 @@
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use net2::UdpBuilder;
+use net2::UdpSocketExt;
+#[cfg(unix)]
+use net2::unix::UnixUdpBuilderExt;
+
+use crate::tracker::Response;
+use crate::util::{self, FHashMap};
+
+const LSD_PORT: u16 = 6771;
+const LSD_GROUP4: Ipv4Addr = Ipv4Addr::new(239, 192, 152, 143);
+const LSD_GROUP6: Ipv6Addr = Ipv6Addr::new(0xff15, 0, 0, 0, 0, 0, 0xefc0, 0x988f);
+/// BEP 14 asks for no more than one announce per torrent a minute
+const MIN_ANNOUNCE_SECS: u64 = 60;
+/// Torrents which haven't asked to be announced for this long are forgotten,
+/// so peers stop being handed to ones that were paused or removed
+const FORGET_SECS: u64 = 15 * 60;
+
+/// Local Service Discovery (BEP 14): announces torrents to the local network over
+/// multicast and hands peers announcing the same torrents to them.
+pub struct Handler {
+    socks: Vec<Sock>,
+    /// Port we accept peer connections on
+    port: u16,
+    /// Sent with our announces so that they can be told apart when looped back
+    cookie: String,
+    torrents: FHashMap<[u8; 20], Entry>,
+    buf: Vec<u8>,
+}
+
+struct Sock {
+    id: usize,
+    sock: UdpSocket,
+    group: SocketAddr,
+}
+
+struct Entry {
+    tid: usize,
+    requested: Instant,
+    announced: Option<Instant>,
+}
+
+impl Handler {
+    /// Joins the IPv4 group, and the IPv6 one if the host supports it.
+    pub fn new(reg: &amy::Registrar, port: u16) -> io::Result<Handler> {
+        let mut h = Handler::bind(reg, LSD_GROUP4, Ipv4Addr::UNSPECIFIED, LSD_PORT, port)?;
+        match bind_v6(LSD_PORT) {
+            Ok(sock) => h.add_sock(reg, sock, SocketAddr::new(LSD_GROUP6.into(), LSD_PORT))?,
+            Err(e) => debug!("Not doing IPv6 local peer discovery: {}", e),
+        }
+        Ok(h)
+    }
+
+    fn bind(
+        reg: &amy::Registrar,
+        group: Ipv4Addr,
+        iface: Ipv4Addr,
+        lsd_port: u16,
+        port: u16,
+    ) -> io::Result<Handler> {
+        let mut h = Handler {
+            socks: Vec::new(),
+            port,
+            cookie: util::random_string(8),
+            torrents: FHashMap::default(),
+            buf: vec![0u8; 1500],
+        };
+        let sock = reuse(UdpBuilder::new_v4()?)?.bind((Ipv4Addr::UNSPECIFIED, lsd_port))?;
+        sock.join_multicast_v4(&group, &iface)?;
+        sock.set_multicast_if_v4(&iface)?;
+        sock.set_multicast_loop_v4(true)?;
+        h.add_sock(reg, sock, SocketAddr::new(group.into(), lsd_port))?;
+        Ok(h)
+    }
+
+    fn add_sock(
+        &mut self,
+        reg: &amy::Registrar,
+        sock: UdpSocket,
+        group: SocketAddr,
+    ) -> io::Result<()> {
+        sock.set_nonblocking(true)?;
+        let id = reg.register(&sock, amy::Event::Read)?;
+        self.socks.push(Sock { id, sock, group });
+        Ok(())
+    }
+
+    pub fn contains(&self, id: usize) -> bool {
+        self.socks.iter().any(|s| s.id == id)
+    }
+
+    /// Announces a torrent, unless it was announced within the last minute, and starts
+    /// accepting peers for it.
+    pub fn announce(&mut self, tid: usize, hash: [u8; 20]) {
+        let now = Instant::now();
+        let entry = self.torrents.entry(hash).or_insert(Entry {
+            tid,
+            requested: now,
+            announced: None,
+        });
+        entry.tid = tid;
+        entry.requested = now;
+        if entry
+            .announced
+            .is_some_and(|at| now.duration_since(at) < Duration::from_secs(MIN_ANNOUNCE_SECS))
+        {
+            return;
+        }
+        entry.announced = Some(now);
+        for s in &self.socks {
+            let msg = announcement(s.group, self.port, &hash, &self.cookie);
+            if let Err(e) = s.sock.send_to(msg.as_bytes(), s.group) {
+                debug!("Failed to send LSD announce to {}: {}", s.group, e);
+            }
+        }
+    }
+
+    /// Forgets torrents which have stopped asking to be announced.
+    pub fn tick(&mut self) {
+        let now = Instant::now();
+        self.torrents
+            .retain(|_, e| now.duration_since(e.requested) < Duration::from_secs(FORGET_SECS));
+    }
+
+    pub fn readable(&mut self, id: usize) -> Vec<Response> {
+        let mut resps = Vec::new();
+        let Some(sock) = self.socks.iter().find(|s| s.id == id) else {
+            return resps;
+        };
+        while let Ok((len, addr)) = sock.sock.recv_from(&mut self.buf) {
+            let Some(ann) = parse_announcement(&self.buf[..len]) else {
+                trace!("Ignoring malformed LSD message from {}", addr);
+                continue;
+            };
+            if ann.cookie.as_deref() == Some(self.cookie.as_str()) {
+                continue;
+            }
+            let peer = SocketAddr::new(addr.ip(), ann.port);
+            for hash in ann.hashes {
+                if let Some(e) = self.torrents.get(&hash) {
+                    debug!("Found local peer {}", peer);
+                    resps.push(Response::LSD {
+                        tid: e.tid,
+                        peers: vec![peer],
+                    });
+                }
+            }
+        }
+        resps
+    }
+}
+
+#[cfg(unix)]
+fn reuse(b: UdpBuilder) -> io::Result<UdpBuilder> {
+    // Other clients on the host listen on the same port
+    b.reuse_address(true)?;
+    b.reuse_port(true)?;
+    Ok(b)
+}
+
+#[cfg(not(unix))]
+fn reuse(b: UdpBuilder) -> io::Result<UdpBuilder> {
+    b.reuse_address(true)?;
+    Ok(b)
+}
+
+fn bind_v6(port: u16) -> io::Result<UdpSocket> {
+    let b = UdpBuilder::new_v6()?;
+    b.only_v6(true)?;
+    let sock = reuse(b)?.bind((Ipv6Addr::UNSPECIFIED, port))?;
+    sock.join_multicast_v6(&LSD_GROUP6, 0)?;
+    sock.set_multicast_loop_v6(true)?;
+    Ok(sock)
+}
+
+fn announcement(group: SocketAddr, port: u16, hash: &[u8; 20], cookie: &str) -> String {
+    format!(
+        "BT-SEARCH * HTTP/1.1\r\n\
+         Host: {group}\r\n\
+         Port: {port}\r\n\
+         Infohash: {}\r\n\
+         cookie: {cookie}\r\n\r\n\r\n",
+        util::hash_to_id(hash)
+    )
+}
+
+#[derive(Debug, PartialEq)]
+struct Announcement {
+    port: u16,
+    hashes: Vec<[u8; 20]>,
+    cookie: Option<String>,
+}
+
+fn parse_announcement(data: &[u8]) -> Option<Announcement> {
+    let mut headers = [httparse::EMPTY_HEADER; 16];
+    let mut req = httparse::Request::new(&mut headers);
+    req.parse(data).ok()?;
+    if req.method != Some("BT-SEARCH") {
+        return None;
+    }
+    let mut ann = Announcement {
+        port: 0,
+        hashes: Vec::new(),
+        cookie: None,
+    };
+    for h in req.headers.iter() {
+        let Ok(value) = std::str::from_utf8(h.value) else {
+            continue;
+        };
+        let value = value.trim();
+        if h.name.eq_ignore_ascii_case("port") {
+            ann.port = value.parse().ok()?;
+        } else if h.name.eq_ignore_ascii_case("infohash") {
+            ann.hashes.extend(util::id_to_hash(value));
+        } else if h.name.eq_ignore_ascii_case("cookie") {
+            ann.cookie = Some(value.to_owned());
+        }
+    }
+    if ann.port == 0 || ann.hashes.is_empty() {
+        return None;
+    }
+    Some(ann)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+    use std::thread;
+
+    use super::*;
+
+    const HASH: [u8; 20] = [0xAB; 20];
+
+    #[test]
+    fn test_parse_announcement() {
+        let msg = announcement(
+            SocketAddr::new(LSD_GROUP4.into(), LSD_PORT),
+            16493,
+            &HASH,
+            "c00k1e",
+        );
+        assert_eq!(
+            parse_announcement(msg.as_bytes()),
+            Some(Announcement {
+                port: 16493,
+                hashes: vec![HASH],
+                cookie: Some("c00k1e".to_owned()),
+            })
+        );
+
+        // Several hashes can share an announce, and the cookie is optional
+        let msg = format!(
+            "BT-SEARCH * HTTP/1.1\r\nHost: 239.192.152.143:6771\r\nport: 6881\r\n\
+             Infohash: {}\r\nInfohash: {}\r\n\r\n\r\n",
+            "ab".repeat(20),
+            "01".repeat(20)
+        );
+        let ann = parse_announcement(msg.as_bytes()).unwrap();
+        assert_eq!(ann.hashes, [HASH, [1; 20]]);
+        assert_eq!(ann.cookie, None);
+
+        let bad = msg.replace("port: 6881", "port: 0");
+        assert_eq!(parse_announcement(bad.as_bytes()), None);
+        let bad = msg.replace("BT-SEARCH", "M-SEARCH");
+        assert_eq!(parse_announcement(bad.as_bytes()), None);
+    }
+
+    #[test]
+    fn test_discovery() {
+        let poll = amy::Poller::new().unwrap();
+        let reg = poll.get_registrar();
+        let group = Ipv4Addr::new(239, 192, 152, 144);
+        let lsd_port = UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let mut a = Handler::bind(&reg, group, Ipv4Addr::LOCALHOST, lsd_port, 16001).unwrap();
+        let mut b = Handler::bind(&reg, group, Ipv4Addr::LOCALHOST, lsd_port, 16002).unwrap();
+        a.announce(1, HASH);
+        b.announce(2, HASH);
+        b.announce(2, [0xCD; 20]);
+
+        let read = |h: &mut Handler| {
+            let mut peers = Vec::new();
+            let start = Instant::now();
+            while start.elapsed() < Duration::from_millis(500) {
+                for id in h.socks.iter().map(|s| s.id).collect::<Vec<_>>() {
+                    for r in h.readable(id) {
+                        match r {
+                            Response::LSD { tid, peers: p } => {
+                                peers.extend(p.into_iter().map(|p| (tid, p)))
+                            }
+                            r => panic!("unexpected response {r:?}"),
+                        }
+                    }
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+            peers
+        };
+        // Each side only sees the other, and only for torrents it has
+        let local = |port| SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port);
+        assert_eq!(read(&mut a), [(1, local(16002))]);
+        assert_eq!(read(&mut b), [(2, local(16001))]);
+
+        // Announces are rate limited
+        b.announce(2, HASH);
+        assert!(read(&mut a).is_empty());
+    }
+}
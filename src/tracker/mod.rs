@@ -1,15 +1,20 @@
 mod dht;
 mod dns;
 mod errors;
+mod ext_ip;
 mod http;
+mod lsd;
+mod natpmp;
 mod udp;
+mod upnp;
 
 use std::collections::VecDeque;
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4};
 use std::sync::Arc;
-use std::{io, result, thread};
+use std::{io, result, thread, time};
 
 use byteorder::{BigEndian, ByteOrder};
+use chrono::{DateTime, Utc};
 use url::Url;
 
 pub use self::errors::{Error, Result};
@@ -19,6 +24,7 @@ use crate::control::cio;
 use crate::disk;
 use crate::handle;
 use crate::torrent::Torrent;
+use crate::util::http::Headers;
 
 pub struct Tracker {
     config: Arc<Config>,
@@ -29,6 +35,9 @@ pub struct Tracker {
     udp: udp::Handler,
     dht: dht::Manager,
     dns: dns::Resolver,
+    ext_ip: ext_ip::Votes,
+    upnp: Option<upnp::PortMapper>,
+    lsd: Option<lsd::Handler>,
     timer: usize,
     shutting_down: bool,
 }
@@ -38,7 +47,15 @@ pub enum Request {
     Announce(Announce),
     GetPeers(GetPeers),
     AddNode(SocketAddr),
+    /// A peer at `voter` reported our external address as `ip`
+    ExternalIp {
+        voter: IpAddr,
+        ip: IpAddr,
+    },
     DHTAnnounce([u8; 20]),
+    /// Announce a torrent to the local network
+    #[allow(clippy::upper_case_acronyms)]
+    LSDAnnounce(GetPeers),
     PurgeDNS,
     Ping,
     Shutdown,
@@ -54,6 +71,14 @@ pub struct Announce {
     left: u64,
     num_want: Option<u16>,
     event: Option<Event>,
+    key: u32,
+    headers: Headers,
+    /// Addresses to report in place of the ones the tracker sees
+    ip: Option<Ipv4Addr>,
+    ipv6: Option<Ipv6Addr>,
+    /// Bytes which failed validation and duplicate bytes, if they're reported
+    corrupt: Option<u64>,
+    redundant: Option<u64>,
 }
 
 #[derive(Debug)]
@@ -67,6 +92,9 @@ pub enum Event {
     Started,
     Stopped,
     Completed,
+    /// Sent by partial seeds, which have every file they want but not the whole torrent, in
+    /// place of regular and completed announces (BEP 21)
+    Paused,
 }
 
 #[derive(Debug)]
@@ -77,20 +105,46 @@ pub enum Response {
         resp: Result<TrackerResponse>,
     },
     #[allow(clippy::upper_case_acronyms)]
-    DHT { tid: usize, peers: Vec<SocketAddr> },
+    DHT {
+        tid: usize,
+        peers: Vec<SocketAddr>,
+    },
+    #[allow(clippy::upper_case_acronyms)]
+    PEX {
+        tid: usize,
+        peers: Vec<SocketAddr>,
+    },
     #[allow(clippy::upper_case_acronyms)]
-    PEX { tid: usize, peers: Vec<SocketAddr> },
+    LSD {
+        tid: usize,
+        peers: Vec<SocketAddr>,
+    },
+    PortMapped(PortMapping),
+}
+
+/// What the gateway forwards to us, ports are None when they aren't forwarded.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PortMapping {
+    pub external_ip: Option<IpAddr>,
+    pub port: Option<u16>,
+    pub dht_port: Option<u16>,
+    /// None for a permanent lease
+    pub expires: Option<DateTime<Utc>>,
 }
 
 #[derive(Debug)]
 pub struct TrackerResponse {
     pub peers: Vec<SocketAddr>,
     pub interval: u32,
+    /// Announces must not be made more often than this, if given
+    pub min_interval: Option<u32>,
     pub leechers: u32,
     pub seeders: u32,
 }
 
-const POLL_INT_MS: usize = 1000;
+/// Interval of the timer which drives timeouts, retransmits and DHT upkeep. It wakes the
+/// poll anyway, so waits never need to outlast it.
+const TICK_MS: usize = 150;
 
 impl Tracker {
     pub fn start(
@@ -101,11 +155,27 @@ impl Tracker {
         let poll = amy::Poller::new()?;
         let mut reg = poll.get_registrar();
         let (ch, dh) = handle::Handle::new(creg, &mut reg)?;
-        let timer = reg.set_interval(150)?;
+        let timer = reg.set_interval(TICK_MS)?;
         let udp = udp::Handler::new(config.trk.port, &reg, config.port)?;
         let dht = dht::Manager::new(config.clone(), &reg, db)?;
-        let http = http::Handler::new(&reg, config.port)?;
-        let dns = dns::Resolver::new(&reg)?;
+        let http = http::Handler::new(&reg, config.port, &config.trk.headers)?;
+        let dns = dns::Resolver::new(&mut reg, &config.dns)?;
+        let upnp = if config.net.upnp {
+            let mut ports = vec![(upnp::Protocol::Tcp, config.port)];
+            if config.dht.bootstrap_node.is_some() || config.dht.bootstrap_node6.is_some() {
+                ports.push((upnp::Protocol::Udp, config.dht.port));
+            }
+            Some(upnp::PortMapper::new(&reg, ports)?)
+        } else {
+            None
+        };
+        let lsd = if config.net.lsd {
+            lsd::Handler::new(&reg, config.port)
+                .inspect_err(|e| error!("Failed to start local peer discovery: {}", e))
+                .ok()
+        } else {
+            None
+        };
         let th = dh.run("trk", move |h| {
             Tracker {
                 config,
@@ -115,6 +185,9 @@ impl Tracker {
                 dht,
                 http,
                 dns,
+                ext_ip: ext_ip::Votes::new(),
+                upnp,
+                lsd,
                 timer,
                 queue: VecDeque::new(),
                 shutting_down: false,
@@ -129,7 +202,7 @@ impl Tracker {
 
         debug!("Initialized!");
         'outer: loop {
-            match self.poll.wait(POLL_INT_MS) {
+            match self.poll.wait(TICK_MS) {
                 Ok(events) => {
                     for event in events {
                         if self.handle_event(event).is_err() {
@@ -145,11 +218,23 @@ impl Tracker {
 
         self.shutting_down = true;
 
-        // Shutdown loop - wait for all requests to complete
-        loop {
-            for event in self.poll.wait(POLL_INT_MS).unwrap() {
-                if self.handle_event(event).is_err() {}
-                if self.http.complete() && self.udp.complete() {
+        // Shutdown loop - wait for outstanding (mostly stopped) announces to
+        // complete, giving up after the shutdown timeout.
+        let deadline =
+            time::Instant::now() + time::Duration::from_secs(self.config.disk.shutdown_timeout);
+        while !(self.http.complete() && self.udp.complete()) {
+            if time::Instant::now() >= deadline {
+                info!("Shutdown deadline reached, abandoning pending announces");
+                return;
+            }
+            match self.poll.wait(TICK_MS) {
+                Ok(events) => {
+                    for event in events {
+                        self.handle_event(event).ok();
+                    }
+                }
+                Err(e) => {
+                    error!("Failed to poll for events: {}", e);
                     return;
                 }
             }
@@ -161,7 +246,7 @@ impl Tracker {
             return self.handle_request();
         } else if event.id == self.timer {
             self.handle_timer();
-        } else if event.id == self.dns.id {
+        } else if event.id == self.dns.id() {
             self.handle_dns();
         } else {
             self.handle_socket(event);
@@ -181,13 +266,25 @@ impl Tracker {
                     trace!("Handling dht node addition req!");
                     self.dht.add_addr(addr);
                 }
+                Request::ExternalIp { voter, ip } => {
+                    if self.ext_ip.vote(voter, ip)
+                        && let Some(ip) = self.ext_ip.external()
+                    {
+                        info!("External IP appears to be {}", ip);
+                    }
+                }
                 Request::DHTAnnounce(hash) => {
                     trace!("Handling dht announce req!");
                     self.dht.announce(hash);
                 }
+                Request::LSDAnnounce(gp) => {
+                    if let Some(lsd) = self.lsd.as_mut() {
+                        lsd.announce(gp.id, gp.hash);
+                    }
+                }
                 Request::Ping => {}
                 Request::PurgeDNS => {
-                    self.dns.res.purge();
+                    self.dns.purge();
                 }
                 Request::Shutdown => {
                     return Err(());
@@ -230,14 +327,14 @@ impl Tracker {
 
     fn handle_dns(&mut self) {
         let mut dresps = vec![];
-        let res = self.dns.res.read(&mut self.dns.sock, |resp| {
+        let res = self.dns.read(|resp| {
             dresps.push(resp);
         });
         if let Err(e) = res {
             error!("DNS resolution failed: {}", e);
         }
         for r in dresps {
-            self.handle_dns_resp(r.into());
+            self.handle_dns_resp(r);
         }
     }
 
@@ -260,15 +357,21 @@ impl Tracker {
         }
 
         self.dht.tick();
+        if let Some(lsd) = self.lsd.as_mut() {
+            lsd.tick();
+        }
+        if let Some(m) = self.upnp.as_mut().and_then(|u| u.tick()) {
+            self.handle_port_mapping(m);
+        }
         let mut dresps = vec![];
-        let res = self.dns.res.tick(&mut self.dns.sock, |resp| {
+        let res = self.dns.tick(|resp| {
             dresps.push(resp);
         });
         if let Err(e) = res {
             info!("Failed to query dns: {}", e);
         }
         for r in dresps {
-            self.handle_dns_resp(r.into());
+            self.handle_dns_resp(r);
         }
     }
 
@@ -286,15 +389,44 @@ impl Tracker {
             for resp in self.udp.readable() {
                 self.send_response(resp);
             }
-        } else if self.dht.id() == event.id {
-            for resp in self.dht.readable() {
+        } else if self.dht.contains(event.id) {
+            for resp in self.dht.readable(event.id) {
+                self.send_response(resp);
+            }
+        } else if let Some(lsd) = self.lsd.as_mut().filter(|l| l.contains(event.id)) {
+            for resp in lsd.readable(event.id) {
                 self.send_response(resp);
             }
+        } else if let Some(upnp) = self.upnp.as_mut().filter(|u| u.contains(event.id)) {
+            let mapping = if event.event.readable() {
+                upnp.readable(event.id)
+            } else {
+                upnp.writable()
+            };
+            if let Some(m) = mapping {
+                self.handle_port_mapping(m);
+            }
         } else {
             error!("Unknown event occured for tracker: {:?}", event);
         };
     }
 
+    /// Announces the ports the gateway forwards, or our own once the mapping is gone.
+    fn handle_port_mapping(&mut self, m: upnp::Mapping) {
+        let port = m.ports.first().copied();
+        let dht_port = m.ports.get(1).copied();
+        self.http.set_peer_port(port.unwrap_or(self.config.port));
+        self.udp.set_peer_port(port.unwrap_or(self.config.port));
+        self.dht
+            .set_announce_port(dht_port.unwrap_or(self.config.dht.port));
+        self.send_response(Response::PortMapped(PortMapping {
+            external_ip: m.external_ip,
+            port,
+            dht_port,
+            expires: m.expires,
+        }));
+    }
+
     fn send_response(&mut self, r: Response) {
         if !self.shutting_down {
             trace!("Sending trk response to control!");
@@ -312,10 +444,16 @@ impl Request {
         torrent: &Torrent<T>,
         event: Option<Event>,
     ) -> Option<Request> {
-        let url = torrent.trackers().front()?.url.clone();
+        let tracker = torrent.trackers().current()?;
+        let (ip, ipv6) = torrent.announce_ips();
+        let report = torrent.info().private || torrent.config().trk.report_corrupt;
+        let event = match event {
+            None | Some(Event::Completed) if torrent.partial_seed() => Some(Event::Paused),
+            event => event,
+        };
         Some(Request::Announce(Announce {
             id: torrent.id(),
-            url,
+            url: tracker.url.clone(),
             hash: torrent.info().hash,
             uploaded: torrent.uploaded(),
             downloaded: torrent.downloaded(),
@@ -330,6 +468,12 @@ impl Request {
             // let existing peers connect otherwise
             num_want: if torrent.complete() { None } else { Some(50) },
             event,
+            key: torrent.announce_key(),
+            headers: tracker.headers.clone(),
+            ip,
+            ipv6,
+            corrupt: report.then(|| torrent.corrupt()),
+            redundant: report.then(|| torrent.redundant()),
         }))
     }
 
@@ -352,6 +496,12 @@ impl Request {
     pub fn custom<T: cio::CIO>(torrent: &Torrent<T>, url: Arc<Url>) -> Option<Request> {
         Request::new_announce(torrent, None).map(|mut r| {
             if let Request::Announce(ref mut a) = r {
+                a.headers = torrent
+                    .trackers()
+                    .iter()
+                    .find(|t| t.url == url)
+                    .map(|t| t.headers.clone())
+                    .unwrap_or_default();
                 a.url = url
             }
             r
@@ -364,6 +514,7 @@ impl TrackerResponse {
         TrackerResponse {
             peers: vec![],
             interval: 900,
+            min_interval: None,
             leechers: 0,
             seeders: 0,
         }
@@ -388,6 +539,9 @@ impl TrackerResponse {
                 resp.peers.push(SocketAddr::V4(socket));
             }
         }
+        if let Some(BEncode::Int(i)) = d.remove(b"min interval".as_ref()) {
+            resp.min_interval = u32::try_from(i).ok();
+        }
         match d.remove(b"interval".as_ref()) {
             Some(BEncode::Int(ref i)) => {
                 resp.interval = *i as u32;
@@ -397,3 +551,388 @@ impl TrackerResponse {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Instant;
+
+    use chrono::Utc;
+    use url::Url;
+
+    use super::{Error, Request, TrackerResponse};
+    use crate::THROT_TOKS;
+    use crate::config::Config;
+    use crate::control::cio::CIO;
+    use crate::control::cio::test::TCIO;
+    use crate::disk;
+    use crate::rpc;
+    use crate::rpc::resource::{Resource, SResourceUpdate};
+    use crate::throttle::Throttler;
+    use crate::torrent::{Info, PeerConn, Torrent};
+
+    fn key(req: Option<Request>) -> u32 {
+        match req {
+            Some(Request::Announce(a)) => a.key,
+            r => panic!("expected announce, got {r:?}"),
+        }
+    }
+
+    const ANNOUNCE_URL: &str = "http://example.com/announce";
+
+    fn throttler() -> Throttler {
+        let poll = amy::Poller::new().unwrap();
+        Throttler::new(None, None, THROT_TOKS, &poll.get_registrar()).unwrap()
+    }
+
+    fn torrent(config: &Arc<Config>, private: bool, cio: &TCIO) -> Torrent<TCIO> {
+        let mut info = Info::with_pieces(1);
+        info.piece_idx = Info::generate_piece_idx(1, u64::from(info.piece_len), &info.files);
+        info.announce = Some(Arc::new(Url::parse(ANNOUNCE_URL).unwrap()));
+        info.private = private;
+        Torrent::new(
+            config.clone(),
+            0,
+            None,
+            info,
+            throttler().get_throttle(0),
+            cio.new_handle(),
+            true,
+            false,
+        )
+    }
+
+    #[test]
+    fn test_announce_key() {
+        let mut config = Config::default();
+        config.disk.validate = false;
+        let config = Arc::new(config);
+        let cio = TCIO::new();
+        let t = torrent(&config, false, &cio);
+
+        let started = cio
+            .data()
+            .trk_msgs
+            .drain(..)
+            .map(|r| key(Some(r)))
+            .next()
+            .unwrap();
+        assert_eq!(key(Request::interval(&t)), started);
+        assert_eq!(key(Request::completed(&t)), started);
+
+        // The key survives a session reload
+        let t = reload(&config, &cio, &TCIO::new());
+        assert_eq!(key(Request::interval(&t)), started);
+    }
+
+    /// Deserializes the last session and info written by a torrent.
+    fn reload(config: &Arc<Config>, cio: &TCIO, new_cio: &TCIO) -> Torrent<TCIO> {
+        let (mut session, mut info) = (None, None);
+        for msg in cio.data().disk_msgs.drain(..) {
+            if let disk::Request::Serialize {
+                data, extension, ..
+            } = msg
+            {
+                match extension {
+                    None => session = Some(data),
+                    Some(".info") => info = Some(data),
+                    Some(_) => {}
+                }
+            }
+        }
+        Torrent::deserialize(
+            config.clone(),
+            1,
+            &session.unwrap(),
+            info.as_deref(),
+            throttler().get_throttle(1),
+            new_cio.new_handle(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_tracker_headers() {
+        let mut config = Config::default();
+        config.disk.validate = false;
+        let config = Arc::new(config);
+        let cio = TCIO::new();
+        let mut t = torrent(&config, true, &cio);
+        let id = t.trackers().iter().next().unwrap().id.clone();
+
+        t.update_tracker_headers(&id, &["Cookie: uid=1; pass=secret".to_owned()]);
+        let redacted = vec!["Cookie: <redacted>".to_owned()];
+        match cio.data().rpc_msgs.last() {
+            Some(rpc::CtlMessage::Update(u)) => match &u[..] {
+                [SResourceUpdate::TrackerHeaders { headers, .. }] => assert_eq!(headers, &redacted),
+                u => panic!("unexpected update {u:?}"),
+            },
+            m => panic!("unexpected message {m:?}"),
+        }
+
+        let req = Request::interval(&t).unwrap();
+        assert!(!format!("{req:?}").contains("secret"));
+        let Request::Announce(a) = req else {
+            unreachable!()
+        };
+        assert_eq!(a.headers.lines(), ["Cookie: uid=1; pass=secret"]);
+
+        // Headers are kept across restarts, and still redacted in the tracker resource
+        t.serialize_session_if_dirty();
+        let new_cio = TCIO::new();
+        let t = reload(&config, &cio, &new_cio);
+        let Some(Request::Announce(a)) = Request::interval(&t) else {
+            unreachable!()
+        };
+        assert_eq!(a.headers.lines(), ["Cookie: uid=1; pass=secret"]);
+        let trackers: Vec<_> = new_cio
+            .data()
+            .rpc_msgs
+            .iter()
+            .filter_map(|m| match m {
+                rpc::CtlMessage::Extant(r) => Some(r.clone()),
+                _ => None,
+            })
+            .flatten()
+            .filter_map(|r| match r {
+                Resource::Tracker(t) => Some(t),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(trackers.len(), 1);
+        assert_eq!(trackers[0].headers, redacted);
+    }
+
+    fn ips(req: Option<Request>) -> (Option<String>, Option<String>) {
+        match req {
+            Some(Request::Announce(a)) => (
+                a.ip.map(|ip| ip.to_string()),
+                a.ipv6.map(|ip| ip.to_string()),
+            ),
+            _ => panic!("expected an announce"),
+        }
+    }
+
+    #[test]
+    fn test_announce_ip() {
+        let mut config = Config::default();
+        config.disk.validate = false;
+        config.trk.announce_ip = Some("203.0.113.7".parse().unwrap());
+        let config = Arc::new(config);
+        let cio = TCIO::new();
+        let mut t = torrent(&config, false, &cio);
+        let some = |ip: &str| Some(ip.to_owned());
+        assert_eq!(ips(Request::interval(&t)), (some("203.0.113.7"), None));
+
+        // An override only replaces the configured address of its own family
+        t.set_announce_ip(Some("2001:db8::7".parse().unwrap()));
+        assert_eq!(
+            ips(Request::interval(&t)),
+            (some("203.0.113.7"), some("2001:db8::7"))
+        );
+        t.set_announce_ip(Some("198.51.100.1".parse().unwrap()));
+        assert_eq!(ips(Request::interval(&t)), (some("198.51.100.1"), None));
+        match cio.data().rpc_msgs.last() {
+            Some(rpc::CtlMessage::Update(u)) => match &u[..] {
+                [SResourceUpdate::TorrentAnnounceIp { announce_ip, .. }] => {
+                    assert_eq!(announce_ip, &some("198.51.100.1"))
+                }
+                u => panic!("unexpected update {u:?}"),
+            },
+            m => panic!("unexpected message {m:?}"),
+        }
+
+        // The override is kept across restarts
+        t.serialize_session_if_dirty();
+        let mut t = reload(&config, &cio, &TCIO::new());
+        assert_eq!(ips(Request::interval(&t)), (some("198.51.100.1"), None));
+
+        t.set_announce_ip(None);
+        assert_eq!(ips(Request::interval(&t)), (some("203.0.113.7"), None));
+    }
+
+    #[test]
+    fn test_report_corrupt() {
+        let reported = |r: Option<Request>| match r {
+            Some(Request::Announce(a)) => (a.corrupt, a.redundant),
+            r => panic!("unexpected request {r:?}"),
+        };
+        let config = |report_corrupt| {
+            let mut config = Config::default();
+            config.disk.validate = false;
+            config.trk.report_corrupt = report_corrupt;
+            Arc::new(config)
+        };
+        let cio = TCIO::new();
+        let t = torrent(&config(false), false, &cio);
+        assert_eq!(reported(Request::interval(&t)), (None, None));
+        let t = torrent(&config(false), true, &cio);
+        assert_eq!(reported(Request::interval(&t)), (Some(0), Some(0)));
+        let t = torrent(&config(true), false, &cio);
+        assert_eq!(reported(Request::started(&t)), (Some(0), Some(0)));
+    }
+
+    #[test]
+    fn test_announce_retry_backoff() {
+        let mut config = Config::default();
+        config.disk.validate = false;
+        config.net.min_announce_interval = 300;
+        let config = Arc::new(config);
+        let cio = TCIO::new();
+        let mut t = torrent(&config, false, &cio);
+        let url = Url::parse(ANNOUNCE_URL).unwrap();
+        let retry = |t: &Torrent<TCIO>| {
+            let trk = t.trackers().current().unwrap();
+            let secs = trk.update.unwrap() - Instant::now();
+            (secs.as_secs() + 1, trk.retry_at.is_some())
+        };
+
+        for expected in [30, 60, 120, 240, 300, 300] {
+            t.set_tracker_response(&url, &Err(Error::Timeout));
+            assert_eq!(retry(&t), (expected, true));
+        }
+        match cio.data().rpc_msgs.last() {
+            Some(rpc::CtlMessage::Update(u)) => match &u[..] {
+                [SResourceUpdate::TrackerStatus { retry_at, .. }] => assert!(retry_at.is_some()),
+                u => panic!("unexpected update {u:?}"),
+            },
+            m => panic!("unexpected message {m:?}"),
+        }
+
+        // A response resets the backoff
+        let mut resp = TrackerResponse::empty();
+        resp.interval = 1800;
+        t.set_tracker_response(&url, &Ok(resp));
+        assert_eq!(retry(&t), (1800, false));
+        t.set_tracker_response(&url, &Err(Error::DnsTimeout));
+        assert_eq!(retry(&t), (30, true));
+
+        // Trackers which responded with an error are retried at the minimum interval
+        t.set_tracker_response(&url, &Err(Error::TrackerError("unregistered".to_owned())));
+        assert_eq!(retry(&t), (300, true));
+        t.set_tracker_response(&url, &Err(Error::Timeout));
+        assert_eq!(retry(&t), (30, true));
+    }
+
+    #[test]
+    fn test_tracker_rpc_status() {
+        let mut config = Config::default();
+        config.disk.validate = false;
+        config.net.min_announce_interval = 300;
+        let config = Arc::new(config);
+        let cio = TCIO::new();
+        let mut t = torrent(&config, false, &cio);
+        let url = Url::parse(ANNOUNCE_URL).unwrap();
+        let status = || match cio.data().rpc_msgs.last() {
+            Some(rpc::CtlMessage::Update(u)) => match &u[..] {
+                [
+                    SResourceUpdate::TrackerStatus {
+                        error,
+                        next_announce,
+                        seeders,
+                        leechers,
+                        last_interval,
+                        ..
+                    },
+                ] => (
+                    error.is_some(),
+                    (*next_announce - Utc::now()).num_seconds(),
+                    (*seeders, *leechers, *last_interval),
+                ),
+                u => panic!("unexpected update {u:?}"),
+            },
+            m => panic!("unexpected message {m:?}"),
+        };
+        // The announce was scheduled a moment ago, so allow for the time since
+        let check = |failed, interval: i64, counts| {
+            let (error, secs, swarm) = status();
+            assert_eq!((error, swarm), (failed, counts));
+            assert!(
+                (interval - 5..=interval).contains(&secs),
+                "next announce in {secs}s, expected {interval}s"
+            );
+        };
+
+        let mut resp = TrackerResponse::empty();
+        resp.interval = 1800;
+        resp.seeders = 12;
+        resp.leechers = 3;
+        t.set_tracker_response(&url, &Ok(resp));
+        check(false, 1800, (Some(12), Some(3), Some(1800)));
+
+        // Failures clear the swarm counts and report the retry instead
+        t.set_tracker_response(&url, &Err(Error::Timeout));
+        check(true, 30, (None, None, None));
+        t.set_tracker_response(&url, &Err(Error::TrackerError("unregistered".to_owned())));
+        check(true, 300, (None, None, None));
+    }
+
+    #[test]
+    fn test_lsd_announce() {
+        let lsd = |lsd, private| {
+            let mut config = Config::default();
+            config.disk.validate = false;
+            config.net.lsd = lsd;
+            let cio = TCIO::new();
+            let mut t = torrent(&Arc::new(config), private, &cio);
+            t.lsd_announce();
+            cio.data()
+                .trk_msgs
+                .iter()
+                .filter(|r| matches!(r, Request::LSDAnnounce(gp) if gp.hash == t.info().hash))
+                .count()
+        };
+        // Once on start and once more when asked
+        assert_eq!(lsd(true, false), 2);
+        assert_eq!(lsd(true, true), 0);
+        assert_eq!(lsd(false, false), 0);
+    }
+
+    #[test]
+    fn test_private_announce_first() {
+        let mut config = Config::default();
+        config.disk.validate = false;
+        config.trk.private_announce_first = true;
+        let config = Arc::new(config);
+        let cio = TCIO::new();
+        let url = Url::parse(ANNOUNCE_URL).unwrap();
+
+        let mut public = torrent(&config, false, &cio);
+        assert!(public.peers_allowed());
+        assert!(public.add_peer(PeerConn::test()).is_some());
+
+        let mut t = torrent(&config, true, &cio);
+        assert!(!t.peers_allowed());
+        assert_eq!(t.add_peer(PeerConn::test()), None);
+        t.set_tracker_response(&url, &Err(Error::DnsTimeout));
+        assert!(!t.peers_allowed());
+        t.set_tracker_response(&url, &Ok(TrackerResponse::empty()));
+        assert!(t.peers_allowed());
+        assert!(t.add_peer(PeerConn::test()).is_some());
+    }
+
+    fn decode(data: &[u8]) -> super::Result<TrackerResponse> {
+        TrackerResponse::from_bencode(crate::bencode::decode_buf(data).unwrap())
+    }
+
+    #[test]
+    fn test_decode_failure() {
+        match decode(b"d14:failure reason12:unregistered8:intervali1800ee") {
+            Err(Error::TrackerError(reason)) => assert_eq!(reason, "unregistered"),
+            r => panic!("expected tracker error, got {r:?}"),
+        }
+    }
+
+    #[test]
+    fn test_decode_min_interval() {
+        let resp =
+            decode(b"d8:intervali900e12:min intervali1800e5:peers6:\x7f\x00\x00\x01\x1a\xe1e")
+                .unwrap();
+        assert_eq!(resp.interval, 900);
+        assert_eq!(resp.min_interval, Some(1800));
+        assert_eq!(resp.peers, vec!["127.0.0.1:6881".parse().unwrap()]);
+
+        let resp = decode(b"d8:intervali900e5:peers0:e").unwrap();
+        assert_eq!(resp.min_interval, None);
+    }
+}
@@ -0,0 +1,118 @@
+//! NAT-PMP (RFC 6886) messages, used with gateways that don't answer UPnP discovery.
+
+use std::net::Ipv4Addr;
+
+use byteorder::{BigEndian, ByteOrder};
+
+use super::upnp::Protocol;
+use crate::tracker::{Error, Result};
+
+/// Port gateways listen for requests on
+pub const PORT: u16 = 5351;
+const VERSION: u8 = 0;
+const OP_EXTERNAL_IP: u8 = 0;
+const OP_MAP_UDP: u8 = 1;
+const OP_MAP_TCP: u8 = 2;
+/// Added to the opcode of a request in its response
+const OP_RESPONSE: u8 = 128;
+
+#[derive(Debug, PartialEq)]
+pub enum Response {
+    ExternalIp(Ipv4Addr),
+    Mapped {
+        proto: Protocol,
+        internal: u16,
+        external: u16,
+        lifetime: u32,
+    },
+}
+
+pub fn external_ip_request() -> [u8; 2] {
+    [VERSION, OP_EXTERNAL_IP]
+}
+
+/// Asks for `internal` to be forwarded from `external` for `lifetime` seconds. The gateway
+/// may pick another external port or lifetime.
+pub fn map_request(proto: Protocol, internal: u16, external: u16, lifetime: u32) -> [u8; 12] {
+    let mut req = [0u8; 12];
+    req[0] = VERSION;
+    req[1] = match proto {
+        Protocol::Udp => OP_MAP_UDP,
+        Protocol::Tcp => OP_MAP_TCP,
+    };
+    BigEndian::write_u16(&mut req[4..6], internal);
+    BigEndian::write_u16(&mut req[6..8], external);
+    BigEndian::write_u32(&mut req[8..12], lifetime);
+    req
+}
+
+pub fn parse(data: &[u8]) -> Result<Response> {
+    let invalid = || Error::PortMapping("invalid NAT-PMP response".to_owned());
+    if data.len() < 4 || data[0] != VERSION || data[1] < OP_RESPONSE {
+        return Err(invalid());
+    }
+    let code = BigEndian::read_u16(&data[2..4]);
+    if code != 0 {
+        return Err(Error::NatPmpResult(code));
+    }
+    let proto = match (data[1] - OP_RESPONSE, data.len()) {
+        (OP_EXTERNAL_IP, 12) => {
+            return Ok(Response::ExternalIp(Ipv4Addr::new(
+                data[8], data[9], data[10], data[11],
+            )));
+        }
+        (OP_MAP_UDP, 16) => Protocol::Udp,
+        (OP_MAP_TCP, 16) => Protocol::Tcp,
+        _ => return Err(invalid()),
+    };
+    Ok(Response::Mapped {
+        proto,
+        internal: BigEndian::read_u16(&data[8..10]),
+        external: BigEndian::read_u16(&data[10..12]),
+        lifetime: BigEndian::read_u32(&data[12..16]),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_map_request() {
+        assert_eq!(
+            map_request(Protocol::Tcp, 16493, 16500, 3600),
+            [0, 2, 0, 0, 0x40, 0x6d, 0x40, 0x74, 0, 0, 0x0e, 0x10]
+        );
+        assert_eq!(map_request(Protocol::Udp, 1, 2, 0)[1], 1);
+        assert_eq!(external_ip_request(), [0, 0]);
+    }
+
+    #[test]
+    fn test_parse() {
+        let resp = [
+            0, 130, 0, 0, 0, 0, 0, 9, 0x40, 0x6d, 0x40, 0x74, 0, 0, 0x07, 0x08,
+        ];
+        assert_eq!(
+            parse(&resp).unwrap(),
+            Response::Mapped {
+                proto: Protocol::Tcp,
+                internal: 16493,
+                external: 16500,
+                lifetime: 1800,
+            }
+        );
+        assert_eq!(
+            parse(&[0, 128, 0, 0, 0, 0, 0, 9, 203, 0, 113, 5]).unwrap(),
+            Response::ExternalIp(Ipv4Addr::new(203, 0, 113, 5))
+        );
+        // Not authorized
+        assert!(matches!(
+            parse(&[0, 130, 0, 2, 0, 0, 0, 9]),
+            Err(Error::NatPmpResult(2))
+        ));
+        // Requests and truncated responses
+        assert!(parse(&[0, 2, 0, 0, 0x40, 0x6d, 0x40, 0x74, 0, 0, 0x0e, 0x10]).is_err());
+        assert!(parse(&resp[..12]).is_err());
+        assert!(parse(&[1, 128, 0, 0]).is_err());
+    }
+}
@@ -1,12 +1,13 @@
 use std::io::{self, Cursor, Read, Write};
 use std::net::{SocketAddr, UdpSocket};
-use std::time;
+use std::time::{Duration, Instant};
 
 use byteorder::{BigEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
 use rand::random;
 
 use crate::PEER_ID;
 use crate::tracker::{Announce, Error, Event, Response, Result, TrackerResponse, dns};
+use crate::util::timer::Timers;
 use crate::util::{FHashMap, UHashMap, bytes_to_addr};
 
 // We're not going to bother with backoff, if the tracker/network aren't working now
@@ -23,22 +24,78 @@ pub struct Handler {
     transactions: FHashMap<u32, usize>,
     conn_count: usize,
     buf: Vec<u8>,
+    timers: Timers<Deadline>,
 }
 
 struct Connection {
     torrent: usize,
-    last_updated: time::Instant,
-    last_retrans: time::Instant,
+    /// Transaction id of the outstanding request
+    transaction: Option<u32>,
     state: State,
     announce: Announce,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+enum Deadline {
+    Timeout(usize),
+    Retransmit(usize),
+}
+
 enum State {
     ResolvingDNS { port: u16 },
     Connecting { addr: SocketAddr, data: [u8; 16] },
     Announcing { addr: SocketAddr, data: [u8; 98] },
 }
 
+/// Builds a BEP 15 announce request.
+fn announce_packet(announce: &Announce, connection_id: u64, tid: u32, port: u16) -> [u8; 98] {
+    let mut data = [0u8; 98];
+    let mut announce_req = Cursor::new(&mut data[..]);
+    announce_req.write_u64::<BigEndian>(connection_id).unwrap();
+    // announce action
+    announce_req.write_u32::<BigEndian>(1).unwrap();
+    announce_req.write_u32::<BigEndian>(tid).unwrap();
+
+    announce_req.write_all(&announce.hash).unwrap();
+    announce_req.write_all(&PEER_ID[..]).unwrap();
+    announce_req
+        .write_u64::<BigEndian>(announce.downloaded)
+        .unwrap();
+    announce_req.write_u64::<BigEndian>(announce.left).unwrap();
+    announce_req
+        .write_u64::<BigEndian>(announce.uploaded)
+        .unwrap();
+    match announce.event {
+        Some(Event::Started) => {
+            announce_req.write_u32::<BigEndian>(2).unwrap();
+        }
+        Some(Event::Stopped) => {
+            announce_req.write_u32::<BigEndian>(3).unwrap();
+        }
+        Some(Event::Completed) => {
+            announce_req.write_u32::<BigEndian>(1).unwrap();
+        }
+        Some(Event::Paused) => {
+            announce_req.write_u32::<BigEndian>(4).unwrap();
+        }
+        None => {
+            announce_req.write_u32::<BigEndian>(0).unwrap();
+        }
+    }
+
+    // IP, 0 lets the tracker use the packet's source address
+    let ip = announce.ip.map(u32::from).unwrap_or(0);
+    announce_req.write_u32::<BigEndian>(ip).unwrap();
+    // Key
+    announce_req.write_u32::<BigEndian>(announce.key).unwrap();
+    // Num want
+    let nw = announce.num_want.map(i32::from).unwrap_or(-1);
+    announce_req.write_i32::<BigEndian>(nw).unwrap();
+    // port
+    announce_req.write_u16::<BigEndian>(port).unwrap();
+    data
+}
+
 impl Handler {
     pub fn new(listening_port: u16, reg: &amy::Registrar, peer_port: u16) -> io::Result<Handler> {
         let sock = UdpSocket::bind(("0.0.0.0", listening_port))?;
@@ -52,6 +109,7 @@ impl Handler {
             transactions: FHashMap::default(),
             conn_count: 0,
             buf: vec![0u8; 350],
+            timers: Timers::new(),
         })
     }
 
@@ -63,6 +121,10 @@ impl Handler {
         self.connections.is_empty()
     }
 
+    pub fn set_peer_port(&mut self, port: u16) {
+        self.peer_port = port;
+    }
+
     pub fn active_requests(&self) -> usize {
         self.connections.len()
     }
@@ -86,12 +148,12 @@ impl Handler {
             id,
             Connection {
                 torrent: req.id,
-                last_updated: time::Instant::now(),
-                last_retrans: time::Instant::now(),
+                transaction: None,
                 state: State::ResolvingDNS { port },
                 announce: req,
             },
         );
+        self.reset_timeout(id);
         debug!("Dispatching DNS req for {:?}, url: {:?}", id, host);
         if let Some(ip) = dns.new_query(id, host).map_err(Error::DnsIo)? {
             debug!("Using cached DNS response");
@@ -110,7 +172,10 @@ impl Handler {
         let resp = if let Some(conn) = self.connections.get_mut(&id) {
             match conn.state {
                 State::ResolvingDNS { port } => {
-                    conn.last_updated = time::Instant::now();
+                    self.timers.schedule(
+                        Deadline::Timeout(id),
+                        Instant::now() + Duration::from_millis(TIMEOUT_MS),
+                    );
                     let tid = random::<u32>();
                     let mut data = [0u8; 16];
                     {
@@ -127,6 +192,7 @@ impl Handler {
                                 data,
                             };
                             self.transactions.insert(tid, id);
+                            conn.transaction = Some(tid);
                             None
                         }
                         Err(e) => Some(Response::Tracker {
@@ -142,7 +208,7 @@ impl Handler {
             None
         };
         if resp.is_some() {
-            self.connections.remove(&id);
+            self.remove(id);
             resp
         } else if success {
             self.send_data(id)
@@ -182,33 +248,25 @@ impl Handler {
 
     pub fn tick(&mut self) -> Vec<Response> {
         let mut resps = Vec::new();
-        let mut retrans = Vec::new();
-        {
-            self.connections.retain(|id, conn| {
-                if conn.last_updated.elapsed() > time::Duration::from_millis(TIMEOUT_MS) {
-                    resps.push(Response::Tracker {
-                        tid: conn.torrent,
-                        url: conn.announce.url.clone(),
-                        resp: Err(Error::Timeout),
-                    });
-                    debug!("Announce {:?} timed out", id);
-                    false
-                } else {
-                    if conn.last_retrans.elapsed() > time::Duration::from_millis(RETRANS_MS) {
-                        debug!("Retransmiting req {:?}", id);
-                        retrans.push(*id);
+        let now = Instant::now();
+        while let Some(deadline) = self.timers.pop_expired(now) {
+            match deadline {
+                Deadline::Timeout(id) => {
+                    if let Some(conn) = self.remove(id) {
+                        debug!("Announce {:?} timed out", id);
+                        resps.push(Response::Tracker {
+                            tid: conn.torrent,
+                            url: conn.announce.url,
+                            resp: Err(Error::Timeout),
+                        });
+                    }
+                }
+                Deadline::Retransmit(id) => {
+                    debug!("Retransmiting req {:?}", id);
+                    if let Some(r) = self.send_data(id) {
+                        resps.push(r)
                     }
-                    true
                 }
-            });
-
-            let c = &self.connections;
-            self.transactions.retain(|_, id| c.contains_key(id));
-        }
-
-        for id in retrans {
-            if let Some(r) = self.send_data(id) {
-                resps.push(r)
             }
         }
         resps
@@ -224,74 +282,29 @@ impl Handler {
 
         let id = self.transactions.remove(&transaction_id)?;
 
-        let mut data = [0u8; 98];
         {
             let conn = self.connections.get_mut(&id)?;
             let addr = match conn.state {
                 State::Connecting { addr, .. } => addr,
                 _ => return None,
             };
-
-            {
-                let mut announce_req = Cursor::new(&mut data[..]);
-                announce_req.write_u64::<BigEndian>(connection_id).unwrap();
-                // announce action
-                announce_req.write_u32::<BigEndian>(1).unwrap();
-
-                let tid = random::<u32>();
-                announce_req.write_u32::<BigEndian>(tid).unwrap();
-                self.transactions.insert(tid, id);
-
-                announce_req.write_all(&conn.announce.hash).unwrap();
-                announce_req.write_all(&PEER_ID[..]).unwrap();
-                announce_req
-                    .write_u64::<BigEndian>(conn.announce.downloaded)
-                    .unwrap();
-                announce_req
-                    .write_u64::<BigEndian>(conn.announce.left)
-                    .unwrap();
-                announce_req
-                    .write_u64::<BigEndian>(conn.announce.uploaded)
-                    .unwrap();
-                match conn.announce.event {
-                    Some(Event::Started) => {
-                        announce_req.write_u32::<BigEndian>(2).unwrap();
-                    }
-                    Some(Event::Stopped) => {
-                        announce_req.write_u32::<BigEndian>(3).unwrap();
-                    }
-                    Some(Event::Completed) => {
-                        announce_req.write_u32::<BigEndian>(1).unwrap();
-                    }
-                    None => {
-                        announce_req.write_u32::<BigEndian>(0).unwrap();
-                    }
-                }
-
-                // IP
-                announce_req.write_u32::<BigEndian>(0).unwrap();
-                // Key - TODO: randomly generate this
-                announce_req.write_u32::<BigEndian>(0xFFFF_00BA).unwrap();
-                // Num want
-                let nw = conn.announce.num_want.map(i32::from).unwrap_or(-1);
-                announce_req.write_i32::<BigEndian>(nw).unwrap();
-                // port
-                announce_req.write_u16::<BigEndian>(self.peer_port).unwrap();
-            }
+            let tid = random::<u32>();
+            self.transactions.insert(tid, id);
+            conn.transaction = Some(tid);
+            let data = announce_packet(&conn.announce, connection_id, tid, self.peer_port);
             conn.state = State::Announcing { addr, data };
-            conn.last_updated = time::Instant::now();
         }
+        self.reset_timeout(id);
         self.send_data(id)
     }
 
     fn process_announce(&mut self, len: usize) -> Option<Response> {
-        let mut announce_resp = Cursor::new(&self.buf[4..len]);
-        let mut resp = TrackerResponse::empty();
-        let transaction_id = announce_resp.read_u32::<BigEndian>().unwrap();
-
+        let transaction_id = BigEndian::read_u32(&self.buf[4..8]);
         let id = self.transactions.remove(&transaction_id)?;
+        let conn = self.remove(id)?;
 
-        let conn = self.connections.remove(&id)?;
+        let mut announce_resp = Cursor::new(&self.buf[8..len]);
+        let mut resp = TrackerResponse::empty();
 
         resp.interval = announce_resp.read_u32::<BigEndian>().unwrap();
         resp.leechers = announce_resp.read_u32::<BigEndian>().unwrap();
@@ -310,13 +323,12 @@ impl Handler {
     }
 
     fn process_error(&mut self, len: usize) -> Option<Response> {
-        let mut s = String::new();
-        let mut connect_resp = Cursor::new(&self.buf[4..len]);
-        let transaction_id = connect_resp.read_u32::<BigEndian>().unwrap();
-
+        let transaction_id = BigEndian::read_u32(&self.buf[4..8]);
         let id = self.transactions.remove(&transaction_id)?;
+        let conn = self.remove(id)?;
 
-        let conn = self.connections.remove(&id)?;
+        let mut s = String::new();
+        let mut connect_resp = Cursor::new(&self.buf[8..len]);
 
         match connect_resp.read_to_string(&mut s) {
             Ok(_) => Some(Response::Tracker {
@@ -338,6 +350,24 @@ impl Handler {
         c
     }
 
+    fn reset_timeout(&mut self, id: usize) {
+        self.timers.schedule(
+            Deadline::Timeout(id),
+            Instant::now() + Duration::from_millis(TIMEOUT_MS),
+        );
+    }
+
+    /// Removes a connection along with its pending transaction and deadlines.
+    fn remove(&mut self, id: usize) -> Option<Connection> {
+        let conn = self.connections.remove(&id)?;
+        if let Some(tid) = conn.transaction {
+            self.transactions.remove(&tid);
+        }
+        self.timers.cancel(Deadline::Timeout(id));
+        self.timers.cancel(Deadline::Retransmit(id));
+        Some(conn)
+    }
+
     fn send_data(&mut self, id: usize) -> Option<Response> {
         let tid;
         let res = {
@@ -347,20 +377,22 @@ impl Handler {
             // and i dont think we need to care
             match conn.state {
                 State::Connecting { ref addr, ref data } => {
-                    conn.last_retrans = time::Instant::now();
                     self.sock.send_to(data, addr).map_err(Error::SendTo)
                 }
                 State::Announcing { ref addr, ref data } => {
-                    conn.last_retrans = time::Instant::now();
                     self.sock.send_to(data, addr).map_err(Error::SendTo)
                 }
-                _ => Ok(0),
+                _ => return None,
             }
         };
+        self.timers.schedule(
+            Deadline::Retransmit(id),
+            Instant::now() + Duration::from_millis(RETRANS_MS),
+        );
 
         match res {
             Err(e) => {
-                let url = self.connections.remove(&id).unwrap().announce.url;
+                let url = self.remove(id).unwrap().announce.url;
                 Some(Response::Tracker {
                     tid,
                     url,
@@ -371,3 +403,53 @@ impl Handler {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use url::Url;
+
+    use super::announce_packet;
+    use crate::tracker::{Announce, Event};
+    use crate::util::http::Headers;
+
+    #[test]
+    fn test_announce_packet() {
+        let mut announce = Announce {
+            id: 0,
+            url: Arc::new(Url::parse("udp://tracker.example.org:1337").unwrap()),
+            hash: [7; 20],
+            uploaded: 1,
+            downloaded: 2,
+            left: 3,
+            num_want: Some(50),
+            event: Some(Event::Started),
+            key: 0xdead_beef,
+            headers: Headers::default(),
+            ip: None,
+            ipv6: None,
+            corrupt: None,
+            redundant: None,
+        };
+        let data = announce_packet(&announce, 0x0102_0304_0506_0708, 42, 16384);
+        assert_eq!(
+            &data[..16],
+            &[1, 2, 3, 4, 5, 6, 7, 8, 0, 0, 0, 1, 0, 0, 0, 42]
+        );
+        assert_eq!(&data[80..84], &[0, 0, 0, 2]);
+        assert_eq!(&data[84..88], &[0, 0, 0, 0]);
+        assert_eq!(&data[88..92], &[0xde, 0xad, 0xbe, 0xef]);
+        assert_eq!(&data[92..98], &[0, 0, 0, 50, 0x40, 0x00]);
+
+        announce.ip = Some("203.0.113.7".parse().unwrap());
+        // Only IPv4 fits in the packet
+        announce.ipv6 = Some("2001:db8::7".parse().unwrap());
+        let data = announce_packet(&announce, 0, 42, 16384);
+        assert_eq!(&data[84..88], &[203, 0, 113, 7]);
+
+        announce.event = Some(Event::Paused);
+        let data = announce_packet(&announce, 0, 42, 16384);
+        assert_eq!(&data[80..84], &[0, 0, 0, 4]);
+    }
+}
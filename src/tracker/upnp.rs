@@ -0,0 +1,844 @@
+use std::fmt::Write as _;
+use std::io;
+use std::mem;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use rand::RngExt;
+use sstream::SStream;
+use url::Url;
+
+use super::http::reader::{ReadRes, Reader};
+use super::http::writer::Writer;
+use super::natpmp;
+use crate::tracker::{Error, Result};
+use crate::util::{http, native};
+
+const SSDP_ADDR: &str = "239.255.255.250:1900";
+const SEARCH_TARGET: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+const SERVICES: [&str; 2] = [
+    "urn:schemas-upnp-org:service:WANIPConnection:",
+    "urn:schemas-upnp-org:service:WANPPPConnection:",
+];
+/// Requested lease, mappings are renewed once half of it has passed
+const LEASE_SECS: u32 = 3600;
+/// Delay before discovery is retried after a failure
+const RETRY_SECS: u64 = 300;
+const TIMEOUT_MS: u64 = 5_000;
+/// External ports tried for a mapping before giving up on conflicts
+const MAX_ATTEMPTS: usize = 4;
+/// ConflictInMappingEntry, the external port is forwarded elsewhere
+const ERR_CONFLICT: u32 = 718;
+/// OnlyPermanentLeasesSupported
+const ERR_PERMANENT_ONLY: u32 = 725;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+/// What the gateway currently forwards to us.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Mapping {
+    pub external_ip: Option<IpAddr>,
+    /// External port of each mapped port, in the order they were given
+    pub ports: Vec<u16>,
+    /// None for a permanent lease
+    pub expires: Option<DateTime<Utc>>,
+}
+
+/// Maps ports on a UPnP internet gateway device, found through SSDP, and keeps the leases
+/// renewed. If no UPnP gateway answers, NAT-PMP is tried with the default gateway instead.
+/// Only one request to the gateway is in flight at a time.
+pub struct PortMapper {
+    reg: amy::Registrar,
+    /// Sends SSDP searches and NAT-PMP requests
+    udp: UdpSocket,
+    udp_id: usize,
+    ports: Vec<(Protocol, u16)>,
+    gateway: Option<Gateway>,
+    /// NAT-PMP gateway, used when there's no UPnP one
+    pmp: Option<SocketAddr>,
+    state: State,
+    /// When the next discovery or renewal is due, or the current step times out
+    deadline: Instant,
+    lease: u32,
+    mapping: Mapping,
+    /// External ports mapped so far in the current round
+    mapped: Vec<u16>,
+}
+
+struct Gateway {
+    control: Url,
+    service: String,
+    addr: SocketAddr,
+    /// Our address on the gateway's network
+    local_ip: IpAddr,
+}
+
+enum State {
+    Idle,
+    Discovering,
+    Request {
+        id: usize,
+        sock: SStream,
+        conn: Conn,
+        action: Action,
+    },
+    Pmp(PmpAction),
+}
+
+enum Conn {
+    Writing(Writer),
+    Reading(Reader),
+}
+
+enum Action {
+    Describe(Url),
+    Map {
+        idx: usize,
+        port: u16,
+        attempt: usize,
+    },
+    ExternalIp,
+}
+
+#[derive(Clone, Copy)]
+enum PmpAction {
+    Map(usize),
+    ExternalIp,
+}
+
+impl PortMapper {
+    pub fn new(reg: &amy::Registrar, ports: Vec<(Protocol, u16)>) -> io::Result<PortMapper> {
+        let udp = UdpSocket::bind("0.0.0.0:0")?;
+        udp.set_nonblocking(true)?;
+        let udp_id = reg.register(&udp, amy::Event::Read)?;
+        Ok(PortMapper {
+            reg: reg.clone(),
+            udp,
+            udp_id,
+            ports,
+            gateway: None,
+            pmp: None,
+            state: State::Idle,
+            deadline: Instant::now(),
+            lease: LEASE_SECS,
+            mapping: Mapping::default(),
+            mapped: Vec::new(),
+        })
+    }
+
+    pub fn contains(&self, id: usize) -> bool {
+        self.udp_id == id || matches!(self.state, State::Request { id: rid, .. } if rid == id)
+    }
+
+    /// Starts discovery or renews the mappings when due, and fails stalled requests.
+    pub fn tick(&mut self) -> Option<Mapping> {
+        if Instant::now() < self.deadline {
+            return None;
+        }
+        let res = match self.state {
+            State::Idle if self.gateway.is_some() => self.map(0, None, 0),
+            State::Idle if self.pmp.is_some() => self.pmp_map(0),
+            State::Idle => self.discover(),
+            State::Discovering => self.pmp_discover(),
+            State::Request { .. } | State::Pmp(_) => Err(Error::Timeout),
+        };
+        res.err().and_then(|e| self.fail(e))
+    }
+
+    pub fn readable(&mut self, id: usize) -> Option<Mapping> {
+        if id == self.udp_id {
+            self.read_udp()
+        } else {
+            self.progress()
+        }
+    }
+
+    pub fn writable(&mut self) -> Option<Mapping> {
+        self.progress()
+    }
+
+    fn discover(&mut self) -> Result<()> {
+        debug!("Searching for UPnP gateways");
+        let search = format!(
+            "M-SEARCH * HTTP/1.1\r\n\
+             HOST: {SSDP_ADDR}\r\n\
+             MAN: \"ssdp:discover\"\r\n\
+             MX: 2\r\n\
+             ST: {SEARCH_TARGET}\r\n\r\n"
+        );
+        self.udp
+            .send_to(search.as_bytes(), SSDP_ADDR)
+            .map_err(Error::SendTo)?;
+        self.state = State::Discovering;
+        self.deadline = Instant::now() + Duration::from_millis(TIMEOUT_MS);
+        Ok(())
+    }
+
+    fn read_udp(&mut self) -> Option<Mapping> {
+        let mut buf = [0u8; 1500];
+        let mut res = None;
+        while let Ok((len, from)) = self.udp.recv_from(&mut buf) {
+            match self.state {
+                // Only the first gateway to answer is used
+                State::Discovering => {
+                    let Some(location) = parse_location(&buf[..len]) else {
+                        continue;
+                    };
+                    // Hosts may only point us at themselves, anything else on the
+                    // network could otherwise aim our requests wherever it likes
+                    if !gateway_addr(&location).is_ok_and(|addr| addr.ip() == from.ip()) {
+                        debug!(
+                            "Ignoring UPnP gateway at {} advertised by {}",
+                            location, from
+                        );
+                        continue;
+                    }
+                    debug!("Found UPnP gateway at {}", location);
+                    if let Err(e) = self.describe(location) {
+                        res = self.fail(e);
+                    }
+                }
+                State::Pmp(action) if Some(from) == self.pmp => {
+                    let m = match self.pmp_complete(action, &buf[..len]) {
+                        Ok(m) => m,
+                        Err(e) => self.fail(e),
+                    };
+                    res = m.or(res);
+                }
+                _ => {}
+            }
+        }
+        res
+    }
+
+    /// Falls back to NAT-PMP with the default gateway once UPnP discovery times out.
+    fn pmp_discover(&mut self) -> Result<()> {
+        let gw = native::default_gateway()
+            .ok_or_else(|| Error::PortMapping("no gateway found".to_owned()))?;
+        debug!("No UPnP gateway found, trying NAT-PMP with {}", gw);
+        self.pmp = Some(SocketAddr::new(gw.into(), natpmp::PORT));
+        self.pmp_map(0)
+    }
+
+    /// Maps the port at `idx` over NAT-PMP, asking for the current external port.
+    fn pmp_map(&mut self, idx: usize) -> Result<()> {
+        if idx == 0 {
+            self.mapped.clear();
+        }
+        let (proto, internal) = self.ports[idx];
+        let port = self.mapping.ports.get(idx).copied().unwrap_or(internal);
+        let req = natpmp::map_request(proto, internal, port, self.lease);
+        self.pmp_request(&req, PmpAction::Map(idx))
+    }
+
+    fn pmp_request(&mut self, req: &[u8], action: PmpAction) -> Result<()> {
+        self.udp
+            .send_to(req, self.pmp.unwrap())
+            .map_err(Error::SendTo)?;
+        self.state = State::Pmp(action);
+        self.deadline = Instant::now() + Duration::from_millis(TIMEOUT_MS);
+        Ok(())
+    }
+
+    /// Handles a NAT-PMP response and sends the next request. Gateways pick the external
+    /// port and lifetime of a mapping, so the shortest lifetime granted sets the renewal.
+    fn pmp_complete(&mut self, action: PmpAction, data: &[u8]) -> Result<Option<Mapping>> {
+        match (action, natpmp::parse(data)?) {
+            (
+                PmpAction::Map(idx),
+                natpmp::Response::Mapped {
+                    proto,
+                    internal,
+                    external,
+                    lifetime,
+                },
+            ) if (proto, internal) == self.ports[idx] => {
+                if lifetime == 0 {
+                    return Err(Error::PortMapping(
+                        "NAT-PMP mapping without a lease".to_owned(),
+                    ));
+                }
+                self.mapped.push(external);
+                self.lease = self.lease.min(lifetime);
+                if idx + 1 < self.ports.len() {
+                    self.pmp_map(idx + 1)?;
+                } else {
+                    self.pmp_request(&natpmp::external_ip_request(), PmpAction::ExternalIp)?;
+                }
+            }
+            (PmpAction::ExternalIp, natpmp::Response::ExternalIp(ip)) => {
+                self.state = State::Idle;
+                let mapping = Mapping {
+                    external_ip: Some(ip.into()),
+                    ports: mem::take(&mut self.mapped),
+                    expires: Some(Utc::now() + chrono::Duration::seconds(i64::from(self.lease))),
+                };
+                self.deadline = Instant::now() + Duration::from_secs(u64::from(self.lease / 2));
+                if mapping != self.mapping {
+                    info!(
+                        "Mapped ports {:?} to {:?} on {:?} through NAT-PMP",
+                        self.ports, mapping.ports, mapping.external_ip
+                    );
+                    self.mapping = mapping.clone();
+                    return Ok(Some(mapping));
+                }
+            }
+            // A late answer to an earlier request
+            _ => {}
+        }
+        Ok(None)
+    }
+
+    /// Fetches the description of the gateway at `location` to find its control URL.
+    fn describe(&mut self, location: Url) -> Result<()> {
+        let addr = gateway_addr(&location)?;
+        let mut req = Vec::with_capacity(256);
+        http::RequestBuilder::new("GET", location.path(), location.query())
+            .header("Host", &addr.to_string())
+            .header("Connection", "close")
+            .encode(&mut req);
+        self.request(addr, req, Action::Describe(location))
+    }
+
+    /// Maps the port at `idx`, asking for `port` externally or else the current mapping.
+    fn map(&mut self, idx: usize, port: Option<u16>, attempt: usize) -> Result<()> {
+        if idx == 0 {
+            self.mapped.clear();
+        }
+        let (proto, internal) = self.ports[idx];
+        let port = port
+            .or_else(|| self.mapping.ports.get(idx).copied())
+            .unwrap_or(internal);
+        let gw = self.gateway.as_ref().unwrap();
+        let proto = match proto {
+            Protocol::Tcp => "TCP",
+            Protocol::Udp => "UDP",
+        };
+        let req = soap_request(
+            gw,
+            "AddPortMapping",
+            &[
+                ("NewRemoteHost", String::new()),
+                ("NewExternalPort", port.to_string()),
+                ("NewProtocol", proto.to_owned()),
+                ("NewInternalPort", internal.to_string()),
+                ("NewInternalClient", gw.local_ip.to_string()),
+                ("NewEnabled", "1".to_owned()),
+                ("NewPortMappingDescription", format!("synapse {proto}")),
+                ("NewLeaseDuration", self.lease.to_string()),
+            ],
+        );
+        let addr = gw.addr;
+        self.request(addr, req, Action::Map { idx, port, attempt })
+    }
+
+    fn request(&mut self, addr: SocketAddr, req: Vec<u8>, action: Action) -> Result<()> {
+        let mut sock = SStream::new_v4(None).map_err(Error::CreateSocket)?;
+        let id = self
+            .reg
+            .register(&sock, amy::Event::Both)
+            .map_err(Error::Registrar)?;
+        sock.connect(addr).map_err(Error::Connect)?;
+        self.state = State::Request {
+            id,
+            sock,
+            conn: Conn::Writing(Writer::new(req)),
+            action,
+        };
+        self.deadline = Instant::now() + Duration::from_millis(TIMEOUT_MS);
+        Ok(())
+    }
+
+    fn progress(&mut self) -> Option<Mapping> {
+        let State::Request { sock, conn, .. } = &mut self.state else {
+            return None;
+        };
+        self.deadline = Instant::now() + Duration::from_millis(TIMEOUT_MS);
+        let res = match exchange(sock, conn) {
+            Ok(Some(body)) => self.complete(&String::from_utf8_lossy(&body)),
+            Ok(None) => return None,
+            Err(e) => Err(e),
+        };
+        match res {
+            Ok(m) => m,
+            Err(e) => self.fail(e),
+        }
+    }
+
+    /// Handles the response to the current request and starts the next one.
+    fn complete(&mut self, body: &str) -> Result<Option<Mapping>> {
+        let State::Request { action, .. } = mem::replace(&mut self.state, State::Idle) else {
+            unreachable!()
+        };
+        match action {
+            Action::Describe(location) => {
+                let (service, control) = parse_service(body)
+                    .ok_or_else(|| Error::PortMapping("no WAN connection service".to_owned()))?;
+                let control = location
+                    .join(&control)
+                    .map_err(|e| Error::UrlParse("invalid control URL", control, e))?;
+                let addr = gateway_addr(&control)?;
+                if addr.ip() != gateway_addr(&location)?.ip() {
+                    return Err(Error::PortMapping(format!(
+                        "control URL {control} isn't on the gateway"
+                    )));
+                }
+                self.gateway = Some(Gateway {
+                    local_ip: local_ip(addr).map_err(Error::Connect)?,
+                    control,
+                    service,
+                    addr,
+                });
+                self.map(0, None, 0)?;
+            }
+            Action::Map { idx, port, attempt } => match parse_fault(body) {
+                Some((ERR_CONFLICT, _)) if attempt + 1 < MAX_ATTEMPTS => {
+                    let port = rand::rng().random_range(1025..=u16::MAX);
+                    debug!("UPnP port {} is taken, trying {}", self.ports[idx].1, port);
+                    self.map(idx, Some(port), attempt + 1)?;
+                }
+                Some((ERR_PERMANENT_ONLY, _)) if self.lease != 0 => {
+                    self.lease = 0;
+                    self.map(idx, Some(port), attempt)?;
+                }
+                Some((code, desc)) => return Err(Error::UpnpFault(code, desc)),
+                None => {
+                    self.mapped.push(port);
+                    if idx + 1 < self.ports.len() {
+                        self.map(idx + 1, None, 0)?;
+                    } else {
+                        let req = soap_request(
+                            self.gateway.as_ref().unwrap(),
+                            "GetExternalIPAddress",
+                            &[],
+                        );
+                        let addr = self.gateway.as_ref().unwrap().addr;
+                        self.request(addr, req, Action::ExternalIp)?;
+                    }
+                }
+            },
+            Action::ExternalIp => {
+                if let Some((code, desc)) = parse_fault(body) {
+                    return Err(Error::UpnpFault(code, desc));
+                }
+                let mapping = Mapping {
+                    external_ip: tag(body, "NewExternalIPAddress").and_then(|ip| ip.parse().ok()),
+                    ports: mem::take(&mut self.mapped),
+                    expires: (self.lease != 0)
+                        .then(|| Utc::now() + chrono::Duration::seconds(i64::from(self.lease))),
+                };
+                self.deadline = Instant::now() + Duration::from_secs(u64::from(LEASE_SECS / 2));
+                if mapping != self.mapping {
+                    info!(
+                        "Mapped ports {:?} to {:?} on {:?} through UPnP",
+                        self.ports, mapping.ports, mapping.external_ip
+                    );
+                    self.mapping = mapping.clone();
+                    return Ok(Some(mapping));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Goes back to discovery after a delay, dropping any mapping we had.
+    fn fail(&mut self, e: Error) -> Option<Mapping> {
+        info!("Port mapping failed: {}", e);
+        self.state = State::Idle;
+        self.gateway = None;
+        self.pmp = None;
+        self.lease = LEASE_SECS;
+        self.deadline = Instant::now() + Duration::from_secs(RETRY_SECS);
+        if self.mapping == Mapping::default() {
+            None
+        } else {
+            self.mapping = Mapping::default();
+            Some(Mapping::default())
+        }
+    }
+}
+
+/// Writes out the request and then reads the response body until the gateway closes the
+/// connection.
+fn exchange(sock: &mut SStream, conn: &mut Conn) -> Result<Option<Vec<u8>>> {
+    if let Conn::Writing(w) = conn {
+        if w.writable(sock)?.is_none() {
+            return Ok(None);
+        }
+        *conn = Conn::Reading(Reader::new());
+    }
+    let Conn::Reading(r) = conn else {
+        unreachable!()
+    };
+    match r.readable(sock)? {
+        ReadRes::Done(body) => Ok(Some(body)),
+        ReadRes::None => Ok(None),
+        ReadRes::Redirect(_) => Err(Error::PortMapping("unexpected redirect".to_owned())),
+        ReadRes::Decoded(_) => unreachable!(),
+    }
+}
+
+fn soap_request(gw: &Gateway, action: &str, args: &[(&str, String)]) -> Vec<u8> {
+    let mut body = format!(
+        "<?xml version=\"1.0\"?>\r\n\
+         <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+         s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+         <s:Body><u:{action} xmlns:u=\"{}\">",
+        xml_escape(&gw.service)
+    );
+    for (name, value) in args {
+        write!(body, "<{name}>{value}</{name}>").unwrap();
+    }
+    write!(body, "</u:{action}></s:Body></s:Envelope>\r\n").unwrap();
+
+    let mut req = Vec::with_capacity(512 + body.len());
+    http::RequestBuilder::new("POST", gw.control.path(), gw.control.query())
+        .header("Host", &gw.addr.to_string())
+        .header("Content-Type", "text/xml; charset=\"utf-8\"")
+        .header("SOAPAction", &format!("\"{}#{action}\"", gw.service))
+        .header("Content-Length", &body.len().to_string())
+        .header("Connection", "close")
+        .encode(&mut req);
+    req.extend_from_slice(body.as_bytes());
+    req
+}
+
+/// Escapes text from the gateway for use in an attribute or element.
+fn xml_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Gateways advertise themselves by IP, so no DNS lookup is needed.
+fn gateway_addr(url: &Url) -> Result<SocketAddr> {
+    let ip = url
+        .host_str()
+        .and_then(|h| h.parse().ok())
+        .ok_or_else(|| Error::UrlNoHost(url.as_str().to_owned()))?;
+    Ok(SocketAddr::new(
+        ip,
+        url.port_or_known_default().unwrap_or(80),
+    ))
+}
+
+/// The local address packets to `addr` would be sent from. Connecting a UDP socket
+/// only selects the route, nothing is sent.
+fn local_ip(addr: SocketAddr) -> io::Result<IpAddr> {
+    let sock = UdpSocket::bind("0.0.0.0:0")?;
+    sock.connect(addr)?;
+    Ok(sock.local_addr()?.ip())
+}
+
+fn parse_location(data: &[u8]) -> Option<Url> {
+    let mut headers = [httparse::EMPTY_HEADER; 32];
+    let mut resp = httparse::Response::new(&mut headers);
+    resp.parse(data).ok()?;
+    if resp.code != Some(200) {
+        return None;
+    }
+    let location = resp
+        .headers
+        .iter()
+        .find(|h| h.name.eq_ignore_ascii_case("location"))?;
+    Url::parse(std::str::from_utf8(location.value).ok()?.trim()).ok()
+}
+
+/// Finds the type and control URL of the first WAN connection service in a device
+/// description.
+fn parse_service(xml: &str) -> Option<(String, String)> {
+    xml.split("<service>").skip(1).find_map(|s| {
+        let ty = tag(s, "serviceType")?;
+        // The type is also sent back in the SOAPAction header
+        if !SERVICES.iter().any(|p| ty.starts_with(p))
+            || ty.contains(|c: char| c.is_control() || c == '"')
+        {
+            return None;
+        }
+        Some((ty.to_owned(), tag(s, "controlURL")?.to_owned()))
+    })
+}
+
+/// The error code and description of a SOAP fault.
+fn parse_fault(xml: &str) -> Option<(u32, String)> {
+    let code = tag(xml, "errorCode")?.parse().ok()?;
+    let desc = tag(xml, "errorDescription").unwrap_or_default();
+    Some((code, desc.to_owned()))
+}
+
+/// The text of the first element named `name`, which mustn't have attributes.
+fn tag<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{name}>"))? + name.len() + 2;
+    let len = xml[start..].find(&format!("</{name}>"))?;
+    Some(xml[start..start + len].trim())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::*;
+
+    const DESCRIPTION: &str = "<?xml version=\"1.0\"?>\
+        <root xmlns=\"urn:schemas-upnp-org:device-1-0\"><device><serviceList>\
+        <service><serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType>\
+        <controlURL>/ctl/L3F</controlURL></service>\
+        </serviceList><deviceList><device><serviceList>\
+        <service><serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>\
+        <controlURL>/ctl/IPConn</controlURL></service>\
+        </serviceList></device></deviceList></device></root>";
+
+    fn fault(code: u32) -> String {
+        format!(
+            "<s:Envelope><s:Body><s:Fault><detail><UPnPError>\
+             <errorCode>{code}</errorCode><errorDescription>Nope</errorDescription>\
+             </UPnPError></detail></s:Fault></s:Body></s:Envelope>"
+        )
+    }
+
+    #[test]
+    fn test_parse_location() {
+        let resp = b"HTTP/1.1 200 OK\r\n\
+            CACHE-CONTROL: max-age=120\r\n\
+            ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\
+            Location: http://192.168.1.1:5000/rootDesc.xml\r\n\r\n";
+        assert_eq!(
+            parse_location(resp).unwrap().as_str(),
+            "http://192.168.1.1:5000/rootDesc.xml"
+        );
+        assert!(parse_location(b"HTTP/1.1 404 Not Found\r\n\r\n").is_none());
+        assert!(parse_location(b"NOTIFY * HTTP/1.1\r\n\r\n").is_none());
+    }
+
+    #[test]
+    fn test_parse_service() {
+        assert_eq!(
+            parse_service(DESCRIPTION).unwrap(),
+            (
+                "urn:schemas-upnp-org:service:WANIPConnection:1".to_owned(),
+                "/ctl/IPConn".to_owned()
+            )
+        );
+        assert!(parse_service("<root><service></service></root>").is_none());
+        let quoted = DESCRIPTION.replace("WANIPConnection:1", "WANIPConnection:1\"\r\nX: y");
+        assert!(parse_service(&quoted).is_none());
+        assert_eq!(xml_escape("a\"><b>&'"), "a&quot;&gt;&lt;b&gt;&amp;&apos;");
+        assert_eq!(parse_fault(&fault(718)).unwrap(), (718, "Nope".to_owned()));
+        assert!(parse_fault("<NewExternalIPAddress>1.2.3.4</NewExternalIPAddress>").is_none());
+    }
+
+    /// Serves a device description and answers SOAP actions, refusing the first TCP
+    /// mapping with a conflict. Returns the SOAP requests received.
+    fn gateway(listener: TcpListener, requests: usize) -> thread::JoinHandle<Vec<String>> {
+        thread::spawn(move || {
+            let mut received = Vec::new();
+            let mut conflicted = false;
+            for _ in 0..requests {
+                let (mut conn, _) = listener.accept().unwrap();
+                let mut req = Vec::new();
+                let mut buf = [0; 4096];
+                // Read the headers and, for a POST, the whole body
+                loop {
+                    let n = conn.read(&mut buf).unwrap();
+                    req.extend_from_slice(&buf[..n]);
+                    let s = String::from_utf8_lossy(&req);
+                    if (s.starts_with("GET") && s.contains("\r\n\r\n"))
+                        || s.contains("</s:Envelope>")
+                    {
+                        break;
+                    }
+                }
+                let req = String::from_utf8(req).unwrap();
+                let body = if req.starts_with("GET /rootDesc.xml") {
+                    DESCRIPTION.to_owned()
+                } else if req.contains("#AddPortMapping") {
+                    if req.contains("<NewProtocol>TCP") && !conflicted {
+                        conflicted = true;
+                        fault(ERR_CONFLICT)
+                    } else {
+                        "<s:Envelope><s:Body><u:AddPortMappingResponse/></s:Body></s:Envelope>"
+                            .to_owned()
+                    }
+                } else if req.contains("#GetExternalIPAddress") {
+                    "<s:Envelope><s:Body><u:GetExternalIPAddressResponse>\
+                     <NewExternalIPAddress>203.0.113.5</NewExternalIPAddress>\
+                     </u:GetExternalIPAddressResponse></s:Body></s:Envelope>"
+                        .to_owned()
+                } else {
+                    panic!("unexpected request {req}");
+                };
+                let status = if body.contains("errorCode") {
+                    "500 Internal Server Error"
+                } else {
+                    "200 OK"
+                };
+                write!(conn, "HTTP/1.1 {status}\r\nConnection: close\r\n\r\n{body}").unwrap();
+                received.push(req);
+            }
+            received
+        })
+    }
+
+    #[test]
+    fn test_map_ports() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let location = format!("http://{}/rootDesc.xml", listener.local_addr().unwrap());
+        // Description, the conflicting and retried TCP mapping, UDP and the external IP
+        let gw = gateway(listener, 5);
+
+        let poll = amy::Poller::new().unwrap();
+        let ports = vec![(Protocol::Tcp, 16493), (Protocol::Udp, 16494)];
+        let mut pm = PortMapper::new(&poll.get_registrar(), ports).unwrap();
+        pm.describe(Url::parse(&location).unwrap()).unwrap();
+
+        // Spurious wakeups are harmless, so just keep driving the exchange
+        let start = Instant::now();
+        let mapping = loop {
+            assert!(
+                start.elapsed() < Duration::from_secs(10),
+                "mapping timed out"
+            );
+            if let Some(m) = pm.writable() {
+                break m;
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+        let requests = gw.join().unwrap();
+
+        assert_eq!(mapping.external_ip, Some("203.0.113.5".parse().unwrap()));
+        assert_eq!(mapping.ports.len(), 2);
+        assert_ne!(mapping.ports[0], 16493);
+        assert_eq!(mapping.ports[1], 16494);
+        assert!(mapping.expires.unwrap() > Utc::now());
+        assert!(requests[1].starts_with("POST /ctl/IPConn "));
+        assert!(requests[1].contains("<NewInternalClient>127.0.0.1</NewInternalClient>"));
+        assert!(
+            requests[2].contains(&format!("<NewExternalPort>{}<", mapping.ports[0])),
+            "{}",
+            requests[2]
+        );
+        assert!(matches!(pm.state, State::Idle));
+
+        // Failures fall back to the internal ports
+        assert_eq!(pm.fail(Error::Timeout), Some(Mapping::default()));
+        assert_eq!(pm.fail(Error::Timeout), None);
+    }
+
+    #[test]
+    fn test_untrusted_gateway() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let local = format!("http://{}/rootDesc.xml", listener.local_addr().unwrap());
+        let poll = amy::Poller::new().unwrap();
+        let mut pm = PortMapper::new(&poll.get_registrar(), vec![(Protocol::Tcp, 16493)]).unwrap();
+        let ssdp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let to = SocketAddr::new(
+            "127.0.0.1".parse().unwrap(),
+            pm.udp.local_addr().unwrap().port(),
+        );
+        let reply = |location: &str| {
+            let resp = format!("HTTP/1.1 200 OK\r\nLocation: {location}\r\n\r\n");
+            ssdp.send_to(resp.as_bytes(), to).unwrap();
+            thread::sleep(Duration::from_millis(50));
+        };
+
+        // Replies pointing at another host are ignored
+        pm.state = State::Discovering;
+        reply("http://192.0.2.1:5000/rootDesc.xml");
+        assert_eq!(pm.readable(pm.udp_id), None);
+        assert!(matches!(pm.state, State::Discovering));
+        reply(&local);
+        assert_eq!(pm.readable(pm.udp_id), None);
+        assert!(matches!(pm.state, State::Request { .. }));
+
+        // As is a description whose control URL is elsewhere
+        let desc = DESCRIPTION.replace("/ctl/IPConn", "http://192.0.2.1/ctl/IPConn");
+        assert!(pm.complete(&desc).is_err());
+        assert!(pm.gateway.is_none());
+    }
+
+    /// Answers NAT-PMP requests, mapping each port to the next one up. TCP mappings get a
+    /// shorter lifetime than was asked for.
+    fn pmp_gateway(sock: UdpSocket, requests: usize) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            let mut buf = [0; 64];
+            for _ in 0..requests {
+                let (len, from) = sock.recv_from(&mut buf).unwrap();
+                let resp = match &buf[..len] {
+                    [0, 0] => vec![0, 128, 0, 0, 0, 0, 0, 1, 203, 0, 113, 5],
+                    [0, op, 0, 0, req @ ..] if req.len() == 8 => {
+                        let internal = u16::from_be_bytes([req[0], req[1]]);
+                        let lifetime: u32 = if *op == 2 { 1800 } else { 3600 };
+                        let mut resp = vec![0, 128 + op, 0, 0, 0, 0, 0, 1];
+                        resp.extend_from_slice(&internal.to_be_bytes());
+                        resp.extend_from_slice(&(internal + 1).to_be_bytes());
+                        resp.extend_from_slice(&lifetime.to_be_bytes());
+                        resp
+                    }
+                    req => panic!("unexpected request {req:?}"),
+                };
+                sock.send_to(&resp, from).unwrap();
+            }
+        })
+    }
+
+    #[test]
+    fn test_pmp_map_ports() {
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = sock.local_addr().unwrap();
+        // Both mappings and the external IP
+        let gw = pmp_gateway(sock, 3);
+
+        let poll = amy::Poller::new().unwrap();
+        let ports = vec![(Protocol::Tcp, 16493), (Protocol::Udp, 16494)];
+        let mut pm = PortMapper::new(&poll.get_registrar(), ports).unwrap();
+        // Answers from anything but the gateway are ignored
+        UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .send_to(
+                &[0, 128, 0, 0, 0, 0, 0, 1, 1, 2, 3, 4],
+                pm.udp.local_addr().unwrap(),
+            )
+            .unwrap();
+        pm.pmp = Some(addr);
+        pm.pmp_map(0).unwrap();
+
+        let start = Instant::now();
+        let mapping = loop {
+            assert!(
+                start.elapsed() < Duration::from_secs(10),
+                "mapping timed out"
+            );
+            if let Some(m) = pm.readable(pm.udp_id) {
+                break m;
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+        gw.join().unwrap();
+
+        assert_eq!(mapping.external_ip, Some("203.0.113.5".parse().unwrap()));
+        assert_eq!(mapping.ports, [16494, 16495]);
+        assert!(mapping.expires.unwrap() < Utc::now() + chrono::Duration::seconds(1801));
+        assert_eq!(pm.lease, 1800);
+        assert!(matches!(pm.state, State::Idle));
+        assert!(pm.deadline > Instant::now() + Duration::from_secs(800));
+
+        assert_eq!(pm.fail(Error::Timeout), Some(Mapping::default()));
+        assert_eq!(pm.pmp, None);
+    }
+}
@@ -1,5 +1,9 @@
+use std::fmt;
+
 use percent_encoding::percent_encode_byte;
 
+use crate::rpc::resource::{parse_tracker_header, redact_tracker_header};
+
 #[derive(Debug)]
 pub struct RequestBuilder<'a> {
     method: &'a str,
@@ -21,6 +25,11 @@ pub struct QueryPair<'a> {
     value: &'a [u8],
 }
 
+/// Extra headers added to tracker requests. These usually carry credentials, so only their
+/// names are ever displayed.
+#[derive(Clone, Default, PartialEq)]
+pub struct Headers(Vec<(String, String)>);
+
 pub trait EncodeToBuf {
     fn encode(&self, buf: &mut Vec<u8>);
 }
@@ -54,6 +63,61 @@ impl<'a> RequestBuilder<'a> {
         self.headers.push(HttpHeader { name, value });
         self
     }
+
+    pub fn has_header(&self, name: &str) -> bool {
+        self.headers
+            .iter()
+            .any(|h| h.name.eq_ignore_ascii_case(name))
+    }
+}
+
+impl Headers {
+    pub fn parse<S: AsRef<str>>(lines: &[S]) -> Result<Headers, String> {
+        let mut headers = Headers::default();
+        headers.update(lines)?;
+        Ok(headers)
+    }
+
+    /// Sets each `Name: value` line, replacing any header of the same name, or removes the
+    /// header if the value is empty. Nothing is changed if any line is invalid.
+    pub fn update<S: AsRef<str>>(&mut self, lines: &[S]) -> Result<(), String> {
+        let parsed = lines
+            .iter()
+            .map(|l| parse_tracker_header(l.as_ref()))
+            .collect::<Result<Vec<_>, _>>()?;
+        for (name, value) in parsed {
+            self.0.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+            if !value.is_empty() {
+                self.0.push((name.to_owned(), value.to_owned()));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+
+    pub fn lines(&self) -> Vec<String> {
+        self.iter().map(|(n, v)| format!("{n}: {v}")).collect()
+    }
+
+    pub fn redacted(&self) -> Vec<String> {
+        self.0
+            .iter()
+            .map(|(n, _)| redact_tracker_header(n))
+            .collect()
+    }
+}
+
+impl fmt::Debug for Headers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.redacted()).finish()
+    }
 }
 
 impl RequestBuilder<'_> {
@@ -148,4 +212,30 @@ mod test {
             ["GET /foobar/baz?a=%26 HTTP/1.0", "\r\n",].join("\r\n")
         );
     }
+
+    #[test]
+    fn test_headers() {
+        let mut headers = Headers::parse(&["Cookie: uid=1; pass=abc", "X-Api-Key:  k"]).unwrap();
+        assert_eq!(
+            headers.iter().collect::<Vec<_>>(),
+            [("Cookie", "uid=1; pass=abc"), ("X-Api-Key", "k")]
+        );
+        assert_eq!(
+            format!("{headers:?}"),
+            r#"["Cookie: <redacted>", "X-Api-Key: <redacted>"]"#
+        );
+
+        headers.update(&["cookie: uid=2", "X-Api-Key:"]).unwrap();
+        assert_eq!(headers.lines(), ["cookie: uid=2"]);
+
+        assert!(
+            headers
+                .update(&["X-Other: 1", "Host: example.com"])
+                .is_err()
+        );
+        assert!(headers.update(&["Content-Length: 0"]).is_err());
+        assert!(headers.update(&["X-Bad: a\r\nHost: b"]).is_err());
+        assert!(headers.update(&["no separator"]).is_err());
+        assert_eq!(headers.lines(), ["cookie: uid=2"]);
+    }
 }
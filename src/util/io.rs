@@ -19,29 +19,150 @@ pub enum IOR {
     Err(io::Error),
 }
 
-/// Do an async read, returning the appropriate IOR.
+/// Do an async read, returning the appropriate IOR. Interrupted reads are retried.
 pub fn aread<R: io::Read>(b: &mut [u8], r: &mut R) -> IOR {
     if b.is_empty() {
         return IOR::Complete;
     }
-    match r.read(b) {
-        Ok(0) => IOR::EOF,
-        Ok(a) if a == b.len() => IOR::Complete,
-        Ok(a) => IOR::Incomplete(a),
-        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => IOR::Blocked,
-        Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => IOR::Blocked,
-        Err(e) => IOR::Err(e),
+    loop {
+        return match r.read(b) {
+            Ok(0) => IOR::EOF,
+            Ok(a) if a == b.len() => IOR::Complete,
+            Ok(a) => IOR::Incomplete(a),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => IOR::Blocked,
+            Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => IOR::Blocked,
+            Err(e) => IOR::Err(e),
+        };
     }
 }
 
-/// Do an async write, returning the appropriate IOR.
+/// Do an async write, returning the appropriate IOR. Interrupted writes are retried, and
+/// a write which accepts nothing is an error rather than an EOF.
 pub fn awrite<W: io::Write>(b: &[u8], w: &mut W) -> IOR {
-    match w.write(b) {
-        Ok(0) => IOR::EOF,
-        Ok(a) if a == b.len() => IOR::Complete,
-        Ok(a) => IOR::Incomplete(a),
-        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => IOR::Blocked,
-        Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => IOR::Blocked,
-        Err(e) => IOR::Err(e),
+    if b.is_empty() {
+        return IOR::Complete;
+    }
+    loop {
+        return match w.write(b) {
+            Ok(0) => IOR::Err(io::ErrorKind::WriteZero.into()),
+            Ok(a) if a == b.len() => IOR::Complete,
+            Ok(a) => IOR::Incomplete(a),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => IOR::Blocked,
+            Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => IOR::Blocked,
+            Err(e) => IOR::Err(e),
+        };
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::io::{self, Read, Write};
+
+    use rand::rngs::StdRng;
+    use rand::{RngExt, SeedableRng};
+
+    /// Wraps a stream, randomly failing calls with EINTR or WouldBlock and cutting reads and
+    /// writes short, as a loaded kernel might.
+    pub struct Flaky<S> {
+        pub inner: S,
+        rng: StdRng,
+    }
+
+    impl<S> Flaky<S> {
+        pub fn new(inner: S, seed: u64) -> Flaky<S> {
+            Flaky {
+                inner,
+                rng: StdRng::seed_from_u64(seed),
+            }
+        }
+
+        /// Either an injected error or how much of a buffer of `len` to pass through.
+        fn chaos(&mut self, len: usize) -> io::Result<usize> {
+            match self.rng.random_range(0..10) {
+                0 | 1 => Err(io::ErrorKind::Interrupted.into()),
+                2 => Err(io::ErrorKind::WouldBlock.into()),
+                3..=5 if len > 1 => Ok(self.rng.random_range(1..len)),
+                _ => Ok(len),
+            }
+        }
+    }
+
+    impl<S: Read> Read for Flaky<S> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = self.chaos(buf.len())?;
+            self.inner.read(&mut buf[..len])
+        }
+    }
+
+    impl<S: Write> Write for Flaky<S> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let len = self.chaos(buf.len())?;
+            self.inner.write(&buf[..len])
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.inner.flush()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Write};
+    use std::os::unix::net::UnixStream;
+
+    use super::test::Flaky;
+    use super::*;
+
+    #[test]
+    fn test_flaky_stream() {
+        let (a, b) = UnixStream::pair().unwrap();
+        a.set_nonblocking(true).unwrap();
+        b.set_nonblocking(true).unwrap();
+        let (mut tx, mut rx) = (Flaky::new(a, 1), Flaky::new(b, 2));
+        let data: Vec<u8> = (0..1_000_000u32).map(|i| (i % 251) as u8).collect();
+
+        let (mut sent, mut recvd) = (0, vec![0u8; data.len()]);
+        let mut pos = 0;
+        while pos < data.len() {
+            if sent < data.len() {
+                let end = (sent + 65_536).min(data.len());
+                match awrite(&data[sent..end], &mut tx) {
+                    IOR::Complete => sent = end,
+                    IOR::Incomplete(n) => sent += n,
+                    IOR::Blocked => {}
+                    IOR::EOF => panic!("write EOF"),
+                    IOR::Err(e) => panic!("write failed: {e}"),
+                }
+            }
+            match aread(&mut recvd[pos..], &mut rx) {
+                IOR::Complete => pos = data.len(),
+                IOR::Incomplete(n) => pos += n,
+                IOR::Blocked => {}
+                IOR::EOF => panic!("read EOF"),
+                IOR::Err(e) => panic!("read failed: {e}"),
+            }
+        }
+        assert!(recvd == data);
+    }
+
+    #[test]
+    fn test_write_zero() {
+        struct Full;
+        impl Write for Full {
+            fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+                Ok(0)
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        assert!(matches!(
+            awrite(b"data", &mut Full),
+            IOR::Err(ref e) if e.kind() == io::ErrorKind::WriteZero
+        ));
+        assert!(matches!(awrite(b"", &mut Full), IOR::Complete));
     }
 }
@@ -0,0 +1,198 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use ip_network::IpNetwork;
+use ip_network_table::IpNetworkTable;
+
+/// Weight of prefixes blocked over RPC or by a blocklist, which is at or below any threshold
+pub const BLOCK: u8 = 0;
+/// eMule DAT entries with an access level below this are blocked
+const DAT_BLOCK_LEVEL: u32 = 127;
+
+/// Parses a CIDR prefix, a single address or a `start - end` range into the prefixes covering it.
+pub fn parse_range(s: &str) -> Result<Vec<IpNetwork>, String> {
+    let s = s.trim();
+    if let Some((start, end)) = s.split_once('-') {
+        range_to_networks(parse_addr(start)?, parse_addr(end)?)
+            .ok_or_else(|| format!("invalid address range {s}"))
+    } else if s.contains('/') {
+        IpNetwork::from_str_truncate(s)
+            .map(|n| vec![n])
+            .map_err(|_| format!("invalid prefix {s}"))
+    } else {
+        parse_addr(s).map(|ip| vec![ip.into()])
+    }
+}
+
+/// Whether connections to and from `ip` are refused. Only the longest prefix containing the
+/// address counts, so a narrower prefix can allow part of a blocked range or the other way
+/// around, and addresses it's blocked for are those with a weight at or below `threshold`.
+/// Addresses no prefix contains are allowed.
+pub fn blocks(filter: &IpNetworkTable<u8>, threshold: u8, ip: IpAddr) -> bool {
+    filter
+        .longest_match(ip)
+        .is_some_and(|(_, weight)| *weight <= threshold)
+}
+
+/// Parses a blocklist with one range per line, as accepted by `parse_range`, in the eMule
+/// DAT format of `start - end , level , description` or in the P2P format of
+/// `description:start-end`. Blank lines and lines starting with `#` are ignored.
+pub fn parse_file(data: &str) -> Result<Vec<IpNetwork>, String> {
+    let mut networks = Vec::new();
+    for (i, line) in data.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        // The description of a P2P line can contain colons and commas, an IPv4 range can't
+        if let Some((_, range)) = line.rsplit_once(':')
+            && range.contains('-')
+            && let Ok(nets) = parse_range(range)
+        {
+            networks.extend(nets);
+            continue;
+        }
+        let mut fields = line.split(',');
+        let range = fields.next().unwrap_or_default();
+        if let Some(level) = fields.next() {
+            let level: u32 = level
+                .trim()
+                .parse()
+                .map_err(|_| format!("line {}: invalid access level", i + 1))?;
+            if level >= DAT_BLOCK_LEVEL {
+                continue;
+            }
+        }
+        networks.extend(parse_range(range).map_err(|e| format!("line {}: {e}", i + 1))?);
+    }
+    Ok(networks)
+}
+
+fn parse_addr(s: &str) -> Result<IpAddr, String> {
+    let s = s.trim();
+    if let Ok(ip) = s.parse() {
+        return Ok(ip);
+    }
+    // DAT files zero pad IPv4 octets, e.g. 001.002.003.004
+    let octets: Vec<u8> = s.split('.').filter_map(|o| o.parse().ok()).collect();
+    match octets[..] {
+        [a, b, c, d] if s.split('.').count() == 4 => Ok(Ipv4Addr::new(a, b, c, d).into()),
+        _ => Err(format!("invalid address {s}")),
+    }
+}
+
+/// Splits an inclusive address range into the smallest set of prefixes covering it.
+fn range_to_networks(start: IpAddr, end: IpAddr) -> Option<Vec<IpNetwork>> {
+    let (mut lo, hi, bits) = match (start, end) {
+        (IpAddr::V4(s), IpAddr::V4(e)) => (u128::from(u32::from(s)), u128::from(u32::from(e)), 32),
+        (IpAddr::V6(s), IpAddr::V6(e)) => (u128::from(s), u128::from(e), 128),
+        _ => return None,
+    };
+    if lo > hi {
+        return None;
+    }
+    let mask = |size: u32| {
+        if size == 128 {
+            u128::MAX
+        } else {
+            (1 << size) - 1
+        }
+    };
+    let mut networks = Vec::new();
+    loop {
+        // The largest block aligned at lo which doesn't extend past hi
+        let mut size = lo.trailing_zeros().min(bits);
+        while mask(size) > hi - lo {
+            size -= 1;
+        }
+        let addr = if bits == 32 {
+            IpAddr::V4(Ipv4Addr::from(lo as u32))
+        } else {
+            IpAddr::V6(Ipv6Addr::from(lo))
+        };
+        networks.push(IpNetwork::new(addr, (bits - size) as u8).ok()?);
+        if lo + mask(size) == hi {
+            return Some(networks);
+        }
+        lo += mask(size) + 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nets(v: &[&str]) -> Vec<IpNetwork> {
+        v.iter().map(|n| n.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("1.2.3.0/24").unwrap(), nets(&["1.2.3.0/24"]));
+        assert_eq!(parse_range("1.2.3.4/24").unwrap(), nets(&["1.2.3.0/24"]));
+        assert_eq!(parse_range("1.2.3.4").unwrap(), nets(&["1.2.3.4/32"]));
+        assert_eq!(parse_range("::1").unwrap(), nets(&["::1/128"]));
+        assert_eq!(
+            parse_range("1.2.3.0 - 1.2.4.255").unwrap(),
+            nets(&["1.2.3.0/24", "1.2.4.0/24"])
+        );
+        assert_eq!(
+            parse_range("10.0.0.1-10.0.0.6").unwrap(),
+            nets(&["10.0.0.1/32", "10.0.0.2/31", "10.0.0.4/31", "10.0.0.6/32"])
+        );
+        assert_eq!(
+            parse_range("0.0.0.0-255.255.255.255").unwrap(),
+            nets(&["0.0.0.0/0"])
+        );
+        assert_eq!(
+            parse_range("::-ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff").unwrap(),
+            nets(&["::/0"])
+        );
+        assert!(parse_range("1.2.3.4 - ::1").is_err());
+        assert!(parse_range("1.2.3.4 - 1.2.3.3").is_err());
+        assert!(parse_range("example.com").is_err());
+    }
+
+    #[test]
+    fn test_parse_file() {
+        let data = "# comment\n\
+                    \n\
+                    1.2.3.0/24\n\
+                    001.002.004.000 - 001.002.004.255 , 000 , Some org\n\
+                    001.002.005.000 - 001.002.005.255 , 200 , Allowed\n\
+                    Some, Inc: the org:1.2.6.0-1.2.6.255\n\
+                    2001:db8::-2001:db8::ff\n";
+        assert_eq!(
+            parse_file(data).unwrap(),
+            nets(&["1.2.3.0/24", "1.2.4.0/24", "1.2.6.0/24", "2001:db8::/120"])
+        );
+        assert_eq!(
+            parse_file("1.2.3.0/24\nbogus\n").unwrap_err(),
+            "line 2: invalid address bogus"
+        );
+    }
+
+    #[test]
+    fn test_blocks() {
+        let mut filter = IpNetworkTable::new();
+        let weights = [
+            ("0.0.0.0/0", 127),
+            ("10.0.0.0/8", 0),
+            ("10.1.0.0/16", 200),
+            ("10.1.2.0/24", 50),
+        ];
+        for (net, weight) in weights {
+            filter.insert(net.parse::<IpNetwork>().unwrap(), weight);
+        }
+        let ip = |s: &str| s.parse().unwrap();
+
+        assert!(blocks(&filter, 0, ip("10.2.0.1")));
+        assert!(!blocks(&filter, 0, ip("10.1.3.4")));
+        assert!(!blocks(&filter, 0, ip("10.1.2.3")));
+        assert!(!blocks(&filter, 0, ip("8.8.8.8")));
+        assert!(!blocks(&filter, 0, ip("::1")));
+
+        assert!(blocks(&filter, 50, ip("10.1.2.3")));
+        assert!(!blocks(&filter, 50, ip("10.1.3.4")));
+        assert!(blocks(&filter, 127, ip("8.8.8.8")));
+    }
+}
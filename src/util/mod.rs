@@ -1,11 +1,13 @@
 pub mod http;
 mod io;
+pub mod ip_filter;
 pub mod native;
+pub mod timer;
 
 use std::collections::{HashMap, HashSet};
 use std::fmt::Write as FWrite;
 use std::hash::BuildHasherDefault;
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 use std::path::Path;
 
 use byteorder::{BigEndian, ByteOrder};
@@ -13,6 +15,7 @@ use metrohash::MetroHash;
 use rand::distr::{Alphanumeric, SampleString};
 use rand::{self, RngExt};
 use sha1::{Digest, Sha1};
+use sha2::Sha256;
 use url::Url;
 
 pub type FHashMap<K, V> = fnv::FnvHashMap<K, V>;
@@ -25,6 +28,8 @@ pub type MHashMap<K, V> = HashMap<K, V, MBuildHasher>;
 pub type MHashSet<T> = HashSet<T, MBuildHasher>;
 pub type SHashMap<T> = MHashMap<String, T>;
 
+#[cfg(test)]
+pub use self::io::test::Flaky;
 pub use self::io::{IOR, aread, awrite, io_err, io_err_val};
 
 #[derive(Debug, Clone, Copy, Eq, Ord, PartialEq, PartialOrd)]
@@ -108,6 +113,30 @@ pub fn sha1_hash(data: &[u8]) -> [u8; 20] {
     ctx.finalize().into()
 }
 
+/// HMAC-SHA1 of `data` per RFC 2104.
+pub fn hmac_sha1(key: &[u8], data: &[u8]) -> [u8; 20] {
+    const BLOCK: usize = 64;
+    let mut k = [0u8; BLOCK];
+    if key.len() > BLOCK {
+        k[..20].copy_from_slice(&sha1_hash(key));
+    } else {
+        k[..key.len()].copy_from_slice(key);
+    }
+    let mut inner = Sha1::new();
+    inner.update(k.map(|b| b ^ 0x36));
+    inner.update(data);
+    let mut outer = Sha1::new();
+    outer.update(k.map(|b| b ^ 0x5c));
+    outer.update(inner.finalize());
+    outer.finalize().into()
+}
+
+pub fn sha256_hash(data: &[u8]) -> [u8; 32] {
+    let mut ctx = Sha256::new();
+    ctx.update(data);
+    ctx.finalize().into()
+}
+
 pub fn peer_rpc_id(torrent: &[u8; 20], peer: u64) -> String {
     const PEER_ID: &[u8] = b"PEER";
 
@@ -136,6 +165,12 @@ pub fn trk_rpc_id(torrent: &[u8; 20], url: &Url) -> String {
     hash_to_id(&ctx.finalize().into())
 }
 
+/// A random id formatted like the hash based ones, for resources which must keep their
+/// id as the data it would be derived from changes.
+pub fn random_rpc_id() -> String {
+    hash_to_id(&rand::random())
+}
+
 pub fn hash_to_id(hash: &[u8; 20]) -> String {
     let mut hash_str = String::with_capacity(hash.len() * 2);
     for i in hash {
@@ -183,24 +218,25 @@ fn hex_to_bit(c: char) -> Option<u8> {
     Some(r)
 }
 
+/// Reads a compact address, 6 bytes for IPv4 or 18 for IPv6.
 pub fn bytes_to_addr(p: &[u8]) -> SocketAddr {
-    let ip = Ipv4Addr::new(p[0], p[1], p[2], p[3]);
-    SocketAddr::V4(SocketAddrV4::new(ip, BigEndian::read_u16(&p[4..])))
-}
-
-pub fn addr_to_bytes(addr: &SocketAddr) -> [u8; 6] {
-    let mut data = [0u8; 6];
-    match *addr {
-        SocketAddr::V4(s) => {
-            let oct = s.ip().octets();
-            data[0] = oct[0];
-            data[1] = oct[1];
-            data[2] = oct[2];
-            data[3] = oct[3];
-            BigEndian::write_u16(&mut data[4..], s.port());
-        }
-        _ => panic!("IPv6 DHT not supported"),
+    if p.len() == 18 {
+        let mut ip = [0u8; 16];
+        ip.copy_from_slice(&p[..16]);
+        let port = BigEndian::read_u16(&p[16..]);
+        SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::from(ip), port, 0, 0))
+    } else {
+        let ip = Ipv4Addr::new(p[0], p[1], p[2], p[3]);
+        SocketAddr::V4(SocketAddrV4::new(ip, BigEndian::read_u16(&p[4..])))
     }
+}
+
+pub fn addr_to_bytes(addr: &SocketAddr) -> Vec<u8> {
+    let mut data = match addr.ip() {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+    data.extend_from_slice(&addr.port().to_be_bytes());
     data
 }
 
@@ -210,6 +246,67 @@ pub fn find_subseq(haystack: &[u8], needle: &[u8]) -> Option<usize> {
         .position(|window| window == needle)
 }
 
+/// BEP 40 canonical priority of the connection between two peers, the same from either
+/// end. Both IPs are masked down to a prefix they differ in, so peers sharing a network
+/// don't all rank each other the same way, and the CRC32-C of the sorted pair is taken.
+/// Between ports of the same IP, the ports are hashed instead.
+pub fn peer_priority(a: &SocketAddr, b: &SocketAddr) -> u32 {
+    if a.ip() == b.ip() {
+        let (lo, hi) = (a.port().min(b.port()), a.port().max(b.port()));
+        let mut buf = [0u8; 4];
+        BigEndian::write_u16(&mut buf, lo);
+        BigEndian::write_u16(&mut buf[2..], hi);
+        return crc32c(&buf);
+    }
+    let (mut x, mut y) = match (a.ip(), b.ip()) {
+        (IpAddr::V4(x), IpAddr::V4(y)) => (x.octets().to_vec(), y.octets().to_vec()),
+        (x, y) => (v6_octets(x).to_vec(), v6_octets(y).to_vec()),
+    };
+    // Keep /16 and /24 for IPv4 or /32 and /48 for IPv6 when the peers differ there,
+    // and the whole address for peers on the same small network
+    let (short, long) = if x.len() == 4 { (2, 3) } else { (4, 6) };
+    let keep = if x[..short] != y[..short] {
+        short
+    } else if x[..long] != y[..long] {
+        long
+    } else {
+        x.len()
+    };
+    for ip in [&mut x, &mut y] {
+        for b in &mut ip[keep..] {
+            *b &= 0x55;
+        }
+    }
+    if x > y {
+        std::mem::swap(&mut x, &mut y);
+    }
+    x.extend_from_slice(&y);
+    crc32c(&x)
+}
+
+fn v6_octets(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    }
+}
+
+/// CRC32-C (Castagnoli), as used by BEP 40 and BEP 42.
+pub fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in data {
+        crc ^= u32::from(b);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0x82F6_3B78
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -250,4 +347,97 @@ mod test {
         let s = hash_to_id(&hash);
         assert_eq!(id_to_hash(&s).unwrap(), hash);
     }
+
+    #[test]
+    fn test_hash_vectors() {
+        // FIPS 180-2 test vectors
+        let mut s = String::new();
+        for b in sha1_hash(b"abc") {
+            write!(&mut s, "{b:02x}").unwrap();
+        }
+        assert_eq!(s, "a9993e364706816aba3e25717850c26c9cd0d89d");
+        s.clear();
+        for b in sha256_hash(b"abc") {
+            write!(&mut s, "{b:02x}").unwrap();
+        }
+        assert_eq!(
+            s,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn test_hmac_sha1() {
+        // Test cases 1, 2 and 6 of RFC 2202
+        let hex = |h: [u8; 20]| h.iter().map(|b| format!("{b:02x}")).collect::<String>();
+        assert_eq!(
+            hex(hmac_sha1(&[0x0b; 20], b"Hi There")),
+            "b617318655057264e28bc0b6fb378c8ef146be00"
+        );
+        assert_eq!(
+            hex(hmac_sha1(b"Jefe", b"what do ya want for nothing?")),
+            "effcdf6ae5eb2fa2d27416d5f184df9c259a7c79"
+        );
+        assert_eq!(
+            hex(hmac_sha1(
+                &[0xaa; 80],
+                b"Test Using Larger Than Block-Size Key - Hash Key First"
+            )),
+            "aa4ae5e15272d00e95705637ce8a3b55ed402112"
+        );
+    }
+
+    #[test]
+    fn test_crc32c() {
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+        assert_eq!(crc32c(b""), 0);
+    }
+
+    #[test]
+    fn test_peer_priority() {
+        let p = |a: &str, b: &str| peer_priority(&a.parse().unwrap(), &b.parse().unwrap());
+        // Reference values from BEP 40
+        assert_eq!(p("123.213.32.10:0", "98.76.54.32:0"), 0xec2d_7224);
+        assert_eq!(p("123.213.32.10:0", "123.213.32.234:0"), 0x9956_8189);
+        // The masked buffers from libtorrent's tests
+        assert_eq!(
+            p("230.12.123.3:0", "230.12.123.1:0"),
+            crc32c(&[0xe6, 0x0c, 0x7b, 0x01, 0xe6, 0x0c, 0x7b, 0x03])
+        );
+        assert_eq!(
+            p("123.213.32.10:0", "123.213.33.10:0"),
+            crc32c(&[0x7b, 0xd5, 0x20, 0x00, 0x7b, 0xd5, 0x21, 0x00])
+        );
+        assert_eq!(
+            p("230.12.123.3:1", "230.12.123.3:2"),
+            crc32c(&[0x00, 0x01, 0x00, 0x02])
+        );
+        // Either end gets the same value
+        assert_eq!(
+            p("98.76.54.32:6881", "123.213.32.10:51413"),
+            p("123.213.32.10:1", "98.76.54.32:2")
+        );
+        assert_eq!(p("10.0.0.1:2", "10.0.0.1:1"), p("10.0.0.1:1", "10.0.0.1:2"));
+
+        let mut a = [0u8; 16];
+        a[..4].copy_from_slice(&[0x20, 0x01, 0x0d, 0xb8]);
+        a[15] = 0xff;
+        let mut b = [0x55u8; 16];
+        b[..4].copy_from_slice(&[0x2a, 0x00, 0x14, 0x50]);
+        let mut buf = a.map(|x| x & 0x55).to_vec();
+        buf[..4].copy_from_slice(&a[..4]);
+        buf.extend_from_slice(&b.map(|x| x & 0x55));
+        buf[16..20].copy_from_slice(&b[..4]);
+        assert_eq!(
+            p(
+                "[2001:db8::ff]:0",
+                "[2a00:1450:5555:5555:5555:5555:5555:5555]:0"
+            ),
+            crc32c(&buf)
+        );
+        assert_eq!(
+            p("[2001:db8::1]:0", "[2001:db8::2]:0"),
+            p("[2001:db8::2]:0", "[2001:db8::1]:0")
+        );
+    }
 }
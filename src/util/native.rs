@@ -1,12 +1,18 @@
 use std::fs::File;
 use std::io;
+use std::net::Ipv4Addr;
 use std::os::unix::fs::MetadataExt;
+use std::path::Path;
 
 use rustix::io::Errno;
 
 /// Returns `true` if `f` is sparse and `false` otherwise.
 pub fn is_sparse(f: &File) -> io::Result<bool> {
     let stat = f.metadata()?;
+    // Seeking for a hole at or past the end of the file fails with ENXIO
+    if stat.size() == 0 {
+        return Ok(false);
+    }
     let f = rustix::io::dup(f)?;
     let pos = rustix::fs::seek(f, rustix::fs::SeekFrom::Hole(0))?;
     Ok(pos < stat.size())
@@ -28,6 +34,78 @@ pub fn fallocate(f: &File, len: u64) -> io::Result<bool> {
     }
 }
 
+/// The filesystem a path resides on.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FsInfo {
+    pub name: String,
+    /// Largest file which can be stored, if known to be limited
+    pub max_file_size: Option<u64>,
+}
+
+/// Identifies the filesystem of `path`, or of its closest existing
+/// ancestor if it has yet to be created.
+#[cfg(target_os = "linux")]
+pub fn fs_info(mut path: &Path) -> io::Result<FsInfo> {
+    while !path.exists()
+        && let Some(parent) = path.parent()
+    {
+        path = parent;
+    }
+    let st = rustix::fs::statfs(path)?;
+    let (name, max_file_size) = match st.f_type as u64 {
+        0x4d44 => ("vfat", Some(u64::from(u32::MAX))),
+        // ext2, ext3 and ext4 share a magic number. Without extents, files are limited
+        // further, so this only rejects files no ext filesystem could store.
+        0xef53 => ("ext2/3/4", Some((st.f_bsize as u64) << 32)),
+        0x2011_bab0 => ("exfat", None),
+        0x5846_5342 => ("xfs", None),
+        0x9123_683e => ("btrfs", None),
+        0x6969 => ("nfs", None),
+        t => {
+            return Ok(FsInfo {
+                name: format!("{t:#x}"),
+                max_file_size: None,
+            });
+        }
+    };
+    Ok(FsInfo {
+        name: name.to_owned(),
+        max_file_size,
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn fs_info(_: &Path) -> io::Result<FsInfo> {
+    Ok(FsInfo {
+        name: "unknown".to_owned(),
+        max_file_size: None,
+    })
+}
+
+/// The IPv4 gateway of the default route, if there is one.
+#[cfg(target_os = "linux")]
+pub fn default_gateway() -> Option<Ipv4Addr> {
+    parse_default_route(&std::fs::read_to_string("/proc/net/route").ok()?)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn default_gateway() -> Option<Ipv4Addr> {
+    None
+}
+
+/// Addresses in the route table are printed as native endian integers.
+#[cfg(any(target_os = "linux", test))]
+fn parse_default_route(routes: &str) -> Option<Ipv4Addr> {
+    routes.lines().skip(1).find_map(|line| {
+        let mut fields = line.split_whitespace().skip(1);
+        if fields.next()? != "00000000" {
+            return None;
+        }
+        let gw = u32::from_str_radix(fields.next()?, 16).ok()?;
+        (gw != 0).then(|| Ipv4Addr::from(gw.to_ne_bytes()))
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -64,4 +142,29 @@ mod tests {
         test_file.write_all(b"12345678").unwrap();
         assert_matches!(is_sparse(&test_file), Ok(false));
     }
+
+    #[test]
+    fn fs_info_missing_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let expected = fs_info(dir.path()).unwrap();
+        assert_eq!(fs_info(&dir.path().join("a/b/c")).unwrap(), expected);
+    }
+
+    #[test]
+    fn parse_default_route_gateway() {
+        let gw = u32::from_ne_bytes([192, 168, 1, 1]);
+        let routes = format!(
+            "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\n\
+             eth0\t0001A8C0\t00000000\t0001\t0\t0\t100\t00FFFFFF\n\
+             eth0\t00000000\t{gw:08X}\t0003\t0\t0\t100\t00000000\n"
+        );
+        assert_eq!(
+            parse_default_route(&routes),
+            Some(Ipv4Addr::new(192, 168, 1, 1))
+        );
+        assert_eq!(
+            parse_default_route(routes.split_once("eth0\t0000").unwrap().0),
+            None
+        );
+    }
 }
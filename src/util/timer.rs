@@ -0,0 +1,231 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::hash::Hash;
+use std::time::Instant;
+
+use crate::util::FHashMap;
+
+/// Slack allowed for cancelled and superseded heap entries before the heap is rebuilt.
+const COMPACT_SLACK: usize = 64;
+
+/// Keyed deadlines, so timeouts can be collected as they come due rather than by
+/// scanning every connection each tick.
+///
+/// A key has at most one pending deadline. Pushing a deadline back, which idle timeouts
+/// do on every bit of activity, only touches the map: the old heap entry is requeued at
+/// the new deadline once it surfaces. Cancelled entries are dropped lazily.
+#[derive(Clone, Debug)]
+pub struct Timers<K: Copy + Eq + Hash + Ord> {
+    heap: BinaryHeap<Reverse<(Instant, u64, K)>>,
+    /// The deadline of each key and the sequence number of its live heap entry
+    pending: FHashMap<K, (Instant, u64)>,
+    seq: u64,
+}
+
+impl<K: Copy + Eq + Hash + Ord> Timers<K> {
+    pub fn new() -> Timers<K> {
+        Timers {
+            heap: BinaryHeap::new(),
+            pending: FHashMap::default(),
+            seq: 0,
+        }
+    }
+
+    /// Sets the deadline of a key, replacing any previous one.
+    pub fn schedule(&mut self, key: K, at: Instant) {
+        match self.pending.get_mut(&key) {
+            // The queued entry still fires first and will be requeued
+            Some((deadline, _)) if *deadline <= at => *deadline = at,
+            _ => {
+                self.seq += 1;
+                self.pending.insert(key, (at, self.seq));
+                self.heap.push(Reverse((at, self.seq, key)));
+                self.compact();
+            }
+        }
+    }
+
+    /// Removes the deadline of a key, returning whether it had one.
+    pub fn cancel(&mut self, key: K) -> bool {
+        let cancelled = self.pending.remove(&key).is_some();
+        if cancelled {
+            self.compact();
+        }
+        cancelled
+    }
+
+    /// Returns a key whose deadline is at or before `now`, removing it.
+    pub fn pop_expired(&mut self, now: Instant) -> Option<K> {
+        while let Some(&Reverse((at, seq, key))) = self.heap.peek() {
+            if at > now {
+                return None;
+            }
+            self.heap.pop();
+            match self.pending.get(&key) {
+                Some(&(deadline, s)) if s == seq => {
+                    if deadline > now {
+                        self.heap.push(Reverse((deadline, seq, key)));
+                    } else {
+                        self.pending.remove(&key);
+                        return Some(key);
+                    }
+                }
+                // Cancelled or rescheduled earlier
+                _ => {}
+            }
+        }
+        None
+    }
+
+    fn compact(&mut self) {
+        if self.heap.len() > 2 * self.pending.len() + COMPACT_SLACK {
+            self.heap = self
+                .pending
+                .iter()
+                .map(|(key, &(at, seq))| Reverse((at, seq, *key)))
+                .collect();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use rand::{RngExt, rng};
+
+    use super::*;
+
+    const TICK: Duration = Duration::from_millis(500);
+
+    fn expired(t: &mut Timers<usize>, now: Instant) -> Vec<usize> {
+        let mut keys: Vec<_> = std::iter::from_fn(|| t.pop_expired(now)).collect();
+        keys.sort_unstable();
+        keys
+    }
+
+    #[test]
+    fn test_fire_within_tick() {
+        let mut r = rng();
+        let start = Instant::now();
+        let mut t = Timers::new();
+        let mut deadlines = FHashMap::default();
+        for key in 0..5_000 {
+            let at = start + Duration::from_millis(r.random_range(0..30_000));
+            t.schedule(key, at);
+            deadlines.insert(key, at);
+        }
+        let mut now = start;
+        while !deadlines.is_empty() {
+            for key in expired(&mut t, now) {
+                let at = deadlines.remove(&key).unwrap();
+                assert!(at <= now && now - at < TICK);
+            }
+            now += TICK;
+        }
+        assert!(t.pop_expired(now + Duration::from_secs(60)).is_none());
+    }
+
+    #[test]
+    fn test_cancel() {
+        let start = Instant::now();
+        let mut t = Timers::new();
+        for key in 0..1_000 {
+            t.schedule(key, start + Duration::from_millis(key as u64));
+        }
+        for key in (0..1_000).filter(|k| k % 3 == 0) {
+            assert!(t.cancel(key));
+        }
+        assert!(!t.cancel(0));
+        let fired = expired(&mut t, start + Duration::from_secs(1));
+        assert_eq!(fired, (0..1_000).filter(|k| k % 3 != 0).collect::<Vec<_>>());
+        assert!(t.pending.is_empty());
+        assert!(t.heap.is_empty());
+
+        // Cancelled entries don't pile up in the heap
+        for key in 0..1_000 {
+            t.schedule(key, start);
+            t.cancel(key);
+        }
+        assert!(t.heap.len() <= COMPACT_SLACK + 1);
+        assert_eq!(t.pop_expired(start), None);
+    }
+
+    #[test]
+    fn test_reschedule() {
+        let start = Instant::now();
+        let secs = |s| start + Duration::from_secs(s);
+        let mut t = Timers::new();
+        t.schedule(0, secs(5));
+        t.schedule(1, secs(5));
+        t.schedule(2, secs(5));
+        // Later, earlier and a cancel then reschedule
+        t.schedule(0, secs(10));
+        t.schedule(1, secs(2));
+        t.cancel(2);
+        t.schedule(2, secs(7));
+
+        assert_eq!(expired(&mut t, secs(1)), Vec::<usize>::new());
+        assert_eq!(expired(&mut t, secs(2)), vec![1]);
+        assert_eq!(expired(&mut t, secs(6)), Vec::<usize>::new());
+        assert_eq!(expired(&mut t, secs(7)), vec![2]);
+        assert_eq!(expired(&mut t, secs(9)), Vec::<usize>::new());
+        assert_eq!(expired(&mut t, secs(10)), vec![0]);
+        assert_eq!(expired(&mut t, secs(100)), Vec::<usize>::new());
+
+        // Repeatedly pushing a timeout back doesn't grow the heap
+        for i in 0..10_000 {
+            t.schedule(3, secs(20 + i));
+        }
+        assert_eq!(t.heap.len(), 1);
+        assert_eq!(expired(&mut t, secs(10_018)), Vec::<usize>::new());
+        assert_eq!(expired(&mut t, secs(10_019)), vec![3]);
+    }
+
+    /// Compares the per tick cost of collecting timeouts against scanning every entry, with
+    /// 5k timers which are each pushed back once per tick. Run with
+    /// `cargo test bench_tick -- --ignored --nocapture`.
+    #[ignore]
+    #[test]
+    fn bench_tick() {
+        const TIMERS: usize = 5_000;
+        const TICKS: u32 = 1_000;
+        let timeout = Duration::from_secs(15);
+        let start = Instant::now();
+
+        let mut last_updated = vec![start; TIMERS];
+        let mut scanned = 0;
+        let scan = Instant::now();
+        for i in 0..TICKS {
+            let now = start + TICK * i;
+            for (key, updated) in last_updated.iter_mut().enumerate() {
+                if key as u32 % TICKS == i {
+                    *updated = now;
+                }
+                if now.duration_since(*updated) > timeout {
+                    scanned += 1;
+                }
+            }
+        }
+        let scan = scan.elapsed();
+
+        let mut t = Timers::new();
+        for key in 0..TIMERS {
+            t.schedule(key, start + timeout);
+        }
+        let mut popped = 0;
+        let timers = Instant::now();
+        for i in 0..TICKS {
+            let now = start + TICK * i;
+            for key in (i as usize..TIMERS).step_by(TICKS as usize) {
+                t.schedule(key, now + timeout);
+            }
+            popped += std::iter::from_fn(|| t.pop_expired(now)).count();
+        }
+        let timers = timers.elapsed();
+
+        std::hint::black_box((scanned, popped));
+        println!("Scan: {:?}/tick", scan / TICKS);
+        println!("Timers: {:?}/tick", timers / TICKS);
+    }
+}
@@ -22,17 +22,18 @@ impl<I: Debug + Send + 'static, O: Debug + Send + 'static> Worker<I, O> {
         Ok((worker_handle, worker))
     }
 
-    pub fn run<F: AsyncFnOnce(Self) + Send + 'static>(
+    pub fn run<R: Send + 'static, F: AsyncFnOnce(Self) -> R + Send + 'static>(
         self,
         thread_name: &'static str,
         f: F,
-    ) -> io::Result<thread::JoinHandle<()>> {
+    ) -> io::Result<thread::JoinHandle<R>> {
         let builder = thread::Builder::new().name(thread_name.to_owned());
         builder.spawn(move || {
             debug!("{} worker started", thread_name);
             let runtime = compio::runtime::Runtime::new().unwrap();
-            runtime.block_on(f(self));
+            let res = runtime.block_on(f(self));
             debug!("{} worker completed", thread_name);
+            res
         })
     }
 }
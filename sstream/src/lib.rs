@@ -165,18 +165,14 @@ impl io::Read for SStream {
 
 impl io::Write for SStream {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // rustls pushes out as many records as the socket accepts after buffering the data,
+        // reporting the bytes as written even if the socket blocked. Failing the write at
+        // that point would make the caller send them again. The rest go out ahead of the
+        // next write, or on flush.
         match &mut self.conn {
             SConn::Plain(stream) => stream.write(buf),
-            SConn::SSLC(stream) => {
-                let result = stream.write(buf);
-                stream.conn.complete_io(&mut stream.sock)?;
-                result
-            }
-            SConn::SSLS(stream) => {
-                let result = stream.write(buf);
-                stream.conn.complete_io(&mut stream.sock)?;
-                result
-            }
+            SConn::SSLC(stream) => stream.write(buf),
+            SConn::SSLS(stream) => stream.write(buf),
         }
     }
 
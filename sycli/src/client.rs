@@ -1,4 +1,9 @@
+use std::io::{self, Write};
+use std::time::Duration;
+
 use anyhow::{bail, Result};
+use serde::Serialize;
+use serde_json::Value;
 use sstream::SStream;
 use url::Url;
 use ws::protocol::Message as WSMessage;
@@ -6,15 +11,79 @@ use ws::protocol::Message as WSMessage;
 use crate::rpc::message::{CMessage, SMessage, Version};
 
 const OS_IN_PROGRESS_ERROR: i32 = 36;
+/// Fields whose values are never written to the verbose log
+const SECRET_FIELDS: &[&str] = &["password", "token", "download_token"];
+const REDACTED: &str = "<redacted>";
+
+/// Carries serialized RPC messages to and from the server.
+pub trait Transport {
+    fn send_text(&mut self, data: String) -> Result<()>;
+    fn recv_text(&mut self) -> Result<String>;
+
+    /// Like `recv_text`, but gives up with `None` once `timeout` passes.
+    fn recv_text_timeout(&mut self, _timeout: Duration) -> Result<Option<String>> {
+        self.recv_text().map(Some)
+    }
+}
+
+impl Transport for ws::WebSocket<SStream> {
+    fn send_text(&mut self, data: String) -> Result<()> {
+        self.send(WSMessage::Text(data.into()))?;
+        Ok(())
+    }
+
+    fn recv_text(&mut self) -> Result<String> {
+        loop {
+            match self.read() {
+                Ok(WSMessage::Text(s)) => return Ok(s.to_string()),
+                Ok(WSMessage::Ping(p)) => {
+                    self.send(WSMessage::Pong(p))?;
+                }
+                Err(e) => Err(e)?,
+                _ => {}
+            };
+        }
+    }
+
+    fn recv_text_timeout(&mut self, timeout: Duration) -> Result<Option<String>> {
+        self.get_ref()
+            .get_stream()
+            .set_read_timeout(Some(timeout))?;
+        let res = self.recv_text();
+        self.get_ref().get_stream().set_read_timeout(None)?;
+        match res {
+            Err(e) if timed_out(&e) => Ok(None),
+            res => res.map(Some),
+        }
+    }
+}
+
+fn timed_out(e: &anyhow::Error) -> bool {
+    match e.downcast_ref::<ws::Error>() {
+        Some(ws::Error::Io(e)) => {
+            matches!(
+                e.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            )
+        }
+        _ => false,
+    }
+}
 
 pub struct Client {
-    ws: ws::WebSocket<SStream>,
+    transport: Box<dyn Transport>,
     version: Version,
     serial: u64,
+    /// 0: silent, 1: requests and their responses, 2: every message
+    verbosity: u8,
+    log: Box<dyn Write>,
 }
 
 impl Client {
-    pub fn new(url: Url) -> Result<Client> {
+    pub fn new(url: Url, verbosity: u8) -> Result<Client> {
+        if verbosity > 0 {
+            eprintln!("Connecting to {}", redact_url(&url));
+        }
         if !url.has_host() {
             bail!("Invalid websocket URL {}!", url);
         }
@@ -48,20 +117,32 @@ impl Client {
             if let Ok((client, _response)) =
                 ws::client::client_with_config(url.as_str(), stream, Some(config))
             {
-                let mut c = Client {
-                    ws: client,
-                    serial: 0,
-                    version: Version { major: 0, minor: 0 },
-                };
-                if let SMessage::RpcVersion(v) = c.recv()? {
-                    c.version = v;
-                    return Ok(c);
-                } else {
-                    bail!("Expected a version message on start!");
-                }
+                return Client::with_transport(Box::new(client), verbosity, Box::new(io::stderr()));
             }
         }
-        bail!("Could not connect to provided URL {}!", url);
+        bail!("Could not connect to provided URL {}!", redact_url(&url));
+    }
+
+    /// Creates a client over an established transport, waiting for the
+    /// server's version message. Verbose output is written to `log`.
+    pub fn with_transport(
+        transport: Box<dyn Transport>,
+        verbosity: u8,
+        log: Box<dyn Write>,
+    ) -> Result<Client> {
+        let mut c = Client {
+            transport,
+            serial: 0,
+            version: Version { major: 0, minor: 0 },
+            verbosity,
+            log,
+        };
+        if let SMessage::RpcVersion(v) = c.recv()? {
+            c.version = v;
+            Ok(c)
+        } else {
+            bail!("Expected a version message on start!");
+        }
     }
 
     pub fn version(&self) -> &Version {
@@ -74,28 +155,252 @@ impl Client {
     }
 
     pub fn send(&mut self, msg: CMessage) -> Result<()> {
+        if self.verbosity >= 1 {
+            self.trace("->", &msg)?;
+        }
         let msg_data = serde_json::to_string(&msg)?;
-        self.ws.send(WSMessage::Text(msg_data.into()))?;
-        Ok(())
+        self.transport.send_text(msg_data)
     }
 
     pub fn recv(&mut self) -> Result<SMessage<'static>> {
-        loop {
-            match self.ws.read() {
-                Ok(WSMessage::Text(s)) => {
-                    return Ok(serde_json::from_str(&s)?);
-                }
-                Ok(WSMessage::Ping(p)) => {
-                    self.ws.send(WSMessage::Pong(p))?;
-                }
-                Err(e) => Err(e)?,
-                _ => {}
-            };
+        let data = self.transport.recv_text()?;
+        self.parse(&data)
+    }
+
+    /// Waits up to `timeout` for a message, `None` meaning none arrived in time.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<Option<SMessage<'static>>> {
+        match self.transport.recv_text_timeout(timeout)? {
+            Some(data) => self.parse(&data).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn parse(&mut self, data: &str) -> Result<SMessage<'static>> {
+        let msg = serde_json::from_str(data)?;
+        if self.verbosity >= 2 {
+            self.trace("<-", &msg)?;
         }
+        Ok(msg)
     }
 
+    /// Sends a request and returns the reply, error messages being returned as a
+    /// `message::Error` which callers can downcast to for its code.
     pub fn rr(&mut self, msg: CMessage) -> Result<SMessage<'static>> {
         self.send(msg)?;
-        self.recv()
+        let resp = self.recv()?;
+        // At higher verbosity this was already logged by recv
+        if self.verbosity == 1 {
+            self.trace("<-", &resp)?;
+        }
+        if let Some(e) = resp.error() {
+            return Err(e.clone().into());
+        }
+        Ok(resp)
+    }
+
+    fn trace<M: Serialize>(&mut self, dir: &str, msg: &M) -> Result<()> {
+        let mut v = serde_json::to_value(msg)?;
+        redact(&mut v);
+        writeln!(self.log, "{} {}", dir, serde_json::to_string_pretty(&v)?)?;
+        Ok(())
+    }
+}
+
+/// Replaces the values of any secret fields in a JSON message.
+fn redact(v: &mut Value) {
+    match v {
+        Value::Object(o) => {
+            for (k, v) in o.iter_mut() {
+                if SECRET_FIELDS.contains(&k.as_str()) {
+                    *v = Value::String(REDACTED.to_owned());
+                } else {
+                    redact(v);
+                }
+            }
+        }
+        Value::Array(a) => a.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+fn redact_url(url: &Url) -> Url {
+    let mut url = url.clone();
+    let pairs: Vec<(String, String)> = url
+        .query_pairs()
+        .map(|(k, v)| {
+            if SECRET_FIELDS.contains(&k.as_ref()) {
+                (k.into_owned(), REDACTED.to_owned())
+            } else {
+                (k.into_owned(), v.into_owned())
+            }
+        })
+        .collect();
+    if !pairs.is_empty() {
+        url.query_pairs_mut().clear().extend_pairs(pairs);
+    }
+    url
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::message::{self, ErrorCode};
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::rc::Rc;
+
+    #[derive(Clone, Default)]
+    struct Buf(Rc<RefCell<Vec<u8>>>);
+
+    impl Buf {
+        fn contents(&self) -> String {
+            String::from_utf8(self.0.borrow().clone()).unwrap()
+        }
+    }
+
+    impl Write for Buf {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(data)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    struct MockTransport {
+        incoming: VecDeque<String>,
+        sent: Vec<String>,
+    }
+
+    impl Transport for MockTransport {
+        fn send_text(&mut self, data: String) -> Result<()> {
+            self.sent.push(data);
+            Ok(())
+        }
+
+        fn recv_text(&mut self) -> Result<String> {
+            match self.incoming.pop_front() {
+                Some(s) => Ok(s),
+                None => bail!("connection closed"),
+            }
+        }
+    }
+
+    fn client(verbosity: u8, log: Buf) -> Client {
+        let msgs = [
+            r#"{"type":"TRANSFER_OFFER","serial":0,"expires":"2024-01-01T00:00:00Z","token":"secret-token","size":10}"#,
+            r#"{"type":"RESOURCE_PENDING","serial":0,"id":"abcd"}"#,
+        ];
+        mock(&msgs, verbosity, log)
+    }
+
+    /// A client whose server replies with `msgs`, after the version message.
+    fn mock(msgs: &[&str], verbosity: u8, log: Buf) -> Client {
+        let version = r#"{"type":"RPC_VERSION","major":1,"minor":0}"#;
+        let transport = MockTransport {
+            incoming: Some(&version)
+                .into_iter()
+                .chain(msgs)
+                .map(|m| m.to_string())
+                .collect(),
+            sent: Vec::new(),
+        };
+        Client::with_transport(Box::new(transport), verbosity, Box::new(log)).unwrap()
+    }
+
+    fn upload(c: &mut Client) -> SMessage<'static> {
+        let msg = CMessage::UploadTorrent {
+            serial: c.next_serial(),
+            size: 10,
+            path: None,
+            start: true,
+            import: false,
+            file_priorities: Default::default(),
+            strategy: None,
+        };
+        c.rr(msg).unwrap()
+    }
+
+    #[test]
+    fn verbose_silent() {
+        let log = Buf::default();
+        let mut c = client(0, log.clone());
+        match upload(&mut c) {
+            SMessage::TransferOffer { token, .. } => assert_eq!(token, "secret-token"),
+            m => panic!("unexpected message {:?}", m),
+        }
+        assert_eq!(log.contents(), "");
+    }
+
+    #[test]
+    fn verbose_requests() {
+        let log = Buf::default();
+        let mut c = client(1, log.clone());
+        // Secrets are only hidden from the log, not the caller
+        match upload(&mut c) {
+            SMessage::TransferOffer { token, .. } => assert_eq!(token, "secret-token"),
+            m => panic!("unexpected message {:?}", m),
+        }
+        c.recv().unwrap();
+
+        let out = log.contents();
+        assert!(out.starts_with("-> {"));
+        assert!(out.contains("\"type\": \"UPLOAD_TORRENT\""));
+        assert!(out.contains("\"token\": \"<redacted>\""));
+        assert!(!out.contains("secret-token"));
+        assert!(!out.contains("RPC_VERSION"));
+        assert!(!out.contains("RESOURCE_PENDING"));
+    }
+
+    #[test]
+    fn verbose_all() {
+        let log = Buf::default();
+        let mut c = client(2, log.clone());
+        upload(&mut c);
+        c.recv().unwrap();
+
+        let out = log.contents();
+        assert!(out.contains("RPC_VERSION"));
+        assert!(out.contains("RESOURCE_PENDING"));
+        assert_eq!(out.matches("TRANSFER_OFFER").count(), 1);
+        assert!(!out.contains("secret-token"));
+    }
+
+    #[test]
+    fn error_codes() {
+        let msgs = [
+            r#"{"type":"UNKNOWN_RESOURCE","serial":0,"code":"unknown_resource","reason":"unknown resource id abcd","detail":"abcd"}"#,
+            r#"{"type":"INVALID_REQUEST","serial":1,"reason":"no code"}"#,
+        ];
+        let mut c = mock(&msgs, 0, Buf::default());
+        let mut get = || {
+            let msg = CMessage::GetResources {
+                serial: c.next_serial(),
+                ids: vec!["abcd".to_owned()],
+            };
+            c.rr(msg).unwrap_err()
+        };
+
+        let err = get();
+        assert_eq!(
+            err.to_string(),
+            "unknown resource id abcd [unknown_resource]"
+        );
+        let e = err.downcast_ref::<message::Error>().unwrap();
+        assert_eq!(e.code, ErrorCode::UnknownResource);
+        assert_eq!(e.detail.as_deref(), Some("abcd"));
+        // Servers predating codes still produce errors
+        let err = get();
+        let e = err.downcast_ref::<message::Error>().unwrap();
+        assert_eq!(e.code, ErrorCode::Unknown);
+    }
+
+    #[test]
+    fn redact_password() {
+        let url = Url::parse("ws://localhost:8412/?password=hunter2&x=1").unwrap();
+        let redacted = redact_url(&url).to_string();
+        assert!(!redacted.contains("hunter2"));
+        assert!(redacted.contains("x=1"));
     }
 }
@@ -1,6 +1,8 @@
 use std::borrow::Cow;
-use std::io::{self, Read};
-use std::path::Path;
+use std::collections::{BTreeMap, HashSet};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::{cmp, fs, mem};
 
 use anyhow::{anyhow, bail, Result};
@@ -12,102 +14,469 @@ use url::Url;
 
 use rpc::criterion::{Criterion, Operation, Value};
 use rpc::message::{self, CMessage, SMessage};
-use rpc::resource::{CResourceUpdate, PathUpdate, Resource, ResourceKind, SResourceUpdate, Server};
+use rpc::resource::{
+    self, CResourceUpdate, PathUpdate, Resource, ResourceKind, SResourceUpdate, Server,
+};
+use synapse_bencode as bencode;
 use synapse_rpc as rpc;
 
 use crate::client::Client;
 
+/// File priorities and strategy torrents are added with.
+#[derive(Default)]
+pub struct Preset {
+    pub file_priorities: BTreeMap<usize, u8>,
+    pub strategy: Option<resource::Strategy>,
+}
+
+/// A torrent or magnet link to be added.
+#[derive(Debug, PartialEq)]
+enum Addable {
+    Torrent(Vec<u8>),
+    Magnet(Url),
+}
+
+/// Adds torrents to the server, returning the infohash and resource of each.
+trait Adder {
+    fn add(&mut self, item: Addable) -> Result<(String, Resource)>;
+}
+
+struct ClientAdder<'a> {
+    c: Client,
+    dir: Option<&'a str>,
+    start: bool,
+    import: bool,
+    preset: &'a Preset,
+}
+
+impl Adder for ClientAdder<'_> {
+    fn add(&mut self, item: Addable) -> Result<(String, Resource)> {
+        match item {
+            Addable::Torrent(torrent) => add_torrent(
+                &mut self.c,
+                torrent,
+                self.dir,
+                self.start,
+                self.import,
+                self.preset,
+            ),
+            Addable::Magnet(magnet) => {
+                add_magnet(&mut self.c, magnet, self.dir, self.start, self.preset)
+            }
+        }
+    }
+}
+
+/// Adds torrent files and magnet links, with `-` reading either from stdin. The `ids` output
+/// prints just the ID of each added torrent on a line of its own.
 pub fn add(
-    mut c: Client,
-    url: &str,
+    c: Client,
     files: Vec<&str>,
     dir: Option<&str>,
     start: bool,
     import: bool,
+    preset: &Preset,
     output: &str,
 ) -> Result<()> {
+    let mut adder = ClientAdder {
+        c,
+        dir,
+        start,
+        import,
+        preset,
+    };
+    add_all(
+        &mut adder,
+        files,
+        &mut io::stdin().lock(),
+        &mut io::stdout().lock(),
+        output,
+    )
+}
+
+fn add_all<A: Adder>(
+    adder: &mut A,
+    files: Vec<&str>,
+    stdin: &mut impl Read,
+    out: &mut impl Write,
+    output: &str,
+) -> Result<()> {
+    let mut failed = 0;
     for file in files {
-        if let Ok(magnet) = Url::parse(file) {
-            add_magnet(&mut c, magnet, dir, start, output)?;
-        } else {
-            add_file(&mut c, url, file, dir, start, import, output)?;
+        let items = match addables(file, stdin) {
+            Ok(items) => items,
+            Err(e) => {
+                eprintln!("Failed to read {}: {}", file, e);
+                failed += 1;
+                continue;
+            }
+        };
+        for item in items {
+            match adder.add(item) {
+                Ok((infohash, resource)) => print_added(out, output, &infohash, &resource)?,
+                Err(e) => {
+                    eprintln!("Failed to add {}: {}", file, e);
+                    failed += 1;
+                }
+            }
+        }
+    }
+    if failed != 0 {
+        bail!("{} torrents could not be added", failed);
+    }
+    Ok(())
+}
+
+/// Reads what a command line argument refers to: a magnet link, a torrent file, or
+/// either from stdin given `-`.
+fn addables(file: &str, stdin: &mut impl Read) -> Result<Vec<Addable>> {
+    if file == "-" {
+        let mut data = Vec::new();
+        stdin.read_to_end(&mut data)?;
+        return sniff(data);
+    }
+    if let Ok(magnet) = Url::parse(file) {
+        return Ok(vec![Addable::Magnet(magnet)]);
+    }
+    Ok(vec![Addable::Torrent(fs::read(file)?)])
+}
+
+/// Tells a bencoded torrent apart from newline separated magnet links.
+fn sniff(data: Vec<u8>) -> Result<Vec<Addable>> {
+    let text = data.trim_ascii_start();
+    if text.starts_with(b"magnet:") {
+        return std::str::from_utf8(text)?
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty())
+            .map(|l| match Url::parse(l) {
+                Ok(magnet) if magnet.scheme() == "magnet" => Ok(Addable::Magnet(magnet)),
+                _ => Err(anyhow!("Invalid magnet link {}", l)),
+            })
+            .collect();
+    }
+    // Torrents are bencoded dictionaries
+    if data.first() == Some(&b'd') {
+        return Ok(vec![Addable::Torrent(data)]);
+    }
+    if text.is_empty() {
+        bail!("Nothing to add on stdin");
+    }
+    bail!("stdin holds neither a torrent nor magnet links");
+}
+
+fn print_added(
+    out: &mut impl Write,
+    output: &str,
+    infohash: &str,
+    resource: &Resource,
+) -> Result<()> {
+    match output {
+        "ids" => {
+            writeln!(out, "{}", resource.id())?;
         }
+        "text" => {
+            writeln!(out, "Added {}", infohash)?;
+            writeln!(out, "{}", resource)?;
+        }
+        "json" => {
+            writeln!(out, "{}", serde_json::to_string_pretty(resource)?)?;
+        }
+        _ => unreachable!(),
     }
     Ok(())
 }
 
 fn add_file(
     c: &mut Client,
-    url: &str,
     file: &str,
     dir: Option<&str>,
     start: bool,
     import: bool,
-    output: &str,
-) -> Result<()> {
-    let mut torrent = Vec::new();
-    let mut f = fs::File::open(file)?;
-    f.read_to_end(&mut torrent)?;
+    preset: &Preset,
+) -> Result<(String, Resource)> {
+    add_torrent(c, fs::read(file)?, dir, start, import, preset)
+}
 
-    let msg = CMessage::UploadTorrent {
+fn add_torrent(
+    c: &mut Client,
+    torrent: Vec<u8>,
+    dir: Option<&str>,
+    start: bool,
+    import: bool,
+    preset: &Preset,
+) -> Result<(String, Resource)> {
+    let msg = CMessage::AddTorrent {
         serial: c.next_serial(),
-        size: torrent.len() as u64,
+        torrent: Some(BASE64_STANDARD.encode(torrent)),
+        magnet: None,
         path: dir.as_ref().map(|d| d.to_string()),
         start,
         import,
+        file_priorities: preset.file_priorities.clone(),
+        strategy: preset.strategy,
     };
-    let token = if let SMessage::TransferOffer { token, .. } = c.rr(msg)? {
-        token
-    } else {
-        bail!("Failed to receieve transfer offer from synapse!");
+    added(c.rr(msg)?)
+}
+
+fn add_magnet(
+    c: &mut Client,
+    magnet: Url,
+    dir: Option<&str>,
+    start: bool,
+    preset: &Preset,
+) -> Result<(String, Resource)> {
+    let msg = CMessage::AddTorrent {
+        serial: c.next_serial(),
+        torrent: None,
+        magnet: Some(magnet.as_str().to_owned()),
+        path: dir.as_ref().map(|d| d.to_string()),
+        start,
+        import: false,
+        file_priorities: preset.file_priorities.clone(),
+        strategy: preset.strategy,
     };
-    let _resp = ureq::post(url)
-        .header("Authorization", &format!("Bearer {}", token))
-        .send(&torrent)
-        .map_err(|e| anyhow!(e).context("Could not POST to synapse"))?;
+    added(c.rr(msg)?)
+}
 
-    match c.recv()? {
-        SMessage::ResourcesExtant { ids, .. } => {
-            get_(c, ids[0].as_ref(), output)?;
-        }
-        SMessage::InvalidRequest(message::Error { reason, .. }) => {
-            bail!("{}", reason);
-        }
-        SMessage::TransferFailed(message::Error { reason, .. }) => {
-            bail!("{}", reason);
+/// Extracts the infohash and torrent from the reply to an ADD_TORRENT request.
+fn added(msg: SMessage) -> Result<(String, Resource)> {
+    match msg {
+        SMessage::TorrentAdded {
+            infohash, resource, ..
+        } => Ok((infohash, resource)),
+        SMessage::AddTorrentFailed { error, reason, .. } => {
+            bail!("{} ({:?})", reason, error);
         }
         _ => {
             bail!("Failed to receieve upload acknowledgement from synapse");
         }
     }
+}
 
-    Ok(())
+/// Server side operations needed to import a directory of torrents.
+trait Importer {
+    /// Returns the subset of the given torrent IDs which the server already has.
+    fn existing(&mut self, ids: &[String]) -> Result<HashSet<String>>;
+    fn add_torrent(&mut self, path: &Path) -> Result<String>;
+    fn add_magnet(&mut self, magnet: Url) -> Result<String>;
 }
-fn add_magnet(
-    c: &mut Client,
-    magnet: Url,
+
+struct ClientImporter<'a> {
+    c: Client,
+    dir: Option<&'a str>,
+    start: bool,
+}
+
+impl Importer for ClientImporter<'_> {
+    fn existing(&mut self, ids: &[String]) -> Result<HashSet<String>> {
+        if ids.is_empty() {
+            return Ok(HashSet::new());
+        }
+        let crit = vec![Criterion {
+            field: "id".to_owned(),
+            op: Operation::In,
+            value: Value::V(ids.iter().cloned().map(Value::S).collect()),
+        }];
+        let res = search(&mut self.c, ResourceKind::Torrent, crit)?;
+        Ok(res.iter().map(|r| r.id().to_owned()).collect())
+    }
+
+    fn add_torrent(&mut self, path: &Path) -> Result<String> {
+        let file = path
+            .to_str()
+            .ok_or_else(|| anyhow!("Non UTF-8 path {}", path.display()))?;
+        add_file(
+            &mut self.c,
+            file,
+            self.dir,
+            self.start,
+            false,
+            &Preset::default(),
+        )
+        .map(|(id, _)| id)
+    }
+
+    fn add_magnet(&mut self, magnet: Url) -> Result<String> {
+        add_magnet(
+            &mut self.c,
+            magnet,
+            self.dir,
+            self.start,
+            &Preset::default(),
+        )
+        .map(|(id, _)| id)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum ImportStatus {
+    Added(String),
+    Exists(String),
+    Failed(String),
+}
+
+pub fn import(
+    c: Client,
+    path: &str,
     dir: Option<&str>,
     start: bool,
-    output: &str,
+    recursive: bool,
+    delete: bool,
 ) -> Result<()> {
-    let msg = CMessage::UploadMagnet {
-        serial: c.next_serial(),
-        uri: magnet.as_str().to_owned(),
-        path: dir.as_ref().map(|d| d.to_string()),
-        start,
+    let mut importer = ClientImporter { c, dir, start };
+    let results = import_dir(&mut importer, Path::new(path), recursive, delete)?;
+
+    let mut table = Table::new();
+    table.set_format(*TABLE_FORMAT);
+    table.set_titles(row!["File", "Status", "Detail"]);
+    for (file, status) in &results {
+        let (status, detail) = match status {
+            ImportStatus::Added(id) => ("added", id.as_str()),
+            ImportStatus::Exists(id) => ("exists", id.as_str()),
+            ImportStatus::Failed(reason) => ("failed", reason.as_str()),
+        };
+        table.add_row(row![file.display(), status, detail]);
+    }
+    table.printstd();
+    let count = |f: fn(&ImportStatus) -> bool| results.iter().filter(|(_, s)| f(s)).count();
+    let failed = count(|s| matches!(s, ImportStatus::Failed(_)));
+    println!(
+        "{} added, {} already present, {} failed",
+        count(|s| matches!(s, ImportStatus::Added(_))),
+        count(|s| matches!(s, ImportStatus::Exists(_))),
+        failed,
+    );
+    if failed != 0 {
+        bail!("{} files could not be imported", failed);
+    }
+    Ok(())
+}
+
+/// Name of the subdirectory successfully imported files are moved into.
+const LOADED_DIR: &str = "loaded";
+
+fn import_dir<I: Importer>(
+    imp: &mut I,
+    path: &Path,
+    recursive: bool,
+    delete: bool,
+) -> Result<Vec<(PathBuf, ImportStatus)>> {
+    let mut files = Vec::new();
+    find_importable(path, recursive, &mut files)?;
+    files.sort();
+
+    let ids: Vec<_> = files.iter().map(|f| importable_id(f)).collect();
+    let existing = imp.existing(
+        &ids.iter()
+            .filter_map(|id| id.as_ref().ok().cloned())
+            .collect::<Vec<_>>(),
+    )?;
+
+    let mut results = Vec::new();
+    for (file, id) in files.into_iter().zip(ids) {
+        let status = match id {
+            Err(e) => ImportStatus::Failed(e.to_string()),
+            Ok(id) if existing.contains(&id) => ImportStatus::Exists(id),
+            Ok(_) => match import_file(imp, &file, delete) {
+                Ok(id) => ImportStatus::Added(id),
+                Err(e) => ImportStatus::Failed(e.to_string()),
+            },
+        };
+        results.push((file, status));
+    }
+    Ok(results)
+}
+
+fn import_file<I: Importer>(imp: &mut I, file: &Path, delete: bool) -> Result<String> {
+    let id = if is_magnet(file) {
+        imp.add_magnet(read_magnet(file)?)?
+    } else {
+        imp.add_torrent(file)?
     };
-    match c.rr(msg)? {
-        SMessage::ResourcesExtant { ids, .. } => {
-            get_(c, ids[0].as_ref(), output)?;
+    if delete {
+        fs::remove_file(file)?;
+    } else {
+        let loaded = file.with_file_name(LOADED_DIR);
+        fs::create_dir_all(&loaded)?;
+        fs::rename(file, loaded.join(file.file_name().unwrap()))?;
+    }
+    Ok(id)
+}
+
+fn find_importable(dir: &Path, recursive: bool, files: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            if recursive && path.file_name() != Some(LOADED_DIR.as_ref()) {
+                find_importable(&path, recursive, files)?;
+            }
+        } else if is_magnet(&path) || path.extension() == Some("torrent".as_ref()) {
+            files.push(path);
         }
-        SMessage::InvalidRequest(message::Error { reason, .. }) => {
-            bail!("{}", reason);
+    }
+    Ok(())
+}
+
+fn is_magnet(path: &Path) -> bool {
+    path.extension() == Some("magnet".as_ref())
+}
+
+fn read_magnet(path: &Path) -> Result<Url> {
+    let magnet = Url::parse(fs::read_to_string(path)?.trim())?;
+    if magnet.scheme() != "magnet" {
+        bail!("Not a magnet link");
+    }
+    Ok(magnet)
+}
+
+/// Computes the torrent ID synapse will assign to a .torrent or .magnet file.
+fn importable_id(path: &Path) -> Result<String> {
+    let hash = if is_magnet(path) {
+        let magnet = read_magnet(path)?;
+        let xt = magnet
+            .query_pairs()
+            .find(|(k, v)| k == "xt" && v.starts_with("urn:btih:"))
+            .ok_or_else(|| anyhow!("No info hash in magnet link"))?;
+        let hash = &xt.1[9..];
+        match hash.len() {
+            40 => (0..40)
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&hash[i..i + 2], 16))
+                .collect::<std::result::Result<Vec<_>, _>>()?,
+            32 => base32_decode(hash).ok_or_else(|| anyhow!("Invalid info hash"))?,
+            _ => bail!("Invalid info hash"),
         }
-        _ => {
-            bail!("Failed to receieve upload acknowledgement from synapse");
+    } else {
+        let data = fs::read(path)?;
+        let info = bencode::decode_buf(&data)
+            .map_err(|e| anyhow!("Invalid torrent file: {}", e))?
+            .into_dict()
+            .and_then(|mut d| d.remove(b"info".as_ref()))
+            .ok_or_else(|| anyhow!("Invalid torrent file: no info dictionary"))?;
+        Sha1::digest(info.encode_to_buf()).to_vec()
+    };
+    Ok(hash.iter().map(|b| format!("{:02X}", b)).collect())
+}
+
+/// Decodes unpadded RFC 4648 base32, as used by some magnet links.
+fn base32_decode(s: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(s.len() * 5 / 8);
+    let (mut acc, mut bits) = (0u32, 0);
+    for c in s.bytes() {
+        let v = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+        acc = (acc << 5) | u32::from(v);
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
         }
     }
-    Ok(())
+    Some(out)
 }
 
 pub fn del(mut c: Client, torrents: Vec<&str>, artifacts: bool) -> Result<()> {
@@ -118,68 +487,36 @@ pub fn del(mut c: Client, torrents: Vec<&str>, artifacts: bool) -> Result<()> {
 }
 
 fn del_torrent(c: &mut Client, torrent: &str, artifacts: bool) -> Result<()> {
-    let resources = search_torrent_name(c, torrent)?;
-    if resources.len() == 1 {
-        let msg = CMessage::RemoveResource {
-            serial: c.next_serial(),
-            id: resources[0].id().to_owned(),
-            artifacts: Some(artifacts),
-        };
-        c.send(msg)?;
-    } else if resources.is_empty() {
-        eprintln!("Could not find any matching torrents for {}", torrent);
-    } else {
-        eprintln!(
-            "Ambiguous results searching for {}. Potential alternatives include: ",
-            torrent
-        );
-        for res in resources.into_iter().take(3) {
-            if let Resource::Torrent(t) = res {
-                eprintln!(
-                    "{}",
-                    t.name.unwrap_or_else(|| "[Unknown Magnet]".to_owned())
-                );
-            }
+    match resolve_torrent(c, torrent) {
+        Ok(res) => {
+            let msg = CMessage::RemoveResource {
+                serial: c.next_serial(),
+                id: res.id().to_owned(),
+                artifacts: Some(artifacts),
+            };
+            c.send(msg)?;
         }
+        Err(e) => eprintln!("{}", e),
     }
     Ok(())
 }
 
 pub fn dl(mut c: Client, url: &str, name: &str) -> Result<()> {
-    let resources = search_torrent_name(&mut c, name)?;
+    let torrent = resolve_torrent(&mut c, name)?;
     let token = get_server(&mut c)?.download_token;
-    let files = if resources.len() == 1 {
-        let msg = CMessage::FilterSubscribe {
-            serial: c.next_serial(),
-            kind: ResourceKind::File,
-            criteria: vec![Criterion {
-                field: "torrent_id".to_owned(),
-                op: Operation::Eq,
-                value: Value::S(resources[0].id().to_owned()),
-            }],
-        };
-        if let SMessage::ResourcesExtant { ids, .. } = c.rr(msg)? {
-            get_resources(&mut c, ids.iter().map(Cow::to_string).collect())?
-        } else {
-            bail!("Could not get files for torrent!");
-        }
-    } else if resources.is_empty() {
-        eprintln!("Could not find any matching torrents for {}", name);
-        return Ok(());
+    let msg = CMessage::FilterSubscribe {
+        serial: c.next_serial(),
+        kind: ResourceKind::File,
+        criteria: vec![Criterion {
+            field: "torrent_id".to_owned(),
+            op: Operation::Eq,
+            value: Value::S(torrent.id().to_owned()),
+        }],
+    };
+    let files = if let SMessage::ResourcesExtant { ids, .. } = c.rr(msg)? {
+        get_resources(&mut c, ids.iter().map(Cow::to_string).collect())?
     } else {
-        eprintln!(
-            "Ambiguous results searching for {}. Potential alternatives include: ",
-            name
-        );
-        for res in resources.into_iter().take(3) {
-            if let Resource::Torrent(t) = res {
-                eprintln!(
-                    "{}",
-                    t.name.unwrap_or_else(|| "[Unknown Magnet]".to_owned())
-                );
-            }
-        }
-        return Ok(());
+        bail!("Could not get files for torrent!");
     };
 
     for file in files {
@@ -214,7 +551,11 @@ pub fn get(mut c: Client, id: &str, output: &str) -> Result<()> {
 }
 
 pub fn get_(c: &mut Client, id: &str, output: &str) -> Result<()> {
-    let res = get_resources(c, vec![id.to_owned()])?;
+    let res = if is_full_id(id) {
+        get_resources(c, vec![id.to_owned()])?
+    } else {
+        vec![resolve_torrent(c, id)?]
+    };
     if res.is_empty() {
         bail!("Resource not found");
     }
@@ -230,7 +571,13 @@ pub fn get_(c: &mut Client, id: &str, output: &str) -> Result<()> {
     Ok(())
 }
 
-pub fn list(mut c: Client, kind: &str, crit: Vec<Criterion>, output: &str) -> Result<()> {
+pub fn list(
+    mut c: Client,
+    kind: &str,
+    crit: Vec<Criterion>,
+    page: Option<(usize, Option<usize>)>,
+    output: &str,
+) -> Result<()> {
     let k = match kind {
         "torrent" => ResourceKind::Torrent,
         "tracker" => ResourceKind::Tracker,
@@ -240,7 +587,10 @@ pub fn list(mut c: Client, kind: &str, crit: Vec<Criterion>, output: &str) -> Re
         "server" => ResourceKind::Server,
         _ => bail!("Unexpected resource kind {}", kind),
     };
-    let results = search(&mut c, k, crit)?;
+    let results = match page {
+        Some((offset, limit)) => query(&mut c, k, crit, offset, limit)?,
+        None => search(&mut c, k, crit)?,
+    };
     if output == "text" {
         let mut table = Table::new();
         table.set_format(*TABLE_FORMAT);
@@ -330,28 +680,15 @@ pub fn pause<T: AsRef<str>>(mut c: Client, torrents: &[T]) -> Result<()> {
 }
 
 fn pause_torrent(c: &mut Client, torrent: &str) -> Result<()> {
-    let resources = search_torrent_name(c, torrent)?;
-    if resources.len() == 1 {
-        let msg = CMessage::PauseTorrent {
-            serial: c.next_serial(),
-            id: resources[0].id().to_owned(),
-        };
-        c.send(msg)?;
-    } else if resources.is_empty() {
-        eprintln!("Could not find any matching torrents for {}", torrent);
-    } else {
-        eprintln!(
-            "Ambiguous results searching for {}. Potential alternatives include: ",
-            torrent
-        );
-        for res in resources.into_iter().take(3) {
-            if let Resource::Torrent(t) = res {
-                eprintln!(
-                    "{}",
-                    t.name.unwrap_or_else(|| "[Unknown Magnet]".to_owned())
-                );
-            }
+    match resolve_torrent(c, torrent) {
+        Ok(res) => {
+            let msg = CMessage::PauseTorrent {
+                serial: c.next_serial(),
+                id: res.id().to_owned(),
+            };
+            c.send(msg)?;
         }
+        Err(e) => eprintln!("{}", e),
     }
     Ok(())
 }
@@ -364,41 +701,34 @@ pub fn resume<T: AsRef<str>>(mut c: Client, torrents: &[T]) -> Result<()> {
 }
 
 fn resume_torrent(c: &mut Client, torrent: &str) -> Result<()> {
-    let resources = search_torrent_name(c, torrent)?;
-    if resources.len() == 1 {
-        let msg = CMessage::ResumeTorrent {
-            serial: c.next_serial(),
-            id: resources[0].id().to_owned(),
-        };
-        c.send(msg)?;
-    } else if resources.is_empty() {
-        eprintln!("Could not find any matching torrents for {}", torrent);
-    } else {
-        eprintln!(
-            "Ambiguous results searching for {}. Potential alternatives include: ",
-            torrent
-        );
-        for res in resources.into_iter().take(3) {
-            if let Resource::Torrent(t) = res {
-                eprintln!(
-                    "{}",
-                    t.name.unwrap_or_else(|| "[Unknown Magnet]".to_owned())
-                );
-            }
+    match resolve_torrent(c, torrent) {
+        Ok(res) => {
+            let msg = CMessage::ResumeTorrent {
+                serial: c.next_serial(),
+                id: res.id().to_owned(),
+            };
+            c.send(msg)?;
         }
+        Err(e) => eprintln!("{}", e),
     }
     Ok(())
 }
 
 pub fn watch(mut c: Client, id: &str, output: &str, completion: bool) -> Result<()> {
-    let res = get_resources(&mut c, vec![id.to_owned()])?;
-    if res.is_empty() {
-        bail!("Resource not found");
-    }
+    let id = if is_full_id(id) {
+        let res = get_resources(&mut c, vec![id.to_owned()])?;
+        if res.is_empty() {
+            bail!("Resource not found");
+        }
+        id.to_owned()
+    } else {
+        resolve_torrent(&mut c, id)?.id().to_owned()
+    };
 
     let msg = CMessage::Subscribe {
         serial: c.next_serial(),
-        ids: vec![id.to_owned()],
+        ids: vec![id],
+        block_progress: output != "json",
     };
 
     let resources = if let SMessage::UpdateResources { resources, .. } = c.rr(msg)? {
@@ -425,10 +755,31 @@ pub fn watch(mut c: Client, id: &str, output: &str, completion: bool) -> Result<
             return Ok(());
         }
     }
+    if output == "jsonl" {
+        let mut stdout = io::stdout().lock();
+        write_jsonl(&mut stdout, &SResourceUpdate::Resource(Cow::Borrowed(&res)))?;
+        loop {
+            if let SMessage::UpdateResources { resources, .. } = c.recv()? {
+                for r in resources {
+                    write_jsonl(&mut stdout, &r)?;
+                    if let SResourceUpdate::TorrentTransfer { progress, .. } = r {
+                        if completion && progress == 1.0 {
+                            return Ok(());
+                        }
+                    }
+                }
+            }
+        }
+    }
+    // Block progress of partially downloaded pieces
+    let mut partial = BTreeMap::new();
     loop {
         match output {
             "text" => {
                 println!("{}", res);
+                for (piece, (done, total)) in &partial {
+                    println!("piece {}: {}/{} blocks", piece, done, total);
+                }
             }
             "json" => {
                 println!("{}", serde_json::to_string(&res)?);
@@ -438,12 +789,26 @@ pub fn watch(mut c: Client, id: &str, output: &str, completion: bool) -> Result<
         loop {
             if let SMessage::UpdateResources { resources, .. } = c.recv()? {
                 for r in resources {
-                    if let SResourceUpdate::TorrentTransfer { progress, .. } = r {
-                        if completion && progress == 1.0 {
+                    match r {
+                        SResourceUpdate::TorrentTransfer { progress, .. }
+                            if completion && progress == 1.0 =>
+                        {
                             return Ok(());
                         }
+                        SResourceUpdate::TorrentBlockProgress {
+                            piece,
+                            blocks_done,
+                            blocks_total,
+                            ..
+                        } => {
+                            if blocks_done == 0 || blocks_done >= blocks_total {
+                                partial.remove(&piece);
+                            } else {
+                                partial.insert(piece, (blocks_done, blocks_total));
+                            }
+                        }
+                        r => res.update(r),
                     }
-                    res.update(r);
                 }
                 break;
             }
@@ -451,15 +816,21 @@ pub fn watch(mut c: Client, id: &str, output: &str, completion: bool) -> Result<
     }
 }
 
+/// Writes an update as a single line of JSON, flushing so that
+/// consumers see it immediately.
+fn write_jsonl<W: Write>(w: &mut W, update: &SResourceUpdate) -> Result<()> {
+    serde_json::to_writer(&mut *w, update)?;
+    w.write_all(b"\n")?;
+    w.flush()?;
+    Ok(())
+}
+
 pub fn move_torrent(mut c: Client, id: &str, dir: &str, skip_files: bool) -> Result<()> {
-    let torrent = search_torrent_name(&mut c, id)?;
-    if torrent.len() != 1 {
-        bail!("Could not find appropriate torrent!");
-    }
+    let torrent = resolve_torrent(&mut c, id)?;
     let update = CMessage::UpdateResource {
         serial: c.next_serial(),
         resource: CResourceUpdate {
-            id: torrent[0].id().to_owned(),
+            id: torrent.id().to_owned(),
             path: Some(if skip_files {
                 PathUpdate::MoveSkipFiles(dir.to_owned())
             } else {
@@ -473,25 +844,149 @@ pub fn move_torrent(mut c: Client, id: &str, dir: &str, skip_files: bool) -> Res
 }
 
 pub fn verify_torrent(mut c: Client, id: &str) -> Result<()> {
-    let torrent = search_torrent_name(&mut c, id)?;
-    if torrent.len() != 1 {
-        bail!("Could not find appropriate torrent!");
-    }
+    let torrent = resolve_torrent(&mut c, id)?;
     let msg = CMessage::ValidateResources {
         serial: c.next_serial(),
-        ids: vec![torrent[0].id().to_owned()],
+        ids: vec![torrent.id().to_owned()],
     };
     c.send(msg)?;
     Ok(())
 }
 
-pub fn add_trackers(mut c: Client, id: &str, trackers: Vec<&str>) -> Result<()> {
-    let torrent = search_torrent_name(&mut c, id)?;
-    if torrent.len() != 1 {
-        bail!("Could not find appropriate torrent!");
+pub fn export_torrent(mut c: Client, id: &str, path: &str) -> Result<()> {
+    let torrent = resolve_torrent(&mut c, id)?;
+    let msg = CMessage::GetMetainfo {
+        serial: c.next_serial(),
+        id: torrent.id().to_owned(),
+    };
+    let metainfo = match c.rr(msg)? {
+        SMessage::Metainfo(m) => m,
+        _ => {
+            bail!("Failed to receive metainfo from synapse!");
+        }
+    };
+    fs::write(path, encode_metainfo(&metainfo)?)?;
+    Ok(())
+}
+
+pub fn get_history(mut c: Client, id: &str, output: &str) -> Result<()> {
+    let torrent = resolve_torrent(&mut c, id)?;
+    let msg = CMessage::GetHistory {
+        serial: c.next_serial(),
+        id: torrent.id().to_owned(),
+    };
+    let history = match c.rr(msg)? {
+        SMessage::History(h) => h,
+        _ => {
+            bail!("Failed to receive history from synapse!");
+        }
+    };
+    if output == "json" {
+        println!("{}", serde_json::to_string_pretty(&history.entries)?);
+        return Ok(());
+    }
+    let mut table = Table::new();
+    table.set_format(*TABLE_FORMAT);
+    table.set_titles(row!["Time", "By", "Action", "Detail"]);
+    for e in history.entries {
+        let action = serde_json::to_value(e.action)?;
+        table.add_row(row![
+            e.time.format("%Y-%m-%d %H:%M:%S"),
+            e.by,
+            action.as_str().unwrap_or_default(),
+            e.detail.as_deref().unwrap_or("")
+        ]);
     }
+    table.printstd();
+    Ok(())
+}
+
+/// Rebuilds a .torrent file from the server's metainfo, checking that
+/// the info dictionary hashes to the torrent's infohash.
+fn encode_metainfo(m: &message::Metainfo) -> Result<Vec<u8>> {
+    let name = match m.name_raw {
+        Some(ref n) => BASE64_STANDARD.decode(n)?,
+        None => m.name.clone().into_bytes(),
+    };
+    let mut info = BTreeMap::new();
+    info.insert(b"name".to_vec(), bencode::BEncode::String(name));
+    info.insert(
+        b"piece length".to_vec(),
+        bencode::BEncode::from_int(i64::from(m.piece_length)),
+    );
+    info.insert(
+        b"pieces".to_vec(),
+        bencode::BEncode::String(BASE64_STANDARD.decode(&m.pieces)?),
+    );
+    if m.private {
+        info.insert(b"private".to_vec(), bencode::BEncode::from_int(1));
+    }
+    match &m.files[..] {
+        [f] if f.path.len() == 1 => {
+            info.insert(
+                b"length".to_vec(),
+                bencode::BEncode::from_int(f.length as i64),
+            );
+        }
+        files => {
+            let files = files
+                .iter()
+                .map(|f| {
+                    let path = f.path.iter().skip(1).map(|c| bencode::BEncode::from_str(c));
+                    let mut fd = BTreeMap::new();
+                    fd.insert(
+                        b"length".to_vec(),
+                        bencode::BEncode::from_int(f.length as i64),
+                    );
+                    fd.insert(b"path".to_vec(), bencode::BEncode::List(path.collect()));
+                    bencode::BEncode::Dict(fd)
+                })
+                .collect();
+            info.insert(b"files".to_vec(), bencode::BEncode::List(files));
+        }
+    }
+    let info = bencode::BEncode::Dict(info);
+    let hash: String = Sha1::digest(info.encode_to_buf())
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect();
+    if !hash.eq_ignore_ascii_case(&m.hash) {
+        bail!(
+            "Rebuilt metainfo has infohash {}, expected {}; the original .torrent \
+             likely contained fields synapse does not keep",
+            hash,
+            m.hash
+        );
+    }
+
+    let mut t = BTreeMap::new();
+    t.insert(b"info".to_vec(), info);
+    if let Some(ref announce) = m.announce {
+        t.insert(b"announce".to_vec(), bencode::BEncode::from_str(announce));
+    }
+    if !m.announce_list.is_empty() {
+        let tiers = m
+            .announce_list
+            .iter()
+            .map(|tier| {
+                bencode::BEncode::List(tier.iter().map(|u| bencode::BEncode::from_str(u)).collect())
+            })
+            .collect();
+        t.insert(b"announce-list".to_vec(), bencode::BEncode::List(tiers));
+    }
+    if let Some(ref creator) = m.creator {
+        t.insert(b"created by".to_vec(), bencode::BEncode::from_str(creator));
+    }
+    if let Some(ref comment) = m.comment {
+        t.insert(b"comment".to_vec(), bencode::BEncode::from_str(comment));
+    }
+    Ok(bencode::BEncode::Dict(t).encode_to_buf())
+}
+
+pub fn add_trackers(mut c: Client, id: &str, trackers: Vec<&str>) -> Result<()> {
+    let torrent = resolve_torrent(&mut c, id)?;
     for tracker in trackers {
-        if let Err(e) = add_tracker(&mut c, torrent[0].id(), tracker) {
+        if let Err(e) = add_tracker(&mut c, torrent.id(), tracker) {
             eprintln!("Failed to add tracker {}: {}", tracker, e);
         }
     }
@@ -507,9 +1002,6 @@ fn add_tracker(c: &mut Client, id: &str, tracker: &str) -> Result<()> {
 
     match c.rr(msg)? {
         SMessage::ResourcesExtant { .. } => Ok(()),
-        SMessage::InvalidRequest(message::Error { reason, .. }) => {
-            bail!("{}", reason);
-        }
         _ => {
             bail!("Failed to receieve tracker extancy from synapse!");
         }
@@ -536,30 +1028,101 @@ pub fn announce_trackers(mut c: Client, trackers: Vec<&str>) -> Result<()> {
     Ok(())
 }
 
-fn remove_res(c: &mut Client, res: &str) -> Result<()> {
-    let msg = CMessage::RemoveResource {
+pub fn reannounce_all(mut c: Client) -> Result<()> {
+    let server = get_server(&mut c)?;
+    let msg = CMessage::Subscribe {
         serial: c.next_serial(),
-        id: res.to_owned(),
-        artifacts: None,
+        ids: vec![server.id],
+        block_progress: false,
     };
-    match c.rr(msg)? {
-        SMessage::ResourcesRemoved { .. } => Ok(()),
-        SMessage::InvalidRequest(message::Error { reason, .. }) => {
-            bail!("{}", reason);
-        }
-        _ => {
+    if !matches!(c.rr(msg)?, SMessage::UpdateResources { .. }) {
+        bail!("Failed to subscribe to the server!");
+    }
+    let serial = c.next_serial();
+    c.send(CMessage::ReannounceAll { serial })?;
+
+    let mut last = None;
+    loop {
+        if let SMessage::UpdateResources { resources, .. } = c.recv()? {
+            for r in resources {
+                if let SResourceUpdate::ServerReannounce {
+                    reannounce_queued,
+                    reannounce_done,
+                    reannounce_failed,
+                    reannounce_deferred,
+                    ..
+                } = r
+                {
+                    let counts = reannounce_queued
+                        .zip(reannounce_done)
+                        .zip(reannounce_failed.zip(reannounce_deferred));
+                    match (counts, last) {
+                        (Some(counts), _) if Some(counts) != last => {
+                            let ((queued, done), (failed, deferred)) = counts;
+                            println!(
+                                "queued: {}, done: {}, failed: {}, deferred: {}",
+                                queued, done, failed, deferred
+                            );
+                            last = Some(counts);
+                        }
+                        // Cleared once every announce was answered
+                        (None, Some(_)) => return Ok(()),
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub fn set_tracker_headers(mut c: Client, id: &str, headers: Vec<&str>) -> Result<()> {
+    for header in &headers {
+        resource::parse_tracker_header(header).map_err(|e| anyhow!(e))?;
+    }
+    let update = CMessage::UpdateResource {
+        serial: c.next_serial(),
+        resource: CResourceUpdate {
+            id: id.to_owned(),
+            headers: Some(headers.into_iter().map(str::to_owned).collect()),
+            ..Default::default()
+        },
+    };
+    c.send(update)?;
+    Ok(())
+}
+
+pub fn set_tracker_url(mut c: Client, id: &str, url: &str) -> Result<()> {
+    let url = Url::parse(url)?;
+    let update = CMessage::UpdateResource {
+        serial: c.next_serial(),
+        resource: CResourceUpdate {
+            id: id.to_owned(),
+            url: Some(url),
+            ..Default::default()
+        },
+    };
+    c.send(update)?;
+    Ok(())
+}
+
+fn remove_res(c: &mut Client, res: &str) -> Result<()> {
+    let msg = CMessage::RemoveResource {
+        serial: c.next_serial(),
+        id: res.to_owned(),
+        artifacts: None,
+    };
+    match c.rr(msg)? {
+        SMessage::ResourcesRemoved { .. } => Ok(()),
+        _ => {
             bail!("Failed to receieve removal confirmation from synapse!");
         }
     }
 }
 
 pub fn add_peers(mut c: Client, id: &str, peers: Vec<&str>) -> Result<()> {
-    let torrent = search_torrent_name(&mut c, id)?;
-    if torrent.len() != 1 {
-        bail!("Could not find appropriate torrent!");
-    }
+    let torrent = resolve_torrent(&mut c, id)?;
     for peer in peers {
-        if let Err(e) = add_peer(&mut c, torrent[0].id(), peer) {
+        if let Err(e) = add_peer(&mut c, torrent.id(), peer) {
             eprintln!("Failed to add peer {}: {}", peer, e);
         }
     }
@@ -574,9 +1137,6 @@ fn add_peer(c: &mut Client, id: &str, peer: &str) -> Result<()> {
     };
     match c.rr(msg)? {
         SMessage::ResourcePending { .. } => Ok(()),
-        SMessage::InvalidRequest(message::Error { reason, .. }) => {
-            bail!("{}", reason);
-        }
         _ => {
             bail!("Failed to peer extancy confirmation from synapse!");
         }
@@ -642,11 +1202,8 @@ pub fn get_tags(mut c: Client, id: &str) -> Result<()> {
 }
 
 fn get_tags_(c: &mut Client, id: &str) -> Result<(String, Vec<String>)> {
-    let mut sres = search_torrent_name(c, id)?;
-    if sres.len() != 1 {
-        bail!("Could not find appropriate torrent!");
-    }
-    let torrent = sres[0].as_torrent_mut();
+    let mut res = resolve_torrent(c, id)?;
+    let torrent = res.as_torrent_mut();
     let prev_data = mem::replace(&mut torrent.user_data, serde_json::Value::Null);
     Ok((
         torrent.id.clone(),
@@ -663,14 +1220,11 @@ fn get_tags_(c: &mut Client, id: &str) -> Result<(String, Vec<String>)> {
 
 pub fn set_torrent_pri(mut c: Client, id: &str, pri: &str) -> Result<()> {
     let p: u8 = pri.parse()?;
-    let torrent = search_torrent_name(&mut c, id)?;
-    if torrent.len() != 1 {
-        bail!("Could not find appropriate torrent!");
-    }
+    let torrent = resolve_torrent(&mut c, id)?;
     let update = CMessage::UpdateResource {
         serial: c.next_serial(),
         resource: CResourceUpdate {
-            id: torrent[0].id().to_owned(),
+            id: torrent.id().to_owned(),
             priority: Some(p),
             ..Default::default()
         },
@@ -679,6 +1233,20 @@ pub fn set_torrent_pri(mut c: Client, id: &str, pri: &str) -> Result<()> {
     Ok(())
 }
 
+pub fn set_file_order(mut c: Client, id: &str, files: Vec<&str>) -> Result<()> {
+    let torrent = resolve_torrent(&mut c, id)?;
+    let update = CMessage::UpdateResource {
+        serial: c.next_serial(),
+        resource: CResourceUpdate {
+            id: torrent.id().to_owned(),
+            file_order: Some(files.into_iter().map(str::to_ascii_uppercase).collect()),
+            ..Default::default()
+        },
+    };
+    c.send(update)?;
+    Ok(())
+}
+
 pub fn set_file_pri(mut c: Client, id: &str, pri: &str) -> Result<()> {
     let p: u8 = pri.parse()?;
     let update = CMessage::UpdateResource {
@@ -702,21 +1270,50 @@ pub fn get_peers(mut c: Client, id: &str, output: &str) -> Result<()> {
 }
 
 pub fn get_trackers(mut c: Client, id: &str, output: &str) -> Result<()> {
-    print_torrent_res(&mut c, id, ResourceKind::Tracker, output)
+    if output != "text" {
+        return print_torrent_res(&mut c, id, ResourceKind::Tracker, output);
+    }
+    let torrent = resolve_torrent(&mut c, id)?;
+    let trackers = search(
+        &mut c,
+        ResourceKind::Tracker,
+        vec![Criterion {
+            field: "torrent_id".to_owned(),
+            op: Operation::Eq,
+            value: Value::S(torrent.id().to_owned()),
+        }],
+    )?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64);
+    let mut table = Table::new();
+    table.set_format(*TABLE_FORMAT);
+    table.set_titles(row!["URL", "Tier", "Seeders", "Leechers", "Next", "Error"]);
+    for res in trackers {
+        let t = res.as_tracker();
+        let count = |n: Option<u32>| n.map(|n| n.to_string()).unwrap_or_default();
+        table.add_row(row![
+            t.url.as_str(),
+            t.tier,
+            count(t.seeders),
+            count(t.leechers),
+            fmt_countdown(t.next_announce.timestamp() - now),
+            t.error.as_deref().unwrap_or("")
+        ]);
+    }
+    table.printstd();
+    Ok(())
 }
 
 fn print_torrent_res(c: &mut Client, id: &str, kind: ResourceKind, output: &str) -> Result<()> {
-    let torrent = search_torrent_name(c, id)?;
-    if torrent.len() != 1 {
-        bail!("Could not find appropriate torrent!");
-    }
+    let torrent = resolve_torrent(c, id)?;
     let files = search(
         c,
         kind,
         vec![Criterion {
             field: "torrent_id".to_owned(),
             op: Operation::Eq,
-            value: Value::S(torrent[0].id().to_owned()),
+            value: Value::S(torrent.id().to_owned()),
         }],
     )?;
     for file in files {
@@ -751,11 +1348,316 @@ pub fn status(mut c: Client) -> Result<()> {
                 fmt_bytes(s.transferred_up as f64),
                 fmt_bytes(s.transferred_down as f64),
             );
+            if let Some(quota) = s.quota {
+                let used = s.quota_used.unwrap_or(0);
+                print!(
+                    "Quota: {}/{} ({}%)",
+                    fmt_bytes(used as f64),
+                    fmt_bytes(quota as f64),
+                    used.saturating_mul(100) / quota.max(1),
+                );
+                match s.quota_days_left {
+                    Some(days) => println!(", resets in {} days", days),
+                    None => println!(),
+                }
+            }
         }
         _ => {
             bail!("synapse server incorrectly reported server status!");
         }
     };
+    let msg = CMessage::GetDiskStats {
+        serial: c.next_serial(),
+    };
+    match c.rr(msg)? {
+        SMessage::DiskStats(stats) => {
+            println!("Disk:");
+            print_disk_counters("total", &stats.total);
+            print_disk_counters("last minute", &stats.last_minute);
+        }
+        _ => {
+            bail!("Failed to receive disk stats from synapse!");
+        }
+    }
+    Ok(())
+}
+
+/// Shortest wait for updates between checks for a due repaint
+const MIN_STATS_WAIT: Duration = Duration::from_millis(10);
+
+/// Shows the server's and each torrent's transfer, repainted every `interval` seconds
+/// until interrupted.
+pub fn stats(mut c: Client, interval: u64) -> Result<()> {
+    let server = get_server(&mut c)?;
+    let msg = CMessage::Subscribe {
+        serial: c.next_serial(),
+        ids: vec![server.id],
+        block_progress: false,
+    };
+    c.send(msg)?;
+    // Matches every torrent, and goes on to report those added or removed later
+    let msg = CMessage::FilterSubscribe {
+        serial: c.next_serial(),
+        kind: ResourceKind::Torrent,
+        criteria: vec![],
+    };
+    c.send(msg)?;
+
+    let mut dash = Dashboard::new(Duration::from_secs(interval));
+    let mut stdout = io::stdout();
+    loop {
+        if let Some(msg) = c.recv_timeout(dash.wait(Instant::now()))? {
+            if let Some(e) = msg.error() {
+                return Err(e.clone().into());
+            }
+            let ids = dash.apply(msg);
+            if !ids.is_empty() {
+                let msg = CMessage::Subscribe {
+                    serial: c.next_serial(),
+                    ids,
+                    block_progress: false,
+                };
+                c.send(msg)?;
+            }
+        }
+        if let Some(frame) = dash.frame(Instant::now()) {
+            // Clear the screen and draw from the top left
+            write!(stdout, "\x1b[2J\x1b[H{}", frame)?;
+            stdout.flush()?;
+        }
+    }
+}
+
+/// Resources shown by `stats`. However many updates arrive in an interval, it's
+/// repainted at most once.
+struct Dashboard {
+    server: Option<Resource>,
+    torrents: BTreeMap<String, Resource>,
+    interval: Duration,
+    painted: Option<Instant>,
+    /// Whether anything changed since the last paint
+    dirty: bool,
+}
+
+impl Dashboard {
+    fn new(interval: Duration) -> Dashboard {
+        Dashboard {
+            server: None,
+            torrents: BTreeMap::new(),
+            interval,
+            painted: None,
+            dirty: false,
+        }
+    }
+
+    /// Applies a message from the server, returning the IDs of torrents which need to
+    /// be subscribed to.
+    fn apply(&mut self, msg: SMessage<'_>) -> Vec<String> {
+        match msg {
+            SMessage::ResourcesExtant { ids, .. } => ids
+                .into_iter()
+                .filter(|id| !self.torrents.contains_key(id.as_ref()))
+                .map(Cow::into_owned)
+                .collect(),
+            SMessage::ResourcesRemoved { ids, .. } => {
+                for id in ids {
+                    self.dirty |= self.torrents.remove(&id).is_some();
+                }
+                vec![]
+            }
+            SMessage::UpdateResources { resources, .. } => {
+                for update in resources {
+                    self.update(update);
+                }
+                vec![]
+            }
+            _ => vec![],
+        }
+    }
+
+    fn update(&mut self, update: SResourceUpdate<'_>) {
+        if let SResourceUpdate::Resource(res) = update {
+            let res = res.into_owned();
+            match res {
+                Resource::Server(_) => self.server = Some(res),
+                Resource::Torrent(_) => {
+                    self.torrents.insert(res.id().to_owned(), res);
+                }
+                _ => return,
+            }
+            self.dirty = true;
+            return;
+        }
+        let res = match self.server {
+            Some(ref mut s) if s.id() == update.id() => s,
+            _ => match self.torrents.get_mut(update.id()) {
+                Some(t) => t,
+                None => return,
+            },
+        };
+        res.update(update);
+        self.dirty = true;
+    }
+
+    /// How long to wait for updates before a repaint could be due.
+    fn wait(&self, now: Instant) -> Duration {
+        match self.painted {
+            Some(painted) if self.dirty => (painted + self.interval)
+                .saturating_duration_since(now)
+                .max(MIN_STATS_WAIT),
+            _ => self.interval,
+        }
+    }
+
+    /// The dashboard to show at `now`, if it changed and the last was shown at least an
+    /// interval ago.
+    fn frame(&mut self, now: Instant) -> Option<String> {
+        if !self.dirty
+            || self
+                .painted
+                .is_some_and(|p| now.duration_since(p) < self.interval)
+        {
+            return None;
+        }
+        self.dirty = false;
+        self.painted = Some(now);
+        Some(self.render())
+    }
+
+    fn render(&self) -> String {
+        let mut out = String::new();
+        if let Some(Resource::Server(s)) = &self.server {
+            out += &format!(
+                "UL: {}/s, DL: {}/s, {} torrents\n\n",
+                fmt_bytes(s.rate_up as f64),
+                fmt_bytes(s.rate_down as f64),
+                self.torrents.len()
+            );
+        }
+        let mut torrents: Vec<_> = self.torrents.values().map(Resource::as_torrent).collect();
+        // Busiest first, like top
+        torrents.sort_by(|a, b| {
+            (b.rate_down + b.rate_up)
+                .cmp(&(a.rate_down + a.rate_up))
+                .then_with(|| a.name.cmp(&b.name))
+        });
+        let mut table = Table::new();
+        table.set_format(*TABLE_FORMAT);
+        table.set_titles(row!["Name", "Done", "DL RT", "UL RT", "Peers", "ETA"]);
+        for t in torrents {
+            let eta = if t.progress >= 1. {
+                "done".to_owned()
+            } else {
+                eta(t.size, t.progress, t.rate_down).map_or("-".to_owned(), fmt_countdown)
+            };
+            table.add_row(row![
+                t.name.as_deref().unwrap_or("[Unknown Magnet]"),
+                format!("{:.2}%", t.progress * 100.),
+                fmt_bytes(t.rate_down as f64) + "/s",
+                fmt_bytes(t.rate_up as f64) + "/s",
+                t.peers,
+                eta
+            ]);
+        }
+        out + &table.to_string()
+    }
+}
+
+/// Seconds left to download the rest of a torrent at `rate` bytes/s, unknown while its
+/// size is or it isn't downloading.
+fn eta(size: Option<u64>, progress: f32, rate: u64) -> Option<i64> {
+    let left = size? as f64 * (1. - f64::from(progress.clamp(0., 1.)));
+    if left < 1. {
+        return Some(0);
+    }
+    if rate == 0 {
+        return None;
+    }
+    Some((left / rate as f64).ceil() as i64)
+}
+
+fn print_disk_counters(label: &str, d: &message::DiskCounters) {
+    println!(
+        "  {}: {} reads ({}), {} writes ({}), file cache {}/{} hits, piece cache {}/{} hits, fallocate {} ok/{} failed, {} fsyncs",
+        label,
+        d.reads,
+        fmt_bytes(d.bytes_read as f64),
+        d.writes,
+        fmt_bytes(d.bytes_written as f64),
+        d.cache_hits,
+        d.cache_hits + d.cache_misses,
+        d.piece_cache_hits,
+        d.piece_cache_hits + d.piece_cache_misses,
+        d.fallocate_ok,
+        d.fallocate_failed,
+        d.fsyncs,
+    );
+}
+
+pub fn update_ip_filter(
+    mut c: Client,
+    action: message::IpFilterAction,
+    ranges: Vec<String>,
+    path: Option<String>,
+) -> Result<()> {
+    let msg = CMessage::UpdateIpFilter {
+        serial: c.next_serial(),
+        action,
+        ranges,
+        path,
+    };
+    match c.rr(msg)? {
+        SMessage::IpFilterUpdated { blocked, .. } => {
+            println!("{} blocked prefixes", blocked);
+            Ok(())
+        }
+        _ => {
+            bail!("Failed to receive ip filter update from synapse!");
+        }
+    }
+}
+
+pub fn analyze_path(mut c: Client, path: &str, output: &str) -> Result<()> {
+    let msg = CMessage::AnalyzePath {
+        serial: c.next_serial(),
+        path: path.to_owned(),
+    };
+    let analysis = match c.rr(msg)? {
+        SMessage::PathAnalysis(a) => a,
+        _ => {
+            bail!("Failed to receive path analysis from synapse!");
+        }
+    };
+    if output == "json" {
+        println!("{}", serde_json::to_string_pretty(&analysis)?);
+        return Ok(());
+    }
+
+    println!(
+        "{} files, {}{}",
+        analysis.file_count,
+        fmt_bytes(analysis.total_size as f64),
+        if analysis.complete {
+            ""
+        } else {
+            " (scan incomplete)"
+        }
+    );
+    let mut table = Table::new();
+    table.set_format(*TABLE_FORMAT);
+    table.set_titles(row!["Piece Size", "Pieces", ""]);
+    for cand in analysis.candidates {
+        let rec = if cand.piece_length == analysis.piece_length {
+            "recommended"
+        } else {
+            ""
+        };
+        table.add_row(row![fmt_bytes(cand.piece_length as f64), cand.pieces, rec]);
+    }
+    table.printstd();
+    for dir in analysis.unreadable {
+        println!("Skipped unreadable directory {dir}");
+    }
     Ok(())
 }
 
@@ -766,28 +1668,100 @@ fn get_server(c: &mut Client) -> Result<Server> {
     }
 }
 
-fn search_torrent_name(c: &mut Client, name: &str) -> Result<Vec<Resource>> {
-    let mut res = search(
-        c,
-        ResourceKind::Torrent,
-        vec![Criterion {
+/// Queries the server's torrents, abstracted so that id resolution can be tested.
+trait TorrentQuery {
+    fn torrents(&mut self, criteria: Vec<Criterion>) -> Result<Vec<Resource>>;
+}
+
+impl TorrentQuery for Client {
+    fn torrents(&mut self, criteria: Vec<Criterion>) -> Result<Vec<Resource>> {
+        query(self, ResourceKind::Torrent, criteria, 0, None)
+    }
+}
+
+/// Length of a resource id, the hex encoding of a SHA-1 digest
+const ID_LEN: usize = 40;
+
+fn is_full_id(id: &str) -> bool {
+    id.len() == ID_LEN && is_hex(id)
+}
+
+fn is_hex(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Resolves a command line argument to a torrent, accepting its full id, a unique prefix
+/// of its id or a unique case insensitive substring of its name, where ids take
+/// precedence. It takes a single query: the server matches full ids and names which
+/// can't be an id prefix, while other hex arguments are matched against every torrent.
+fn resolve_torrent<Q: TorrentQuery>(q: &mut Q, arg: &str) -> Result<Resource> {
+    let mut res = if is_full_id(arg) {
+        q.torrents(vec![Criterion {
             field: "id".to_owned(),
             op: Operation::Eq,
-            value: Value::S(name.to_owned()),
-        }],
-    )?;
-    if res.is_empty() {
-        res = search(
-            c,
-            ResourceKind::Torrent,
-            vec![Criterion {
-                field: "name".to_owned(),
-                op: Operation::ILike,
-                value: Value::S(format!("%{}%", name)),
-            }],
-        )?;
+            value: Value::S(arg.to_ascii_lowercase()),
+        }])?
+    } else if is_hex(arg) {
+        let id = arg.to_ascii_lowercase();
+        let (ids, others): (Vec<_>, Vec<_>) = q
+            .torrents(Vec::new())?
+            .into_iter()
+            .partition(|r| r.id().starts_with(&id));
+        if ids.is_empty() {
+            others
+                .into_iter()
+                .filter(|r| match r {
+                    Resource::Torrent(t) => t
+                        .name
+                        .as_ref()
+                        .is_some_and(|n| n.to_lowercase().contains(&id)),
+                    _ => false,
+                })
+                .collect()
+        } else {
+            ids
+        }
+    } else {
+        q.torrents(vec![Criterion {
+            field: "name".to_owned(),
+            op: Operation::ILike,
+            value: Value::S(format!("%{}%", escape_like(arg))),
+        }])?
+    };
+    match res.len() {
+        0 => bail!("Could not find any matching torrents for {}", arg),
+        1 => Ok(res.remove(0)),
+        _ => {
+            let candidates: Vec<_> = res
+                .iter()
+                .map(|r| match r {
+                    Resource::Torrent(t) => format!(
+                        "  {} {}",
+                        t.id,
+                        t.name.as_deref().unwrap_or("[Unknown Magnet]")
+                    ),
+                    r => format!("  {}", r.id()),
+                })
+                .collect();
+            bail!(
+                "Ambiguous results searching for {}, candidates are:\n{}",
+                arg,
+                candidates.join("\n")
+            )
+        }
+    }
+}
+
+/// Escapes the wildcards of a LIKE pattern, so that `s` only matches itself.
+fn escape_like(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
     }
-    Ok(res)
+    escaped
 }
 
 fn search(c: &mut Client, kind: ResourceKind, criteria: Vec<Criterion>) -> Result<Vec<Resource>> {
@@ -797,7 +1771,8 @@ fn search(c: &mut Client, kind: ResourceKind, criteria: Vec<Criterion>) -> Resul
         kind,
         criteria,
     };
-    if let SMessage::ResourcesExtant { ids, .. } = c.rr(msg)? {
+    let resp = c.rr(msg)?;
+    if let SMessage::ResourcesExtant { ids, .. } = resp {
         let ns = c.next_serial();
         c.send(CMessage::FilterUnsubscribe {
             serial: ns,
@@ -809,12 +1784,37 @@ fn search(c: &mut Client, kind: ResourceKind, criteria: Vec<Criterion>) -> Resul
     }
 }
 
+fn query(
+    c: &mut Client,
+    kind: ResourceKind,
+    criteria: Vec<Criterion>,
+    offset: usize,
+    limit: Option<usize>,
+) -> Result<Vec<Resource>> {
+    let msg = CMessage::QueryResources {
+        serial: c.next_serial(),
+        kind,
+        criteria,
+        offset,
+        limit,
+        fields: None,
+    };
+    match c.rr(msg)? {
+        SMessage::ResourcesQueried { resources, .. } => resources
+            .into_iter()
+            .map(|r| Ok(serde_json::from_value(r)?))
+            .collect(),
+        _ => bail!("Failed to receive queried resources!"),
+    }
+}
+
 fn get_resources(c: &mut Client, ids: Vec<String>) -> Result<Vec<Resource>> {
     let mut resources = vec![];
     for ids_chunk in ids.chunks(4096) {
         let msg = CMessage::Subscribe {
             serial: c.next_serial(),
             ids: ids_chunk.to_vec(),
+            block_progress: false,
         };
         let unsub = CMessage::Unsubscribe {
             serial: c.next_serial(),
@@ -860,3 +1860,525 @@ fn fmt_bytes(num: f64) -> String {
     let unit = units[exponent as usize];
     format!("{} {}", pretty_bytes, unit)
 }
+
+/// Formats the seconds left until some point, e.g. `1h 05m` or `4m 30s`.
+fn fmt_countdown(secs: i64) -> String {
+    match secs {
+        s if s <= 0 => "now".to_owned(),
+        s if s < 60 => format!("{}s", s),
+        s if s < 3600 => format!("{}m {:02}s", s / 60, s % 60),
+        s => format!("{}h {:02}m", s / 3600, s % 3600 / 60),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct MockImporter {
+        existing: HashSet<String>,
+        added: Vec<PathBuf>,
+        magnets: Vec<Url>,
+    }
+
+    impl Importer for MockImporter {
+        fn existing(&mut self, ids: &[String]) -> Result<HashSet<String>> {
+            Ok(ids
+                .iter()
+                .filter(|id| self.existing.contains(*id))
+                .cloned()
+                .collect())
+        }
+
+        fn add_torrent(&mut self, path: &Path) -> Result<String> {
+            self.added.push(path.to_owned());
+            importable_id(path)
+        }
+
+        fn add_magnet(&mut self, magnet: Url) -> Result<String> {
+            self.magnets.push(magnet);
+            Ok("MAGNET".to_owned())
+        }
+    }
+
+    fn torrent(name: &str) -> Vec<u8> {
+        let mut info = BTreeMap::new();
+        info.insert(b"name".to_vec(), bencode::BEncode::from_str(name));
+        info.insert(b"length".to_vec(), bencode::BEncode::from_int(1));
+        let mut t = BTreeMap::new();
+        t.insert(b"info".to_vec(), bencode::BEncode::Dict(info));
+        bencode::BEncode::Dict(t).encode_to_buf()
+    }
+
+    const MAGNET: &str = "magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a";
+
+    #[test]
+    fn import_mixed_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.torrent"), torrent("a")).unwrap();
+        fs::write(dir.path().join("b.torrent"), torrent("b")).unwrap();
+        fs::write(dir.path().join("corrupt.torrent"), b"d4:infoi3e").unwrap();
+        fs::write(dir.path().join("c.magnet"), format!("{}\n", MAGNET)).unwrap();
+        fs::write(dir.path().join("ignored.txt"), b"").unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        fs::write(dir.path().join("sub").join("d.torrent"), torrent("d")).unwrap();
+
+        let b_id = importable_id(&dir.path().join("b.torrent")).unwrap();
+        let mut imp = MockImporter::default();
+        imp.existing.insert(b_id.clone());
+
+        let res = import_dir(&mut imp, dir.path(), false, false).unwrap();
+        let status: Vec<_> = res
+            .iter()
+            .map(|(p, s)| (p.file_name().unwrap().to_str().unwrap(), s))
+            .collect();
+        assert_eq!(status.len(), 4);
+        assert_matches(&status[0], "a.torrent", |s| {
+            matches!(s, ImportStatus::Added(_))
+        });
+        assert_eq!(status[1], ("b.torrent", &ImportStatus::Exists(b_id)));
+        assert_eq!(
+            status[2],
+            ("c.magnet", &ImportStatus::Added("MAGNET".to_owned()))
+        );
+        assert_matches(&status[3], "corrupt.torrent", |s| {
+            matches!(s, ImportStatus::Failed(_))
+        });
+
+        assert_eq!(imp.added, vec![dir.path().join("a.torrent")]);
+        assert_eq!(imp.magnets, vec![Url::parse(MAGNET).unwrap()]);
+        // Only imported files are moved
+        assert!(dir.path().join(LOADED_DIR).join("a.torrent").exists());
+        assert!(dir.path().join(LOADED_DIR).join("c.magnet").exists());
+        assert!(!dir.path().join("a.torrent").exists());
+        assert!(dir.path().join("b.torrent").exists());
+        assert!(dir.path().join("corrupt.torrent").exists());
+        assert!(dir.path().join("sub").join("d.torrent").exists());
+    }
+
+    #[test]
+    fn import_recursive_delete() {
+        let dir = tempfile::tempdir().unwrap();
+        let sub = dir.path().join("sub");
+        fs::create_dir_all(sub.join(LOADED_DIR)).unwrap();
+        fs::write(sub.join("d.torrent"), torrent("d")).unwrap();
+        fs::write(sub.join(LOADED_DIR).join("e.torrent"), torrent("e")).unwrap();
+
+        let mut imp = MockImporter::default();
+        let res = import_dir(&mut imp, dir.path(), true, true).unwrap();
+        assert_eq!(res.len(), 1);
+        assert_eq!(imp.added, vec![sub.join("d.torrent")]);
+        assert!(!sub.join("d.torrent").exists());
+        assert!(!sub.join(LOADED_DIR).join("d.torrent").exists());
+    }
+
+    #[test]
+    fn magnet_base32_id() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("x.magnet");
+        fs::write(
+            &path,
+            "magnet:?xt=urn:btih:YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK",
+        )
+        .unwrap();
+        assert_eq!(
+            importable_id(&path).unwrap(),
+            "C12FE1C06BBA254A9DC9F519B335AA7C1367A88A"
+        );
+    }
+
+    fn metainfo(files: Vec<(Vec<&str>, u64)>, info: &bencode::BEncode) -> message::Metainfo {
+        message::Metainfo {
+            hash: Sha1::digest(info.encode_to_buf())
+                .iter()
+                .map(|b| format!("{:02X}", b))
+                .collect(),
+            name: "t".to_owned(),
+            piece_length: 16384,
+            pieces: BASE64_STANDARD.encode([7; 20]),
+            files: files
+                .into_iter()
+                .map(|(path, length)| message::MetainfoFile {
+                    path: path.into_iter().map(str::to_owned).collect(),
+                    length,
+                })
+                .collect(),
+            announce: Some("http://a/announce".to_owned()),
+            announce_list: vec![
+                vec!["http://a/announce".to_owned()],
+                vec!["http://b/announce".to_owned()],
+            ],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn export_metainfo() {
+        let file = |path: &[&str], len| {
+            let mut f = BTreeMap::new();
+            f.insert(b"length".to_vec(), bencode::BEncode::from_int(len));
+            let path = path.iter().map(|c| bencode::BEncode::from_str(c));
+            f.insert(b"path".to_vec(), bencode::BEncode::List(path.collect()));
+            bencode::BEncode::Dict(f)
+        };
+        let mut info = BTreeMap::new();
+        info.insert(b"name".to_vec(), bencode::BEncode::from_str("t"));
+        info.insert(b"piece length".to_vec(), bencode::BEncode::from_int(16384));
+        info.insert(b"pieces".to_vec(), bencode::BEncode::String(vec![7; 20]));
+        info.insert(
+            b"files".to_vec(),
+            bencode::BEncode::List(vec![file(&["a"], 10), file(&["d", "b"], 20)]),
+        );
+        let info = bencode::BEncode::Dict(info);
+
+        let m = metainfo(vec![(vec!["t", "a"], 10), (vec!["t", "d", "b"], 20)], &info);
+        let mut t = bencode::decode_buf(&encode_metainfo(&m).unwrap())
+            .unwrap()
+            .into_dict()
+            .unwrap();
+        assert_eq!(t.remove(b"info".as_ref()), Some(info.clone()));
+        let tiers = t
+            .remove(b"announce-list".as_ref())
+            .unwrap()
+            .into_list()
+            .unwrap();
+        assert_eq!(tiers.len(), 2);
+
+        // Single file torrents use the top level length key, which
+        // doesn't match the multi-file info dict
+        let m = metainfo(vec![(vec!["t"], 10)], &info);
+        assert!(encode_metainfo(&m).is_err());
+    }
+
+    #[test]
+    fn jsonl_records() {
+        let updates = vec![
+            SResourceUpdate::Throttle {
+                id: "s".to_owned(),
+                kind: ResourceKind::Server,
+                throttle_up: Some(10),
+                throttle_down: None,
+            },
+            SResourceUpdate::Rate {
+                id: "t".to_owned(),
+                kind: ResourceKind::Torrent,
+                rate_up: 1,
+                rate_down: 2,
+            },
+        ];
+        let mut out = Vec::new();
+        for u in &updates {
+            write_jsonl(&mut out, u).unwrap();
+        }
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.ends_with('\n'));
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(lines.len(), 2);
+        for (line, u) in lines.iter().zip(&updates) {
+            let parsed: SResourceUpdate = serde_json::from_str(line).unwrap();
+            assert_eq!(&parsed, u);
+        }
+    }
+
+    #[derive(Default)]
+    struct MockTorrents {
+        torrents: Vec<Resource>,
+        queries: usize,
+    }
+
+    impl MockTorrents {
+        fn new(torrents: &[(&str, &str)]) -> MockTorrents {
+            MockTorrents {
+                torrents: torrents
+                    .iter()
+                    .map(|(id, name)| {
+                        Resource::Torrent(resource::Torrent {
+                            id: id.repeat(ID_LEN / id.len()),
+                            name: Some(name.to_string()),
+                            ..Default::default()
+                        })
+                    })
+                    .collect(),
+                queries: 0,
+            }
+        }
+    }
+
+    impl TorrentQuery for MockTorrents {
+        fn torrents(&mut self, criteria: Vec<Criterion>) -> Result<Vec<Resource>> {
+            self.queries += 1;
+            let criteria = criteria
+                .into_iter()
+                .map(|c| c.compile().unwrap())
+                .collect::<Vec<_>>();
+            Ok(self
+                .torrents
+                .iter()
+                .filter(|t| criteria.iter().all(|c| c.matches(*t)))
+                .cloned()
+                .collect())
+        }
+    }
+
+    fn resolved_name(q: &mut MockTorrents, arg: &str) -> Result<String> {
+        let res = resolve_torrent(q, arg)?;
+        Ok(res.as_torrent().name.clone().unwrap())
+    }
+
+    #[test]
+    fn resolve_torrent_ids() {
+        let mut q = MockTorrents::new(&[
+            ("ab12", "Debian netinst"),
+            ("ab34", "Ubuntu desktop"),
+            ("cd56", "Debian DVD"),
+        ]);
+        let full = "ab12".repeat(10);
+        assert_eq!(resolved_name(&mut q, &full).unwrap(), "Debian netinst");
+        assert_eq!(resolved_name(&mut q, "ab1").unwrap(), "Debian netinst");
+        assert_eq!(resolved_name(&mut q, "CD").unwrap(), "Debian DVD");
+        assert_eq!(q.queries, 3);
+
+        // An id prefix shared by several torrents lists them
+        let err = resolve_torrent(&mut q, "ab").unwrap_err().to_string();
+        assert!(err.starts_with("Ambiguous results searching for ab"));
+        assert!(err.contains("Debian netinst") && err.contains("Ubuntu desktop"));
+        assert!(!err.contains("Debian DVD"));
+
+        // Ids only match from the start
+        assert!(resolve_torrent(&mut q, "12ab").is_err());
+    }
+
+    #[test]
+    fn resolve_torrent_names() {
+        let mut q = MockTorrents::new(&[
+            ("ab12", "Debian netinst"),
+            ("ab34", "Ubuntu desktop"),
+            ("cd56", "Debian DVD"),
+            ("ef78", "Fedora cafe"),
+        ]);
+        assert_eq!(resolved_name(&mut q, "ubuntu").unwrap(), "Ubuntu desktop");
+        assert_eq!(q.queries, 1);
+        // Hex arguments which aren't an id prefix fall back to names
+        assert_eq!(resolved_name(&mut q, "CAFE").unwrap(), "Fedora cafe");
+        assert_eq!(q.queries, 2);
+
+        let err = resolve_torrent(&mut q, "debian").unwrap_err().to_string();
+        assert!(err.contains("Debian netinst") && err.contains("Debian DVD"));
+        assert_eq!(
+            resolve_torrent(&mut q, "arch").unwrap_err().to_string(),
+            "Could not find any matching torrents for arch"
+        );
+    }
+
+    #[test]
+    fn resolve_torrent_wildcards() {
+        let mut q = MockTorrents::new(&[
+            ("ab12", "100% legit_file"),
+            ("ab34", "1000 legitXfile"),
+            ("cd56", "back\\slash"),
+        ]);
+        // LIKE wildcards in the argument only match themselves
+        assert_eq!(resolved_name(&mut q, "100%").unwrap(), "100% legit_file");
+        assert_eq!(resolved_name(&mut q, "t_f").unwrap(), "100% legit_file");
+        assert_eq!(resolved_name(&mut q, "k\\s").unwrap(), "back\\slash");
+        assert_eq!(resolved_name(&mut q, "_").unwrap(), "100% legit_file");
+        assert_eq!(q.queries, 4);
+    }
+
+    #[test]
+    fn countdown() {
+        assert_eq!(fmt_countdown(-5), "now");
+        assert_eq!(fmt_countdown(0), "now");
+        assert_eq!(fmt_countdown(42), "42s");
+        assert_eq!(fmt_countdown(270), "4m 30s");
+        assert_eq!(fmt_countdown(3900), "1h 05m");
+    }
+
+    fn assert_matches(
+        status: &(&str, &ImportStatus),
+        name: &str,
+        f: impl Fn(&ImportStatus) -> bool,
+    ) {
+        assert_eq!(status.0, name);
+        assert!(f(status.1), "unexpected status {:?}", status.1);
+    }
+
+    #[derive(Default)]
+    struct MockAdder {
+        added: Vec<Addable>,
+    }
+
+    impl Adder for MockAdder {
+        fn add(&mut self, item: Addable) -> Result<(String, Resource)> {
+            let id = match &item {
+                Addable::Torrent(t) if t == b"d4:infoi3ee" => "BAD".to_owned(),
+                Addable::Torrent(t) => format!("T{}", t.len()),
+                Addable::Magnet(m) => m.as_str()[20..24].to_uppercase(),
+            };
+            self.added.push(item);
+            if id == "BAD" {
+                bail!("Invalid torrent");
+            }
+            let resource = Resource::Torrent(resource::Torrent {
+                id: id.clone(),
+                ..Default::default()
+            });
+            Ok((id, resource))
+        }
+    }
+
+    #[test]
+    fn stats_eta() {
+        assert_eq!(eta(None, 0.5, 100), None);
+        assert_eq!(eta(Some(1000), 0.5, 0), None);
+        assert_eq!(eta(Some(1000), 0.5, 100), Some(5));
+        assert_eq!(eta(Some(1000), 0.5, 300), Some(2));
+        assert_eq!(eta(Some(1000), 1., 0), Some(0));
+        assert_eq!(eta(Some(1000), 1.5, 100), Some(0));
+        assert_eq!(eta(Some(0), 0., 0), Some(0));
+    }
+
+    fn dash_torrent(id: &str, name: &str) -> SResourceUpdate<'static> {
+        SResourceUpdate::Resource(Cow::Owned(Resource::Torrent(resource::Torrent {
+            id: id.to_owned(),
+            name: Some(name.to_owned()),
+            size: Some(1000),
+            ..Default::default()
+        })))
+    }
+
+    fn transfer(id: &str, rate_down: u64, progress: f32) -> SResourceUpdate<'static> {
+        SResourceUpdate::TorrentTransfer {
+            id: id.to_owned(),
+            kind: ResourceKind::Torrent,
+            rate_up: 0,
+            rate_down,
+            transferred_up: 0,
+            transferred_down: 0,
+            ses_transferred_up: 0,
+            ses_transferred_down: 0,
+            progress,
+            endgame: false,
+        }
+    }
+
+    fn updates(resources: Vec<SResourceUpdate<'static>>) -> SMessage<'static> {
+        SMessage::UpdateResources {
+            serial: None,
+            resources,
+        }
+    }
+
+    #[test]
+    fn stats_dashboard() {
+        let second = Duration::from_secs(1);
+        let start = Instant::now();
+        let mut dash = Dashboard::new(2 * second);
+        assert_eq!(dash.wait(start), 2 * second);
+        assert_eq!(dash.frame(start), None);
+
+        let extant = SMessage::ResourcesExtant {
+            serial: 1,
+            ids: vec![Cow::Borrowed("A"), Cow::Borrowed("B")],
+        };
+        assert_eq!(dash.apply(extant), ["A", "B"]);
+        let server = Resource::Server(resource::Server {
+            id: "S".to_owned(),
+            rate_down: 2048,
+            ..Default::default()
+        });
+        dash.apply(updates(vec![
+            SResourceUpdate::Resource(Cow::Owned(server)),
+            dash_torrent("A", "alpha"),
+            dash_torrent("B", "beta"),
+        ]));
+        let frame = dash.frame(start).unwrap();
+        assert!(frame.starts_with("UL: 0 B/s, DL: 2 kiB/s, 2 torrents"));
+        assert!(frame.contains("alpha") && frame.contains("beta"));
+
+        // Updates within the interval are folded into the next paint
+        dash.apply(updates(vec![transfer("A", 100, 0.1)]));
+        dash.apply(updates(vec![transfer("A", 50, 0.5)]));
+        assert_eq!(dash.frame(start + second), None);
+        assert_eq!(dash.wait(start + second), second);
+        let frame = dash.frame(start + 2 * second).unwrap();
+        assert!(frame.contains("50 B/s") && !frame.contains("100 B/s"));
+        assert!(frame.contains("50.00%") && frame.contains("10s"));
+        // The busiest torrent comes first
+        assert!(frame.find("alpha") < frame.find("beta"));
+        assert_eq!(dash.frame(start + 10 * second), None);
+
+        // Torrents come and go mid-session
+        let extant = SMessage::ResourcesExtant {
+            serial: 1,
+            ids: vec![Cow::Borrowed("A"), Cow::Borrowed("C")],
+        };
+        assert_eq!(dash.apply(extant), ["C"]);
+        dash.apply(SMessage::ResourcesRemoved {
+            serial: 1,
+            ids: vec!["B".to_owned()],
+        });
+        dash.apply(updates(vec![
+            dash_torrent("C", "gamma"),
+            transfer("C", 0, 1.),
+        ]));
+        // Updates for torrents no longer shown are dropped
+        dash.apply(updates(vec![transfer("B", 10, 0.)]));
+        let frame = dash.frame(start + 10 * second).unwrap();
+        assert!(frame.contains("2 torrents") && !frame.contains("beta"));
+        assert!(frame.contains("gamma") && frame.contains("done"));
+    }
+
+    #[test]
+    fn sniff_stdin() {
+        let magnets = format!("\n{}\r\n\n  {}\n", MAGNET, MAGNET.replace("c12f", "ab12"));
+        assert_eq!(
+            sniff(magnets.into_bytes()).unwrap(),
+            vec![
+                Addable::Magnet(Url::parse(MAGNET).unwrap()),
+                Addable::Magnet(Url::parse(&MAGNET.replace("c12f", "ab12")).unwrap()),
+            ]
+        );
+        let t = torrent("a");
+        assert_eq!(sniff(t.clone()).unwrap(), vec![Addable::Torrent(t)]);
+
+        assert!(sniff(format!("{}\nnot a magnet", MAGNET).into_bytes()).is_err());
+        assert!(sniff(b"<html>".to_vec()).is_err());
+        assert!(sniff(b"\n".to_vec()).is_err());
+    }
+
+    #[test]
+    fn add_quiet() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("a.torrent");
+        fs::write(&file, torrent("a")).unwrap();
+        let mut adder = MockAdder::default();
+        let mut out = Vec::new();
+        let stdin = format!("{}\n{}\n", MAGNET, MAGNET.replace("c12f", "ab12"));
+        let res = add_all(
+            &mut adder,
+            vec![file.to_str().unwrap(), "-", MAGNET],
+            &mut stdin.as_bytes(),
+            &mut out,
+            "ids",
+        );
+        assert!(res.is_ok());
+        let t = format!("T{}", torrent("a").len());
+        let expected = format!("{}\nC12F\nAB12\nC12F\n", t);
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+        assert_eq!(adder.added.len(), 4);
+
+        // Failures are left out of the IDs, but still fail the command
+        let mut out = Vec::new();
+        let res = add_all(
+            &mut adder,
+            vec!["-", MAGNET, "/nonexistent.torrent"],
+            &mut b"d4:infoi3ee".as_ref(),
+            &mut out,
+            "ids",
+        );
+        assert!(res.is_err());
+        assert_eq!(String::from_utf8(out).unwrap(), "C12F\n");
+    }
+}
@@ -7,6 +7,7 @@ use synapse_rpc as rpc;
 extern crate tungstenite as ws;
 
 use rpc::criterion::Criterion;
+use rpc::message::IpFilterAction;
 
 mod client;
 mod cmd;
@@ -45,6 +46,13 @@ fn main() {
                 .short('p')
                 .long("password"),
         )
+        .arg(
+            Arg::new("verbose")
+                .help("Print RPC traffic to stderr, repeat to include all received messages.")
+                .short('v')
+                .long("verbose")
+                .action(ArgAction::Count),
+        )
         .subcommands([
             Command::new("add")
                 .about("Adds torrents to synapse.")
@@ -68,13 +76,72 @@ fn main() {
                         .long("import")
                         .action(ArgAction::SetTrue),
                 )
+                .arg(
+                    Arg::new("file priority")
+                        .help(
+                            "Priority of a file given as INDEX=PRIORITY, with priorities from \
+                             0 (skip) to 5. Files left out get the default of 3.",
+                        )
+                        .long("file-priority")
+                        .value_parser(parse_file_priority)
+                        .action(ArgAction::Append),
+                )
+                .arg(
+                    Arg::new("sequential")
+                        .help("Download pieces in order rather than rarest first.")
+                        .short('s')
+                        .long("sequential")
+                        .action(ArgAction::SetTrue),
+                )
                 .arg(
                     Arg::new("files")
-                        .help("Torrent files or magnets to add")
+                        .help(
+                            "Torrent files or magnets to add, - reading a torrent or newline \
+                             separated magnets from stdin",
+                        )
                         .required(true)
                         .index(1)
                         .action(ArgAction::Append),
                 )
+                .arg(
+                    Arg::new("output")
+                        .help("Output the results in the specified format.")
+                        .short('o')
+                        .long("output")
+                        .value_parser(["json", "text"])
+                        .default_value("text"),
+                )
+                .arg(
+                    Arg::new("quiet")
+                        .help("Only print the IDs of added torrents, one per line.")
+                        .short('q')
+                        .long("quiet")
+                        .action(ArgAction::SetTrue),
+                ),
+            Command::new("announce")
+                .about("Announce to the trackers of every running torrent.")
+                .long_about(
+                    "Announce to the trackers of every running torrent, printing progress until \
+                     every announce has been answered. Announces are paced by the server, and \
+                     trackers which asked not to be announced to yet are reported as deferred.",
+                )
+                .arg(
+                    Arg::new("all")
+                        .help("Announce to every tracker.")
+                        .short('a')
+                        .long("all")
+                        .required(true)
+                        .action(ArgAction::SetTrue),
+                ),
+            Command::new("create")
+                .about("Helpers for creating torrents.")
+                .arg(
+                    Arg::new("analyze")
+                        .help("Path on the server to suggest a piece size for.")
+                        .short('a')
+                        .long("analyze")
+                        .required(true),
+                )
                 .arg(
                     Arg::new("output")
                         .help("Output the results in the specified format.")
@@ -134,10 +201,80 @@ fn main() {
                 )
                 .arg(
                     Arg::new("id")
-                        .help("ID of the resource.")
+                        .help("ID of the resource, or a torrent's id prefix or name.")
                         .index(1)
                         .required(true),
                 ),
+            Command::new("import")
+                .about("Adds all .torrent and .magnet files in a directory to synapse.")
+                .arg(
+                    Arg::new("directory")
+                        .help("Custom directory to download the torrents to.")
+                        .short('d')
+                        .long("directory"),
+                )
+                .arg(
+                    Arg::new("pause")
+                        .help("Whether or not the torrents should start paused.")
+                        .short('P')
+                        .long("pause")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("recursive")
+                        .help("Import files in subdirectories as well.")
+                        .short('r')
+                        .long("recursive")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("delete")
+                        .help("Delete imported files rather than moving them into loaded/.")
+                        .long("delete")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("path")
+                        .help("Directory to import from.")
+                        .required(true)
+                        .index(1),
+                ),
+            Command::new("ipfilter")
+                .about("Manage the blocklist of peer addresses.")
+                .subcommand_required(true)
+                .subcommands([
+                    Command::new("add")
+                        .about("Block addresses, disconnecting any matching peers")
+                        .arg(
+                            Arg::new("ranges")
+                                .help("CIDR prefixes, addresses or start-end ranges to block.")
+                                .index(1)
+                                .required(true)
+                                .action(ArgAction::Append),
+                        ),
+                    Command::new("remove").about("Unblock addresses").arg(
+                        Arg::new("ranges")
+                            .help("CIDR prefixes, addresses or start-end ranges to unblock.")
+                            .index(1)
+                            .required(true)
+                            .action(ArgAction::Append),
+                    ),
+                    Command::new("load")
+                        .about("Block the ranges listed in a CIDR or DAT file")
+                        .arg(
+                            Arg::new("replace")
+                                .help("Replace the current blocklist instead of adding to it.")
+                                .short('r')
+                                .long("replace")
+                                .action(ArgAction::SetTrue),
+                        )
+                        .arg(
+                            Arg::new("file")
+                                .help("Path of the file on the synapse server.")
+                                .index(1)
+                                .required(true),
+                        ),
+                ]),
             Command::new("list")
                 .about("Lists resources of a given type in synapse.")
                 .arg(
@@ -161,6 +298,19 @@ fn main() {
                         .long("output")
                         .value_parser(["json", "text"])
                         .default_value("text"),
+                )
+                .arg(
+                    Arg::new("limit")
+                        .help("List at most this many resources, ordered by id.")
+                        .short('l')
+                        .long("limit")
+                        .value_parser(clap::value_parser!(usize)),
+                )
+                .arg(
+                    Arg::new("offset")
+                        .help("Skip this many matching resources, ordered by id.")
+                        .long("offset")
+                        .value_parser(clap::value_parser!(usize)),
                 ),
             Command::new("pause")
                 .about("Pauses the given torrents.")
@@ -180,15 +330,32 @@ fn main() {
                         .index(1)
                         .action(ArgAction::Append),
                 ),
+            Command::new("stats")
+                .about("Shows live transfer rates of the server and its torrents.")
+                .arg(
+                    Arg::new("interval")
+                        .help("Seconds between repaints.")
+                        .short('i')
+                        .long("interval")
+                        .value_parser(clap::value_parser!(u64).range(1..))
+                        .default_value("2"),
+                ),
             Command::new("status").about("Server status"),
             Command::new("watch")
                 .about("Watches the specified resource, printing out updates.")
                 .arg(
                     Arg::new("output")
                         .help("Output the results in the specified format.")
+                        .long_help(
+                            "Output the results in the specified format. \
+                             text and json print the whole resource after every update, \
+                             while jsonl prints the initial resource followed by each \
+                             update event as a single line JSON object. jsonl output is \
+                             never wrapped in an array, so it can be consumed incrementally.",
+                        )
                         .short('o')
                         .long("output")
-                        .value_parser(["json", "text"])
+                        .value_parser(["json", "jsonl", "text"])
                         .default_value("text"),
                 )
                 .arg(
@@ -200,7 +367,7 @@ fn main() {
                 )
                 .arg(
                     Arg::new("id")
-                        .help("ID of the resource.")
+                        .help("ID of the resource, or a torrent's id prefix or name.")
                         .index(1)
                         .required(true),
                 ),
@@ -208,7 +375,7 @@ fn main() {
                 .about("Manipulate torrent related resources")
                 .arg(
                     Arg::new("torrent id")
-                        .help("Name of torrent to download.")
+                        .help("ID, unique id prefix or name of the torrent.")
                         .index(1),
                 )
                 .subcommand_required(true)
@@ -309,11 +476,32 @@ fn main() {
                                 .index(1)
                                 .required(true),
                         ),
+                    Command::new("order")
+                        .about(
+                            "Download files to completion in the given order before the rest, \
+                             no files clears the order",
+                        )
+                        .arg(
+                            Arg::new("file ids")
+                                .help("IDs of the files, in order.")
+                                .index(1)
+                                .action(ArgAction::Append),
+                        ),
                     Command::new("trackers").about("Prints a torrent's trackers"),
                     Command::new("peers").about("Prints a torrent's peers"),
                     Command::new("tags").about("Prints a torrent's tags"),
                     Command::new("files").about("Prints a torrent's files"),
+                    Command::new("history")
+                        .about("Prints who paused, moved or otherwise changed a torrent"),
                     Command::new("verify").about("Verify integrity of downloaded files"),
+                    Command::new("export")
+                        .about("Write out the torrent's .torrent file")
+                        .arg(
+                            Arg::new("path")
+                                .help("Path to write the .torrent file to.")
+                                .index(1)
+                                .required(true),
+                        ),
                 ])
                 .arg(
                     Arg::new("output")
@@ -323,6 +511,42 @@ fn main() {
                         .value_parser(["json", "text"])
                         .default_value("text"),
                 ),
+            Command::new("tracker")
+                .about("Manipulate a tracker.")
+                .subcommand_required(true)
+                .subcommands([
+                    Command::new("set-header")
+                        .about(
+                            "Set headers sent with HTTP announces, an empty value removes a header",
+                        )
+                        .arg(
+                            Arg::new("tracker id")
+                                .help("ID of tracker to use.")
+                                .index(1)
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::new("headers")
+                                .help("Headers of the form \"Name: value\".")
+                                .index(2)
+                                .required(true)
+                                .action(ArgAction::Append),
+                        ),
+                    Command::new("set-url")
+                        .about("Change the announce URL of a tracker, keeping its ID")
+                        .arg(
+                            Arg::new("tracker id")
+                                .help("ID of tracker to use.")
+                                .index(1)
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::new("url")
+                                .help("New announce URL.")
+                                .index(2)
+                                .required(true),
+                        ),
+                ]),
         ])
         .get_matches();
 
@@ -349,9 +573,12 @@ fn main() {
             process::exit(1);
         }
     };
-    url.query_pairs_mut().append_pair("password", pass);
+    url.query_pairs_mut()
+        .append_pair("password", pass)
+        .append_pair("minor", &rpc::MINOR_VERSION.to_string());
 
-    let client = match Client::new(url.clone()) {
+    let verbosity = *matches.get_one::<u8>("verbose").unwrap();
+    let client = match Client::new(url.clone(), verbosity) {
         Ok(c) => c,
         Err(e) => {
             eprintln!(
@@ -392,14 +619,31 @@ fn main() {
                 .unwrap()
                 .map(String::as_str)
                 .collect();
-            let output = add_args.get_one::<String>("output").unwrap();
+            let output = if add_args.get_flag("quiet") {
+                "ids"
+            } else {
+                add_args.get_one::<String>("output").unwrap()
+            };
+            let preset = cmd::Preset {
+                file_priorities: add_args
+                    .get_many::<(usize, u8)>("file priority")
+                    .into_iter()
+                    .flatten()
+                    .copied()
+                    .collect(),
+                strategy: if add_args.get_flag("sequential") {
+                    Some(rpc::resource::Strategy::Sequential)
+                } else {
+                    None
+                },
+            };
             let res = cmd::add(
                 client,
-                url.as_str(),
                 files,
                 add_args.get_one::<String>("directory").map(String::as_str),
                 !add_args.get_flag("pause"),
                 add_args.get_flag("import"),
+                &preset,
                 output,
             );
             if let Err(e) = res {
@@ -407,6 +651,23 @@ fn main() {
                 process::exit(1);
             }
         }
+        ("announce", _) => {
+            if let Err(e) = cmd::reannounce_all(client) {
+                eprintln!("Failed to announce: {:?}", e);
+                process::exit(1);
+            }
+        }
+        ("create", create_args) => {
+            let res = cmd::analyze_path(
+                client,
+                create_args.get_one::<String>("analyze").unwrap(),
+                create_args.get_one::<String>("output").unwrap(),
+            );
+            if let Err(e) = res {
+                eprintln!("Failed to analyze path: {:?}", e);
+                process::exit(1);
+            }
+        }
         ("del", del_args) => {
             let res = cmd::del(
                 client,
@@ -459,21 +720,73 @@ fn main() {
                 process::exit(1);
             }
         }
+        ("import", import_args) => {
+            let res = cmd::import(
+                client,
+                import_args.get_one::<String>("path").unwrap(),
+                import_args
+                    .get_one::<String>("directory")
+                    .map(String::as_str),
+                !import_args.get_flag("pause"),
+                import_args.get_flag("recursive"),
+                import_args.get_flag("delete"),
+            );
+            if let Err(e) = res {
+                eprintln!("Failed to import torrents: {:?}", e);
+                process::exit(1);
+            }
+        }
         ("list", list_args) => {
             let crit = if let Some(searches) = list_args.get_one::<String>("filter") {
                 parse_filter(searches)
             } else {
                 Vec::new()
             };
+            if let Err(e) = crit.iter().try_for_each(|c| c.clone().compile().map(drop)) {
+                eprintln!("Invalid filter: {}", e);
+                process::exit(1);
+            }
 
             let kind = list_args.get_one::<String>("kind").unwrap();
             let output = list_args.get_one::<String>("output").unwrap();
-            let res = cmd::list(client, kind, crit, output);
+            let page = match (
+                list_args.get_one::<usize>("offset"),
+                list_args.get_one::<usize>("limit"),
+            ) {
+                (None, None) => None,
+                (offset, limit) => Some((offset.copied().unwrap_or(0), limit.copied())),
+            };
+            let res = cmd::list(client, kind, crit, page, output);
             if let Err(e) = res {
                 eprintln!("Failed to list torrents: {:?}", e);
                 process::exit(1);
             }
         }
+        ("ipfilter", filter_args) => {
+            let ranges = |args: &clap::ArgMatches| -> Vec<String> {
+                args.get_many::<String>("ranges")
+                    .unwrap()
+                    .cloned()
+                    .collect()
+            };
+            let (action, ranges, path) = match filter_args.subcommand().unwrap() {
+                ("add", args) => (IpFilterAction::Add, ranges(args), None),
+                ("remove", args) => (IpFilterAction::Remove, ranges(args), None),
+                ("load", args) => {
+                    let action = if args.get_flag("replace") {
+                        IpFilterAction::Replace
+                    } else {
+                        IpFilterAction::Add
+                    };
+                    (action, vec![], args.get_one::<String>("file").cloned())
+                }
+                _ => unreachable!(),
+            };
+            if let Err(e) = cmd::update_ip_filter(client, action, ranges, path) {
+                eprintln!("Failed to update ip filter: {:?}", e);
+                process::exit(1);
+            }
+        }
         ("pause", pause_args) => {
             let res = cmd::pause(
                 client,
@@ -502,6 +815,13 @@ fn main() {
                 process::exit(1);
             }
         }
+        ("stats", stats_args) => {
+            let interval = *stats_args.get_one::<u64>("interval").unwrap();
+            if let Err(e) = cmd::stats(client, interval) {
+                eprintln!("Failed to show stats: {:?}", e);
+                process::exit(1);
+            }
+        }
         ("status", _) => {
             if let Err(e) = cmd::status(client) {
                 eprintln!("Failed to get server status: {:?}", e);
@@ -531,6 +851,13 @@ fn main() {
                         process::exit(1);
                     }
                 }
+                ("export", export_args) => {
+                    let path = export_args.get_one::<String>("path").unwrap();
+                    if let Err(e) = cmd::export_torrent(client, &id, path) {
+                        eprintln!("Failed to export torrent: {:?}", e);
+                        process::exit(1);
+                    }
+                }
                 ("tracker", tracker_args) => match tracker_args.subcommand().unwrap() {
                     ("add", add_args) => {
                         if let Err(e) = cmd::add_trackers(
@@ -642,6 +969,16 @@ fn main() {
                         process::exit(1);
                     }
                 }
+                ("order", order_args) => {
+                    let files = order_args
+                        .get_many::<String>("file ids")
+                        .map(|v| v.map(|s| s.as_str()).collect())
+                        .unwrap_or_default();
+                    if let Err(e) = cmd::set_file_order(client, &id, files) {
+                        eprintln!("Failed to set file order: {:?}", e);
+                        process::exit(1);
+                    }
+                }
                 ("files", _) => {
                     if let Err(e) = cmd::get_files(client, &id, output) {
                         eprintln!("Failed to get torrent files: {:?}", e);
@@ -666,9 +1003,38 @@ fn main() {
                         process::exit(1);
                     }
                 }
+                ("history", _) => {
+                    if let Err(e) = cmd::get_history(client, &id, output) {
+                        eprintln!("Failed to get torrent history: {:?}", e);
+                        process::exit(1);
+                    }
+                }
                 _ => unreachable!(),
             }
         }
+        ("tracker", tracker_args) => match tracker_args.subcommand().unwrap() {
+            ("set-header", header_args) => {
+                let id = header_args.get_one::<String>("tracker id").unwrap();
+                let headers = header_args
+                    .get_many::<String>("headers")
+                    .unwrap()
+                    .map(String::as_str)
+                    .collect();
+                if let Err(e) = cmd::set_tracker_headers(client, id, headers) {
+                    eprintln!("Failed to set tracker headers: {:?}", e);
+                    process::exit(1);
+                }
+            }
+            ("set-url", url_args) => {
+                let id = url_args.get_one::<String>("tracker id").unwrap();
+                let url = url_args.get_one::<String>("url").unwrap();
+                if let Err(e) = cmd::set_tracker_url(client, id, url) {
+                    eprintln!("Failed to set tracker url: {:?}", e);
+                    process::exit(1);
+                }
+            }
+            _ => unreachable!(),
+        },
         ("watch", watch_args) => {
             let id = watch_args
                 .get_one::<String>("id")
@@ -686,6 +1052,20 @@ fn main() {
     }
 }
 
+/// Parse an INDEX=PRIORITY file priority
+fn parse_file_priority(s: &str) -> Result<(usize, u8), String> {
+    let (file, priority) = s
+        .split_once('=')
+        .ok_or_else(|| format!("{} is not of the form INDEX=PRIORITY", s))?;
+    let file = file
+        .parse()
+        .map_err(|_| format!("{} is not a file index", file))?;
+    match priority.parse() {
+        Ok(p) if p <= 5 => Ok((file, p)),
+        _ => Err(format!("{} is not a priority from 0 to 5", priority)),
+    }
+}
+
 /// Parse search criteria out of a filter string
 fn parse_filter(searches: &str) -> Vec<Criterion> {
     use regex::Regex;
@@ -698,11 +1078,12 @@ fn parse_filter(searches: &str) -> Vec<Criterion> {
     let string_searches = Regex::new(
         r#"(?x)
         # field name
-        \b(id|name|path|status|tracker|error|torrent_id)
+        \b(id|name|path|status|tracker|error|torrent_id
+           |creator|comment|strategy)
         # delimiter
-        (==|!=|::|:)
+        (==|!=|::|:|~=)
         # quoted argument
-        ("(.+?)"
+        ("(.+?)"|'(.+?)'
         # unquoted argument
         |([0-9.a-zA-Z]+))
         "#,
@@ -715,7 +1096,7 @@ fn parse_filter(searches: &str) -> Vec<Criterion> {
         # field name
         \b(size|progress|priority|availability
            |rate_up|rate_down|throttle_up|throttle_down
-           |transferred_up|transferred_down
+           |transferred_up|transferred_down|ses_transferred_up|ses_transferred_down
            |peers|trackers|files)
         # delimiter
         (>=|<=|==|!=|>|<)
@@ -735,13 +1116,14 @@ fn parse_filter(searches: &str) -> Vec<Criterion> {
             "!=" => Operation::Neq,
             "::" => Operation::Like,
             ":" => Operation::ILike,
+            "~=" => Operation::Matches,
             _ => unreachable!(),
         };
-        let arg = if let Some(quoted) = cap.get(4) {
+        let arg = if let Some(quoted) = cap.get(4).or_else(|| cap.get(5)) {
             quoted
         } else {
             // if quoted arg did not match, an unquoted arg must have matched
-            cap.get(5).unwrap()
+            cap.get(6).unwrap()
         }
         .as_str();
         let value = Value::S(arg.to_string());
@@ -788,6 +1170,15 @@ mod tests {
     use super::*;
     use rpc::criterion::{Operation, Value};
 
+    #[test]
+    fn parse_file_priorities() {
+        assert_eq!(parse_file_priority("0=0"), Ok((0, 0)));
+        assert_eq!(parse_file_priority("3=5"), Ok((3, 5)));
+        assert!(parse_file_priority("3").is_err());
+        assert!(parse_file_priority("a=1").is_err());
+        assert!(parse_file_priority("1=6").is_err());
+    }
+
     #[test]
     fn parse_filter_simple() {
         let name_query = vec![Criterion {
@@ -799,6 +1190,49 @@ mod tests {
         assert_eq!(parse_filter("name:abcd"), name_query);
     }
 
+    #[test]
+    fn parse_filter_metadata_fields() {
+        let query = |field: &str, op, value: &str| {
+            vec![Criterion {
+                field: field.to_string(),
+                op,
+                value: Value::S(value.to_string()),
+            }]
+        };
+        assert_eq!(
+            parse_filter("comment::ubuntu"),
+            query("comment", Operation::Like, "ubuntu")
+        );
+        assert_eq!(
+            parse_filter(r#"creator:"mktorrent 1.1""#),
+            query("creator", Operation::ILike, "mktorrent 1.1")
+        );
+        assert_eq!(
+            parse_filter("strategy==sequential"),
+            query("strategy", Operation::Eq, "sequential")
+        );
+        // Fields which merely start with a known name are still name queries
+        assert_eq!(
+            parse_filter("commentary:foo"),
+            query("name", Operation::ILike, "commentary:foo")
+        );
+    }
+
+    #[test]
+    fn parse_filter_regex() {
+        let query = |value: &str| {
+            vec![Criterion {
+                field: "name".to_string(),
+                op: Operation::Matches,
+                value: Value::S(value.to_string()),
+            }]
+        };
+        assert_eq!(parse_filter(r"name~='S0[1-3]E\d+'"), query(r"S0[1-3]E\d+"));
+        assert_eq!(parse_filter(r#"name~="^a b""#), query("^a b"));
+        assert_eq!(parse_filter("name~=abc"), query("abc"));
+        assert!(parse_filter("name~='('").remove(0).compile().is_err());
+    }
+
     #[test]
     fn parse_filter_simple_with_space() {
         let name_query = vec![Criterion {